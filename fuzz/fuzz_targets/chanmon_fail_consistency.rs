@@ -171,6 +171,11 @@ impl KeysInterface for KeyProvider {
 		SecretKey::from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, id, 10, self.node_id]).unwrap()
 	}
 
+	fn get_payment_preimage(&self) -> [u8; 32] {
+		let id = self.session_id.fetch_add(1, atomic::Ordering::Relaxed);
+		[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, id, 12, self.node_id]
+	}
+
 	fn get_channel_id(&self) -> [u8; 32] {
 		let id = self.channel_id.fetch_add(1, atomic::Ordering::Relaxed);
 		[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, id, 11, self.node_id]
@@ -344,10 +349,16 @@ pub fn do_test(data: &[u8]) {
 				}
 			}
 
-			for node in $nodes.iter() {
+			for (idx, node) in $nodes.iter().enumerate() {
 				let events = node.get_and_clear_pending_msg_events();
 				for event in events {
 					if let events::MessageSendEvent::SendAnnouncementSignatures { .. } = event {
+					} else if let events::MessageSendEvent::SendChannelUpdate { ref node_id, ref msg } = event {
+						for dest in $nodes.iter() {
+							if dest.get_our_node_id() == *node_id {
+								dest.handle_channel_update(&$nodes[idx].get_our_node_id(), msg).unwrap();
+							}
+						}
 					} else { panic!("Wrong event type"); }
 				}
 			}
@@ -519,6 +530,13 @@ pub fn do_test(data: &[u8]) {
 						events::MessageSendEvent::SendFundingLocked { .. } => {
 							// Can be generated as a reestablish response
 						},
+						events::MessageSendEvent::SendChannelUpdate { ref node_id, ref msg } => {
+							for dest in nodes.iter() {
+								if dest.get_our_node_id() == *node_id {
+									test_err!(dest.handle_channel_update(&nodes[$node].get_our_node_id(), msg));
+								}
+							}
+						},
 						events::MessageSendEvent::PaymentFailureNetworkUpdate { .. } => {
 							// Can be generated due to a payment forward being rejected due to a
 							// channel having previously failed a monitor update
@@ -538,6 +556,7 @@ pub fn do_test(data: &[u8]) {
 							events::MessageSendEvent::SendRevokeAndACK { .. } => {},
 							events::MessageSendEvent::SendChannelReestablish { .. } => {},
 							events::MessageSendEvent::SendFundingLocked { .. } => {},
+							events::MessageSendEvent::SendChannelUpdate { .. } => {},
 							events::MessageSendEvent::PaymentFailureNetworkUpdate { .. } => {},
 							_ => panic!("Unhandled message event"),
 						}
@@ -550,6 +569,7 @@ pub fn do_test(data: &[u8]) {
 							events::MessageSendEvent::SendRevokeAndACK { .. } => {},
 							events::MessageSendEvent::SendChannelReestablish { .. } => {},
 							events::MessageSendEvent::SendFundingLocked { .. } => {},
+							events::MessageSendEvent::SendChannelUpdate { .. } => {},
 							events::MessageSendEvent::PaymentFailureNetworkUpdate { .. } => {},
 							_ => panic!("Unhandled message event"),
 						}
@@ -571,6 +591,7 @@ pub fn do_test(data: &[u8]) {
 							if *node_id != drop_node_id { true } else { false }
 						},
 						events::MessageSendEvent::SendFundingLocked { .. } => false,
+						events::MessageSendEvent::SendChannelUpdate { .. } => false,
 						events::MessageSendEvent::PaymentFailureNetworkUpdate { .. } => false,
 						_ => panic!("Unhandled message event"),
 					};
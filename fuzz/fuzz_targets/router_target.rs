@@ -5,6 +5,7 @@ extern crate secp256k1;
 
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 use bitcoin::blockdata::script::{Script, Builder};
+use bitcoin::network::constants::Network;
 
 use lightning::chain::chaininterface::{ChainError,ChainWatchInterface, ChainListener};
 use lightning::ln::channelmanager::ChannelDetails;
@@ -127,6 +128,7 @@ pub fn do_test(data: &[u8]) {
 					msgs::DecodeError::InvalidValue => return,
 					msgs::DecodeError::ExtraAddressesPerType => return,
 					msgs::DecodeError::BadLengthDescriptor => return,
+					msgs::DecodeError::UnsupportedCompression => return,
 					msgs::DecodeError::ShortRead => panic!("We picked the length..."),
 					msgs::DecodeError::Io(e) => panic!(format!("{}", e)),
 				}
@@ -158,7 +160,7 @@ pub fn do_test(data: &[u8]) {
 	});
 
 	let our_pubkey = get_pubkey!();
-	let router = Router::new(our_pubkey.clone(), chain_monitor, Arc::clone(&logger));
+	let router = Router::new(our_pubkey.clone(), Network::Bitcoin, chain_monitor, Arc::clone(&logger));
 
 	loop {
 		match get_slice!(1)[0] {
@@ -205,6 +207,8 @@ pub fn do_test(data: &[u8]) {
 								inbound_capacity_msat: 0,
 								is_live: true,
 								outbound_capacity_msat: 0,
+								pending_inbound_htlc_msat: 0,
+								pending_outbound_htlc_msat: 0,
 							});
 						}
 						Some(&first_hops_vec[..])
@@ -2,7 +2,7 @@
 //! spendable on-chain outputs which the user owns and is responsible for using just as any other
 //! on-chain output which is theirs.
 
-use bitcoin::blockdata::transaction::{OutPoint, TxOut};
+use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut, SigHashType};
 use bitcoin::blockdata::script::{Script, Builder};
 use bitcoin::blockdata::opcodes;
 use bitcoin::network::constants::Network;
@@ -17,6 +17,7 @@ use secp256k1::key::{SecretKey, PublicKey};
 use secp256k1::Secp256k1;
 use secp256k1;
 
+use ln::chan_utils;
 use util::byte_utils;
 use util::logger::Logger;
 
@@ -66,6 +67,55 @@ pub enum SpendableOutputDescriptor {
 	}
 }
 
+impl SpendableOutputDescriptor {
+	/// Gets a TxIn which spends this output, with nSequence set as required to satisfy any
+	/// relative timelock (ie to_self_delay for DynamicOutputP2WSH) and an empty witness.
+	pub fn to_txin(&self) -> TxIn {
+		let (outpoint, sequence) = match self {
+			&SpendableOutputDescriptor::StaticOutput { ref outpoint, .. } => (outpoint, 0),
+			&SpendableOutputDescriptor::DynamicOutputP2WSH { ref outpoint, ref to_self_delay, .. } => (outpoint, *to_self_delay as u32),
+			&SpendableOutputDescriptor::DynamicOutputP2WPKH { ref outpoint, .. } => (outpoint, 0),
+		};
+		TxIn {
+			previous_output: outpoint.clone(),
+			script_sig: Script::new(),
+			sequence,
+			witness: Vec::new(),
+		}
+	}
+
+	/// Builds the witness which spends this output as input input_idx of spend_tx, signing with
+	/// SIGHASH_ALL. The input should have been built with to_txin() so that its nSequence is
+	/// correct, and spend_tx must otherwise be complete as it is committed to by the signature.
+	///
+	/// Returns None for StaticOutput, which pays to a script provided via KeysInterface and must
+	/// thus be signed by your own wallet.
+	pub fn get_spend_witness<T: secp256k1::Signing>(&self, secp_ctx: &Secp256k1<T>, spend_tx: &Transaction, input_idx: usize) -> Option<Vec<Vec<u8>>> {
+		match self {
+			&SpendableOutputDescriptor::StaticOutput { .. } => None,
+			&SpendableOutputDescriptor::DynamicOutputP2WSH { ref key, ref witness_script, ref output, .. } => {
+				let sighash = hash_to_message!(&chan_utils::sighash_commitment(spend_tx, input_idx, witness_script, output.value)[..]);
+				let mut local_delayedsig = secp_ctx.sign(&sighash, key).serialize_der().to_vec();
+				local_delayedsig.push(SigHashType::All as u8);
+				Some(vec![local_delayedsig, vec![], witness_script.clone().into_bytes()])
+			},
+			&SpendableOutputDescriptor::DynamicOutputP2WPKH { ref key, ref output, .. } => {
+				let pubkey = PublicKey::from_secret_key(secp_ctx, key);
+				let witness_script = Builder::new().push_opcode(opcodes::all::OP_DUP)
+				                              .push_opcode(opcodes::all::OP_HASH160)
+				                              .push_slice(&Hash160::hash(&pubkey.serialize())[..])
+				                              .push_opcode(opcodes::all::OP_EQUALVERIFY)
+				                              .push_opcode(opcodes::all::OP_CHECKSIG)
+				                              .into_script();
+				let sighash = hash_to_message!(&chan_utils::sighash_commitment(spend_tx, input_idx, &witness_script, output.value)[..]);
+				let mut sig = secp_ctx.sign(&sighash, key).serialize_der().to_vec();
+				sig.push(SigHashType::All as u8);
+				Some(vec![sig, pubkey.serialize().to_vec()])
+			},
+		}
+	}
+}
+
 /// A trait to describe an object which can get user secrets and key material.
 pub trait KeysInterface: Send + Sync {
 	/// Get node secret key (aka node_id or network_key)
@@ -79,6 +129,18 @@ pub trait KeysInterface: Send + Sync {
 	fn get_channel_keys(&self, inbound: bool) -> ChannelKeys;
 	/// Get a secret for construting an onion packet
 	fn get_session_key(&self) -> SecretKey;
+	/// Get a fresh secret to use as the payment preimage of an invoice we create. These MUST be
+	/// unique even if you restarted with some stale data, as anyone who learns a preimage can
+	/// claim payments to any invoice using it.
+	///
+	/// By default this hashes a fresh session key from get_session_key, so implementations which
+	/// predate invoice creation need not be changed.
+	fn get_payment_preimage(&self) -> [u8; 32] {
+		let mut sha = Sha256::engine();
+		sha.input(b"payment preimage");
+		sha.input(&self.get_session_key()[..]);
+		Sha256::from_engine(sha).into_inner()
+	}
 	/// Get a unique temporary channel id. Channels will be referred to by this until the funding
 	/// transaction is created, at which point they will use the outpoint in the funding
 	/// transaction.
@@ -129,6 +191,8 @@ pub struct KeysManager {
 	session_child_index: AtomicUsize,
 	channel_id_master_key: ExtendedPrivKey,
 	channel_id_child_index: AtomicUsize,
+	payment_preimage_master_key: ExtendedPrivKey,
+	payment_preimage_child_index: AtomicUsize,
 
 	unique_start: Sha256State,
 	logger: Arc<Logger>,
@@ -175,6 +239,7 @@ impl KeysManager {
 				let channel_master_key = master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(3).unwrap()).expect("Your RNG is busted");
 				let session_master_key = master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(4).unwrap()).expect("Your RNG is busted");
 				let channel_id_master_key = master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(5).unwrap()).expect("Your RNG is busted");
+				let payment_preimage_master_key = master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(6).unwrap()).expect("Your RNG is busted");
 
 				let mut unique_start = Sha256::engine();
 				unique_start.input(&byte_utils::be64_to_array(starting_time_secs));
@@ -192,6 +257,8 @@ impl KeysManager {
 					session_child_index: AtomicUsize::new(0),
 					channel_id_master_key,
 					channel_id_child_index: AtomicUsize::new(0),
+					payment_preimage_master_key,
+					payment_preimage_child_index: AtomicUsize::new(0),
 
 					unique_start,
 					logger,
@@ -200,28 +267,15 @@ impl KeysManager {
 			Err(_) => panic!("Your rng is busted"),
 		}
 	}
-}
-
-impl KeysInterface for KeysManager {
-	fn get_node_secret(&self) -> SecretKey {
-		self.node_secret.clone()
-	}
 
-	fn get_destination_script(&self) -> Script {
-		self.destination_script.clone()
-	}
-
-	fn get_shutdown_pubkey(&self) -> PublicKey {
-		self.shutdown_pubkey.clone()
-	}
-
-	fn get_channel_keys(&self, _inbound: bool) -> ChannelKeys {
+	/// Derives the keys for the channel with the given child index. The result depends only on
+	/// the seed, the starting time given in the constructor and child_ix.
+	fn derive_channel_keys(&self, child_ix: usize) -> ChannelKeys {
 		// We only seriously intend to rely on the channel_master_key for true secure
 		// entropy, everything else just ensures uniqueness. We rely on the unique_start (ie
 		// starting_time provided in the constructor) to be unique.
 		let mut sha = self.unique_start.clone();
 
-		let child_ix = self.channel_child_index.fetch_add(1, Ordering::AcqRel);
 		let child_privkey = self.channel_master_key.ckd_priv(&self.secp_ctx, ChildNumber::from_hardened_idx(child_ix as u32).expect("key space exhausted")).expect("Your RNG is busted");
 		sha.input(&child_privkey.private_key.key[..]);
 
@@ -257,6 +311,25 @@ impl KeysInterface for KeysManager {
 			commitment_seed,
 		}
 	}
+}
+
+impl KeysInterface for KeysManager {
+	fn get_node_secret(&self) -> SecretKey {
+		self.node_secret.clone()
+	}
+
+	fn get_destination_script(&self) -> Script {
+		self.destination_script.clone()
+	}
+
+	fn get_shutdown_pubkey(&self) -> PublicKey {
+		self.shutdown_pubkey.clone()
+	}
+
+	fn get_channel_keys(&self, _inbound: bool) -> ChannelKeys {
+		let child_ix = self.channel_child_index.fetch_add(1, Ordering::AcqRel);
+		self.derive_channel_keys(child_ix)
+	}
 
 	fn get_session_key(&self) -> SecretKey {
 		let mut sha = self.unique_start.clone();
@@ -267,6 +340,16 @@ impl KeysInterface for KeysManager {
 		SecretKey::from_slice(&Sha256::from_engine(sha).into_inner()).expect("Your RNG is busted")
 	}
 
+	fn get_payment_preimage(&self) -> [u8; 32] {
+		let mut sha = self.unique_start.clone();
+
+		let child_ix = self.payment_preimage_child_index.fetch_add(1, Ordering::AcqRel);
+		let child_privkey = self.payment_preimage_master_key.ckd_priv(&self.secp_ctx, ChildNumber::from_hardened_idx(child_ix as u32).expect("key space exhausted")).expect("Your RNG is busted");
+		sha.input(&child_privkey.private_key.key[..]);
+
+		(Sha256::from_engine(sha).into_inner())
+	}
+
 	fn get_channel_id(&self) -> [u8; 32] {
 		let mut sha = self.unique_start.clone();
 
@@ -277,3 +360,95 @@ impl KeysInterface for KeysManager {
 		(Sha256::from_engine(sha).into_inner())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use chain::keysinterface::{ChannelKeys, KeysInterface, KeysManager, SpendableOutputDescriptor};
+	use ln::chan_utils;
+	use util::test_utils::TestLogger;
+
+	use bitcoin::network::constants::Network;
+
+	use bitcoin::blockdata::opcodes;
+	use bitcoin::blockdata::script::{Builder, Script};
+	use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+
+	use secp256k1::key::{PublicKey, SecretKey};
+	use secp256k1::Secp256k1;
+
+	use std::sync::Arc;
+
+	#[test]
+	fn test_spend_to_local_output() {
+		let secp_ctx = Secp256k1::new();
+		let delayed_key = SecretKey::from_slice(&[42; 32]).unwrap();
+		let revocation_key = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[43; 32]).unwrap());
+		let to_self_delay = 144;
+		let witness_script = chan_utils::get_revokeable_redeemscript(&revocation_key, to_self_delay, &PublicKey::from_secret_key(&secp_ctx, &delayed_key));
+
+		let commitment_tx = Transaction {
+			version: 2,
+			lock_time: 0,
+			input: vec![TxIn { previous_output: OutPoint::default(), script_sig: Script::new(), sequence: 0, witness: Vec::new() }],
+			output: vec![TxOut { script_pubkey: witness_script.to_v0_p2wsh(), value: 100000 }],
+		};
+		let descriptor = SpendableOutputDescriptor::DynamicOutputP2WSH {
+			outpoint: OutPoint { txid: commitment_tx.txid(), vout: 0 },
+			key: delayed_key,
+			witness_script: witness_script.clone(),
+			to_self_delay,
+			output: commitment_tx.output[0].clone(),
+		};
+
+		let mut spend_tx = Transaction {
+			version: 2,
+			lock_time: 0,
+			input: vec![descriptor.to_txin()],
+			output: vec![TxOut { script_pubkey: Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script(), value: 99000 }],
+		};
+		assert_eq!(spend_tx.input[0].sequence, to_self_delay as u32);
+		spend_tx.input[0].witness = descriptor.get_spend_witness(&secp_ctx, &spend_tx, 0).unwrap();
+		assert_eq!(spend_tx.input[0].witness.len(), 3);
+		assert!(spend_tx.input[0].witness[1].is_empty());
+		assert_eq!(spend_tx.input[0].witness[2], witness_script.clone().into_bytes());
+
+		let get_output = |out_point: &OutPoint| {
+			if out_point.txid == commitment_tx.txid() { commitment_tx.output.get(out_point.vout as usize).cloned() } else { None }
+		};
+		spend_tx.verify(get_output).unwrap();
+
+		// Spending before the CSV delay has passed fails OP_CSV
+		let mut early_spend_tx = spend_tx.clone();
+		early_spend_tx.input[0].sequence = to_self_delay as u32 - 1;
+		early_spend_tx.input[0].witness = descriptor.get_spend_witness(&secp_ctx, &early_spend_tx, 0).unwrap();
+		assert!(early_spend_tx.verify(get_output).is_err());
+
+		let static_output = SpendableOutputDescriptor::StaticOutput { outpoint: OutPoint { txid: commitment_tx.txid(), vout: 0 }, output: commitment_tx.output[0].clone() };
+		assert!(static_output.get_spend_witness(&secp_ctx, &spend_tx, 0).is_none());
+	}
+
+	fn assert_keys_eq(a: &ChannelKeys, b: &ChannelKeys) {
+		assert_eq!(a.funding_key, b.funding_key);
+		assert_eq!(a.revocation_base_key, b.revocation_base_key);
+		assert_eq!(a.payment_base_key, b.payment_base_key);
+		assert_eq!(a.delayed_payment_base_key, b.delayed_payment_base_key);
+		assert_eq!(a.htlc_base_key, b.htlc_base_key);
+		assert_eq!(a.commitment_seed, b.commitment_seed);
+	}
+
+	#[test]
+	fn test_channel_keys_deterministic() {
+		let seed = [42; 32];
+		let keys_manager = KeysManager::new(&seed, Network::Testnet, Arc::new(TestLogger::new()), 10, 20);
+		assert_keys_eq(&keys_manager.derive_channel_keys(0), &keys_manager.derive_channel_keys(0));
+		assert!(keys_manager.derive_channel_keys(0).funding_key != keys_manager.derive_channel_keys(1).funding_key);
+
+		// Restarting with the same seed and starting time hands out the same keys in order
+		let first = keys_manager.get_channel_keys(false);
+		let second = keys_manager.get_channel_keys(true);
+		let restarted = KeysManager::new(&seed, Network::Testnet, Arc::new(TestLogger::new()), 10, 20);
+		assert_keys_eq(&restarted.get_channel_keys(false), &first);
+		assert_keys_eq(&restarted.get_channel_keys(true), &second);
+		assert_keys_eq(&restarted.derive_channel_keys(1), &second);
+	}
+}
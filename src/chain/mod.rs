@@ -3,3 +3,7 @@
 pub mod chaininterface;
 pub mod transaction;
 pub mod keysinterface;
+
+/// ChannelMonitor lives alongside Channel in ln, but watches the chain on its behalf, so it is
+/// also reachable here.
+pub use ln::channelmonitor;
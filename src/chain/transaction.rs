@@ -3,6 +3,8 @@
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 use bitcoin::blockdata::transaction::OutPoint as BitcoinOutPoint;
 
+use ln::chan_utils;
+
 /// A reference to a transaction output.
 ///
 /// Differs from bitcoin::blockdata::transaction::OutPoint as the index is a u16 instead of u32
@@ -23,11 +25,9 @@ impl OutPoint {
 
 	/// Convert an `OutPoint` to a lightning channel id.
 	pub fn to_channel_id(&self) -> [u8; 32] {
-		let mut res = [0; 32];
-		res[..].copy_from_slice(&self.txid[..]);
-		res[30] ^= ((self.index >> 8) & 0xff) as u8;
-		res[31] ^= ((self.index >> 0) & 0xff) as u8;
-		res
+		let mut txid = [0; 32];
+		txid.copy_from_slice(&self.txid[..]);
+		chan_utils::derive_channel_id(&txid, self.index)
 	}
 
 	/// Converts this OutPoint into the OutPoint field as used by rust-bitcoin
@@ -10,7 +10,8 @@
 //! instead of having a rather-separate lightning appendage to a wallet.
 
 #![cfg_attr(not(feature = "fuzztarget"), deny(missing_docs))]
-#![forbid(unsafe_code)]
+// The only unsafe code is the volatile writes used to wipe secrets in peer_channel_encryptor
+#![deny(unsafe_code)]
 
 extern crate bitcoin;
 extern crate bitcoin_hashes;
@@ -1,6 +1,7 @@
 use bitcoin::blockdata::script::{Script,Builder};
 use bitcoin::blockdata::opcodes;
 use bitcoin::blockdata::transaction::{TxIn,TxOut,OutPoint,Transaction};
+use bitcoin::util::bip143;
 
 use bitcoin_hashes::{Hash, HashEngine};
 use bitcoin_hashes::sha256::Hash as Sha256;
@@ -9,11 +10,14 @@ use bitcoin_hashes::hash160::Hash as Hash160;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 
 use ln::channelmanager::PaymentHash;
+use ln::msgs::HandleError;
 
 use secp256k1::key::{PublicKey,SecretKey};
-use secp256k1::Secp256k1;
+use secp256k1::{Message, Secp256k1, Signature};
 use secp256k1;
 
+pub const COMMITMENT_TX_BASE_WEIGHT: u64 = 724;
+pub const COMMITMENT_TX_WEIGHT_PER_HTLC: u64 = 172;
 pub const HTLC_SUCCESS_TX_WEIGHT: u64 = 703;
 pub const HTLC_TIMEOUT_TX_WEIGHT: u64 = 663;
 
@@ -32,6 +36,45 @@ pub fn build_commitment_secret(commitment_seed: [u8; 32], idx: u64) -> [u8; 32]
 	res
 }
 
+/// Derives the 48-bit factor which commitment transaction numbers are XOR'd with before being
+/// encoded in a commitment transaction, per BOLT #3. This is the lower 48 bits of
+/// SHA256(open_channel payment_basepoint || accept_channel payment_basepoint), so outbound
+/// indicates whether we sent the open_channel (and thus our basepoint comes first).
+pub fn get_commitment_transaction_number_obscure_factor(our_payment_basepoint: &PublicKey, their_payment_basepoint: &PublicKey, outbound: bool) -> u64 {
+	let mut sha = Sha256::engine();
+	if outbound {
+		sha.input(&our_payment_basepoint.serialize());
+		sha.input(&their_payment_basepoint.serialize());
+	} else {
+		sha.input(&their_payment_basepoint.serialize());
+		sha.input(&our_payment_basepoint.serialize());
+	}
+	let res = Sha256::from_engine(sha).into_inner();
+
+	((res[26] as u64) << 5*8) |
+	((res[27] as u64) << 4*8) |
+	((res[28] as u64) << 3*8) |
+	((res[29] as u64) << 2*8) |
+	((res[30] as u64) << 1*8) |
+	((res[31] as u64) << 0*8)
+}
+
+/// Obscures the given (BOLT #3, ie counting up from 0) commitment number and encodes it, returning
+/// the (nSequence, nLockTime) to use in the commitment transaction. The upper 24 bits of the
+/// obscured number go in the input's nSequence (with upper byte 0x80) and the lower 24 bits in
+/// nLockTime (with upper byte 0x20).
+pub fn encode_commitment_transaction_number(commitment_number: u64, obscure_factor: u64) -> (u32, u32) {
+	let obscured_commitment_transaction_number = obscure_factor ^ commitment_number;
+	(((0x80 as u32) << 8*3) | ((obscured_commitment_transaction_number >> 3*8) as u32 & 0xffffff),
+	 ((0x20 as u32) << 8*3) | ((obscured_commitment_transaction_number & 0xffffffu64) as u32))
+}
+
+/// Decodes the (BOLT #3, ie counting up from 0) commitment number from a commitment transaction's
+/// input nSequence and nLockTime, given the obscure factor for the channel.
+pub fn decode_commitment_transaction_number(sequence: u32, lock_time: u32, obscure_factor: u64) -> u64 {
+	(((sequence as u64 & 0xffffff) << 3*8) | (lock_time as u64 & 0xffffff)) ^ obscure_factor
+}
+
 pub fn derive_private_key<T: secp256k1::Signing>(secp_ctx: &Secp256k1<T>, per_commitment_point: &PublicKey, base_secret: &SecretKey) -> Result<SecretKey, secp256k1::Error> {
 	let mut sha = Sha256::engine();
 	sha.input(&per_commitment_point.serialize());
@@ -141,6 +184,35 @@ pub fn get_revokeable_redeemscript(revocation_key: &PublicKey, to_self_delay: u1
 	              .into_script()
 }
 
+/// Gets the 2-of-2 multisig redeemscript for a channel's funding output, with the funding pubkeys
+/// sorted lexicographically (by their compressed serialization) per BOLT #3.
+pub fn make_funding_redeemscript(a: &PublicKey, b: &PublicKey) -> Script {
+	let a = a.serialize();
+	let b = b.serialize();
+	let builder = Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_2);
+	if a[..] < b[..] {
+		builder.push_slice(&a).push_slice(&b)
+	} else {
+		builder.push_slice(&b).push_slice(&a)
+	}.push_opcode(opcodes::all::OP_PUSHNUM_2).push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script()
+}
+
+/// Gets the P2WSH scriptPubKey the funding transaction pays to, ie
+/// make_funding_redeemscript(a, b).to_v0_p2wsh().
+pub fn make_funding_output_script(a: &PublicKey, b: &PublicKey) -> Script {
+	make_funding_redeemscript(a, b).to_v0_p2wsh()
+}
+
+/// Derives the permanent channel_id for a channel funded by the given output, per BOLT #2: the
+/// funding txid (in its serialized byte order) with its last two bytes XOR'd with the big-endian
+/// output index.
+pub fn derive_channel_id(funding_txid: &[u8; 32], output_index: u16) -> [u8; 32] {
+	let mut res = *funding_txid;
+	res[30] ^= ((output_index >> 8) & 0xff) as u8;
+	res[31] ^= ((output_index >> 0) & 0xff) as u8;
+	res
+}
+
 #[derive(Clone, PartialEq)]
 pub struct HTLCOutputInCommitment {
 	pub offered: bool,
@@ -222,6 +294,26 @@ pub fn get_htlc_redeemscript(htlc: &HTLCOutputInCommitment, keys: &TxCreationKey
 	get_htlc_redeemscript_with_explicit_keys(htlc, &keys.a_htlc_key, &keys.b_htlc_key, &keys.revocation_key)
 }
 
+/// Returns true if an HTLC of htlc_msat would be trimmed from a commitment transaction at the given
+/// feerate and dust limit, per BOLT #3. offered is from the point of view of the commitment
+/// transaction's owner, as it determines whether an HTLC-timeout or HTLC-success transaction (and
+/// thus which fee) would be needed to claim it.
+pub fn htlc_is_dust(htlc_msat: u64, feerate_per_kw: u64, dust_limit_satoshis: u64, offered: bool) -> bool {
+	let htlc_tx_fee = if offered {
+			feerate_per_kw * HTLC_TIMEOUT_TX_WEIGHT / 1000
+		} else {
+			feerate_per_kw * HTLC_SUCCESS_TX_WEIGHT / 1000
+		};
+	htlc_msat / 1000 < dust_limit_satoshis + htlc_tx_fee
+}
+
+/// Gets the commitment transaction fee, in msat, which the channel funder pays at the given feerate
+/// with the given number of untrimmed HTLC outputs. Per BOLT #3, the fee is rounded down to a whole
+/// satoshi.
+pub fn commit_tx_fee_msat(feerate_per_kw: u64, num_untrimmed_htlcs: usize) -> u64 {
+	feerate_per_kw * (COMMITMENT_TX_BASE_WEIGHT + num_untrimmed_htlcs as u64 * COMMITMENT_TX_WEIGHT_PER_HTLC) / 1000 * 1000
+}
+
 /// panics if htlc.transaction_output_index.is_none()!
 pub fn build_htlc_transaction(prev_hash: &Sha256dHash, feerate_per_kw: u64, to_self_delay: u16, htlc: &HTLCOutputInCommitment, a_delayed_payment_key: &PublicKey, revocation_key: &PublicKey) -> Transaction {
 	let mut txins: Vec<TxIn> = Vec::new();
@@ -254,3 +346,257 @@ pub fn build_htlc_transaction(prev_hash: &Sha256dHash, feerate_per_kw: u64, to_s
 		output: txouts,
 	}
 }
+
+/// Gets the BIP 143 SIGHASH_ALL hash which each party signs for the given HTLC-timeout or
+/// HTLC-success transaction (as built by build_htlc_transaction), given the HTLC's redeemscript.
+pub fn get_htlc_transaction_sighash(htlc_tx: &Transaction, htlc_redeemscript: &Script, htlc: &HTLCOutputInCommitment) -> Sha256dHash {
+	sighash_commitment(htlc_tx, 0, htlc_redeemscript, htlc.amount_msat / 1000)
+}
+
+/// Gets the BIP 143 (segwit v0) SIGHASH_ALL sighash for spending the given input of tx, which
+/// spends a P2WSH output of amount_sat paying to redeemscript. All of our commitment, HTLC and
+/// closing transaction signatures are over this.
+/// Panics if input_index is out of bounds.
+pub fn sighash_commitment(tx: &Transaction, input_index: usize, redeemscript: &Script, amount_sat: u64) -> Sha256dHash {
+	bip143::SighashComponents::new(tx).sighash_all(&tx.input[input_index], redeemscript, amount_sat)
+}
+
+/// Checks the counterparty's signature on a commitment transaction we rebuilt, which spends the
+/// funding output paying to funding_redeemscript.to_v0_p2wsh(). The error has no action set, the
+/// caller should close the channel.
+pub fn verify_commitment_sig<T: secp256k1::Verification>(secp_ctx: &Secp256k1<T>, tx: &Transaction, funding_redeemscript: &Script, channel_value_satoshis: u64, sig: &Signature, their_funding_key: &PublicKey) -> Result<(), HandleError> {
+	let sighash = sighash_commitment(tx, 0, funding_redeemscript, channel_value_satoshis);
+	let sighash = Message::from_slice(&sighash[..]).unwrap();
+	match secp_ctx.verify(&sighash, sig, their_funding_key) {
+		Ok(()) => Ok(()),
+		Err(_) => Err(HandleError { err: "Invalid commitment tx signature from peer", action: None }),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ln::chan_utils::{build_htlc_transaction, decode_commitment_transaction_number, derive_channel_id, encode_commitment_transaction_number, get_commitment_transaction_number_obscure_factor, get_htlc_redeemscript_with_explicit_keys, get_htlc_transaction_sighash, htlc_is_dust, commit_tx_fee_msat, make_funding_output_script, make_funding_redeemscript, sighash_commitment, verify_commitment_sig, HTLCOutputInCommitment};
+	use ln::channelmanager::PaymentHash;
+
+	use bitcoin::consensus::encode::{deserialize, serialize};
+	use bitcoin::blockdata::transaction::Transaction;
+	use bitcoin_hashes::sha256::Hash as Sha256;
+	use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+	use bitcoin_hashes::hex::FromHex;
+	use bitcoin_hashes::Hash;
+
+	use secp256k1::key::PublicKey;
+	use secp256k1::{Message, Secp256k1, Signature};
+
+	use hex;
+
+	fn pubkey_from_hex(hex: &str) -> PublicKey {
+		PublicKey::from_slice(&hex::decode(hex).unwrap()[..]).unwrap()
+	}
+
+	/// The local and remote funding pubkeys from BOLT #3 Appendices B and C
+	fn bolt3_funding_pubkeys() -> (PublicKey, PublicKey) {
+		(pubkey_from_hex("023da092f6980e58d2c037173180e9a465476026ee50f96695963e8efe436f54eb"),
+		 pubkey_from_hex("030e9f7b623d2ccc7c9bd44d66d5ce21ce504c0acf6385a132cec6d3c39fa711c1"))
+	}
+
+	/// The fully-signed BOLT #3 Appendix C "simple commitment tx with no HTLCs", which spends the
+	/// 10000000 sat funding output
+	fn bolt3_simple_commitment_tx() -> Transaction {
+		deserialize(&hex::decode("02000000000101bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8002c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de84311054a56a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e0400473044022051b75c73198c6deee1a875871c3961832909acd297c6b908d59e3319e5185a46022055c419379c5051a78d00dbbce11b5b664a0c22815fbcc6fcef6b1937c383693901483045022100f51d2e566a70ba740fc5d8c0f07b9b93d2ed741c3c0860c613173de7d39e7968022041376d520e9c0e1ad52248ddf4b22e12be8763007df977253ef45a4ca3bdb7c001475221023da092f6980e58d2c037173180e9a465476026ee50f96695963e8efe436f54eb21030e9f7b623d2ccc7c9bd44d66d5ce21ce504c0acf6385a132cec6d3c39fa711c152ae3e195220").unwrap()[..]).unwrap()
+	}
+
+	/// Builds the HTLC transaction for htlc, an output of the BOLT #3 Appendix C "commitment tx with
+	/// all five HTLCs untrimmed (minimum feerate)", checks it against the signed expected_tx_hex
+	/// from the spec and that both of the spec's signatures are valid over our sighash.
+	fn check_bolt3_htlc_transaction(htlc: &HTLCOutputInCommitment, expected_tx_hex: &str) -> Transaction {
+		let secp_ctx = Secp256k1::new();
+		let local_delayedpubkey = pubkey_from_hex("03fd5960528dc152014952efdb702a88f71e3c1653b2314431701ec77e57fde83c");
+		let local_revocation_pubkey = pubkey_from_hex("0212a140cd0c6539d07cd08dfe09984dec3251ea808b892efeac3ede9402bf2b19");
+		let local_htlcpubkey = pubkey_from_hex("030d417a46946384f88d5f3337267c5e579765875dc4daca813e21734b140639e7");
+		let remote_htlcpubkey = pubkey_from_hex("0394854aa6eab5b2a8122cc726e9dded053a2184d88256816826d6231c068d4a5b");
+		let commitment_txid = Sha256dHash::from_hex("97214ec97013024db9808226693cf896224deb4d4c65396cb55f1af1ccec5481").unwrap();
+
+		let htlc_tx = build_htlc_transaction(&commitment_txid, 0, 144, htlc, &local_delayedpubkey, &local_revocation_pubkey);
+
+		let mut expected_tx: Transaction = deserialize(&hex::decode(expected_tx_hex).unwrap()[..]).unwrap();
+		let witness = expected_tx.input[0].witness.split_off(0);
+		assert_eq!(serialize(&htlc_tx), serialize(&expected_tx));
+
+		let htlc_redeemscript = get_htlc_redeemscript_with_explicit_keys(htlc, &local_htlcpubkey, &remote_htlcpubkey, &local_revocation_pubkey);
+		assert_eq!(htlc_redeemscript[..], witness[4][..]);
+
+		// Both the remote and local signatures from the test vector are valid over our sighash
+		let sighash = Message::from_slice(&get_htlc_transaction_sighash(&htlc_tx, &htlc_redeemscript, htlc)[..]).unwrap();
+		let remote_sig = Signature::from_der(&witness[1][..witness[1].len() - 1]).unwrap();
+		let local_sig = Signature::from_der(&witness[2][..witness[2].len() - 1]).unwrap();
+		secp_ctx.verify(&sighash, &remote_sig, &remote_htlcpubkey).unwrap();
+		secp_ctx.verify(&sighash, &local_sig, &local_htlcpubkey).unwrap();
+
+		htlc_tx
+	}
+
+	#[test]
+	fn test_commitment_number_obscuring() {
+		// Test vectors from BOLT #3 Appendix C, where the local node is the funder
+		let local_payment_basepoint = pubkey_from_hex("034f355bdcb7cc0af728ef3cceb9615d90684bb5b2ca5f859ab0f0b704075871aa");
+		let remote_payment_basepoint = pubkey_from_hex("032c0b7cf95324a07d05398b240174dc0c2be444d96b159aa6c7f7b1e668680991");
+		let obscure_factor = get_commitment_transaction_number_obscure_factor(&local_payment_basepoint, &remote_payment_basepoint, true);
+		assert_eq!(obscure_factor, 0x2bb038521914);
+		assert_eq!(get_commitment_transaction_number_obscure_factor(&remote_payment_basepoint, &local_payment_basepoint, false), obscure_factor);
+
+		let (sequence, lock_time) = encode_commitment_transaction_number(42, obscure_factor);
+		assert_eq!(sequence, 2150346808);
+		assert_eq!(lock_time, 542251326);
+		assert_eq!(decode_commitment_transaction_number(sequence, lock_time, obscure_factor), 42);
+
+		for commitment_number in [0, 1, 0xffffff, 0x1000000, 0xffffffffffff].iter() {
+			let (sequence, lock_time) = encode_commitment_transaction_number(*commitment_number, obscure_factor);
+			assert_eq!(sequence >> 3*8, 0x80);
+			assert_eq!(lock_time >> 3*8, 0x20);
+			assert_eq!(decode_commitment_transaction_number(sequence, lock_time, obscure_factor), *commitment_number);
+		}
+	}
+
+	#[test]
+	fn test_funding_scripts() {
+		// BOLT #3 Appendix B funding transaction
+		let (local_funding_pubkey, remote_funding_pubkey) = bolt3_funding_pubkeys();
+		let redeemscript = make_funding_redeemscript(&local_funding_pubkey, &remote_funding_pubkey);
+		assert_eq!(redeemscript[..], hex::decode("5221023da092f6980e58d2c037173180e9a465476026ee50f96695963e8efe436f54eb21030e9f7b623d2ccc7c9bd44d66d5ce21ce504c0acf6385a132cec6d3c39fa711c152ae").unwrap()[..]);
+		// The order the keys are given in doesn't matter
+		assert_eq!(make_funding_redeemscript(&remote_funding_pubkey, &local_funding_pubkey), redeemscript);
+
+		let output_script = make_funding_output_script(&local_funding_pubkey, &remote_funding_pubkey);
+		assert_eq!(output_script[..], hex::decode("0020c015c4a6be010e21657068fc2e6a9d02b27ebe4d490a25846f7237f104d1a3cd").unwrap()[..]);
+		assert_eq!(make_funding_output_script(&remote_funding_pubkey, &local_funding_pubkey), output_script);
+	}
+
+	#[test]
+	fn test_sighash_commitment() {
+		// BOLT #3 Appendix C "simple commitment tx with no HTLCs": both funding signatures in the
+		// witness must be over our sighash
+		let secp_ctx = Secp256k1::new();
+		let (local_funding_pubkey, remote_funding_pubkey) = bolt3_funding_pubkeys();
+		let funding_redeemscript = make_funding_redeemscript(&local_funding_pubkey, &remote_funding_pubkey);
+		let mut tx = bolt3_simple_commitment_tx();
+		let witness = tx.input[0].witness.split_off(0);
+		assert_eq!(witness[3][..], funding_redeemscript[..]);
+
+		let sighash = Message::from_slice(&sighash_commitment(&tx, 0, &funding_redeemscript, 10000000)[..]).unwrap();
+		// Signatures in the witness carry a trailing SIGHASH_ALL byte
+		let local_sig = Signature::from_der(&witness[1][..witness[1].len() - 1]).unwrap();
+		let remote_sig = Signature::from_der(&witness[2][..witness[2].len() - 1]).unwrap();
+		secp_ctx.verify(&sighash, &local_sig, &local_funding_pubkey).unwrap();
+		secp_ctx.verify(&sighash, &remote_sig, &remote_funding_pubkey).unwrap();
+
+		// The amount being spent is committed to
+		let sighash = Message::from_slice(&sighash_commitment(&tx, 0, &funding_redeemscript, 9999999)[..]).unwrap();
+		assert!(secp_ctx.verify(&sighash, &remote_sig, &remote_funding_pubkey).is_err());
+	}
+
+	#[test]
+	fn test_verify_commitment_sig() {
+		// BOLT #3 Appendix C "simple commitment tx with no HTLCs", signed by the remote funding key
+		let secp_ctx = Secp256k1::new();
+		let (local_funding_pubkey, remote_funding_pubkey) = bolt3_funding_pubkeys();
+		let funding_redeemscript = make_funding_redeemscript(&local_funding_pubkey, &remote_funding_pubkey);
+		let mut tx = bolt3_simple_commitment_tx();
+		tx.input[0].witness.clear();
+		let remote_sig = Signature::from_der(&hex::decode("3045022100f51d2e566a70ba740fc5d8c0f07b9b93d2ed741c3c0860c613173de7d39e7968022041376d520e9c0e1ad52248ddf4b22e12be8763007df977253ef45a4ca3bdb7c0").unwrap()[..]).unwrap();
+		assert!(verify_commitment_sig(&secp_ctx, &tx, &funding_redeemscript, 10000000, &remote_sig, &remote_funding_pubkey).is_ok());
+
+		// The signature is over the funding amount and the key it's checked against
+		assert!(verify_commitment_sig(&secp_ctx, &tx, &funding_redeemscript, 10000001, &remote_sig, &remote_funding_pubkey).is_err());
+		assert!(verify_commitment_sig(&secp_ctx, &tx, &funding_redeemscript, 10000000, &remote_sig, &local_funding_pubkey).is_err());
+
+		let mut flipped_sig = remote_sig.serialize_compact();
+		flipped_sig[63] ^= 1;
+		let flipped_sig = Signature::from_compact(&flipped_sig[..]).unwrap();
+		assert!(verify_commitment_sig(&secp_ctx, &tx, &funding_redeemscript, 10000000, &flipped_sig, &remote_funding_pubkey).is_err());
+
+		tx.lock_time ^= 1;
+		assert!(verify_commitment_sig(&secp_ctx, &tx, &funding_redeemscript, 10000000, &remote_sig, &remote_funding_pubkey).is_err());
+	}
+
+	#[test]
+	fn test_htlc_trimming() {
+		// At a zero feerate only the dust limit matters
+		assert!(!htlc_is_dust(546000, 0, 546, true));
+		assert!(htlc_is_dust(545999, 0, 546, true));
+		assert!(!htlc_is_dust(546000, 0, 546, false));
+		assert!(htlc_is_dust(545999, 0, 546, false));
+
+		// At 5000 sat/kw an HTLC-timeout costs 3315 sat and an HTLC-success 3515 sat
+		assert!(!htlc_is_dust(3861000, 5000, 546, true));
+		assert!(htlc_is_dust(3860999, 5000, 546, true));
+		assert!(!htlc_is_dust(4061000, 5000, 546, false));
+		assert!(htlc_is_dust(4060999, 5000, 546, false));
+		// ...so an HTLC between the two is only trimmed when received
+		assert!(!htlc_is_dust(4000000, 5000, 546, true));
+		assert!(htlc_is_dust(4000000, 5000, 546, false));
+
+		// BOLT #3 Appendix C: the 1000 sat received HTLC #0 is untrimmed at 647 sat/kw ("seven
+		// outputs untrimmed (maximum feerate)") but trimmed at 648 ("six outputs untrimmed (minimum
+		// feerate)"), while the 2000 sat offered HTLC #2 stays untrimmed.
+		assert!(!htlc_is_dust(1000000, 647, 546, false));
+		assert!(htlc_is_dust(1000000, 648, 546, false));
+		assert!(!htlc_is_dust(2000000, 648, 546, true));
+	}
+
+	#[test]
+	fn test_commit_tx_fee() {
+		// BOLT #3 Appendix C "simple commitment tx with no HTLCs"
+		assert_eq!(commit_tx_fee_msat(15000, 0), 10860000);
+		assert_eq!(commit_tx_fee_msat(0, 5), 0);
+		assert_eq!(commit_tx_fee_msat(253, 1), 226000); // 896 weight, rounded down from 226.688 sat
+		// BOLT #3 "Fee Calculation" example: of two offered and two received HTLCs at 5000 sat/kw,
+		// the 1000 sat offered and 800 sat received ones are trimmed, leaving two untrimmed.
+		let htlcs = [(5000000, true), (1000000, true), (7000000, false), (800000, false)];
+		let untrimmed = htlcs.iter().filter(|&&(amount_msat, offered)| !htlc_is_dust(amount_msat, 5000, 546, offered)).count();
+		assert_eq!(untrimmed, 2);
+		assert_eq!(commit_tx_fee_msat(5000, untrimmed), 5340000);
+		// BOLT #3 Appendix C "commitment tx with seven outputs untrimmed (maximum feerate)"
+		assert_eq!(commit_tx_fee_msat(647, 5), 1024000);
+	}
+
+	#[test]
+	fn test_htlc_success_transaction() {
+		// HTLC #0, a received HTLC spent by an HTLC-success transaction
+		check_bolt3_htlc_transaction(&HTLCOutputInCommitment {
+			offered: false,
+			amount_msat: 1000000,
+			cltv_expiry: 500,
+			payment_hash: PaymentHash(Sha256::hash(&[0; 32]).into_inner()),
+			transaction_output_index: Some(0),
+		}, "020000000001018154ecccf11a5fb56c39654c4deb4d2296f83c69268280b94d021370c94e219700000000000000000001e8030000000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e050047304402206a6e59f18764a5bf8d4fa45eebc591566689441229c918b480fb2af8cc6a4aeb02205248f273be447684b33e3c8d1d85a8e0ca9fa0bae9ae33f0527ada9c162919a60147304402207cb324fa0de88f452ffa9389678127ebcf4cabe1dd848b8e076c1a1962bf34720220116ed922b12311bd602d67e60d2529917f21c5b82f25ff6506c0f87886b4dfd5012000000000000000000000000000000000000000000000000000000000000000008a76a91414011f7254d96b819c76986c277d115efce6f7b58763ac67210394854aa6eab5b2a8122cc726e9dded053a2184d88256816826d6231c068d4a5b7c8201208763a914b8bcb07f6344b42ab04250c86a6e8b75d3fdbbc688527c21030d417a46946384f88d5f3337267c5e579765875dc4daca813e21734b140639e752ae677502f401b175ac686800000000");
+	}
+
+	#[test]
+	fn test_htlc_timeout_transaction() {
+		// HTLC #2, an offered HTLC spent by an HTLC-timeout transaction, which can't be mined until
+		// the HTLC expires
+		let htlc_tx = check_bolt3_htlc_transaction(&HTLCOutputInCommitment {
+			offered: true,
+			amount_msat: 2000000,
+			cltv_expiry: 502,
+			payment_hash: PaymentHash(Sha256::hash(&[2; 32]).into_inner()),
+			transaction_output_index: Some(1),
+		}, "020000000001018154ecccf11a5fb56c39654c4deb4d2296f83c69268280b94d021370c94e219701000000000000000001d0070000000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e0500483045022100d5275b3619953cb0c3b5aa577f04bc512380e60fa551762ce3d7a1bb7401cff9022037237ab0dac3fe100cde094e82e2bed9ba0ed1bb40154b48e56aa70f259e608b01483045022100c89172099507ff50f4c925e6c5150e871fb6e83dd73ff9fbb72f6ce829a9633f02203a63821d9162e99f9be712a68f9e589483994feae2661e4546cd5b6cec007be501008576a91414011f7254d96b819c76986c277d115efce6f7b58763ac67210394854aa6eab5b2a8122cc726e9dded053a2184d88256816826d6231c068d4a5b7c820120876475527c21030d417a46946384f88d5f3337267c5e579765875dc4daca813e21734b140639e752ae67a914b43e1b38138a41b37f7cd9a1d274bc63e3a9b5d188ac6868f6010000");
+		assert_eq!(htlc_tx.lock_time, 502);
+	}
+
+	#[test]
+	fn test_derive_channel_id() {
+		// The funding transaction from chain::transaction's test_channel_id_calculation, whose
+		// txid (in serialized byte order) ends in 0xc25e
+		let tx: Transaction = deserialize(&hex::decode("020000000001010e0adef48412e4361325ac1c6e36411299ab09d4f083b9d8ddb55fbc06e1b0c00000000000feffffff0220a1070000000000220020f81d95e040bd0a493e38bae27bff52fe2bb58b93b293eb579c01c31b05c5af1dc072cfee54a3000016001434b1d6211af5551905dc2642d05f5b04d25a8fe80247304402207f570e3f0de50546aad25a872e3df059d277e776dda4269fa0d2cc8c2ee6ec9a022054e7fae5ca94d47534c86705857c24ceea3ad51c69dd6051c5850304880fc43a012103cb11a1bacc223d98d91f1946c6752e358a5eb1a1c983b3e6fb15378f453b76bd00000000").unwrap()[..]).unwrap();
+		let mut txid = [0; 32];
+		txid.copy_from_slice(&tx.txid()[..]);
+
+		assert_eq!(&derive_channel_id(&txid, 0)[..], &hex::decode("3e88dd7165faf7be58b3c5bb2c9c452aebef682807ea57080f62e6f6e113c25e").unwrap()[..]);
+		assert_eq!(&derive_channel_id(&txid, 1)[..], &hex::decode("3e88dd7165faf7be58b3c5bb2c9c452aebef682807ea57080f62e6f6e113c25f").unwrap()[..]);
+		// The index is XOR'd in big-endian, so its upper byte lands in the second-to-last byte
+		assert_eq!(&derive_channel_id(&txid, 0x0102)[..], &hex::decode("3e88dd7165faf7be58b3c5bb2c9c452aebef682807ea57080f62e6f6e113c35c").unwrap()[..]);
+	}
+}
@@ -3,11 +3,9 @@ use bitcoin::blockdata::script::{Script,Builder};
 use bitcoin::blockdata::transaction::{TxIn, TxOut, Transaction, SigHashType};
 use bitcoin::blockdata::opcodes;
 use bitcoin::util::hash::BitcoinHash;
-use bitcoin::util::bip143;
 use bitcoin::consensus::encode::{self, Encodable, Decodable};
 
 use bitcoin_hashes::{Hash, HashEngine};
-use bitcoin_hashes::sha256::Hash as Sha256;
 use bitcoin_hashes::hash160::Hash as Hash160;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 
@@ -18,12 +16,14 @@ use secp256k1;
 use ln::msgs;
 use ln::msgs::{DecodeError, OptionalField, LocalFeatures, DataLossProtect};
 use ln::channelmonitor::ChannelMonitor;
-use ln::channelmanager::{PendingHTLCStatus, HTLCSource, HTLCFailReason, HTLCFailureMsg, PendingForwardHTLCInfo, RAACommitmentOrder, PaymentPreimage, PaymentHash, BREAKDOWN_TIMEOUT, MAX_LOCAL_BREAKDOWN_TIMEOUT};
+use ln::channelmanager::{PendingHTLCStatus, HTLCSource, HTLCFailReason, HTLCFailureMsg, PendingForwardHTLCInfo, RAACommitmentOrder, PaymentPreimage, PaymentHash, BREAKDOWN_TIMEOUT, MAX_LOCAL_BREAKDOWN_TIMEOUT, MIN_CLTV_EXPIRY_DELTA};
 use ln::chan_utils::{TxCreationKeys,HTLCOutputInCommitment,HTLC_SUCCESS_TX_WEIGHT,HTLC_TIMEOUT_TX_WEIGHT};
 use ln::chan_utils;
+use ln::channelmanager;
 use chain::chaininterface::{FeeEstimator,ConfirmationTarget};
 use chain::transaction::OutPoint;
 use chain::keysinterface::{ChannelKeys, KeysInterface};
+use util::amount::{MilliSatoshi, Satoshi};
 use util::transaction_utils;
 use util::ser::{Readable, ReadableArgs, Writeable, Writer, WriterWriteAdaptor};
 use util::logger::{Logger, LogHolder};
@@ -46,6 +46,21 @@ pub struct ChannelValueStat {
 	pub their_max_htlc_value_in_flight_msat: u64, // outgoing
 }
 
+/// The forwarding policy our counterparty applies to HTLCs they forward to us over a channel,
+/// taken from the channel_update they sent us for it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CounterpartyForwardingInfo {
+	pub fee_base_msat: u32,
+	pub fee_proportional_millionths: u32,
+	pub cltv_expiry_delta: u16,
+}
+
+impl_writeable!(CounterpartyForwardingInfo, 4+4+2, {
+	fee_base_msat,
+	fee_proportional_millionths,
+	cltv_expiry_delta
+});
+
 enum InboundHTLCRemovalReason {
 	FailRelay(msgs::OnionErrorPacket),
 	FailMalformed(([u8; 32], u16)),
@@ -338,6 +353,8 @@ pub(super) struct Channel {
 
 	their_shutdown_scriptpubkey: Option<Script>,
 
+	counterparty_forwarding_info: Option<CounterpartyForwardingInfo>,
+
 	channel_monitor: ChannelMonitor,
 
 	logger: Arc<Logger>,
@@ -349,8 +366,7 @@ pub const OUR_MAX_HTLCS: u16 = 50; //TODO
 /// really allow for this, so instead we're stuck closing it out at that point.
 const UNCONF_THRESHOLD: u32 = 6;
 /// Exposing these two constants for use in test in ChannelMonitor
-pub const COMMITMENT_TX_BASE_WEIGHT: u64 = 724;
-pub const COMMITMENT_TX_WEIGHT_PER_HTLC: u64 = 172;
+pub use ln::chan_utils::{COMMITMENT_TX_BASE_WEIGHT, COMMITMENT_TX_WEIGHT_PER_HTLC};
 const SPENDING_INPUT_FOR_A_OUTPUT_WEIGHT: u64 = 79; // prevout: 36, nSequence: 4, script len: 1, witness lengths: (3+1)/4, sig: 73/4, if-selector: 1, redeemScript: (6 ops + 2*33 pubkeys + 1*2 delay)/4
 const B_OUTPUT_PLUS_SPENDING_INPUT_WEIGHT: u64 = 104; // prevout: 40, nSequence: 4, script len: 1, witness lengths: 3/4, sig: 73/4, pubkey: 33/4, output: 31 (TODO: Wrong? Useless?)
 /// Maximmum `funding_satoshis` value, according to the BOLT #2 specification
@@ -364,11 +380,14 @@ pub const ACCEPTED_HTLC_SCRIPT_WEIGHT: usize = 139;
 pub const OFFERED_HTLC_SCRIPT_WEIGHT: usize = 133;
 
 /// Used to return a simple Error back to ChannelManager. Will get converted to a
-/// msgs::ErrorAction::SendErrorMessage or msgs::ErrorAction::IgnoreError as appropriate with our
-/// channel_id in ChannelManager.
+/// msgs::ErrorAction::SendErrorMessage, msgs::ErrorAction::DisconnectPeer or
+/// msgs::ErrorAction::IgnoreError as appropriate with our channel_id in ChannelManager.
 pub(super) enum ChannelError {
 	Ignore(&'static str),
 	Close(&'static str),
+	/// The peer got out of sync with us in a way a channel_reestablish will resolve, so we
+	/// should disconnect without touching the channel state.
+	Disconnect(&'static str),
 	CloseDelayBroadcast {
 		msg: &'static str,
 		update: Option<ChannelMonitor>
@@ -380,6 +399,7 @@ impl fmt::Debug for ChannelError {
 		match self {
 			&ChannelError::Ignore(e) => write!(f, "Ignore : {}", e),
 			&ChannelError::Close(e) => write!(f, "Close : {}", e),
+			&ChannelError::Disconnect(e) => write!(f, "Disconnect : {}", e),
 			&ChannelError::CloseDelayBroadcast { msg, .. } => write!(f, "CloseDelayBroadcast : {}", msg)
 		}
 	}
@@ -430,6 +450,9 @@ impl Channel {
 		if config.own_channel_config.our_to_self_delay < BREAKDOWN_TIMEOUT {
 			return Err(APIError::APIMisuseError{err: "Configured with an unreasonable our_to_self_delay putting user funds at risks"});
 		}
+		if config.channel_options.cltv_expiry_delta < MIN_CLTV_EXPIRY_DELTA {
+			return Err(APIError::APIMisuseError{err: "Configured with a cltv_expiry_delta too small to safely forward HTLCs"});
+		}
 
 
 		let background_feerate = fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Background);
@@ -515,6 +538,8 @@ impl Channel {
 
 			their_shutdown_scriptpubkey: None,
 
+			counterparty_forwarding_info: None,
+
 			channel_monitor: channel_monitor,
 
 			logger,
@@ -531,114 +556,86 @@ impl Channel {
 		Ok(())
 	}
 
-	/// Creates a new channel from a remote sides' request for one.
+	/// Checks a remote sides' request for a channel against our configuration and the message
+	/// sanity rules, without deriving any keys for it.
 	/// Assumes chain_hash has already been checked and corresponds with what we expect!
-	pub fn new_from_req(fee_estimator: &FeeEstimator, keys_provider: &Arc<KeysInterface>, their_node_id: PublicKey, their_local_features: LocalFeatures, msg: &msgs::OpenChannel, user_id: u64, logger: Arc<Logger>, config: &UserConfig) -> Result<Channel, ChannelError> {
-		let chan_keys = keys_provider.get_channel_keys(true);
-		let mut local_config = (*config).channel_options.clone();
-
+	pub fn check_open_channel(fee_estimator: &FeeEstimator, msg: &msgs::OpenChannel, config: &UserConfig) -> Result<(), ChannelError> {
 		if config.own_channel_config.our_to_self_delay < BREAKDOWN_TIMEOUT {
 			return Err(ChannelError::Close("Configured with an unreasonable our_to_self_delay putting user funds at risks"));
 		}
+		if config.channel_options.cltv_expiry_delta < MIN_CLTV_EXPIRY_DELTA {
+			return Err(ChannelError::Close("Configured with a cltv_expiry_delta too small to safely forward HTLCs"));
+		}
 
 		// Check sanity of message fields:
-		if msg.funding_satoshis >= MAX_FUNDING_SATOSHIS {
-			return Err(ChannelError::Close("funding value > 2^24"));
-		}
-		if msg.channel_reserve_satoshis > msg.funding_satoshis {
-			return Err(ChannelError::Close("Bogus channel_reserve_satoshis"));
-		}
-		if msg.push_msat > (msg.funding_satoshis - msg.channel_reserve_satoshis) * 1000 {
-			return Err(ChannelError::Close("push_msat larger than funding value"));
-		}
-		if msg.dust_limit_satoshis > msg.funding_satoshis {
-			return Err(ChannelError::Close("Peer never wants payout outputs?"));
-		}
-		if msg.dust_limit_satoshis > msg.channel_reserve_satoshis {
-			return Err(ChannelError::Close("Bogus; channel reserve is less than dust limit"));
-		}
-		if msg.htlc_minimum_msat >= (msg.funding_satoshis - msg.channel_reserve_satoshis) * 1000 {
-			return Err(ChannelError::Close("Minimum htlc value is full channel value"));
+		if let Err(e) = msg.check_remote_sanity(&config.peer_channel_config_limits) {
+			return Err(ChannelError::Close(e.err));
 		}
 		Channel::check_remote_fee(fee_estimator, msg.feerate_per_kw)?;
 
-		if msg.to_self_delay > config.peer_channel_config_limits.their_to_self_delay || msg.to_self_delay > MAX_LOCAL_BREAKDOWN_TIMEOUT {
-			return Err(ChannelError::Close("They wanted our payments to be delayed by a needlessly long period"));
-		}
-		if msg.max_accepted_htlcs < 1 {
-			return Err(ChannelError::Close("0 max_accpted_htlcs makes for a useless channel"));
-		}
-		if msg.max_accepted_htlcs > 483 {
-			return Err(ChannelError::Close("max_accpted_htlcs > 483"));
-		}
-
-		// Now check against optional parameters as set by config...
-		if msg.funding_satoshis < config.peer_channel_config_limits.min_funding_satoshis {
-			return Err(ChannelError::Close("funding satoshis is less than the user specified limit"));
-		}
-		if msg.htlc_minimum_msat > config.peer_channel_config_limits.max_htlc_minimum_msat {
-			return Err(ChannelError::Close("htlc minimum msat is higher than the user specified limit"));
-		}
-		if msg.max_htlc_value_in_flight_msat < config.peer_channel_config_limits.min_max_htlc_value_in_flight_msat {
-			return Err(ChannelError::Close("max htlc value in flight msat is less than the user specified limit"));
-		}
-		if msg.channel_reserve_satoshis > config.peer_channel_config_limits.max_channel_reserve_satoshis {
-			return Err(ChannelError::Close("channel reserve satoshis is higher than the user specified limit"));
-		}
-		if msg.max_accepted_htlcs < config.peer_channel_config_limits.min_max_accepted_htlcs {
-			return Err(ChannelError::Close("max accepted htlcs is less than the user specified limit"));
-		}
-		if msg.dust_limit_satoshis < config.peer_channel_config_limits.min_dust_limit_satoshis {
-			return Err(ChannelError::Close("dust limit satoshis is less than the user specified limit"));
-		}
-		if msg.dust_limit_satoshis > config.peer_channel_config_limits.max_dust_limit_satoshis {
-			return Err(ChannelError::Close("dust limit satoshis is greater than the user specified limit"));
-		}
-
-		// Convert things into internal flags and prep our state:
+		let funding_satoshis = msg.funding_satoshis.0;
+		let push_msat = msg.push_msat.0;
+		let dust_limit_satoshis = msg.dust_limit_satoshis.0;
+		let channel_reserve_satoshis = msg.channel_reserve_satoshis.0;
 
 		let their_announce = if (msg.channel_flags & 1) == 1 { true } else { false };
 		if config.peer_channel_config_limits.force_announced_channel_preference {
-			if local_config.announced_channel != their_announce {
+			if config.channel_options.announced_channel != their_announce {
 				return Err(ChannelError::Close("Peer tried to open channel but their announcement preference is different from ours"));
 			}
 		}
-		// we either accept their preference or the preferences match
-		local_config.announced_channel = their_announce;
 
 		let background_feerate = fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Background);
 
 		let our_dust_limit_satoshis = Channel::derive_our_dust_limit_satoshis(background_feerate);
-		let our_channel_reserve_satoshis = Channel::get_our_channel_reserve_satoshis(msg.funding_satoshis);
+		let our_channel_reserve_satoshis = Channel::get_our_channel_reserve_satoshis(funding_satoshis);
 		if our_channel_reserve_satoshis < our_dust_limit_satoshis {
 			return Err(ChannelError::Close("Suitable channel reserve not found. aborting"));
 		}
-		if msg.channel_reserve_satoshis < our_dust_limit_satoshis {
+		if channel_reserve_satoshis < our_dust_limit_satoshis {
 			return Err(ChannelError::Close("channel_reserve_satoshis too small"));
 		}
-		if our_channel_reserve_satoshis < msg.dust_limit_satoshis {
+		if our_channel_reserve_satoshis < dust_limit_satoshis {
 			return Err(ChannelError::Close("Dust limit too high for our channel reserve"));
 		}
 
 		// check if the funder's amount for the initial commitment tx is sufficient
 		// for full fee payment
-		let funders_amount_msat = msg.funding_satoshis * 1000 - msg.push_msat;
+		let funders_amount_msat = funding_satoshis * 1000 - push_msat;
 		if funders_amount_msat < background_feerate * COMMITMENT_TX_BASE_WEIGHT {
 			return Err(ChannelError::Close("Insufficient funding amount for initial commitment"));
 		}
 
-		let to_local_msat = msg.push_msat;
+		let to_local_msat = push_msat;
 		let to_remote_msat = funders_amount_msat - background_feerate * COMMITMENT_TX_BASE_WEIGHT;
-		if to_local_msat <= msg.channel_reserve_satoshis * 1000 && to_remote_msat <= our_channel_reserve_satoshis * 1000 {
+		if to_local_msat <= channel_reserve_satoshis * 1000 && to_remote_msat <= our_channel_reserve_satoshis * 1000 {
 			return Err(ChannelError::Close("Insufficient funding amount for initial commitment"));
 		}
 
-		let secp_ctx = Secp256k1::new();
-		let mut channel_monitor = ChannelMonitor::new(&chan_keys.revocation_base_key, &chan_keys.delayed_payment_base_key,
-		                                              &chan_keys.htlc_base_key, &chan_keys.payment_base_key, &keys_provider.get_shutdown_pubkey(), config.own_channel_config.our_to_self_delay,
-		                                              keys_provider.get_destination_script(), logger.clone());
-		channel_monitor.set_their_base_keys(&msg.htlc_basepoint, &msg.delayed_payment_basepoint);
-		channel_monitor.set_their_to_self_delay(msg.to_self_delay);
+		Ok(())
+	}
+
+	/// Creates a new channel from a remote sides' request for one.
+	/// Assumes chain_hash has already been checked and corresponds with what we expect!
+	pub fn new_from_req(fee_estimator: &FeeEstimator, keys_provider: &Arc<KeysInterface>, their_node_id: PublicKey, their_local_features: LocalFeatures, msg: &msgs::OpenChannel, user_id: u64, logger: Arc<Logger>, config: &UserConfig) -> Result<Channel, ChannelError> {
+		Channel::check_open_channel(fee_estimator, msg, config)?;
+
+		// Beyond the sanity checks we track amounts as plain u64s, as the rest of Channel does
+		let funding_satoshis = msg.funding_satoshis.0;
+		let push_msat = msg.push_msat.0;
+		let dust_limit_satoshis = msg.dust_limit_satoshis.0;
+		let max_htlc_value_in_flight_msat = msg.max_htlc_value_in_flight_msat.0;
+		let channel_reserve_satoshis = msg.channel_reserve_satoshis.0;
+		let htlc_minimum_msat = msg.htlc_minimum_msat.0;
+
+		// Convert things into internal flags and prep our state:
+
+		let mut local_config = (*config).channel_options.clone();
+		// we either accept their preference or the preferences match
+		local_config.announced_channel = (msg.channel_flags & 1) == 1;
+
+		let background_feerate = fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Background);
+		let our_dust_limit_satoshis = Channel::derive_our_dust_limit_satoshis(background_feerate);
 
 		let their_shutdown_scriptpubkey = if their_local_features.supports_upfront_shutdown_script() {
 			match &msg.shutdown_scriptpubkey {
@@ -661,6 +658,14 @@ impl Channel {
 			}
 		} else { None };
 
+		let chan_keys = keys_provider.get_channel_keys(true);
+		let secp_ctx = Secp256k1::new();
+		let mut channel_monitor = ChannelMonitor::new(&chan_keys.revocation_base_key, &chan_keys.delayed_payment_base_key,
+		                                              &chan_keys.htlc_base_key, &chan_keys.payment_base_key, &keys_provider.get_shutdown_pubkey(), config.own_channel_config.our_to_self_delay,
+		                                              keys_provider.get_destination_script(), logger.clone());
+		channel_monitor.set_their_base_keys(&msg.htlc_basepoint, &msg.delayed_payment_basepoint);
+		channel_monitor.set_their_to_self_delay(msg.to_self_delay);
+
 		let mut chan = Channel {
 			user_id: user_id,
 			config: local_config,
@@ -674,7 +679,7 @@ impl Channel {
 			shutdown_pubkey: keys_provider.get_shutdown_pubkey(),
 			cur_local_commitment_transaction_number: INITIAL_COMMITMENT_NUMBER,
 			cur_remote_commitment_transaction_number: INITIAL_COMMITMENT_NUMBER,
-			value_to_self_msat: msg.push_msat,
+			value_to_self_msat: push_msat,
 
 			pending_inbound_htlcs: Vec::new(),
 			pending_outbound_htlcs: Vec::new(),
@@ -694,9 +699,9 @@ impl Channel {
 			monitor_pending_failures: Vec::new(),
 
 			#[cfg(debug_assertions)]
-			max_commitment_tx_output_local: ::std::sync::Mutex::new((msg.push_msat, msg.funding_satoshis * 1000 - msg.push_msat)),
+			max_commitment_tx_output_local: ::std::sync::Mutex::new((push_msat, funding_satoshis * 1000 - push_msat)),
 			#[cfg(debug_assertions)]
-			max_commitment_tx_output_remote: ::std::sync::Mutex::new((msg.push_msat, msg.funding_satoshis * 1000 - msg.push_msat)),
+			max_commitment_tx_output_remote: ::std::sync::Mutex::new((push_msat, funding_satoshis * 1000 - push_msat)),
 
 			last_local_commitment_txn: Vec::new(),
 
@@ -708,12 +713,12 @@ impl Channel {
 			funding_tx_confirmations: 0,
 
 			feerate_per_kw: msg.feerate_per_kw as u64,
-			channel_value_satoshis: msg.funding_satoshis,
-			their_dust_limit_satoshis: msg.dust_limit_satoshis,
+			channel_value_satoshis: funding_satoshis,
+			their_dust_limit_satoshis: dust_limit_satoshis,
 			our_dust_limit_satoshis: our_dust_limit_satoshis,
-			their_max_htlc_value_in_flight_msat: cmp::min(msg.max_htlc_value_in_flight_msat, msg.funding_satoshis * 1000),
-			their_channel_reserve_satoshis: msg.channel_reserve_satoshis,
-			their_htlc_minimum_msat: msg.htlc_minimum_msat,
+			their_max_htlc_value_in_flight_msat: cmp::min(max_htlc_value_in_flight_msat, funding_satoshis * 1000),
+			their_channel_reserve_satoshis: channel_reserve_satoshis,
+			their_htlc_minimum_msat: htlc_minimum_msat,
 			our_htlc_minimum_msat: Channel::derive_our_htlc_minimum_msat(msg.feerate_per_kw as u64),
 			their_to_self_delay: msg.to_self_delay,
 			our_to_self_delay: config.own_channel_config.our_to_self_delay,
@@ -732,6 +737,8 @@ impl Channel {
 
 			their_shutdown_scriptpubkey,
 
+			counterparty_forwarding_info: None,
+
 			channel_monitor: channel_monitor,
 
 			logger,
@@ -753,24 +760,8 @@ impl Channel {
 	// Utilities to build transactions:
 
 	fn get_commitment_transaction_number_obscure_factor(&self) -> u64 {
-		let mut sha = Sha256::engine();
 		let our_payment_basepoint = PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.payment_base_key);
-
-		if self.channel_outbound {
-			sha.input(&our_payment_basepoint.serialize());
-			sha.input(&self.their_payment_basepoint.unwrap().serialize());
-		} else {
-			sha.input(&self.their_payment_basepoint.unwrap().serialize());
-			sha.input(&our_payment_basepoint.serialize());
-		}
-		let res = Sha256::from_engine(sha).into_inner();
-
-		((res[26] as u64) << 5*8) |
-		((res[27] as u64) << 4*8) |
-		((res[28] as u64) << 3*8) |
-		((res[29] as u64) << 2*8) |
-		((res[30] as u64) << 1*8) |
-		((res[31] as u64) << 0*8)
+		chan_utils::get_commitment_transaction_number_obscure_factor(&our_payment_basepoint, &self.their_payment_basepoint.unwrap(), self.channel_outbound)
 	}
 
 	/// Transaction nomenclature is somewhat confusing here as there are many different cases - a
@@ -792,14 +783,14 @@ impl Channel {
 	/// sources are provided only for outbound HTLCs in the third return value.
 	#[inline]
 	fn build_commitment_transaction(&self, commitment_number: u64, keys: &TxCreationKeys, local: bool, generated_by_local: bool, feerate_per_kw: u64) -> (Transaction, usize, Vec<(HTLCOutputInCommitment, Option<&HTLCSource>)>) {
-		let obscured_commitment_transaction_number = self.get_commitment_transaction_number_obscure_factor() ^ (INITIAL_COMMITMENT_NUMBER - commitment_number);
+		let (sequence, lock_time) = chan_utils::encode_commitment_transaction_number(INITIAL_COMMITMENT_NUMBER - commitment_number, self.get_commitment_transaction_number_obscure_factor());
 
 		let txins = {
 			let mut ins: Vec<TxIn> = Vec::new();
 			ins.push(TxIn {
 				previous_output: self.channel_monitor.get_funding_txo().unwrap().into_bitcoin_outpoint(),
 				script_sig: Script::new(),
-				sequence: ((0x80 as u32) << 8*3) | ((obscured_commitment_transaction_number >> 3*8) as u32),
+				sequence,
 				witness: Vec::new(),
 			});
 			ins
@@ -831,7 +822,7 @@ impl Channel {
 			($htlc: expr, $outbound: expr, $source: expr, $state_name: expr) => {
 				if $outbound == local { // "offered HTLC output"
 					let htlc_in_tx = get_htlc_in_commitment!($htlc, true);
-					if $htlc.amount_msat / 1000 >= dust_limit_satoshis + (feerate_per_kw * HTLC_TIMEOUT_TX_WEIGHT / 1000) {
+					if !chan_utils::htlc_is_dust($htlc.amount_msat, feerate_per_kw, dust_limit_satoshis, true) {
 						log_trace!(self, "   ...including {} {} HTLC {} (hash {}) with value {}", if $outbound { "outbound" } else { "inbound" }, $state_name, $htlc.htlc_id, log_bytes!($htlc.payment_hash.0), $htlc.amount_msat);
 						txouts.push((TxOut {
 							script_pubkey: chan_utils::get_htlc_redeemscript(&htlc_in_tx, &keys).to_v0_p2wsh(),
@@ -843,7 +834,7 @@ impl Channel {
 					}
 				} else {
 					let htlc_in_tx = get_htlc_in_commitment!($htlc, false);
-					if $htlc.amount_msat / 1000 >= dust_limit_satoshis + (feerate_per_kw * HTLC_SUCCESS_TX_WEIGHT / 1000) {
+					if !chan_utils::htlc_is_dust($htlc.amount_msat, feerate_per_kw, dust_limit_satoshis, false) {
 						log_trace!(self, "   ...including {} {} HTLC {} (hash {}) with value {}", if $outbound { "outbound" } else { "inbound" }, $state_name, $htlc.htlc_id, log_bytes!($htlc.payment_hash.0), $htlc.amount_msat);
 						txouts.push((TxOut { // "received HTLC output"
 							script_pubkey: chan_utils::get_htlc_redeemscript(&htlc_in_tx, &keys).to_v0_p2wsh(),
@@ -936,7 +927,7 @@ impl Channel {
 			max_commitment_tx_output.1 = cmp::max(max_commitment_tx_output.1, value_to_remote_msat as u64);
 		}
 
-		let total_fee: u64 = feerate_per_kw * (COMMITMENT_TX_BASE_WEIGHT + (txouts.len() as u64) * COMMITMENT_TX_WEIGHT_PER_HTLC) / 1000;
+		let total_fee: u64 = chan_utils::commit_tx_fee_msat(feerate_per_kw, txouts.len()) / 1000;
 		let (value_to_self, value_to_remote) = if self.channel_outbound {
 			(value_to_self_msat / 1000 - total_fee as i64, value_to_remote_msat / 1000)
 		} else {
@@ -994,7 +985,7 @@ impl Channel {
 
 		(Transaction {
 			version: 2,
-			lock_time: ((0x20 as u32) << 8*3) | ((obscured_commitment_transaction_number & 0xffffffu64) as u32),
+			lock_time,
 			input: txins,
 			output: outputs,
 		}, non_dust_htlc_count, htlcs_included)
@@ -1101,16 +1092,17 @@ impl Channel {
 	/// pays to get_funding_redeemscript().to_v0_p2wsh()).
 	/// Panics if called before accept_channel/new_from_req
 	pub fn get_funding_redeemscript(&self) -> Script {
-		let builder = Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_2);
-		let our_funding_key = PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.funding_key).serialize();
-		let their_funding_key = self.their_funding_pubkey.expect("get_funding_redeemscript only allowed after accept_channel").serialize();
-		if our_funding_key[..] < their_funding_key[..] {
-			builder.push_slice(&our_funding_key)
-				.push_slice(&their_funding_key)
-		} else {
-			builder.push_slice(&their_funding_key)
-				.push_slice(&our_funding_key)
-		}.push_opcode(opcodes::all::OP_PUSHNUM_2).push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script()
+		let our_funding_key = PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.funding_key);
+		let their_funding_key = self.their_funding_pubkey.expect("get_funding_redeemscript only allowed after accept_channel");
+		chan_utils::make_funding_redeemscript(&our_funding_key, &their_funding_key)
+	}
+
+	/// Gets the P2WSH scriptPubKey the funding transaction pays to.
+	/// Panics if called before accept_channel/new_from_req
+	fn get_funding_output_script(&self) -> Script {
+		let our_funding_key = PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.funding_key);
+		let their_funding_key = self.their_funding_pubkey.expect("get_funding_output_script only allowed after accept_channel");
+		chan_utils::make_funding_output_script(&our_funding_key, &their_funding_key)
 	}
 
 	fn sign_commitment_transaction(&self, tx: &mut Transaction, their_sig: &Signature) -> Signature {
@@ -1123,7 +1115,7 @@ impl Channel {
 
 		let funding_redeemscript = self.get_funding_redeemscript();
 
-		let sighash = hash_to_message!(&bip143::SighashComponents::new(&tx).sighash_all(&tx.input[0], &funding_redeemscript, self.channel_value_satoshis)[..]);
+		let sighash = hash_to_message!(&chan_utils::sighash_commitment(&tx, 0, &funding_redeemscript, self.channel_value_satoshis)[..]);
 		let our_sig = self.secp_ctx.sign(&sighash, &self.local_keys.funding_key);
 
 		tx.input[0].witness.push(Vec::new()); // First is the multisig dummy
@@ -1160,7 +1152,7 @@ impl Channel {
 		let htlc_redeemscript = chan_utils::get_htlc_redeemscript(&htlc, &keys);
 
 		let our_htlc_key = secp_check!(chan_utils::derive_private_key(&self.secp_ctx, &keys.per_commitment_point, &self.local_keys.htlc_base_key), "Derived invalid key, peer is maliciously selecting parameters");
-		let sighash = hash_to_message!(&bip143::SighashComponents::new(&tx).sighash_all(&tx.input[0], &htlc_redeemscript, htlc.amount_msat / 1000)[..]);
+		let sighash = hash_to_message!(&chan_utils::get_htlc_transaction_sighash(&tx, &htlc_redeemscript, &htlc)[..]);
 		let is_local_tx = PublicKey::from_secret_key(&self.secp_ctx, &our_htlc_key) == keys.a_htlc_key;
 		Ok((htlc_redeemscript, self.secp_ctx.sign(&sighash, &our_htlc_key), is_local_tx))
 	}
@@ -1213,7 +1205,7 @@ impl Channel {
 		}
 		assert_eq!(self.channel_state & ChannelState::ShutdownComplete as u32, 0);
 
-		let payment_hash_calc = PaymentHash(Sha256::hash(&payment_preimage_arg.0[..]).into_inner());
+		let payment_hash_calc = channelmanager::payment_hash(&payment_preimage_arg);
 
 		// ChannelManager may generate duplicate claims/fails due to HTLC update events from
 		// on-chain ChannelsMonitors during block rescan. Ideally we'd figure out a way to drop
@@ -1378,6 +1370,11 @@ impl Channel {
 	// Message handlers:
 
 	pub fn accept_channel(&mut self, msg: &msgs::AcceptChannel, config: &UserConfig, their_local_features: LocalFeatures) -> Result<(), ChannelError> {
+		let dust_limit_satoshis = msg.dust_limit_satoshis.0;
+		let max_htlc_value_in_flight_msat = msg.max_htlc_value_in_flight_msat.0;
+		let channel_reserve_satoshis = msg.channel_reserve_satoshis.0;
+		let htlc_minimum_msat = msg.htlc_minimum_msat.0;
+
 		// Check sanity of message fields:
 		if !self.channel_outbound {
 			return Err(ChannelError::Close("Got an accept_channel message from an inbound peer"));
@@ -1385,22 +1382,22 @@ impl Channel {
 		if self.channel_state != ChannelState::OurInitSent as u32 {
 			return Err(ChannelError::Close("Got an accept_channel message at a strange time"));
 		}
-		if msg.dust_limit_satoshis > 21000000 * 100000000 {
+		if dust_limit_satoshis > 21000000 * 100000000 {
 			return Err(ChannelError::Close("Peer never wants payout outputs?"));
 		}
-		if msg.channel_reserve_satoshis > self.channel_value_satoshis {
+		if channel_reserve_satoshis > self.channel_value_satoshis {
 			return Err(ChannelError::Close("Bogus channel_reserve_satoshis"));
 		}
-		if msg.dust_limit_satoshis > msg.channel_reserve_satoshis {
+		if dust_limit_satoshis > channel_reserve_satoshis {
 			return Err(ChannelError::Close("Bogus channel_reserve and dust_limit"));
 		}
-		if msg.channel_reserve_satoshis < self.our_dust_limit_satoshis {
+		if channel_reserve_satoshis < self.our_dust_limit_satoshis {
 			return Err(ChannelError::Close("Peer never wants payout outputs?"));
 		}
-		if msg.dust_limit_satoshis > Channel::get_our_channel_reserve_satoshis(self.channel_value_satoshis) {
+		if dust_limit_satoshis > Channel::get_our_channel_reserve_satoshis(self.channel_value_satoshis) {
 			return Err(ChannelError::Close("Dust limit is bigger than our channel reverse"));
 		}
-		if msg.htlc_minimum_msat >= (self.channel_value_satoshis - msg.channel_reserve_satoshis) * 1000 {
+		if htlc_minimum_msat >= (self.channel_value_satoshis - channel_reserve_satoshis) * 1000 {
 			return Err(ChannelError::Close("Minimum htlc value is full channel value"));
 		}
 		if msg.to_self_delay > config.peer_channel_config_limits.their_to_self_delay || msg.to_self_delay > MAX_LOCAL_BREAKDOWN_TIMEOUT {
@@ -1414,22 +1411,22 @@ impl Channel {
 		}
 
 		// Now check against optional parameters as set by config...
-		if msg.htlc_minimum_msat > config.peer_channel_config_limits.max_htlc_minimum_msat {
+		if htlc_minimum_msat > config.peer_channel_config_limits.max_htlc_minimum_msat {
 			return Err(ChannelError::Close("htlc minimum msat is higher than the user specified limit"));
 		}
-		if msg.max_htlc_value_in_flight_msat < config.peer_channel_config_limits.min_max_htlc_value_in_flight_msat {
+		if max_htlc_value_in_flight_msat < config.peer_channel_config_limits.min_max_htlc_value_in_flight_msat {
 			return Err(ChannelError::Close("max htlc value in flight msat is less than the user specified limit"));
 		}
-		if msg.channel_reserve_satoshis > config.peer_channel_config_limits.max_channel_reserve_satoshis {
+		if channel_reserve_satoshis > config.peer_channel_config_limits.max_channel_reserve_satoshis {
 			return Err(ChannelError::Close("channel reserve satoshis is higher than the user specified limit"));
 		}
 		if msg.max_accepted_htlcs < config.peer_channel_config_limits.min_max_accepted_htlcs {
 			return Err(ChannelError::Close("max accepted htlcs is less than the user specified limit"));
 		}
-		if msg.dust_limit_satoshis < config.peer_channel_config_limits.min_dust_limit_satoshis {
+		if dust_limit_satoshis < config.peer_channel_config_limits.min_dust_limit_satoshis {
 			return Err(ChannelError::Close("dust limit satoshis is less than the user specified limit"));
 		}
-		if msg.dust_limit_satoshis > config.peer_channel_config_limits.max_dust_limit_satoshis {
+		if dust_limit_satoshis > config.peer_channel_config_limits.max_dust_limit_satoshis {
 			return Err(ChannelError::Close("dust limit satoshis is greater than the user specified limit"));
 		}
 		if msg.minimum_depth > config.peer_channel_config_limits.max_minimum_depth {
@@ -1459,10 +1456,10 @@ impl Channel {
 
 		self.channel_monitor.set_their_base_keys(&msg.htlc_basepoint, &msg.delayed_payment_basepoint);
 
-		self.their_dust_limit_satoshis = msg.dust_limit_satoshis;
-		self.their_max_htlc_value_in_flight_msat = cmp::min(msg.max_htlc_value_in_flight_msat, self.channel_value_satoshis * 1000);
-		self.their_channel_reserve_satoshis = msg.channel_reserve_satoshis;
-		self.their_htlc_minimum_msat = msg.htlc_minimum_msat;
+		self.their_dust_limit_satoshis = dust_limit_satoshis;
+		self.their_max_htlc_value_in_flight_msat = cmp::min(max_htlc_value_in_flight_msat, self.channel_value_satoshis * 1000);
+		self.their_channel_reserve_satoshis = channel_reserve_satoshis;
+		self.their_htlc_minimum_msat = htlc_minimum_msat;
 		self.their_to_self_delay = msg.to_self_delay;
 		self.their_max_accepted_htlcs = msg.max_accepted_htlcs;
 		self.minimum_depth = msg.minimum_depth;
@@ -1488,7 +1485,7 @@ impl Channel {
 
 		let local_keys = self.build_local_transaction_keys(self.cur_local_commitment_transaction_number)?;
 		let mut local_initial_commitment_tx = self.build_commitment_transaction(self.cur_local_commitment_transaction_number, &local_keys, true, false, self.feerate_per_kw).0;
-		let local_sighash = hash_to_message!(&bip143::SighashComponents::new(&local_initial_commitment_tx).sighash_all(&local_initial_commitment_tx.input[0], &funding_script, self.channel_value_satoshis)[..]);
+		let local_sighash = hash_to_message!(&chan_utils::sighash_commitment(&local_initial_commitment_tx, 0, &funding_script, self.channel_value_satoshis)[..]);
 
 		// They sign the "local" commitment transaction...
 		secp_check!(self.secp_ctx.verify(&local_sighash, &sig, &self.their_funding_pubkey.unwrap()), "Invalid funding_created signature from peer");
@@ -1498,7 +1495,7 @@ impl Channel {
 
 		let remote_keys = self.build_remote_transaction_keys()?;
 		let remote_initial_commitment_tx = self.build_commitment_transaction(self.cur_remote_commitment_transaction_number, &remote_keys, false, false, self.feerate_per_kw).0;
-		let remote_sighash = hash_to_message!(&bip143::SighashComponents::new(&remote_initial_commitment_tx).sighash_all(&remote_initial_commitment_tx.input[0], &funding_script, self.channel_value_satoshis)[..]);
+		let remote_sighash = hash_to_message!(&chan_utils::sighash_commitment(&remote_initial_commitment_tx, 0, &funding_script, self.channel_value_satoshis)[..]);
 
 		// We sign the "remote" commitment transaction, allowing them to broadcast the tx if they wish.
 		Ok((remote_initial_commitment_tx, local_initial_commitment_tx, self.secp_ctx.sign(&remote_sighash, &self.local_keys.funding_key), local_keys))
@@ -1521,7 +1518,7 @@ impl Channel {
 		}
 
 		let funding_txo = OutPoint::new(msg.funding_txid, msg.funding_output_index);
-		let funding_txo_script = self.get_funding_redeemscript().to_v0_p2wsh();
+		let funding_txo_script = self.get_funding_output_script();
 		self.channel_monitor.set_funding_info((funding_txo, funding_txo_script));
 
 		let (remote_initial_commitment_tx, local_initial_commitment_tx, our_signature, local_keys) = match self.funding_created_signature(&msg.signature) {
@@ -1567,7 +1564,7 @@ impl Channel {
 
 		let local_keys = self.build_local_transaction_keys(self.cur_local_commitment_transaction_number)?;
 		let mut local_initial_commitment_tx = self.build_commitment_transaction(self.cur_local_commitment_transaction_number, &local_keys, true, false, self.feerate_per_kw).0;
-		let local_sighash = hash_to_message!(&bip143::SighashComponents::new(&local_initial_commitment_tx).sighash_all(&local_initial_commitment_tx.input[0], &funding_script, self.channel_value_satoshis)[..]);
+		let local_sighash = hash_to_message!(&chan_utils::sighash_commitment(&local_initial_commitment_tx, 0, &funding_script, self.channel_value_satoshis)[..]);
 
 		// They sign the "local" commitment transaction, allowing us to broadcast the tx if we wish.
 		secp_check!(self.secp_ctx.verify(&local_sighash, &msg.signature, &self.their_funding_pubkey.unwrap()), "Invalid funding_signed signature from peer");
@@ -1646,14 +1643,191 @@ impl Channel {
 		(htlc_outbound_count as u32, htlc_outbound_value_msat)
 	}
 
+	/// Returns true if an HTLC of the given amount, offered by us if outbound, would be dust on
+	/// either our or our counterparty's commitment transaction.
+	fn htlc_is_dust_on_either_commitment(&self, amount_msat: u64, outbound: bool) -> bool {
+		chan_utils::htlc_is_dust(amount_msat, self.feerate_per_kw, self.our_dust_limit_satoshis, outbound) ||
+			chan_utils::htlc_is_dust(amount_msat, self.feerate_per_kw, self.their_dust_limit_satoshis, !outbound)
+	}
+
+	/// Gets the total value (in msat) of pending HTLCs, including adds in our holding cell, which
+	/// are dust on either commitment transaction and would thus be lost to fees on force-close.
+	pub fn get_dust_htlc_exposure_msat(&self) -> u64 {
+		let mut res = 0;
+		for htlc in self.pending_inbound_htlcs.iter() {
+			if self.htlc_is_dust_on_either_commitment(htlc.amount_msat, false) {
+				res += htlc.amount_msat;
+			}
+		}
+		for htlc in self.pending_outbound_htlcs.iter() {
+			if self.htlc_is_dust_on_either_commitment(htlc.amount_msat, true) {
+				res += htlc.amount_msat;
+			}
+		}
+		for update in self.holding_cell_htlc_updates.iter() {
+			if let &HTLCUpdateAwaitingACK::AddHTLC { amount_msat, .. } = update {
+				if self.htlc_is_dust_on_either_commitment(amount_msat, true) {
+					res += amount_msat;
+				}
+			}
+		}
+		res
+	}
+
+	/// Returns true if adding an HTLC of the given amount, offered by us if outbound, would put our
+	/// dust HTLC exposure over the configured max_dust_htlc_exposure_msat.
+	pub fn would_exceed_dust_htlc_exposure(&self, amount_msat: u64, outbound: bool) -> bool {
+		self.htlc_is_dust_on_either_commitment(amount_msat, outbound) &&
+			self.get_dust_htlc_exposure_msat() + amount_msat > self.config.max_dust_htlc_exposure_msat
+	}
+
+	/// Gets the amount and cltv_expiry of the inbound HTLC with the given id, if it is still pending.
+	pub fn get_inbound_htlc_amount_and_expiry(&self, htlc_id: u64) -> Option<(u64, u32)> {
+		self.pending_inbound_htlcs.iter().find(|htlc| htlc.htlc_id == htlc_id).map(|htlc| (htlc.amount_msat, htlc.cltv_expiry))
+	}
+
+	/// Gets the outbound HTLCs which are still pending (including adds in our holding cell) and
+	/// whose cltv_expiry is within the configured htlc_expiry_buffer_blocks of height, ie which
+	/// need to be failed back or claimed on-chain before they time out.
+	///
+	/// Inbound HTLCs aren't included, see get_claimable_inbound_htlcs_expiring_before for those
+	/// we have to claim in time.
+	pub fn get_outbound_htlcs_expiring_before(&self, height: u32) -> Vec<(PaymentHash, HTLCSource)> {
+		let threshold = height.saturating_add(self.config.htlc_expiry_buffer_blocks);
+		let mut res = Vec::new();
+		for htlc in self.pending_outbound_htlcs.iter() {
+			match htlc.state {
+				OutboundHTLCState::LocalAnnounced(_) | OutboundHTLCState::Committed => {
+					if htlc.cltv_expiry <= threshold {
+						res.push((htlc.payment_hash, htlc.source.clone()));
+					}
+				},
+				// Already claimed or failed by our counterparty
+				OutboundHTLCState::RemoteRemoved(_) | OutboundHTLCState::AwaitingRemoteRevokeToRemove(_) | OutboundHTLCState::AwaitingRemovedRemoteRevoke(_) => {},
+			}
+		}
+		for update in self.holding_cell_htlc_updates.iter() {
+			if let &HTLCUpdateAwaitingACK::AddHTLC { ref cltv_expiry, ref payment_hash, ref source, .. } = update {
+				if *cltv_expiry <= threshold {
+					res.push((*payment_hash, source.clone()));
+				}
+			}
+		}
+		res
+	}
+
+	/// Gets the inbound HTLCs we hold the preimage for but whose removal our counterparty hasn't
+	/// irrevocably committed to yet (including claims in our holding cell), and whose cltv_expiry is
+	/// within the configured htlc_expiry_buffer_blocks of height. Unless the claim completes
+	/// off-chain first, these have to be claimed on-chain before they time out or we lose them.
+	pub fn get_claimable_inbound_htlcs_expiring_before(&self, height: u32) -> Vec<(PaymentHash, PaymentPreimage)> {
+		let threshold = height.saturating_add(self.config.htlc_expiry_buffer_blocks);
+		let mut res = Vec::new();
+		for htlc in self.pending_inbound_htlcs.iter() {
+			if htlc.cltv_expiry > threshold {
+				continue;
+			}
+			if let InboundHTLCState::LocalRemoved(InboundHTLCRemovalReason::Fulfill(ref payment_preimage)) = htlc.state {
+				res.push((htlc.payment_hash, *payment_preimage));
+			}
+			for update in self.holding_cell_htlc_updates.iter() {
+				if let &HTLCUpdateAwaitingACK::ClaimHTLC { ref payment_preimage, htlc_id } = update {
+					if htlc_id == htlc.htlc_id {
+						res.push((htlc.payment_hash, *payment_preimage));
+					}
+				}
+			}
+		}
+		res
+	}
+
 	/// Get the available (ie not including pending HTLCs) inbound and outbound balance in msat.
 	/// Doesn't bother handling the
 	/// if-we-removed-it-already-but-haven't-fully-resolved-they-can-still-send-an-inbound-HTLC
 	/// corner case properly.
 	pub fn get_inbound_outbound_available_balance_msat(&self) -> (u64, u64) {
 		// Note that we have to handle overflow due to the above case.
-		(cmp::min(self.channel_value_satoshis as i64 * 1000 - self.value_to_self_msat as i64 - self.get_inbound_pending_htlc_stats().1 as i64, 0) as u64,
-		cmp::min(self.value_to_self_msat as i64 - self.get_outbound_pending_htlc_stats().1 as i64, 0) as u64)
+		(cmp::max(self.channel_value_satoshis as i64 * 1000 - self.value_to_self_msat as i64 - self.get_inbound_pending_htlc_stats().1 as i64, 0) as u64,
+		cmp::max(self.value_to_self_msat as i64 - self.get_outbound_pending_htlc_stats().1 as i64, 0) as u64)
+	}
+
+	/// Gets the total value (in msat) of HTLCs which are not yet fully resolved, as
+	/// (inbound, outbound). Outbound HTLCs waiting in our holding cell are included.
+	pub fn pending_htlc_value_msat(&self) -> (u64, u64) {
+		(self.get_inbound_pending_htlc_stats().1, self.get_outbound_pending_htlc_stats().1)
+	}
+
+	/// Gets the commitment transaction fee (in msat) the funder must be able to pay once one more
+	/// HTLC is added. We don't bother working out which pending HTLCs are trimmed, so this counts
+	/// every pending HTLC (including adds in our holding cell) as an output and thus may overshoot.
+	fn next_commit_tx_fee_msat(&self) -> u64 {
+		let (inbound_htlc_count, _) = self.get_inbound_pending_htlc_stats();
+		let (outbound_htlc_count, _) = self.get_outbound_pending_htlc_stats();
+		chan_utils::commit_tx_fee_msat(self.feerate_per_kw, (inbound_htlc_count + outbound_htlc_count) as usize + 1)
+	}
+
+	/// Gets the value (in msat) of outbound HTLCs which our counterparty has removed but which
+	/// will not be present in the next commitment transaction we send them. Fulfilled ones have
+	/// already been taken out of value_to_self_msat but won't be in our counterparty's balance.
+	fn get_removed_outbound_htlc_value_msat(&self) -> u64 {
+		let mut removed_outbound_total_msat = 0;
+		for ref htlc in self.pending_outbound_htlcs.iter() {
+			if let OutboundHTLCState::AwaitingRemoteRevokeToRemove(None) = htlc.state {
+				removed_outbound_total_msat += htlc.amount_msat;
+			} else if let OutboundHTLCState::AwaitingRemovedRemoteRevoke(None) = htlc.state {
+				removed_outbound_total_msat += htlc.amount_msat;
+			}
+		}
+		removed_outbound_total_msat
+	}
+
+	/// Returns true if our counterparty already has as many HTLCs from us pending (including adds
+	/// in our holding cell) as they will accept, so no more can be sent until some resolve.
+	pub fn at_their_max_accepted_htlcs(&self) -> bool {
+		self.get_outbound_pending_htlc_stats().0 + 1 > self.their_max_accepted_htlcs as u32
+	}
+
+	/// Caps capacity_msat by the room left under max_dust_htlc_exposure_msat if an HTLC of that
+	/// size would be dust, as then every HTLC we could add is dust and counts towards the exposure.
+	fn cap_dust_capacity_msat(&self, capacity_msat: u64, outbound: bool) -> u64 {
+		if self.htlc_is_dust_on_either_commitment(capacity_msat, outbound) {
+			cmp::min(capacity_msat, self.config.max_dust_htlc_exposure_msat.saturating_sub(self.get_dust_htlc_exposure_msat()))
+		} else {
+			capacity_msat
+		}
+	}
+
+	/// Gets the largest HTLC value (in msat) we could currently add to this channel, taking into
+	/// account our pending outbound HTLCs, their max_accepted_htlcs, their channel reserve, their
+	/// max_htlc_value_in_flight_msat, our max dust HTLC exposure and, if we're the funder, the
+	/// commitment transaction fee. This is exactly what send_htlc checks against. Does not check
+	/// whether the channel is live.
+	pub fn outbound_capacity_msat(&self) -> u64 {
+		if self.at_their_max_accepted_htlcs() {
+			return 0;
+		}
+		let htlc_outbound_value_msat = self.get_outbound_pending_htlc_stats().1;
+		let commit_fee_msat = if self.channel_outbound { self.next_commit_tx_fee_msat() } else { 0 };
+		let reserve_limit = self.value_to_self_msat.saturating_sub(self.their_channel_reserve_satoshis * 1000 + htlc_outbound_value_msat + commit_fee_msat);
+		let in_flight_limit = self.their_max_htlc_value_in_flight_msat.saturating_sub(htlc_outbound_value_msat);
+		self.cap_dust_capacity_msat(cmp::min(reserve_limit, in_flight_limit), true)
+	}
+
+	/// Gets the largest HTLC value (in msat) our counterparty could currently add to this channel,
+	/// taking into account their pending HTLCs to us, our max accepted HTLCs, our channel reserve,
+	/// our max_htlc_value_in_flight_msat, our max dust HTLC exposure and, if they're the funder,
+	/// the commitment transaction fee. Any HTLC up to this value will pass update_add_htlc's
+	/// checks and won't be failed back for dust exposure.
+	pub fn inbound_capacity_msat(&self) -> u64 {
+		let (htlc_inbound_count, htlc_inbound_value_msat) = self.get_inbound_pending_htlc_stats();
+		if htlc_inbound_count + 1 > OUR_MAX_HTLCS as u32 {
+			return 0;
+		}
+		let commit_fee_msat = if self.channel_outbound { 0 } else { self.next_commit_tx_fee_msat() };
+		let their_balance_msat = (self.channel_value_satoshis * 1000 + self.get_removed_outbound_htlc_value_msat()).saturating_sub(self.value_to_self_msat);
+		let reserve_limit = their_balance_msat.saturating_sub(Channel::get_our_channel_reserve_satoshis(self.channel_value_satoshis) * 1000 + htlc_inbound_value_msat + commit_fee_msat);
+		let in_flight_limit = Channel::get_our_max_htlc_value_in_flight_msat(self.channel_value_satoshis).saturating_sub(htlc_inbound_value_msat);
+		self.cap_dust_capacity_msat(cmp::min(reserve_limit, in_flight_limit), false)
 	}
 
 	pub fn update_add_htlc(&mut self, msg: &msgs::UpdateAddHTLC, pending_forward_state: PendingHTLCStatus) -> Result<(), ChannelError> {
@@ -1690,14 +1864,7 @@ impl Channel {
 		// violate the reserve value if we do not do this (as we forget inbound HTLCs from the
 		// Channel state once they will not be present in the next received commitment
 		// transaction).
-		let mut removed_outbound_total_msat = 0;
-		for ref htlc in self.pending_outbound_htlcs.iter() {
-			if let OutboundHTLCState::AwaitingRemoteRevokeToRemove(None) = htlc.state {
-				removed_outbound_total_msat += htlc.amount_msat;
-			} else if let OutboundHTLCState::AwaitingRemovedRemoteRevoke(None) = htlc.state {
-				removed_outbound_total_msat += htlc.amount_msat;
-			}
-		}
+		let removed_outbound_total_msat = self.get_removed_outbound_htlc_value_msat();
 		if htlc_inbound_value_msat + msg.amount_msat + self.value_to_self_msat > (self.channel_value_satoshis - Channel::get_our_channel_reserve_satoshis(self.channel_value_satoshis)) * 1000 + removed_outbound_total_msat {
 			return Err(ChannelError::Close("Remote HTLC add would put them over their reserve value"));
 		}
@@ -1763,7 +1930,7 @@ impl Channel {
 			return Err(ChannelError::Close("Peer sent update_fulfill_htlc when we needed a channel_reestablish"));
 		}
 
-		let payment_hash = PaymentHash(Sha256::hash(&msg.payment_preimage.0[..]).into_inner());
+		let payment_hash = channelmanager::payment_hash(&msg.payment_preimage);
 		self.mark_outbound_htlc_removed(msg.htlc_id, Some(payment_hash), None).map(|source| source.clone())
 	}
 
@@ -1820,14 +1987,15 @@ impl Channel {
 			(commitment_tx.0, commitment_tx.1, htlcs_cloned)
 		};
 		let local_commitment_txid = local_commitment_tx.0.txid();
-		let local_sighash = hash_to_message!(&bip143::SighashComponents::new(&local_commitment_tx.0).sighash_all(&local_commitment_tx.0.input[0], &funding_script, self.channel_value_satoshis)[..]);
 		log_trace!(self, "Checking commitment tx signature {} by key {} against tx {} with redeemscript {}", log_bytes!(msg.signature.serialize_compact()[..]), log_bytes!(self.their_funding_pubkey.unwrap().serialize()), encode::serialize_hex(&local_commitment_tx.0), encode::serialize_hex(&funding_script));
-		secp_check!(self.secp_ctx.verify(&local_sighash, &msg.signature, &self.their_funding_pubkey.unwrap()), "Invalid commitment tx signature from peer");
+		if let Err(e) = chan_utils::verify_commitment_sig(&self.secp_ctx, &local_commitment_tx.0, &funding_script, self.channel_value_satoshis, &msg.signature, &self.their_funding_pubkey.unwrap()) {
+			return Err(ChannelError::Close(e.err));
+		}
 
 		//If channel fee was updated by funder confirm funder can afford the new fee rate when applied to the current local commitment transaction
 		if update_fee {
 			let num_htlcs = local_commitment_tx.1;
-			let total_fee: u64 = feerate_per_kw as u64 * (COMMITMENT_TX_BASE_WEIGHT + (num_htlcs as u64) * COMMITMENT_TX_WEIGHT_PER_HTLC) / 1000;
+			let total_fee: u64 = chan_utils::commit_tx_fee_msat(feerate_per_kw as u64, num_htlcs) / 1000;
 
 			if self.channel_value_satoshis - self.value_to_self_msat / 1000 < total_fee + self.their_channel_reserve_satoshis {
 				return Err(ChannelError::Close("Funding remote cannot afford proposed new fee"));
@@ -1848,7 +2016,7 @@ impl Channel {
 				let mut htlc_tx = self.build_htlc_transaction(&local_commitment_txid, &htlc, true, &local_keys, feerate_per_kw);
 				let htlc_redeemscript = chan_utils::get_htlc_redeemscript(&htlc, &local_keys);
 				log_trace!(self, "Checking HTLC tx signature {} by key {} against tx {} with redeemscript {}", log_bytes!(msg.htlc_signatures[idx].serialize_compact()[..]), log_bytes!(local_keys.b_htlc_key.serialize()), encode::serialize_hex(&htlc_tx), encode::serialize_hex(&htlc_redeemscript));
-				let htlc_sighash = hash_to_message!(&bip143::SighashComponents::new(&htlc_tx).sighash_all(&htlc_tx.input[0], &htlc_redeemscript, htlc.amount_msat / 1000)[..]);
+				let htlc_sighash = hash_to_message!(&chan_utils::get_htlc_transaction_sighash(&htlc_tx, &htlc_redeemscript, &htlc)[..]);
 				secp_check!(self.secp_ctx.verify(&htlc_sighash, &msg.htlc_signatures[idx], &local_keys.b_htlc_key), "Invalid HTLC tx signature from peer");
 				let htlc_sig = if htlc.offered {
 					let htlc_sig = self.sign_htlc_transaction(&mut htlc_tx, &msg.htlc_signatures[idx], &None, &htlc, &local_keys)?;
@@ -2066,6 +2234,12 @@ impl Channel {
 		if self.channel_state & BOTH_SIDES_SHUTDOWN_MASK == BOTH_SIDES_SHUTDOWN_MASK && self.last_sent_closing_fee.is_some() {
 			return Err(ChannelError::Close("Peer sent revoke_and_ack after we'd started exchanging closing_signeds"));
 		}
+		if self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32) == 0 {
+			// Applying the revocation would step cur_remote_commitment_transaction_number past the
+			// commitment transaction they actually hold, so leave our state alone and let the
+			// channel_reestablish on reconnect sort out where we are.
+			return Err(ChannelError::Disconnect("Peer sent revoke_and_ack when we had no commitment_signed awaiting revocation"));
+		}
 
 		if let Some(their_prev_commitment_point) = self.their_prev_commitment_point {
 			if PublicKey::from_secret_key(&self.secp_ctx, &secp_check!(SecretKey::from_slice(&msg.per_commitment_secret), "Peer provided an invalid per_commitment_secret")) != their_prev_commitment_point {
@@ -2090,7 +2264,10 @@ impl Channel {
 		let mut update_fail_htlcs = Vec::new();
 		let mut update_fail_malformed_htlcs = Vec::new();
 		let mut require_commitment = false;
-		let mut value_to_self_msat_diff: i64 = 0;
+		// Tracked separately (rather than as a signed diff) so that each can be checked against
+		// overflow, which would otherwise silently corrupt our balance. None indicates overflow.
+		let mut received_msat = Some(MilliSatoshi(0));
+		let mut sent_msat = Some(MilliSatoshi(0));
 
 		{
 			// Take references explicitly so that we can hold multiple references to self.
@@ -2103,7 +2280,7 @@ impl Channel {
 				if let &InboundHTLCState::LocalRemoved(ref reason) = &htlc.state {
 					log_trace!(logger, " ...removing inbound LocalRemoved {}", log_bytes!(htlc.payment_hash.0));
 					if let &InboundHTLCRemovalReason::Fulfill(_) = reason {
-						value_to_self_msat_diff += htlc.amount_msat as i64;
+						received_msat = received_msat.and_then(|total| total.checked_add(MilliSatoshi(htlc.amount_msat)));
 					}
 					false
 				} else { true }
@@ -2115,7 +2292,7 @@ impl Channel {
 						revoked_htlcs.push((htlc.source.clone(), htlc.payment_hash, reason));
 					} else {
 						// They fulfilled, so we sent them money
-						value_to_self_msat_diff -= htlc.amount_msat as i64;
+						sent_msat = sent_msat.and_then(|total| total.checked_add(MilliSatoshi(htlc.amount_msat)));
 					}
 					false
 				} else { true }
@@ -2172,7 +2349,12 @@ impl Channel {
 				}
 			}
 		}
-		self.value_to_self_msat = (self.value_to_self_msat as i64 + value_to_self_msat_diff) as u64;
+		// Adding first means we only fail if we'd really end up with a negative balance
+		self.value_to_self_msat = match received_msat.and_then(|received| MilliSatoshi(self.value_to_self_msat).checked_add(received))
+				.and_then(|balance| sent_msat.and_then(|sent| balance.checked_sub(sent))) {
+			Some(balance) => balance.0,
+			None => return Err(ChannelError::Close("Removing HTLCs over- or underflowed our balance")),
+		};
 
 		if self.channel_outbound {
 			if let Some(feerate) = self.pending_update_fee.take() {
@@ -2604,7 +2786,7 @@ impl Channel {
 				// now!
 				match self.free_holding_cell_htlcs() {
 					Err(ChannelError::Close(msg)) => return Err(ChannelError::Close(msg)),
-					Err(ChannelError::Ignore(_)) | Err(ChannelError::CloseDelayBroadcast { .. }) => panic!("Got non-channel-failing result from free_holding_cell_htlcs"),
+					Err(ChannelError::Ignore(_)) | Err(ChannelError::Disconnect(_)) | Err(ChannelError::CloseDelayBroadcast { .. }) => panic!("Got non-channel-failing result from free_holding_cell_htlcs"),
 					Ok(Some((commitment_update, channel_monitor))) => return Ok((resend_funding_locked, required_revoke, Some(commitment_update), Some(channel_monitor), self.resend_order.clone(), shutdown_msg)),
 					Ok(None) => return Ok((resend_funding_locked, required_revoke, None, None, self.resend_order.clone(), shutdown_msg)),
 				}
@@ -2645,7 +2827,7 @@ impl Channel {
 
 		let (closing_tx, total_fee_satoshis) = self.build_closing_transaction(proposed_total_fee_satoshis, false);
 		let funding_redeemscript = self.get_funding_redeemscript();
-		let sighash = hash_to_message!(&bip143::SighashComponents::new(&closing_tx).sighash_all(&closing_tx.input[0], &funding_redeemscript, self.channel_value_satoshis)[..]);
+		let sighash = hash_to_message!(&chan_utils::sighash_commitment(&closing_tx, 0, &funding_redeemscript, self.channel_value_satoshis)[..]);
 
 		self.last_sent_closing_fee = Some((proposed_feerate, total_fee_satoshis));
 		Some(msgs::ClosingSigned {
@@ -2747,7 +2929,7 @@ impl Channel {
 		if used_total_fee != msg.fee_satoshis {
 			return Err(ChannelError::Close("Remote sent us a closing_signed with a fee greater than the value they can claim"));
 		}
-		let mut sighash = hash_to_message!(&bip143::SighashComponents::new(&closing_tx).sighash_all(&closing_tx.input[0], &funding_redeemscript, self.channel_value_satoshis)[..]);
+		let mut sighash = hash_to_message!(&chan_utils::sighash_commitment(&closing_tx, 0, &funding_redeemscript, self.channel_value_satoshis)[..]);
 
 		match self.secp_ctx.verify(&sighash, &msg.signature, &self.their_funding_pubkey.unwrap()) {
 			Ok(_) => {},
@@ -2755,7 +2937,7 @@ impl Channel {
 				// The remote end may have decided to revoke their output due to inconsistent dust
 				// limits, so check for that case by re-checking the signature here.
 				closing_tx = self.build_closing_transaction(msg.fee_satoshis, true).0;
-				sighash = hash_to_message!(&bip143::SighashComponents::new(&closing_tx).sighash_all(&closing_tx.input[0], &funding_redeemscript, self.channel_value_satoshis)[..]);
+				sighash = hash_to_message!(&chan_utils::sighash_commitment(&closing_tx, 0, &funding_redeemscript, self.channel_value_satoshis)[..]);
 				secp_check!(self.secp_ctx.verify(&sighash, &msg.signature, &self.their_funding_pubkey.unwrap()), "Invalid closing tx signature from peer");
 			},
 		};
@@ -2769,13 +2951,13 @@ impl Channel {
 			}
 		}
 
-		macro_rules! propose_new_feerate {
-			($new_feerate: expr) => {
-				let closing_tx_max_weight = Self::get_closing_transaction_weight(&self.get_closing_scriptpubkey(), self.their_shutdown_scriptpubkey.as_ref().unwrap());
-				let (closing_tx, used_total_fee) = self.build_closing_transaction($new_feerate * closing_tx_max_weight / 1000, false);
-				sighash = hash_to_message!(&bip143::SighashComponents::new(&closing_tx).sighash_all(&closing_tx.input[0], &funding_redeemscript, self.channel_value_satoshis)[..]);
+		let closing_tx_max_weight = Self::get_closing_transaction_weight(&self.get_closing_scriptpubkey(), self.their_shutdown_scriptpubkey.as_ref().unwrap());
+		macro_rules! propose_new_fee {
+			($new_fee: expr) => {
+				let (closing_tx, used_total_fee) = self.build_closing_transaction($new_fee, false);
+				sighash = hash_to_message!(&chan_utils::sighash_commitment(&closing_tx, 0, &funding_redeemscript, self.channel_value_satoshis)[..]);
 				let our_sig = self.secp_ctx.sign(&sighash, &self.local_keys.funding_key);
-				self.last_sent_closing_fee = Some(($new_feerate, used_total_fee));
+				self.last_sent_closing_fee = Some((used_total_fee * 1000 / closing_tx_max_weight, used_total_fee));
 				return Ok((Some(msgs::ClosingSigned {
 					channel_id: self.channel_id,
 					fee_satoshis: used_total_fee,
@@ -2784,26 +2966,29 @@ impl Channel {
 			}
 		}
 
-		let proposed_sat_per_kw = msg.fee_satoshis * 1000 / closing_tx.get_weight() as u64;
-		if self.channel_outbound {
-			let our_max_feerate = fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Normal);
-			if proposed_sat_per_kw > our_max_feerate {
-				if let Some((last_feerate, _)) = self.last_sent_closing_fee {
-					if our_max_feerate <= last_feerate {
-						return Err(ChannelError::Close("Unable to come to consensus about closing feerate, remote wanted something higher than our Normal feerate"));
-					}
-				}
-				propose_new_feerate!(our_max_feerate);
-			}
+		// If the remote's proposal is outside the range we'd accept, we first counter with the edge
+		// of our range and afterwards, per BOLT 2, with a fee strictly between our last proposal
+		// and theirs, so that each round at least halves the gap. Once no such fee exists we take
+		// theirs.
+		// Fees are compared at the same (max signed) weight the proposer used to compute them.
+		let our_limit_fee = if self.channel_outbound {
+			fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Normal) * closing_tx_max_weight / 1000
 		} else {
-			let our_min_feerate = fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Background);
-			if proposed_sat_per_kw < our_min_feerate {
-				if let Some((last_feerate, _)) = self.last_sent_closing_fee {
-					if our_min_feerate >= last_feerate {
-						return Err(ChannelError::Close("Unable to come to consensus about closing feerate, remote wanted something lower than our Background feerate"));
+			fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Background) * closing_tx_max_weight / 1000
+		};
+		let acceptable = if self.channel_outbound { msg.fee_satoshis <= our_limit_fee } else { msg.fee_satoshis >= our_limit_fee };
+		if !acceptable {
+			if self.channel_outbound && msg.fee_satoshis > self.feerate_per_kw * COMMITMENT_TX_BASE_WEIGHT / 1000 {
+				return Err(ChannelError::Close("Remote wanted a closing fee higher than our final commitment transaction's base fee"));
+			}
+			match self.last_sent_closing_fee {
+				None => { propose_new_fee!(our_limit_fee); },
+				Some((_, last_fee)) => {
+					let (low, high) = if last_fee < msg.fee_satoshis { (last_fee, msg.fee_satoshis) } else { (msg.fee_satoshis, last_fee) };
+					if high - low > 1 {
+						propose_new_fee!(low + (high - low) / 2);
 					}
-				}
-				propose_new_feerate!(our_min_feerate);
+				},
 			}
 		}
 
@@ -2874,6 +3059,31 @@ impl Channel {
 		self.config.fee_proportional_millionths
 	}
 
+	/// Gets the CLTV delta we require when forwarding HTLCs over this channel
+	pub fn get_cltv_expiry_delta(&self) -> u16 {
+		self.config.cltv_expiry_delta
+	}
+
+	/// Updates the runtime-changeable parts of our config (ie our forwarding policy and limits).
+	/// Whether the channel is announced and whether we committed to an upfront shutdown pubkey
+	/// were fixed at channel open and are left as-is.
+	pub fn update_config(&mut self, config: &ChannelConfig) {
+		self.config.fee_proportional_millionths = config.fee_proportional_millionths;
+		self.config.fee_base_msat = config.fee_base_msat;
+		self.config.cltv_expiry_delta = config.cltv_expiry_delta;
+		self.config.htlc_expiry_buffer_blocks = config.htlc_expiry_buffer_blocks;
+		self.config.max_dust_htlc_exposure_msat = config.max_dust_htlc_exposure_msat;
+	}
+
+	/// Gets the forwarding policy from the last channel_update our counterparty sent us, if any
+	pub fn counterparty_forwarding_info(&self) -> Option<CounterpartyForwardingInfo> {
+		self.counterparty_forwarding_info
+	}
+
+	pub fn set_counterparty_forwarding_info(&mut self, info: CounterpartyForwardingInfo) {
+		self.counterparty_forwarding_info = Some(info);
+	}
+
 	#[cfg(test)]
 	pub fn get_feerate(&self) -> u64 {
 		self.feerate_per_kw
@@ -2925,6 +3135,12 @@ impl Channel {
 		self.channel_update_count
 	}
 
+	/// Bumps the counter used as the channel_update timestamp so that the next channel_update we
+	/// generate supersedes any previously-broadcast one.
+	pub fn bump_channel_update_count(&mut self) {
+		self.channel_update_count += 1;
+	}
+
 	pub fn should_announce(&self) -> bool {
 		self.config.announced_channel
 	}
@@ -2933,9 +3149,14 @@ impl Channel {
 		self.channel_outbound
 	}
 
-	/// Gets the fee we'd want to charge for adding an HTLC output to this Channel
+	/// Gets the fee we'd want to charge for adding an HTLC output to this Channel, unless our
+	/// config sets a fixed base fee
 	/// Allowed in any state (including after shutdown)
 	pub fn get_our_fee_base_msat(&self, fee_estimator: &FeeEstimator) -> u32 {
+		if let Some(fee_base_msat) = self.config.fee_base_msat {
+			return fee_base_msat;
+		}
+
 		// For lack of a better metric, we calculate what it would cost to consolidate the new HTLC
 		// output value back into a transaction with the regular channel output:
 
@@ -3051,7 +3272,7 @@ impl Channel {
 			for (ref tx, index_in_block) in txn_matched.iter().zip(indexes_of_txn_matched) {
 				if tx.txid() == self.channel_monitor.get_funding_txo().unwrap().txid {
 					let txo_idx = self.channel_monitor.get_funding_txo().unwrap().index as usize;
-					if txo_idx >= tx.output.len() || tx.output[txo_idx].script_pubkey != self.get_funding_redeemscript().to_v0_p2wsh() ||
+					if txo_idx >= tx.output.len() || tx.output[txo_idx].script_pubkey != self.get_funding_output_script() ||
 							tx.output[txo_idx].value != self.channel_value_satoshis {
 						if self.channel_outbound {
 							// If we generated the funding transaction and it doesn't match what it
@@ -3128,12 +3349,12 @@ impl Channel {
 		msgs::OpenChannel {
 			chain_hash: chain_hash,
 			temporary_channel_id: self.channel_id,
-			funding_satoshis: self.channel_value_satoshis,
-			push_msat: self.channel_value_satoshis * 1000 - self.value_to_self_msat,
-			dust_limit_satoshis: self.our_dust_limit_satoshis,
-			max_htlc_value_in_flight_msat: Channel::get_our_max_htlc_value_in_flight_msat(self.channel_value_satoshis),
-			channel_reserve_satoshis: Channel::get_our_channel_reserve_satoshis(self.channel_value_satoshis),
-			htlc_minimum_msat: self.our_htlc_minimum_msat,
+			funding_satoshis: Satoshi(self.channel_value_satoshis),
+			push_msat: MilliSatoshi(self.channel_value_satoshis * 1000 - self.value_to_self_msat),
+			dust_limit_satoshis: Satoshi(self.our_dust_limit_satoshis),
+			max_htlc_value_in_flight_msat: MilliSatoshi(Channel::get_our_max_htlc_value_in_flight_msat(self.channel_value_satoshis)),
+			channel_reserve_satoshis: Satoshi(Channel::get_our_channel_reserve_satoshis(self.channel_value_satoshis)),
+			htlc_minimum_msat: MilliSatoshi(self.our_htlc_minimum_msat),
 			feerate_per_kw: fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Background) as u32,
 			to_self_delay: self.our_to_self_delay,
 			max_accepted_htlcs: OUR_MAX_HTLCS,
@@ -3163,10 +3384,10 @@ impl Channel {
 
 		msgs::AcceptChannel {
 			temporary_channel_id: self.channel_id,
-			dust_limit_satoshis: self.our_dust_limit_satoshis,
-			max_htlc_value_in_flight_msat: Channel::get_our_max_htlc_value_in_flight_msat(self.channel_value_satoshis),
-			channel_reserve_satoshis: Channel::get_our_channel_reserve_satoshis(self.channel_value_satoshis),
-			htlc_minimum_msat: self.our_htlc_minimum_msat,
+			dust_limit_satoshis: Satoshi(self.our_dust_limit_satoshis),
+			max_htlc_value_in_flight_msat: MilliSatoshi(Channel::get_our_max_htlc_value_in_flight_msat(self.channel_value_satoshis)),
+			channel_reserve_satoshis: Satoshi(Channel::get_our_channel_reserve_satoshis(self.channel_value_satoshis)),
+			htlc_minimum_msat: MilliSatoshi(self.our_htlc_minimum_msat),
 			minimum_depth: self.minimum_depth,
 			to_self_delay: self.our_to_self_delay,
 			max_accepted_htlcs: OUR_MAX_HTLCS,
@@ -3186,7 +3407,7 @@ impl Channel {
 
 		let remote_keys = self.build_remote_transaction_keys()?;
 		let remote_initial_commitment_tx = self.build_commitment_transaction(self.cur_remote_commitment_transaction_number, &remote_keys, false, false, self.feerate_per_kw).0;
-		let remote_sighash = hash_to_message!(&bip143::SighashComponents::new(&remote_initial_commitment_tx).sighash_all(&remote_initial_commitment_tx.input[0], &funding_script, self.channel_value_satoshis)[..]);
+		let remote_sighash = hash_to_message!(&chan_utils::sighash_commitment(&remote_initial_commitment_tx, 0, &funding_script, self.channel_value_satoshis)[..]);
 
 		// We sign the "remote" commitment transaction, allowing them to broadcast the tx if they wish.
 		Ok((self.secp_ctx.sign(&remote_sighash, &self.local_keys.funding_key), remote_initial_commitment_tx))
@@ -3212,7 +3433,7 @@ impl Channel {
 			panic!("Should not have advanced channel commitment tx numbers prior to funding_created");
 		}
 
-		let funding_txo_script = self.get_funding_redeemscript().to_v0_p2wsh();
+		let funding_txo_script = self.get_funding_output_script();
 		self.channel_monitor.set_funding_info((funding_txo, funding_txo_script));
 
 		let (our_signature, commitment_tx) = match self.get_outbound_funding_created_signature() {
@@ -3354,10 +3575,10 @@ impl Channel {
 			return Err(ChannelError::Ignore("Cannot send an HTLC while disconnected/frozen for channel monitor update"));
 		}
 
-		let (outbound_htlc_count, htlc_outbound_value_msat) = self.get_outbound_pending_htlc_stats();
-		if outbound_htlc_count + 1 > self.their_max_accepted_htlcs as u32 {
+		if self.at_their_max_accepted_htlcs() {
 			return Err(ChannelError::Ignore("Cannot push more than their max accepted HTLCs"));
 		}
+		let htlc_outbound_value_msat = self.get_outbound_pending_htlc_stats().1;
 		// Check their_max_htlc_value_in_flight_msat
 		if htlc_outbound_value_msat + amount_msat > self.their_max_htlc_value_in_flight_msat {
 			return Err(ChannelError::Ignore("Cannot send value that would put us over the max HTLC value in flight our peer will accept"));
@@ -3365,8 +3586,13 @@ impl Channel {
 
 		// Check self.their_channel_reserve_satoshis (the amount we must keep as
 		// reserve for them to have something to claim if we misbehave)
-		if self.value_to_self_msat < self.their_channel_reserve_satoshis * 1000 + amount_msat + htlc_outbound_value_msat {
-			return Err(ChannelError::Ignore("Cannot send value that would put us over their reserve value"));
+		let commit_fee_msat = if self.channel_outbound { self.next_commit_tx_fee_msat() } else { 0 };
+		if let Err(e) = channelmanager::can_send_htlc(self.value_to_self_msat.saturating_sub(htlc_outbound_value_msat), self.their_channel_reserve_satoshis, amount_msat, commit_fee_msat) {
+			return Err(ChannelError::Ignore(e.err));
+		}
+
+		if self.would_exceed_dust_htlc_exposure(amount_msat, true) {
+			return Err(ChannelError::Ignore("Cannot send an HTLC which would put us over our max dust HTLC exposure"));
 		}
 
 		//TODO: Check cltv_expiry? Do this in channel manager?
@@ -3491,7 +3717,7 @@ impl Channel {
 		let remote_keys = self.build_remote_transaction_keys()?;
 		let remote_commitment_tx = self.build_commitment_transaction(self.cur_remote_commitment_transaction_number, &remote_keys, false, true, feerate_per_kw);
 		let remote_commitment_txid = remote_commitment_tx.0.txid();
-		let remote_sighash = hash_to_message!(&bip143::SighashComponents::new(&remote_commitment_tx.0).sighash_all(&remote_commitment_tx.0.input[0], &funding_script, self.channel_value_satoshis)[..]);
+		let remote_sighash = hash_to_message!(&chan_utils::sighash_commitment(&remote_commitment_tx.0, 0, &funding_script, self.channel_value_satoshis)[..]);
 		let our_sig = self.secp_ctx.sign(&remote_sighash, &self.local_keys.funding_key);
 		log_trace!(self, "Signing remote commitment tx {} with redeemscript {} with pubkey {} -> {}", encode::serialize_hex(&remote_commitment_tx.0), encode::serialize_hex(&funding_script), log_bytes!(PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.funding_key).serialize()), log_bytes!(our_sig.serialize_compact()[..]));
 
@@ -3500,7 +3726,7 @@ impl Channel {
 			if let Some(_) = htlc.transaction_output_index {
 				let htlc_tx = self.build_htlc_transaction(&remote_commitment_txid, htlc, false, &remote_keys, feerate_per_kw);
 				let htlc_redeemscript = chan_utils::get_htlc_redeemscript(&htlc, &remote_keys);
-				let htlc_sighash = hash_to_message!(&bip143::SighashComponents::new(&htlc_tx).sighash_all(&htlc_tx.input[0], &htlc_redeemscript, htlc.amount_msat / 1000)[..]);
+				let htlc_sighash = hash_to_message!(&chan_utils::get_htlc_transaction_sighash(&htlc_tx, &htlc_redeemscript, &htlc)[..]);
 				let our_htlc_key = secp_check!(chan_utils::derive_private_key(&self.secp_ctx, &remote_keys.per_commitment_point, &self.local_keys.htlc_base_key), "Derived invalid key, peer is maliciously selecting parameters");
 				htlc_sigs.push(self.secp_ctx.sign(&htlc_sighash, &our_htlc_key));
 				log_trace!(self, "Signing remote HTLC tx {} with redeemscript {} with pubkey {} -> {}", encode::serialize_hex(&htlc_tx), encode::serialize_hex(&htlc_redeemscript), log_bytes!(PublicKey::from_secret_key(&self.secp_ctx, &our_htlc_key).serialize()), log_bytes!(htlc_sigs.last().unwrap().serialize_compact()[..]));
@@ -3613,8 +3839,8 @@ impl Channel {
 	}
 }
 
-const SERIALIZATION_VERSION: u8 = 1;
-const MIN_SERIALIZATION_VERSION: u8 = 1;
+const SERIALIZATION_VERSION: u8 = 2;
+const MIN_SERIALIZATION_VERSION: u8 = 2;
 
 impl Writeable for InboundHTLCRemovalReason {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
@@ -3847,6 +4073,8 @@ impl Writeable for Channel {
 
 		write_option!(self.their_shutdown_scriptpubkey);
 
+		write_option!(self.counterparty_forwarding_info);
+
 		self.channel_monitor.write_for_disk(writer)?;
 		Ok(())
 	}
@@ -3854,14 +4082,23 @@ impl Writeable for Channel {
 
 impl<R : ::std::io::Read> ReadableArgs<R, Arc<Logger>> for Channel {
 	fn read(reader: &mut R, logger: Arc<Logger>) -> Result<Self, DecodeError> {
-		let _ver: u8 = Readable::read(reader)?;
+		let ver: u8 = Readable::read(reader)?;
 		let min_ver: u8 = Readable::read(reader)?;
 		if min_ver > SERIALIZATION_VERSION {
 			return Err(DecodeError::UnknownVersion);
 		}
 
 		let user_id = Readable::read(reader)?;
-		let config: ChannelConfig = Readable::read(reader)?;
+		let config: ChannelConfig = if ver >= 2 {
+			Readable::read(reader)?
+		} else {
+			// Version 1 channels only stored the first few options, so use our defaults for the rest
+			let mut config = ChannelConfig::new();
+			config.fee_proportional_millionths = Readable::read(reader)?;
+			config.announced_channel = Readable::read(reader)?;
+			config.commit_upfront_shutdown_pubkey = Readable::read(reader)?;
+			config
+		};
 
 		let channel_id = Readable::read(reader)?;
 		let channel_state = Readable::read(reader)?;
@@ -4009,6 +4246,9 @@ impl<R : ::std::io::Read> ReadableArgs<R, Arc<Logger>> for Channel {
 		let their_node_id = Readable::read(reader)?;
 
 		let their_shutdown_scriptpubkey = Readable::read(reader)?;
+
+		// Version 1 channels predate tracking our counterparty's forwarding policy
+		let counterparty_forwarding_info = if ver >= 2 { Readable::read(reader)? } else { None };
 		let (monitor_last_block, channel_monitor) = ReadableArgs::read(reader, logger.clone())?;
 		// We drop the ChannelMonitor's last block connected hash cause we don't actually bother
 		// doing full block connection operations on the internal CHannelMonitor copies
@@ -4089,6 +4329,8 @@ impl<R : ::std::io::Read> ReadableArgs<R, Arc<Logger>> for Channel {
 
 			their_shutdown_scriptpubkey,
 
+			counterparty_forwarding_info,
+
 			channel_monitor,
 
 			logger,
@@ -4112,15 +4354,21 @@ mod tests {
 	use chain::chaininterface::{FeeEstimator,ConfirmationTarget};
 	use chain::keysinterface::KeysInterface;
 	use chain::transaction::OutPoint;
-	use util::config::UserConfig;
+	use util::config::{UserConfig, ChannelConfig};
 	use util::test_utils;
 	use util::logger::Logger;
+	use util::ser::{Writeable, ReadableArgs};
+	use ln::msgs::LocalFeatures;
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::network::constants::Network;
+	use bitcoin::util::hash::BitcoinHash;
 	use secp256k1::{Secp256k1,Message,Signature};
 	use secp256k1::key::{SecretKey,PublicKey};
 	use bitcoin_hashes::sha256::Hash as Sha256;
 	use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 	use bitcoin_hashes::hash160::Hash as Hash160;
 	use bitcoin_hashes::Hash;
+	use std::io::Cursor;
 	use std::sync::Arc;
 
 	struct TestFeeEstimator {
@@ -4158,6 +4406,7 @@ mod tests {
 
 		fn get_channel_keys(&self, _inbound: bool) -> ChannelKeys { self.chan_keys.clone() }
 		fn get_session_key(&self) -> SecretKey { panic!(); }
+		fn get_payment_preimage(&self) -> [u8; 32] { panic!(); }
 		fn get_channel_id(&self) -> [u8; 32] { [0; 32] }
 	}
 
@@ -4244,7 +4493,7 @@ mod tests {
 				let ref htlc = unsigned_tx.1[$htlc_idx];
 				let mut htlc_tx = chan.build_htlc_transaction(&unsigned_tx.0.txid(), &htlc, true, &keys, chan.feerate_per_kw);
 				let htlc_redeemscript = chan_utils::get_htlc_redeemscript(&htlc, &keys);
-				let htlc_sighash = Message::from_slice(&bip143::SighashComponents::new(&htlc_tx).sighash_all(&htlc_tx.input[0], &htlc_redeemscript, htlc.amount_msat / 1000)[..]).unwrap();
+				let htlc_sighash = Message::from_slice(&chan_utils::get_htlc_transaction_sighash(&htlc_tx, &htlc_redeemscript, &htlc)[..]).unwrap();
 				secp_ctx.verify(&htlc_sighash, &remote_signature, &keys.b_htlc_key).unwrap();
 
 				let mut preimage: Option<PaymentPreimage> = None;
@@ -4651,6 +4900,70 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn legacy_channel_config_read_test() {
+		// Version 1 channels were written with only the first three ChannelConfig options (and no
+		// counterparty forwarding info), which we should still be able to read.
+		let feeest = TestFeeEstimator{fee_est: 15000};
+		let logger : Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let keys_provider = |seed: u8| -> Arc<KeysInterface> {
+			Arc::new(Keys { chan_keys: ChannelKeys {
+				funding_key: SecretKey::from_slice(&[seed; 32]).unwrap(),
+				payment_base_key: SecretKey::from_slice(&[seed + 1; 32]).unwrap(),
+				delayed_payment_base_key: SecretKey::from_slice(&[seed + 2; 32]).unwrap(),
+				htlc_base_key: SecretKey::from_slice(&[seed + 3; 32]).unwrap(),
+				revocation_base_key: SecretKey::from_slice(&[seed + 4; 32]).unwrap(),
+				commitment_seed: [seed + 5; 32],
+			}})
+		};
+		let secp_ctx = Secp256k1::new();
+		let our_node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[1; 32]).unwrap());
+		let their_node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		let mut config = UserConfig::new();
+		config.channel_options.fee_proportional_millionths = 1234;
+		config.channel_options.commit_upfront_shutdown_pubkey = false;
+
+		// Run through the funding handshake so that we have something worth serializing
+		let mut chan = Channel::new_outbound(&feeest, &keys_provider(10), their_node_id, 10000000, 100000, 42, Arc::clone(&logger), &config).unwrap();
+		let open_channel = chan.get_open_channel(genesis_block(Network::Testnet).header.bitcoin_hash(), &feeest);
+		let mut their_chan = Channel::new_from_req(&feeest, &keys_provider(20), our_node_id, LocalFeatures::new(), &open_channel, 43, Arc::clone(&logger), &UserConfig::new()).unwrap();
+		chan.accept_channel(&their_chan.get_accept_channel(), &config, LocalFeatures::new()).unwrap();
+		let funding_info = OutPoint::new(Sha256dHash::from_hex("8984484a580b825b9972d7adb15050b3ab624ccd731946b3eeddb92f4e7ef6be").unwrap(), 0);
+		let (funding_created, _) = chan.get_outbound_funding_created(funding_info).unwrap();
+		let (funding_signed, _) = their_chan.funding_created(&funding_created).unwrap();
+		chan.funding_signed(&funding_signed).unwrap();
+
+		let encoded = chan.encode();
+		let mut encoded_monitor = Vec::new();
+		chan.channel_monitor.write_for_disk(&mut encoded_monitor).unwrap();
+		let config_end = 2 + 8 + chan.config.encode().len();
+		// The monitor is preceded by our (unset) counterparty_forwarding_info
+		let monitor_start = encoded.len() - encoded_monitor.len();
+		assert_eq!(encoded[monitor_start - 1], 0);
+
+		let mut legacy_encoded = vec![1, 1];
+		legacy_encoded.extend_from_slice(&encoded[2..2 + 8]);
+		legacy_encoded.extend_from_slice(&1234u32.encode());
+		legacy_encoded.extend_from_slice(&[config.channel_options.announced_channel as u8, 0]);
+		legacy_encoded.extend_from_slice(&encoded[config_end..monitor_start - 1]);
+		legacy_encoded.extend_from_slice(&encoded_monitor);
+
+		let read_chan: Channel = ReadableArgs::read(&mut Cursor::new(&legacy_encoded), Arc::clone(&logger)).unwrap();
+		assert_eq!(read_chan.config.fee_proportional_millionths, 1234);
+		assert!(!read_chan.config.commit_upfront_shutdown_pubkey);
+		assert_eq!(read_chan.config.htlc_expiry_buffer_blocks, ChannelConfig::new().htlc_expiry_buffer_blocks);
+		// Channels written before we limited dust exposure get the default limit
+		assert_eq!(read_chan.config.max_dust_htlc_exposure_msat, ChannelConfig::new().max_dust_htlc_exposure_msat);
+		assert_eq!(read_chan.config.fee_base_msat, None);
+		assert_eq!(read_chan.config.cltv_expiry_delta, ChannelConfig::new().cltv_expiry_delta);
+		assert!(read_chan.counterparty_forwarding_info.is_none());
+		// Everything else survives, and we write the channel back out in the current format
+		assert_eq!(read_chan.encode(), encoded);
+
+		let read_chan: Channel = ReadableArgs::read(&mut Cursor::new(&encoded), Arc::clone(&logger)).unwrap();
+		assert_eq!(read_chan.encode(), encoded);
+	}
+
 	#[test]
 	fn test_per_commitment_secret_gen() {
 		// Test vectors from BOLT 3 Appendix D:
@@ -27,15 +27,16 @@ use secp256k1;
 
 use chain::chaininterface::{BroadcasterInterface,ChainListener,ChainWatchInterface,FeeEstimator};
 use chain::transaction::OutPoint;
-use ln::channel::{Channel, ChannelError};
+use ln::channel::{Channel, ChannelError, CounterpartyForwardingInfo};
 use ln::channelmonitor::{ChannelMonitor, ChannelMonitorUpdateErr, ManyChannelMonitor, CLTV_CLAIM_BUFFER, LATENCY_GRACE_PERIOD_BLOCKS, ANTI_REORG_DELAY};
-use ln::router::Route;
+use ln::invoice::{Currency, Invoice, InvoiceBuilder};
+use ln::router::{Route, RouteHint};
 use ln::msgs;
 use ln::msgs::LocalFeatures;
 use ln::onion_utils;
 use ln::msgs::{ChannelMessageHandler, DecodeError, HandleError};
 use chain::keysinterface::KeysInterface;
-use util::config::UserConfig;
+use util::config::{UserConfig, ChannelConfig};
 use util::{byte_utils, events};
 use util::ser::{Readable, ReadableArgs, Writeable, Writer};
 use util::chacha20::ChaCha20;
@@ -47,7 +48,7 @@ use std::collections::{HashMap, hash_map, HashSet};
 use std::io::Cursor;
 use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // We hold various information about HTLC relay in the HTLC objects in Channel itself:
 //
@@ -134,6 +135,33 @@ pub struct PaymentHash(pub [u8;32]);
 #[derive(Hash, Copy, Clone, PartialEq, Eq, Debug)]
 pub struct PaymentPreimage(pub [u8;32]);
 
+/// Gets the payment_hash which locks HTLCs claimable with the given payment_preimage
+pub fn payment_hash(payment_preimage: &PaymentPreimage) -> PaymentHash {
+	PaymentHash(Sha256::hash(&payment_preimage.0[..]).into_inner())
+}
+
+/// Checks whether payment_preimage is the preimage of payment_hash. The comparison is constant
+/// time so that it can be used on untrusted preimages.
+pub fn verify_preimage(payment_preimage: &PaymentPreimage, payment_hash: &PaymentHash) -> bool {
+	fixed_time_eq(&self::payment_hash(payment_preimage).0[..], &payment_hash.0[..])
+}
+
+/// Checks that sending an HTLC of htlc_msat leaves balance_msat (our balance, less any HTLCs we
+/// already have outbound) at or above the reserve_sat the counterparty requires us to keep, per
+/// BOLT #2. The funder also pays the commitment transaction fee out of its balance, so it should
+/// pass the fee of the commitment transaction including the new HTLC as commit_fee_msat, while
+/// the fundee passes 0.
+pub fn can_send_htlc(balance_msat: u64, reserve_sat: u64, htlc_msat: u64, commit_fee_msat: u64) -> Result<(), HandleError> {
+	let required_msat = reserve_sat.saturating_mul(1000).saturating_add(htlc_msat).saturating_add(commit_fee_msat);
+	if balance_msat < required_msat {
+		return Err(HandleError {
+			err: "Cannot send value that would put us over their reserve value",
+			action: Some(msgs::ErrorAction::IgnoreError),
+		});
+	}
+	Ok(())
+}
+
 type ShutdownResult = (Vec<Transaction>, Vec<(HTLCSource, PaymentHash)>);
 
 /// Error type returned across the channel_state mutex boundary. When an Err is generated for a
@@ -208,6 +236,11 @@ impl MsgHandleErrInternal {
 						},
 					}),
 				},
+				ChannelError::Disconnect(msg) => HandleError {
+					err: msg,
+					// Sending an error message for the channel would make our peer close it
+					action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }),
+				},
 				ChannelError::CloseDelayBroadcast { msg, .. } => HandleError {
 					err: msg,
 					action: Some(msgs::ErrorAction::SendErrorMessage {
@@ -241,6 +274,79 @@ pub(super) enum HTLCForwardInfo {
 	},
 }
 
+/// An HTLC which we received and are relaying onwards, but which has not yet been fulfilled or
+/// failed, along with what we need to know to fail it back before its incoming cltv_expiry.
+#[derive(Clone, PartialEq, Debug)]
+pub struct PendingForwardHTLC {
+	/// The channel over which we received the HTLC
+	pub incoming_channel_id: [u8; 32],
+	/// The HTLC's id in the incoming channel
+	pub incoming_htlc_id: u64,
+	/// The payment_hash which both the incoming and outgoing HTLCs are locked to
+	pub payment_hash: PaymentHash,
+	/// The amount of the incoming HTLC
+	pub incoming_amount_msat: u64,
+	/// The block height at which the incoming HTLC times out
+	pub incoming_cltv_expiry: u32,
+	/// The short_channel_id of the channel we are relaying the HTLC over
+	pub outgoing_short_channel_id: u64,
+}
+
+/// Tracks the PendingForwardHTLCs we are relaying, keyed by their incoming (channel_id, htlc_id).
+pub struct PendingForwardHTLCs {
+	htlcs: HashMap<([u8; 32], u64), PendingForwardHTLC>,
+}
+
+impl PendingForwardHTLCs {
+	/// Creates an empty tracker
+	pub fn new() -> Self {
+		PendingForwardHTLCs { htlcs: HashMap::new() }
+	}
+
+	/// Starts tracking a newly-forwarded HTLC. Fails if an HTLC with the same incoming
+	/// (channel_id, htlc_id) is already being tracked.
+	pub fn add(&mut self, htlc: PendingForwardHTLC) -> Result<(), APIError> {
+		match self.htlcs.entry((htlc.incoming_channel_id, htlc.incoming_htlc_id)) {
+			hash_map::Entry::Occupied(_) => Err(APIError::APIMisuseError { err: "HTLC is already being forwarded" }),
+			hash_map::Entry::Vacant(e) => {
+				e.insert(htlc);
+				Ok(())
+			},
+		}
+	}
+
+	/// Stops tracking the given HTLC once it has been fulfilled or failed back, returning it if it
+	/// was being tracked.
+	pub fn resolve(&mut self, incoming_channel_id: &[u8; 32], incoming_htlc_id: u64) -> Option<PendingForwardHTLC> {
+		self.htlcs.remove(&(*incoming_channel_id, incoming_htlc_id))
+	}
+
+	/// Gets the HTLCs whose incoming cltv_expiry is at or below the given height, soonest-expiring
+	/// first. Callers should include in height whatever buffer they need to fail HTLCs back safely.
+	pub fn expiring_by(&self, height: u32) -> Vec<&PendingForwardHTLC> {
+		let mut res: Vec<&PendingForwardHTLC> = self.htlcs.values().filter(|htlc| htlc.incoming_cltv_expiry <= height).collect();
+		res.sort_unstable_by_key(|htlc| (htlc.incoming_cltv_expiry, htlc.incoming_channel_id, htlc.incoming_htlc_id));
+		res
+	}
+
+	/// Stops tracking HTLCs received over channels for which is_open returns false. Once an
+	/// incoming channel has closed its HTLCs are resolved on-chain, so there's nothing left for us
+	/// to fail back.
+	pub fn retain_open_channels<F: Fn(&[u8; 32]) -> bool>(&mut self, is_open: F) {
+		self.htlcs.retain(|&(ref channel_id, _), _| is_open(channel_id));
+	}
+
+	/// Gets the number of HTLCs being tracked
+	pub fn len(&self) -> usize {
+		self.htlcs.len()
+	}
+
+	/// Returns true if no HTLCs are being tracked
+	pub fn is_empty(&self) -> bool {
+		self.htlcs.is_empty()
+	}
+}
+
 /// For events which result in both a RevokeAndACK and a CommitmentUpdate, by default they should
 /// be sent in the order they appear in the return value, however sometimes the order needs to be
 /// variable at runtime (eg Channel::channel_reestablish needs to re-send messages in the order
@@ -268,6 +374,9 @@ pub(super) struct ChannelHolder {
 	/// guarantees are made about the channels given here actually existing anymore by the time you
 	/// go to read them!
 	pub(super) claimable_htlcs: HashMap<PaymentHash, Vec<(u64, HTLCPreviousHopData)>>,
+	/// HTLCs we've relayed onwards out of forward_htlcs, until they're fulfilled or failed back.
+	/// Entries for incoming channels which have since closed are only pruned when listed.
+	pub(super) pending_forwards: PendingForwardHTLCs,
 	/// Messages to send to peers - pushed to in the same lock that they are generated in (except
 	/// for broadcast messages, where ordering isn't as strict).
 	pub(super) pending_msg_events: Vec<events::MessageSendEvent>,
@@ -277,6 +386,7 @@ pub(super) struct MutChannelHolder<'a> {
 	pub(super) short_to_id: &'a mut HashMap<u64, [u8; 32]>,
 	pub(super) forward_htlcs: &'a mut HashMap<u64, Vec<HTLCForwardInfo>>,
 	pub(super) claimable_htlcs: &'a mut HashMap<PaymentHash, Vec<(u64, HTLCPreviousHopData)>>,
+	pub(super) pending_forwards: &'a mut PendingForwardHTLCs,
 	pub(super) pending_msg_events: &'a mut Vec<events::MessageSendEvent>,
 }
 impl ChannelHolder {
@@ -286,6 +396,7 @@ impl ChannelHolder {
 			short_to_id: &mut self.short_to_id,
 			forward_htlcs: &mut self.forward_htlcs,
 			claimable_htlcs: &mut self.claimable_htlcs,
+			pending_forwards: &mut self.pending_forwards,
 			pending_msg_events: &mut self.pending_msg_events,
 		}
 	}
@@ -294,6 +405,17 @@ impl ChannelHolder {
 #[cfg(not(any(target_pointer_width = "32", target_pointer_width = "64")))]
 const ERR: () = "You need at least 32 bit pointers (well, usize, but we'll assume they're the same) for ChannelManager::latest_block_height";
 
+/// Decides whether to accept channels our peers try to open with us, eg to only accept channels
+/// from known peers or above some minimum size. Registered via
+/// ChannelManager::set_inbound_channel_acceptor.
+pub trait InboundChannelAcceptor: Send + Sync {
+	/// Called for each open_channel message once it has passed our own checks against the
+	/// UserConfig limits, before any keys are derived for the channel, it is stored or an
+	/// accept_channel is sent. Returning an Err rejects the channel, sending our peer an error
+	/// message containing the given reason.
+	fn accept_inbound_channel(&self, their_node_id: &PublicKey, msg: &msgs::OpenChannel) -> Result<(), &'static str>;
+}
+
 /// Manager which keeps track of a number of channels and sends messages to the appropriate
 /// channel, also tracking HTLC preimages and forwarding onion packets appropriately.
 ///
@@ -340,6 +462,10 @@ pub struct ChannelManager {
 	our_network_key: SecretKey,
 
 	pending_events: Mutex<Vec<events::Event>>,
+	/// Preimages for the payments we've created invoices for and not yet claimed
+	invoice_preimages: Mutex<HashMap<PaymentHash, (PaymentPreimage, u64)>>,
+	/// Consulted before accepting any inbound channel, if set
+	inbound_channel_acceptor: RwLock<Option<Arc<InboundChannelAcceptor>>>,
 	/// Used when we have to take a BIG lock to make sure everything is self-consistent.
 	/// Essentially just when we're serializing ourselves out.
 	/// Taken first everywhere where we are making changes before any other locks.
@@ -361,7 +487,7 @@ pub(crate) const MAX_LOCAL_BREAKDOWN_TIMEOUT: u16 = 6 * 24 * 7;
 /// ie the node we forwarded the payment on to should always have enough room to reliably time out
 /// the HTLC via a full update_fail_htlc/commitment_signed dance before we hit the
 /// CLTV_CLAIM_BUFFER point (we static assert that it's at least 3 blocks more).
-const CLTV_EXPIRY_DELTA: u16 = 6 * 12; //TODO?
+pub(crate) const CLTV_EXPIRY_DELTA: u16 = 6 * 12; //TODO?
 pub(super) const CLTV_FAR_FAR_AWAY: u32 = 6 * 24 * 7; //TODO?
 
 // Check that our CLTV_EXPIRY is at least CLTV_CLAIM_BUFFER + ANTI_REORG_DELAY + LATENCY_GRACE_PERIOD_BLOCKS,
@@ -380,6 +506,15 @@ const CHECK_CLTV_EXPIRY_SANITY: u32 = CLTV_EXPIRY_DELTA as u32 - LATENCY_GRACE_P
 #[allow(dead_code)]
 const CHECK_CLTV_EXPIRY_SANITY_2: u32 = CLTV_EXPIRY_DELTA as u32 - LATENCY_GRACE_PERIOD_BLOCKS - 2*CLTV_CLAIM_BUFFER;
 
+/// The smallest ChannelConfig::cltv_expiry_delta we accept, ie the bound CLTV_EXPIRY_DELTA is
+/// checked against above.
+pub(super) const MIN_CLTV_EXPIRY_DELTA: u16 = (LATENCY_GRACE_PERIOD_BLOCKS + CLTV_CLAIM_BUFFER + ANTI_REORG_DELAY + LATENCY_GRACE_PERIOD_BLOCKS) as u16;
+
+// Check that MIN_CLTV_EXPIRY_DELTA also leaves room for the inbound claim delay described above.
+#[deny(const_err)]
+#[allow(dead_code)]
+const CHECK_MIN_CLTV_EXPIRY_DELTA_SANITY: u32 = MIN_CLTV_EXPIRY_DELTA as u32 - LATENCY_GRACE_PERIOD_BLOCKS - 2*CLTV_CLAIM_BUFFER;
+
 macro_rules! secp_call {
 	( $res: expr, $err: expr ) => {
 		match $res {
@@ -405,17 +540,22 @@ pub struct ChannelDetails {
 	pub channel_value_satoshis: u64,
 	/// The user_id passed in to create_channel, or 0 if the channel was inbound.
 	pub user_id: u64,
-	/// The available outbound capacity for sending HTLCs to the remote peer. This does not include
-	/// any pending HTLCs which are not yet fully resolved (and, thus, who's balance is not
-	/// available for inclusion in new outbound HTLCs). This further does not include any pending
-	/// outgoing HTLCs which are awaiting some other resolution to be sent.
+	/// The largest HTLC we could currently send to the remote peer over this channel. This
+	/// accounts for pending HTLCs which are not yet fully resolved (including outgoing HTLCs which
+	/// are awaiting some other resolution to be sent), the channel reserve the remote peer
+	/// requires of us, their max_htlc_value_in_flight_msat and, if we funded the channel, the
+	/// commitment transaction fee.
 	pub outbound_capacity_msat: u64,
-	/// The available inbound capacity for the remote peer to send HTLCs to us. This does not
-	/// include any pending HTLCs which are not yet fully resolved (and, thus, who's balance is not
-	/// available for inclusion in new inbound HTLCs).
-	/// Note that there are some corner cases not fully handled here, so the actual available
-	/// inbound capacity may be slightly higher than this.
+	/// The largest HTLC the remote peer could currently send to us over this channel. This
+	/// accounts for pending HTLCs which are not yet fully resolved, the channel reserve we require
+	/// of them, our max_htlc_value_in_flight_msat and, if they funded the channel, the commitment
+	/// transaction fee.
 	pub inbound_capacity_msat: u64,
+	/// The total value of HTLCs the remote peer has sent us which are not yet fully resolved.
+	pub pending_inbound_htlc_msat: u64,
+	/// The total value of HTLCs we have sent (or are waiting to send) to the remote peer which are
+	/// not yet fully resolved.
+	pub pending_outbound_htlc_msat: u64,
 	/// True if the channel is (a) confirmed and funding_locked messages have been exchanged, (b)
 	/// the peer is connected, and (c) no monitor update failure is pending resolution.
 	pub is_live: bool,
@@ -448,13 +588,16 @@ macro_rules! break_chan_entry {
 			Err(ChannelError::Ignore(msg)) => {
 				break Err(MsgHandleErrInternal::from_chan_no_close(ChannelError::Ignore(msg), $entry.key().clone()))
 			},
+			Err(ChannelError::Disconnect(msg)) => {
+				break Err(MsgHandleErrInternal::from_chan_no_close(ChannelError::Disconnect(msg), $entry.key().clone()))
+			},
 			Err(ChannelError::Close(msg)) => {
 				log_trace!($self, "Closing channel {} due to Close-required error: {}", log_bytes!($entry.key()[..]), msg);
 				let (channel_id, mut chan) = $entry.remove_entry();
 				if let Some(short_id) = chan.get_short_channel_id() {
 					$channel_state.short_to_id.remove(&short_id);
 				}
-				break Err(MsgHandleErrInternal::from_finish_shutdown(msg, channel_id, chan.force_shutdown(), $self.get_channel_update(&chan).ok()))
+				break Err(MsgHandleErrInternal::from_finish_shutdown(msg, channel_id, chan.force_shutdown(), $self.get_channel_update_for_broadcast(&chan).ok()))
 			},
 			Err(ChannelError::CloseDelayBroadcast { .. }) => { panic!("Wait is only generated on receipt of channel_reestablish, which is handled by try_chan_entry, we don't bother to support it here"); }
 		}
@@ -468,13 +611,16 @@ macro_rules! try_chan_entry {
 			Err(ChannelError::Ignore(msg)) => {
 				return Err(MsgHandleErrInternal::from_chan_no_close(ChannelError::Ignore(msg), $entry.key().clone()))
 			},
+			Err(ChannelError::Disconnect(msg)) => {
+				return Err(MsgHandleErrInternal::from_chan_no_close(ChannelError::Disconnect(msg), $entry.key().clone()))
+			},
 			Err(ChannelError::Close(msg)) => {
 				log_trace!($self, "Closing channel {} due to Close-required error: {}", log_bytes!($entry.key()[..]), msg);
 				let (channel_id, mut chan) = $entry.remove_entry();
 				if let Some(short_id) = chan.get_short_channel_id() {
 					$channel_state.short_to_id.remove(&short_id);
 				}
-				return Err(MsgHandleErrInternal::from_finish_shutdown(msg, channel_id, chan.force_shutdown(), $self.get_channel_update(&chan).ok()))
+				return Err(MsgHandleErrInternal::from_finish_shutdown(msg, channel_id, chan.force_shutdown(), $self.get_channel_update_for_broadcast(&chan).ok()))
 			},
 			Err(ChannelError::CloseDelayBroadcast { msg, update }) => {
 				log_error!($self, "Channel {} need to be shutdown but closing transactions not broadcast due to {}", log_bytes!($entry.key()[..]), msg);
@@ -499,7 +645,7 @@ macro_rules! try_chan_entry {
 					log_error!($self, "You have a toxic local commitment transaction {} avaible in channel monitor, read comment in ChannelMonitor::get_latest_local_commitment_txn to be informed of manual action to take", shutdown_res.0[0].txid());
 				}
 				shutdown_res.0.clear();
-				return Err(MsgHandleErrInternal::from_finish_shutdown(msg, channel_id, shutdown_res, $self.get_channel_update(&chan).ok()))
+				return Err(MsgHandleErrInternal::from_finish_shutdown(msg, channel_id, shutdown_res, $self.get_channel_update_for_broadcast(&chan).ok()))
 			}
 		}
 	}
@@ -526,7 +672,7 @@ macro_rules! handle_monitor_err {
 				// splitting hairs we'd prefer to claim payments that were to us, but we haven't
 				// given up the preimage yet, so might as well just wait until the payment is
 				// retried, avoiding the on-chain fees.
-				let res: Result<(), _> = Err(MsgHandleErrInternal::from_finish_shutdown("ChannelMonitor storage failure", channel_id, chan.force_shutdown(), $self.get_channel_update(&chan).ok()));
+				let res: Result<(), _> = Err(MsgHandleErrInternal::from_finish_shutdown("ChannelMonitor storage failure", channel_id, chan.force_shutdown(), $self.get_channel_update_for_broadcast(&chan).ok()));
 				res
 			},
 			ChannelMonitorUpdateErr::TemporaryFailure => {
@@ -605,11 +751,14 @@ impl ChannelManager {
 				short_to_id: HashMap::new(),
 				forward_htlcs: HashMap::new(),
 				claimable_htlcs: HashMap::new(),
+				pending_forwards: PendingForwardHTLCs::new(),
 				pending_msg_events: Vec::new(),
 			}),
 			our_network_key: keys_manager.get_node_secret(),
 
 			pending_events: Mutex::new(Vec::new()),
+			invoice_preimages: Mutex::new(HashMap::new()),
+			inbound_channel_acceptor: RwLock::new(None),
 			total_consistency_lock: RwLock::new(()),
 
 			keys_manager,
@@ -634,11 +783,28 @@ impl ChannelManager {
 	/// Raises APIError::APIMisuseError when channel_value_satoshis > 2**24 or push_msat is
 	/// greater than channel_value_satoshis * 1k or channel_value_satoshis is < 1000.
 	pub fn create_channel(&self, their_network_key: PublicKey, channel_value_satoshis: u64, push_msat: u64, user_id: u64) -> Result<(), APIError> {
+		self.create_channel_with_config(their_network_key, channel_value_satoshis, push_msat, user_id, &self.default_configuration)
+	}
+
+	/// Creates a new outbound channel exactly as create_channel does, except that whether the
+	/// channel is publicly announced is set by announce instead of by
+	/// default_configuration.channel_options.announced_channel.
+	///
+	/// announce sets the announce_channel bit in our open_channel's channel_flags. Channels which
+	/// are not announced never have announcement_signatures or a channel_announcement generated
+	/// for them, nor any broadcast channel_updates.
+	pub fn create_channel_with_announce(&self, their_network_key: PublicKey, channel_value_satoshis: u64, push_msat: u64, user_id: u64, announce: bool) -> Result<(), APIError> {
+		let mut config = self.default_configuration.clone();
+		config.channel_options.announced_channel = announce;
+		self.create_channel_with_config(their_network_key, channel_value_satoshis, push_msat, user_id, &config)
+	}
+
+	fn create_channel_with_config(&self, their_network_key: PublicKey, channel_value_satoshis: u64, push_msat: u64, user_id: u64, config: &UserConfig) -> Result<(), APIError> {
 		if channel_value_satoshis < 1000 {
 			return Err(APIError::APIMisuseError { err: "channel_value must be at least 1000 satoshis" });
 		}
 
-		let channel = Channel::new_outbound(&*self.fee_estimator, &self.keys_manager, their_network_key, channel_value_satoshis, push_msat, user_id, Arc::clone(&self.logger), &self.default_configuration)?;
+		let channel = Channel::new_outbound(&*self.fee_estimator, &self.keys_manager, their_network_key, channel_value_satoshis, push_msat, user_id, Arc::clone(&self.logger), config)?;
 		let res = channel.get_open_channel(self.genesis_hash.clone(), &*self.fee_estimator);
 
 		let _ = self.total_consistency_lock.read().unwrap();
@@ -666,14 +832,16 @@ impl ChannelManager {
 		let channel_state = self.channel_state.lock().unwrap();
 		let mut res = Vec::with_capacity(channel_state.by_id.len());
 		for (channel_id, channel) in channel_state.by_id.iter() {
-			let (inbound_capacity_msat, outbound_capacity_msat) = channel.get_inbound_outbound_available_balance_msat();
+			let (pending_inbound_htlc_msat, pending_outbound_htlc_msat) = channel.pending_htlc_value_msat();
 			res.push(ChannelDetails {
 				channel_id: (*channel_id).clone(),
 				short_channel_id: channel.get_short_channel_id(),
 				remote_network_id: channel.get_their_node_id(),
 				channel_value_satoshis: channel.get_value_satoshis(),
-				inbound_capacity_msat,
-				outbound_capacity_msat,
+				inbound_capacity_msat: channel.inbound_capacity_msat(),
+				outbound_capacity_msat: channel.outbound_capacity_msat(),
+				pending_inbound_htlc_msat,
+				pending_outbound_htlc_msat,
 				user_id: channel.get_user_id(),
 				is_live: channel.is_live(),
 			});
@@ -681,6 +849,15 @@ impl ChannelManager {
 		res
 	}
 
+	/// Sets the InboundChannelAcceptor which decides whether to accept channels our peers open with
+	/// us, replacing any previously-set one. If None (the default), all inbound channels within
+	/// our UserConfig's limits are accepted.
+	///
+	/// This is not serialized and must be set again after deserialization.
+	pub fn set_inbound_channel_acceptor(&self, acceptor: Option<Arc<InboundChannelAcceptor>>) {
+		*self.inbound_channel_acceptor.write().unwrap() = acceptor;
+	}
+
 	/// Gets the list of usable channels, in random order. Useful as an argument to
 	/// Router::get_route to ensure non-announced channels are used.
 	///
@@ -694,14 +871,16 @@ impl ChannelManager {
 			// internal/external nomenclature, but that's ok cause that's probably what the user
 			// really wanted anyway.
 			if channel.is_live() {
-				let (inbound_capacity_msat, outbound_capacity_msat) = channel.get_inbound_outbound_available_balance_msat();
+				let (pending_inbound_htlc_msat, pending_outbound_htlc_msat) = channel.pending_htlc_value_msat();
 				res.push(ChannelDetails {
 					channel_id: (*channel_id).clone(),
 					short_channel_id: channel.get_short_channel_id(),
 					remote_network_id: channel.get_their_node_id(),
 					channel_value_satoshis: channel.get_value_satoshis(),
-					inbound_capacity_msat,
-					outbound_capacity_msat,
+					inbound_capacity_msat: channel.inbound_capacity_msat(),
+					outbound_capacity_msat: channel.outbound_capacity_msat(),
+					pending_inbound_htlc_msat,
+					pending_outbound_htlc_msat,
 					user_id: channel.get_user_id(),
 					is_live: true,
 				});
@@ -710,6 +889,86 @@ impl ChannelManager {
 		res
 	}
 
+	/// Records the forwarding policy our counterparty set in a channel_update for one of our
+	/// channels with them, so that get_route_hints can tell payers what forwarding to us over it
+	/// costs. As private channels are never announced, their channel_updates only ever reach us
+	/// directly from our counterparty. Updates for channels which aren't ours are ignored.
+	fn internal_channel_update(&self, their_node_id: &PublicKey, msg: &msgs::ChannelUpdate) -> Result<(), HandleError> {
+		if msg.contents.chain_hash != self.genesis_hash {
+			return Err(HandleError{err: "Channel update chain_hash didn't match our genesis block hash", action: Some(msgs::ErrorAction::IgnoreError)});
+		}
+		let mut channel_state_lock = self.channel_state.lock().unwrap();
+		let channel_state = channel_state_lock.borrow_parts();
+		let channel_id = match channel_state.short_to_id.get(&msg.contents.short_channel_id) {
+			Some(channel_id) => channel_id,
+			// Most channel_updates are gossip about other nodes' channels, left to the Router
+			None => return Ok(()),
+		};
+		let chan = channel_state.by_id.get_mut(channel_id).unwrap();
+		if chan.get_their_node_id() != *their_node_id {
+			return Err(HandleError{err: "Got a channel_update for a channel from the wrong node", action: Some(msgs::ErrorAction::IgnoreError)});
+		}
+		let they_are_node_one = their_node_id.serialize()[..] < PublicKey::from_secret_key(&self.secp_ctx, &self.our_network_key).serialize()[..];
+		if (msg.contents.flags & 1 == 0) != they_are_node_one {
+			return Err(HandleError{err: "Got a channel_update for the direction of a channel we control", action: Some(msgs::ErrorAction::IgnoreError)});
+		}
+		msg.verify(&self.secp_ctx, their_node_id)?;
+		chan.set_counterparty_forwarding_info(CounterpartyForwardingInfo {
+			fee_base_msat: msg.contents.fee_base_msat,
+			fee_proportional_millionths: msg.contents.fee_proportional_millionths,
+			cltv_expiry_delta: msg.contents.cltv_expiry_delta,
+		});
+		Ok(())
+	}
+
+	/// Lists the HTLCs we've relayed onwards which haven't yet been fulfilled or failed back and
+	/// whose incoming cltv_expiry is at or below height, soonest-expiring first. If the outbound
+	/// HTLC is still pending as the incoming one nears expiry, we have to go on-chain to avoid
+	/// losing the HTLC's value.
+	pub fn list_pending_forwards_expiring_by(&self, height: u32) -> Vec<PendingForwardHTLC> {
+		let mut channel_state_lock = self.channel_state.lock().unwrap();
+		let channel_state = channel_state_lock.borrow_parts();
+		let by_id = &*channel_state.by_id;
+		channel_state.pending_forwards.retain_open_channels(|channel_id| by_id.contains_key(channel_id));
+		channel_state.pending_forwards.expiring_by(height).into_iter().cloned().collect()
+	}
+
+	/// Gets RouteHints describing our usable private channels, through which payers who can't see
+	/// them in the network graph may still reach us.
+	///
+	/// Unless UserConfig::include_all_private_channel_hints is set, channels without enough inbound
+	/// capacity to receive amount_msat (if provided) are skipped. Channels whose counterparty
+	/// hasn't yet sent us a channel_update are always skipped, as we don't know what they charge
+	/// to forward over them.
+	pub fn get_route_hints(&self, amount_msat: Option<u64>) -> Vec<RouteHint> {
+		let channel_state = self.channel_state.lock().unwrap();
+		let mut res = Vec::new();
+		for (_, channel) in channel_state.by_id.iter() {
+			if !channel.is_live() || channel.should_announce() { continue; }
+			let short_channel_id = match channel.get_short_channel_id() {
+				Some(id) => id,
+				None => continue,
+			};
+			if !self.default_configuration.include_all_private_channel_hints {
+				let inbound_capacity_msat = channel.inbound_capacity_msat();
+				if inbound_capacity_msat == 0 || inbound_capacity_msat < amount_msat.unwrap_or(0) { continue; }
+			}
+			let forwarding_info = match channel.counterparty_forwarding_info() {
+				Some(info) => info,
+				None => continue,
+			};
+			res.push(RouteHint {
+				src_node_id: channel.get_their_node_id(),
+				short_channel_id,
+				fee_base_msat: forwarding_info.fee_base_msat,
+				fee_proportional_millionths: forwarding_info.fee_proportional_millionths,
+				cltv_expiry_delta: forwarding_info.cltv_expiry_delta,
+				htlc_minimum_msat: channel.get_our_htlc_minimum_msat(),
+			});
+		}
+		res
+	}
+
 	/// Begins the process of closing a channel. After this call (plus some timeout), no new HTLCs
 	/// will be accepted on the given channel, and after additional timeout/the closing of all
 	/// pending HTLCs, the channel will be closed on chain.
@@ -742,7 +1001,7 @@ impl ChannelManager {
 			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), htlc_source.0, &htlc_source.1, HTLCFailReason::Reason { failure_code: 0x4000 | 8, data: Vec::new() });
 		}
 		let chan_update = if let Some(chan) = chan_option {
-			if let Ok(update) = self.get_channel_update(&chan) {
+			if let Ok(update) = self.get_channel_update_for_broadcast(&chan) {
 				Some(update)
 			} else { None }
 		} else { None };
@@ -757,6 +1016,40 @@ impl ChannelManager {
 		Ok(())
 	}
 
+	/// Abandons a channel which has not yet been funded, removing it and sending an error message
+	/// to the peer so that it can forget the channel as well. This is useful if you change your
+	/// mind after calling create_channel but before calling funding_transaction_generated.
+	///
+	/// Once funding has been initiated the funding transaction may be broadcast at any time, so
+	/// this will fail with an APIError::APIMisuseError and you must use force_close_channel
+	/// instead.
+	pub fn abandon_channel(&self, temporary_channel_id: &[u8; 32]) -> Result<(), APIError> {
+		let _ = self.total_consistency_lock.read().unwrap();
+
+		let mut channel_state_lock = self.channel_state.lock().unwrap();
+		let channel_state = channel_state_lock.borrow_parts();
+		match channel_state.by_id.entry(temporary_channel_id.clone()) {
+			hash_map::Entry::Occupied(chan_entry) => {
+				if chan_entry.get().is_funding_initiated() {
+					return Err(APIError::APIMisuseError{err: "Cannot abandon a channel once funding has been initiated, use force_close_channel instead"});
+				}
+				let chan = chan_entry.remove_entry().1;
+				log_trace!(self, "Abandoning unfunded channel {}", log_bytes!(temporary_channel_id[..]));
+				channel_state.pending_msg_events.push(events::MessageSendEvent::HandleError {
+					node_id: chan.get_their_node_id(),
+					action: Some(msgs::ErrorAction::SendErrorMessage {
+						msg: msgs::ErrorMessage {
+							channel_id: temporary_channel_id.clone(),
+							data: "Channel abandoned prior to funding".to_owned()
+						},
+					}),
+				});
+				Ok(())
+			},
+			hash_map::Entry::Vacant(_) => Err(APIError::ChannelUnavailable{err: "No such channel"})
+		}
+	}
+
 	#[inline]
 	fn finish_force_close_channel(&self, shutdown_res: ShutdownResult) {
 		let (local_txn, mut failed_htlcs) = shutdown_res;
@@ -771,7 +1064,12 @@ impl ChannelManager {
 
 	/// Force closes a channel, immediately broadcasting the latest local commitment transaction to
 	/// the chain and rejecting new HTLCs on the given channel.
-	pub fn force_close_channel(&self, channel_id: &[u8; 32]) {
+	///
+	/// Returns the broadcast commitment transaction, whose outputs are now watched on the chain so
+	/// that the ChannelMonitor can sweep them once it confirms. If the channel was closed before
+	/// funding was signed there is no commitment transaction and an Err is returned, though the
+	/// channel is still closed.
+	pub fn force_close_channel(&self, channel_id: &[u8; 32]) -> Result<Transaction, HandleError> {
 		let _ = self.total_consistency_lock.read().unwrap();
 
 		let mut chan = {
@@ -783,27 +1081,103 @@ impl ChannelManager {
 				}
 				chan
 			} else {
-				return;
+				return Err(HandleError{err: "No such channel", action: None});
 			}
 		};
 		log_trace!(self, "Force-closing channel {}", log_bytes!(channel_id[..]));
-		self.finish_force_close_channel(chan.force_shutdown());
-		if let Ok(update) = self.get_channel_update(&chan) {
+		let shutdown_res = chan.force_shutdown();
+		// The first of the local transactions is always the commitment transaction itself, any
+		// others are HTLC transactions spending it.
+		let commitment_tx = shutdown_res.0.first().cloned();
+		self.finish_force_close_channel(shutdown_res);
+		if let Ok(update) = self.get_channel_update_for_broadcast(&chan) {
 			let mut channel_state = self.channel_state.lock().unwrap();
 			channel_state.pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
 				msg: update
 			});
 		}
+
+		match commitment_tx {
+			Some(tx) => {
+				let txid = tx.txid();
+				for (idx, output) in tx.output.iter().enumerate() {
+					self.chain_monitor.install_watch_outpoint((txid, idx as u32), &output.script_pubkey);
+				}
+				Ok(tx)
+			},
+			None => Err(HandleError{err: "Channel had no commitment transaction to broadcast", action: None}),
+		}
 	}
 
 	/// Force close all channels, immediately broadcasting the latest local commitment transaction
 	/// for each to the chain and rejecting new HTLCs on each.
 	pub fn force_close_all_channels(&self) {
 		for chan in self.list_channels() {
-			self.force_close_channel(&chan.channel_id);
+			let _ = self.force_close_channel(&chan.channel_id);
 		}
 	}
 
+	/// Generates a fresh channel_update, with a bumped timestamp, for each of our usable, announced
+	/// channels and queues it for broadcast. This is useful after changing fee policy or when you
+	/// otherwise wish to re-announce all of your channels at once.
+	///
+	/// Each channel gets exactly one new channel_update per call, and since the timestamp is bumped
+	/// each time, no two channel_updates we generate for a channel ever share a timestamp.
+	///
+	/// Note that ChannelManager does not track our node's addresses, so if those changed you must
+	/// also generate and broadcast a new node_announcement yourself.
+	pub fn broadcast_fresh_channel_updates(&self) {
+		let _ = self.total_consistency_lock.read().unwrap();
+
+		let mut channel_state_lock = self.channel_state.lock().unwrap();
+		let channel_state = channel_state_lock.borrow_parts();
+		for (_, chan) in channel_state.by_id.iter_mut() {
+			if !chan.should_announce() || !chan.is_usable() {
+				continue;
+			}
+			chan.bump_channel_update_count();
+			if let Ok(update) = self.get_channel_update(chan) {
+				channel_state.pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
+					msg: update
+				});
+			}
+		}
+	}
+
+	/// Updates the forwarding policy and limits of the given channel to those in config, and, if
+	/// the channel is usable and announced, queues a freshly-signed channel_update advertising the
+	/// new policy for broadcast.
+	///
+	/// Only the fields of ChannelConfig which may change at runtime are applied, see
+	/// Channel::update_config.
+	///
+	/// Raises APIError::APIMisuseError if config.cltv_expiry_delta is too small for us to safely
+	/// time out forwarded HTLCs on-chain.
+	pub fn update_channel_config(&self, channel_id: &[u8; 32], config: &ChannelConfig) -> Result<(), APIError> {
+		if config.cltv_expiry_delta < MIN_CLTV_EXPIRY_DELTA {
+			return Err(APIError::APIMisuseError { err: "cltv_expiry_delta is too small to safely forward HTLCs" });
+		}
+
+		let _ = self.total_consistency_lock.read().unwrap();
+
+		let mut channel_state_lock = self.channel_state.lock().unwrap();
+		let channel_state = channel_state_lock.borrow_parts();
+		let chan = match channel_state.by_id.get_mut(channel_id) {
+			Some(chan) => chan,
+			None => return Err(APIError::ChannelUnavailable{err: "No such channel"}),
+		};
+		chan.update_config(config);
+		chan.bump_channel_update_count();
+		if chan.should_announce() && chan.is_usable() {
+			if let Ok(update) = self.get_channel_update(chan) {
+				channel_state.pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
+					msg: update
+				});
+			}
+		}
+		Ok(())
+	}
+
 	const ZERO:[u8; 65] = [0; 65];
 	fn decode_update_add_htlc_onion(&self, msg: &msgs::UpdateAddHTLC) -> (PendingHTLCStatus, MutexGuard<ChannelHolder>) {
 		macro_rules! return_malformed_err {
@@ -972,7 +1346,7 @@ impl ChannelManager {
 					if fee.is_none() || msg.amount_msat < fee.unwrap() || (msg.amount_msat - fee.unwrap()) < *amt_to_forward { // fee_insufficient
 						break Some(("Prior hop has deviated from specified fees parameters or origin node has obsolete ones", 0x1000 | 12, Some(self.get_channel_update(chan).unwrap())));
 					}
-					if (msg.cltv_expiry as u64) < (*outgoing_cltv_value) as u64 + CLTV_EXPIRY_DELTA as u64 { // incorrect_cltv_expiry
+					if (msg.cltv_expiry as u64) < (*outgoing_cltv_value) as u64 + chan.get_cltv_expiry_delta() as u64 { // incorrect_cltv_expiry
 						break Some(("Forwarding node has tampered with the intended HTLC values or origin node has an obsolete cltv_expiry_delta", 0x1000 | 13, Some(self.get_channel_update(chan).unwrap())));
 					}
 					let cur_height = self.latest_block_height.load(Ordering::Acquire) as u32 + 1;
@@ -1007,6 +1381,25 @@ impl ChannelManager {
 		(pending_forward_info, channel_state.unwrap())
 	}
 
+	/// Gets a channel_update for the given channel which may be broadcast to the network, failing
+	/// for channels which aren't announced so as not to reveal them.
+	fn get_channel_update_for_broadcast(&self, chan: &Channel) -> Result<msgs::ChannelUpdate, HandleError> {
+		if !chan.should_announce() {
+			return Err(HandleError{err: "Cannot broadcast a channel_update for a private channel", action: None});
+		}
+		self.get_channel_update(chan)
+	}
+
+	/// Gets the channel_update for a private channel which we send directly to our counterparty
+	/// once funding_locked has been exchanged in both directions. As it's never broadcast, this is
+	/// the only way they learn our forwarding policy for it (eg to advertise it in route hints).
+	fn get_private_channel_update(&self, chan: &Channel) -> Option<msgs::ChannelUpdate> {
+		if chan.should_announce() || !chan.is_usable() {
+			return None;
+		}
+		self.get_channel_update(chan).ok()
+	}
+
 	/// only fails if the channel does not yet have an assigned short_id
 	/// May be called with channel_state already locked!
 	fn get_channel_update(&self, chan: &Channel) -> Result<msgs::ChannelUpdate, HandleError> {
@@ -1022,7 +1415,7 @@ impl ChannelManager {
 			short_channel_id: short_channel_id,
 			timestamp: chan.get_channel_update_count(),
 			flags: (!were_node_one) as u16 | ((!chan.is_live() as u16) << 1),
-			cltv_expiry_delta: CLTV_EXPIRY_DELTA,
+			cltv_expiry_delta: chan.get_cltv_expiry_delta(),
 			htlc_minimum_msat: chan.get_our_htlc_minimum_msat(),
 			fee_base_msat: chan.get_our_fee_base_msat(&*self.fee_estimator),
 			fee_proportional_millionths: chan.get_fee_proportional_millionths(),
@@ -1060,11 +1453,26 @@ impl ChannelManager {
 	/// relevant updates.
 	///
 	/// In case of APIError::RouteError/APIError::ChannelUnavailable, the payment send has failed
-	/// and you may wish to retry via a different route immediately.
+	/// and you may wish to retry via a different route immediately. This includes the case where
+	/// the first hop channel lacks the outbound capacity for the amount plus all route fees.
 	/// In case of APIError::MonitorUpdateFailed, the commitment update has been irrevocably
 	/// committed on our end and we're just waiting for a monitor update to send it. Do NOT retry
 	/// the payment via a different route unless you intend to pay twice!
 	pub fn send_payment(&self, route: Route, payment_hash: PaymentHash) -> Result<(), APIError> {
+		self.send_payment_internal(route, payment_hash, None)
+	}
+
+	/// Sends a payment exactly as send_payment does, except that the onion session key is derived
+	/// from session_seed and payment_hash instead of being fetched from the KeysInterface. Sending
+	/// the same payment_hash with the same seed and route thus always builds the same onion, which
+	/// is useful for tests and for making retries of a single payment attempt idempotent.
+	///
+	/// session_seed must be kept secret and should not be reused across distinct payment attempts.
+	pub fn send_payment_with_session_seed(&self, route: Route, payment_hash: PaymentHash, session_seed: &[u8; 32]) -> Result<(), APIError> {
+		self.send_payment_internal(route, payment_hash, Some(session_seed))
+	}
+
+	fn send_payment_internal(&self, route: Route, payment_hash: PaymentHash, session_seed: Option<&[u8; 32]>) -> Result<(), APIError> {
 		if route.hops.len() < 1 || route.hops.len() > 20 {
 			return Err(APIError::RouteError{err: "Route didn't go anywhere/had bogus size"});
 		}
@@ -1075,14 +1483,17 @@ impl ChannelManager {
 			}
 		}
 
-		let session_priv = self.keys_manager.get_session_key();
+		let session_priv = match session_seed {
+			Some(seed) => onion_utils::derive_session_key(seed, &payment_hash),
+			None => self.keys_manager.get_session_key(),
+		};
 
 		let cur_height = self.latest_block_height.load(Ordering::Acquire) as u32 + 1;
 
 		let onion_keys = secp_call!(onion_utils::construct_onion_keys(&self.secp_ctx, &route, &session_priv),
 				APIError::RouteError{err: "Pubkey along hop was maliciously selected"});
 		let (onion_payloads, htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(&route, cur_height)?;
-		let onion_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, &payment_hash);
+		let onion_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, &payment_hash)?;
 
 		let _ = self.total_consistency_lock.read().unwrap();
 
@@ -1103,6 +1514,12 @@ impl ChannelManager {
 					if !chan.get().is_live() {
 						return Err(APIError::ChannelUnavailable{err: "Peer for first hop currently disconnected/pending monitor update!"});
 					}
+					if chan.get().at_their_max_accepted_htlcs() {
+						return Err(APIError::ChannelUnavailable{err: "Cannot push more than their max accepted HTLCs"});
+					}
+					if chan.get().outbound_capacity_msat() < htlc_msat {
+						return Err(APIError::ChannelUnavailable{err: "Insufficient outbound capacity on first hop for payment amount plus fees"});
+					}
 					break_chan_entry!(self, chan.get_mut().send_htlc_and_commit(htlc_msat, payment_hash.clone(), htlc_cltv, HTLCSource::OutboundRoute {
 						route: route.clone(),
 						session_priv: session_priv.clone(),
@@ -1269,6 +1686,7 @@ impl ChannelManager {
 		{
 			let mut channel_state_lock = self.channel_state.lock().unwrap();
 			let channel_state = channel_state_lock.borrow_parts();
+			let mut relayed_htlcs = Vec::new();
 
 			for (short_chan_id, mut pending_forwards) in channel_state.forward_htlcs.drain() {
 				if short_chan_id != 0 {
@@ -1321,6 +1739,7 @@ impl ChannelManager {
 											continue;
 										},
 										Ok(update_add) => {
+											relayed_htlcs.push((prev_short_channel_id, prev_htlc_id, forward_info.payment_hash, short_chan_id));
 											match update_add {
 												Some(msg) => { add_htlc_msgs.push(msg); },
 												None => {
@@ -1421,6 +1840,29 @@ impl ChannelManager {
 					}
 				}
 			}
+
+			// Only now that we're done with the outbound channels can we look up the incoming HTLCs
+			for (prev_short_channel_id, prev_htlc_id, payment_hash, outgoing_short_channel_id) in relayed_htlcs.drain(..) {
+				let incoming_channel_id = match channel_state.short_to_id.get(&prev_short_channel_id) {
+					Some(chan_id) => chan_id.clone(),
+					// The incoming channel has closed, so the HTLC will be resolved on-chain
+					None => continue,
+				};
+				let (incoming_amount_msat, incoming_cltv_expiry) = match channel_state.by_id.get(&incoming_channel_id).and_then(|chan| chan.get_inbound_htlc_amount_and_expiry(prev_htlc_id)) {
+					Some(htlc) => htlc,
+					None => continue,
+				};
+				if channel_state.pending_forwards.add(PendingForwardHTLC {
+					incoming_channel_id,
+					incoming_htlc_id: prev_htlc_id,
+					payment_hash,
+					incoming_amount_msat,
+					incoming_cltv_expiry,
+					outgoing_short_channel_id,
+				}).is_err() {
+					log_trace!(self, "Relayed HTLC with payment_hash {} from short id {} was already being tracked", log_bytes!(payment_hash.0), prev_short_channel_id);
+				}
+			}
 		}
 
 		for (htlc_source, payment_hash, failure_code, update) in failed_forwards.drain(..) {
@@ -1490,9 +1932,9 @@ impl ChannelManager {
 				match &onion_error {
 					&HTLCFailReason::ErrorPacket { ref err } => {
 #[cfg(test)]
-						let (channel_update, payment_retryable, onion_error_code) = onion_utils::process_onion_failure(&self.secp_ctx, &self.logger, &source, err.data.clone());
+						let (channel_update, payment_retryable, onion_error_code, failed_short_channel_id) = onion_utils::process_onion_failure(&self.secp_ctx, &self.logger, &source, err.data.clone());
 #[cfg(not(test))]
-						let (channel_update, payment_retryable, _) = onion_utils::process_onion_failure(&self.secp_ctx, &self.logger, &source, err.data.clone());
+						let (channel_update, payment_retryable, _, failed_short_channel_id) = onion_utils::process_onion_failure(&self.secp_ctx, &self.logger, &source, err.data.clone());
 						// TODO: If we decided to blame ourselves (or one of our channels) in
 						// process_onion_failure we should close that channel as it implies our
 						// next-hop is needlessly blaming us!
@@ -1507,6 +1949,7 @@ impl ChannelManager {
 							events::Event::PaymentFailed {
 								payment_hash: payment_hash.clone(),
 								rejected_by_dest: !payment_retryable,
+								failed_short_channel_id,
 #[cfg(test)]
 								error_code: onion_error_code
 							}
@@ -1527,6 +1970,7 @@ impl ChannelManager {
 							events::Event::PaymentFailed {
 								payment_hash: payment_hash.clone(),
 								rejected_by_dest: route.hops.len() == 1,
+								failed_short_channel_id: None,
 #[cfg(test)]
 								error_code: Some(*failure_code),
 							}
@@ -1535,6 +1979,9 @@ impl ChannelManager {
 				}
 			},
 			HTLCSource::PreviousHopData(HTLCPreviousHopData { short_channel_id, htlc_id, incoming_packet_shared_secret }) => {
+				if let Some(chan_id) = channel_state_lock.short_to_id.get(&short_channel_id).cloned() {
+					channel_state_lock.pending_forwards.resolve(&chan_id, htlc_id);
+				}
 				let err_packet = match onion_error {
 					HTLCFailReason::Reason { failure_code, data } => {
 						log_trace!(self, "Failing HTLC with payment_hash {} backwards from us with code {}", log_bytes!(payment_hash.0), failure_code);
@@ -1570,13 +2017,69 @@ impl ChannelManager {
 		}
 	}
 
+	/// Creates a signed BOLT 11 invoice for a payment to us of amount_msat, or of any amount if
+	/// None, timestamped with the current system time and expiring expiry_secs later.
+	///
+	/// See create_invoice_with_time for details.
+	pub fn create_invoice(&self, amount_msat: Option<u64>, description: String, expiry_secs: u32) -> Result<Invoice, HandleError> {
+		let now_unix = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs();
+		self.create_invoice_with_time(amount_msat, description, expiry_secs, now_unix)
+	}
+
+	/// Creates a signed BOLT 11 invoice for a payment to us of amount_msat, or of any amount if
+	/// None, created at now_unix (in seconds since the UNIX epoch) and expiring expiry_secs later.
+	///
+	/// A fresh payment preimage is generated and remembered, so that once the payment arrives (as
+	/// an Event::PaymentReceived) it can be fetched with get_invoice_preimage and passed to
+	/// claim_funds. Preimages of invoices which expired before now_unix and have no payment
+	/// pending are forgotten.
+	///
+	/// Our private channels are included as route hints, see get_route_hints.
+	pub fn create_invoice_with_time(&self, amount_msat: Option<u64>, description: String, expiry_secs: u32, now_unix: u64) -> Result<Invoice, HandleError> {
+		let network = [Network::Bitcoin, Network::Testnet, Network::Regtest].iter()
+			.find(|network| genesis_block(**network).header.bitcoin_hash() == self.genesis_hash).cloned();
+		let currency = match network {
+			Some(network) => Currency::from_network(network),
+			None => return Err(HandleError{err: "Cannot create invoices for an unknown chain", action: None}),
+		};
+
+		let _ = self.total_consistency_lock.read().unwrap();
+
+		let payment_preimage = PaymentPreimage(self.keys_manager.get_payment_preimage());
+		let payment_hash = payment_hash(&payment_preimage);
+		let mut builder = InvoiceBuilder::new(currency, payment_hash, now_unix, description)
+			.expiry_time(expiry_secs as u64)
+			.min_final_cltv_expiry((CLTV_CLAIM_BUFFER + LATENCY_GRACE_PERIOD_BLOCKS) as u64);
+		if let Some(amount_msat) = amount_msat {
+			builder = builder.amount_msat(amount_msat);
+		}
+		for hint in self.get_route_hints(amount_msat).drain(..) {
+			builder = builder.route_hint(vec![hint]);
+		}
+		let invoice = builder.build_signed(&self.our_network_key)?;
+
+		let channel_state = self.channel_state.lock().unwrap();
+		let mut invoice_preimages = self.invoice_preimages.lock().unwrap();
+		invoice_preimages.retain(|payment_hash, &mut (_, expires_at)| {
+			expires_at >= now_unix || channel_state.claimable_htlcs.contains_key(payment_hash)
+		});
+		invoice_preimages.insert(payment_hash, (payment_preimage, now_unix + expiry_secs as u64));
+		Ok(invoice)
+	}
+
+	/// Gets the preimage for a payment we created an invoice for with create_invoice and haven't
+	/// yet claimed, eg to pass to claim_funds in response to a PaymentReceived event.
+	pub fn get_invoice_preimage(&self, payment_hash: &PaymentHash) -> Option<PaymentPreimage> {
+		self.invoice_preimages.lock().unwrap().get(payment_hash).map(|&(payment_preimage, _)| payment_preimage)
+	}
+
 	/// Provides a payment preimage in response to a PaymentReceived event, returning true and
 	/// generating message events for the net layer to claim the payment, if possible. Thus, you
 	/// should probably kick the net layer to go send messages if this returns true!
 	///
 	/// May panic if called except in response to a PaymentReceived event.
 	pub fn claim_funds(&self, payment_preimage: PaymentPreimage) -> bool {
-		let payment_hash = PaymentHash(Sha256::hash(&payment_preimage.0).into_inner());
+		let payment_hash = payment_hash(&payment_preimage);
 
 		let _ = self.total_consistency_lock.read().unwrap();
 
@@ -1590,6 +2093,7 @@ impl ChannelManager {
 				if channel_state.is_none() { channel_state = Some(self.channel_state.lock().unwrap()); }
 				self.claim_funds_internal(channel_state.take().unwrap(), HTLCSource::PreviousHopData(htlc_with_hash), payment_preimage);
 			}
+			self.invoice_preimages.lock().unwrap().remove(&payment_hash);
 			true
 		} else { false }
 	}
@@ -1616,6 +2120,7 @@ impl ChannelManager {
 							return
 						}
 					};
+					channel_state.pending_forwards.resolve(&chan_id, htlc_id);
 
 					if let hash_map::Entry::Occupied(mut chan) = channel_state.by_id.entry(chan_id) {
 						let was_frozen_for_monitor = chan.get().is_awaiting_monitor_update();
@@ -1709,7 +2214,7 @@ impl ChannelManager {
 									short_to_id.remove(&short_id);
 								}
 								close_results.push(channel.force_shutdown());
-								if let Ok(update) = self.get_channel_update(&channel) {
+								if let Ok(update) = self.get_channel_update_for_broadcast(&channel) {
 									pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
 										msg: update
 									});
@@ -1768,6 +2273,12 @@ impl ChannelManager {
 									msg: announcement_sigs,
 								});
 							}
+							if let Some(update) = self.get_private_channel_update(channel) {
+								pending_msg_events.push(events::MessageSendEvent::SendChannelUpdate {
+									node_id: channel.get_their_node_id(),
+									msg: update,
+								});
+							}
 							short_to_id.insert(channel.get_short_channel_id().unwrap(), channel.channel_id());
 						}
 						true
@@ -1793,6 +2304,13 @@ impl ChannelManager {
 			return Err(MsgHandleErrInternal::send_err_msg_no_close("Unknown genesis block hash", msg.temporary_channel_id.clone()));
 		}
 
+		Channel::check_open_channel(&*self.fee_estimator, msg, &self.default_configuration)
+			.map_err(|e| MsgHandleErrInternal::from_chan_no_close(e, msg.temporary_channel_id))?;
+		// Consult the acceptor before new_from_req so that rejected channels never derive keys
+		if let Some(ref acceptor) = *self.inbound_channel_acceptor.read().unwrap() {
+			acceptor.accept_inbound_channel(their_node_id, msg)
+				.map_err(|e| MsgHandleErrInternal::send_err_msg_no_close(e, msg.temporary_channel_id))?;
+		}
 		let channel = Channel::new_from_req(&*self.fee_estimator, &self.keys_manager, their_node_id.clone(), their_local_features, msg, 0, Arc::clone(&self.logger), &self.default_configuration)
 			.map_err(|e| MsgHandleErrInternal::from_chan_no_close(e, msg.temporary_channel_id))?;
 		let mut channel_state_lock = self.channel_state.lock().unwrap();
@@ -1932,6 +2450,12 @@ impl ChannelManager {
 						msg: announcement_sigs,
 					});
 				}
+				if let Some(update) = self.get_private_channel_update(chan.get()) {
+					channel_state.pending_msg_events.push(events::MessageSendEvent::SendChannelUpdate {
+						node_id: their_node_id.clone(),
+						msg: update,
+					});
+				}
 				Ok(())
 			},
 			hash_map::Entry::Vacant(_) => Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
@@ -1976,7 +2500,7 @@ impl ChannelManager {
 			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), htlc_source.0, &htlc_source.1, HTLCFailReason::Reason { failure_code: 0x4000 | 8, data: Vec::new() });
 		}
 		if let Some(chan) = chan_option {
-			if let Ok(update) = self.get_channel_update(&chan) {
+			if let Ok(update) = self.get_channel_update_for_broadcast(&chan) {
 				let mut channel_state = self.channel_state.lock().unwrap();
 				channel_state.pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
 					msg: update
@@ -2022,7 +2546,7 @@ impl ChannelManager {
 			self.tx_broadcaster.broadcast_transaction(&broadcast_tx);
 		}
 		if let Some(chan) = chan_option {
-			if let Ok(update) = self.get_channel_update(&chan) {
+			if let Ok(update) = self.get_channel_update_for_broadcast(&chan) {
 				let mut channel_state = self.channel_state.lock().unwrap();
 				channel_state.pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
 					msg: update
@@ -2051,24 +2575,31 @@ impl ChannelManager {
 					//TODO: here MsgHandleErrInternal, #153 case
 					return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 				}
-				if !chan.get().is_usable() {
-					// If the update_add is completely bogus, the call will Err and we will close,
-					// but if we've sent a shutdown and they haven't acknowledged it yet, we just
-					// want to reject the new HTLC and fail it backwards instead of forwarding.
+				// If the update_add is completely bogus, the call will Err and we will close, but if
+				// we've sent a shutdown and they haven't acknowledged it yet, or the HTLC would push
+				// our dust HTLC exposure over our limit, we just want to reject the new HTLC and fail
+				// it backwards instead of forwarding.
+				// TODO: Note that |20 is defined as "channel FROM the processing node has been
+				// disabled" (emphasis mine), which seems to imply that we can't return |20 for an
+				// inbound channel being disabled. This probably needs a spec update but should
+				// definitely be allowed.
+				let failure_code = if !chan.get().is_usable() {
+					Some(0x1000|20)
+				} else if chan.get().would_exceed_dust_htlc_exposure(msg.amount_msat, false) {
+					Some(0x1000|7)
+				} else { None };
+				if let Some(failure_code) = failure_code {
 					if let PendingHTLCStatus::Forward(PendingForwardHTLCInfo { incoming_shared_secret, .. }) = pending_forward_info {
 						let chan_update = self.get_channel_update(chan.get());
 						pending_forward_info = PendingHTLCStatus::Fail(HTLCFailureMsg::Relay(msgs::UpdateFailHTLC {
 							channel_id: msg.channel_id,
 							htlc_id: msg.htlc_id,
 							reason: if let Ok(update) = chan_update {
-								// TODO: Note that |20 is defined as "channel FROM the processing
-								// node has been disabled" (emphasis mine), which seems to imply
-								// that we can't return |20 for an inbound channel being disabled.
-								// This probably needs a spec update but should definitely be
-								// allowed.
-								onion_utils::build_first_hop_failure_packet(&incoming_shared_secret, 0x1000|20, &{
+								onion_utils::build_first_hop_failure_packet(&incoming_shared_secret, failure_code, &{
 									let mut res = Vec::with_capacity(8 + 128);
-									res.extend_from_slice(&byte_utils::be16_to_array(update.contents.flags));
+									if failure_code == 0x1000|20 {
+										res.extend_from_slice(&byte_utils::be16_to_array(update.contents.flags));
+									}
 									res.extend_from_slice(&update.encode_with_len()[..]);
 									res
 								}[..])
@@ -2458,6 +2989,59 @@ impl ChannelManager {
 			},
 		}
 	}
+
+	/// Handles a batch of channel messages from the given peer, eg all the complete messages which
+	/// were read from its socket at once. This is equivalent to calling the relevant
+	/// ChannelMessageHandler method for each message in order, except that the whole batch is
+	/// processed under one hold of our consistency lock, so a set of updates and the
+	/// commitment_signed which commits to them are never observed (or serialized) half-applied.
+	///
+	/// The resulting MessageSendEvents and Events are queued as usual and may all be fetched with
+	/// a single call to get_and_clear_pending_msg_events/get_and_clear_pending_events afterwards.
+	///
+	/// Only messages which are handled by ChannelMessageHandler without additional context may be
+	/// included - open_channel and accept_channel require the peer's Init features, and error
+	/// messages (which may close channels) must be handled individually, as must non-channel
+	/// messages. If any such message is present the batch is rejected before any of it is
+	/// processed. Otherwise, processing stops at the first message which returns an Err, which is
+	/// returned (earlier messages in the batch remain applied).
+	pub fn handle_messages(&self, their_node_id: &PublicKey, msgs: &[msgs::Message]) -> Result<(), HandleError> {
+		for msg in msgs.iter() {
+			match msg {
+				&msgs::Message::FundingCreated(_) | &msgs::Message::FundingSigned(_) | &msgs::Message::FundingLocked(_) |
+				&msgs::Message::Shutdown(_) | &msgs::Message::ClosingSigned(_) |
+				&msgs::Message::UpdateAddHTLC(_) | &msgs::Message::UpdateFulfillHTLC(_) | &msgs::Message::UpdateFailHTLC(_) |
+				&msgs::Message::UpdateFailMalformedHTLC(_) | &msgs::Message::CommitmentSigned(_) | &msgs::Message::RevokeAndACK(_) |
+				&msgs::Message::UpdateFee(_) | &msgs::Message::AnnouncementSignatures(_) | &msgs::Message::ChannelReestablish(_) => {},
+				_ => return Err(HandleError {
+					err: "Batches may only contain channel messages which need no peer context",
+					action: Some(msgs::ErrorAction::IgnoreError),
+				}),
+			}
+		}
+
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
+		for msg in msgs.iter() {
+			match msg {
+				&msgs::Message::FundingCreated(ref msg) => handle_error!(self, self.internal_funding_created(their_node_id, msg))?,
+				&msgs::Message::FundingSigned(ref msg) => handle_error!(self, self.internal_funding_signed(their_node_id, msg))?,
+				&msgs::Message::FundingLocked(ref msg) => handle_error!(self, self.internal_funding_locked(their_node_id, msg))?,
+				&msgs::Message::Shutdown(ref msg) => handle_error!(self, self.internal_shutdown(their_node_id, msg))?,
+				&msgs::Message::ClosingSigned(ref msg) => handle_error!(self, self.internal_closing_signed(their_node_id, msg))?,
+				&msgs::Message::UpdateAddHTLC(ref msg) => handle_error!(self, self.internal_update_add_htlc(their_node_id, msg))?,
+				&msgs::Message::UpdateFulfillHTLC(ref msg) => handle_error!(self, self.internal_update_fulfill_htlc(their_node_id, msg))?,
+				&msgs::Message::UpdateFailHTLC(ref msg) => handle_error!(self, self.internal_update_fail_htlc(their_node_id, msg))?,
+				&msgs::Message::UpdateFailMalformedHTLC(ref msg) => handle_error!(self, self.internal_update_fail_malformed_htlc(their_node_id, msg))?,
+				&msgs::Message::CommitmentSigned(ref msg) => handle_error!(self, self.internal_commitment_signed(their_node_id, msg))?,
+				&msgs::Message::RevokeAndACK(ref msg) => handle_error!(self, self.internal_revoke_and_ack(their_node_id, msg))?,
+				&msgs::Message::UpdateFee(ref msg) => handle_error!(self, self.internal_update_fee(their_node_id, msg))?,
+				&msgs::Message::AnnouncementSignatures(ref msg) => handle_error!(self, self.internal_announcement_signatures(their_node_id, msg))?,
+				&msgs::Message::ChannelReestablish(ref msg) => handle_error!(self, self.internal_channel_reestablish(their_node_id, msg))?,
+				_ => unreachable!(),
+			}
+		}
+		Ok(())
+	}
 }
 
 impl events::MessageSendEventsProvider for ChannelManager {
@@ -2534,6 +3118,12 @@ impl ChainListener for ChannelManager {
 							msg: announcement_sigs,
 						});
 					}
+					if let Some(update) = self.get_private_channel_update(channel) {
+						pending_msg_events.push(events::MessageSendEvent::SendChannelUpdate {
+							node_id: channel.get_their_node_id(),
+							msg: update,
+						});
+					}
 					short_to_id.insert(channel.get_short_channel_id().unwrap(), channel.channel_id());
 				} else if let Err(e) = chan_res {
 					pending_msg_events.push(events::MessageSendEvent::HandleError {
@@ -2554,7 +3144,7 @@ impl ChainListener for ChannelManager {
 								// broadcast our latest local state as well here, just in case its
 								// some kind of SPV attack, though we expect these to be dropped.
 								failed_channels.push(channel.force_shutdown());
-								if let Ok(update) = self.get_channel_update(&channel) {
+								if let Ok(update) = self.get_channel_update_for_broadcast(&channel) {
 									pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
 										msg: update
 									});
@@ -2564,6 +3154,12 @@ impl ChainListener for ChannelManager {
 						}
 					}
 				}
+				for (payment_hash, _) in channel.get_outbound_htlcs_expiring_before(height) {
+					log_warn!(self, "Outbound HTLC with payment hash {} in channel {} is about to time out", log_bytes!(payment_hash.0), log_bytes!(channel.channel_id()));
+				}
+				for (payment_hash, _) in channel.get_claimable_inbound_htlcs_expiring_before(height) {
+					log_warn!(self, "Inbound HTLC with payment hash {} in channel {}, which we hold the preimage for, is about to time out", log_bytes!(payment_hash.0), log_bytes!(channel.channel_id()));
+				}
 				if channel.is_funding_initiated() && channel.channel_monitor().would_broadcast_at_height(height) {
 					if let Some(short_id) = channel.get_short_channel_id() {
 						short_to_id.remove(&short_id);
@@ -2573,7 +3169,7 @@ impl ChainListener for ChannelManager {
 					// the latest local tx for us, so we should skip that here (it doesn't really
 					// hurt anything, but does make tests a bit simpler).
 					failed_channels.last_mut().unwrap().0 = Vec::new();
-					if let Ok(update) = self.get_channel_update(&channel) {
+					if let Ok(update) = self.get_channel_update_for_broadcast(&channel) {
 						pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
 							msg: update
 						});
@@ -2605,7 +3201,7 @@ impl ChainListener for ChannelManager {
 						short_to_id.remove(&short_id);
 					}
 					failed_channels.push(v.force_shutdown());
-					if let Ok(update) = self.get_channel_update(&v) {
+					if let Ok(update) = self.get_channel_update_for_broadcast(&v) {
 						pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
 							msg: update
 						});
@@ -2706,6 +3302,11 @@ impl ChannelMessageHandler for ChannelManager {
 		handle_error!(self, self.internal_channel_reestablish(their_node_id, msg))
 	}
 
+	fn handle_channel_update(&self, their_node_id: &PublicKey, msg: &msgs::ChannelUpdate) -> Result<(), HandleError> {
+		let _ = self.total_consistency_lock.read().unwrap();
+		self.internal_channel_update(their_node_id, msg)
+	}
+
 	fn peer_disconnected(&self, their_node_id: &PublicKey, no_connection_possible: bool) {
 		let _ = self.total_consistency_lock.read().unwrap();
 		let mut failed_channels = Vec::new();
@@ -2723,7 +3324,7 @@ impl ChannelMessageHandler for ChannelManager {
 							short_to_id.remove(&short_id);
 						}
 						failed_channels.push(chan.force_shutdown());
-						if let Ok(update) = self.get_channel_update(&chan) {
+						if let Ok(update) = self.get_channel_update_for_broadcast(&chan) {
 							pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
 								msg: update
 							});
@@ -2766,6 +3367,7 @@ impl ChannelMessageHandler for ChannelManager {
 					&events::MessageSendEvent::SendClosingSigned { ref node_id, .. } => node_id != their_node_id,
 					&events::MessageSendEvent::SendShutdown { ref node_id, .. } => node_id != their_node_id,
 					&events::MessageSendEvent::SendChannelReestablish { ref node_id, .. } => node_id != their_node_id,
+					&events::MessageSendEvent::SendChannelUpdate { ref node_id, .. } => node_id != their_node_id,
 					&events::MessageSendEvent::BroadcastChannelAnnouncement { .. } => true,
 					&events::MessageSendEvent::BroadcastChannelUpdate { .. } => true,
 					&events::MessageSendEvent::HandleError { ref node_id, .. } => node_id != their_node_id,
@@ -2816,17 +3418,17 @@ impl ChannelMessageHandler for ChannelManager {
 		if msg.channel_id == [0; 32] {
 			for chan in self.list_channels() {
 				if chan.remote_network_id == *their_node_id {
-					self.force_close_channel(&chan.channel_id);
+					let _ = self.force_close_channel(&chan.channel_id);
 				}
 			}
 		} else {
-			self.force_close_channel(&msg.channel_id);
+			let _ = self.force_close_channel(&msg.channel_id);
 		}
 	}
 }
 
-const SERIALIZATION_VERSION: u8 = 1;
-const MIN_SERIALIZATION_VERSION: u8 = 1;
+const SERIALIZATION_VERSION: u8 = 3;
+const MIN_SERIALIZATION_VERSION: u8 = 2;
 
 impl Writeable for PendingForwardHTLCInfo {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
@@ -2905,6 +3507,15 @@ impl<R: ::std::io::Read> Readable<R> for PendingHTLCStatus {
 	}
 }
 
+impl_writeable!(PendingForwardHTLC, 32+8+32+8+4+8, {
+	incoming_channel_id,
+	incoming_htlc_id,
+	payment_hash,
+	incoming_amount_msat,
+	incoming_cltv_expiry,
+	outgoing_short_channel_id
+});
+
 impl_writeable!(HTLCPreviousHopData, 0, {
 	short_channel_id,
 	htlc_id,
@@ -3053,6 +3664,19 @@ impl Writeable for ChannelManager {
 			}
 		}
 
+		let invoice_preimages = self.invoice_preimages.lock().unwrap();
+		(invoice_preimages.len() as u64).write(writer)?;
+		for (payment_hash, &(ref payment_preimage, expires_at)) in invoice_preimages.iter() {
+			payment_hash.write(writer)?;
+			payment_preimage.write(writer)?;
+			expires_at.write(writer)?;
+		}
+
+		(channel_state.pending_forwards.len() as u64).write(writer)?;
+		for htlc in channel_state.pending_forwards.htlcs.values() {
+			htlc.write(writer)?;
+		}
+
 		Ok(())
 	}
 }
@@ -3118,7 +3742,7 @@ pub struct ChannelManagerReadArgs<'a> {
 
 impl<'a, R : ::std::io::Read> ReadableArgs<R, ChannelManagerReadArgs<'a>> for (Sha256dHash, ChannelManager) {
 	fn read(reader: &mut R, args: ChannelManagerReadArgs<'a>) -> Result<Self, DecodeError> {
-		let _ver: u8 = Readable::read(reader)?;
+		let ver: u8 = Readable::read(reader)?;
 		let min_ver: u8 = Readable::read(reader)?;
 		if min_ver > SERIALIZATION_VERSION {
 			return Err(DecodeError::UnknownVersion);
@@ -3190,6 +3814,25 @@ impl<'a, R : ::std::io::Read> ReadableArgs<R, ChannelManagerReadArgs<'a>> for (S
 			claimable_htlcs.insert(payment_hash, previous_hops);
 		}
 
+		// Version 1 managers predate create_invoice and so have no invoice preimages
+		let invoice_preimages_count: u64 = if ver >= 2 { Readable::read(reader)? } else { 0 };
+		let mut invoice_preimages = HashMap::with_capacity(cmp::min(invoice_preimages_count as usize, 128));
+		for _ in 0..invoice_preimages_count {
+			let payment_hash = Readable::read(reader)?;
+			let payment_preimage = Readable::read(reader)?;
+			let expires_at = Readable::read(reader)?;
+			invoice_preimages.insert(payment_hash, (payment_preimage, expires_at));
+		}
+
+		// Version 2 managers didn't track relayed HTLCs
+		let pending_forwards_count: u64 = if ver >= 3 { Readable::read(reader)? } else { 0 };
+		let mut pending_forwards = PendingForwardHTLCs::new();
+		for _ in 0..pending_forwards_count {
+			if pending_forwards.add(Readable::read(reader)?).is_err() {
+				return Err(DecodeError::InvalidValue);
+			}
+		}
+
 		let channel_manager = ChannelManager {
 			genesis_hash,
 			fee_estimator: args.fee_estimator,
@@ -3206,11 +3849,14 @@ impl<'a, R : ::std::io::Read> ReadableArgs<R, ChannelManagerReadArgs<'a>> for (S
 				short_to_id,
 				forward_htlcs,
 				claimable_htlcs,
+				pending_forwards,
 				pending_msg_events: Vec::new(),
 			}),
 			our_network_key: args.keys_manager.get_node_secret(),
 
 			pending_events: Mutex::new(Vec::new()),
+			invoice_preimages: Mutex::new(invoice_preimages),
+			inbound_channel_acceptor: RwLock::new(None),
 			total_consistency_lock: RwLock::new(()),
 			keys_manager: args.keys_manager,
 			logger: args.logger,
@@ -3226,3 +3872,82 @@ impl<'a, R : ::std::io::Read> ReadableArgs<R, ChannelManagerReadArgs<'a>> for (S
 		Ok((last_block_hash.clone(), channel_manager))
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use ln::channelmanager::{can_send_htlc, payment_hash, verify_preimage, PaymentHash, PaymentPreimage, PendingForwardHTLC, PendingForwardHTLCs};
+	use ln::chan_utils::commit_tx_fee_msat;
+
+	#[test]
+	fn htlc_reserve_boundary() {
+		// As the fundee, the whole balance above the reserve may be sent
+		assert!(can_send_htlc(5_000_000, 1000, 4_000_000, 0).is_ok());
+		assert!(can_send_htlc(5_000_000, 1000, 4_000_001, 0).is_err());
+		assert!(can_send_htlc(999_999, 1000, 0, 0).is_err());
+
+		// As the funder, the commitment fee comes out of the same balance
+		let fee_msat = commit_tx_fee_msat(253, 1);
+		assert!(can_send_htlc(5_000_000, 1000, 4_000_000 - fee_msat, fee_msat).is_ok());
+		assert!(can_send_htlc(5_000_000, 1000, 4_000_001 - fee_msat, fee_msat).is_err());
+		assert!(can_send_htlc(5_000_000, 1000, 4_000_000, fee_msat).is_err());
+
+		// Huge values don't overflow into passing
+		assert!(can_send_htlc(u64::max_value() - 1, u64::max_value() / 1000, 1000, 0).is_err());
+	}
+
+	#[test]
+	fn payment_hash_helpers() {
+		let preimage = PaymentPreimage([0; 32]);
+		let hash = PaymentHash([0x66, 0x68, 0x7a, 0xad, 0xf8, 0x62, 0xbd, 0x77, 0x6c, 0x8f, 0xc1, 0x8b, 0x8e, 0x9f, 0x8e, 0x20, 0x08, 0x97, 0x14, 0x85, 0x6e, 0xe2, 0x33, 0xb3, 0x90, 0x2a, 0x59, 0x1d, 0x0d, 0x5f, 0x29, 0x25]);
+		assert_eq!(payment_hash(&preimage), hash);
+		assert!(verify_preimage(&preimage, &hash));
+
+		let mut wrong_preimage = preimage;
+		wrong_preimage.0[31] = 1;
+		assert!(!verify_preimage(&wrong_preimage, &hash));
+	}
+
+	fn forward(channel_byte: u8, htlc_id: u64, cltv_expiry: u32) -> PendingForwardHTLC {
+		PendingForwardHTLC {
+			incoming_channel_id: [channel_byte; 32],
+			incoming_htlc_id: htlc_id,
+			payment_hash: PaymentHash([htlc_id as u8; 32]),
+			incoming_amount_msat: 100000,
+			incoming_cltv_expiry: cltv_expiry,
+			outgoing_short_channel_id: 42,
+		}
+	}
+
+	#[test]
+	fn pending_forward_fulfilled() {
+		let mut pending = PendingForwardHTLCs::new();
+		pending.add(forward(1, 0, 500)).unwrap();
+		// The same htlc_id on another channel is a different HTLC
+		pending.add(forward(2, 0, 500)).unwrap();
+		assert!(pending.add(forward(1, 0, 600)).is_err());
+		assert_eq!(pending.len(), 2);
+
+		assert_eq!(pending.resolve(&[1; 32], 0), Some(forward(1, 0, 500)));
+		assert_eq!(pending.resolve(&[2; 32], 0), Some(forward(2, 0, 500)));
+		assert!(pending.is_empty());
+		assert_eq!(pending.resolve(&[1; 32], 0), None);
+		// Once resolved, the incoming HTLC id may be reused
+		pending.add(forward(1, 0, 600)).unwrap();
+	}
+
+	#[test]
+	fn pending_forward_expiry() {
+		let mut pending = PendingForwardHTLCs::new();
+		pending.add(forward(1, 0, 510)).unwrap();
+		pending.add(forward(1, 1, 500)).unwrap();
+		pending.add(forward(2, 0, 520)).unwrap();
+
+		assert!(pending.expiring_by(499).is_empty());
+		assert_eq!(pending.expiring_by(500), vec![&forward(1, 1, 500)]);
+		assert_eq!(pending.expiring_by(515), vec![&forward(1, 1, 500), &forward(1, 0, 510)]);
+
+		// A failed-back HTLC no longer needs failing
+		pending.resolve(&[1; 32], 1).unwrap();
+		assert_eq!(pending.expiring_by(600), vec![&forward(1, 0, 510), &forward(2, 0, 520)]);
+	}
+}
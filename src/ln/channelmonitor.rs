@@ -18,7 +18,6 @@ use bitcoin::blockdata::script::{Script, Builder};
 use bitcoin::blockdata::opcodes;
 use bitcoin::consensus::encode::{self, Decodable, Encodable};
 use bitcoin::util::hash::BitcoinHash;
-use bitcoin::util::bip143;
 
 use bitcoin_hashes::Hash;
 use bitcoin_hashes::sha256::Hash as Sha256;
@@ -32,7 +31,7 @@ use secp256k1;
 use ln::msgs::DecodeError;
 use ln::chan_utils;
 use ln::chan_utils::HTLCOutputInCommitment;
-use ln::channelmanager::{HTLCSource, PaymentPreimage, PaymentHash};
+use ln::channelmanager::{HTLCSource, PaymentPreimage, PaymentHash, payment_hash};
 use ln::channel::{ACCEPTED_HTLC_SCRIPT_WEIGHT, OFFERED_HTLC_SCRIPT_WEIGHT};
 use chain::chaininterface::{ChainListener, ChainWatchInterface, BroadcasterInterface, FeeEstimator, ConfirmationTarget};
 use chain::transaction::OutPoint;
@@ -122,6 +121,22 @@ pub trait ManyChannelMonitor: Send + Sync {
 	fn fetch_pending_htlc_updated(&self) -> Vec<HTLCUpdate>;
 }
 
+/// A hook which is called by SimpleManyChannelMonitor<OutPoint> every time a channel's monitor is
+/// added or updated, before add_update_monitor returns.
+///
+/// This should write the monitor (eg via write_for_disk) somewhere it will survive a restart. Any
+/// error returned is passed back to the ChannelManager, which will not proceed with the channel
+/// update which generated it (see ChannelMonitorUpdateErr for the semantics of each variant).
+///
+/// It is called with a copy of the monitor and without SimpleManyChannelMonitor's monitors lock
+/// held, so a slow write doesn't block updates to other channels or block processing. Updates to
+/// the same channel are serialized across the update and the write, so monitors for a given
+/// funding_txo are always persisted in the order they were updated.
+pub trait ChannelMonitorPersister: Send + Sync {
+	/// Persists the given (already-updated) monitor for the channel with the given funding_txo.
+	fn persist_monitor(&self, funding_txo: &OutPoint, monitor: &ChannelMonitor) -> Result<(), ChannelMonitorUpdateErr>;
+}
+
 /// A simple implementation of a ManyChannelMonitor and ChainListener. Can be used to create a
 /// watchtower or watch our own channels.
 ///
@@ -143,7 +158,11 @@ pub struct SimpleManyChannelMonitor<Key> {
 	pending_events: Mutex<Vec<events::Event>>,
 	pending_htlc_updated: Mutex<HashMap<PaymentHash, Vec<(HTLCSource, Option<PaymentPreimage>)>>>,
 	logger: Arc<Logger>,
-	fee_estimator: Arc<FeeEstimator>
+	fee_estimator: Arc<FeeEstimator>,
+	persister: Option<Arc<ChannelMonitorPersister>>,
+	/// Held from the time a monitor is updated until it has been persisted, so that two updates to
+	/// the same channel can't have their persists reordered.
+	persist_locks: Mutex<HashMap<Key, Arc<Mutex<()>>>>,
 }
 
 impl<Key : Send + cmp::Eq + hash::Hash> ChainListener for SimpleManyChannelMonitor<Key> {
@@ -214,6 +233,10 @@ impl<Key : Send + cmp::Eq + hash::Hash + 'static> SimpleManyChannelMonitor<Key>
 	/// Creates a new object which can be used to monitor several channels given the chain
 	/// interface with which to register to receive notifications.
 	pub fn new(chain_monitor: Arc<ChainWatchInterface>, broadcaster: Arc<BroadcasterInterface>, logger: Arc<Logger>, feeest: Arc<FeeEstimator>) -> Arc<SimpleManyChannelMonitor<Key>> {
+		Self::new_internal(chain_monitor, broadcaster, logger, feeest, None)
+	}
+
+	fn new_internal(chain_monitor: Arc<ChainWatchInterface>, broadcaster: Arc<BroadcasterInterface>, logger: Arc<Logger>, feeest: Arc<FeeEstimator>, persister: Option<Arc<ChannelMonitorPersister>>) -> Arc<SimpleManyChannelMonitor<Key>> {
 		let res = Arc::new(SimpleManyChannelMonitor {
 			monitors: Mutex::new(HashMap::new()),
 			chain_monitor,
@@ -222,6 +245,8 @@ impl<Key : Send + cmp::Eq + hash::Hash + 'static> SimpleManyChannelMonitor<Key>
 			pending_htlc_updated: Mutex::new(HashMap::new()),
 			logger,
 			fee_estimator: feeest,
+			persister,
+			persist_locks: Mutex::new(HashMap::new()),
 		});
 		let weak_res = Arc::downgrade(&res);
 		res.chain_monitor.register_listener(weak_res);
@@ -260,12 +285,39 @@ impl<Key : Send + cmp::Eq + hash::Hash + 'static> SimpleManyChannelMonitor<Key>
 	}
 }
 
+impl SimpleManyChannelMonitor<OutPoint> {
+	/// Creates a new object as new() does, but which hands every added or updated monitor to
+	/// persister before add_update_monitor returns, passing back any error it returns.
+	pub fn new_with_persister(chain_monitor: Arc<ChainWatchInterface>, broadcaster: Arc<BroadcasterInterface>, logger: Arc<Logger>, feeest: Arc<FeeEstimator>, persister: Arc<ChannelMonitorPersister>) -> Arc<SimpleManyChannelMonitor<OutPoint>> {
+		Self::new_internal(chain_monitor, broadcaster, logger, feeest, Some(persister))
+	}
+}
+
 impl ManyChannelMonitor for SimpleManyChannelMonitor<OutPoint> {
 	fn add_update_monitor(&self, funding_txo: OutPoint, monitor: ChannelMonitor) -> Result<(), ChannelMonitorUpdateErr> {
+		let persister = match self.persister {
+			Some(ref persister) => persister,
+			None => {
+				return match self.add_update_monitor_by_key(funding_txo, monitor) {
+					Ok(_) => Ok(()),
+					Err(_) => Err(ChannelMonitorUpdateErr::PermanentFailure),
+				};
+			},
+		};
+		// Hold this channel's persist lock across both the update and the persist, otherwise a
+		// concurrent update could persist its (newer) monitor before we persist ours, leaving a
+		// stale monitor on disk.
+		let persist_lock = self.persist_locks.lock().unwrap().entry(funding_txo).or_insert_with(|| Arc::new(Mutex::new(()))).clone();
+		let _persist_guard = persist_lock.lock().unwrap();
 		match self.add_update_monitor_by_key(funding_txo, monitor) {
-			Ok(_) => Ok(()),
-			Err(_) => Err(ChannelMonitorUpdateErr::PermanentFailure),
-		}
+			Ok(_) => {},
+			Err(_) => return Err(ChannelMonitorUpdateErr::PermanentFailure),
+		}
+		// Persist the combined monitor as it now stands, not just the update we were handed. It is
+		// copied out first so that a slow persister doesn't hold up every other channel's updates
+		// (and block_connected) behind the monitors lock.
+		let monitor = self.monitors.lock().unwrap().get(&funding_txo).unwrap().clone();
+		persister.persist_monitor(&funding_txo, &monitor)
 	}
 
 	fn fetch_pending_htlc_updated(&self) -> Vec<HTLCUpdate> {
@@ -853,8 +905,8 @@ impl ChannelMonitor {
 		}
 		if let Some(ref local_tx) = self.current_local_signed_commitment_tx {
 			if let Some(ref other_local_tx) = other.current_local_signed_commitment_tx {
-				let our_commitment_number = 0xffffffffffff - ((((local_tx.tx.input[0].sequence as u64 & 0xffffff) << 3*8) | (local_tx.tx.lock_time as u64 & 0xffffff)) ^ self.commitment_transaction_number_obscure_factor);
-				let other_commitment_number = 0xffffffffffff - ((((other_local_tx.tx.input[0].sequence as u64 & 0xffffff) << 3*8) | (other_local_tx.tx.lock_time as u64 & 0xffffff)) ^ other.commitment_transaction_number_obscure_factor);
+				let our_commitment_number = 0xffffffffffff - chan_utils::decode_commitment_transaction_number(local_tx.tx.input[0].sequence, local_tx.tx.lock_time, self.commitment_transaction_number_obscure_factor);
+				let other_commitment_number = 0xffffffffffff - chan_utils::decode_commitment_transaction_number(other_local_tx.tx.input[0].sequence, other_local_tx.tx.lock_time, other.commitment_transaction_number_obscure_factor);
 				if our_commitment_number >= other_commitment_number {
 					self.key_storage = other.key_storage;
 				}
@@ -955,7 +1007,7 @@ impl ChannelMonitor {
 	}
 
 	/// Serializes into a vec, with various modes for the exposed pub fns
-	fn write<W: Writer>(&self, writer: &mut W, for_local_storage: bool) -> Result<(), ::std::io::Error> {
+	fn write_internal<W: Writer>(&self, writer: &mut W, for_local_storage: bool) -> Result<(), ::std::io::Error> {
 		//TODO: We still write out all the serialization here manually instead of using the fancy
 		//serialization framework we have, we should migrate things over to it.
 		writer.write_all(&[SERIALIZATION_VERSION; 1])?;
@@ -1204,7 +1256,7 @@ impl ChannelMonitor {
 	/// common block that appears on your best chain as well as on the chain which contains the
 	/// last block hash returned) upon deserializing the object!
 	pub fn write_for_disk<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
-		self.write(writer, true)
+		self.write_internal(writer, true)
 	}
 
 	/// Encodes this monitor into the given writer, suitable for sending to a remote watchtower
@@ -1215,7 +1267,7 @@ impl ChannelMonitor {
 	/// common block that appears on your best chain as well as on the chain which contains the
 	/// last block hash returned) upon deserializing the object!
 	pub fn write_for_watchtower<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
-		self.write(writer, false)
+		self.write_internal(writer, false)
 	}
 
 	/// Can only fail if idx is < get_min_seen_secret
@@ -1246,7 +1298,7 @@ impl ChannelMonitor {
 
 	pub(super) fn get_cur_local_commitment_number(&self) -> u64 {
 		if let &Some(ref local_tx) = &self.current_local_signed_commitment_tx {
-			0xffff_ffff_ffff - ((((local_tx.tx.input[0].sequence as u64 & 0xffffff) << 3*8) | (local_tx.tx.lock_time as u64 & 0xffffff)) ^ self.commitment_transaction_number_obscure_factor)
+			0xffff_ffff_ffff - chan_utils::decode_commitment_transaction_number(local_tx.tx.input[0].sequence, local_tx.tx.lock_time, self.commitment_transaction_number_obscure_factor)
 		} else { 0xffff_ffff_ffff }
 	}
 
@@ -1275,7 +1327,7 @@ impl ChannelMonitor {
 			};
 		}
 
-		let commitment_number = 0xffffffffffff - ((((tx.input[0].sequence as u64 & 0xffffff) << 3*8) | (tx.lock_time as u64 & 0xffffff)) ^ self.commitment_transaction_number_obscure_factor);
+		let commitment_number = 0xffffffffffff - chan_utils::decode_commitment_transaction_number(tx.input[0].sequence, tx.lock_time, self.commitment_transaction_number_obscure_factor);
 		if commitment_number >= self.get_min_seen_secret() {
 			let secret = self.get_secret(commitment_number).unwrap();
 			let per_commitment_key = ignore_error!(SecretKey::from_slice(&secret));
@@ -1338,7 +1390,7 @@ impl ChannelMonitor {
 			}
 
 			macro_rules! sign_input {
-				($sighash_parts: expr, $input: expr, $htlc_idx: expr, $amount: expr) => {
+				($tx: expr, $input_idx: expr, $htlc_idx: expr, $amount: expr) => {
 					{
 						let (sig, redeemscript, revocation_key) = match self.key_storage {
 							Storage::Local { ref revocation_base_key, .. } => {
@@ -1346,7 +1398,7 @@ impl ChannelMonitor {
 									let htlc = &per_commitment_option.unwrap()[$htlc_idx.unwrap()].0;
 									chan_utils::get_htlc_redeemscript_with_explicit_keys(htlc, &a_htlc_key, &b_htlc_key, &revocation_pubkey)
 								};
-								let sighash = hash_to_message!(&$sighash_parts.sighash_all(&$input, &redeemscript, $amount)[..]);
+								let sighash = hash_to_message!(&chan_utils::sighash_commitment(&$tx, $input_idx, &redeemscript, $amount)[..]);
 								let revocation_key = ignore_error!(chan_utils::derive_private_revocation_key(&self.secp_ctx, &per_commitment_key, &revocation_base_key));
 								(self.secp_ctx.sign(&sighash, &revocation_key), redeemscript, revocation_key)
 							},
@@ -1354,14 +1406,15 @@ impl ChannelMonitor {
 								unimplemented!();
 							}
 						};
-						$input.witness.push(sig.serialize_der().to_vec());
-						$input.witness[0].push(SigHashType::All as u8);
+						let input = &mut $tx.input[$input_idx];
+						input.witness.push(sig.serialize_der().to_vec());
+						input.witness[0].push(SigHashType::All as u8);
 						if $htlc_idx.is_none() {
-							$input.witness.push(vec!(1));
+							input.witness.push(vec!(1));
 						} else {
-							$input.witness.push(revocation_pubkey.serialize().to_vec());
+							input.witness.push(revocation_pubkey.serialize().to_vec());
 						}
-						$input.witness.push(redeemscript.clone().into_bytes());
+						input.witness.push(redeemscript.clone().into_bytes());
 						(redeemscript, revocation_key)
 					}
 				}
@@ -1406,8 +1459,7 @@ impl ChannelMonitor {
 							let height_timer = Self::get_height_timer(height, htlc.cltv_expiry);
 							let mut used_feerate;
 							if subtract_high_prio_fee!(self, fee_estimator, single_htlc_tx.output[0].value, predicted_weight, tx.txid(), used_feerate) {
-								let sighash_parts = bip143::SighashComponents::new(&single_htlc_tx);
-								let (redeemscript, revocation_key) = sign_input!(sighash_parts, single_htlc_tx.input[0], Some(idx), htlc.amount_msat / 1000);
+								let (redeemscript, revocation_key) = sign_input!(single_htlc_tx, 0, Some(idx), htlc.amount_msat / 1000);
 								assert!(predicted_weight >= single_htlc_tx.get_weight());
 								match self.our_claim_txn_waiting_first_conf.entry(single_htlc_tx.input[0].previous_output.clone()) {
 									hash_map::Entry::Occupied(_) => {},
@@ -1484,12 +1536,10 @@ impl ChannelMonitor {
 				return (txn_to_broadcast, (commitment_txid, watch_outputs), spendable_outputs);
 			}
 
-			let sighash_parts = bip143::SighashComponents::new(&spend_tx);
-
-			for (input, info) in spend_tx.input.iter_mut().zip(inputs_info.iter()) {
-				let (redeemscript, revocation_key) = sign_input!(sighash_parts, input, info.0, info.1);
+			for (input_idx, info) in inputs_info.iter().enumerate() {
+				let (redeemscript, revocation_key) = sign_input!(spend_tx, input_idx, info.0, info.1);
 				let height_timer = Self::get_height_timer(height, info.2);
-				match self.our_claim_txn_waiting_first_conf.entry(input.previous_output.clone()) {
+				match self.our_claim_txn_waiting_first_conf.entry(spend_tx.input[input_idx].previous_output.clone()) {
 					hash_map::Entry::Occupied(_) => {},
 					hash_map::Entry::Vacant(entry) => { entry.insert((height_timer, TxMaterial::Revoked { script: redeemscript, pubkey: if info.0.is_some() { Some(revocation_pubkey) } else { None }, key: revocation_key, is_htlc: if info.0.is_some() { true } else { false }, amount: info.1 }, used_feerate, if !info.0.is_some() { height + info.2 } else { info.2 }, height)); }
 				}
@@ -1611,13 +1661,13 @@ impl ChannelMonitor {
 					let mut inputs_info = Vec::new();
 
 					macro_rules! sign_input {
-						($sighash_parts: expr, $input: expr, $amount: expr, $preimage: expr) => {
+						($tx: expr, $input_idx: expr, $amount: expr, $preimage: expr) => {
 							{
 								let (sig, redeemscript, htlc_key) = match self.key_storage {
 									Storage::Local { ref htlc_base_key, .. } => {
-										let htlc = &per_commitment_option.unwrap()[$input.sequence as usize].0;
+										let htlc = &per_commitment_option.unwrap()[$tx.input[$input_idx].sequence as usize].0;
 										let redeemscript = chan_utils::get_htlc_redeemscript_with_explicit_keys(htlc, &a_htlc_key, &b_htlc_key, &revocation_pubkey);
-										let sighash = hash_to_message!(&$sighash_parts.sighash_all(&$input, &redeemscript, $amount)[..]);
+										let sighash = hash_to_message!(&chan_utils::sighash_commitment(&$tx, $input_idx, &redeemscript, $amount)[..]);
 										let htlc_key = ignore_error!(chan_utils::derive_private_key(&self.secp_ctx, revocation_point, &htlc_base_key));
 										(self.secp_ctx.sign(&sighash, &htlc_key), redeemscript, htlc_key)
 									},
@@ -1625,10 +1675,11 @@ impl ChannelMonitor {
 										unimplemented!();
 									}
 								};
-								$input.witness.push(sig.serialize_der().to_vec());
-								$input.witness[0].push(SigHashType::All as u8);
-								$input.witness.push($preimage);
-								$input.witness.push(redeemscript.clone().into_bytes());
+								let input = &mut $tx.input[$input_idx];
+								input.witness.push(sig.serialize_der().to_vec());
+								input.witness[0].push(SigHashType::All as u8);
+								input.witness.push($preimage);
+								input.witness.push(redeemscript.clone().into_bytes());
 								(redeemscript, htlc_key)
 							}
 						}
@@ -1671,8 +1722,7 @@ impl ChannelMonitor {
 									let height_timer = Self::get_height_timer(height, htlc.cltv_expiry);
 									let mut used_feerate;
 									if subtract_high_prio_fee!(self, fee_estimator, single_htlc_tx.output[0].value, predicted_weight, tx.txid(), used_feerate) {
-										let sighash_parts = bip143::SighashComponents::new(&single_htlc_tx);
-										let (redeemscript, htlc_key) = sign_input!(sighash_parts, single_htlc_tx.input[0], htlc.amount_msat / 1000, payment_preimage.0.to_vec());
+										let (redeemscript, htlc_key) = sign_input!(single_htlc_tx, 0, htlc.amount_msat / 1000, payment_preimage.0.to_vec());
 										assert!(predicted_weight >= single_htlc_tx.get_weight());
 										spendable_outputs.push(SpendableOutputDescriptor::StaticOutput {
 											outpoint: BitcoinOutPoint { txid: single_htlc_tx.txid(), vout: 0 },
@@ -1711,8 +1761,7 @@ impl ChannelMonitor {
 								let height_timer = Self::get_height_timer(height, htlc.cltv_expiry);
 								let mut used_feerate;
 								if subtract_high_prio_fee!(self, fee_estimator, timeout_tx.output[0].value, predicted_weight, tx.txid(), used_feerate) {
-									let sighash_parts = bip143::SighashComponents::new(&timeout_tx);
-									let (redeemscript, htlc_key) = sign_input!(sighash_parts, timeout_tx.input[0], htlc.amount_msat / 1000, vec![0]);
+									let (redeemscript, htlc_key) = sign_input!(timeout_tx, 0, htlc.amount_msat / 1000, vec![0]);
 									assert!(predicted_weight >= timeout_tx.get_weight());
 									//TODO: track SpendableOutputDescriptor
 									match self.our_claim_txn_waiting_first_conf.entry(timeout_tx.input[0].previous_output.clone()) {
@@ -1745,12 +1794,10 @@ impl ChannelMonitor {
 						return (txn_to_broadcast, (commitment_txid, watch_outputs), spendable_outputs);
 					}
 
-					let sighash_parts = bip143::SighashComponents::new(&spend_tx);
-
-					for (input, info) in spend_tx.input.iter_mut().zip(inputs_info.iter()) {
-						let (redeemscript, htlc_key) = sign_input!(sighash_parts, input, info.1, (info.0).0.to_vec());
+					for (input_idx, info) in inputs_info.iter().enumerate() {
+						let (redeemscript, htlc_key) = sign_input!(spend_tx, input_idx, info.1, (info.0).0.to_vec());
 						let height_timer = Self::get_height_timer(height, info.2);
-						match self.our_claim_txn_waiting_first_conf.entry(input.previous_output.clone()) {
+						match self.our_claim_txn_waiting_first_conf.entry(spend_tx.input[input_idx].previous_output.clone()) {
 							hash_map::Entry::Occupied(_) => {},
 							hash_map::Entry::Vacant(entry) => { entry.insert((height_timer, TxMaterial::RemoteHTLC { script: redeemscript, key: htlc_key, preimage: Some(*(info.0)), amount: info.1}, used_feerate, info.2, height)); }
 						}
@@ -1846,11 +1893,9 @@ impl ChannelMonitor {
 				return (None, None);
 			}
 
-			let sighash_parts = bip143::SighashComponents::new(&spend_tx);
-
 			let (sig, revocation_key) = match self.key_storage {
 				Storage::Local { ref revocation_base_key, .. } => {
-					let sighash = hash_to_message!(&sighash_parts.sighash_all(&spend_tx.input[0], &redeemscript, amount)[..]);
+					let sighash = hash_to_message!(&chan_utils::sighash_commitment(&spend_tx, 0, &redeemscript, amount)[..]);
 					let revocation_key = ignore_error!(chan_utils::derive_private_revocation_key(&self.secp_ctx, &per_commitment_key, &revocation_base_key));
 					(self.secp_ctx.sign(&sighash, &revocation_key), revocation_key)
 				}
@@ -2467,6 +2512,14 @@ impl ChannelMonitor {
 
 const MAX_ALLOC_SIZE: usize = 64*1024;
 
+/// Writes the monitor as write_for_disk does, so that it can be read back with the
+/// (Sha256dHash, ChannelMonitor) ReadableArgs implementation.
+impl Writeable for ChannelMonitor {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		self.write_for_disk(writer)
+	}
+}
+
 impl<R: ::std::io::Read> ReadableArgs<R, Arc<Logger>> for (Sha256dHash, ChannelMonitor) {
 	fn read(reader: &mut R, logger: Arc<Logger>) -> Result<Self, DecodeError> {
 		let secp_ctx = Secp256k1::new();
@@ -2547,6 +2600,21 @@ impl<R: ::std::io::Read> ReadableArgs<R, Arc<Logger>> for (Sha256dHash, ChannelM
 			*secret = Readable::read(reader)?;
 			*idx = Readable::read(reader)?;
 		}
+		// Each secret must sit in the slot its index maps to and must derive every secret stored
+		// below it which shares its index prefix, as provide_secret enforced when it was inserted.
+		for (pos, &(secret, idx)) in old_secrets.iter().enumerate() {
+			if idx == 1 << 48 {
+				continue;
+			}
+			if ChannelMonitor::place_secret(idx) as usize != pos {
+				return Err(DecodeError::InvalidValue);
+			}
+			for &(old_secret, old_idx) in old_secrets[..pos].iter() {
+				if old_idx != 1 << 48 && old_idx >> pos == idx >> pos && ChannelMonitor::derive_secret(secret, pos as u8, old_idx) != old_secret {
+					return Err(DecodeError::InvalidValue);
+				}
+			}
+		}
 
 		macro_rules! read_htlc_in_commitment {
 			() => {
@@ -2668,7 +2736,7 @@ impl<R: ::std::io::Read> ReadableArgs<R, Arc<Logger>> for (Sha256dHash, ChannelM
 		let mut payment_preimages = HashMap::with_capacity(cmp::min(payment_preimages_len as usize, MAX_ALLOC_SIZE / 32));
 		for _ in 0..payment_preimages_len {
 			let preimage: PaymentPreimage = Readable::read(reader)?;
-			let hash = PaymentHash(Sha256::hash(&preimage.0[..]).into_inner());
+			let hash = payment_hash(&preimage);
 			if let Some(_) = payment_preimages.insert(hash, preimage) {
 				return Err(DecodeError::InvalidValue);
 			}
@@ -2821,14 +2889,22 @@ mod tests {
 	use bitcoin_hashes::hex::FromHex;
 	use hex;
 	use ln::channelmanager::{PaymentPreimage, PaymentHash};
-	use ln::channelmonitor::{ChannelMonitor, InputDescriptors};
+	use bitcoin::network::constants::Network;
+	use ln::channelmonitor::{ChannelMonitor, ChannelMonitorPersister, ChannelMonitorUpdateErr, InputDescriptors, ManyChannelMonitor, SimpleManyChannelMonitor};
 	use ln::chan_utils;
 	use ln::chan_utils::{HTLCOutputInCommitment, TxCreationKeys};
-	use util::test_utils::TestLogger;
+	use ln::msgs::DecodeError;
+	use chain::chaininterface::ChainWatchInterfaceUtil;
+	use chain::transaction::OutPoint;
+	use util::logger::Logger;
+	use util::ser::{ReadableArgs, Writeable};
+	use util::test_utils::{TestBroadcaster, TestFeeEstimator, TestLogger};
 	use secp256k1::key::{SecretKey,PublicKey};
 	use secp256k1::Secp256k1;
 	use rand::{thread_rng,Rng};
-	use std::sync::Arc;
+	use std::sync::{mpsc, Arc, Mutex};
+	use std::thread;
+	use std::time::Duration;
 
 	#[test]
 	fn test_per_commitment_storage() {
@@ -3411,5 +3487,176 @@ mod tests {
 		assert_eq!(base_weight + ChannelMonitor::get_witnesses_weight(&inputs_des[..]), claim_tx.get_weight() + /* max_length_isg */ (73 * inputs_des.len() - sum_actual_sigs));
 	}
 
+	fn serializable_monitor() -> ChannelMonitor {
+		let secp_ctx = Secp256k1::new();
+		let logger = Arc::new(TestLogger::new());
+		let dummy_key = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42; 32]).unwrap());
+
+		let mut monitor = ChannelMonitor::new(&SecretKey::from_slice(&[42; 32]).unwrap(), &SecretKey::from_slice(&[43; 32]).unwrap(), &SecretKey::from_slice(&[44; 32]).unwrap(), &SecretKey::from_slice(&[44; 32]).unwrap(), &PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[45; 32]).unwrap()), 10, Script::new(), logger);
+		monitor.set_commitment_obscure_factor(42);
+		monitor.set_funding_info((OutPoint { txid: Sha256dHash::from_slice(&[43; 32]).unwrap(), index: 0 }, Script::new()));
+		monitor.set_their_base_keys(&dummy_key, &dummy_key);
+		monitor.set_their_to_self_delay(10);
+		let dummy_tx = Transaction { version: 0, lock_time: 0, input: Vec::new(), output: Vec::new() };
+		let htlc = HTLCOutputInCommitment {
+			offered: true,
+			amount_msat: 1000,
+			cltv_expiry: 500,
+			payment_hash: PaymentHash([42; 32]),
+			transaction_output_index: Some(0),
+		};
+		monitor.provide_latest_remote_commitment_tx_info(&dummy_tx, vec![(htlc, None)], 281474976710651, dummy_key);
+
+		// Test vectors from BOLT 3:
+		monitor.provide_secret(281474976710655, hex_bytes("7cc854b54e3e0dcdb010d7a3fee464a9687be6e8db3be6854c475621e007a5dc")).unwrap();
+		monitor.provide_secret(281474976710654, hex_bytes("c7518c8ae4660ed02894df8976fa1a3659c1a8b4b5bec0c4b872abeba4cb8964")).unwrap();
+		monitor.provide_secret(281474976710653, hex_bytes("2273e227a5b7449b6e70f1fb4652864038b1cbf9cd7c043a7d6456b7fc275ad8")).unwrap();
+		monitor.provide_secret(281474976710652, hex_bytes("27cddaa5624534cb6cb9d7da077cf2b22ab21e9b506fd4998a51d54502e99116")).unwrap();
+		monitor
+	}
+
+	fn hex_bytes(hex_str: &str) -> [u8; 32] {
+		let mut res = [0; 32];
+		res.copy_from_slice(&hex::decode(hex_str).unwrap());
+		res
+	}
+
+	#[test]
+	fn test_serialization_round_trip() {
+		let monitor = serializable_monitor();
+		let serialized = monitor.encode();
+		let (_, read_monitor) = <(Sha256dHash, ChannelMonitor)>::read(&mut ::std::io::Cursor::new(&serialized), Arc::new(TestLogger::new())).unwrap();
+		assert!(read_monitor == monitor);
+		assert_eq!(read_monitor.get_min_seen_secret(), 281474976710652);
+		assert_eq!(read_monitor.encode(), serialized);
+	}
+
+	#[test]
+	fn test_truncated_serialization() {
+		let serialized = serializable_monitor().encode();
+		for len in 0..serialized.len() {
+			match <(Sha256dHash, ChannelMonitor)>::read(&mut ::std::io::Cursor::new(&serialized[..len]), Arc::new(TestLogger::new())) {
+				Err(DecodeError::ShortRead) => {},
+				Err(DecodeError::Io(_)) => {},
+				_ => panic!("Truncated monitor of length {} did not fail with a short read", len),
+			}
+		}
+	}
+
+	#[test]
+	fn test_inconsistent_secrets_rejected() {
+		let mut monitor = serializable_monitor();
+		monitor.old_secrets[0].0[0] ^= 1;
+		let serialized = monitor.encode();
+		match <(Sha256dHash, ChannelMonitor)>::read(&mut ::std::io::Cursor::new(&serialized), Arc::new(TestLogger::new())) {
+			Err(DecodeError::InvalidValue) => {},
+			_ => panic!("Monitor with a secret that doesn't match its successors was accepted"),
+		}
+
+		let mut monitor = serializable_monitor();
+		monitor.old_secrets.swap(0, 1);
+		match <(Sha256dHash, ChannelMonitor)>::read(&mut ::std::io::Cursor::new(&monitor.encode()), Arc::new(TestLogger::new())) {
+			Err(DecodeError::InvalidValue) => {},
+			_ => panic!("Monitor with a misplaced secret was accepted"),
+		}
+	}
+
+	struct TestPersister {
+		persisted: Mutex<Vec<(OutPoint, u64)>>,
+		ret: Mutex<Result<(), ChannelMonitorUpdateErr>>,
+		// If set, the next persist signals this and then stalls before recording the monitor
+		stall: Mutex<Option<mpsc::Sender<()>>>,
+	}
+	impl ChannelMonitorPersister for TestPersister {
+		fn persist_monitor(&self, funding_txo: &OutPoint, monitor: &ChannelMonitor) -> Result<(), ChannelMonitorUpdateErr> {
+			if let Some(stalled) = self.stall.lock().unwrap().take() {
+				stalled.send(()).unwrap();
+				thread::sleep(Duration::from_millis(100));
+			}
+			self.persisted.lock().unwrap().push((*funding_txo, monitor.get_min_seen_secret()));
+			self.ret.lock().unwrap().clone()
+		}
+	}
+
+	#[test]
+	fn test_persister_called_on_update() {
+		let logger: Arc<Logger> = Arc::new(TestLogger::new());
+		let persister = Arc::new(TestPersister { persisted: Mutex::new(Vec::new()), ret: Mutex::new(Ok(())), stall: Mutex::new(None) });
+		let chain_monitor = Arc::new(ChainWatchInterfaceUtil::new(Network::Testnet, logger.clone()));
+		let broadcaster = Arc::new(TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) });
+		let fee_estimator = Arc::new(TestFeeEstimator { sat_per_kw: 253 });
+		let many_monitor = SimpleManyChannelMonitor::new_with_persister(chain_monitor, broadcaster, logger, fee_estimator, persister.clone());
+
+		let monitor = serializable_monitor();
+		let funding_txo = monitor.get_funding_txo().unwrap();
+		assert!(many_monitor.add_update_monitor(funding_txo, monitor).is_ok());
+		assert_eq!(*persister.persisted.lock().unwrap(), vec![(funding_txo, 281474976710652)]);
+
+		// An update carrying a new secret is merged in, and the merged monitor is what gets persisted
+		let mut update = serializable_monitor();
+		update.provide_secret(281474976710651, hex_bytes("c65716add7aa98ba7acb236352d665cab17345fe45b55fb879ff80e6bd0c41dd")).unwrap();
+		assert!(many_monitor.add_update_monitor(funding_txo, update).is_ok());
+		assert_eq!(persister.persisted.lock().unwrap()[1], (funding_txo, 281474976710651));
+	}
+
+	#[test]
+	fn test_persister_failure_returned() {
+		let logger: Arc<Logger> = Arc::new(TestLogger::new());
+		let persister = Arc::new(TestPersister { persisted: Mutex::new(Vec::new()), ret: Mutex::new(Err(ChannelMonitorUpdateErr::TemporaryFailure)), stall: Mutex::new(None) });
+		let chain_monitor = Arc::new(ChainWatchInterfaceUtil::new(Network::Testnet, logger.clone()));
+		let broadcaster = Arc::new(TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) });
+		let fee_estimator = Arc::new(TestFeeEstimator { sat_per_kw: 253 });
+		let many_monitor = SimpleManyChannelMonitor::new_with_persister(chain_monitor, broadcaster, logger, fee_estimator, persister.clone());
+
+		let monitor = serializable_monitor();
+		let funding_txo = monitor.get_funding_txo().unwrap();
+		match many_monitor.add_update_monitor(funding_txo, monitor) {
+			Err(ChannelMonitorUpdateErr::TemporaryFailure) => {},
+			_ => panic!("Persistence failure was not passed back"),
+		}
+		assert_eq!(persister.persisted.lock().unwrap().len(), 1);
+
+		*persister.ret.lock().unwrap() = Err(ChannelMonitorUpdateErr::PermanentFailure);
+		match many_monitor.add_update_monitor(funding_txo, serializable_monitor()) {
+			Err(ChannelMonitorUpdateErr::PermanentFailure) => {},
+			_ => panic!("Persistence failure was not passed back"),
+		}
+	}
+
+	#[test]
+	fn test_concurrent_updates_persisted_in_order() {
+		// If a persist of one update is slow, a later update to the same channel must not be
+		// persisted underneath it, or the older monitor would be what ends up on disk.
+		let logger: Arc<Logger> = Arc::new(TestLogger::new());
+		let persister = Arc::new(TestPersister { persisted: Mutex::new(Vec::new()), ret: Mutex::new(Ok(())), stall: Mutex::new(None) });
+		let chain_monitor = Arc::new(ChainWatchInterfaceUtil::new(Network::Testnet, logger.clone()));
+		let broadcaster = Arc::new(TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) });
+		let fee_estimator = Arc::new(TestFeeEstimator { sat_per_kw: 253 });
+		let many_monitor = SimpleManyChannelMonitor::new_with_persister(chain_monitor, broadcaster, logger, fee_estimator, persister.clone());
+
+		let monitor = serializable_monitor();
+		let funding_txo = monitor.get_funding_txo().unwrap();
+		assert!(many_monitor.add_update_monitor(funding_txo, monitor).is_ok());
+
+		let (stall_send, stall_recv) = mpsc::channel();
+		*persister.stall.lock().unwrap() = Some(stall_send);
+		let mut first_update = serializable_monitor();
+		first_update.provide_secret(281474976710651, hex_bytes("c65716add7aa98ba7acb236352d665cab17345fe45b55fb879ff80e6bd0c41dd")).unwrap();
+		let thread_monitor = many_monitor.clone();
+		let first_thread = thread::spawn(move || {
+			assert!(thread_monitor.add_update_monitor(funding_txo, first_update).is_ok());
+		});
+
+		// Wait until the first update is being persisted, then race a second update against it
+		stall_recv.recv().unwrap();
+		let mut second_update = serializable_monitor();
+		second_update.provide_secret(281474976710651, hex_bytes("c65716add7aa98ba7acb236352d665cab17345fe45b55fb879ff80e6bd0c41dd")).unwrap();
+		second_update.provide_secret(281474976710650, hex_bytes("969660042a28f32d9be17344e09374b379962d03db1574df5a8a5a47e19ce3f2")).unwrap();
+		assert!(many_monitor.add_update_monitor(funding_txo, second_update).is_ok());
+		first_thread.join().unwrap();
+
+		assert_eq!(*persister.persisted.lock().unwrap(), vec![(funding_txo, 281474976710652), (funding_txo, 281474976710651), (funding_txo, 281474976710650)]);
+	}
+
 	// Further testing is done in the ChannelManager integration tests.
 }
@@ -0,0 +1,334 @@
+//! Lightning feature bits, as described in BOLT #9.
+//!
+//! Features are set in pairs: the even bit of each pair marks the feature as required and the odd
+//! bit marks it as optional ("it's OK to be odd"). Features are parameterized over the context
+//! they appear in so that eg node features can't be accidentally sent in an init message.
+
+use std::fmt;
+use std::io::Read;
+use std::marker::PhantomData;
+
+use ln::msgs::{DecodeError, ErrorAction, HandleError};
+use util::ser::{Readable, Writeable, Writer};
+
+/// The context in which a set of Features appears.
+pub trait FeatureContext {}
+
+/// Features used in init messages
+#[derive(Clone, PartialEq, Debug)]
+pub struct InitContext {}
+impl FeatureContext for InitContext {}
+/// Features used in node_announcement messages
+#[derive(Clone, PartialEq, Debug)]
+pub struct NodeContext {}
+impl FeatureContext for NodeContext {}
+/// Features used in channel_announcement messages
+#[derive(Clone, PartialEq, Debug)]
+pub struct ChannelContext {}
+impl FeatureContext for ChannelContext {}
+/// Features used in BOLT #11 invoices
+#[derive(Clone, PartialEq, Debug)]
+pub struct InvoiceContext {}
+impl FeatureContext for InvoiceContext {}
+
+/// The (even) bit for option_data_loss_protect
+pub const DATA_LOSS_PROTECT: usize = 0;
+/// The (even) bit for option_upfront_shutdown_script
+pub const UPFRONT_SHUTDOWN_SCRIPT: usize = 4;
+/// The (even) bit for gossip_queries
+pub const GOSSIP_QUERIES: usize = 6;
+/// The (even) bit for var_onion_optin
+pub const VAR_ONION_OPTIN: usize = 8;
+/// The (even) bit for gossip_queries_ex, which depends on gossip_queries
+pub const GOSSIP_QUERIES_EX: usize = 10;
+
+/// The even bits of all features we understand
+const KNOWN_FEATURES: [usize; 5] = [DATA_LOSS_PROTECT, UPFRONT_SHUTDOWN_SCRIPT, GOSSIP_QUERIES, VAR_ONION_OPTIN, GOSSIP_QUERIES_EX];
+
+/// A set of feature bits for the given context.
+pub struct Features<T: FeatureContext> {
+	/// Little-endian, ie flags[0] holds bits 0-7. Never has trailing zero bytes.
+	flags: Vec<u8>,
+	mark: PhantomData<T>,
+}
+
+/// Features used in init messages
+pub type InitFeatures = Features<InitContext>;
+/// Features used in node_announcement messages
+pub type NodeFeatures = Features<NodeContext>;
+/// Features used in channel_announcement messages
+pub type ChannelFeatures = Features<ChannelContext>;
+/// Features used in BOLT #11 invoices
+pub type InvoiceFeatures = Features<InvoiceContext>;
+
+impl<T: FeatureContext> Clone for Features<T> {
+	fn clone(&self) -> Self {
+		Features {
+			flags: self.flags.clone(),
+			mark: PhantomData,
+		}
+	}
+}
+impl<T: FeatureContext> PartialEq for Features<T> {
+	fn eq(&self, other: &Self) -> bool {
+		self.flags == other.flags
+	}
+}
+impl<T: FeatureContext> fmt::Debug for Features<T> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		write!(f, "Features(")?;
+		for byte in self.flags.iter().rev() {
+			write!(f, "{:08b}", byte)?;
+		}
+		write!(f, ")")
+	}
+}
+
+impl<T: FeatureContext> Features<T> {
+	/// Creates a set with no feature bits set
+	pub fn empty() -> Self {
+		Features {
+			flags: Vec::new(),
+			mark: PhantomData,
+		}
+	}
+
+	fn set_bit(&mut self, bit: usize) {
+		if self.flags.len() <= bit / 8 {
+			self.flags.resize(bit / 8 + 1, 0);
+		}
+		self.flags[bit / 8] |= 1 << (bit % 8);
+	}
+
+	fn is_bit_set(&self, bit: usize) -> bool {
+		self.flags.len() > bit / 8 && (self.flags[bit / 8] & (1 << (bit % 8))) != 0
+	}
+
+	/// Marks the feature containing the given bit as required, ie sets the even bit of its pair
+	pub fn set_required(&mut self, bit: usize) {
+		self.set_bit(bit & !1);
+	}
+
+	/// Marks the feature containing the given bit as optional, ie sets the odd bit of its pair
+	pub fn set_optional(&mut self, bit: usize) {
+		self.set_bit(bit | 1);
+	}
+
+	/// Returns true if the feature containing the given bit is set, either as required or optional
+	pub fn supports(&self, bit: usize) -> bool {
+		self.is_bit_set(bit & !1) || self.is_bit_set(bit | 1)
+	}
+
+	/// Returns true if the feature containing the given bit is set as required
+	pub fn requires(&self, bit: usize) -> bool {
+		self.is_bit_set(bit & !1)
+	}
+
+	/// Returns true if option_data_loss_protect is set
+	pub fn supports_data_loss_protect(&self) -> bool {
+		self.supports(DATA_LOSS_PROTECT)
+	}
+	/// Returns true if option_upfront_shutdown_script is set
+	pub fn supports_upfront_shutdown_script(&self) -> bool {
+		self.supports(UPFRONT_SHUTDOWN_SCRIPT)
+	}
+	/// Returns true if gossip_queries is set
+	pub fn supports_gossip_queries(&self) -> bool {
+		self.supports(GOSSIP_QUERIES)
+	}
+	/// Returns true if var_onion_optin is set
+	pub fn supports_var_onion_optin(&self) -> bool {
+		self.supports(VAR_ONION_OPTIN)
+	}
+	/// Returns true if gossip_queries_ex is set
+	pub fn supports_gossip_queries_ex(&self) -> bool {
+		self.supports(GOSSIP_QUERIES_EX)
+	}
+
+	/// Returns true if any required (even) bit is set for a feature we don't understand
+	pub fn requires_unknown_bits(&self) -> bool {
+		(0..self.flags.len() * 4).map(|pair| pair * 2).any(|bit| {
+			self.is_bit_set(bit) && !KNOWN_FEATURES.contains(&bit)
+		})
+	}
+
+	/// Checks that a peer with the features in other can be talked to by a node with the features
+	/// in self, ie that other doesn't require anything we don't support and that it doesn't set
+	/// features without the features they depend on. Errors should result in a disconnect.
+	pub fn is_compatible(&self, other: &Features<T>) -> Result<(), HandleError> {
+		macro_rules! incompatible {
+			($err: expr) => {
+				return Err(HandleError {
+					err: $err,
+					action: Some(ErrorAction::DisconnectPeer { msg: None }),
+				})
+			}
+		}
+
+		if other.requires_unknown_bits() {
+			incompatible!("Peer requires features unknown to us");
+		}
+		for &bit in KNOWN_FEATURES.iter() {
+			if other.requires(bit) && !self.supports(bit) {
+				incompatible!("Peer requires a feature we don't support");
+			}
+		}
+		if other.supports_gossip_queries_ex() && !other.supports_gossip_queries() {
+			incompatible!("Peer set gossip_queries_ex without gossip_queries");
+		}
+		Ok(())
+	}
+}
+
+impl<T: FeatureContext> Writeable for Features<T> {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		// On the wire the bitfield is big-endian
+		(self.flags.len() as u16).write(w)?;
+		for byte in self.flags.iter().rev() {
+			byte.write(w)?;
+		}
+		Ok(())
+	}
+}
+
+impl<R: Read, T: FeatureContext> Readable<R> for Features<T> {
+	fn read(r: &mut R) -> Result<Self, DecodeError> {
+		let mut flags: Vec<u8> = Readable::read(r)?;
+		flags.reverse();
+		while flags.last() == Some(&0) {
+			flags.pop();
+		}
+		Ok(Features {
+			flags,
+			mark: PhantomData,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use util::ser::{Readable, Writeable};
+
+	use std::io::Cursor;
+
+	#[test]
+	fn set_and_check_bits() {
+		let mut features = InitFeatures::empty();
+		assert!(!features.supports_data_loss_protect());
+		assert!(!features.supports_gossip_queries());
+
+		features.set_optional(GOSSIP_QUERIES);
+		assert!(features.supports_gossip_queries());
+		assert!(features.supports(GOSSIP_QUERIES + 1));
+		assert!(!features.requires(GOSSIP_QUERIES));
+		assert!(!features.supports_upfront_shutdown_script());
+
+		features.set_required(VAR_ONION_OPTIN);
+		assert!(features.supports_var_onion_optin());
+		assert!(features.requires(VAR_ONION_OPTIN));
+		assert!(features.requires(VAR_ONION_OPTIN + 1));
+
+		features.set_required(DATA_LOSS_PROTECT + 1);
+		assert!(features.requires(DATA_LOSS_PROTECT));
+		assert!(features.supports_data_loss_protect());
+	}
+
+	#[test]
+	fn even_required_odd_optional() {
+		let mut features = NodeFeatures::empty();
+		features.set_optional(12);
+		assert_eq!(features.encode(), vec![0, 2, 0x20, 0x00]);
+		assert!(features.supports(12) && !features.requires(12));
+
+		let mut features = NodeFeatures::empty();
+		features.set_required(13);
+		assert_eq!(features.encode(), vec![0, 2, 0x10, 0x00]);
+		assert!(features.supports(13) && features.requires(13));
+	}
+
+	#[test]
+	fn minimal_big_endian_encoding() {
+		assert_eq!(InitFeatures::empty().encode(), vec![0, 0]);
+
+		let mut features = InitFeatures::empty();
+		features.set_optional(UPFRONT_SHUTDOWN_SCRIPT);
+		assert_eq!(features.encode(), vec![0, 1, 0x20]);
+
+		features.set_optional(VAR_ONION_OPTIN);
+		assert_eq!(features.encode(), vec![0, 2, 0x02, 0x20]);
+	}
+
+	#[test]
+	fn features_round_trip() {
+		let mut features = ChannelFeatures::empty();
+		features.set_optional(DATA_LOSS_PROTECT);
+		features.set_required(GOSSIP_QUERIES);
+		features.set_optional(101);
+		let encoded = features.encode();
+		assert_eq!(encoded.len(), 2 + 13);
+		let decoded: ChannelFeatures = Readable::read(&mut Cursor::new(&encoded)).unwrap();
+		assert_eq!(decoded, features);
+
+		// Leading zero bytes on the wire are dropped
+		let decoded: ChannelFeatures = Readable::read(&mut Cursor::new(vec![0, 3, 0, 0, 0x20])).unwrap();
+		let mut expected = ChannelFeatures::empty();
+		expected.set_optional(UPFRONT_SHUTDOWN_SCRIPT);
+		assert_eq!(decoded, expected);
+		assert_eq!(decoded.encode(), vec![0, 1, 0x20]);
+	}
+
+	fn assert_incompatible(ours: &InitFeatures, theirs: &InitFeatures, expected_err: &str) {
+		match ours.is_compatible(theirs) {
+			Err(HandleError { err, action: Some(ErrorAction::DisconnectPeer { msg: None }) }) => assert_eq!(err, expected_err),
+			_ => panic!(),
+		}
+	}
+
+	#[test]
+	fn unknown_bits() {
+		let mut features = InitFeatures::empty();
+		features.set_required(GOSSIP_QUERIES);
+		features.set_optional(100);
+		assert!(!features.requires_unknown_bits());
+		features.set_required(100);
+		assert!(features.requires_unknown_bits());
+
+		let mut features = InitFeatures::empty();
+		features.set_required(2);
+		assert!(features.requires_unknown_bits());
+	}
+
+	#[test]
+	fn compatible_features() {
+		let mut ours = InitFeatures::empty();
+		ours.set_optional(DATA_LOSS_PROTECT);
+		ours.set_optional(GOSSIP_QUERIES);
+		let mut theirs = InitFeatures::empty();
+		theirs.set_required(DATA_LOSS_PROTECT);
+		theirs.set_optional(GOSSIP_QUERIES);
+		theirs.set_optional(GOSSIP_QUERIES_EX);
+		theirs.set_optional(UPFRONT_SHUTDOWN_SCRIPT);
+		theirs.set_optional(57);
+		assert!(ours.is_compatible(&theirs).is_ok());
+		assert!(ours.is_compatible(&InitFeatures::empty()).is_ok());
+	}
+
+	#[test]
+	fn incompatible_features() {
+		let mut ours = InitFeatures::empty();
+		ours.set_optional(GOSSIP_QUERIES);
+
+		let mut theirs = InitFeatures::empty();
+		theirs.set_required(56);
+		assert_incompatible(&ours, &theirs, "Peer requires features unknown to us");
+
+		let mut theirs = InitFeatures::empty();
+		theirs.set_required(VAR_ONION_OPTIN);
+		assert_incompatible(&ours, &theirs, "Peer requires a feature we don't support");
+
+		let mut theirs = InitFeatures::empty();
+		theirs.set_optional(GOSSIP_QUERIES_EX);
+		assert_incompatible(&ours, &theirs, "Peer set gossip_queries_ex without gossip_queries");
+	}
+}
@@ -7,18 +7,21 @@ use chain::chaininterface::{ChainListener, ChainWatchInterface, ChainWatchInterf
 use chain::keysinterface::{KeysInterface, SpendableOutputDescriptor, KeysManager};
 use chain::keysinterface;
 use ln::channel::{COMMITMENT_TX_BASE_WEIGHT, COMMITMENT_TX_WEIGHT_PER_HTLC};
-use ln::channelmanager::{ChannelManager,ChannelManagerReadArgs,HTLCForwardInfo,RAACommitmentOrder, PaymentPreimage, PaymentHash, BREAKDOWN_TIMEOUT};
+use ln::chan_utils::commit_tx_fee_msat;
+use ln::channelmanager::{ChannelManager,ChannelManagerReadArgs,HTLCForwardInfo,RAACommitmentOrder, InboundChannelAcceptor, PaymentPreimage, PaymentHash, BREAKDOWN_TIMEOUT, MIN_CLTV_EXPIRY_DELTA, verify_preimage};
 use ln::channelmonitor::{ChannelMonitor, CLTV_CLAIM_BUFFER, LATENCY_GRACE_PERIOD_BLOCKS, ManyChannelMonitor, ANTI_REORG_DELAY};
 use ln::channel::{ACCEPTED_HTLC_SCRIPT_WEIGHT, OFFERED_HTLC_SCRIPT_WEIGHT, Channel, ChannelError};
+use ln::invoice::{Currency, Invoice};
 use ln::onion_utils;
 use ln::router::{Route, RouteHop};
 use ln::msgs;
 use ln::msgs::{ChannelMessageHandler,RoutingMessageHandler,HTLCFailChannelUpdate, LocalFeatures, ErrorAction};
+use util::amount::{MilliSatoshi, Satoshi};
 use util::test_utils;
 use util::events::{Event, EventsProvider, MessageSendEvent, MessageSendEventsProvider};
 use util::errors::APIError;
 use util::ser::{Writeable, ReadableArgs};
-use util::config::UserConfig;
+use util::config::{UserConfig, ChannelConfig};
 use util::logger::Logger;
 
 use bitcoin::util::hash::BitcoinHash;
@@ -82,17 +85,17 @@ fn test_insane_channel_opens() {
 	use ln::channelmanager::MAX_LOCAL_BREAKDOWN_TIMEOUT;
 
 	// Test all mutations that would make the channel open message insane
-	insane_open_helper("funding value > 2^24", |mut msg| { msg.funding_satoshis = MAX_FUNDING_SATOSHIS; msg });
+	insane_open_helper("funding value > 2^24", |mut msg| { msg.funding_satoshis = Satoshi(MAX_FUNDING_SATOSHIS); msg });
 
-	insane_open_helper("Bogus channel_reserve_satoshis", |mut msg| { msg.channel_reserve_satoshis = msg.funding_satoshis + 1; msg });
+	insane_open_helper("Bogus channel_reserve_satoshis", |mut msg| { msg.channel_reserve_satoshis = msg.funding_satoshis + Satoshi(1); msg });
 
-	insane_open_helper("push_msat larger than funding value", |mut msg| { msg.push_msat = (msg.funding_satoshis - msg.channel_reserve_satoshis) * 1000 + 1; msg });
+	insane_open_helper("push_msat larger than funding value", |mut msg| { msg.push_msat = (msg.funding_satoshis - msg.channel_reserve_satoshis).to_msat().unwrap() + MilliSatoshi(1); msg });
 
-	insane_open_helper("Peer never wants payout outputs?", |mut msg| { msg.dust_limit_satoshis = msg.funding_satoshis + 1 ; msg });
+	insane_open_helper("Peer never wants payout outputs?", |mut msg| { msg.dust_limit_satoshis = msg.funding_satoshis + Satoshi(1); msg });
 
-	insane_open_helper("Bogus; channel reserve is less than dust limit", |mut msg| { msg.dust_limit_satoshis = msg.channel_reserve_satoshis + 1; msg });
+	insane_open_helper("Bogus; channel reserve is less than dust limit", |mut msg| { msg.dust_limit_satoshis = msg.channel_reserve_satoshis + Satoshi(1); msg });
 
-	insane_open_helper("Minimum htlc value is full channel value", |mut msg| { msg.htlc_minimum_msat = (msg.funding_satoshis - msg.channel_reserve_satoshis) * 1000; msg });
+	insane_open_helper("Minimum htlc value is full channel value", |mut msg| { msg.htlc_minimum_msat = (msg.funding_satoshis - msg.channel_reserve_satoshis).to_msat().unwrap(); msg });
 
 	insane_open_helper("They wanted our payments to be delayed by a needlessly long period", |mut msg| { msg.to_self_delay = MAX_LOCAL_BREAKDOWN_TIMEOUT + 1; msg });
 
@@ -101,6 +104,54 @@ fn test_insane_channel_opens() {
 	insane_open_helper("max_accpted_htlcs > 483", |mut msg| { msg.max_accepted_htlcs = 484; msg });
 }
 
+#[test]
+fn test_inbound_channel_acceptor() {
+	// An InboundChannelAcceptor can reject inbound channels, which gets our peer an error message
+	// and leaves us without any state for the channel.
+	struct MinimumSizeAcceptor(u64);
+	impl InboundChannelAcceptor for MinimumSizeAcceptor {
+		fn accept_inbound_channel(&self, _their_node_id: &PublicKey, msg: &msgs::OpenChannel) -> Result<(), &'static str> {
+			if msg.funding_satoshis() < Satoshi(self.0) { Err("Channel is below our minimum size") } else { Ok(()) }
+		}
+	}
+
+	let nodes = create_network(2, &[None, None]);
+	nodes[1].node.set_inbound_channel_acceptor(Some(Arc::new(MinimumSizeAcceptor(50000))));
+	let keys_derived = nodes[1].keys_manager.channel_keys_derived.load(Ordering::Acquire);
+
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 31337, 0, 42).unwrap();
+	let open_channel = get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id());
+	match nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), LocalFeatures::new(), &open_channel) {
+		Err(msgs::HandleError{ err, action: Some(msgs::ErrorAction::SendErrorMessage { msg })}) => {
+			assert_eq!(err, "Channel is below our minimum size");
+			assert_eq!(msg.channel_id, open_channel.temporary_channel_id);
+			assert_eq!(msg.data, "Channel is below our minimum size");
+		},
+		_ => panic!("Below-minimum channel was not rejected with an error message"),
+	}
+	assert!(nodes[1].node.list_channels().is_empty());
+	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+	// Rejected channels never get as far as deriving keys
+	assert_eq!(nodes[1].keys_manager.channel_keys_derived.load(Ordering::Acquire), keys_derived);
+
+	// The acceptor is only consulted once the open_channel has passed our own checks
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 31337, 0, 44).unwrap();
+	let mut open_channel = get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id());
+	open_channel.max_accepted_htlcs = 0;
+	match nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), LocalFeatures::new(), &open_channel) {
+		Err(msgs::HandleError{ err, .. }) => assert_eq!(err, "0 max_accpted_htlcs makes for a useless channel"),
+		_ => panic!("Invalid channel was not rejected"),
+	}
+	assert!(nodes[1].node.list_channels().is_empty());
+	assert_eq!(nodes[1].keys_manager.channel_keys_derived.load(Ordering::Acquire), keys_derived);
+
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100000, 0, 43).unwrap();
+	nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), LocalFeatures::new(), &get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id())).unwrap();
+	get_event_msg!(nodes[1], MessageSendEvent::SendAcceptChannel, nodes[0].node.get_our_node_id());
+	assert_eq!(nodes[1].node.list_channels().len(), 1);
+	assert_eq!(nodes[1].keys_manager.channel_keys_derived.load(Ordering::Acquire), keys_derived + 1);
+}
+
 #[test]
 fn test_async_inbound_update_fee() {
 	let mut nodes = create_network(2, &[None, None]);
@@ -981,6 +1032,297 @@ fn test_shutdown_rebroadcast() {
 	do_test_shutdown_rebroadcast(2);
 }
 
+#[test]
+fn test_closing_signed_immediate_agreement() {
+	// With matching fee estimators the fundee accepts the funder's first proposal as-is and the
+	// closing tx pays exactly that fee (both balances are well above dust so nothing else is
+	// burned to fees).
+	let nodes = create_network(2, &[None, None]);
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 50000000, LocalFeatures::new(), LocalFeatures::new());
+
+	nodes[0].node.close_channel(&chan.2).unwrap();
+	nodes[1].node.handle_shutdown(&nodes[0].node.get_our_node_id(), &get_event_msg!(nodes[0], MessageSendEvent::SendShutdown, nodes[1].node.get_our_node_id())).unwrap();
+	nodes[0].node.handle_shutdown(&nodes[1].node.get_our_node_id(), &get_event_msg!(nodes[1], MessageSendEvent::SendShutdown, nodes[0].node.get_our_node_id())).unwrap();
+	let as_closing_signed = get_event_msg!(nodes[0], MessageSendEvent::SendClosingSigned, nodes[1].node.get_our_node_id());
+
+	nodes[1].node.handle_closing_signed(&nodes[0].node.get_our_node_id(), &as_closing_signed).unwrap();
+	let (_, bs_closing_signed) = get_closing_signed_broadcast!(nodes[1].node, nodes[0].node.get_our_node_id());
+	assert_eq!(bs_closing_signed.as_ref().unwrap().fee_satoshis, as_closing_signed.fee_satoshis);
+	nodes[0].node.handle_closing_signed(&nodes[1].node.get_our_node_id(), &bs_closing_signed.unwrap()).unwrap();
+	let (_, none) = get_closing_signed_broadcast!(nodes[0].node, nodes[1].node.get_our_node_id());
+	assert!(none.is_none());
+
+	let closing_tx = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().remove(0);
+	assert_eq!(closing_tx, nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap().remove(0));
+	check_spends!(closing_tx, chan.3);
+	let outputs_value: u64 = closing_tx.output.iter().map(|o| o.value).sum();
+	assert_eq!(chan.3.output[0].value - outputs_value, as_closing_signed.fee_satoshis);
+}
+
+#[test]
+fn test_closing_signed_multi_round_negotiation() {
+	// The fundee wants a higher fee than the funder is willing to pay, so they counter-propose
+	// back and forth, each new fee strictly between the last two, until they meet.
+	let nodes = create_network(2, &[None, None]);
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, LocalFeatures::new(), LocalFeatures::new());
+
+	nodes[0].node.close_channel(&chan.2).unwrap();
+	nodes[1].node.handle_shutdown(&nodes[0].node.get_our_node_id(), &get_event_msg!(nodes[0], MessageSendEvent::SendShutdown, nodes[1].node.get_our_node_id())).unwrap();
+	nodes[0].node.handle_shutdown(&nodes[1].node.get_our_node_id(), &get_event_msg!(nodes[1], MessageSendEvent::SendShutdown, nodes[0].node.get_our_node_id())).unwrap();
+	let mut closing_signed = get_event_msg!(nodes[0], MessageSendEvent::SendClosingSigned, nodes[1].node.get_our_node_id());
+
+	// Drive the Channels directly so each side can use its own fee estimator. nodes[0] is happy
+	// with up to 253 sat/kW, nodes[1] insists on at least 300 sat/kW.
+	let fee_estimators = [test_utils::TestFeeEstimator { sat_per_kw: 253 }, test_utils::TestFeeEstimator { sat_per_kw: 300 }];
+	let mut fees = vec![closing_signed.fee_satoshis];
+	let mut closing_txn = Vec::new();
+	let mut receiver = 1;
+	for _ in 0..20 {
+		let (resp, tx) = nodes[receiver].node.channel_state.lock().unwrap().by_id.get_mut(&chan.2).unwrap().closing_signed(&fee_estimators[receiver], &closing_signed).unwrap();
+		if let Some(tx) = tx { closing_txn.push(tx); }
+		match resp {
+			Some(msg) => {
+				fees.push(msg.fee_satoshis);
+				closing_signed = msg;
+				receiver ^= 1;
+			},
+			None => break,
+		}
+	}
+
+	// Closing weight is 602, so the opening positions are 152 and 180 sats.
+	assert_eq!(fees, vec![152, 180, 166, 173, 169, 171, 170, 170]);
+	for i in 2..fees.len() - 1 {
+		let (low, high) = if fees[i - 2] < fees[i - 1] { (fees[i - 2], fees[i - 1]) } else { (fees[i - 1], fees[i - 2]) };
+		assert!(fees[i] > low && fees[i] < high);
+	}
+	assert_eq!(closing_txn.len(), 2);
+	assert_eq!(closing_txn[0], closing_txn[1]);
+	check_spends!(closing_txn[0], chan.3);
+}
+
+#[test]
+fn test_create_invoice_payment_recognized() {
+	// The payment hash of an invoice we created is recognized when a payment for it arrives, and
+	// forgotten once it's been claimed.
+	let nodes = create_network(2, &[None, None]);
+	create_announced_chan_between_nodes(&nodes, 0, 1, LocalFeatures::new(), LocalFeatures::new());
+
+	let invoice = nodes[1].node.create_invoice_with_time(Some(1_000_000), "coffee".to_owned(), 3600, 1_500_000_000).unwrap();
+	let invoice = Invoice::parse(&invoice.to_string()).unwrap();
+	assert_eq!(invoice.currency(), Currency::BitcoinTestnet);
+	assert_eq!(invoice.payee_pub_key(), &nodes[1].node.get_our_node_id());
+	assert_eq!(invoice.description(), Some("coffee"));
+	assert_eq!(invoice.timestamp(), 1_500_000_000);
+	assert_eq!(invoice.expiry_time().as_secs(), 3600);
+	let payment_hash = *invoice.payment_hash();
+
+	let route = nodes[0].router.get_route(invoice.payee_pub_key(), None, &Vec::new(), invoice.amount_msat().unwrap(), invoice.min_final_cltv_expiry().unwrap() as u32).unwrap();
+	send_along_route_with_hash(&nodes[0], route, &[&nodes[1]], 1_000_000, payment_hash);
+
+	let payment_preimage = nodes[1].node.get_invoice_preimage(&payment_hash).unwrap();
+	assert!(verify_preimage(&payment_preimage, &payment_hash));
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+	assert!(nodes[1].node.get_invoice_preimage(&payment_hash).is_none());
+
+	// Each invoice gets its own preimage, and without an explicit time is stamped with the
+	// current system time
+	let other_invoice = nodes[1].node.create_invoice(None, "tip".to_owned(), 60).unwrap();
+	assert!(other_invoice.payment_hash() != invoice.payment_hash());
+	assert_eq!(other_invoice.amount_msat(), None);
+	assert!(other_invoice.timestamp() > 1_500_000_000);
+	assert!(nodes[1].node.get_invoice_preimage(other_invoice.payment_hash()).is_some());
+}
+#[test]
+fn test_expired_invoice_preimages_forgotten() {
+	// Preimages of expired invoices are forgotten when we next create an invoice, unless a payment
+	// for them is waiting to be claimed.
+	let nodes = create_network(2, &[None, None]);
+	create_announced_chan_between_nodes(&nodes, 0, 1, LocalFeatures::new(), LocalFeatures::new());
+
+	let unpaid_invoice = nodes[1].node.create_invoice_with_time(None, "tip".to_owned(), 60, 1_500_000_000).unwrap();
+	let paid_invoice = nodes[1].node.create_invoice_with_time(Some(1_000_000), "coffee".to_owned(), 60, 1_500_000_000).unwrap();
+	let route = nodes[0].router.get_route_for_invoice(&paid_invoice, None, None).unwrap();
+	send_along_route_with_hash(&nodes[0], route, &[&nodes[1]], 1_000_000, *paid_invoice.payment_hash());
+
+	// An invoice is still payable at the moment it expires
+	nodes[1].node.create_invoice_with_time(None, "later".to_owned(), 3600, 1_500_000_060).unwrap();
+	assert!(nodes[1].node.get_invoice_preimage(unpaid_invoice.payment_hash()).is_some());
+
+	nodes[1].node.create_invoice_with_time(None, "later".to_owned(), 3600, 1_500_000_061).unwrap();
+	assert!(nodes[1].node.get_invoice_preimage(unpaid_invoice.payment_hash()).is_none());
+	let payment_preimage = nodes[1].node.get_invoice_preimage(paid_invoice.payment_hash()).unwrap();
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+}
+
+#[test]
+fn test_invoice_preimages_serialize_deserialize() {
+	// Invoice preimages survive a reload, and managers written before we created invoices (which
+	// ended just before the invoice preimages) can still be read.
+	let nodes = create_network(2, &[None, None]);
+	let invoice = nodes[0].node.create_invoice_with_time(None, "tip".to_owned(), 3600, 1_500_000_000).unwrap();
+	let payment_preimage = nodes[0].node.get_invoice_preimage(invoice.payment_hash()).unwrap();
+
+	let read_manager = |node: &Node, mut serialized: &[u8]| {
+		let (_, manager) = <(Sha256dHash, ChannelManager)>::read(&mut serialized, ChannelManagerReadArgs {
+			default_config: UserConfig::new(),
+			keys_manager: Arc::new(test_utils::TestKeysInterface::new(&node.node_seed, Network::Testnet, Arc::new(test_utils::TestLogger::new()))),
+			fee_estimator: Arc::new(test_utils::TestFeeEstimator { sat_per_kw: 253 }),
+			monitor: node.chan_monitor.clone(),
+			chain_monitor: node.chain_monitor.clone(),
+			tx_broadcaster: node.tx_broadcaster.clone(),
+			logger: Arc::new(test_utils::TestLogger::new()),
+			channel_monitors: &HashMap::new(),
+		}).unwrap();
+		assert!(serialized.is_empty());
+		manager
+	};
+
+	let nodes_0_deserialized = read_manager(&nodes[0], &nodes[0].node.encode());
+	assert_eq!(nodes_0_deserialized.get_invoice_preimage(invoice.payment_hash()), Some(payment_preimage));
+
+	let nodes_1_serialized = nodes[1].node.encode();
+	// Version 3 added the pending forwards after the invoice preimages, both empty here
+	let (trailing_counts, rest) = (&nodes_1_serialized[nodes_1_serialized.len() - 16..], &nodes_1_serialized[2..nodes_1_serialized.len() - 16]);
+	assert_eq!(trailing_counts, &[0; 16]);
+	let mut legacy_serialized = vec![1, 1];
+	legacy_serialized.extend_from_slice(rest);
+	let nodes_1_deserialized = read_manager(&nodes[1], &legacy_serialized);
+	assert_eq!(nodes_1_deserialized.get_our_node_id(), nodes[1].node.get_our_node_id());
+	assert_eq!(nodes_1_deserialized.encode(), nodes_1_serialized);
+}
+
+
+#[test]
+fn test_pay_amountless_invoice() {
+	// An invoice without an amount can only be paid once the payer picks one.
+	let nodes = create_network(2, &[None, None]);
+	create_announced_chan_between_nodes(&nodes, 0, 1, LocalFeatures::new(), LocalFeatures::new());
+
+	let invoice = nodes[1].node.create_invoice_with_time(None, "tip".to_owned(), 3600, 1_500_000_000).unwrap();
+	let invoice = Invoice::parse(&invoice.to_string()).unwrap();
+	assert_eq!(invoice.amount_msat(), None);
+	match nodes[0].router.get_route_for_invoice(&invoice, None, None) {
+		Err(e) => assert_eq!(e.err, "Invoice has no amount, so an amount to pay must be specified"),
+		Ok(_) => panic!("Got a route without an amount to pay"),
+	}
+
+	let route = nodes[0].router.get_route_for_invoice(&invoice, None, Some(500_000)).unwrap();
+	assert_eq!(route.hops.last().unwrap().fee_msat, 500_000);
+	assert_eq!(route.hops.last().unwrap().cltv_expiry_delta as u64, invoice.min_final_cltv_expiry().unwrap());
+	let payment_hash = *invoice.payment_hash();
+	send_along_route_with_hash(&nodes[0], route, &[&nodes[1]], 500_000, payment_hash);
+	claim_payment(&nodes[0], &[&nodes[1]], nodes[1].node.get_invoice_preimage(&payment_hash).unwrap());
+}
+
+#[test]
+fn test_private_channel_route_hints() {
+	// Our usable private channels with enough inbound capacity are advertised as route hints, but
+	// only once our counterparty has sent us a channel_update with its forwarding policy, which it
+	// does directly once funding_locked has been exchanged.
+	let mut private_config = UserConfig::new();
+	private_config.channel_options.announced_channel = false;
+	private_config.channel_options.fee_base_msat = Some(1234);
+	private_config.channel_options.fee_proportional_millionths = 56;
+	private_config.channel_options.cltv_expiry_delta = 144;
+	let mut include_all_config = private_config.clone();
+	include_all_config.include_all_private_channel_hints = true;
+	include_all_config.channel_options.fee_base_msat = Some(4321);
+	include_all_config.channel_options.fee_proportional_millionths = 65;
+	let nodes = create_network(2, &[Some(include_all_config), Some(private_config)]);
+
+	let tx = create_chan_between_nodes_with_value_init(&nodes[0], &nodes[1], 100000, 10001, LocalFeatures::new(), LocalFeatures::new());
+	create_chan_between_nodes_with_value_confirm_first(&nodes[0], &nodes[1], &tx);
+	confirm_transaction(&nodes[0].chain_monitor, &tx, tx.version);
+
+	// nodes[0] has now both sent and received funding_locked, so follows up with its channel_update
+	let events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 2);
+	let as_funding_locked = match events[0] {
+		MessageSendEvent::SendFundingLocked { ref node_id, ref msg } => {
+			assert_eq!(*node_id, nodes[1].node.get_our_node_id());
+			msg.clone()
+		},
+		_ => panic!("Unexpected event"),
+	};
+	let as_update = match events[1] {
+		MessageSendEvent::SendChannelUpdate { ref node_id, ref msg } => {
+			assert_eq!(*node_id, nodes[1].node.get_our_node_id());
+			msg.clone()
+		},
+		_ => panic!("Unexpected event"),
+	};
+	nodes[1].node.handle_funding_locked(&nodes[0].node.get_our_node_id(), &as_funding_locked).unwrap();
+	let bs_update = get_event_msg!(nodes[1], MessageSendEvent::SendChannelUpdate, nodes[0].node.get_our_node_id());
+	let short_channel_id = nodes[1].node.list_usable_channels()[0].short_channel_id.unwrap();
+	assert_eq!(as_update.contents.short_channel_id, short_channel_id);
+	assert_eq!(bs_update.contents.short_channel_id, short_channel_id);
+
+	// Until nodes[0]'s channel_update arrives we can't describe the channel
+	assert!(nodes[1].node.get_route_hints(Some(1_000_000)).is_empty());
+
+	// Updates relayed by the wrong peer or for the direction we control are rejected
+	assert!(nodes[1].node.handle_channel_update(&nodes[1].node.get_our_node_id(), &as_update).is_err());
+	assert!(nodes[1].node.handle_channel_update(&nodes[0].node.get_our_node_id(), &bs_update).is_err());
+	assert!(nodes[1].node.get_route_hints(Some(1_000_000)).is_empty());
+	nodes[1].node.handle_channel_update(&nodes[0].node.get_our_node_id(), &as_update).unwrap();
+
+	let hints = nodes[1].node.get_route_hints(Some(1_000_000));
+	assert_eq!(hints.len(), 1);
+	assert_eq!(hints[0].src_node_id, nodes[0].node.get_our_node_id());
+	assert_eq!(hints[0].short_channel_id, short_channel_id);
+
+	let invoice = nodes[1].node.create_invoice_with_time(Some(1_000_000), "coffee".to_owned(), 3600, 1_500_000_000).unwrap();
+	let invoice = Invoice::parse(&invoice.to_string()).unwrap();
+	assert_eq!(invoice.route_hints().len(), 1);
+	let hint = &invoice.route_hints()[0][0];
+	assert_eq!(hint.src_node_id, nodes[0].node.get_our_node_id());
+	assert_eq!(hint.short_channel_id, short_channel_id);
+	assert_eq!(hint.fee_base_msat, 4321);
+	assert_eq!(hint.fee_proportional_millionths, 65);
+	assert_eq!(hint.cltv_expiry_delta, 144);
+
+	// Channels which can't receive the invoice amount are skipped, unless configured otherwise
+	assert!(nodes[1].node.get_route_hints(Some(100_000_000)).is_empty());
+	assert!(nodes[0].node.get_route_hints(Some(1_000_000)).is_empty());
+	nodes[0].node.handle_channel_update(&nodes[1].node.get_our_node_id(), &bs_update).unwrap();
+	let hints = nodes[0].node.get_route_hints(Some(1_000_000));
+	assert_eq!(hints.len(), 1);
+	assert_eq!(hints[0].src_node_id, nodes[1].node.get_our_node_id());
+	assert_eq!(hints[0].fee_base_msat, 1234);
+	assert_eq!(hints[0].fee_proportional_millionths, 56);
+}
+
+#[test]
+fn test_pending_forwards_tracked_until_resolved() {
+	// HTLCs we relay are tracked by their incoming channel until they're fulfilled or failed back
+	let nodes = create_network(3, &[None, None, None]);
+	let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1, LocalFeatures::new(), LocalFeatures::new());
+	let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2, LocalFeatures::new(), LocalFeatures::new());
+	assert!(nodes[1].node.list_pending_forwards_expiring_by(::std::u32::MAX).is_empty());
+
+	let (payment_preimage, payment_hash) = route_payment(&nodes[0], &[&nodes[1], &nodes[2]], 1000000);
+	let forwards = nodes[1].node.list_pending_forwards_expiring_by(::std::u32::MAX);
+	assert_eq!(forwards.len(), 1);
+	assert_eq!(forwards[0].incoming_channel_id, chan_1.2);
+	assert_eq!(forwards[0].payment_hash, payment_hash);
+	assert!(forwards[0].incoming_amount_msat >= 1000000);
+	assert_eq!(forwards[0].outgoing_short_channel_id, chan_2.0.contents.short_channel_id);
+	assert!(nodes[1].node.list_pending_forwards_expiring_by(forwards[0].incoming_cltv_expiry - 1).is_empty());
+	assert_eq!(nodes[1].node.list_pending_forwards_expiring_by(forwards[0].incoming_cltv_expiry), forwards);
+	// Neither the sender nor the recipient relayed anything
+	assert!(nodes[0].node.list_pending_forwards_expiring_by(::std::u32::MAX).is_empty());
+	assert!(nodes[2].node.list_pending_forwards_expiring_by(::std::u32::MAX).is_empty());
+
+	claim_payment(&nodes[0], &[&nodes[1], &nodes[2]], payment_preimage);
+	assert!(nodes[1].node.list_pending_forwards_expiring_by(::std::u32::MAX).is_empty());
+
+	let (_, payment_hash) = route_payment(&nodes[0], &[&nodes[1], &nodes[2]], 1000000);
+	assert_eq!(nodes[1].node.list_pending_forwards_expiring_by(::std::u32::MAX).len(), 1);
+	fail_payment(&nodes[0], &[&nodes[1], &nodes[2]], payment_hash);
+	assert!(nodes[1].node.list_pending_forwards_expiring_by(::std::u32::MAX).is_empty());
+}
+
 #[test]
 fn fake_network_test() {
 	// Simple test which builds a network of ChannelManagers, connects them to each other, and
@@ -1250,8 +1592,8 @@ fn do_channel_reserve_test(test_recv: bool) {
 	use ln::msgs::HandleError;
 
 	let mut nodes = create_network(3, &[None, None, None]);
-	let chan_1 = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 1900, 1001, LocalFeatures::new(), LocalFeatures::new());
-	let chan_2 = create_announced_chan_between_nodes_with_value(&nodes, 1, 2, 1900, 1001, LocalFeatures::new(), LocalFeatures::new());
+	let chan_1 = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 9000, 1001, LocalFeatures::new(), LocalFeatures::new());
+	let chan_2 = create_announced_chan_between_nodes_with_value(&nodes, 1, 2, 9000, 1001, LocalFeatures::new(), LocalFeatures::new());
 
 	let mut stat01 = get_channel_value_stat!(nodes[0], chan_1.2);
 	let mut stat11 = get_channel_value_stat!(nodes[1], chan_1.2);
@@ -1279,6 +1621,12 @@ fn do_channel_reserve_test(test_recv: bool) {
 
 	let feemsat = 239; // somehow we know?
 	let total_fee_msat = (nodes.len() - 2) as u64 * 239;
+	// As the funder, nodes[0] must also be able to pay the commitment transaction fee including
+	// the new HTLC, counting every pending HTLC as an output
+	let feerate = get_feerate!(nodes[0], chan_1.2);
+	let commit_tx_fee_1 = commit_tx_fee_msat(feerate, 1);
+	let commit_tx_fee_2 = commit_tx_fee_msat(feerate, 2);
+	let commit_tx_fee_3 = commit_tx_fee_msat(feerate, 3);
 
 	let recv_value_0 = stat01.their_max_htlc_value_in_flight_msat - total_fee_msat;
 
@@ -1288,7 +1636,7 @@ fn do_channel_reserve_test(test_recv: bool) {
 		assert!(route.hops.iter().rev().skip(1).all(|h| h.fee_msat == feemsat));
 		let err = nodes[0].node.send_payment(route, our_payment_hash).err().unwrap();
 		match err {
-			APIError::ChannelUnavailable{err} => assert_eq!(err, "Cannot send value that would put us over the max HTLC value in flight our peer will accept"),
+			APIError::ChannelUnavailable{err} => assert_eq!(err, "Insufficient outbound capacity on first hop for payment amount plus fees"),
 			_ => panic!("Unknown error variants"),
 		}
 	}
@@ -1298,7 +1646,7 @@ fn do_channel_reserve_test(test_recv: bool) {
 	// nodes[0]'s wealth
 	loop {
 		let amt_msat = recv_value_0 + total_fee_msat;
-		if stat01.value_to_self_msat - amt_msat < stat01.channel_reserve_msat {
+		if stat01.value_to_self_msat - amt_msat < stat01.channel_reserve_msat + commit_tx_fee_1 {
 			break;
 		}
 		send_payment(&nodes[0], &vec![&nodes[1], &nodes[2]][..], recv_value_0);
@@ -1319,18 +1667,19 @@ fn do_channel_reserve_test(test_recv: bool) {
 	}
 
 	{
-		let recv_value = stat01.value_to_self_msat - stat01.channel_reserve_msat - total_fee_msat;
+		let recv_value = stat01.value_to_self_msat - stat01.channel_reserve_msat - total_fee_msat - commit_tx_fee_1;
 		// attempt to get channel_reserve violation
 		let (route, our_payment_hash, _) = get_route_and_payment_hash!(recv_value + 1);
 		let err = nodes[0].node.send_payment(route.clone(), our_payment_hash).err().unwrap();
 		match err {
-			APIError::ChannelUnavailable{err} => assert_eq!(err, "Cannot send value that would put us over their reserve value"),
+			APIError::ChannelUnavailable{err} => assert_eq!(err, "Insufficient outbound capacity on first hop for payment amount plus fees"),
 			_ => panic!("Unknown error variants"),
 		}
 	}
 
 	// adding pending output
-	let recv_value_1 = (stat01.value_to_self_msat - stat01.channel_reserve_msat - total_fee_msat)/2;
+	// leave enough for the commitment transaction fee once three HTLCs are pending
+	let recv_value_1 = (stat01.value_to_self_msat - stat01.channel_reserve_msat - total_fee_msat - commit_tx_fee_3)/2;
 	let amt_msat_1 = recv_value_1 + total_fee_msat;
 
 	let (route_1, our_payment_hash_1, our_payment_preimage_1) = get_route_and_payment_hash!(recv_value_1);
@@ -1345,18 +1694,19 @@ fn do_channel_reserve_test(test_recv: bool) {
 	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event_1.msgs[0]).unwrap();
 
 	// channel reserve test with htlc pending output > 0
-	let recv_value_2 = stat01.value_to_self_msat - amt_msat_1 - stat01.channel_reserve_msat - total_fee_msat;
+	let recv_value_2 = stat01.value_to_self_msat - amt_msat_1 - stat01.channel_reserve_msat - total_fee_msat - commit_tx_fee_2;
 	{
 		let (route, our_payment_hash, _) = get_route_and_payment_hash!(recv_value_2 + 1);
 		match nodes[0].node.send_payment(route, our_payment_hash).err().unwrap() {
-			APIError::ChannelUnavailable{err} => assert_eq!(err, "Cannot send value that would put us over their reserve value"),
+			APIError::ChannelUnavailable{err} => assert_eq!(err, "Insufficient outbound capacity on first hop for payment amount plus fees"),
 			_ => panic!("Unknown error variants"),
 		}
 	}
 
 	{
-		// test channel_reserve test on nodes[1] side
-		let (route, our_payment_hash, _) = get_route_and_payment_hash!(recv_value_2 + 1);
+		// test channel_reserve test on nodes[1] side (which doesn't check that nodes[0] can afford
+		// the commitment transaction fee)
+		let (route, our_payment_hash, _) = get_route_and_payment_hash!(recv_value_2 + commit_tx_fee_2 + 1);
 
 		// Need to manually create update_add_htlc message to go around the channel reserve check in send_htlc()
 		let secp_ctx = Secp256k1::new();
@@ -1370,7 +1720,7 @@ fn do_channel_reserve_test(test_recv: bool) {
 		let cur_height = nodes[0].node.latest_block_height.load(Ordering::Acquire) as u32 + 1;
 		let onion_keys = onion_utils::construct_onion_keys(&secp_ctx, &route, &session_priv).unwrap();
 		let (onion_payloads, htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(&route, cur_height).unwrap();
-		let onion_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, &our_payment_hash);
+		let onion_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, &our_payment_hash).unwrap();
 		let msg = msgs::UpdateAddHTLC {
 			channel_id: chan_1.2,
 			htlc_id,
@@ -1394,11 +1744,11 @@ fn do_channel_reserve_test(test_recv: bool) {
 	}
 
 	// split the rest to test holding cell
-	let recv_value_21 = recv_value_2/2;
-	let recv_value_22 = recv_value_2 - recv_value_21 - total_fee_msat;
+	let recv_value_21 = (recv_value_2 - (commit_tx_fee_3 - commit_tx_fee_2))/2;
+	let recv_value_22 = recv_value_2 - recv_value_21 - total_fee_msat - (commit_tx_fee_3 - commit_tx_fee_2);
 	{
 		let stat = get_channel_value_stat!(nodes[0], chan_1.2);
-		assert_eq!(stat.value_to_self_msat - (stat.pending_outbound_htlcs_amount_msat + recv_value_21 + recv_value_22 + total_fee_msat + total_fee_msat), stat.channel_reserve_msat);
+		assert_eq!(stat.value_to_self_msat - (stat.pending_outbound_htlcs_amount_msat + recv_value_21 + recv_value_22 + total_fee_msat + total_fee_msat), stat.channel_reserve_msat + commit_tx_fee_3);
 	}
 
 	// now see if they go through on both sides
@@ -1413,7 +1763,7 @@ fn do_channel_reserve_test(test_recv: bool) {
 	{
 		let (route, our_payment_hash, _) = get_route_and_payment_hash!(recv_value_22+1);
 		match nodes[0].node.send_payment(route, our_payment_hash).err().unwrap() {
-			APIError::ChannelUnavailable{err} => assert_eq!(err, "Cannot send value that would put us over their reserve value"),
+			APIError::ChannelUnavailable{err} => assert_eq!(err, "Insufficient outbound capacity on first hop for payment amount plus fees"),
 			_ => panic!("Unknown error variants"),
 		}
 	}
@@ -1491,7 +1841,7 @@ fn do_channel_reserve_test(test_recv: bool) {
 	let expected_value_to_self = stat01.value_to_self_msat - (recv_value_1 + total_fee_msat) - (recv_value_21 + total_fee_msat) - (recv_value_22 + total_fee_msat);
 	let stat0 = get_channel_value_stat!(nodes[0], chan_1.2);
 	assert_eq!(stat0.value_to_self_msat, expected_value_to_self);
-	assert_eq!(stat0.value_to_self_msat, stat0.channel_reserve_msat);
+	assert_eq!(stat0.value_to_self_msat, stat0.channel_reserve_msat + commit_tx_fee_3);
 
 	let stat2 = get_channel_value_stat!(nodes[2], chan_2.2);
 	assert_eq!(stat2.value_to_self_msat, stat22.value_to_self_msat + recv_value_1 + recv_value_21 + recv_value_22);
@@ -1503,6 +1853,138 @@ fn channel_reserve_test() {
 	do_channel_reserve_test(true);
 }
 
+#[test]
+fn test_channel_capacities_with_pending_htlcs() {
+	// outbound_capacity_msat and inbound_capacity_msat account for reserves, pending HTLCs in both
+	// directions and the commitment transaction fee, and agree with what send_payment accepts.
+	let nodes = create_network(2, &[None, None]);
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 95000000, LocalFeatures::new(), LocalFeatures::new());
+
+	macro_rules! get_capacities {
+		($node: expr) => {{
+			let chan_lock = $node.node.channel_state.lock().unwrap();
+			let channel = chan_lock.by_id.get(&chan.2).unwrap();
+			(channel.outbound_capacity_msat(), channel.inbound_capacity_msat())
+		}}
+	}
+
+	let (payment_preimage_1, _) = route_payment(&nodes[0], &[&nodes[1]], 3_000_000);
+	let (payment_preimage_2, _) = route_payment(&nodes[1], &[&nodes[0]], 2_000_000);
+
+	let reserve_msat = get_channel_value_stat!(nodes[0], chan.2).channel_reserve_msat;
+	let max_in_flight_msat = get_channel_value_stat!(nodes[0], chan.2).their_max_htlc_value_in_flight_msat;
+	// nodes[0] is the funder, so has to cover the commitment transaction fee with a third HTLC
+	let commit_tx_fee = commit_tx_fee_msat(get_feerate!(nodes[0], chan.2), 3);
+
+	let (as_outbound, as_inbound) = get_capacities!(nodes[0]);
+	let (bs_outbound, bs_inbound) = get_capacities!(nodes[1]);
+	// nodes[0] is limited by the reserve nodes[1] requires of it...
+	assert_eq!(as_outbound, 5_000_000 - 3_000_000 - reserve_msat - commit_tx_fee);
+	// ...while nodes[1] is limited by nodes[0]'s max_htlc_value_in_flight_msat
+	assert_eq!(bs_outbound, max_in_flight_msat - 2_000_000);
+	assert_eq!(as_outbound, bs_inbound);
+	assert_eq!(bs_outbound, as_inbound);
+
+	let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &[], as_outbound + 1, TEST_FINAL_CLTV).unwrap();
+	let (_, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+	if let Err(APIError::ChannelUnavailable{err}) = nodes[0].node.send_payment(route, payment_hash) {
+		assert_eq!(err, "Insufficient outbound capacity on first hop for payment amount plus fees");
+	} else { panic!("Sent more than our outbound capacity"); }
+	let sent_msat = as_outbound;
+	let (payment_preimage_3, _) = route_payment(&nodes[0], &[&nodes[1]], sent_msat);
+	assert_eq!(get_capacities!(nodes[0]).0, 0);
+
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage_1);
+	claim_payment(&nodes[1], &[&nodes[0]], payment_preimage_2);
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage_3);
+	let (as_outbound, as_inbound) = get_capacities!(nodes[0]);
+	assert_eq!(as_outbound, 5_000_000 - 3_000_000 + 2_000_000 - sent_msat - reserve_msat - commit_tx_fee_msat(get_feerate!(nodes[0], chan.2), 1));
+	assert_eq!(as_inbound, max_in_flight_msat);
+}
+
+#[test]
+fn test_channel_capacities_at_max_accepted_htlcs() {
+	// Once the recipient's max_accepted_htlcs HTLCs are pending, no more can be added however much
+	// balance is left, so both sides report no capacity in that direction.
+	let nodes = create_network(2, &[None, None]);
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 1000000, 0, LocalFeatures::new(), LocalFeatures::new());
+
+	macro_rules! get_capacities {
+		($node: expr) => {{
+			let chan_lock = $node.node.channel_state.lock().unwrap();
+			let channel = chan_lock.by_id.get(&chan.2).unwrap();
+			(channel.outbound_capacity_msat(), channel.inbound_capacity_msat())
+		}}
+	}
+
+	let mut payment_preimages = Vec::new();
+	for _ in 0..::ln::channel::OUR_MAX_HTLCS - 1 {
+		payment_preimages.push(route_payment(&nodes[0], &[&nodes[1]], 1_000_000).0);
+	}
+	assert!(get_capacities!(nodes[0]).0 >= 1_000_000);
+	assert!(get_capacities!(nodes[1]).1 >= 1_000_000);
+
+	payment_preimages.push(route_payment(&nodes[0], &[&nodes[1]], 1_000_000).0);
+	assert_eq!(get_capacities!(nodes[0]).0, 0);
+	assert_eq!(get_capacities!(nodes[1]).1, 0);
+	let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &[], 1_000_000, TEST_FINAL_CLTV).unwrap();
+	let (_, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+	match nodes[0].node.send_payment(route, payment_hash) {
+		Err(APIError::ChannelUnavailable{..}) => {},
+		_ => panic!("Sent an HTLC over the recipient's max_accepted_htlcs"),
+	}
+
+	// Resolving one frees up a slot again
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimages.pop().unwrap());
+	assert!(get_capacities!(nodes[0]).0 >= 1_000_000);
+	assert!(get_capacities!(nodes[1]).1 >= 1_000_000);
+}
+
+#[test]
+fn test_channel_capacities_capped_by_dust_exposure() {
+	// When the most that could be sent is a dust HTLC, it must also fit under the remaining
+	// max_dust_htlc_exposure_msat, otherwise it would be refused (or failed back) for that.
+	let mut config = UserConfig::new();
+	config.channel_options.announced_channel = true;
+	config.peer_channel_config_limits.force_announced_channel_preference = false;
+	config.channel_options.max_dust_htlc_exposure_msat = 700_000;
+	let nodes = create_network(2, &[Some(config.clone()), Some(config)]);
+	// nodes[1] needs a reserve plus 900 sat, above the (roughly 723 sat) dust threshold
+	let reserve_msat = ::ln::channel::Channel::get_our_channel_reserve_satoshis(100000) * 1000;
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, reserve_msat + 900_000, LocalFeatures::new(), LocalFeatures::new());
+
+	macro_rules! get_capacities {
+		($node: expr) => {{
+			let chan_lock = $node.node.channel_state.lock().unwrap();
+			let channel = chan_lock.by_id.get(&chan.2).unwrap();
+			(channel.outbound_capacity_msat(), channel.inbound_capacity_msat())
+		}}
+	}
+
+	// A non-dust capacity isn't limited by the dust exposure
+	assert_eq!(get_capacities!(nodes[1]).0, 900_000);
+	assert_eq!(get_capacities!(nodes[0]).1, 900_000);
+
+	// After a 400 sat dust HTLC only 500 sat (itself dust) is left, of which 300 sat fits under the
+	// 700 sat dust exposure limit
+	let (payment_preimage, _) = route_payment(&nodes[1], &[&nodes[0]], 400_000);
+	let (bs_outbound, _) = get_capacities!(nodes[1]);
+	assert_eq!(bs_outbound, 300_000);
+	assert_eq!(get_capacities!(nodes[0]).1, 300_000);
+
+	let route = nodes[1].router.get_route(&nodes[0].node.get_our_node_id(), None, &[], bs_outbound + 1, TEST_FINAL_CLTV).unwrap();
+	let (_, payment_hash) = get_payment_preimage_hash!(nodes[1]);
+	match nodes[1].node.send_payment(route, payment_hash) {
+		Err(APIError::ChannelUnavailable{..}) => {},
+		_ => panic!("Sent more than our outbound capacity"),
+	}
+	let (payment_preimage_2, _) = route_payment(&nodes[1], &[&nodes[0]], bs_outbound);
+	assert_eq!(get_capacities!(nodes[1]).0, 0);
+
+	claim_payment(&nodes[1], &[&nodes[0]], payment_preimage);
+	claim_payment(&nodes[1], &[&nodes[0]], payment_preimage_2);
+}
+
 #[test]
 fn channel_reserve_in_flight_removes() {
 	// In cases where one side claims an HTLC, it thinks it has additional available funds that it
@@ -2633,7 +3115,7 @@ fn test_htlc_ignore_latest_remote_commitment() {
 	create_announced_chan_between_nodes(&nodes, 0, 1, LocalFeatures::new(), LocalFeatures::new());
 
 	route_payment(&nodes[0], &[&nodes[1]], 10000000);
-	nodes[0].node.force_close_channel(&nodes[0].node.list_channels()[0].channel_id);
+	nodes[0].node.force_close_channel(&nodes[0].node.list_channels()[0].channel_id).unwrap();
 	check_closed_broadcast!(nodes[0]);
 
 	let node_txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap();
@@ -2688,7 +3170,7 @@ fn test_force_close_fail_back() {
 	// state or updated nodes[1]' state. Now force-close and broadcast that commitment/HTLC
 	// transaction and ensure nodes[1] doesn't fail-backwards (this was originally a bug!).
 
-	nodes[2].node.force_close_channel(&payment_event.commitment_msg.channel_id);
+	nodes[2].node.force_close_channel(&payment_event.commitment_msg.channel_id).unwrap();
 	check_closed_broadcast!(nodes[2]);
 	let tx = {
 		let mut node_txn = nodes[2].tx_broadcaster.txn_broadcasted.lock().unwrap();
@@ -3469,15 +3951,9 @@ macro_rules! check_spendable_outputs {
 			for event in events {
 				match event {
 					Event::SpendableOutputs { ref outputs } => {
-						for outp in outputs {
-							match *outp {
-								SpendableOutputDescriptor::DynamicOutputP2WPKH { ref outpoint, ref key, ref output } => {
-									let input = TxIn {
-										previous_output: outpoint.clone(),
-										script_sig: Script::new(),
-										sequence: 0,
-										witness: Vec::new(),
-									};
+						for outp_desc in outputs {
+							match *outp_desc {
+								SpendableOutputDescriptor::DynamicOutputP2WPKH { ref output, .. } | SpendableOutputDescriptor::DynamicOutputP2WSH { ref output, .. } => {
 									let outp = TxOut {
 										script_pubkey: Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script(),
 										value: output.value,
@@ -3485,43 +3961,11 @@ macro_rules! check_spendable_outputs {
 									let mut spend_tx = Transaction {
 										version: 2,
 										lock_time: 0,
-										input: vec![input],
+										input: vec![outp_desc.to_txin()],
 										output: vec![outp],
 									};
 									let secp_ctx = Secp256k1::new();
-									let remotepubkey = PublicKey::from_secret_key(&secp_ctx, &key);
-									let witness_script = Address::p2pkh(&::bitcoin::PublicKey{compressed: true, key: remotepubkey}, Network::Testnet).script_pubkey();
-									let sighash = Message::from_slice(&bip143::SighashComponents::new(&spend_tx).sighash_all(&spend_tx.input[0], &witness_script, output.value)[..]).unwrap();
-									let remotesig = secp_ctx.sign(&sighash, key);
-									spend_tx.input[0].witness.push(remotesig.serialize_der().to_vec());
-									spend_tx.input[0].witness[0].push(SigHashType::All as u8);
-									spend_tx.input[0].witness.push(remotepubkey.serialize().to_vec());
-									txn.push(spend_tx);
-								},
-								SpendableOutputDescriptor::DynamicOutputP2WSH { ref outpoint, ref key, ref witness_script, ref to_self_delay, ref output } => {
-									let input = TxIn {
-										previous_output: outpoint.clone(),
-										script_sig: Script::new(),
-										sequence: *to_self_delay as u32,
-										witness: Vec::new(),
-									};
-									let outp = TxOut {
-										script_pubkey: Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script(),
-										value: output.value,
-									};
-									let mut spend_tx = Transaction {
-										version: 2,
-										lock_time: 0,
-										input: vec![input],
-										output: vec![outp],
-									};
-									let secp_ctx = Secp256k1::new();
-									let sighash = Message::from_slice(&bip143::SighashComponents::new(&spend_tx).sighash_all(&spend_tx.input[0], witness_script, output.value)[..]).unwrap();
-									let local_delaysig = secp_ctx.sign(&sighash, key);
-									spend_tx.input[0].witness.push(local_delaysig.serialize_der().to_vec());
-									spend_tx.input[0].witness[0].push(SigHashType::All as u8);
-									spend_tx.input[0].witness.push(vec!(0));
-									spend_tx.input[0].witness.push(witness_script.clone().into_bytes());
+									spend_tx.input[0].witness = outp_desc.get_spend_witness(&secp_ctx, &spend_tx, 0).unwrap();
 									txn.push(spend_tx);
 								},
 								SpendableOutputDescriptor::StaticOutput { ref outpoint, ref output } => {
@@ -3579,7 +4023,7 @@ fn test_claim_sizeable_push_msat() {
 	let nodes = create_network(2, &[None, None]);
 
 	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 99000000, LocalFeatures::new(), LocalFeatures::new());
-	nodes[1].node.force_close_channel(&chan.2);
+	nodes[1].node.force_close_channel(&chan.2).unwrap();
 	check_closed_broadcast!(nodes[1]);
 	let node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
 	assert_eq!(node_txn.len(), 1);
@@ -3601,7 +4045,7 @@ fn test_claim_on_remote_sizeable_push_msat() {
 	let nodes = create_network(2, &[None, None]);
 
 	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 99000000, LocalFeatures::new(), LocalFeatures::new());
-	nodes[0].node.force_close_channel(&chan.2);
+	nodes[0].node.force_close_channel(&chan.2).unwrap();
 	check_closed_broadcast!(nodes[0]);
 
 	let node_txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap();
@@ -3618,6 +4062,39 @@ fn test_claim_on_remote_sizeable_push_msat() {
 	check_spends!(spend_txn[0], node_txn[0].clone());
 }
 
+#[test]
+fn test_force_close_returns_commitment_tx() {
+	// force_close_channel hands back the commitment transaction it broadcast and starts watching
+	// its outputs so they can be swept once it confirms.
+	let nodes = create_network(2, &[None, None]);
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, LocalFeatures::new(), LocalFeatures::new());
+	route_payment(&nodes[0], &[&nodes[1]], 3000000);
+
+	let commitment_tx = nodes[0].node.force_close_channel(&chan.2).unwrap();
+	check_closed_broadcast!(nodes[0]);
+	check_spends!(commitment_tx, chan.3.clone());
+	{
+		let node_txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(node_txn[0], commitment_tx);
+	}
+	// to_local and the offered HTLC, to_remote is trimmed as the push_msat is dust
+	assert_eq!(commitment_tx.output.len(), 2);
+	for idx in 0..commitment_tx.output.len() {
+		let spend_tx = Transaction { version: 2, lock_time: 0, input: vec![TxIn {
+			previous_output: BitcoinOutPoint { txid: commitment_tx.txid(), vout: idx as u32 },
+			script_sig: Script::new(), sequence: 0xffffffff, witness: Vec::new(),
+		}], output: Vec::new() };
+		assert!(nodes[0].chain_monitor.does_match_tx(&spend_tx));
+		assert!(!nodes[1].chain_monitor.does_match_tx(&spend_tx));
+	}
+
+	// The channel is gone, so a second attempt fails
+	match nodes[0].node.force_close_channel(&chan.2) {
+		Err(e) => assert_eq!(e.err, "No such channel"),
+		Ok(_) => panic!("Force-closed an already closed channel"),
+	}
+}
+
 #[test]
 fn test_claim_on_remote_revoked_sizeable_push_msat() {
 	// Same test as previous, just test on remote revoked commitment tx, as per_commitment_point registration changes following you're funder/fundee and
@@ -3645,6 +4122,37 @@ fn test_claim_on_remote_revoked_sizeable_push_msat() {
 	check_spends!(spend_txn[1], node_txn[0].clone());
 }
 
+#[test]
+fn test_justice_claim_only_on_revoked_commitment() {
+	// A ChannelMonitor which sees its counterparty's latest commitment tx confirm must not try to
+	// claim the to_local output, but must build a justice tx if the commitment tx was revoked.
+	for &broadcast_revoked in [false, true].iter() {
+		let nodes = create_network(2, &[None, None]);
+		let chan = create_announced_chan_between_nodes(&nodes, 0, 1, LocalFeatures::new(), LocalFeatures::new());
+		let payment_preimage = route_payment(&nodes[0], &vec!(&nodes[1])[..], 3000000).0;
+		let revoked_local_txn = nodes[0].node.channel_state.lock().unwrap().by_id.get(&chan.2).unwrap().last_local_commitment_txn.clone();
+		claim_payment(&nodes[0], &vec!(&nodes[1])[..], payment_preimage);
+		let latest_local_txn = nodes[0].node.channel_state.lock().unwrap().by_id.get(&chan.2).unwrap().last_local_commitment_txn.clone();
+		assert_ne!(revoked_local_txn[0].txid(), latest_local_txn[0].txid());
+
+		let commitment_tx = if broadcast_revoked { revoked_local_txn[0].clone() } else { latest_local_txn[0].clone() };
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		nodes[1].chain_monitor.block_connected_with_filtering(&Block { header, txdata: vec![commitment_tx.clone()] }, 1);
+		check_closed_broadcast!(nodes[1]);
+
+		let node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		let claim_txn: Vec<&Transaction> = node_txn.iter().filter(|tx| tx.input.iter().any(|input| input.previous_output.txid == commitment_tx.txid())).collect();
+		if broadcast_revoked {
+			assert_eq!(claim_txn.len(), 2); // An outpoint registration will result in a 2nd block_connected
+			assert_eq!(claim_txn[0], claim_txn[1]);
+			assert_eq!(claim_txn[0].input.len(), 2); // The revoked to_local output and the HTLC output
+			check_spends!(claim_txn[0], commitment_tx);
+		} else {
+			assert!(claim_txn.is_empty());
+		}
+	}
+}
+
 #[test]
 fn test_static_spendable_outputs_preimage_tx() {
 	let nodes = create_network(2, &[None, None]);
@@ -4630,9 +5138,16 @@ fn run_onion_failure_test_with_fail_intercept<F1,F2,F3>(_name: &str, test_case:
 
 	let events = nodes[0].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 1);
-	if let &Event::PaymentFailed { payment_hash:_, ref rejected_by_dest, ref error_code } = &events[0] {
+	if let &Event::PaymentFailed { payment_hash:_, ref rejected_by_dest, ref failed_short_channel_id, ref error_code } = &events[0] {
 		assert_eq!(*rejected_by_dest, !expected_retryable);
 		assert_eq!(*error_code, expected_error_code);
+		// Every failure here is attributed to the second channel, if to any at all
+		if let &Some(short_channel_id) = failed_short_channel_id {
+			assert_eq!(short_channel_id, route.hops[1].short_channel_id);
+		}
+		if let Some(HTLCFailChannelUpdate::ChannelUpdateMessage { .. }) = expected_channel_update {
+			assert!(failed_short_channel_id.is_some());
+		}
 	} else {
 		panic!("Uexpected event");
 	}
@@ -4698,13 +5213,9 @@ impl msgs::ChannelUpdate {
 fn test_onion_failure() {
 	use ln::msgs::ChannelUpdate;
 	use ln::channelmanager::CLTV_FAR_FAR_AWAY;
+	use ln::onion::{BADONION, PERM, NODE, UPDATE};
 	use secp256k1;
 
-	const BADONION: u16 = 0x8000;
-	const PERM: u16 = 0x4000;
-	const NODE: u16 = 0x2000;
-	const UPDATE: u16 = 0x1000;
-
 	let mut nodes = create_network(3, &[None, None, None]);
 	for node in nodes.iter() {
 		*node.keys_manager.override_session_priv.lock().unwrap() = Some(SecretKey::from_slice(&[3; 32]).unwrap());
@@ -4722,7 +5233,7 @@ fn test_onion_failure() {
 		let onion_keys = onion_utils::construct_onion_keys(&Secp256k1::new(), &route, &session_priv).unwrap();
 		let (mut onion_payloads, _htlc_msat, _htlc_cltv) = onion_utils::build_onion_payloads(&route, cur_height).unwrap();
 		onion_payloads[0].realm = 3;
-		msg.onion_routing_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, &payment_hash);
+		msg.onion_routing_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, &payment_hash).unwrap();
 	}, ||{}, true, Some(PERM|1), Some(msgs::HTLCFailChannelUpdate::ChannelClosed{short_channel_id: channels[1].0.contents.short_channel_id, is_permanent: true}));//XXX incremented channels idx here
 
 	// final node failure
@@ -4732,7 +5243,7 @@ fn test_onion_failure() {
 		let onion_keys = onion_utils::construct_onion_keys(&Secp256k1::new(), &route, &session_priv).unwrap();
 		let (mut onion_payloads, _htlc_msat, _htlc_cltv) = onion_utils::build_onion_payloads(&route, cur_height).unwrap();
 		onion_payloads[1].realm = 3;
-		msg.onion_routing_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, &payment_hash);
+		msg.onion_routing_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, &payment_hash).unwrap();
 	}, ||{}, false, Some(PERM|1), Some(msgs::HTLCFailChannelUpdate::ChannelClosed{short_channel_id: channels[1].0.contents.short_channel_id, is_permanent: true}));
 
 	// the following three with run_onion_failure_test_with_fail_intercept() test only the origin node
@@ -4908,7 +5419,7 @@ fn test_onion_failure() {
 		route.hops[1].cltv_expiry_delta += CLTV_FAR_FAR_AWAY + route.hops[0].cltv_expiry_delta + 1;
 		let onion_keys = onion_utils::construct_onion_keys(&Secp256k1::new(), &route, &session_priv).unwrap();
 		let (onion_payloads, _, htlc_cltv) = onion_utils::build_onion_payloads(&route, height).unwrap();
-		let onion_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, &payment_hash);
+		let onion_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, &payment_hash).unwrap();
 		msg.cltv_expiry = htlc_cltv;
 		msg.onion_routing_packet = onion_packet;
 	}, ||{}, true, Some(21), None);
@@ -5000,13 +5511,74 @@ fn test_update_add_htlc_bolt2_sender_value_below_minimum_msat() {
 	}
 }
 
+#[test]
+fn test_max_dust_htlc_exposure() {
+	// HTLCs which would push the total value of dust HTLCs in a channel over the configured
+	// max_dust_htlc_exposure_msat are refused when sending and failed back when received, while
+	// non-dust HTLCs are unaffected.
+	let mut limited_config = UserConfig::new();
+	limited_config.channel_options.announced_channel = true;
+	limited_config.peer_channel_config_limits.force_announced_channel_preference = false;
+	limited_config.channel_options.max_dust_htlc_exposure_msat = 1_000_000;
+	let mut unlimited_config = limited_config.clone();
+	unlimited_config.channel_options.max_dust_htlc_exposure_msat = ::std::u64::MAX;
+	let nodes = create_network(3, &[Some(limited_config.clone()), Some(unlimited_config), Some(limited_config)]);
+	create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 50000000, LocalFeatures::new(), LocalFeatures::new());
+	create_announced_chan_between_nodes_with_value(&nodes, 1, 2, 100000, 50000000, LocalFeatures::new(), LocalFeatures::new());
+
+	// nodes[0] won't send a second dust HTLC to nodes[1]...
+	let (payment_preimage_1, _) = route_payment(&nodes[0], &[&nodes[1]], 600_000);
+	let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &[], 600_000, TEST_FINAL_CLTV).unwrap();
+	let (_, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+	if let Err(APIError::ChannelUnavailable{err}) = nodes[0].node.send_payment(route, payment_hash) {
+		assert_eq!(err, "Cannot send an HTLC which would put us over our max dust HTLC exposure");
+	} else { panic!("Dust HTLC over the limit was sent"); }
+	check_added_monitors!(nodes[0], 0);
+	// ...but can still send a non-dust one
+	let (payment_preimage_2, _) = route_payment(&nodes[0], &[&nodes[1]], 5_000_000);
+
+	// nodes[2] fails back a second dust HTLC from nodes[1] instead of accepting it
+	let (payment_preimage_3, _) = route_payment(&nodes[1], &[&nodes[2]], 600_000);
+	let route = nodes[1].router.get_route(&nodes[2].node.get_our_node_id(), None, &[], 600_000, TEST_FINAL_CLTV).unwrap();
+	let (_, payment_hash) = get_payment_preimage_hash!(nodes[1]);
+	nodes[1].node.send_payment(route, payment_hash).unwrap();
+	check_added_monitors!(nodes[1], 1);
+	let mut events = nodes[1].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.pop().unwrap());
+	nodes[2].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &payment_event.msgs[0]).unwrap();
+	commitment_signed_dance!(nodes[2], nodes[1], payment_event.commitment_msg, false, true);
+	let fail_updates = get_htlc_update_msgs!(nodes[2], nodes[1].node.get_our_node_id());
+	assert_eq!(fail_updates.update_fail_htlcs.len(), 1);
+	nodes[1].node.handle_update_fail_htlc(&nodes[2].node.get_our_node_id(), &fail_updates.update_fail_htlcs[0]).unwrap();
+	commitment_signed_dance!(nodes[1], nodes[2], fail_updates.commitment_signed, false, true);
+	nodes[1].node.get_and_clear_pending_msg_events();
+	let events = nodes[1].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PaymentFailed { payment_hash: failed_hash, rejected_by_dest, .. } => {
+			assert_eq!(failed_hash, payment_hash);
+			assert!(!rejected_by_dest);
+		},
+		_ => panic!("Unexpected event"),
+	}
+	let (payment_preimage_4, _) = route_payment(&nodes[1], &[&nodes[2]], 5_000_000);
+
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage_1);
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage_2);
+	claim_payment(&nodes[1], &[&nodes[2]], payment_preimage_3);
+	claim_payment(&nodes[1], &[&nodes[2]], payment_preimage_4);
+}
+
 #[test]
 fn test_update_add_htlc_bolt2_sender_cltv_expiry_too_high() {
 	//BOLT 2 Requirement: MUST set cltv_expiry less than 500000000.
 	//It is enforced when constructing a route.
 	let mut nodes = create_network(2, &[None, None]);
 	let _chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 0, LocalFeatures::new(), LocalFeatures::new());
-	let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &[], 100000000, 500000001).unwrap();
+	let mut route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &[], 100000000, TEST_FINAL_CLTV).unwrap();
+	// The Router won't generate such a route itself, so set the CLTV directly
+	route.hops[0].cltv_expiry_delta = 500000001;
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 
 	let err = nodes[0].node.send_payment(route, our_payment_hash);
@@ -5076,7 +5648,7 @@ fn test_update_add_htlc_bolt2_sender_exceed_max_htlc_value_in_flight() {
 	let err = nodes[0].node.send_payment(route, our_payment_hash);
 
 	if let Err(APIError::ChannelUnavailable{err}) = err {
-		assert_eq!(err, "Cannot send value that would put us over the max HTLC value in flight our peer will accept");
+		assert_eq!(err, "Insufficient outbound capacity on first hop for payment amount plus fees");
 	} else {
 		assert!(false);
 	}
@@ -5119,8 +5691,9 @@ fn test_update_add_htlc_bolt2_receiver_sender_can_afford_amount_sent() {
 	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 95000000, LocalFeatures::new(), LocalFeatures::new());
 
 	let their_channel_reserve = get_channel_value_stat!(nodes[0], chan.2).channel_reserve_msat;
+	let outbound_capacity = nodes[0].node.channel_state.lock().unwrap().by_id.get(&chan.2).unwrap().outbound_capacity_msat();
 
-	let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &[], 5000000-their_channel_reserve, TEST_FINAL_CLTV).unwrap();
+	let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &[], outbound_capacity, TEST_FINAL_CLTV).unwrap();
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	nodes[0].node.send_payment(route, our_payment_hash).unwrap();
 	check_added_monitors!(nodes[0], 1);
@@ -5158,7 +5731,7 @@ fn test_update_add_htlc_bolt2_receiver_check_max_htlc_limit() {
 	let cur_height = nodes[0].node.latest_block_height.load(Ordering::Acquire) as u32 + 1;
 	let onion_keys = onion_utils::construct_onion_keys(&Secp256k1::signing_only(), &route, &session_priv).unwrap();
 	let (onion_payloads, _htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(&route, cur_height).unwrap();
-	let onion_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, &our_payment_hash);
+	let onion_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, &our_payment_hash).unwrap();
 
 	let mut msg = msgs::UpdateAddHTLC {
 		channel_id: chan.2,
@@ -5214,7 +5787,7 @@ fn test_update_add_htlc_bolt2_receiver_check_cltv_expiry() {
 	//BOLT2 Requirement: if sending node sets cltv_expiry to greater or equal to 500000000: SHOULD fail the channel.
 	let mut nodes = create_network(2, &[None, None]);
 	create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 95000000, LocalFeatures::new(), LocalFeatures::new());
-	let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &[], 3999999, TEST_FINAL_CLTV).unwrap();
+	let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &[], 3000000, TEST_FINAL_CLTV).unwrap();
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	nodes[0].node.send_payment(route, our_payment_hash).unwrap();
 	check_added_monitors!(nodes[0], 1);
@@ -6004,6 +6577,32 @@ fn test_user_configurable_csv_delay() {
 		}
 	} else { assert!(false); }
 }
+#[test]
+fn test_user_configurable_cltv_expiry_delta() {
+	// We test our channel constructors yield errors when we pass them a cltv_expiry_delta too
+	// small to safely forward HTLCs, as update_channel_config would
+	let mut low_cltv_config = UserConfig::new();
+	low_cltv_config.channel_options.cltv_expiry_delta = 5;
+	let nodes = create_network(2, &[None, None]);
+	let keys_manager: Arc<KeysInterface> = Arc::new(KeysManager::new(&nodes[0].node_seed, Network::Testnet, Arc::new(test_utils::TestLogger::new()), 10, 20));
+
+	match Channel::new_outbound(&test_utils::TestFeeEstimator { sat_per_kw: 253 }, &keys_manager, nodes[1].node.get_our_node_id(), 1000000, 1000000, 0, Arc::new(test_utils::TestLogger::new()), &low_cltv_config) {
+		Err(APIError::APIMisuseError { err }) => assert_eq!(err, "Configured with a cltv_expiry_delta too small to safely forward HTLCs"),
+		_ => panic!("Unexpected result"),
+	}
+
+	nodes[1].node.create_channel(nodes[0].node.get_our_node_id(), 1000000, 1000000, 42).unwrap();
+	let open_channel = get_event_msg!(nodes[1], MessageSendEvent::SendOpenChannel, nodes[0].node.get_our_node_id());
+	match Channel::new_from_req(&test_utils::TestFeeEstimator { sat_per_kw: 253 }, &keys_manager, nodes[1].node.get_our_node_id(), LocalFeatures::new(), &open_channel, 0, Arc::new(test_utils::TestLogger::new()), &low_cltv_config) {
+		Err(ChannelError::Close(err)) => assert_eq!(err, "Configured with a cltv_expiry_delta too small to safely forward HTLCs"),
+		_ => panic!("Unexpected result"),
+	}
+
+	// The smallest safe delta is accepted
+	low_cltv_config.channel_options.cltv_expiry_delta = MIN_CLTV_EXPIRY_DELTA;
+	Channel::new_outbound(&test_utils::TestFeeEstimator { sat_per_kw: 253 }, &keys_manager, nodes[1].node.get_our_node_id(), 1000000, 1000000, 0, Arc::new(test_utils::TestLogger::new()), &low_cltv_config).unwrap();
+}
+
 
 #[test]
 fn test_data_loss_protect() {
@@ -6114,3 +6713,530 @@ fn test_data_loss_protect() {
 	assert_eq!(spend_txn.len(), 1);
 	check_spends!(spend_txn[0], node_txn[0].clone());
 }
+
+#[test]
+fn test_handle_messages_batch() {
+	// Deliver an update_add_htlc and the commitment_signed which commits to it as a single batch
+	// and check that the recipient responds exactly as it would to the two messages delivered
+	// individually.
+	let nodes = create_network(2, &[None, None]);
+	create_announced_chan_between_nodes(&nodes, 0, 1, LocalFeatures::new(), LocalFeatures::new());
+
+	let (payment_preimage, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+	let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+	nodes[0].node.send_payment(route, payment_hash).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	let payment_event = SendEvent::from_node(&nodes[0]);
+	assert_eq!(payment_event.msgs.len(), 1);
+
+	// A batch containing anything which needs more context is rejected before any of it is applied
+	let err = nodes[1].node.handle_messages(&nodes[0].node.get_our_node_id(), &[
+		msgs::Message::UpdateAddHTLC(payment_event.msgs[0].clone()),
+		msgs::Message::Ping(msgs::Ping { ponglen: 0, byteslen: 0 }),
+	]).unwrap_err();
+	assert_eq!(err.err, "Batches may only contain channel messages which need no peer context");
+	check_added_monitors!(nodes[1], 0);
+	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+
+	nodes[1].node.handle_messages(&nodes[0].node.get_our_node_id(), &[
+		msgs::Message::UpdateAddHTLC(payment_event.msgs[0].clone()),
+		msgs::Message::CommitmentSigned(payment_event.commitment_msg.clone()),
+	]).unwrap();
+	check_added_monitors!(nodes[1], 1);
+	let (bs_revoke_and_ack, bs_commitment_signed) = get_revoke_commit_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+
+	nodes[0].node.handle_messages(&nodes[1].node.get_our_node_id(), &[
+		msgs::Message::RevokeAndACK(bs_revoke_and_ack),
+		msgs::Message::CommitmentSigned(bs_commitment_signed),
+	]).unwrap();
+	check_added_monitors!(nodes[0], 2);
+	let as_revoke_and_ack = get_event_msg!(nodes[0], MessageSendEvent::SendRevokeAndACK, nodes[1].node.get_our_node_id());
+	nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &as_revoke_and_ack).unwrap();
+	check_added_monitors!(nodes[1], 1);
+
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	expect_payment_received!(nodes[1], payment_hash, 1000000);
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+}
+
+#[test]
+fn test_send_payment_insufficient_first_hop_capacity() {
+	// nodes[1] has no balance on its only channel, so any payment it attempts over that channel
+	// must be rejected locally, without an HTLC ever being sent or a monitor updated.
+	let nodes = create_network(2, &[None, None]);
+	create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 0, LocalFeatures::new(), LocalFeatures::new());
+
+	let route = nodes[1].router.get_route(&nodes[0].node.get_our_node_id(), None, &[], 10000, TEST_FINAL_CLTV).unwrap();
+	let (_, payment_hash) = get_payment_preimage_hash!(nodes[1]);
+	match nodes[1].node.send_payment(route, payment_hash) {
+		Err(APIError::ChannelUnavailable{err}) => assert_eq!(err, "Insufficient outbound capacity on first hop for payment amount plus fees"),
+		_ => panic!("Unexpected send_payment result"),
+	}
+	check_added_monitors!(nodes[1], 0);
+	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+
+	// The channel itself is still usable in the other direction
+	send_payment(&nodes[0], &[&nodes[1]], 10000);
+}
+
+#[test]
+fn test_abandon_channel() {
+	// Abandoning a channel before funding removes it on both ends, but once funding has been
+	// initiated we must refuse and leave it to force_close_channel.
+	let nodes = create_network(2, &[None, None]);
+
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100000, 10001, 42).unwrap();
+	nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), LocalFeatures::new(), &get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id())).unwrap();
+	nodes[0].node.handle_accept_channel(&nodes[1].node.get_our_node_id(), LocalFeatures::new(), &get_event_msg!(nodes[1], MessageSendEvent::SendAcceptChannel, nodes[0].node.get_our_node_id())).unwrap();
+	let (temporary_channel_id, _, funding_output) = create_funding_transaction(&nodes[0], 100000, 42);
+	assert_eq!(nodes[0].node.list_channels().len(), 1);
+	assert_eq!(nodes[1].node.list_channels().len(), 1);
+
+	nodes[0].node.abandon_channel(&temporary_channel_id).unwrap();
+	assert!(nodes[0].node.list_channels().is_empty());
+	let events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		MessageSendEvent::HandleError { ref node_id, action: Some(msgs::ErrorAction::SendErrorMessage { ref msg }) } => {
+			assert_eq!(*node_id, nodes[1].node.get_our_node_id());
+			assert_eq!(msg.channel_id, temporary_channel_id);
+			nodes[1].node.handle_error(&nodes[0].node.get_our_node_id(), msg);
+		},
+		_ => panic!("Unexpected event"),
+	}
+	assert!(nodes[1].node.list_channels().is_empty());
+
+	// Funding the abandoned channel is a no-op and we can't abandon it twice
+	nodes[0].node.funding_transaction_generated(&temporary_channel_id, funding_output);
+	check_added_monitors!(nodes[0], 0);
+	match nodes[0].node.abandon_channel(&temporary_channel_id) {
+		Err(APIError::ChannelUnavailable{..}) => {},
+		_ => panic!("Abandoned channel was still known"),
+	}
+
+	// Funded channels cannot be abandoned
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, LocalFeatures::new(), LocalFeatures::new());
+	match nodes[0].node.abandon_channel(&chan.2) {
+		Err(APIError::APIMisuseError{err}) => assert_eq!(err, "Cannot abandon a channel once funding has been initiated, use force_close_channel instead"),
+		_ => panic!("Funded channel was abandoned"),
+	}
+	assert_eq!(nodes[0].node.list_channels().len(), 1);
+}
+
+#[test]
+fn test_duplicate_funding_locked() {
+	// A peer may resend funding_locked (eg after reconnecting), which must be a no-op as long as
+	// the next_per_commitment_point matches, but a conflicting resend must fail the channel.
+	let nodes = create_network(2, &[None, None]);
+	let (as_funding_msgs, _, _) = create_chan_between_nodes_with_value_a(&nodes[0], &nodes[1], 100000, 10001, LocalFeatures::new(), LocalFeatures::new());
+	let (announcement, as_update, bs_update) = create_chan_between_nodes_with_value_b(&nodes[0], &nodes[1], &as_funding_msgs);
+	for node in nodes.iter() {
+		assert!(node.router.handle_channel_announcement(&announcement).unwrap());
+		node.router.handle_channel_update(&as_update).unwrap();
+		node.router.handle_channel_update(&bs_update).unwrap();
+	}
+
+	// The resend is accepted, with us simply re-sending our announcement_signatures in response
+	nodes[1].node.handle_funding_locked(&nodes[0].node.get_our_node_id(), &as_funding_msgs.0).unwrap();
+	get_event_msg!(nodes[1], MessageSendEvent::SendAnnouncementSignatures, nodes[0].node.get_our_node_id());
+	check_added_monitors!(nodes[1], 0);
+	assert_eq!(nodes[1].node.list_usable_channels().len(), 1);
+	send_payment(&nodes[0], &[&nodes[1]], 1000000);
+
+	let (as_funding_msgs, chan_id, _) = create_chan_between_nodes_with_value_a(&nodes[0], &nodes[1], 100000, 10001, LocalFeatures::new(), LocalFeatures::new());
+	create_chan_between_nodes_with_value_b(&nodes[0], &nodes[1], &as_funding_msgs);
+	assert_eq!(nodes[1].node.list_usable_channels().len(), 2);
+
+	let mut conflicting_funding_locked = as_funding_msgs.0.clone();
+	let secp_ctx = Secp256k1::new();
+	conflicting_funding_locked.next_per_commitment_point = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42; 32]).unwrap());
+	match nodes[1].node.handle_funding_locked(&nodes[0].node.get_our_node_id(), &conflicting_funding_locked) {
+		Err(msgs::HandleError { err, action: Some(msgs::ErrorAction::SendErrorMessage { .. }) }) => {
+			assert_eq!(err, "Peer sent a reconnect funding_locked with a different point");
+		},
+		_ => panic!("Conflicting funding_locked was accepted"),
+	}
+	check_closed_broadcast!(nodes[1]);
+	assert_eq!(nodes[1].node.list_channels().len(), 1);
+	assert!(nodes[1].node.list_channels().iter().all(|chan| chan.channel_id != chan_id));
+}
+
+#[test]
+fn test_channel_details_pending_htlc_value() {
+	// With one HTLC pending in each direction, ChannelDetails on both ends should report them as
+	// in flight.
+	let nodes = create_network(2, &[None, None]);
+	create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 50000000, LocalFeatures::new(), LocalFeatures::new());
+
+	let channels = nodes[0].node.list_channels();
+	assert_eq!(channels[0].pending_inbound_htlc_msat, 0);
+	assert_eq!(channels[0].pending_outbound_htlc_msat, 0);
+
+	let (payment_preimage_1, _) = route_payment(&nodes[0], &[&nodes[1]], 3000000);
+	let (payment_preimage_2, _) = route_payment(&nodes[1], &[&nodes[0]], 1000000);
+
+	let channels = nodes[0].node.list_channels();
+	assert_eq!(channels[0].pending_inbound_htlc_msat, 1000000);
+	assert_eq!(channels[0].pending_outbound_htlc_msat, 3000000);
+	let channels = nodes[1].node.list_channels();
+	assert_eq!(channels[0].pending_inbound_htlc_msat, 3000000);
+	assert_eq!(channels[0].pending_outbound_htlc_msat, 1000000);
+
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage_1);
+	claim_payment(&nodes[1], &[&nodes[0]], payment_preimage_2);
+
+	let channels = nodes[0].node.list_channels();
+	assert_eq!(channels[0].pending_inbound_htlc_msat, 0);
+	assert_eq!(channels[0].pending_outbound_htlc_msat, 0);
+}
+
+#[test]
+fn test_channel_details_capacity() {
+	// ChannelDetails' capacities used to be clamped with min(.., 0) instead of max(.., 0), so
+	// they were always reported as zero. They are now the largest HTLC each side could send.
+	let nodes = create_network(2, &[None, None]);
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 10001, LocalFeatures::new(), LocalFeatures::new());
+	let max_in_flight_msat = get_channel_value_stat!(nodes[0], chan.2).their_max_htlc_value_in_flight_msat;
+
+	// nodes[0] is limited by nodes[1]'s max_htlc_value_in_flight_msat, while nodes[1] has less than
+	// the reserve nodes[0] requires of it
+	let channels = nodes[0].node.list_channels();
+	assert_eq!(channels[0].outbound_capacity_msat, max_in_flight_msat);
+	assert_eq!(channels[0].inbound_capacity_msat, 0);
+	let channels = nodes[1].node.list_channels();
+	assert_eq!(channels[0].outbound_capacity_msat, 0);
+	assert_eq!(channels[0].inbound_capacity_msat, max_in_flight_msat);
+
+	// Pending HTLCs are not available to either side
+	let (payment_preimage, _) = route_payment(&nodes[0], &[&nodes[1]], 3000000);
+	let channels = nodes[0].node.list_channels();
+	assert_eq!(channels[0].outbound_capacity_msat, max_in_flight_msat - 3000000);
+	let channels = nodes[1].node.list_channels();
+	assert_eq!(channels[0].inbound_capacity_msat, max_in_flight_msat - 3000000);
+
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+	let bs_reserve_msat = get_channel_value_stat!(nodes[1], chan.2).channel_reserve_msat;
+	let channels = nodes[1].node.list_usable_channels();
+	assert_eq!(channels[0].outbound_capacity_msat, 10001 + 3000000 - bs_reserve_msat);
+	assert_eq!(channels[0].inbound_capacity_msat, max_in_flight_msat);
+}
+
+#[test]
+fn test_unexpected_revoke_and_ack() {
+	// A revoke_and_ack received while we have no commitment_signed awaiting revocation must not be
+	// applied, and should instead cause us to disconnect (without closing the channel) so that
+	// channel_reestablish can resync us.
+	let nodes = create_network(2, &[None, None]);
+	create_announced_chan_between_nodes(&nodes, 0, 1, LocalFeatures::new(), LocalFeatures::new());
+
+	let (payment_preimage, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+	let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+	nodes[0].node.send_payment(route, payment_hash).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	let payment_event = SendEvent::from_node(&nodes[0]);
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]).unwrap();
+	nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), &payment_event.commitment_msg).unwrap();
+	check_added_monitors!(nodes[1], 1);
+	let (bs_revoke_and_ack, bs_commitment_signed) = get_revoke_commit_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	nodes[0].node.handle_revoke_and_ack(&nodes[1].node.get_our_node_id(), &bs_revoke_and_ack).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &bs_commitment_signed).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	let as_revoke_and_ack = get_event_msg!(nodes[0], MessageSendEvent::SendRevokeAndACK, nodes[1].node.get_our_node_id());
+	nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &as_revoke_and_ack).unwrap();
+	check_added_monitors!(nodes[1], 1);
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	expect_payment_received!(nodes[1], payment_hash, 1000000);
+
+	// nodes[1] is no longer waiting on a revocation, so a (replayed) revoke_and_ack is unexpected
+	match nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &as_revoke_and_ack) {
+		Err(msgs::HandleError { err, action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }) }) => {
+			assert_eq!(err, "Peer sent revoke_and_ack when we had no commitment_signed awaiting revocation");
+		},
+		_ => panic!("Unexpected revoke_and_ack was not rejected"),
+	}
+	check_added_monitors!(nodes[1], 0);
+	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+	assert_eq!(nodes[1].node.list_channels().len(), 1);
+
+	// After the disconnect we resync cleanly and can still claim the payment
+	nodes[0].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
+	nodes[1].node.peer_disconnected(&nodes[0].node.get_our_node_id(), false);
+	reconnect_nodes(&nodes[0], &nodes[1], (false, false), (0, 0), (0, 0), (0, 0), (0, 0), (false, false));
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+}
+
+#[test]
+fn test_broadcast_fresh_channel_updates() {
+	// broadcast_fresh_channel_updates should generate exactly one channel_update for each usable,
+	// announced channel, each time with a strictly newer timestamp.
+	let nodes = create_network(3, &[None, None, None]);
+	let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1, LocalFeatures::new(), LocalFeatures::new());
+	let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2, LocalFeatures::new(), LocalFeatures::new());
+	// An unfunded channel isn't usable and shouldn't get a channel_update
+	nodes[1].node.create_channel(nodes[2].node.get_our_node_id(), 100000, 10001, 42).unwrap();
+	get_event_msg!(nodes[1], MessageSendEvent::SendOpenChannel, nodes[2].node.get_our_node_id());
+	assert_eq!(nodes[1].node.list_channels().len(), 3);
+
+	let mut last_timestamps = HashMap::new();
+	last_timestamps.insert(chan_1.1.contents.short_channel_id, chan_1.1.contents.timestamp);
+	last_timestamps.insert(chan_2.0.contents.short_channel_id, chan_2.0.contents.timestamp);
+
+	for _ in 0..2 {
+		nodes[1].node.broadcast_fresh_channel_updates();
+		let events = nodes[1].node.get_and_clear_pending_msg_events();
+		assert_eq!(events.len(), 2);
+		let mut seen = HashSet::new();
+		for event in events {
+			match event {
+				MessageSendEvent::BroadcastChannelUpdate { msg } => {
+					assert!(seen.insert(msg.contents.short_channel_id));
+					let last_timestamp = last_timestamps.get_mut(&msg.contents.short_channel_id).unwrap();
+					assert!(msg.contents.timestamp > *last_timestamp);
+					*last_timestamp = msg.contents.timestamp;
+					// Other nodes accept the update as superseding the previous one
+					nodes[0].router.handle_channel_update(&msg).unwrap();
+				},
+				_ => panic!("Unexpected event"),
+			}
+		}
+	}
+}
+
+#[test]
+fn test_update_channel_config() {
+	// Changing a channel's forwarding policy should generate a new channel_update which carries
+	// the new policy and supersedes the old one, and new forwards should be held to it.
+	let nodes = create_network(3, &[None, None, None]);
+	let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1, LocalFeatures::new(), LocalFeatures::new());
+	let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2, LocalFeatures::new(), LocalFeatures::new());
+
+	let mut config = ChannelConfig::new();
+	config.cltv_expiry_delta = 5;
+	match nodes[1].node.update_channel_config(&chan_2.2, &config) {
+		Err(APIError::APIMisuseError { err }) => assert_eq!(err, "cltv_expiry_delta is too small to safely forward HTLCs"),
+		_ => panic!("Unexpected result"),
+	}
+	match nodes[1].node.update_channel_config(&[42; 32], &ChannelConfig::new()) {
+		Err(APIError::ChannelUnavailable { err }) => assert_eq!(err, "No such channel"),
+		_ => panic!("Unexpected result"),
+	}
+	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+
+	config.cltv_expiry_delta = 100;
+	config.fee_base_msat = Some(1000);
+	config.fee_proportional_millionths = 100;
+	nodes[1].node.update_channel_config(&chan_2.2, &config).unwrap();
+	let events = nodes[1].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let update = match events[0] {
+		MessageSendEvent::BroadcastChannelUpdate { ref msg } => msg.clone(),
+		_ => panic!("Unexpected event"),
+	};
+	assert_eq!(update.contents.short_channel_id, chan_2.0.contents.short_channel_id);
+	assert!(update.contents.timestamp > chan_2.0.contents.timestamp);
+	assert_eq!(update.contents.fee_base_msat, 1000);
+	assert_eq!(update.contents.fee_proportional_millionths, 100);
+	assert_eq!(update.contents.cltv_expiry_delta, 100);
+	for node in nodes.iter() {
+		node.router.handle_channel_update(&update).unwrap();
+	}
+
+	// A route built with the new policy pays the new fee and succeeds
+	let route = nodes[0].router.get_route(&nodes[2].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+	assert_eq!(route.hops[0].fee_msat, 1000 + 100);
+	assert_eq!(route.hops[0].cltv_expiry_delta, 100);
+	send_along_route(&nodes[0], route, &[&nodes[1], &nodes[2]], 1000000);
+
+	// The other channel's policy was left untouched
+	nodes[1].node.broadcast_fresh_channel_updates();
+	for event in nodes[1].node.get_and_clear_pending_msg_events() {
+		match event {
+			MessageSendEvent::BroadcastChannelUpdate { msg } => {
+				if msg.contents.short_channel_id == chan_1.0.contents.short_channel_id {
+					assert_eq!(msg.contents.fee_proportional_millionths, 0);
+					assert_eq!(msg.contents.cltv_expiry_delta, chan_1.1.contents.cltv_expiry_delta);
+				} else {
+					assert_eq!(msg.contents.fee_base_msat, 1000);
+				}
+			},
+			_ => panic!("Unexpected event"),
+		}
+	}
+}
+
+#[test]
+fn test_create_channel_with_announce() {
+	// The per-channel announce option overrides our default config, and only announced channels
+	// get announcement_signatures, a channel_announcement or broadcast channel_updates.
+	let mut private_config = UserConfig::new();
+	private_config.channel_options.announced_channel = false;
+	private_config.peer_channel_config_limits.force_announced_channel_preference = false;
+	let nodes = create_network(3, &[Some(private_config), None, None]);
+
+	let mut announcement = None;
+	for &(node_b, announce) in [(1, false), (2, true)].iter() {
+		nodes[0].node.create_channel_with_announce(nodes[node_b].node.get_our_node_id(), 100000, 10001, 42, announce).unwrap();
+		let open_channel = get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[node_b].node.get_our_node_id());
+		assert_eq!(open_channel.channel_flags & 1 == 1, announce);
+		nodes[node_b].node.handle_open_channel(&nodes[0].node.get_our_node_id(), LocalFeatures::new(), &open_channel).unwrap();
+		nodes[0].node.handle_accept_channel(&nodes[node_b].node.get_our_node_id(), LocalFeatures::new(), &get_event_msg!(nodes[node_b], MessageSendEvent::SendAcceptChannel, nodes[0].node.get_our_node_id())).unwrap();
+
+		let (temporary_channel_id, tx, funding_output) = create_funding_transaction(&nodes[0], 100000, 42);
+		nodes[0].node.funding_transaction_generated(&temporary_channel_id, funding_output);
+		check_added_monitors!(nodes[0], 1);
+		nodes[node_b].node.handle_funding_created(&nodes[0].node.get_our_node_id(), &get_event_msg!(nodes[0], MessageSendEvent::SendFundingCreated, nodes[node_b].node.get_our_node_id())).unwrap();
+		check_added_monitors!(nodes[node_b], 1);
+		nodes[0].node.handle_funding_signed(&nodes[node_b].node.get_our_node_id(), &get_event_msg!(nodes[node_b], MessageSendEvent::SendFundingSigned, nodes[0].node.get_our_node_id())).unwrap();
+		check_added_monitors!(nodes[0], 1);
+		assert_eq!(nodes[0].node.get_and_clear_pending_events().len(), 1);
+
+		create_chan_between_nodes_with_value_confirm_first(&nodes[0], &nodes[node_b], &tx);
+		confirm_transaction(&nodes[0].chain_monitor, &tx, tx.version);
+		if announce {
+			// The announced channel goes on to produce a channel_announcement
+			let as_funding_msgs = create_chan_between_nodes_with_value_confirm_second(&nodes[node_b], &nodes[0]).0;
+			announcement = Some(create_chan_between_nodes_with_value_b(&nodes[0], &nodes[node_b], &as_funding_msgs).0);
+		} else {
+			// While the private channel only exchanges funding_locked, followed by channel_updates
+			// sent directly to the counterparty
+			let events = nodes[0].node.get_and_clear_pending_msg_events();
+			assert_eq!(events.len(), 2);
+			match events[0] {
+				MessageSendEvent::SendFundingLocked { ref node_id, ref msg } => {
+					assert_eq!(*node_id, nodes[node_b].node.get_our_node_id());
+					nodes[node_b].node.handle_funding_locked(&nodes[0].node.get_our_node_id(), msg).unwrap();
+				},
+				_ => panic!("Unexpected event"),
+			}
+			match events[1] {
+				MessageSendEvent::SendChannelUpdate { ref node_id, .. } => assert_eq!(*node_id, nodes[node_b].node.get_our_node_id()),
+				_ => panic!("Unexpected event"),
+			}
+			get_event_msg!(nodes[node_b], MessageSendEvent::SendChannelUpdate, nodes[0].node.get_our_node_id());
+		}
+	}
+	assert_eq!(nodes[0].node.list_usable_channels().len(), 2);
+	let announcement = announcement.unwrap();
+	assert!(nodes[2].router.handle_channel_announcement(&announcement).unwrap());
+
+	// Only the announced channel gets a fresh channel_update
+	nodes[0].node.broadcast_fresh_channel_updates();
+	let events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		MessageSendEvent::BroadcastChannelUpdate { ref msg } => assert_eq!(msg.contents.short_channel_id, announcement.contents.short_channel_id),
+		_ => panic!("Unexpected event"),
+	}
+
+	// Nor is the private channel revealed when it is closed
+	let private_chan_id = nodes[0].node.list_channels().iter().find(|chan| chan.remote_network_id == nodes[1].node.get_our_node_id()).unwrap().channel_id;
+	nodes[0].node.force_close_channel(&private_chan_id).unwrap();
+	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
+}
+
+#[test]
+fn test_events_cleared_on_get() {
+	// Events are queued until the user polls for them, at which point the queue is drained.
+	let nodes = create_network(2, &[None, None]);
+
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100000, 10001, 42).unwrap();
+	nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), LocalFeatures::new(), &get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id())).unwrap();
+	assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+	nodes[0].node.handle_accept_channel(&nodes[1].node.get_our_node_id(), LocalFeatures::new(), &get_event_msg!(nodes[1], MessageSendEvent::SendAcceptChannel, nodes[0].node.get_our_node_id())).unwrap();
+
+	let events = nodes[0].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::FundingGenerationReady { channel_value_satoshis, user_channel_id, .. } => {
+			assert_eq!(channel_value_satoshis, 100000);
+			assert_eq!(user_channel_id, 42);
+		},
+		_ => panic!("Unexpected event"),
+	}
+	assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+	// Payment events are likewise only returned once
+	create_announced_chan_between_nodes(&nodes, 0, 1, LocalFeatures::new(), LocalFeatures::new());
+	let (payment_preimage, _) = route_payment(&nodes[0], &[&nodes[1]], 1000000);
+	assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+	assert!(nodes[1].node.claim_funds(payment_preimage));
+	check_added_monitors!(nodes[1], 1);
+	let updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	nodes[0].node.handle_update_fulfill_htlc(&nodes[1].node.get_our_node_id(), &updates.update_fulfill_htlcs[0]).unwrap();
+	let events = nodes[0].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PaymentSent { payment_preimage: ref preimage } => assert_eq!(*preimage, payment_preimage),
+		_ => panic!("Unexpected event"),
+	}
+	assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+}
+
+#[test]
+fn test_open_channel_surfaces_as_msg_event() {
+	// create_channel enqueues a SendOpenChannel for the peer, which is returned exactly once via
+	// MessageSendEventsProvider.
+	let nodes = create_network(2, &[None, None]);
+	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
+
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100000, 10001, 42).unwrap();
+	let events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		MessageSendEvent::SendOpenChannel { ref node_id, ref msg } => {
+			assert_eq!(*node_id, nodes[1].node.get_our_node_id());
+			assert_eq!(msg.funding_satoshis, Satoshi(100000));
+			assert_eq!(msg.push_msat, MilliSatoshi(10001));
+		},
+		_ => panic!("Unexpected event"),
+	}
+	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
+}
+
+#[test]
+fn test_htlcs_expiring_before() {
+	let nodes = create_network(2, &[None, None]);
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, LocalFeatures::new(), LocalFeatures::new());
+
+	let cur_height = nodes[0].node.latest_block_height.load(Ordering::Acquire) as u32 + 1;
+	let (payment_preimage, payment_hash) = route_payment(&nodes[0], &[&nodes[1]], 1000000);
+	let htlc_cltv = cur_height + TEST_FINAL_CLTV;
+	let buffer = UserConfig::new().channel_options.htlc_expiry_buffer_blocks;
+
+	{
+		let channel_state = nodes[0].node.channel_state.lock().unwrap();
+		let channel = channel_state.by_id.get(&chan.2).unwrap();
+		// An HTLC expiring exactly at height + buffer is reported, one further out isn't yet
+		let expiring = channel.get_outbound_htlcs_expiring_before(htlc_cltv - buffer);
+		assert_eq!(expiring.len(), 1);
+		assert_eq!(expiring[0].0, payment_hash);
+		assert!(channel.get_outbound_htlcs_expiring_before(htlc_cltv - buffer - 1).is_empty());
+	}
+	{
+		// Until the recipient learns the preimage it has nothing to claim
+		let channel_state = nodes[1].node.channel_state.lock().unwrap();
+		assert!(channel_state.by_id.get(&chan.2).unwrap().get_claimable_inbound_htlcs_expiring_before(htlc_cltv).is_empty());
+	}
+
+	// Once the recipient claims the HTLC it has to see the claim through before the HTLC expires
+	assert!(nodes[1].node.claim_funds(payment_preimage));
+	check_added_monitors!(nodes[1], 1);
+	{
+		let channel_state = nodes[1].node.channel_state.lock().unwrap();
+		let channel = channel_state.by_id.get(&chan.2).unwrap();
+		assert_eq!(channel.get_claimable_inbound_htlcs_expiring_before(htlc_cltv - buffer), vec![(payment_hash, payment_preimage)]);
+		assert!(channel.get_claimable_inbound_htlcs_expiring_before(htlc_cltv - buffer - 1).is_empty());
+		assert!(channel.get_outbound_htlcs_expiring_before(htlc_cltv).is_empty());
+	}
+
+	// Once the claim is irrevocably committed the HTLC is no longer either side's to worry about
+	let updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	nodes[0].node.handle_update_fulfill_htlc(&nodes[1].node.get_our_node_id(), &updates.update_fulfill_htlcs[0]).unwrap();
+	commitment_signed_dance!(nodes[0], nodes[1], updates.commitment_signed, false);
+	expect_payment_sent!(nodes[0], payment_preimage);
+	let channel_state = nodes[0].node.channel_state.lock().unwrap();
+	assert!(channel_state.by_id.get(&chan.2).unwrap().get_outbound_htlcs_expiring_before(htlc_cltv).is_empty());
+	let channel_state = nodes[1].node.channel_state.lock().unwrap();
+	assert!(channel_state.by_id.get(&chan.2).unwrap().get_claimable_inbound_htlcs_expiring_before(htlc_cltv).is_empty());
+}
@@ -0,0 +1,933 @@
+//! Parsing and creation of BOLT #11 invoices.
+//!
+//! An invoice is a bech32 string whose human-readable part holds the network and amount, and
+//! whose data holds a timestamp, a list of tagged fields and a recoverable signature by the payee.
+
+use ln::channelmanager::PaymentHash;
+use ln::msgs::HandleError;
+use ln::router::RouteHint;
+use util::bech32::{self, u5};
+use util::byte_utils;
+
+use bitcoin::network::constants::Network;
+
+use bitcoin_hashes::sha256::Hash as Sha256;
+use bitcoin_hashes::{Hash, HashEngine};
+
+use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::recovery::{RecoverableSignature, RecoveryId};
+use secp256k1::{Message, Secp256k1};
+
+use std::fmt;
+use std::time::Duration;
+
+const MSAT_PER_BTC: u64 = 100_000_000_000;
+
+/// The expiry time, in seconds, of an invoice which has no expiry field
+pub const DEFAULT_EXPIRY_TIME: u64 = 3600;
+/// The min_final_cltv_expiry of an invoice which has no min_final_cltv_expiry field
+pub const DEFAULT_MIN_FINAL_CLTV_EXPIRY: u64 = 9;
+
+const TIMESTAMP_LEN: usize = 7;
+const SIGNATURE_LEN: usize = 104;
+
+const TAG_PAYMENT_HASH: u8 = 1;
+const TAG_ROUTE: u8 = 3;
+const TAG_EXPIRY_TIME: u8 = 6;
+const TAG_FALLBACK: u8 = 9;
+const TAG_DESCRIPTION: u8 = 13;
+const TAG_PAYEE_PUB_KEY: u8 = 19;
+const TAG_DESCRIPTION_HASH: u8 = 23;
+const TAG_MIN_FINAL_CLTV_EXPIRY: u8 = 24;
+
+/// The length of a single hop in an r field: pubkey, short_channel_id, fee_base_msat,
+/// fee_proportional_millionths and cltv_expiry_delta.
+const ROUTE_HOP_LEN: usize = 33 + 8 + 4 + 4 + 2;
+/// Tagged field lengths are encoded in two 5-bit characters
+const MAX_FIELD_LEN: usize = 1023;
+
+macro_rules! invalid_invoice {
+	($err: expr) => {
+		return Err(HandleError {
+			err: $err,
+			action: None,
+		})
+	}
+}
+
+fn parse_decimal(digits: &str) -> Result<u64, HandleError> {
+	if digits.is_empty() {
+		invalid_invoice!("Invoice amount had no digits");
+	}
+	if digits.starts_with('0') {
+		invalid_invoice!("Invoice amount had a leading zero");
+	}
+	let mut res: u64 = 0;
+	for c in digits.chars() {
+		let digit = match c.to_digit(10) {
+			Some(digit) => digit as u64,
+			None => invalid_invoice!("Invoice amount was not a decimal number"),
+		};
+		res = match res.checked_mul(10).and_then(|res| res.checked_add(digit)) {
+			Some(res) => res,
+			None => invalid_invoice!("Invoice amount overflowed"),
+		};
+	}
+	Ok(res)
+}
+
+/// Parses the amount from an invoice's human-readable part (the part after the currency prefix),
+/// a decimal number of bitcoin optionally followed by a multiplier: m (milli), u (micro),
+/// n (nano) or p (pico). Returns the amount in millisatoshis, failing if it doesn't fit in a u64
+/// or, for pico amounts, isn't a whole number of millisatoshis.
+pub fn parse_amount_msat(amount: &str) -> Result<u64, HandleError> {
+	let (digits, msat_per_unit) = match amount.chars().last() {
+		Some('m') => (&amount[..amount.len() - 1], MSAT_PER_BTC / 1_000),
+		Some('u') => (&amount[..amount.len() - 1], MSAT_PER_BTC / 1_000_000),
+		Some('n') => (&amount[..amount.len() - 1], MSAT_PER_BTC / 1_000_000_000),
+		Some('p') => {
+			// A pico-bitcoin is a tenth of a msat, so drop the last digit (which must be 0) rather
+			// than dividing, which also lets amounts up to u64::MAX msat be parsed.
+			let digits = &amount[..amount.len() - 1];
+			if digits.len() < 2 || !digits.ends_with('0') {
+				invalid_invoice!("Invoice pico amount was not a whole number of millisatoshis");
+			}
+			(&digits[..digits.len() - 1], 1)
+		},
+		_ => (amount, MSAT_PER_BTC),
+	};
+	match parse_decimal(digits)?.checked_mul(msat_per_unit) {
+		Some(msat) => Ok(msat),
+		None => invalid_invoice!("Invoice amount overflowed"),
+	}
+}
+
+/// Encodes an amount for an invoice's human-readable part, using the largest multiplier which
+/// represents it exactly.
+fn encode_amount_msat(amount_msat: u64) -> String {
+	for &(msat_per_unit, multiplier) in [(MSAT_PER_BTC, ""), (MSAT_PER_BTC / 1_000, "m"), (MSAT_PER_BTC / 1_000_000, "u"), (MSAT_PER_BTC / 1_000_000_000, "n")].iter() {
+		if amount_msat % msat_per_unit == 0 {
+			return format!("{}{}", amount_msat / msat_per_unit, multiplier);
+		}
+	}
+	format!("{}0p", amount_msat)
+}
+
+/// The network an invoice is for, from the currency prefix of its human-readable part
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Currency {
+	/// Bitcoin mainnet ("bc")
+	Bitcoin,
+	/// Bitcoin testnet ("tb")
+	BitcoinTestnet,
+	/// Bitcoin regtest ("bcrt")
+	Regtest,
+	/// Bitcoin simnet ("sb")
+	Simnet,
+}
+
+impl Currency {
+	/// Gets the currency used for invoices on the given network
+	pub fn from_network(network: Network) -> Currency {
+		match network {
+			Network::Bitcoin => Currency::Bitcoin,
+			Network::Testnet => Currency::BitcoinTestnet,
+			Network::Regtest => Currency::Regtest,
+		}
+	}
+
+	/// Gets the currency prefix used in the human-readable part
+	pub fn prefix(&self) -> &'static str {
+		match *self {
+			Currency::Bitcoin => "bc",
+			Currency::BitcoinTestnet => "tb",
+			Currency::Regtest => "bcrt",
+			Currency::Simnet => "sb",
+		}
+	}
+}
+
+/// An on-chain address the payer may use instead if the payment can't be made over lightning
+#[derive(Clone, PartialEq, Debug)]
+pub enum Fallback {
+	/// A segwit output with the given witness version (0-16) and program
+	SegWitProgram {
+		/// The witness version
+		version: u8,
+		/// The witness program
+		program: Vec<u8>,
+	},
+	/// A P2PKH output paying to the given public key hash
+	PubKeyHash([u8; 20]),
+	/// A P2SH output paying to the given script hash
+	ScriptHash([u8; 20]),
+	/// A fallback with a version we don't understand, kept as its raw bytes
+	Unknown {
+		/// The version, from 19 to 31
+		version: u8,
+		/// The field data following the version
+		data: Vec<u8>,
+	},
+}
+
+impl Fallback {
+	fn read(value: &[u5]) -> Result<Fallback, HandleError> {
+		if value.is_empty() {
+			invalid_invoice!("Invoice fallback address had no version");
+		}
+		let version = value[0].to_u8();
+		let data = bech32::u5s_to_bytes(&value[1..]);
+		let read_hash = |data: &[u8]| -> Result<[u8; 20], HandleError> {
+			if data.len() != 20 {
+				invalid_invoice!("Invoice fallback address hash was not 20 bytes");
+			}
+			let mut hash = [0; 20];
+			hash.copy_from_slice(data);
+			Ok(hash)
+		};
+		match version {
+			17 => Ok(Fallback::PubKeyHash(read_hash(&data)?)),
+			18 => Ok(Fallback::ScriptHash(read_hash(&data)?)),
+			_ if version <= 16 => {
+				if data.len() < 2 || data.len() > 40 || (version == 0 && data.len() != 20 && data.len() != 32) {
+					invalid_invoice!("Invoice fallback witness program had an invalid length");
+				}
+				Ok(Fallback::SegWitProgram { version, program: data })
+			},
+			_ => Ok(Fallback::Unknown { version, data }),
+		}
+	}
+}
+
+/// A BOLT #11 invoice whose signature has been checked.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Invoice {
+	currency: Currency,
+	amount_msat: Option<u64>,
+	timestamp: u64,
+	payment_hash: PaymentHash,
+	description: Option<String>,
+	description_hash: Option<[u8; 32]>,
+	payee_pub_key: PublicKey,
+	expiry_time: Option<u64>,
+	min_final_cltv_expiry: Option<u64>,
+	fallbacks: Vec<Fallback>,
+	route_hints: Vec<Vec<RouteHint>>,
+	encoded: String,
+}
+
+fn read_be_u5s(data: &[u5]) -> Result<u64, HandleError> {
+	if data.len() > 12 {
+		invalid_invoice!("Invoice integer field was too long");
+	}
+	Ok(data.iter().fold(0, |acc, v| (acc << 5) | v.to_u8() as u64))
+}
+
+// Writes v big-endian in 5-bit characters, zero-padded to len characters if given and otherwise
+// without leading zeros.
+fn write_be_u5s(mut v: u64, len: Option<usize>) -> Vec<u5> {
+	let mut res = Vec::new();
+	while v != 0 || len.map_or(false, |len| res.len() < len) {
+		res.insert(0, u5::try_from_u8((v & 0x1f) as u8).unwrap());
+		v >>= 5;
+	}
+	res
+}
+
+/// Gets the message the payee signs: the hash of the human-readable part followed by the data
+/// (without the signature), zero-padded to a byte boundary.
+fn signature_hash(hrp: &str, data: &[u5]) -> Message {
+	let mut engine = Sha256::engine();
+	engine.input(hrp.as_bytes());
+	engine.input(&bech32::u5s_to_padded_bytes(data));
+	hash_to_message!(&Sha256::from_engine(engine)[..])
+}
+
+fn push_field(data: &mut Vec<u5>, tag: u8, value: &[u5]) -> Result<(), HandleError> {
+	if value.len() > MAX_FIELD_LEN {
+		invalid_invoice!("Invoice tagged field was too long");
+	}
+	data.push(u5::try_from_u8(tag).unwrap());
+	data.extend_from_slice(&write_be_u5s(value.len() as u64, Some(2)));
+	data.extend_from_slice(value);
+	Ok(())
+}
+
+fn read_route(value: &[u5]) -> Result<Vec<RouteHint>, HandleError> {
+	let data = bech32::u5s_to_bytes(value);
+	if data.is_empty() || data.len() % ROUTE_HOP_LEN != 0 {
+		invalid_invoice!("Invoice route hint had an invalid length");
+	}
+	let mut hops = Vec::with_capacity(data.len() / ROUTE_HOP_LEN);
+	for hop in data.chunks(ROUTE_HOP_LEN) {
+		let src_node_id = match PublicKey::from_slice(&hop[..33]) {
+			Ok(key) => key,
+			Err(_) => invalid_invoice!("Invoice route hint public key was invalid"),
+		};
+		hops.push(RouteHint {
+			src_node_id,
+			short_channel_id: byte_utils::slice_to_be64(&hop[33..41]),
+			fee_base_msat: byte_utils::slice_to_be32(&hop[41..45]),
+			fee_proportional_millionths: byte_utils::slice_to_be32(&hop[45..49]),
+			cltv_expiry_delta: byte_utils::slice_to_be16(&hop[49..51]),
+			htlc_minimum_msat: 0,
+		});
+	}
+	Ok(hops)
+}
+
+fn write_route(hops: &[RouteHint]) -> Vec<u5> {
+	let mut data = Vec::with_capacity(hops.len() * ROUTE_HOP_LEN);
+	for hop in hops {
+		data.extend_from_slice(&hop.src_node_id.serialize());
+		data.extend_from_slice(&byte_utils::be64_to_array(hop.short_channel_id));
+		data.extend_from_slice(&byte_utils::be32_to_array(hop.fee_base_msat));
+		data.extend_from_slice(&byte_utils::be32_to_array(hop.fee_proportional_millionths));
+		data.extend_from_slice(&byte_utils::be16_to_array(hop.cltv_expiry_delta));
+	}
+	bech32::bytes_to_u5s(&data)
+}
+
+fn parse_hrp(hrp: &str) -> Result<(Currency, Option<u64>), HandleError> {
+	if !hrp.starts_with("ln") {
+		invalid_invoice!("Invoice human-readable part did not start with ln");
+	}
+	let rest = &hrp[2..];
+	// bcrt must be tried before bc, which is a prefix of it
+	for currency in [Currency::Regtest, Currency::Bitcoin, Currency::BitcoinTestnet, Currency::Simnet].iter() {
+		if rest.starts_with(currency.prefix()) {
+			let amount = &rest[currency.prefix().len()..];
+			if amount.is_empty() {
+				return Ok((*currency, None));
+			}
+			return Ok((*currency, Some(parse_amount_msat(amount)?)));
+		}
+	}
+	invalid_invoice!("Unknown invoice currency prefix")
+}
+
+impl Invoice {
+	/// Parses an invoice, checking its signature. The payee's key is taken from the n field if
+	/// present and otherwise recovered from the signature.
+	pub fn parse(s: &str) -> Result<Invoice, HandleError> {
+		let (hrp, data) = bech32::decode_without_length_limit(s)?;
+		let (currency, amount_msat) = parse_hrp(&hrp)?;
+		if data.len() < TIMESTAMP_LEN + SIGNATURE_LEN {
+			invalid_invoice!("Invoice data was too short");
+		}
+		let (signed, sig) = data.split_at(data.len() - SIGNATURE_LEN);
+		let timestamp = read_be_u5s(&signed[..TIMESTAMP_LEN])?;
+
+		let mut payment_hash = None;
+		let mut description = None;
+		let mut description_hash = None;
+		let mut payee_pub_key = None;
+		let mut expiry_time = None;
+		let mut min_final_cltv_expiry = None;
+		let mut fallbacks = Vec::new();
+		let mut route_hints = Vec::new();
+
+		let mut fields = &signed[TIMESTAMP_LEN..];
+		while !fields.is_empty() {
+			if fields.len() < 3 {
+				invalid_invoice!("Invoice tagged field was truncated");
+			}
+			let len = (fields[1].to_u8() as usize) << 5 | fields[2].to_u8() as usize;
+			if fields.len() < 3 + len {
+				invalid_invoice!("Invoice tagged field was truncated");
+			}
+			let tag = fields[0].to_u8();
+			let value = &fields[3..3 + len];
+			fields = &fields[3 + len..];
+
+			// Per the spec, p, h and n fields of the wrong length are skipped, as are unknown
+			// fields. Only the first of each field is used.
+			match tag {
+				TAG_PAYMENT_HASH if len == 52 && payment_hash.is_none() => {
+					let mut hash = [0; 32];
+					hash.copy_from_slice(&bech32::u5s_to_bytes(value)[..32]);
+					payment_hash = Some(PaymentHash(hash));
+				},
+				TAG_DESCRIPTION if description.is_none() => {
+					match String::from_utf8(bech32::u5s_to_bytes(value)) {
+						Ok(desc) => description = Some(desc),
+						Err(_) => invalid_invoice!("Invoice description was not valid UTF-8"),
+					}
+				},
+				TAG_PAYEE_PUB_KEY if len == 53 && payee_pub_key.is_none() => {
+					match PublicKey::from_slice(&bech32::u5s_to_bytes(value)) {
+						Ok(key) => payee_pub_key = Some(key),
+						Err(_) => invalid_invoice!("Invoice payee public key was invalid"),
+					}
+				},
+				TAG_DESCRIPTION_HASH if len == 52 && description_hash.is_none() => {
+					let mut hash = [0; 32];
+					hash.copy_from_slice(&bech32::u5s_to_bytes(value)[..32]);
+					description_hash = Some(hash);
+				},
+				TAG_EXPIRY_TIME if expiry_time.is_none() => expiry_time = Some(read_be_u5s(value)?),
+				TAG_MIN_FINAL_CLTV_EXPIRY if min_final_cltv_expiry.is_none() => min_final_cltv_expiry = Some(read_be_u5s(value)?),
+				TAG_FALLBACK => fallbacks.push(Fallback::read(value)?),
+				TAG_ROUTE => route_hints.push(read_route(value)?),
+				_ => {},
+			}
+		}
+
+		let payment_hash = match payment_hash {
+			Some(hash) => hash,
+			None => invalid_invoice!("Invoice had no payment hash"),
+		};
+		if description.is_none() && description_hash.is_none() {
+			invalid_invoice!("Invoice had neither a description nor a description hash");
+		}
+
+		let sig_bytes = bech32::u5s_to_bytes(sig);
+		let recovery_id = match RecoveryId::from_i32(sig_bytes[64] as i32) {
+			Ok(id) => id,
+			Err(_) => invalid_invoice!("Invoice signature had an invalid recovery id"),
+		};
+		let sig = match RecoverableSignature::from_compact(&sig_bytes[..64], recovery_id) {
+			Ok(sig) => sig,
+			Err(_) => invalid_invoice!("Invoice signature was invalid"),
+		};
+		let msg_hash = signature_hash(&hrp, signed);
+		let recovered = match Secp256k1::verification_only().recover(&msg_hash, &sig) {
+			Ok(key) => key,
+			Err(_) => invalid_invoice!("Invoice signature was invalid"),
+		};
+		let payee_pub_key = match payee_pub_key {
+			Some(key) if key != recovered => invalid_invoice!("Invoice signature did not match its payee public key"),
+			_ => recovered,
+		};
+
+		Ok(Invoice {
+			currency,
+			amount_msat,
+			timestamp,
+			payment_hash,
+			description,
+			description_hash,
+			payee_pub_key,
+			expiry_time,
+			min_final_cltv_expiry,
+			fallbacks,
+			route_hints,
+			encoded: s.to_lowercase(),
+		})
+	}
+
+	/// Gets the network this invoice is for
+	pub fn currency(&self) -> Currency {
+		self.currency
+	}
+
+	/// Gets the amount requested, if any, in millisatoshis
+	pub fn amount_msat(&self) -> Option<u64> {
+		self.amount_msat
+	}
+
+	/// Gets the amount, in millisatoshis, to pay this invoice given the amount the payer chose, if
+	/// any. An invoice without an amount (which lets the payer choose) can only be paid with an
+	/// explicit amount, and one with an amount may only be overpaid, never underpaid.
+	pub fn amount_to_pay_msat(&self, payer_amount_msat: Option<u64>) -> Result<u64, HandleError> {
+		match (self.amount_msat, payer_amount_msat) {
+			(None, None) => Err(HandleError{err: "Invoice has no amount, so an amount to pay must be specified", action: None}),
+			(_, Some(0)) => Err(HandleError{err: "Cannot pay an invoice with a zero amount", action: None}),
+			(Some(amount_msat), Some(payer_amount_msat)) if payer_amount_msat < amount_msat =>
+				Err(HandleError{err: "Amount to pay was less than the invoice amount", action: None}),
+			(_, Some(payer_amount_msat)) => Ok(payer_amount_msat),
+			(Some(amount_msat), None) => Ok(amount_msat),
+		}
+	}
+
+	/// Gets the time the invoice was created, in seconds since the UNIX epoch
+	pub fn timestamp(&self) -> u64 {
+		self.timestamp
+	}
+
+	/// Gets the hash of the preimage the payee will reveal once paid
+	pub fn payment_hash(&self) -> &PaymentHash {
+		&self.payment_hash
+	}
+
+	/// Gets the description of the purpose of the payment, if one was included
+	pub fn description(&self) -> Option<&str> {
+		self.description.as_ref().map(|d| d.as_str())
+	}
+
+	/// Gets the SHA256 of a description which was too long to include, if one was given
+	pub fn description_hash(&self) -> Option<&[u8; 32]> {
+		self.description_hash.as_ref()
+	}
+
+	/// Gets the node_id of the payee
+	pub fn payee_pub_key(&self) -> &PublicKey {
+		&self.payee_pub_key
+	}
+
+	/// Gets the min_final_cltv_expiry the payee asked for, if any
+	pub fn min_final_cltv_expiry(&self) -> Option<u64> {
+		self.min_final_cltv_expiry
+	}
+
+	/// Gets the first on-chain fallback address, if any were included
+	pub fn fallback(&self) -> Option<&Fallback> {
+		self.fallbacks.first()
+	}
+
+	/// Gets all the on-chain fallback addresses, in the order they appeared
+	pub fn fallbacks(&self) -> &[Fallback] {
+		&self.fallbacks
+	}
+
+	/// Gets how long after its timestamp the invoice expires, which is DEFAULT_EXPIRY_TIME seconds
+	/// if the invoice doesn't say.
+	pub fn expiry_time(&self) -> Duration {
+		Duration::from_secs(self.expiry_time.unwrap_or(DEFAULT_EXPIRY_TIME))
+	}
+
+	/// Returns true if the invoice has expired at the given time, in seconds since the UNIX epoch.
+	/// We have no clock of our own, so the caller must provide the current time.
+	pub fn is_expired(&self, now_unix: u64) -> bool {
+		now_unix >= self.timestamp.saturating_add(self.expiry_time().as_secs())
+	}
+
+	/// Gets the private route hints from the r fields, each a path of hops ending at the payee.
+	/// The last hop of each may be passed to Router::get_route as one of its last_hops.
+	pub fn route_hints(&self) -> &[Vec<RouteHint>] {
+		&self.route_hints
+	}
+}
+
+/// Builds a new invoice, which is signed by the payee once complete. Optional fields are left
+/// out of the invoice unless set.
+pub struct InvoiceBuilder {
+	currency: Currency,
+	amount_msat: Option<u64>,
+	timestamp: u64,
+	payment_hash: PaymentHash,
+	description: String,
+	expiry_time: Option<u64>,
+	min_final_cltv_expiry: Option<u64>,
+	route_hints: Vec<Vec<RouteHint>>,
+}
+
+impl InvoiceBuilder {
+	/// Starts building an invoice for payment_hash, created at timestamp (in seconds since the
+	/// UNIX epoch).
+	pub fn new(currency: Currency, payment_hash: PaymentHash, timestamp: u64, description: String) -> InvoiceBuilder {
+		InvoiceBuilder {
+			currency,
+			amount_msat: None,
+			timestamp,
+			payment_hash,
+			description,
+			expiry_time: None,
+			min_final_cltv_expiry: None,
+			route_hints: Vec::new(),
+		}
+	}
+
+	/// Sets the amount requested. If not set, the payer picks the amount.
+	pub fn amount_msat(mut self, amount_msat: u64) -> Self {
+		self.amount_msat = Some(amount_msat);
+		self
+	}
+
+	/// Sets the number of seconds after the timestamp at which the invoice expires. If not set,
+	/// payers assume DEFAULT_EXPIRY_TIME.
+	pub fn expiry_time(mut self, expiry_secs: u64) -> Self {
+		self.expiry_time = Some(expiry_secs);
+		self
+	}
+
+	/// Sets the CLTV expiry delta the payee requires of the final hop. If not set, payers assume
+	/// DEFAULT_MIN_FINAL_CLTV_EXPIRY.
+	pub fn min_final_cltv_expiry(mut self, min_final_cltv_expiry: u64) -> Self {
+		self.min_final_cltv_expiry = Some(min_final_cltv_expiry);
+		self
+	}
+
+	/// Adds a path of hops leading to the payee, for payees which can't be reached through the
+	/// public network alone.
+	pub fn route_hint(mut self, hops: Vec<RouteHint>) -> Self {
+		self.route_hints.push(hops);
+		self
+	}
+
+	/// Signs the invoice with payee_secret, the secret key of the node being paid. The payee's
+	/// public key is left to be recovered from the signature rather than given in an n field.
+	pub fn build_signed(self, payee_secret: &SecretKey) -> Result<Invoice, HandleError> {
+		if self.timestamp >> (5 * TIMESTAMP_LEN) != 0 {
+			invalid_invoice!("Invoice timestamp was too large");
+		}
+		let hrp = match self.amount_msat {
+			Some(amount_msat) => format!("ln{}{}", self.currency.prefix(), encode_amount_msat(amount_msat)),
+			None => format!("ln{}", self.currency.prefix()),
+		};
+
+		let mut data = write_be_u5s(self.timestamp, Some(TIMESTAMP_LEN));
+		push_field(&mut data, TAG_PAYMENT_HASH, &bech32::bytes_to_u5s(&self.payment_hash.0))?;
+		push_field(&mut data, TAG_DESCRIPTION, &bech32::bytes_to_u5s(self.description.as_bytes()))?;
+		if let Some(expiry_time) = self.expiry_time {
+			push_field(&mut data, TAG_EXPIRY_TIME, &write_be_u5s(expiry_time, None))?;
+		}
+		if let Some(min_final_cltv_expiry) = self.min_final_cltv_expiry {
+			push_field(&mut data, TAG_MIN_FINAL_CLTV_EXPIRY, &write_be_u5s(min_final_cltv_expiry, None))?;
+		}
+		for hops in self.route_hints.iter() {
+			push_field(&mut data, TAG_ROUTE, &write_route(hops))?;
+		}
+
+		let msg_hash = signature_hash(&hrp, &data);
+		let (recovery_id, sig) = Secp256k1::signing_only().sign_recoverable(&msg_hash, payee_secret).serialize_compact();
+		let mut sig_bytes = sig.to_vec();
+		sig_bytes.push(recovery_id.to_i32() as u8);
+		data.extend_from_slice(&bech32::bytes_to_u5s(&sig_bytes));
+
+		Invoice::parse(&bech32::encode(&hrp, &data))
+	}
+}
+
+impl fmt::Display for Invoice {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.encoded)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use util::bech32::{bytes_to_u5s, encode};
+
+	use secp256k1::key::SecretKey;
+
+	use hex;
+
+	// Builds and signs an invoice with the given human-readable part, timestamp and tagged fields
+	fn build_invoice(hrp: &str, timestamp: u64, fields: &[(u8, Vec<u5>)], key: &SecretKey) -> String {
+		let mut data: Vec<u5> = (0..TIMESTAMP_LEN).rev().map(|i| u5::try_from_u8(((timestamp >> (5 * i)) & 0x1f) as u8).unwrap()).collect();
+		for &(tag, ref value) in fields.iter() {
+			data.push(u5::try_from_u8(tag).unwrap());
+			data.push(u5::try_from_u8((value.len() >> 5) as u8).unwrap());
+			data.push(u5::try_from_u8((value.len() & 0x1f) as u8).unwrap());
+			data.extend_from_slice(&value[..]);
+		}
+		let msg_hash = signature_hash(&hrp, &data);
+		let (recovery_id, sig) = Secp256k1::signing_only().sign_recoverable(&msg_hash, key).serialize_compact();
+		let mut sig_bytes = sig.to_vec();
+		sig_bytes.push(recovery_id.to_i32() as u8);
+		data.extend_from_slice(&bytes_to_u5s(&sig_bytes));
+		encode(hrp, &data)
+	}
+
+	fn test_fields(expiry: Option<u64>) -> Vec<(u8, Vec<u5>)> {
+		let mut fields = vec![
+			(TAG_PAYMENT_HASH, bytes_to_u5s(&[1; 32])),
+			(TAG_DESCRIPTION, bytes_to_u5s(b"coffee")),
+		];
+		if let Some(expiry) = expiry {
+			fields.push((TAG_EXPIRY_TIME, int_to_u5s(expiry)));
+		}
+		fields
+	}
+
+	fn int_to_u5s(mut v: u64) -> Vec<u5> {
+		let mut res = Vec::new();
+		while v != 0 {
+			res.insert(0, u5::try_from_u8((v & 0x1f) as u8).unwrap());
+			v >>= 5;
+		}
+		res
+	}
+
+	// "Please make a donation of any amount using payment_hash 0001020304050607080900010203040506070809000102030405060708090102 to me @03e7156ae33b0a208d0744199163177e909e80176e55d97a2f221ede0f934dd9ad"
+	const BOLT11_DONATION: &str = "lnbc1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpl2pkx2ctnv5sxxmmwwd5kgetjypeh2ursdae8g6twvus8g6rfwvs8qun0dfjkxaq8rkx3yf5tcsyz3d73gafnh3cax9rn449d9p5uxz9ezhhypd0elx87sjle52x86fux2ypatgddc6k63n7erqz25le42c4u4ecky03ylcqca784w";
+
+	#[test]
+	fn bolt11_donation_vector() {
+		let invoice = Invoice::parse(BOLT11_DONATION).unwrap();
+		assert_eq!(invoice.currency(), Currency::Bitcoin);
+		assert_eq!(invoice.amount_msat(), None);
+		assert_eq!(invoice.timestamp(), 1496314658);
+		assert_eq!(invoice.payment_hash().0[..], hex::decode("0001020304050607080900010203040506070809000102030405060708090102").unwrap()[..]);
+		assert_eq!(invoice.description(), Some("Please consider supporting this project"));
+		assert_eq!(invoice.description_hash(), None);
+		assert_eq!(invoice.payee_pub_key().serialize()[..], hex::decode("03e7156ae33b0a208d0744199163177e909e80176e55d97a2f221ede0f934dd9ad").unwrap()[..]);
+		assert_eq!(invoice.min_final_cltv_expiry(), None);
+
+		// Upper-case invoices are fine too
+		let upper = BOLT11_DONATION.to_uppercase();
+		assert_eq!(Invoice::parse(&upper).unwrap(), invoice);
+	}
+
+	#[test]
+	fn bolt11_coffee_vector() {
+		// "Please send $3 for a cup of coffee to the same peer, within one minute"
+		// The data isn't a whole number of bytes long, so this checks that it's zero-padded before
+		// it's hashed for the signature.
+		let invoice = Invoice::parse("lnbc2500u1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpuaztrnwngzn3kdzw5hydlzf03qdgm2hdq27cqv3agm2awhz5se903vruatfhq77w3ls4evs3ch9zw97j25emudupq63nyw24cg27h2rspfj9srp").unwrap();
+		assert_eq!(invoice.currency(), Currency::Bitcoin);
+		assert_eq!(invoice.amount_msat(), Some(250_000_000));
+		assert_eq!(invoice.timestamp(), 1496314658);
+		assert_eq!(invoice.payment_hash().0[..], hex::decode("0001020304050607080900010203040506070809000102030405060708090102").unwrap()[..]);
+		assert_eq!(invoice.description(), Some("1 cup coffee"));
+		assert_eq!(invoice.expiry_time(), Duration::from_secs(60));
+		assert_eq!(invoice.payee_pub_key().serialize()[..], hex::decode("03e7156ae33b0a208d0744199163177e909e80176e55d97a2f221ede0f934dd9ad").unwrap()[..]);
+	}
+
+	#[test]
+	fn bolt11_hashed_description_vector() {
+		// "Now send $24 for an entire list of things (hashed)"
+		let invoice = Invoice::parse("lnbc20m1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqscc6gd6ql3jrc5yzme8v4ntcewwz5cnw92tz0pc8qcuufvq7khhr8wpald05e92xw006sq94mg8v2ndf4sefvf9sygkshp5zfem29trqq2yxxz7").unwrap();
+		assert_eq!(invoice.currency(), Currency::Bitcoin);
+		assert_eq!(invoice.amount_msat(), Some(2_000_000_000));
+		assert_eq!(invoice.timestamp(), 1496314658);
+		assert_eq!(invoice.description(), None);
+		let description = "One piece of chocolate cake, one icecream cone, one pickle, one slice of swiss cheese, one slice of salami, one lollypop, one piece of cherry pie, one sausage, one cupcake, and one slice of watermelon";
+		assert_eq!(invoice.description_hash(), Some(&Sha256::hash(description.as_bytes()).into_inner()));
+		assert_eq!(invoice.expiry_time(), Duration::from_secs(DEFAULT_EXPIRY_TIME));
+		assert_eq!(invoice.payee_pub_key().serialize()[..], hex::decode("03e7156ae33b0a208d0744199163177e909e80176e55d97a2f221ede0f934dd9ad").unwrap()[..]);
+	}
+
+	#[test]
+	fn explicit_expiry() {
+		let key = SecretKey::from_slice(&[0x42; 32]).unwrap();
+		let invoice = Invoice::parse(&build_invoice("lntb25m", 1500000000, &test_fields(Some(60)), &key)).unwrap();
+		assert_eq!(invoice.currency(), Currency::BitcoinTestnet);
+		assert_eq!(invoice.amount_msat(), Some(2_500_000_000));
+		assert_eq!(invoice.timestamp(), 1500000000);
+		assert_eq!(invoice.payee_pub_key(), &PublicKey::from_secret_key(&Secp256k1::new(), &key));
+		assert_eq!(invoice.expiry_time(), Duration::from_secs(60));
+		assert!(!invoice.is_expired(1500000000));
+		assert!(!invoice.is_expired(1500000059));
+		assert!(invoice.is_expired(1500000060));
+	}
+
+	#[test]
+	fn default_expiry() {
+		let key = SecretKey::from_slice(&[0x42; 32]).unwrap();
+		let invoice = Invoice::parse(&build_invoice("lnbcrt1u", 1500000000, &test_fields(None), &key)).unwrap();
+		assert_eq!(invoice.currency(), Currency::Regtest);
+		assert_eq!(invoice.amount_msat(), Some(100_000));
+		assert_eq!(invoice.expiry_time(), Duration::from_secs(DEFAULT_EXPIRY_TIME));
+		assert!(!invoice.is_expired(1500000000 + DEFAULT_EXPIRY_TIME - 1));
+		assert!(invoice.is_expired(1500000000 + DEFAULT_EXPIRY_TIME));
+
+		// The largest expiry field we accept is 12 characters (60 bits) long
+		let mut fields = test_fields(None);
+		fields.push((TAG_EXPIRY_TIME, int_to_u5s((1 << 60) - 1)));
+		let invoice = Invoice::parse(&build_invoice("lnbc", (1 << 35) - 1, &fields, &key)).unwrap();
+		assert_eq!(invoice.expiry_time(), Duration::from_secs((1 << 60) - 1));
+		assert!(!invoice.is_expired(1 << 60));
+		fields.pop();
+		fields.push((TAG_EXPIRY_TIME, int_to_u5s(1 << 60)));
+		assert_eq!(Invoice::parse(&build_invoice("lnbc", 1, &fields, &key)).unwrap_err().err, "Invoice integer field was too long");
+	}
+
+	fn fallback_field(version: u8, data: &[u8]) -> (u8, Vec<u5>) {
+		let mut value = vec![u5::try_from_u8(version).unwrap()];
+		value.extend_from_slice(&bytes_to_u5s(data));
+		(TAG_FALLBACK, value)
+	}
+
+	#[test]
+	fn fallback_addresses() {
+		let key = SecretKey::from_slice(&[0x42; 32]).unwrap();
+		let invoice = Invoice::parse(&build_invoice("lnbc", 1, &test_fields(None), &key)).unwrap();
+		assert_eq!(invoice.fallback(), None);
+
+		// P2WPKH
+		let program = hex::decode("751e76e8199196d454941c45d1b3a323f1433bd6").unwrap();
+		let mut fields = test_fields(None);
+		fields.push(fallback_field(0, &program));
+		let invoice = Invoice::parse(&build_invoice("lnbc", 1, &fields, &key)).unwrap();
+		assert_eq!(invoice.fallback(), Some(&Fallback::SegWitProgram { version: 0, program: program.clone() }));
+
+		// P2PKH, followed by a P2SH and an unknown version which are kept in order
+		let mut hash = [0; 20];
+		hash.copy_from_slice(&hex::decode("3172b5654f6683c8fb146959d347ce303cae4ca7").unwrap());
+		let mut fields = test_fields(None);
+		fields.push(fallback_field(17, &hash));
+		fields.push(fallback_field(18, &[3; 20]));
+		fields.push(fallback_field(19, &[1, 2, 3]));
+		let invoice = Invoice::parse(&build_invoice("lnbc", 1, &fields, &key)).unwrap();
+		assert_eq!(invoice.fallback(), Some(&Fallback::PubKeyHash(hash)));
+		assert_eq!(invoice.fallbacks(), &[
+			Fallback::PubKeyHash(hash),
+			Fallback::ScriptHash([3; 20]),
+			Fallback::Unknown { version: 19, data: vec![1, 2, 3] },
+		][..]);
+
+		// Wrong lengths
+		for &(version, len) in [(17, 19), (18, 21), (0, 21), (1, 1), (16, 41)].iter() {
+			let mut fields = test_fields(None);
+			fields.push(fallback_field(version, &vec![1; len]));
+			assert!(Invoice::parse(&build_invoice("lnbc", 1, &fields, &key)).is_err());
+		}
+	}
+
+	#[test]
+	fn invalid_invoices() {
+		let key = SecretKey::from_slice(&[0x42; 32]).unwrap();
+		let other_key = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[0x43; 32]).unwrap());
+
+		// A payee key which didn't sign the invoice
+		let mut fields = test_fields(None);
+		fields.push((TAG_PAYEE_PUB_KEY, bytes_to_u5s(&other_key.serialize())));
+		assert_eq!(Invoice::parse(&build_invoice("lnbc", 1, &fields, &key)).unwrap_err().err, "Invoice signature did not match its payee public key");
+
+		// Missing payment hash
+		let fields = vec![(TAG_DESCRIPTION, bytes_to_u5s(b"coffee"))];
+		assert_eq!(Invoice::parse(&build_invoice("lnbc", 1, &fields, &key)).unwrap_err().err, "Invoice had no payment hash");
+
+		// A payment hash of the wrong length is skipped
+		let fields = vec![(TAG_PAYMENT_HASH, bytes_to_u5s(&[1; 31])), (TAG_DESCRIPTION, bytes_to_u5s(b"coffee"))];
+		assert_eq!(Invoice::parse(&build_invoice("lnbc", 1, &fields, &key)).unwrap_err().err, "Invoice had no payment hash");
+
+		// Missing description
+		let fields = vec![(TAG_PAYMENT_HASH, bytes_to_u5s(&[1; 32]))];
+		assert_eq!(Invoice::parse(&build_invoice("lnbc", 1, &fields, &key)).unwrap_err().err, "Invoice had neither a description nor a description hash");
+
+		assert_eq!(Invoice::parse(&build_invoice("lnxx", 1, &test_fields(None), &key)).unwrap_err().err, "Unknown invoice currency prefix");
+		assert_eq!(Invoice::parse(&build_invoice("bc", 1, &test_fields(None), &key)).unwrap_err().err, "Invoice human-readable part did not start with ln");
+		assert!(Invoice::parse(&build_invoice("lnbc1p", 1, &test_fields(None), &key)).is_err());
+	}
+
+	#[test]
+	fn create_round_trip() {
+		let secp_ctx = Secp256k1::new();
+		let key = SecretKey::from_slice(&[0x42; 32]).unwrap();
+		let hops = vec![RouteHint {
+			src_node_id: PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[0x43; 32]).unwrap()),
+			short_channel_id: 0x0102030405060708,
+			fee_base_msat: 1000,
+			fee_proportional_millionths: 20,
+			cltv_expiry_delta: 144,
+			htlc_minimum_msat: 0,
+		}, RouteHint {
+			src_node_id: PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[0x44; 32]).unwrap()),
+			short_channel_id: 42,
+			fee_base_msat: 0,
+			fee_proportional_millionths: 1,
+			cltv_expiry_delta: 6,
+			htlc_minimum_msat: 0,
+		}];
+		let invoice = InvoiceBuilder::new(Currency::Regtest, PaymentHash([1; 32]), 1500000000, "coffee".to_owned())
+			.amount_msat(250_000_000).expiry_time(60).min_final_cltv_expiry(18).route_hint(hops.clone())
+			.build_signed(&key).unwrap();
+		assert!(invoice.to_string().starts_with("lnbcrt2500u1"));
+		assert_eq!(Invoice::parse(&invoice.to_string()).unwrap(), invoice);
+		assert_eq!(invoice.currency(), Currency::Regtest);
+		assert_eq!(invoice.amount_msat(), Some(250_000_000));
+		assert_eq!(invoice.timestamp(), 1500000000);
+		assert_eq!(invoice.payment_hash(), &PaymentHash([1; 32]));
+		assert_eq!(invoice.description(), Some("coffee"));
+		assert_eq!(invoice.payee_pub_key(), &PublicKey::from_secret_key(&secp_ctx, &key));
+		assert_eq!(invoice.expiry_time(), Duration::from_secs(60));
+		assert_eq!(invoice.min_final_cltv_expiry(), Some(18));
+		assert_eq!(invoice.route_hints(), &[hops][..]);
+
+		let invoice = InvoiceBuilder::new(Currency::Bitcoin, PaymentHash([2; 32]), 0, String::new()).build_signed(&key).unwrap();
+		assert!(invoice.to_string().starts_with("lnbc1"));
+		assert_eq!(invoice.amount_msat(), None);
+		assert_eq!(invoice.timestamp(), 0);
+		assert_eq!(invoice.description(), Some(""));
+		assert_eq!(invoice.expiry_time(), Duration::from_secs(DEFAULT_EXPIRY_TIME));
+		assert!(invoice.route_hints().is_empty());
+
+		assert_eq!(InvoiceBuilder::new(Currency::Bitcoin, PaymentHash([2; 32]), 1 << 35, String::new()).build_signed(&key).unwrap_err().err, "Invoice timestamp was too large");
+		let long_description: String = ::std::iter::repeat('a').take(640).collect();
+		assert_eq!(InvoiceBuilder::new(Currency::Bitcoin, PaymentHash([2; 32]), 0, long_description).build_signed(&key).unwrap_err().err, "Invoice tagged field was too long");
+	}
+
+	#[test]
+	fn amountless_invoices() {
+		let key = SecretKey::from_slice(&[0x42; 32]).unwrap();
+		let encoded = build_invoice("lntb", 1500000000, &test_fields(None), &key);
+		assert!(encoded.starts_with("lntb1"));
+		let invoice = Invoice::parse(&encoded).unwrap();
+		assert_eq!(invoice.currency(), Currency::BitcoinTestnet);
+		assert_eq!(invoice.amount_msat(), None);
+
+		let created = InvoiceBuilder::new(Currency::BitcoinTestnet, PaymentHash([1; 32]), 1500000000, "coffee".to_owned()).build_signed(&key).unwrap();
+		assert_eq!(created.to_string(), encoded);
+		assert_eq!(created, invoice);
+
+		// The payer has to pick an amount
+		assert_eq!(invoice.amount_to_pay_msat(None).unwrap_err().err, "Invoice has no amount, so an amount to pay must be specified");
+		assert_eq!(invoice.amount_to_pay_msat(Some(0)).unwrap_err().err, "Cannot pay an invoice with a zero amount");
+		assert_eq!(invoice.amount_to_pay_msat(Some(1234)).unwrap(), 1234);
+
+		// Including for the spec's amountless invoice, which we didn't sign ourselves
+		let donation = Invoice::parse(BOLT11_DONATION).unwrap();
+		assert_eq!(donation.amount_msat(), None);
+		assert_eq!(donation.payee_pub_key().serialize()[..], hex::decode("03e7156ae33b0a208d0744199163177e909e80176e55d97a2f221ede0f934dd9ad").unwrap()[..]);
+		assert_eq!(donation.amount_to_pay_msat(None).unwrap_err().err, "Invoice has no amount, so an amount to pay must be specified");
+		assert_eq!(donation.amount_to_pay_msat(Some(1234)).unwrap(), 1234);
+
+		// While an invoice with an amount can be overpaid, but not underpaid
+		let invoice = InvoiceBuilder::new(Currency::BitcoinTestnet, PaymentHash([1; 32]), 1500000000, "coffee".to_owned()).amount_msat(1000).build_signed(&key).unwrap();
+		assert_eq!(invoice.amount_to_pay_msat(None).unwrap(), 1000);
+		assert_eq!(invoice.amount_to_pay_msat(Some(1500)).unwrap(), 1500);
+		assert_eq!(invoice.amount_to_pay_msat(Some(999)).unwrap_err().err, "Amount to pay was less than the invoice amount");
+	}
+
+	#[test]
+	fn invalid_route_hints() {
+		let key = SecretKey::from_slice(&[0x42; 32]).unwrap();
+		let mut fields = test_fields(None);
+		fields.push((TAG_ROUTE, bytes_to_u5s(&[2; ROUTE_HOP_LEN - 1])));
+		assert_eq!(Invoice::parse(&build_invoice("lnbc", 1, &fields, &key)).unwrap_err().err, "Invoice route hint had an invalid length");
+		fields.pop();
+		fields.push((TAG_ROUTE, bytes_to_u5s(&[0; ROUTE_HOP_LEN])));
+		assert_eq!(Invoice::parse(&build_invoice("lnbc", 1, &fields, &key)).unwrap_err().err, "Invoice route hint public key was invalid");
+	}
+
+	#[test]
+	fn encode_amounts() {
+		for &(msat, encoded) in [(100_000_000_000, "1"), (2_500_000_000, "25m"), (250_000_000, "2500u"), (2_000, "20n"), (1, "10p"), (967_878_534, "9678785340p"), (::std::u64::MAX, "184467440737095516150p")].iter() {
+			assert_eq!(encode_amount_msat(msat), encoded);
+			assert_eq!(parse_amount_msat(encoded).unwrap(), msat);
+		}
+	}
+
+	#[test]
+	fn parse_amounts() {
+		assert_eq!(parse_amount_msat("1").unwrap(), 100_000_000_000);
+		assert_eq!(parse_amount_msat("25m").unwrap(), 2_500_000_000);
+		assert_eq!(parse_amount_msat("2500u").unwrap(), 250_000_000);
+		assert_eq!(parse_amount_msat("20n").unwrap(), 2_000);
+		assert_eq!(parse_amount_msat("10p").unwrap(), 1);
+		assert_eq!(parse_amount_msat("9678785340p").unwrap(), 967_878_534);
+	}
+
+	#[test]
+	fn largest_amounts() {
+		assert_eq!(parse_amount_msat("184467440737095516150p").unwrap(), ::std::u64::MAX);
+		assert_eq!(parse_amount_msat("184467440").unwrap(), 18_446_744_000_000_000_000);
+		assert_eq!(parse_amount_msat("184467440737m").unwrap(), 18_446_744_073_700_000_000);
+	}
+
+	#[test]
+	fn overflowing_amounts() {
+		assert!(parse_amount_msat("184467441").is_err());
+		assert!(parse_amount_msat("184467440738m").is_err());
+		assert!(parse_amount_msat("184467440737095516160p").is_err());
+		assert!(parse_amount_msat("99999999999999999999999").is_err());
+	}
+
+	#[test]
+	fn invalid_amounts() {
+		// Not a whole number of msat
+		assert!(parse_amount_msat("1p").is_err());
+		assert!(parse_amount_msat("15p").is_err());
+		assert!(parse_amount_msat("0p").is_err());
+		assert!(parse_amount_msat("").is_err());
+		assert!(parse_amount_msat("m").is_err());
+		assert!(parse_amount_msat("01m").is_err());
+		assert!(parse_amount_msat("1.5m").is_err());
+		assert!(parse_amount_msat("1x").is_err());
+	}
+}
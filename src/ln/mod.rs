@@ -12,6 +12,9 @@
 pub mod channelmanager;
 pub mod channelmonitor;
 pub mod msgs;
+pub mod features;
+pub mod invoice;
+pub mod onion;
 pub mod router;
 pub mod peer_handler;
 
@@ -21,7 +24,7 @@ pub mod peer_channel_encryptor;
 pub(crate) mod peer_channel_encryptor;
 
 mod channel;
-mod chan_utils;
+pub(crate) mod chan_utils;
 mod onion_utils;
 
 #[cfg(test)]
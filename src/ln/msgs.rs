@@ -16,9 +16,10 @@
 //! track the network on the less-secure system.
 
 use secp256k1::key::PublicKey;
-use secp256k1::Signature;
+use secp256k1::{Secp256k1, Signature, Verification};
 use secp256k1;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
 use bitcoin::blockdata::script::Script;
 
 use std::error::Error;
@@ -26,10 +27,13 @@ use std::{cmp, fmt};
 use std::io::Read;
 use std::result::Result;
 
+use util::amount::{MilliSatoshi, Satoshi};
+use util::config::ChannelHandshakeLimits;
 use util::events;
 use util::ser::{Readable, Writeable, Writer};
 
-use ln::channelmanager::{PaymentPreimage, PaymentHash};
+use ln::channel::MAX_FUNDING_SATOSHIS;
+use ln::channelmanager::{PaymentPreimage, PaymentHash, MAX_LOCAL_BREAKDOWN_TIMEOUT};
 
 /// An error in decoding a message or struct.
 #[derive(Debug)]
@@ -48,6 +52,8 @@ pub enum DecodeError {
 	ExtraAddressesPerType,
 	/// A length descriptor in the packet didn't describe the later data correctly
 	BadLengthDescriptor,
+	/// An encoded set of values was zlib-compressed, which we don't support
+	UnsupportedCompression,
 	/// Error from std::io
 	Io(::std::io::Error),
 }
@@ -59,14 +65,8 @@ pub struct LocalFeatures {
 }
 
 impl LocalFeatures {
-	/// Create a blank LocalFeatures flags (visibility extended for fuzz tests)
-	#[cfg(not(feature = "fuzztarget"))]
-	pub(crate) fn new() -> LocalFeatures {
-		LocalFeatures {
-			flags: vec![2 | 1 << 5],
-		}
-	}
-	#[cfg(feature = "fuzztarget")]
+	/// Create a LocalFeatures flags with the features we support set (visibility extended for
+	/// fuzz tests and for driving a ChannelMessageHandler from outside the crate)
 	pub fn new() -> LocalFeatures {
 		LocalFeatures {
 			flags: vec![2 | 1 << 5],
@@ -95,6 +95,14 @@ impl LocalFeatures {
 		self.flags[0] ^= 1 << 5;
 	}
 
+	pub(crate) fn supports_gossip_queries(&self) -> bool {
+		self.flags.len() > 0 && (self.flags[0] & (3 << 6)) != 0
+	}
+	#[cfg(test)]
+	pub(crate) fn set_gossip_queries(&mut self) {
+		self.flags[0] |= 1 << 7;
+	}
+
 	pub(crate) fn requires_unknown_bits(&self) -> bool {
 		self.flags.iter().enumerate().any(|(idx, &byte)| {
 			( idx != 0 && (byte & 0x55) != 0 ) || ( idx == 0 && (byte & 0x14) != 0 )
@@ -145,40 +153,43 @@ impl GlobalFeatures {
 }
 
 /// An init message to be sent or received from a peer
+#[derive(Clone, PartialEq)]
 pub struct Init {
 	pub(crate) global_features: GlobalFeatures,
 	pub(crate) local_features: LocalFeatures,
 }
 
 /// An error message to be sent or received from a peer
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct ErrorMessage {
 	pub(crate) channel_id: [u8; 32],
 	pub(crate) data: String,
 }
 
 /// A ping message to be sent or received from a peer
+#[derive(Clone, PartialEq)]
 pub struct Ping {
 	pub(crate) ponglen: u16,
 	pub(crate) byteslen: u16,
 }
 
 /// A pong message to be sent or received from a peer
+#[derive(Clone, PartialEq)]
 pub struct Pong {
 	pub(crate) byteslen: u16,
 }
 
 /// An open_channel message to be sent or received from a peer
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct OpenChannel {
 	pub(crate) chain_hash: Sha256dHash,
 	pub(crate) temporary_channel_id: [u8; 32],
-	pub(crate) funding_satoshis: u64,
-	pub(crate) push_msat: u64,
-	pub(crate) dust_limit_satoshis: u64,
-	pub(crate) max_htlc_value_in_flight_msat: u64,
-	pub(crate) channel_reserve_satoshis: u64,
-	pub(crate) htlc_minimum_msat: u64,
+	pub(crate) funding_satoshis: Satoshi,
+	pub(crate) push_msat: MilliSatoshi,
+	pub(crate) dust_limit_satoshis: Satoshi,
+	pub(crate) max_htlc_value_in_flight_msat: MilliSatoshi,
+	pub(crate) channel_reserve_satoshis: Satoshi,
+	pub(crate) htlc_minimum_msat: MilliSatoshi,
 	pub(crate) feerate_per_kw: u32,
 	pub(crate) to_self_delay: u16,
 	pub(crate) max_accepted_htlcs: u16,
@@ -192,14 +203,92 @@ pub struct OpenChannel {
 	pub(crate) shutdown_scriptpubkey: OptionalField<Script>,
 }
 
+impl OpenChannel {
+	/// The value of the channel our peer proposes to open, eg for an InboundChannelAcceptor to
+	/// decide whether the channel is worth accepting.
+	pub fn funding_satoshis(&self) -> Satoshi {
+		self.funding_satoshis
+	}
+
+	/// The amount our peer proposes to give us upfront, out of funding_satoshis.
+	pub fn push_msat(&self) -> MilliSatoshi {
+		self.push_msat
+	}
+
+	/// Checks the channel parameters our peer proposed against the BOLT #2 "MUST fail the channel"
+	/// rules which depend only on the message itself, and against the user's configured limits.
+	/// Note that this does not check the feerate, which depends on current fee estimates.
+	pub fn check_remote_sanity(&self, limits: &ChannelHandshakeLimits) -> Result<(), HandleError> {
+		macro_rules! reject {
+			($err: expr) => {
+				return Err(HandleError{err: $err, action: None})
+			}
+		}
+
+		if self.funding_satoshis >= Satoshi(MAX_FUNDING_SATOSHIS) {
+			reject!("funding value > 2^24");
+		}
+		if self.channel_reserve_satoshis > self.funding_satoshis {
+			reject!("Bogus channel_reserve_satoshis");
+		}
+		// Can't overflow as funding_satoshis is below MAX_FUNDING_SATOSHIS
+		let spendable_msat = (self.funding_satoshis - self.channel_reserve_satoshis).to_msat().unwrap();
+		if self.push_msat > spendable_msat {
+			reject!("push_msat larger than funding value");
+		}
+		if self.dust_limit_satoshis > self.funding_satoshis {
+			reject!("Peer never wants payout outputs?");
+		}
+		if self.dust_limit_satoshis > self.channel_reserve_satoshis {
+			reject!("Bogus; channel reserve is less than dust limit");
+		}
+		if self.htlc_minimum_msat >= spendable_msat {
+			reject!("Minimum htlc value is full channel value");
+		}
+		if self.to_self_delay > limits.their_to_self_delay || self.to_self_delay > MAX_LOCAL_BREAKDOWN_TIMEOUT {
+			reject!("They wanted our payments to be delayed by a needlessly long period");
+		}
+		if self.max_accepted_htlcs < 1 {
+			reject!("0 max_accpted_htlcs makes for a useless channel");
+		}
+		if self.max_accepted_htlcs > 483 {
+			reject!("max_accpted_htlcs > 483");
+		}
+
+		// Now check against optional parameters as set by config...
+		if self.funding_satoshis < Satoshi(limits.min_funding_satoshis) {
+			reject!("funding satoshis is less than the user specified limit");
+		}
+		if self.htlc_minimum_msat > MilliSatoshi(limits.max_htlc_minimum_msat) {
+			reject!("htlc minimum msat is higher than the user specified limit");
+		}
+		if self.max_htlc_value_in_flight_msat < MilliSatoshi(limits.min_max_htlc_value_in_flight_msat) {
+			reject!("max htlc value in flight msat is less than the user specified limit");
+		}
+		if self.channel_reserve_satoshis > Satoshi(limits.max_channel_reserve_satoshis) {
+			reject!("channel reserve satoshis is higher than the user specified limit");
+		}
+		if self.max_accepted_htlcs < limits.min_max_accepted_htlcs {
+			reject!("max accepted htlcs is less than the user specified limit");
+		}
+		if self.dust_limit_satoshis < Satoshi(limits.min_dust_limit_satoshis) {
+			reject!("dust limit satoshis is less than the user specified limit");
+		}
+		if self.dust_limit_satoshis > Satoshi(limits.max_dust_limit_satoshis) {
+			reject!("dust limit satoshis is greater than the user specified limit");
+		}
+		Ok(())
+	}
+}
+
 /// An accept_channel message to be sent or received from a peer
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct AcceptChannel {
 	pub(crate) temporary_channel_id: [u8; 32],
-	pub(crate) dust_limit_satoshis: u64,
-	pub(crate) max_htlc_value_in_flight_msat: u64,
-	pub(crate) channel_reserve_satoshis: u64,
-	pub(crate) htlc_minimum_msat: u64,
+	pub(crate) dust_limit_satoshis: Satoshi,
+	pub(crate) max_htlc_value_in_flight_msat: MilliSatoshi,
+	pub(crate) channel_reserve_satoshis: Satoshi,
+	pub(crate) htlc_minimum_msat: MilliSatoshi,
 	pub(crate) minimum_depth: u32,
 	pub(crate) to_self_delay: u16,
 	pub(crate) max_accepted_htlcs: u16,
@@ -213,7 +302,7 @@ pub struct AcceptChannel {
 }
 
 /// A funding_created message to be sent or received from a peer
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct FundingCreated {
 	pub(crate) temporary_channel_id: [u8; 32],
 	pub(crate) funding_txid: Sha256dHash,
@@ -222,7 +311,7 @@ pub struct FundingCreated {
 }
 
 /// A funding_signed message to be sent or received from a peer
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct FundingSigned {
 	pub(crate) channel_id: [u8; 32],
 	pub(crate) signature: Signature,
@@ -390,6 +479,45 @@ impl NetAddress {
 			&NetAddress::OnionV3 { .. } => { 37 },
 		}
 	}
+
+	/// Gets the human-readable ".onion" hostname (without the port) for Tor onion addresses, or
+	/// None for IP addresses.
+	pub fn onion_hostname(&self) -> Option<String> {
+		let mut onion = match self {
+			&NetAddress::IPv4 { .. } | &NetAddress::IPv6 { .. } => return None,
+			&NetAddress::OnionV2 { ref addr, .. } => base32_encode(&addr[..]),
+			&NetAddress::OnionV3 { ref ed25519_pubkey, ref checksum, ref version, .. } => {
+				let mut addr = [0; 35];
+				addr[..32].copy_from_slice(&ed25519_pubkey[..]);
+				addr[32] = (checksum >> 8) as u8;
+				addr[33] = *checksum as u8;
+				addr[34] = *version;
+				base32_encode(&addr[..])
+			},
+		};
+		onion.push_str(".onion");
+		Some(onion)
+	}
+}
+
+/// Lower-case RFC 4648 base32 without padding, as used in Tor onion hostnames.
+fn base32_encode(data: &[u8]) -> String {
+	const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+	let mut res = String::with_capacity((data.len() * 8 + 4) / 5);
+	let mut buffer: u16 = 0;
+	let mut bits = 0;
+	for byte in data {
+		buffer = (buffer << 8) | *byte as u16;
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			res.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+		}
+	}
+	if bits > 0 {
+		res.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+	}
+	res
 }
 
 impl Writeable for NetAddress {
@@ -481,6 +609,17 @@ pub struct NodeAnnouncement {
 	pub(crate) contents: UnsignedNodeAnnouncement,
 }
 
+impl NodeAnnouncement {
+	/// Checks the signature against the node_id given in the announcement contents.
+	pub fn verify<C: Verification>(&self, secp_ctx: &Secp256k1<C>) -> Result<(), HandleError> {
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&self.contents.encode()[..])[..]);
+		match secp_ctx.verify(&msg_hash, &self.signature, &self.contents.node_id) {
+			Ok(_) => Ok(()),
+			Err(_) => Err(HandleError{err: "Invalid signature from remote node", action: None}),
+		}
+	}
+}
+
 // Only exposed as broadcast of channel_announcement should be filtered by node_id
 /// The unsigned part of a channel_announcement
 #[derive(PartialEq, Clone, Debug)]
@@ -506,6 +645,23 @@ pub struct ChannelAnnouncement {
 	pub(crate) contents: UnsignedChannelAnnouncement,
 }
 
+impl ChannelAnnouncement {
+	/// Checks the two node and two bitcoin signatures against the keys given in the announcement
+	/// contents. Note that this does not check that the funding output exists on chain.
+	pub fn verify<C: Verification>(&self, secp_ctx: &Secp256k1<C>) -> Result<(), HandleError> {
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&self.contents.encode()[..])[..]);
+		for &(sig, key) in [(&self.node_signature_1, &self.contents.node_id_1),
+		                    (&self.node_signature_2, &self.contents.node_id_2),
+		                    (&self.bitcoin_signature_1, &self.contents.bitcoin_key_1),
+		                    (&self.bitcoin_signature_2, &self.contents.bitcoin_key_2)].iter() {
+			if secp_ctx.verify(&msg_hash, sig, key).is_err() {
+				return Err(HandleError{err: "Invalid signature from remote node", action: None});
+			}
+		}
+		Ok(())
+	}
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub(crate) struct UnsignedChannelUpdate {
 	pub(crate) chain_hash: Sha256dHash,
@@ -525,6 +681,78 @@ pub struct ChannelUpdate {
 	pub(crate) contents: UnsignedChannelUpdate,
 }
 
+impl ChannelUpdate {
+	/// Checks the signature against the given node_id, which must be that of the node at the
+	/// origin of the direction this update applies to (ie node_id_2 from the channel_announcement
+	/// if the direction bit in flags is set, otherwise node_id_1).
+	pub fn verify<C: Verification>(&self, secp_ctx: &Secp256k1<C>, node_id: &PublicKey) -> Result<(), HandleError> {
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&self.contents.encode()[..])[..]);
+		match secp_ctx.verify(&msg_hash, &self.signature, node_id) {
+			Ok(_) => Ok(()),
+			Err(_) => Err(HandleError{err: "Invalid signature from remote node", action: None}),
+		}
+	}
+}
+
+/// The encoding type byte which prefixes an encoded set of short_channel_ids in gossip queries
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EncodingType {
+	/// The short_channel_ids follow as an array of big-endian u64s
+	Uncompressed = 0,
+	/// The short_channel_ids follow as a zlib-compressed array of big-endian u64s
+	ZlibCompressed = 1,
+}
+
+/// A query_channel_range message to be sent or received from a peer
+#[derive(PartialEq, Clone, Debug)]
+pub struct QueryChannelRange {
+	pub(crate) chain_hash: Sha256dHash,
+	pub(crate) first_blocknum: u32,
+	pub(crate) number_of_blocks: u32,
+}
+
+/// A reply_channel_range message to be sent or received from a peer
+#[derive(PartialEq, Clone, Debug)]
+pub struct ReplyChannelRange {
+	pub(crate) chain_hash: Sha256dHash,
+	pub(crate) first_blocknum: u32,
+	pub(crate) number_of_blocks: u32,
+	pub(crate) complete: bool,
+	pub(crate) short_channel_ids: Vec<u64>,
+}
+
+/// A query_short_channel_ids message to be sent or received from a peer
+#[derive(PartialEq, Clone, Debug)]
+pub struct QueryShortChannelIds {
+	pub(crate) chain_hash: Sha256dHash,
+	pub(crate) short_channel_ids: Vec<u64>,
+}
+
+/// A reply_short_channel_ids_end message to be sent or received from a peer
+#[derive(PartialEq, Clone, Debug)]
+pub struct ReplyShortChannelIdsEnd {
+	pub(crate) chain_hash: Sha256dHash,
+	pub(crate) full_information: bool,
+}
+
+/// A gossip_timestamp_filter message to be sent or received from a peer
+#[derive(PartialEq, Clone, Debug)]
+pub struct GossipTimestampFilter {
+	pub(crate) chain_hash: Sha256dHash,
+	pub(crate) first_timestamp: u32,
+	pub(crate) timestamp_range: u32,
+}
+
+impl GossipTimestampFilter {
+	/// Returns true if gossip with the given timestamp falls within
+	/// [first_timestamp, first_timestamp + timestamp_range). A range which runs past u32::MAX
+	/// matches every timestamp from first_timestamp onwards.
+	pub fn matches(&self, timestamp: u32) -> bool {
+		timestamp >= self.first_timestamp
+			&& (timestamp as u64) < self.first_timestamp as u64 + self.timestamp_range as u64
+	}
+}
+
 /// Used to put an error message in a HandleError
 #[derive(Clone)]
 pub enum ErrorAction {
@@ -609,6 +837,185 @@ pub enum OptionalField<T> {
 	Absent
 }
 
+/// A wire message of any of the types we understand, as it appears after its 2-byte type prefix
+/// has been read. Messages with a type we don't understand are held as Unknown.
+#[derive(Clone, PartialEq)]
+pub enum Message {
+	/// An init message (type 16)
+	Init(Init),
+	/// An error message (type 17)
+	Error(ErrorMessage),
+	/// A ping message (type 18)
+	Ping(Ping),
+	/// A pong message (type 19)
+	Pong(Pong),
+	/// An open_channel message (type 32)
+	OpenChannel(OpenChannel),
+	/// An accept_channel message (type 33)
+	AcceptChannel(AcceptChannel),
+	/// A funding_created message (type 34)
+	FundingCreated(FundingCreated),
+	/// A funding_signed message (type 35)
+	FundingSigned(FundingSigned),
+	/// A funding_locked message (type 36)
+	FundingLocked(FundingLocked),
+	/// A shutdown message (type 38)
+	Shutdown(Shutdown),
+	/// A closing_signed message (type 39)
+	ClosingSigned(ClosingSigned),
+	/// An update_add_htlc message (type 128)
+	UpdateAddHTLC(UpdateAddHTLC),
+	/// An update_fulfill_htlc message (type 130)
+	UpdateFulfillHTLC(UpdateFulfillHTLC),
+	/// An update_fail_htlc message (type 131)
+	UpdateFailHTLC(UpdateFailHTLC),
+	/// A commitment_signed message (type 132)
+	CommitmentSigned(CommitmentSigned),
+	/// A revoke_and_ack message (type 133)
+	RevokeAndACK(RevokeAndACK),
+	/// An update_fee message (type 134)
+	UpdateFee(UpdateFee),
+	/// An update_fail_malformed_htlc message (type 135)
+	UpdateFailMalformedHTLC(UpdateFailMalformedHTLC),
+	/// A channel_reestablish message (type 136)
+	ChannelReestablish(ChannelReestablish),
+	/// A channel_announcement message (type 256)
+	ChannelAnnouncement(ChannelAnnouncement),
+	/// A node_announcement message (type 257)
+	NodeAnnouncement(NodeAnnouncement),
+	/// A channel_update message (type 258)
+	ChannelUpdate(ChannelUpdate),
+	/// An announcement_signatures message (type 259)
+	AnnouncementSignatures(AnnouncementSignatures),
+	/// A query_short_channel_ids message (type 261)
+	QueryShortChannelIds(QueryShortChannelIds),
+	/// A reply_short_channel_ids_end message (type 262)
+	ReplyShortChannelIdsEnd(ReplyShortChannelIdsEnd),
+	/// A query_channel_range message (type 263)
+	QueryChannelRange(QueryChannelRange),
+	/// A reply_channel_range message (type 264)
+	ReplyChannelRange(ReplyChannelRange),
+	/// A gossip_timestamp_filter message (type 265)
+	GossipTimestampFilter(GossipTimestampFilter),
+	/// A message of a type we don't understand, holding only its type
+	Unknown(u16),
+}
+
+impl Message {
+	/// Gets the 2-byte wire type of this message
+	pub fn type_id(&self) -> u16 {
+		match self {
+			&Message::Init(_) => 16,
+			&Message::Error(_) => 17,
+			&Message::Ping(_) => 18,
+			&Message::Pong(_) => 19,
+			&Message::OpenChannel(_) => 32,
+			&Message::AcceptChannel(_) => 33,
+			&Message::FundingCreated(_) => 34,
+			&Message::FundingSigned(_) => 35,
+			&Message::FundingLocked(_) => 36,
+			&Message::Shutdown(_) => 38,
+			&Message::ClosingSigned(_) => 39,
+			&Message::UpdateAddHTLC(_) => 128,
+			&Message::UpdateFulfillHTLC(_) => 130,
+			&Message::UpdateFailHTLC(_) => 131,
+			&Message::CommitmentSigned(_) => 132,
+			&Message::RevokeAndACK(_) => 133,
+			&Message::UpdateFee(_) => 134,
+			&Message::UpdateFailMalformedHTLC(_) => 135,
+			&Message::ChannelReestablish(_) => 136,
+			&Message::ChannelAnnouncement(_) => 256,
+			&Message::NodeAnnouncement(_) => 257,
+			&Message::ChannelUpdate(_) => 258,
+			&Message::AnnouncementSignatures(_) => 259,
+			&Message::QueryShortChannelIds(_) => 261,
+			&Message::ReplyShortChannelIdsEnd(_) => 262,
+			&Message::QueryChannelRange(_) => 263,
+			&Message::ReplyChannelRange(_) => 264,
+			&Message::GossipTimestampFilter(_) => 265,
+			&Message::Unknown(type_id) => type_id,
+		}
+	}
+
+	/// Serializes the message with its 2-byte type prefix, ready to be passed to the transport
+	/// encryptor. An Unknown message is encoded as only its type.
+	pub fn encode(&self) -> Vec<u8> {
+		let mut res = Vec::new();
+		self.type_id().write(&mut res).unwrap();
+		match self {
+			&Message::Init(ref msg) => msg.write(&mut res),
+			&Message::Error(ref msg) => msg.write(&mut res),
+			&Message::Ping(ref msg) => msg.write(&mut res),
+			&Message::Pong(ref msg) => msg.write(&mut res),
+			&Message::OpenChannel(ref msg) => msg.write(&mut res),
+			&Message::AcceptChannel(ref msg) => msg.write(&mut res),
+			&Message::FundingCreated(ref msg) => msg.write(&mut res),
+			&Message::FundingSigned(ref msg) => msg.write(&mut res),
+			&Message::FundingLocked(ref msg) => msg.write(&mut res),
+			&Message::Shutdown(ref msg) => msg.write(&mut res),
+			&Message::ClosingSigned(ref msg) => msg.write(&mut res),
+			&Message::UpdateAddHTLC(ref msg) => msg.write(&mut res),
+			&Message::UpdateFulfillHTLC(ref msg) => msg.write(&mut res),
+			&Message::UpdateFailHTLC(ref msg) => msg.write(&mut res),
+			&Message::CommitmentSigned(ref msg) => msg.write(&mut res),
+			&Message::RevokeAndACK(ref msg) => msg.write(&mut res),
+			&Message::UpdateFee(ref msg) => msg.write(&mut res),
+			&Message::UpdateFailMalformedHTLC(ref msg) => msg.write(&mut res),
+			&Message::ChannelReestablish(ref msg) => msg.write(&mut res),
+			&Message::ChannelAnnouncement(ref msg) => msg.write(&mut res),
+			&Message::NodeAnnouncement(ref msg) => msg.write(&mut res),
+			&Message::ChannelUpdate(ref msg) => msg.write(&mut res),
+			&Message::AnnouncementSignatures(ref msg) => msg.write(&mut res),
+			&Message::QueryShortChannelIds(ref msg) => msg.write(&mut res),
+			&Message::ReplyShortChannelIdsEnd(ref msg) => msg.write(&mut res),
+			&Message::QueryChannelRange(ref msg) => msg.write(&mut res),
+			&Message::ReplyChannelRange(ref msg) => msg.write(&mut res),
+			&Message::GossipTimestampFilter(ref msg) => msg.write(&mut res),
+			&Message::Unknown(_) => Ok(()),
+		}.unwrap();
+		res
+	}
+
+	/// Reads a message, including its 2-byte type prefix, as decrypted by the transport. This is
+	/// the inverse of encode. Messages of a type we don't understand are returned as Unknown
+	/// without looking at their contents, it is up to the caller to reject unknown even types.
+	pub fn decode(data: &[u8]) -> Result<Message, DecodeError> {
+		let mut r = ::std::io::Cursor::new(data);
+		let type_id: u16 = Readable::read(&mut r)?;
+		Ok(match type_id {
+			16 => Message::Init(Readable::read(&mut r)?),
+			17 => Message::Error(Readable::read(&mut r)?),
+			18 => Message::Ping(Readable::read(&mut r)?),
+			19 => Message::Pong(Readable::read(&mut r)?),
+			32 => Message::OpenChannel(Readable::read(&mut r)?),
+			33 => Message::AcceptChannel(Readable::read(&mut r)?),
+			34 => Message::FundingCreated(Readable::read(&mut r)?),
+			35 => Message::FundingSigned(Readable::read(&mut r)?),
+			36 => Message::FundingLocked(Readable::read(&mut r)?),
+			38 => Message::Shutdown(Readable::read(&mut r)?),
+			39 => Message::ClosingSigned(Readable::read(&mut r)?),
+			128 => Message::UpdateAddHTLC(Readable::read(&mut r)?),
+			130 => Message::UpdateFulfillHTLC(Readable::read(&mut r)?),
+			131 => Message::UpdateFailHTLC(Readable::read(&mut r)?),
+			132 => Message::CommitmentSigned(Readable::read(&mut r)?),
+			133 => Message::RevokeAndACK(Readable::read(&mut r)?),
+			134 => Message::UpdateFee(Readable::read(&mut r)?),
+			135 => Message::UpdateFailMalformedHTLC(Readable::read(&mut r)?),
+			136 => Message::ChannelReestablish(Readable::read(&mut r)?),
+			256 => Message::ChannelAnnouncement(Readable::read(&mut r)?),
+			257 => Message::NodeAnnouncement(Readable::read(&mut r)?),
+			258 => Message::ChannelUpdate(Readable::read(&mut r)?),
+			259 => Message::AnnouncementSignatures(Readable::read(&mut r)?),
+			261 => Message::QueryShortChannelIds(Readable::read(&mut r)?),
+			262 => Message::ReplyShortChannelIdsEnd(Readable::read(&mut r)?),
+			263 => Message::QueryChannelRange(Readable::read(&mut r)?),
+			264 => Message::ReplyChannelRange(Readable::read(&mut r)?),
+			265 => Message::GossipTimestampFilter(Readable::read(&mut r)?),
+			_ => Message::Unknown(type_id),
+		})
+	}
+}
+
 /// A trait to describe an object which can receive channel messages.
 ///
 /// Messages MAY be called in parallel when they originate from different their_node_ids, however
@@ -665,6 +1072,12 @@ pub trait ChannelMessageHandler : events::MessageSendEventsProvider + Send + Syn
 	/// Handle an incoming channel_reestablish message from the given peer.
 	fn handle_channel_reestablish(&self, their_node_id: &PublicKey, msg: &ChannelReestablish) -> Result<(), HandleError>;
 
+	// Channel forwarding policy:
+	/// Handle an incoming channel_update message from the given peer, in addition to the
+	/// RoutingMessageHandler. Lets us learn the forwarding policy our counterparty set for our
+	/// private channels, which is never announced.
+	fn handle_channel_update(&self, their_node_id: &PublicKey, msg: &ChannelUpdate) -> Result<(), HandleError>;
+
 	// Error:
 	/// Handle an incoming error message from the given peer.
 	fn handle_error(&self, their_node_id: &PublicKey, msg: &ErrorMessage);
@@ -683,6 +1096,9 @@ pub trait RoutingMessageHandler : Send + Sync {
 	fn handle_channel_update(&self, msg: &ChannelUpdate) -> Result<bool, HandleError>;
 	/// Handle some updates to the route graph that we learned due to an outbound failed payment.
 	fn handle_htlc_fail_channel_update(&self, update: &HTLCFailChannelUpdate);
+	/// Handle an incoming gossip_timestamp_filter message, returning an Err if the peer's filter
+	/// should be ignored (eg because it is for a chain other than ours).
+	fn handle_gossip_timestamp_filter(&self, msg: &GossipTimestampFilter) -> Result<(), HandleError>;
 	/// Gets a subset of the channel announcements and updates required to dump our routing table
 	/// to a remote node, starting at the short_channel_id indicated by starting_point and
 	/// including batch_amount entries.
@@ -760,6 +1176,7 @@ impl Error for DecodeError {
 			DecodeError::ShortRead => "Packet extended beyond the provided bytes",
 			DecodeError::ExtraAddressesPerType => "More than one address of a single type",
 			DecodeError::BadLengthDescriptor => "A length descriptor in the packet didn't describe the later data correctly",
+			DecodeError::UnsupportedCompression => "Encoded values were zlib-compressed, which is unsupported",
 			DecodeError::Io(ref e) => e.description(),
 		}
 	}
@@ -1233,6 +1650,107 @@ impl_writeable_len_match!(ChannelUpdate, {
 	contents
 });
 
+/// The most short_channel_ids which fit in a single uncompressed encoded set, as its u16 length
+/// also covers the encoding type byte.
+pub const MAX_SHORT_CHANNEL_IDS_PER_MSG: usize = (::std::u16::MAX as usize - 1) / 8;
+
+/// Writes an encoded set of short_channel_ids (a u16 length, the encoding type byte and the ids
+/// themselves), as used in gossip queries. We only ever write the uncompressed form, so callers
+/// must split sets of more than MAX_SHORT_CHANNEL_IDS_PER_MSG ids across several messages, larger
+/// sets fail with an InvalidInput error.
+fn write_short_channel_ids<W: Writer>(w: &mut W, short_channel_ids: &[u64]) -> Result<(), ::std::io::Error> {
+	if short_channel_ids.len() > MAX_SHORT_CHANNEL_IDS_PER_MSG {
+		return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidInput, "Too many short_channel_ids for a u16 length"));
+	}
+	((1 + short_channel_ids.len() * 8) as u16).write(w)?;
+	(EncodingType::Uncompressed as u8).write(w)?;
+	for scid in short_channel_ids.iter() {
+		scid.write(w)?;
+	}
+	Ok(())
+}
+
+/// Reads an encoded set of short_channel_ids as written by write_short_channel_ids, failing with
+/// UnsupportedCompression if the peer sent a zlib-compressed set.
+fn read_short_channel_ids<R: Read>(r: &mut R) -> Result<Vec<u64>, DecodeError> {
+	let encoded_len: u16 = Readable::read(r)?;
+	if encoded_len == 0 {
+		return Err(DecodeError::BadLengthDescriptor);
+	}
+	let encoding_type: u8 = Readable::read(r)?;
+	if encoding_type == EncodingType::ZlibCompressed as u8 {
+		return Err(DecodeError::UnsupportedCompression);
+	} else if encoding_type != EncodingType::Uncompressed as u8 {
+		return Err(DecodeError::InvalidValue);
+	}
+	if (encoded_len - 1) % 8 != 0 {
+		return Err(DecodeError::BadLengthDescriptor);
+	}
+	let count = (encoded_len - 1) / 8;
+	let mut short_channel_ids = Vec::with_capacity(count as usize);
+	for _ in 0..count {
+		short_channel_ids.push(Readable::read(r)?);
+	}
+	Ok(short_channel_ids)
+}
+
+impl Writeable for QueryShortChannelIds {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		w.size_hint(32 + 2 + 1 + self.short_channel_ids.len() * 8);
+		self.chain_hash.write(w)?;
+		write_short_channel_ids(w, &self.short_channel_ids[..])
+	}
+}
+
+impl<R: Read> Readable<R> for QueryShortChannelIds {
+	fn read(r: &mut R) -> Result<Self, DecodeError> {
+		Ok(Self {
+			chain_hash: Readable::read(r)?,
+			short_channel_ids: read_short_channel_ids(r)?,
+		})
+	}
+}
+
+impl_writeable!(ReplyShortChannelIdsEnd, 32+1, {
+	chain_hash,
+	full_information
+});
+
+impl_writeable!(QueryChannelRange, 32+4+4, {
+	chain_hash,
+	first_blocknum,
+	number_of_blocks
+});
+
+impl Writeable for ReplyChannelRange {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		w.size_hint(32 + 4 + 4 + 1 + 2 + 1 + self.short_channel_ids.len() * 8);
+		self.chain_hash.write(w)?;
+		self.first_blocknum.write(w)?;
+		self.number_of_blocks.write(w)?;
+		self.complete.write(w)?;
+		write_short_channel_ids(w, &self.short_channel_ids[..])
+	}
+}
+
+impl<R: Read> Readable<R> for ReplyChannelRange {
+	fn read(r: &mut R) -> Result<Self, DecodeError> {
+		Ok(Self {
+			chain_hash: Readable::read(r)?,
+			first_blocknum: Readable::read(r)?,
+			number_of_blocks: Readable::read(r)?,
+			complete: Readable::read(r)?,
+			short_channel_ids: read_short_channel_ids(r)?,
+		})
+	}
+}
+
+impl_writeable!(GossipTimestampFilter, 32+4+4, {
+	chain_hash,
+	first_timestamp,
+	timestamp_range
+});
+
 impl Writeable for ErrorMessage {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
 		w.size_hint(32 + 2 + self.data.len());
@@ -1387,18 +1905,21 @@ mod tests {
 	use hex;
 	use ln::msgs;
 	use ln::msgs::{GlobalFeatures, LocalFeatures, OptionalField, OnionErrorPacket};
-	use ln::channelmanager::{PaymentPreimage, PaymentHash};
-	use util::ser::Writeable;
+	use ln::channelmanager::{PaymentPreimage, PaymentHash, MAX_LOCAL_BREAKDOWN_TIMEOUT};
+	use util::amount::{MilliSatoshi, Satoshi};
+	use util::config::ChannelHandshakeLimits;
+	use util::ser::{Readable, Writeable};
 
 	use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 	use bitcoin_hashes::hex::FromHex;
+	use bitcoin_hashes::Hash;
 	use bitcoin::util::address::Address;
 	use bitcoin::network::constants::Network;
 	use bitcoin::blockdata::script::Builder;
 	use bitcoin::blockdata::opcodes;
 
 	use secp256k1::key::{PublicKey,SecretKey};
-	use secp256k1::{Secp256k1, Message};
+	use secp256k1::{Secp256k1, Message, Signature};
 
 	#[test]
 	fn encoding_channel_reestablish_no_secret() {
@@ -1414,6 +1935,9 @@ mod tests {
 			encoded_value,
 			vec![4, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 4]
 		);
+
+		let decoded_value: msgs::ChannelReestablish = Readable::read(&mut ::std::io::Cursor::new(&encoded_value)).unwrap();
+		assert!(decoded_value == cr);
 	}
 
 	#[test]
@@ -1435,6 +1959,13 @@ mod tests {
 			encoded_value,
 			vec![4, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 4, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 3, 27, 132, 197, 86, 123, 18, 100, 64, 153, 93, 62, 213, 170, 186, 5, 101, 215, 30, 24, 52, 96, 72, 25, 255, 156, 23, 245, 233, 213, 221, 7, 143]
 		);
+
+		let decoded_value: msgs::ChannelReestablish = Readable::read(&mut ::std::io::Cursor::new(&encoded_value)).unwrap();
+		assert!(decoded_value == cr);
+
+		// A data_loss_protect with a secret but no point is truncated, not absent
+		let truncated: Result<msgs::ChannelReestablish, _> = Readable::read(&mut ::std::io::Cursor::new(&encoded_value[..32+2*8+32]));
+		assert!(truncated.is_err());
 	}
 
 	macro_rules! get_keys_from {
@@ -1471,6 +2002,9 @@ mod tests {
 
 		let encoded_value = announcement_signatures.encode();
 		assert_eq!(encoded_value, hex::decode("040000000000000005000000000000000600000000000000070000000000000000083a840000034dd977cb9b53d93a6ff64bb5f1e158b4094b66e798fb12911168a3ccdf80a83096340a6a95da0ae8d9f776528eecdbb747eb6b545495a4319ed5378e35b21e073acf9953cef4700860f5967838eba2bae89288ad188ebf8b20bf995c3ea53a26df1876d0a3a0e13172ba286a673140190c02ba9da60a2e43a745188c8a83c7f3ef").unwrap());
+
+		let decoded_value: msgs::AnnouncementSignatures = Readable::read(&mut ::std::io::Cursor::new(&encoded_value)).unwrap();
+		assert_eq!(decoded_value, announcement_signatures);
 	}
 
 	fn do_encoding_channel_announcement(unknown_features_bits: bool, non_bitcoin_chain_hash: bool, excess_data: bool) {
@@ -1632,6 +2166,86 @@ mod tests {
 		do_encoding_node_announcement(false, false, true, false, true, false, false);
 	}
 
+	#[test]
+	fn net_address_round_trip() {
+		let addresses = vec![
+			msgs::NetAddress::IPv4 { addr: [255, 254, 253, 252], port: 9735 },
+			msgs::NetAddress::IPv6 { addr: [255, 254, 253, 252, 251, 250, 249, 248, 247, 246, 245, 244, 243, 242, 241, 240], port: 9736 },
+			msgs::NetAddress::OnionV2 { addr: [255, 254, 253, 252, 251, 250, 249, 248, 247, 246], port: 9737 },
+			msgs::NetAddress::OnionV3 { ed25519_pubkey: [42; 32], checksum: 32, version: 3, port: 9738 },
+		];
+		for (addr, id) in addresses.iter().zip(1..) {
+			let encoded = addr.encode();
+			assert_eq!(encoded[0], id);
+			assert_eq!(encoded.len(), addr.len() as usize + 1);
+			let decoded: Result<msgs::NetAddress, u8> = Readable::read(&mut ::std::io::Cursor::new(&encoded)).unwrap();
+			assert_eq!(decoded.unwrap(), *addr);
+		}
+
+		// An unknown type is returned as such
+		let decoded: Result<msgs::NetAddress, u8> = Readable::read(&mut ::std::io::Cursor::new(&[42, 1, 2, 3])).unwrap();
+		assert_eq!(decoded.unwrap_err(), 42);
+
+		// A mixed list followed by an unknown type in a node_announcement keeps the unknown address
+		// (and everything after it) as excess_address_data
+		let secp_ctx = Secp256k1::new();
+		let (privkey_1, pubkey_1) = get_keys_from!("0101010101010101010101010101010101010101010101010101010101010101", secp_ctx);
+		let node_announcement = msgs::NodeAnnouncement {
+			signature: get_sig_on!(privkey_1, secp_ctx, String::from("01010101010101010101010101010101")),
+			contents: msgs::UnsignedNodeAnnouncement {
+				features: GlobalFeatures::new(),
+				timestamp: 20190119,
+				node_id: pubkey_1,
+				rgb: [32; 3],
+				alias: [16; 32],
+				addresses,
+				excess_address_data: vec![5, 0, 3, 1, 2, 3],
+				excess_data: vec![1, 2],
+			},
+		};
+		let decoded: msgs::NodeAnnouncement = Readable::read(&mut ::std::io::Cursor::new(&node_announcement.encode())).unwrap();
+		assert_eq!(decoded.contents, node_announcement.contents);
+	}
+
+	#[test]
+	fn onion_v3_address() {
+		// torproject.org's onion service
+		let onion_v3 = msgs::NetAddress::OnionV3 {
+			ed25519_pubkey: [0xd1, 0xb3, 0x8b, 0x83, 0xa8, 0x3b, 0x3e, 0xd9, 0x18, 0xc5, 0xbb, 0x69, 0xdd, 0x44, 0x4a, 0xd5, 0x6b, 0xc8, 0xd5, 0x83, 0x5a, 0x91, 0x4d, 0xe7, 0x34, 0x47, 0x47, 0x4e, 0x5f, 0x02, 0x59, 0x1b],
+			checksum: 0xddd9,
+			version: 3,
+			port: 9735,
+		};
+		assert_eq!(onion_v3.onion_hostname().unwrap(), "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion");
+		let onion_v2 = msgs::NetAddress::OnionV2 { addr: [0; 10], port: 9735 };
+		assert_eq!(onion_v2.onion_hostname().unwrap(), "aaaaaaaaaaaaaaaa.onion");
+		assert!(msgs::NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }.onion_hostname().is_none());
+
+		let encoded = onion_v3.encode();
+		assert_eq!(encoded, hex::decode("04d1b38b83a83b3ed918c5bb69dd444ad56bc8d5835a914de73447474e5f02591bddd9032607").unwrap());
+		let decoded: Result<msgs::NetAddress, u8> = Readable::read(&mut ::std::io::Cursor::new(&encoded)).unwrap();
+		assert_eq!(decoded.unwrap(), onion_v3);
+
+		// An unknown address type following the onion address is kept as excess_address_data
+		let secp_ctx = Secp256k1::new();
+		let (privkey_1, pubkey_1) = get_keys_from!("0101010101010101010101010101010101010101010101010101010101010101", secp_ctx);
+		let node_announcement = msgs::NodeAnnouncement {
+			signature: get_sig_on!(privkey_1, secp_ctx, String::from("01010101010101010101010101010101")),
+			contents: msgs::UnsignedNodeAnnouncement {
+				features: GlobalFeatures::new(),
+				timestamp: 20190119,
+				node_id: pubkey_1,
+				rgb: [32; 3],
+				alias: [16; 32],
+				addresses: vec![onion_v3],
+				excess_address_data: vec![42, 1, 2, 3, 4, 5],
+				excess_data: Vec::new(),
+			},
+		};
+		let decoded: msgs::NodeAnnouncement = Readable::read(&mut ::std::io::Cursor::new(&node_announcement.encode())).unwrap();
+		assert_eq!(decoded.contents, node_announcement.contents);
+	}
+
 	fn do_encoding_channel_update(non_bitcoin_chain_hash: bool, direction: bool, disable: bool, htlc_maximum_msat: bool) {
 		let secp_ctx = Secp256k1::new();
 		let (privkey_1, _) = get_keys_from!("0101010101010101010101010101010101010101010101010101010101010101", secp_ctx);
@@ -1680,6 +2294,72 @@ mod tests {
 		assert_eq!(encoded_value, target_value);
 	}
 
+	fn sane_open_channel() -> msgs::OpenChannel {
+		let secp_ctx = Secp256k1::new();
+		let (_, pubkey) = get_keys_from!("0101010101010101010101010101010101010101010101010101010101010101", secp_ctx);
+		msgs::OpenChannel {
+			chain_hash: Sha256dHash::from_hex("6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000").unwrap(),
+			temporary_channel_id: [2; 32],
+			funding_satoshis: Satoshi(100000),
+			push_msat: MilliSatoshi(10000000),
+			dust_limit_satoshis: Satoshi(546),
+			max_htlc_value_in_flight_msat: MilliSatoshi(50000000),
+			channel_reserve_satoshis: Satoshi(1000),
+			htlc_minimum_msat: MilliSatoshi(1000),
+			feerate_per_kw: 253,
+			to_self_delay: 144,
+			max_accepted_htlcs: 30,
+			funding_pubkey: pubkey,
+			revocation_basepoint: pubkey,
+			payment_basepoint: pubkey,
+			delayed_payment_basepoint: pubkey,
+			htlc_basepoint: pubkey,
+			first_per_commitment_point: pubkey,
+			channel_flags: 0,
+			shutdown_scriptpubkey: OptionalField::Absent,
+		}
+	}
+
+	#[test]
+	fn open_channel_sanity() {
+		let limits = ChannelHandshakeLimits::new();
+		assert!(sane_open_channel().check_remote_sanity(&limits).is_ok());
+
+		macro_rules! assert_rejected {
+			($field: ident, $value: expr, $limits: expr, $err: expr) => {
+				let mut msg = sane_open_channel();
+				msg.$field = $value;
+				assert_eq!(msg.check_remote_sanity(&$limits).unwrap_err().err, $err);
+			}
+		}
+		assert_rejected!(funding_satoshis, Satoshi(1 << 24), limits, "funding value > 2^24");
+		assert_rejected!(channel_reserve_satoshis, Satoshi(100001), limits, "Bogus channel_reserve_satoshis");
+		assert_rejected!(push_msat, MilliSatoshi(99000001), limits, "push_msat larger than funding value");
+		assert_rejected!(dust_limit_satoshis, Satoshi(100001), limits, "Peer never wants payout outputs?");
+		assert_rejected!(dust_limit_satoshis, Satoshi(1001), limits, "Bogus; channel reserve is less than dust limit");
+		assert_rejected!(htlc_minimum_msat, MilliSatoshi(99000000), limits, "Minimum htlc value is full channel value");
+		assert_rejected!(to_self_delay, MAX_LOCAL_BREAKDOWN_TIMEOUT + 1, limits, "They wanted our payments to be delayed by a needlessly long period");
+		assert_rejected!(max_accepted_htlcs, 0, limits, "0 max_accpted_htlcs makes for a useless channel");
+		assert_rejected!(max_accepted_htlcs, 484, limits, "max_accpted_htlcs > 483");
+
+		// Each of the user's limits is applied on top of the protocol rules
+		macro_rules! assert_limit_rejects {
+			($limit: ident, $value: expr, $err: expr) => {
+				let mut limits = ChannelHandshakeLimits::new();
+				limits.$limit = $value;
+				assert_eq!(sane_open_channel().check_remote_sanity(&limits).unwrap_err().err, $err);
+			}
+		}
+		assert_limit_rejects!(their_to_self_delay, 143, "They wanted our payments to be delayed by a needlessly long period");
+		assert_limit_rejects!(min_funding_satoshis, 100001, "funding satoshis is less than the user specified limit");
+		assert_limit_rejects!(max_htlc_minimum_msat, 999, "htlc minimum msat is higher than the user specified limit");
+		assert_limit_rejects!(min_max_htlc_value_in_flight_msat, 50000001, "max htlc value in flight msat is less than the user specified limit");
+		assert_limit_rejects!(max_channel_reserve_satoshis, 999, "channel reserve satoshis is higher than the user specified limit");
+		assert_limit_rejects!(min_max_accepted_htlcs, 31, "max accepted htlcs is less than the user specified limit");
+		assert_limit_rejects!(min_dust_limit_satoshis, 547, "dust limit satoshis is less than the user specified limit");
+		assert_limit_rejects!(max_dust_limit_satoshis, 545, "dust limit satoshis is greater than the user specified limit");
+	}
+
 	#[test]
 	fn encoding_channel_update() {
 		do_encoding_channel_update(false, false, false, false);
@@ -1701,12 +2381,12 @@ mod tests {
 		let open_channel = msgs::OpenChannel {
 			chain_hash: if !non_bitcoin_chain_hash { Sha256dHash::from_hex("6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000").unwrap() } else { Sha256dHash::from_hex("000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943").unwrap() },
 			temporary_channel_id: [2; 32],
-			funding_satoshis: 1311768467284833366,
-			push_msat: 2536655962884945560,
-			dust_limit_satoshis: 3608586615801332854,
-			max_htlc_value_in_flight_msat: 8517154655701053848,
-			channel_reserve_satoshis: 8665828695742877976,
-			htlc_minimum_msat: 2316138423780173,
+			funding_satoshis: Satoshi(1311768467284833366),
+			push_msat: MilliSatoshi(2536655962884945560),
+			dust_limit_satoshis: Satoshi(3608586615801332854),
+			max_htlc_value_in_flight_msat: MilliSatoshi(8517154655701053848),
+			channel_reserve_satoshis: Satoshi(8665828695742877976),
+			htlc_minimum_msat: MilliSatoshi(2316138423780173),
 			feerate_per_kw: 821716,
 			to_self_delay: 49340,
 			max_accepted_htlcs: 49340,
@@ -1757,10 +2437,10 @@ mod tests {
 		let (_, pubkey_6) = get_keys_from!("0606060606060606060606060606060606060606060606060606060606060606", secp_ctx);
 		let accept_channel = msgs::AcceptChannel {
 			temporary_channel_id: [2; 32],
-			dust_limit_satoshis: 1311768467284833366,
-			max_htlc_value_in_flight_msat: 2536655962884945560,
-			channel_reserve_satoshis: 3608586615801332854,
-			htlc_minimum_msat: 2316138423780173,
+			dust_limit_satoshis: Satoshi(1311768467284833366),
+			max_htlc_value_in_flight_msat: MilliSatoshi(2536655962884945560),
+			channel_reserve_satoshis: Satoshi(3608586615801332854),
+			htlc_minimum_msat: MilliSatoshi(2316138423780173),
 			minimum_depth: 821716,
 			to_self_delay: 49340,
 			max_accepted_htlcs: 49340,
@@ -2060,4 +2740,364 @@ mod tests {
 		let target_value = hex::decode("004000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000").unwrap();
 		assert_eq!(encoded_value, target_value);
 	}
+
+	#[test]
+	fn encoding_query_channel_range() {
+		let query_channel_range = msgs::QueryChannelRange {
+			chain_hash: Sha256dHash::from_hex("6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000").unwrap(),
+			first_blocknum: 100000,
+			number_of_blocks: 1500,
+		};
+		let encoded_value = query_channel_range.encode();
+		let target_value = hex::decode("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f000186a0000005dc").unwrap();
+		assert_eq!(encoded_value, target_value);
+		let decoded_value: msgs::QueryChannelRange = Readable::read(&mut ::std::io::Cursor::new(&encoded_value)).unwrap();
+		assert_eq!(decoded_value, query_channel_range);
+	}
+
+	#[test]
+	fn encoding_reply_channel_range() {
+		let reply_channel_range = msgs::ReplyChannelRange {
+			chain_hash: Sha256dHash::from_hex("6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000").unwrap(),
+			first_blocknum: 100000,
+			number_of_blocks: 1500,
+			complete: true,
+			short_channel_ids: vec![0x0186a00000050001, 0x0186a10000010000, 0x0186b70000020003],
+		};
+		let encoded_value = reply_channel_range.encode();
+		let target_value = hex::decode("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f000186a0000005dc01001900\
+			0186a000000500010186a100000100000186b70000020003").unwrap();
+		assert_eq!(encoded_value, target_value);
+		let decoded_value: msgs::ReplyChannelRange = Readable::read(&mut ::std::io::Cursor::new(&encoded_value)).unwrap();
+		assert_eq!(decoded_value, reply_channel_range);
+	}
+
+	#[test]
+	fn reply_channel_range_compressed_unsupported() {
+		// Same as above but with the encoding type set to zlib and a nonsense compressed body
+		let encoded_value = hex::decode("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f000186a0000005dc0100050102030405").unwrap();
+		let res: Result<msgs::ReplyChannelRange, _> = Readable::read(&mut ::std::io::Cursor::new(&encoded_value));
+		match res {
+			Err(msgs::DecodeError::UnsupportedCompression) => {},
+			_ => panic!("zlib-compressed short_channel_ids should be rejected"),
+		}
+	}
+
+	#[test]
+	fn encoding_query_short_channel_ids() {
+		let query_short_channel_ids = msgs::QueryShortChannelIds {
+			chain_hash: Sha256dHash::from_hex("6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000").unwrap(),
+			short_channel_ids: vec![0x0186a00000050001, 0x0186a10000010000, 0x0186b70000020003],
+		};
+		let encoded_value = query_short_channel_ids.encode();
+		let target_value = hex::decode("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f001900\
+			0186a000000500010186a100000100000186b70000020003").unwrap();
+		assert_eq!(encoded_value, target_value);
+		let decoded_value: msgs::QueryShortChannelIds = Readable::read(&mut ::std::io::Cursor::new(&encoded_value)).unwrap();
+		assert_eq!(decoded_value, query_short_channel_ids);
+	}
+
+	#[test]
+	fn encoding_query_short_channel_ids_too_many() {
+		let chain_hash = Sha256dHash::from_hex("6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000").unwrap();
+		let query_short_channel_ids = msgs::QueryShortChannelIds {
+			chain_hash,
+			short_channel_ids: (0..msgs::MAX_SHORT_CHANNEL_IDS_PER_MSG as u64).collect(),
+		};
+		let encoded_value = query_short_channel_ids.encode();
+		assert_eq!(encoded_value.len(), 32 + 2 + 1 + msgs::MAX_SHORT_CHANNEL_IDS_PER_MSG * 8);
+		let decoded_value: msgs::QueryShortChannelIds = Readable::read(&mut ::std::io::Cursor::new(&encoded_value)).unwrap();
+		assert_eq!(decoded_value, query_short_channel_ids);
+
+		// One more id no longer fits in the u16 length, so we must refuse to write it
+		let query_short_channel_ids = msgs::QueryShortChannelIds {
+			chain_hash,
+			short_channel_ids: (0..msgs::MAX_SHORT_CHANNEL_IDS_PER_MSG as u64 + 1).collect(),
+		};
+		let mut writer = Vec::new();
+		assert!(query_short_channel_ids.write(&mut writer).is_err());
+	}
+
+	fn do_encoding_reply_short_channel_ids_end(full_information: bool) {
+		let reply_short_channel_ids_end = msgs::ReplyShortChannelIdsEnd {
+			chain_hash: Sha256dHash::from_hex("6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000").unwrap(),
+			full_information,
+		};
+		let encoded_value = reply_short_channel_ids_end.encode();
+		let mut target_value = hex::decode("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+		target_value.push(if full_information { 1 } else { 0 });
+		assert_eq!(encoded_value, target_value);
+		let decoded_value: msgs::ReplyShortChannelIdsEnd = Readable::read(&mut ::std::io::Cursor::new(&encoded_value)).unwrap();
+		assert_eq!(decoded_value, reply_short_channel_ids_end);
+	}
+
+	#[test]
+	fn encoding_reply_short_channel_ids_end() {
+		do_encoding_reply_short_channel_ids_end(true);
+		do_encoding_reply_short_channel_ids_end(false);
+
+		// full_information is a single byte which must be 0 or 1
+		let encoded_value = hex::decode("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f02").unwrap();
+		let res: Result<msgs::ReplyShortChannelIdsEnd, _> = Readable::read(&mut ::std::io::Cursor::new(&encoded_value));
+		match res {
+			Err(msgs::DecodeError::InvalidValue) => {},
+			_ => panic!("full_information must be a bool"),
+		}
+	}
+
+	#[test]
+	fn encoding_gossip_timestamp_filter() {
+		let gossip_timestamp_filter = msgs::GossipTimestampFilter {
+			chain_hash: Sha256dHash::from_hex("6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000").unwrap(),
+			first_timestamp: 1590000000,
+			timestamp_range: 0xffffffff,
+		};
+		let encoded_value = gossip_timestamp_filter.encode();
+		let target_value = hex::decode("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f5ec57980ffffffff").unwrap();
+		assert_eq!(encoded_value, target_value);
+		let decoded_value: msgs::GossipTimestampFilter = Readable::read(&mut ::std::io::Cursor::new(&encoded_value)).unwrap();
+		assert_eq!(decoded_value, gossip_timestamp_filter);
+	}
+
+	#[test]
+	fn gossip_timestamp_filter_matches() {
+		let mut filter = msgs::GossipTimestampFilter {
+			chain_hash: Sha256dHash::from_hex("6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000").unwrap(),
+			first_timestamp: 1000,
+			timestamp_range: 100,
+		};
+		assert!(!filter.matches(999));
+		assert!(filter.matches(1000));
+		assert!(filter.matches(1099));
+		assert!(!filter.matches(1100));
+
+		filter.timestamp_range = 0;
+		assert!(!filter.matches(1000));
+
+		// first_timestamp + timestamp_range overflows a u32, which must not wrap around
+		filter.first_timestamp = 0xffffff00;
+		filter.timestamp_range = 0x200;
+		assert!(!filter.matches(0));
+		assert!(!filter.matches(0xff));
+		assert!(!filter.matches(0xfffffeff));
+		assert!(filter.matches(0xffffff00));
+		assert!(filter.matches(0xffffffff));
+	}
+
+	fn get_signed_channel_announcement() -> msgs::ChannelAnnouncement {
+		let secp_ctx = Secp256k1::new();
+		let (privkey_1, pubkey_1) = get_keys_from!("0101010101010101010101010101010101010101010101010101010101010101", secp_ctx);
+		let (privkey_2, pubkey_2) = get_keys_from!("0202020202020202020202020202020202020202020202020202020202020202", secp_ctx);
+		let (privkey_3, pubkey_3) = get_keys_from!("0303030303030303030303030303030303030303030303030303030303030303", secp_ctx);
+		let (privkey_4, pubkey_4) = get_keys_from!("0404040404040404040404040404040404040404040404040404040404040404", secp_ctx);
+		let contents = msgs::UnsignedChannelAnnouncement {
+			features: GlobalFeatures::new(),
+			chain_hash: Sha256dHash::from_hex("6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000").unwrap(),
+			short_channel_id: 2316138423780173,
+			node_id_1: pubkey_1,
+			node_id_2: pubkey_2,
+			bitcoin_key_1: pubkey_3,
+			bitcoin_key_2: pubkey_4,
+			excess_data: Vec::new(),
+		};
+		let msghash = Message::from_slice(&Sha256dHash::hash(&contents.encode()[..])[..]).unwrap();
+		msgs::ChannelAnnouncement {
+			node_signature_1: secp_ctx.sign(&msghash, &privkey_1),
+			node_signature_2: secp_ctx.sign(&msghash, &privkey_2),
+			bitcoin_signature_1: secp_ctx.sign(&msghash, &privkey_3),
+			bitcoin_signature_2: secp_ctx.sign(&msghash, &privkey_4),
+			contents,
+		}
+	}
+
+	#[test]
+	fn verify_channel_announcement() {
+		let secp_ctx = Secp256k1::verification_only();
+		let encoded_value = get_signed_channel_announcement().encode();
+		let channel_announcement: msgs::ChannelAnnouncement = Readable::read(&mut ::std::io::Cursor::new(&encoded_value)).unwrap();
+		assert!(channel_announcement.verify(&secp_ctx).is_ok());
+
+		// Flipping a single byte in any one of the signatures must cause verification to fail
+		for i in 0..4 {
+			let mut bad_announcement = channel_announcement.clone();
+			{
+				let sig = match i {
+					0 => &mut bad_announcement.node_signature_1,
+					1 => &mut bad_announcement.node_signature_2,
+					2 => &mut bad_announcement.bitcoin_signature_1,
+					_ => &mut bad_announcement.bitcoin_signature_2,
+				};
+				let mut sig_bytes = sig.serialize_compact();
+				sig_bytes[63] ^= 1;
+				*sig = Signature::from_compact(&sig_bytes).unwrap();
+			}
+			assert!(bad_announcement.verify(&secp_ctx).is_err());
+		}
+
+		// As must changing the contents the signatures commit to
+		let mut bad_announcement = channel_announcement.clone();
+		bad_announcement.contents.short_channel_id += 1;
+		assert!(bad_announcement.verify(&secp_ctx).is_err());
+	}
+
+	#[test]
+	fn verify_node_announcement() {
+		let secp_ctx = Secp256k1::new();
+		let (privkey, pubkey) = get_keys_from!("0101010101010101010101010101010101010101010101010101010101010101", secp_ctx);
+		let contents = msgs::UnsignedNodeAnnouncement {
+			features: GlobalFeatures::new(),
+			timestamp: 20190119,
+			node_id: pubkey,
+			rgb: [32; 3],
+			alias: [16; 32],
+			addresses: Vec::new(),
+			excess_address_data: Vec::new(),
+			excess_data: Vec::new(),
+		};
+		let msghash = Message::from_slice(&Sha256dHash::hash(&contents.encode()[..])[..]).unwrap();
+		let node_announcement = msgs::NodeAnnouncement {
+			signature: secp_ctx.sign(&msghash, &privkey),
+			contents,
+		};
+		assert!(node_announcement.verify(&secp_ctx).is_ok());
+
+		let mut bad_announcement = node_announcement.clone();
+		bad_announcement.contents.timestamp += 1;
+		assert!(bad_announcement.verify(&secp_ctx).is_err());
+
+		let mut bad_announcement = node_announcement.clone();
+		let mut sig_bytes = bad_announcement.signature.serialize_compact();
+		sig_bytes[63] ^= 1;
+		bad_announcement.signature = Signature::from_compact(&sig_bytes).unwrap();
+		assert!(bad_announcement.verify(&secp_ctx).is_err());
+	}
+
+	#[test]
+	fn verify_channel_update() {
+		let secp_ctx = Secp256k1::new();
+		let (privkey_1, pubkey_1) = get_keys_from!("0101010101010101010101010101010101010101010101010101010101010101", secp_ctx);
+		let (_, pubkey_2) = get_keys_from!("0202020202020202020202020202020202020202020202020202020202020202", secp_ctx);
+		let contents = msgs::UnsignedChannelUpdate {
+			chain_hash: Sha256dHash::from_hex("6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000").unwrap(),
+			short_channel_id: 2316138423780173,
+			timestamp: 20190119,
+			flags: 0,
+			cltv_expiry_delta: 144,
+			htlc_minimum_msat: 1000000,
+			fee_base_msat: 10000,
+			fee_proportional_millionths: 20,
+			excess_data: Vec::new(),
+		};
+		let msghash = Message::from_slice(&Sha256dHash::hash(&contents.encode()[..])[..]).unwrap();
+		let channel_update = msgs::ChannelUpdate {
+			signature: secp_ctx.sign(&msghash, &privkey_1),
+			contents,
+		};
+		assert!(channel_update.verify(&secp_ctx, &pubkey_1).is_ok());
+		// Signed by the other end of the channel
+		assert!(channel_update.verify(&secp_ctx, &pubkey_2).is_err());
+
+		let mut bad_update = channel_update.clone();
+		bad_update.contents.fee_base_msat = 0;
+		assert!(bad_update.verify(&secp_ctx, &pubkey_1).is_err());
+
+		let mut bad_update = channel_update.clone();
+		let mut sig_bytes = bad_update.signature.serialize_compact();
+		sig_bytes[63] ^= 1;
+		bad_update.signature = Signature::from_compact(&sig_bytes).unwrap();
+		assert!(bad_update.verify(&secp_ctx, &pubkey_1).is_err());
+	}
+
+	#[test]
+	fn message_encode_decode_round_trips() {
+		let secp_ctx = Secp256k1::new();
+		let (privkey, pubkey) = get_keys_from!("0101010101010101010101010101010101010101010101010101010101010101", secp_ctx);
+		let sig = get_sig_on!(privkey, secp_ctx, String::from("01010101010101010101010101010101"));
+		let chain_hash = Sha256dHash::from_hex("6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000").unwrap();
+		let script = Builder::new().push_opcode(opcodes::OP_TRUE).into_script();
+
+		let msgs = vec![
+			msgs::Message::Init(msgs::Init { global_features: GlobalFeatures::new(), local_features: LocalFeatures::new() }),
+			msgs::Message::Error(msgs::ErrorMessage { channel_id: [2; 32], data: String::from("rust-lightning") }),
+			msgs::Message::Ping(msgs::Ping { ponglen: 64, byteslen: 3 }),
+			msgs::Message::Pong(msgs::Pong { byteslen: 64 }),
+			msgs::Message::OpenChannel(msgs::OpenChannel {
+				chain_hash, temporary_channel_id: [2; 32], funding_satoshis: Satoshi(1311768467284833366), push_msat: MilliSatoshi(2536655962884945560),
+				dust_limit_satoshis: Satoshi(3608586615801332854), max_htlc_value_in_flight_msat: MilliSatoshi(8517154655701053848),
+				channel_reserve_satoshis: Satoshi(8665828695742877976), htlc_minimum_msat: MilliSatoshi(2316138423780173), feerate_per_kw: 821716,
+				to_self_delay: 49340, max_accepted_htlcs: 49340, funding_pubkey: pubkey, revocation_basepoint: pubkey,
+				payment_basepoint: pubkey, delayed_payment_basepoint: pubkey, htlc_basepoint: pubkey,
+				first_per_commitment_point: pubkey, channel_flags: 1, shutdown_scriptpubkey: OptionalField::Present(script.clone()),
+			}),
+			msgs::Message::AcceptChannel(msgs::AcceptChannel {
+				temporary_channel_id: [2; 32], dust_limit_satoshis: Satoshi(1311768467284833366), max_htlc_value_in_flight_msat: MilliSatoshi(2536655962884945560),
+				channel_reserve_satoshis: Satoshi(3608586615801332854), htlc_minimum_msat: MilliSatoshi(2316138423780173), minimum_depth: 821716,
+				to_self_delay: 49340, max_accepted_htlcs: 49340, funding_pubkey: pubkey, revocation_basepoint: pubkey,
+				payment_basepoint: pubkey, delayed_payment_basepoint: pubkey, htlc_basepoint: pubkey,
+				first_per_commitment_point: pubkey, shutdown_scriptpubkey: OptionalField::Absent,
+			}),
+			msgs::Message::FundingCreated(msgs::FundingCreated { temporary_channel_id: [2; 32], funding_txid: chain_hash, funding_output_index: 255, signature: sig }),
+			msgs::Message::FundingSigned(msgs::FundingSigned { channel_id: [2; 32], signature: sig }),
+			msgs::Message::FundingLocked(msgs::FundingLocked { channel_id: [2; 32], next_per_commitment_point: pubkey }),
+			msgs::Message::Shutdown(msgs::Shutdown { channel_id: [2; 32], scriptpubkey: script }),
+			msgs::Message::ClosingSigned(msgs::ClosingSigned { channel_id: [2; 32], fee_satoshis: 2316138423780173, signature: sig }),
+			msgs::Message::UpdateAddHTLC(msgs::UpdateAddHTLC {
+				channel_id: [2; 32], htlc_id: 2316138423780173, amount_msat: 3608586615801332854, payment_hash: PaymentHash([1; 32]),
+				cltv_expiry: 821716,
+				onion_routing_packet: msgs::OnionPacket { version: 0, public_key: Ok(pubkey), hop_data: [1; 20*65], hmac: [2; 32] },
+			}),
+			msgs::Message::UpdateFulfillHTLC(msgs::UpdateFulfillHTLC { channel_id: [2; 32], htlc_id: 2316138423780173, payment_preimage: PaymentPreimage([1; 32]) }),
+			msgs::Message::UpdateFailHTLC(msgs::UpdateFailHTLC { channel_id: [2; 32], htlc_id: 2316138423780173, reason: OnionErrorPacket { data: vec![1; 32] } }),
+			msgs::Message::CommitmentSigned(msgs::CommitmentSigned { channel_id: [2; 32], signature: sig, htlc_signatures: vec![sig, sig] }),
+			msgs::Message::RevokeAndACK(msgs::RevokeAndACK { channel_id: [2; 32], per_commitment_secret: [1; 32], next_per_commitment_point: pubkey }),
+			msgs::Message::UpdateFee(msgs::UpdateFee { channel_id: [2; 32], feerate_per_kw: 20190119 }),
+			msgs::Message::UpdateFailMalformedHTLC(msgs::UpdateFailMalformedHTLC { channel_id: [2; 32], htlc_id: 2316138423780173, sha256_of_onion: [1; 32], failure_code: 255 }),
+			msgs::Message::ChannelReestablish(msgs::ChannelReestablish { channel_id: [2; 32], next_local_commitment_number: 3, next_remote_commitment_number: 4, data_loss_protect: OptionalField::Absent }),
+			msgs::Message::ChannelAnnouncement(msgs::ChannelAnnouncement {
+				node_signature_1: sig, node_signature_2: sig, bitcoin_signature_1: sig, bitcoin_signature_2: sig,
+				contents: msgs::UnsignedChannelAnnouncement {
+					features: GlobalFeatures::new(), chain_hash, short_channel_id: 2316138423780173,
+					node_id_1: pubkey, node_id_2: pubkey, bitcoin_key_1: pubkey, bitcoin_key_2: pubkey, excess_data: vec![10; 10],
+				},
+			}),
+			msgs::Message::NodeAnnouncement(msgs::NodeAnnouncement {
+				signature: sig,
+				contents: msgs::UnsignedNodeAnnouncement {
+					features: GlobalFeatures::new(), timestamp: 20190119, node_id: pubkey, rgb: [32; 3], alias: [16; 32],
+					addresses: vec![msgs::NetAddress::IPv4 { addr: [255, 254, 253, 252], port: 9735 }],
+					excess_address_data: Vec::new(), excess_data: vec![10; 10],
+				},
+			}),
+			msgs::Message::ChannelUpdate(msgs::ChannelUpdate {
+				signature: sig,
+				contents: msgs::UnsignedChannelUpdate {
+					chain_hash, short_channel_id: 2316138423780173, timestamp: 20190119, flags: 1, cltv_expiry_delta: 144,
+					htlc_minimum_msat: 1000000, fee_base_msat: 10000, fee_proportional_millionths: 20, excess_data: Vec::new(),
+				},
+			}),
+			msgs::Message::AnnouncementSignatures(msgs::AnnouncementSignatures { channel_id: [2; 32], short_channel_id: 2316138423780173, node_signature: sig, bitcoin_signature: sig }),
+			msgs::Message::QueryShortChannelIds(msgs::QueryShortChannelIds { chain_hash, short_channel_ids: vec![1, 2, 3] }),
+			msgs::Message::ReplyShortChannelIdsEnd(msgs::ReplyShortChannelIdsEnd { chain_hash, full_information: true }),
+			msgs::Message::QueryChannelRange(msgs::QueryChannelRange { chain_hash, first_blocknum: 100000, number_of_blocks: 1500 }),
+			msgs::Message::ReplyChannelRange(msgs::ReplyChannelRange { chain_hash, first_blocknum: 100000, number_of_blocks: 1500, complete: true, short_channel_ids: vec![1, 2, 3] }),
+			msgs::Message::GossipTimestampFilter(msgs::GossipTimestampFilter { chain_hash, first_timestamp: 1590000000, timestamp_range: 3600 }),
+			msgs::Message::Unknown(32769),
+		];
+
+		for msg in msgs.iter() {
+			let encoded = msg.encode();
+			assert_eq!(&encoded[..2], &[(msg.type_id() >> 8) as u8, msg.type_id() as u8]);
+			assert!(msgs::Message::decode(&encoded[..]).unwrap() == *msg);
+		}
+	}
+
+	#[test]
+	fn message_decode_errors() {
+		// Too short to hold a type
+		assert!(msgs::Message::decode(&[0]).is_err());
+		// A funding_locked with a truncated point
+		assert!(msgs::Message::decode(&[0, 36, 2, 2, 2]).is_err());
+		// An unknown type's contents are not interpreted
+		assert!(msgs::Message::decode(&[0x80, 0x01, 42]).unwrap() == msgs::Message::Unknown(32769));
+	}
 }
@@ -0,0 +1,761 @@
+//! Parsing of the per-hop payloads found in onion packets, as described in BOLT #4.
+//!
+//! A hop payload is either in the legacy realm-0 format (a 0 byte followed by a fixed 32 bytes
+//! of hop data) or a length-prefixed TLV stream.
+
+use ln::channelmanager::PaymentHash;
+use ln::msgs;
+use ln::msgs::{HandleError, OnionRealm0HopData};
+use ln::onion_utils;
+pub use ln::onion_utils::OnionKeys;
+use ln::router::{Route, RouteHop};
+use util::byte_utils;
+use util::errors::{self, APIError};
+use util::hmac::hmac_sha256;
+use util::ser::{BigSize, Readable, Writeable};
+
+use bitcoin_hashes::cmp::fixed_time_eq;
+
+use secp256k1::key::SecretKey;
+use secp256k1::Secp256k1;
+
+use std::io::Cursor;
+
+/// The data a final node gets in the payment_data TLV record.
+#[derive(Clone, PartialEq, Debug)]
+pub struct FinalHopPaymentData {
+	/// The payment_secret from the invoice, proving the sender knew the invoice
+	pub payment_secret: [u8; 32],
+	/// The total amount of the payment, which may be split across several HTLCs
+	pub total_msat: u64,
+}
+
+/// The format-specific part of the hop data.
+#[derive(Clone, PartialEq, Debug)]
+pub enum HopDataFormat {
+	/// A legacy realm-0 payload, which is always forwarded over short_channel_id unless it is 0
+	Legacy {
+		/// The channel to forward over
+		short_channel_id: u64,
+	},
+	/// A TLV payload for a node which should forward the HTLC
+	NonFinalNode {
+		/// The channel to forward over
+		short_channel_id: u64,
+	},
+	/// A TLV payload for the recipient of the payment
+	FinalNode {
+		/// The payment_data record, if one was included
+		payment_data: Option<FinalHopPaymentData>,
+	},
+}
+
+/// The decoded contents of a per-hop payload.
+#[derive(Clone, PartialEq, Debug)]
+pub struct HopData {
+	/// Which format the payload was in, along with any format-specific fields
+	pub format: HopDataFormat,
+	/// The amount to forward (or, for the final hop, to receive)
+	pub amt_to_forward: u64,
+	/// The CLTV value the outgoing (or, for the final hop, received) HTLC should have
+	pub outgoing_cltv_value: u32,
+}
+
+const AMT_TO_FORWARD_TYPE: u64 = 2;
+const OUTGOING_CLTV_VALUE_TYPE: u64 = 4;
+const SHORT_CHANNEL_ID_TYPE: u64 = 6;
+const PAYMENT_DATA_TYPE: u64 = 8;
+
+macro_rules! invalid_payload {
+	($err: expr) => {
+		return Err(HandleError {
+			err: $err,
+			action: None,
+		})
+	}
+}
+
+/// Writes value as a "truncated" big-endian integer, ie without leading zero bytes.
+fn write_truncated_int(res: &mut Vec<u8>, value: u64) {
+	let bytes = byte_utils::be64_to_array(value);
+	let zeros = bytes.iter().take_while(|b| **b == 0).count();
+	res.extend_from_slice(&bytes[zeros..]);
+}
+
+fn write_record(stream: &mut Vec<u8>, typ: u64, value: &[u8]) {
+	BigSize(typ).write(stream).unwrap();
+	BigSize(value.len() as u64).write(stream).unwrap();
+	stream.extend_from_slice(value);
+}
+
+/// Reads a "truncated" big-endian integer of at most max_len bytes, which must not have leading
+/// zero bytes.
+fn read_truncated_int(value: &[u8], max_len: usize) -> Result<u64, HandleError> {
+	if value.len() > max_len {
+		invalid_payload!("Truncated integer was too long");
+	}
+	if value.len() > 0 && value[0] == 0 {
+		invalid_payload!("Truncated integer was not minimally encoded");
+	}
+	let mut buf = [0; 8];
+	buf[8 - value.len()..].copy_from_slice(value);
+	Ok(byte_utils::slice_to_be64(&buf))
+}
+
+fn read_big_size(r: &mut Cursor<&[u8]>) -> Result<u64, HandleError> {
+	match BigSize::read(r) {
+		Ok(BigSize(v)) => Ok(v),
+		Err(_) => invalid_payload!("Invalid or truncated BigSize in TLV stream"),
+	}
+}
+
+/// Decodes a per-hop payload, telling the legacy and TLV formats apart by the first byte.
+/// Errors carry no action, the HTLC should be failed back with invalid_onion_payload.
+pub fn decode_hop_data(payload: &[u8]) -> Result<HopData, HandleError> {
+	if payload.is_empty() {
+		invalid_payload!("Empty hop payload");
+	}
+
+	if payload[0] == 0 {
+		let data: OnionRealm0HopData = match Readable::read(&mut Cursor::new(&payload[1..])) {
+			Ok(data) => data,
+			Err(_) => invalid_payload!("Legacy hop payload was too short"),
+		};
+		return Ok(HopData {
+			format: HopDataFormat::Legacy { short_channel_id: data.short_channel_id },
+			amt_to_forward: data.amt_to_forward,
+			outgoing_cltv_value: data.outgoing_cltv_value,
+		});
+	}
+
+	let mut r = Cursor::new(payload);
+	let len = read_big_size(&mut r)?;
+	let start = r.position() as usize;
+	if len > (payload.len() - start) as u64 {
+		invalid_payload!("TLV stream was longer than the hop payload");
+	}
+	let stream = &payload[start..start + len as usize];
+
+	let mut amt_to_forward = None;
+	let mut outgoing_cltv_value = None;
+	let mut short_channel_id = None;
+	let mut payment_data = None;
+
+	let mut r = Cursor::new(stream);
+	let mut last_type = None;
+	while (r.position() as usize) < stream.len() {
+		let typ = read_big_size(&mut r)?;
+		if last_type.is_some() && last_type >= Some(typ) {
+			invalid_payload!("TLV records were not in strictly increasing type order");
+		}
+		last_type = Some(typ);
+
+		let len = read_big_size(&mut r)?;
+		let start = r.position() as usize;
+		if len > (stream.len() - start) as u64 {
+			invalid_payload!("TLV record was longer than the TLV stream");
+		}
+		let value = &stream[start..start + len as usize];
+		r.set_position((start + len as usize) as u64);
+
+		match typ {
+			AMT_TO_FORWARD_TYPE => amt_to_forward = Some(read_truncated_int(value, 8)?),
+			OUTGOING_CLTV_VALUE_TYPE => outgoing_cltv_value = Some(read_truncated_int(value, 4)? as u32),
+			SHORT_CHANNEL_ID_TYPE => {
+				if value.len() != 8 {
+					invalid_payload!("short_channel_id TLV record had the wrong length");
+				}
+				short_channel_id = Some(byte_utils::slice_to_be64(value));
+			},
+			PAYMENT_DATA_TYPE => {
+				if value.len() < 32 {
+					invalid_payload!("payment_data TLV record was too short");
+				}
+				let mut payment_secret = [0; 32];
+				payment_secret.copy_from_slice(&value[..32]);
+				payment_data = Some(FinalHopPaymentData {
+					payment_secret,
+					total_msat: read_truncated_int(&value[32..], 8)?,
+				});
+			},
+			_ if typ % 2 == 0 => invalid_payload!("Unknown even TLV record type in hop payload"),
+			_ => {},
+		}
+	}
+
+	let (amt_to_forward, outgoing_cltv_value) = match (amt_to_forward, outgoing_cltv_value) {
+		(Some(amt), Some(cltv)) => (amt, cltv),
+		_ => invalid_payload!("Hop payload was missing amt_to_forward or outgoing_cltv_value"),
+	};
+	let format = match short_channel_id {
+		Some(short_channel_id) => {
+			if payment_data.is_some() {
+				invalid_payload!("payment_data is only allowed in the final hop payload");
+			}
+			HopDataFormat::NonFinalNode { short_channel_id }
+		},
+		None => {
+			if let Some(ref data) = payment_data {
+				if amt_to_forward > data.total_msat {
+					invalid_payload!("Final hop amount was larger than the payment's total_msat");
+				}
+			}
+			HopDataFormat::FinalNode { payment_data }
+		},
+	};
+	Ok(HopData {
+		format,
+		amt_to_forward,
+		outgoing_cltv_value,
+	})
+}
+
+impl HopData {
+	/// Builds the TLV hop data for the recipient of a payment of total_msat, of which this HTLC
+	/// carries amt_to_forward. A payment split over several HTLCs (ie where amt_to_forward is less
+	/// than total_msat) must include the invoice's payment_secret.
+	pub fn final_hop(amt_to_forward: u64, outgoing_cltv_value: u32, payment_secret: Option<[u8; 32]>, total_msat: u64) -> Result<HopData, HandleError> {
+		if amt_to_forward > total_msat {
+			invalid_payload!("Final hop amount was larger than the payment's total_msat");
+		}
+		if amt_to_forward < total_msat && payment_secret.is_none() {
+			invalid_payload!("Multi-part payments must include a payment_secret");
+		}
+		Ok(HopData {
+			format: HopDataFormat::FinalNode {
+				payment_data: payment_secret.map(|payment_secret| FinalHopPaymentData { payment_secret, total_msat }),
+			},
+			amt_to_forward,
+			outgoing_cltv_value,
+		})
+	}
+
+	/// Serializes the hop data into a per-hop payload which decode_hop_data reads back.
+	pub fn encode(&self) -> Vec<u8> {
+		if let HopDataFormat::Legacy { short_channel_id } = self.format {
+			let mut res = vec![0];
+			OnionRealm0HopData {
+				short_channel_id,
+				amt_to_forward: self.amt_to_forward,
+				outgoing_cltv_value: self.outgoing_cltv_value,
+			}.write(&mut res).unwrap();
+			return res;
+		}
+
+		let mut stream = Vec::new();
+		let mut value = Vec::new();
+		write_truncated_int(&mut value, self.amt_to_forward);
+		write_record(&mut stream, AMT_TO_FORWARD_TYPE, &value);
+		value.clear();
+		write_truncated_int(&mut value, self.outgoing_cltv_value as u64);
+		write_record(&mut stream, OUTGOING_CLTV_VALUE_TYPE, &value);
+		match self.format {
+			HopDataFormat::NonFinalNode { short_channel_id } => {
+				write_record(&mut stream, SHORT_CHANNEL_ID_TYPE, &byte_utils::be64_to_array(short_channel_id));
+			},
+			HopDataFormat::FinalNode { payment_data: Some(ref data) } => {
+				value.clear();
+				value.extend_from_slice(&data.payment_secret);
+				write_truncated_int(&mut value, data.total_msat);
+				write_record(&mut stream, PAYMENT_DATA_TYPE, &value);
+			},
+			_ => {},
+		}
+
+		let mut res = Vec::with_capacity(stream.len() + 3);
+		BigSize(stream.len() as u64).write(&mut res).unwrap();
+		res.extend_from_slice(&stream);
+		res
+	}
+}
+
+/// Set in failure codes for errors where the onion itself was unreadable.
+pub const BADONION: u16 = 0x8000;
+/// Set in failure codes for permanent failures, which retrying won't fix.
+pub const PERM: u16 = 0x4000;
+/// Set in failure codes for failures of the erring node, rather than one of its channels.
+pub const NODE: u16 = 0x2000;
+/// Set in failure codes whose failure data includes a channel_update.
+pub const UPDATE: u16 = 0x1000;
+
+/// The BOLT #4 invalid_realm failure code
+pub const INVALID_REALM: u16 = PERM | 1;
+/// The BOLT #4 temporary_node_failure failure code
+pub const TEMPORARY_NODE_FAILURE: u16 = NODE | 2;
+/// The BOLT #4 permanent_node_failure failure code
+pub const PERMANENT_NODE_FAILURE: u16 = PERM | NODE | 2;
+/// The BOLT #4 required_node_feature_missing failure code
+pub const REQUIRED_NODE_FEATURE_MISSING: u16 = PERM | NODE | 3;
+/// The BOLT #4 invalid_onion_version failure code
+pub const INVALID_ONION_VERSION: u16 = BADONION | PERM | 4;
+/// The BOLT #4 invalid_onion_hmac failure code
+pub const INVALID_ONION_HMAC: u16 = BADONION | PERM | 5;
+/// The BOLT #4 invalid_onion_key failure code
+pub const INVALID_ONION_KEY: u16 = BADONION | PERM | 6;
+/// The BOLT #4 temporary_channel_failure failure code
+pub const TEMPORARY_CHANNEL_FAILURE: u16 = UPDATE | 7;
+/// The BOLT #4 permanent_channel_failure failure code
+pub const PERMANENT_CHANNEL_FAILURE: u16 = PERM | 8;
+/// The BOLT #4 required_channel_feature_missing failure code
+pub const REQUIRED_CHANNEL_FEATURE_MISSING: u16 = PERM | 9;
+/// The BOLT #4 unknown_next_peer failure code
+pub const UNKNOWN_NEXT_PEER: u16 = PERM | 10;
+/// The BOLT #4 amount_below_minimum failure code
+pub const AMOUNT_BELOW_MINIMUM: u16 = UPDATE | 11;
+/// The BOLT #4 fee_insufficient failure code
+pub const FEE_INSUFFICIENT: u16 = UPDATE | 12;
+/// The BOLT #4 incorrect_cltv_expiry failure code
+pub const INCORRECT_CLTV_EXPIRY: u16 = UPDATE | 13;
+/// The BOLT #4 expiry_too_soon failure code
+pub const EXPIRY_TOO_SOON: u16 = UPDATE | 14;
+/// The BOLT #4 incorrect_or_unknown_payment_details failure code
+pub const INCORRECT_OR_UNKNOWN_PAYMENT_DETAILS: u16 = PERM | 15;
+/// The BOLT #4 incorrect_payment_amount failure code
+pub const INCORRECT_PAYMENT_AMOUNT: u16 = PERM | 16;
+/// The BOLT #4 final_expiry_too_soon failure code
+pub const FINAL_EXPIRY_TOO_SOON: u16 = 17;
+/// The BOLT #4 final_incorrect_cltv_expiry failure code
+pub const FINAL_INCORRECT_CLTV_EXPIRY: u16 = 18;
+/// The BOLT #4 final_incorrect_htlc_amount failure code
+pub const FINAL_INCORRECT_HTLC_AMOUNT: u16 = 19;
+/// The BOLT #4 channel_disabled failure code
+pub const CHANNEL_DISABLED: u16 = UPDATE | 20;
+/// The BOLT #4 expiry_too_far failure code
+pub const EXPIRY_TOO_FAR: u16 = 21;
+
+/// Whether the failure code indicates the onion we sent couldn't be read
+pub fn is_bad_onion_failure(failure_code: u16) -> bool {
+	failure_code & BADONION == BADONION
+}
+
+/// Whether the failure code indicates a permanent failure
+pub fn is_permanent_failure(failure_code: u16) -> bool {
+	failure_code & PERM == PERM
+}
+
+/// Whether the failure code indicates a failure of the erring node rather than a channel
+pub fn is_node_failure(failure_code: u16) -> bool {
+	failure_code & NODE == NODE
+}
+
+/// Whether the failure data for the failure code includes a channel_update
+pub fn failure_includes_channel_update(failure_code: u16) -> bool {
+	failure_code & UPDATE == UPDATE
+}
+
+/// The length of a serialized onion packet: a version byte, the ephemeral public key, 20 65-byte
+/// hop payloads and the HMAC.
+pub const ONION_PACKET_LEN: usize = 1 + 33 + 20 * 65 + 32;
+
+/// Builds the onion packet for sending payment_hash along route (as returned by get_route, so
+/// that the last hop's fee_msat is the amount the payee receives), encrypted with session_priv.
+/// CLTV values are offset from starting_htlc_offset, which should be the next block height.
+pub fn onion_for_route(route: &[RouteHop], payment_hash: [u8; 32], session_priv: SecretKey, starting_htlc_offset: u32) -> Result<[u8; ONION_PACKET_LEN], HandleError> {
+	// Routes too long for the onion are left to construct_onion_packet's hop data size check
+	if route.len() < 1 {
+		invalid_payload!("Route didn't go anywhere");
+	}
+	let route = Route { hops: route.to_vec() };
+	let secp_ctx = Secp256k1::signing_only();
+	let onion_keys = match onion_utils::construct_onion_keys(&secp_ctx, &route, &session_priv) {
+		Ok(keys) => keys,
+		Err(_) => invalid_payload!("Pubkey along hop was maliciously selected"),
+	};
+	let payloads = match onion_utils::build_onion_payloads(&route, starting_htlc_offset) {
+		Ok((payloads, _, _)) => payloads,
+		Err(e) => return Err(onion_build_error(e)),
+	};
+	let packet = match onion_utils::construct_onion_packet(payloads, onion_keys, &PaymentHash(payment_hash)) {
+		Ok(packet) => packet,
+		Err(e) => return Err(onion_build_error(e)),
+	};
+
+	let mut res = [0; ONION_PACKET_LEN];
+	res.copy_from_slice(&packet.encode());
+	Ok(res)
+}
+
+/// Converts an error from building the onion for a route into a HandleError, keeping its message
+/// where it has a static one.
+fn onion_build_error(e: APIError) -> HandleError {
+	let err = match e {
+		APIError::RouteError { err } | APIError::APIMisuseError { err } | APIError::ChannelUnavailable { err } => err,
+		APIError::FeeRateTooHigh { .. } | APIError::MonitorUpdateFailed => "Failed to build the onion for the route",
+	};
+	HandleError { err, action: None }
+}
+
+/// Builds the failure packet a hop returns when failing an HTLC, encrypted with that hop's shared
+/// secret. UPDATE failure codes must, and others must not, include the hop's latest
+/// channel_update, which is appended to failure_data with a length prefix.
+pub fn build_failure_packet(shared_secret: &[u8; 32], failure_code: u16, failure_data: &[u8], channel_update: Option<&msgs::ChannelUpdate>) -> Result<Vec<u8>, HandleError> {
+	let mut data = failure_data.to_vec();
+	match (failure_includes_channel_update(failure_code), channel_update) {
+		(true, Some(update)) => data.extend_from_slice(&update.encode_with_len()),
+		(true, None) => invalid_payload!("UPDATE failure codes must include a channel_update"),
+		(false, Some(_)) => invalid_payload!("Only UPDATE failure codes may include a channel_update"),
+		(false, None) => {},
+	}
+	if data.len() > 256 - 2 {
+		invalid_payload!("Failure data didn't fit in the failure message");
+	}
+	Ok(onion_utils::build_first_hop_failure_packet(shared_secret, failure_code, &data).data)
+}
+
+/// Gets the channel_update attached to the failure data (ie the failure message after the
+/// failure code) of an UPDATE failure, if it is present and parses.
+pub fn channel_update_from_failure(failure_code: u16, failure_data: &[u8]) -> Option<msgs::ChannelUpdate> {
+	if !failure_includes_channel_update(failure_code) {
+		return None;
+	}
+	let (_, debug_field_size) = errors::get_onion_debug_field(failure_code);
+	if let Some(update_len_slice) = failure_data.get(debug_field_size..debug_field_size + 2) {
+		let update_len = byte_utils::slice_to_be16(&update_len_slice) as usize;
+		if let Some(update_slice) = failure_data.get(debug_field_size + 2..debug_field_size + 2 + update_len) {
+			return msgs::ChannelUpdate::read(&mut Cursor::new(&update_slice)).ok();
+		}
+	}
+	None
+}
+
+/// Decrypts a failure packet returned for an onion we sent, given the shared secrets of each hop
+/// in route order, by peeling one layer per hop until a hop's HMAC verifies.
+/// Returns the index of the erring hop, the failure code and the rest of the failure message.
+pub fn process_onion_failure(shared_secrets: &[[u8; 32]], encrypted_packet: &[u8]) -> Result<(usize, u16, Vec<u8>), HandleError> {
+	let mut packet = encrypted_packet.to_vec();
+	for (idx, shared_secret) in shared_secrets.iter().enumerate() {
+		packet = onion_utils::encrypt_failure_packet(shared_secret, &packet).data;
+
+		if let Ok(err_packet) = msgs::DecodedOnionErrorPacket::read(&mut Cursor::new(&packet)) {
+			let um = onion_utils::gen_um_from_shared_secret(shared_secret);
+			if fixed_time_eq(&hmac_sha256(&um, &err_packet.encode()[32..]), &err_packet.hmac) {
+				if err_packet.failuremsg.len() < 2 {
+					invalid_payload!("Onion failure message was too short to contain a failure code");
+				}
+				let failure_code = byte_utils::slice_to_be16(&err_packet.failuremsg[0..2]);
+				return Ok((idx, failure_code, err_packet.failuremsg[2..].to_vec()));
+			}
+		}
+	}
+	invalid_payload!("Unparseable onion failure: no hop's HMAC matched");
+}
+
+/// Gets the per-hop keys for an onion through the given node pubkeys, in route order, for when
+/// there's no Route. The same keys let the sender decrypt failure packets from each hop.
+///
+/// Errors if blinding the ephemeral key for some hop fails, which only happens if a hop's pubkey
+/// was picked to make the blinded key or the blinding factor invalid. Such an onion can't be
+/// built, so callers should treat this like an unusable route.
+pub fn construct_onion_keys_from_pubkeys<T: ::secp256k1::Signing>(secp_ctx: &Secp256k1<T>, hops: &[::secp256k1::key::PublicKey], session_priv: &SecretKey) -> Result<Vec<OnionKeys>, ::secp256k1::Error> {
+	onion_utils::build_onion_keys(secp_ctx, hops.iter(), hops.len(), session_priv)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use hex;
+
+	#[test]
+	fn legacy_intermediate_hop() {
+		let payload = hex::decode("000102030405060708000000000000271000000090000000000000000000000000").unwrap();
+		assert_eq!(decode_hop_data(&payload).unwrap(), HopData {
+			format: HopDataFormat::Legacy { short_channel_id: 0x0102030405060708 },
+			amt_to_forward: 10000,
+			outgoing_cltv_value: 144,
+		});
+
+		assert!(decode_hop_data(&payload[..20]).is_err());
+	}
+
+	#[test]
+	fn tlv_intermediate_hop() {
+		// amt_to_forward 10000, outgoing_cltv_value 144, short_channel_id 0x0102030405060708
+		let payload = hex::decode("11020227100401900608010203040506070800").unwrap();
+		assert_eq!(decode_hop_data(&payload).unwrap(), HopData {
+			format: HopDataFormat::NonFinalNode { short_channel_id: 0x0102030405060708 },
+			amt_to_forward: 10000,
+			outgoing_cltv_value: 144,
+		});
+	}
+
+	#[test]
+	fn tlv_final_hop_with_payment_secret() {
+		// amt_to_forward 10000, outgoing_cltv_value 144, payment_data with total_msat 20000,
+		// then an unknown odd record which is ignored
+		let payload = hex::decode("2e02022710040190082242424242424242424242424242424242424242424242424242424242424242424e200b0100").unwrap();
+		assert_eq!(decode_hop_data(&payload).unwrap(), HopData {
+			format: HopDataFormat::FinalNode {
+				payment_data: Some(FinalHopPaymentData {
+					payment_secret: [0x42; 32],
+					total_msat: 20000,
+				}),
+			},
+			amt_to_forward: 10000,
+			outgoing_cltv_value: 144,
+		});
+	}
+
+	#[test]
+	fn single_part_final_hop() {
+		let hop = HopData::final_hop(10000, 144, None, 10000).unwrap();
+		assert_eq!(hop.format, HopDataFormat::FinalNode { payment_data: None });
+		assert_eq!(hex::encode(hop.encode()), "0702022710040190");
+		assert_eq!(decode_hop_data(&hop.encode()).unwrap(), hop);
+
+		// A single-part payment may still carry a payment_secret
+		let hop = HopData::final_hop(10000, 144, Some([0x42; 32]), 10000).unwrap();
+		assert_eq!(decode_hop_data(&hop.encode()).unwrap(), hop);
+	}
+
+	#[test]
+	fn mpp_final_hop() {
+		let hop = HopData::final_hop(10000, 144, Some([0x42; 32]), 20000).unwrap();
+		let encoded = hop.encode();
+		// Matches tlv_final_hop_with_payment_secret, without its unknown odd record
+		assert_eq!(hex::encode(&encoded), "2b02022710040190082242424242424242424242424242424242424242424242424242424242424242424e20");
+		assert_eq!(decode_hop_data(&encoded).unwrap(), hop);
+
+		assert_eq!(HopData::final_hop(10000, 144, None, 20000).unwrap_err().err, "Multi-part payments must include a payment_secret");
+		assert_eq!(HopData::final_hop(20001, 144, Some([0x42; 32]), 20000).unwrap_err().err, "Final hop amount was larger than the payment's total_msat");
+
+		// A received part larger than the total is rejected
+		let payload = hex::decode("2b02024e21040190082242424242424242424242424242424242424242424242424242424242424242424e20").unwrap();
+		assert_eq!(decode_hop_data(&payload).unwrap_err().err, "Final hop amount was larger than the payment's total_msat");
+	}
+
+	#[test]
+	fn encode_round_trips() {
+		for hop in [
+			HopData { format: HopDataFormat::Legacy { short_channel_id: 0x0102030405060708 }, amt_to_forward: 10000, outgoing_cltv_value: 144 },
+			HopData { format: HopDataFormat::NonFinalNode { short_channel_id: 0x0102030405060708 }, amt_to_forward: 10000, outgoing_cltv_value: 144 },
+			HopData { format: HopDataFormat::NonFinalNode { short_channel_id: 1 }, amt_to_forward: 0, outgoing_cltv_value: 0 },
+			HopData { format: HopDataFormat::FinalNode { payment_data: None }, amt_to_forward: ::std::u64::MAX, outgoing_cltv_value: ::std::u32::MAX },
+		].iter() {
+			assert_eq!(decode_hop_data(&hop.encode()).unwrap(), *hop);
+		}
+		assert_eq!(hex::encode(HopData { format: HopDataFormat::NonFinalNode { short_channel_id: 0x0102030405060708 }, amt_to_forward: 10000, outgoing_cltv_value: 144 }.encode()),
+			"110202271004019006080102030405060708");
+	}
+
+	#[test]
+	fn three_hop_onion_peels() {
+		use ln::msgs::OnionHopData;
+		use util::chacha20::ChaCha20;
+		use util::hmac::hmac_sha256;
+
+		use bitcoin_hashes::{Hash, HashEngine};
+		use bitcoin_hashes::sha256::Hash as Sha256;
+		use secp256k1::ecdh::SharedSecret;
+		use secp256k1::key::PublicKey;
+
+		let secp_ctx = Secp256k1::new();
+		let node_secrets: Vec<SecretKey> = (1..4).map(|i| SecretKey::from_slice(&[i; 32]).unwrap()).collect();
+		let route: Vec<RouteHop> = node_secrets.iter().enumerate().map(|(i, secret)| RouteHop {
+			pubkey: PublicKey::from_secret_key(&secp_ctx, secret),
+			short_channel_id: 42 + i as u64,
+			fee_msat: if i == 2 { 1000000 } else { 100 + i as u64 },
+			cltv_expiry_delta: if i == 2 { 9 } else { 40 },
+		}).collect();
+		let payment_hash = [0x42; 32];
+		let packet = onion_for_route(&route, payment_hash, SecretKey::from_slice(&[0x41; 32]).unwrap(), 1000).unwrap();
+		assert_eq!(packet[0], 0);
+
+		// (short_channel_id, amt_to_forward, outgoing_cltv_value) each hop should find
+		let expected = [(43, 1000101, 1049), (44, 1000000, 1009), (0, 1000000, 1009)];
+
+		let mut ephemeral = PublicKey::from_slice(&packet[1..34]).unwrap();
+		let mut hop_data = packet[34..34 + 20 * 65].to_vec();
+		let mut hmac = packet[34 + 20 * 65..].to_vec();
+		for (secret, &(short_channel_id, amt_to_forward, outgoing_cltv_value)) in node_secrets.iter().zip(expected.iter()) {
+			let shared_secret = SharedSecret::new(&ephemeral, secret);
+			let (rho, mu) = onion_utils::gen_rho_mu_from_shared_secret(&shared_secret[..]);
+			let mut hmac_data = hop_data.clone();
+			hmac_data.extend_from_slice(&payment_hash);
+			assert_eq!(hmac_sha256(&mu, &hmac_data)[..], hmac[..]);
+
+			let mut padded = hop_data.clone();
+			padded.extend_from_slice(&[0; 65]);
+			let mut decrypted = vec![0; padded.len()];
+			ChaCha20::new(&rho, &[0; 8]).process(&padded, &mut decrypted);
+			let payload: OnionHopData = Readable::read(&mut Cursor::new(&decrypted[..65])).unwrap();
+			assert_eq!(payload.data.short_channel_id, short_channel_id);
+			assert_eq!(payload.data.amt_to_forward, amt_to_forward);
+			assert_eq!(payload.data.outgoing_cltv_value, outgoing_cltv_value);
+
+			hop_data = decrypted[65..].to_vec();
+			hmac = payload.hmac.to_vec();
+			let mut sha = Sha256::engine();
+			sha.input(&ephemeral.serialize()[..]);
+			sha.input(&shared_secret[..]);
+			ephemeral.mul_assign(&secp_ctx, &Sha256::from_engine(sha).into_inner()[..]).unwrap();
+		}
+		// The final hop is told there is no next hop
+		assert_eq!(hmac, vec![0; 32]);
+
+		assert!(onion_for_route(&[], payment_hash, SecretKey::from_slice(&[0x41; 32]).unwrap(), 1000).is_err());
+
+		// Routes onion_utils can't build payloads for are an error too
+		let mut bogus_route = route.clone();
+		bogus_route[0].fee_msat = 21_000_000 * 100_000_000 * 1000;
+		assert_eq!(onion_for_route(&bogus_route, payment_hash, SecretKey::from_slice(&[0x41; 32]).unwrap(), 1000).unwrap_err().err, "Channel fees overflowed?!");
+		let mut long_route = Vec::new();
+		for _ in 0..7 {
+			long_route.extend_from_slice(&route[..]);
+		}
+		assert_eq!(onion_for_route(&long_route[..20], payment_hash, SecretKey::from_slice(&[0x41; 32]).unwrap(), 1000).unwrap().len(), ONION_PACKET_LEN);
+		assert_eq!(onion_for_route(&long_route[..21], payment_hash, SecretKey::from_slice(&[0x41; 32]).unwrap(), 1000).unwrap_err().err, "Onion payloads didn't fit in the 1300-byte hop data");
+	}
+
+	#[test]
+	fn invalid_tlv_hops() {
+		// Missing outgoing_cltv_value
+		assert!(decode_hop_data(&hex::decode("0402022710").unwrap()).is_err());
+		// Records out of order
+		assert!(decode_hop_data(&hex::decode("0704019002022710").unwrap()).is_err());
+		// Unknown even type
+		assert!(decode_hop_data(&hex::decode("09020227100401900a00").unwrap()).is_err());
+		// Non-minimal amt_to_forward
+		assert!(decode_hop_data(&hex::decode("080203002710040190").unwrap()).is_err());
+		// Length runs past the end of the payload
+		assert!(decode_hop_data(&hex::decode("2002022710040190").unwrap()).is_err());
+	}
+
+	#[test]
+	fn onion_failure_from_second_hop() {
+		let shared_secrets = [[1; 32], [2; 32], [3; 32]];
+		// Each hop wraps the packet with its own layer on the way back to us
+		let wrap_from = |erring_hop: usize, failure_code: u16, data: &[u8]| {
+			let failure = onion_utils::build_failure_packet(&shared_secrets[erring_hop], failure_code, data);
+			let mut packet = failure.encode();
+			for shared_secret in shared_secrets[..erring_hop + 1].iter().rev() {
+				packet = onion_utils::encrypt_failure_packet(shared_secret, &packet).data;
+			}
+			packet
+		};
+
+		let packet = wrap_from(1, 0x1000 | 7, &[0x42; 10]);
+		assert_eq!(process_onion_failure(&shared_secrets, &packet).unwrap(), (1, 0x1000 | 7, vec![0x42; 10]));
+		assert_eq!(process_onion_failure(&shared_secrets, &wrap_from(0, 0x4000 | 10, &[])).unwrap(), (0, 0x4000 | 10, Vec::new()));
+		assert_eq!(process_onion_failure(&shared_secrets, &wrap_from(2, 0x4000 | 15, &[1, 2])).unwrap(), (2, 0x4000 | 15, vec![1, 2]));
+
+		// Without the erring hop's secret, or once the packet has been corrupted, no HMAC matches
+		assert_eq!(process_onion_failure(&shared_secrets[..1], &packet).unwrap_err().err, "Unparseable onion failure: no hop's HMAC matched");
+		let mut corrupted = packet.clone();
+		corrupted[40] ^= 1;
+		assert_eq!(process_onion_failure(&shared_secrets, &corrupted).unwrap_err().err, "Unparseable onion failure: no hop's HMAC matched");
+	}
+
+	#[test]
+	fn failure_code_flags() {
+		// temporary_channel_failure, as found at the start of a decrypted failuremsg
+		let failure_code = byte_utils::slice_to_be16(&hex::decode("1007").unwrap());
+		assert_eq!(failure_code, TEMPORARY_CHANNEL_FAILURE);
+		assert!(failure_includes_channel_update(failure_code));
+		assert!(!is_permanent_failure(failure_code));
+		assert!(!is_node_failure(failure_code));
+		assert!(!is_bad_onion_failure(failure_code));
+
+		let failure_code = byte_utils::slice_to_be16(&hex::decode("400a").unwrap());
+		assert_eq!(failure_code, UNKNOWN_NEXT_PEER);
+		assert!(is_permanent_failure(failure_code));
+		assert!(!failure_includes_channel_update(failure_code));
+
+		let failure_code = byte_utils::slice_to_be16(&hex::decode("400f").unwrap());
+		assert_eq!(failure_code, INCORRECT_OR_UNKNOWN_PAYMENT_DETAILS);
+		assert!(is_permanent_failure(failure_code));
+		assert!(!is_node_failure(failure_code));
+
+		let failure_code = byte_utils::slice_to_be16(&hex::decode("6002").unwrap());
+		assert_eq!(failure_code, PERMANENT_NODE_FAILURE);
+		assert!(is_permanent_failure(failure_code) && is_node_failure(failure_code));
+
+		let failure_code = byte_utils::slice_to_be16(&hex::decode("c005").unwrap());
+		assert_eq!(failure_code, INVALID_ONION_HMAC);
+		assert!(is_bad_onion_failure(failure_code) && is_permanent_failure(failure_code));
+		assert!(!failure_includes_channel_update(failure_code));
+
+		assert!(failure_includes_channel_update(CHANNEL_DISABLED));
+		assert!(!is_permanent_failure(FINAL_EXPIRY_TOO_SOON));
+	}
+
+	#[test]
+	fn failure_with_channel_update() {
+		use secp256k1::Message;
+
+		let secp_ctx = Secp256k1::new();
+		let msghash = Message::from_slice(&[1; 32]).unwrap();
+		let channel_update = msgs::ChannelUpdate {
+			signature: secp_ctx.sign(&msghash, &SecretKey::from_slice(&[2; 32]).unwrap()),
+			contents: msgs::UnsignedChannelUpdate {
+				chain_hash: Default::default(),
+				short_channel_id: 42,
+				timestamp: 1_600_000_000,
+				flags: 0,
+				cltv_expiry_delta: 144,
+				htlc_minimum_msat: 1000,
+				fee_base_msat: 1000,
+				fee_proportional_millionths: 1,
+				excess_data: Vec::new(),
+			},
+		};
+		let shared_secrets = [[1; 32], [2; 32], [3; 32]];
+
+		// A temporary_channel_failure from the second hop, wrapped again by the first
+		let packet = build_failure_packet(&shared_secrets[1], TEMPORARY_CHANNEL_FAILURE, &[], Some(&channel_update)).unwrap();
+		let packet = onion_utils::encrypt_failure_packet(&shared_secrets[0], &packet).data;
+		let (erring_hop, failure_code, failure_data) = process_onion_failure(&shared_secrets, &packet).unwrap();
+		assert_eq!((erring_hop, failure_code), (1, TEMPORARY_CHANNEL_FAILURE));
+		assert_eq!(failure_data, channel_update.encode_with_len());
+		assert_eq!(channel_update_from_failure(failure_code, &failure_data), Some(channel_update.clone()));
+
+		// The update follows any debug field, here amount_below_minimum's htlc_msat
+		let packet = build_failure_packet(&shared_secrets[0], AMOUNT_BELOW_MINIMUM, &byte_utils::be64_to_array(500), Some(&channel_update)).unwrap();
+		let (_, failure_code, failure_data) = process_onion_failure(&shared_secrets, &packet).unwrap();
+		assert_eq!(&failure_data[..8], &byte_utils::be64_to_array(500));
+		assert_eq!(channel_update_from_failure(failure_code, &failure_data), Some(channel_update.clone()));
+		assert_eq!(channel_update_from_failure(failure_code, &failure_data[..failure_data.len() - 1]), None);
+
+		// Only UPDATE failures carry, and they always carry, a channel_update
+		assert!(build_failure_packet(&shared_secrets[0], TEMPORARY_CHANNEL_FAILURE, &[], None).is_err());
+		assert!(build_failure_packet(&shared_secrets[0], UNKNOWN_NEXT_PEER, &[], Some(&channel_update)).is_err());
+		let packet = build_failure_packet(&shared_secrets[0], UNKNOWN_NEXT_PEER, &[], None).unwrap();
+		let (_, failure_code, failure_data) = process_onion_failure(&shared_secrets, &packet).unwrap();
+		assert_eq!(failure_code, UNKNOWN_NEXT_PEER);
+		assert_eq!(channel_update_from_failure(failure_code, &failure_data), None);
+	}
+
+	#[test]
+	fn onion_keys_from_pubkeys_vectors() {
+		// Key generation vectors from BOLT 4: the first and last hops' keys
+		use secp256k1::key::PublicKey;
+
+		let secp_ctx = Secp256k1::new();
+		let hops: Vec<PublicKey> = [
+			"02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619",
+			"0324653eac434488002cc06bbfb7f10fe18991e35f9fe4302dbea6d2353dc0ab1c",
+			"027f31ebc5462c1fdce1b737ecff52d37d75dea43ce11c74d25aa297165faa2007",
+			"032c0b7cf95324a07d05398b240174dc0c2be444d96b159aa6c7f7b1e668680991",
+			"02edabbd16b41c8371b92ef2f04c1185b4f03b6dcd52ba9b78d9d7c89c8f221145",
+		].iter().map(|hex_key| PublicKey::from_slice(&hex::decode(hex_key).unwrap()[..]).unwrap()).collect();
+		let session_priv = SecretKey::from_slice(&[0x41; 32]).unwrap();
+
+		let onion_keys = construct_onion_keys_from_pubkeys(&secp_ctx, &hops, &session_priv).unwrap();
+		assert_eq!(onion_keys.len(), 5);
+
+		assert_eq!(onion_keys[0].shared_secret[..], hex::decode("53eb63ea8a3fec3b3cd433b85cd62a4b145e1dda09391b348c4e1cd36a03ea66").unwrap()[..]);
+		assert_eq!(onion_keys[0].blinding_factor[..], hex::decode("2ec2e5da605776054187180343287683aa6a51b4b1c04d6dd49c45d8cffb3c36").unwrap()[..]);
+		assert_eq!(onion_keys[0].ephemeral_pubkey.serialize()[..], hex::decode("02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619").unwrap()[..]);
+		assert_eq!(onion_keys[0].rho[..], hex::decode("ce496ec94def95aadd4bec15cdb41a740c9f2b62347c4917325fcc6fb0453986").unwrap()[..]);
+		assert_eq!(onion_keys[0].mu[..], hex::decode("b57061dc6d0a2b9f261ac410c8b26d64ac5506cbba30267a649c28c179400eba").unwrap()[..]);
+
+		assert_eq!(onion_keys[4].shared_secret[..], hex::decode("b5756b9b542727dbafc6765a49488b023a725d631af688fc031217e90770c328").unwrap()[..]);
+		assert_eq!(onion_keys[4].blinding_factor[..], hex::decode("c96e00dddaf57e7edcd4fb5954be5b65b09f17cb6d20651b4e90315be5779205").unwrap()[..]);
+		assert_eq!(onion_keys[4].ephemeral_pubkey.serialize()[..], hex::decode("03a214ebd875aab6ddfd77f22c5e7311d7f77f17a169e599f157bbcdae8bf071f4").unwrap()[..]);
+		assert_eq!(onion_keys[4].rho[..], hex::decode("034e18b8cc718e8af6339106e706c52d8df89e2b1f7e9142d996acf88df8799b").unwrap()[..]);
+		assert_eq!(onion_keys[4].mu[..], hex::decode("8e45e5c61c2b24cb6382444db6698727afb063adecd72aada233d4bf273d975a").unwrap()[..]);
+
+		// An empty route has no keys rather than failing
+		assert!(construct_onion_keys_from_pubkeys(&secp_ctx, &[], &session_priv).unwrap().is_empty());
+	}
+}
@@ -1,8 +1,10 @@
 use ln::channelmanager::{PaymentHash, HTLCSource};
 use ln::msgs;
+use ln::onion::{self, PERM, NODE, UPDATE};
 use ln::router::{Route,RouteHop};
 use util::byte_utils;
 use util::chacha20::ChaCha20;
+use util::hmac::hmac_sha256;
 use util::errors::{self, APIError};
 use util::ser::{Readable, Writeable};
 use util::logger::{Logger, LogHolder};
@@ -20,55 +22,47 @@ use secp256k1;
 use std::io::Cursor;
 use std::sync::Arc;
 
-pub(super) struct OnionKeys {
-	#[cfg(test)]
-	pub(super) shared_secret: SharedSecret,
-	#[cfg(test)]
-	pub(super) blinding_factor: [u8; 32],
-	pub(super) ephemeral_pubkey: PublicKey,
-	pub(super) rho: [u8; 32],
-	pub(super) mu: [u8; 32],
+/// The keys for one hop of an onion, as derived in BOLT #4.
+pub struct OnionKeys {
+	/// The ECDH secret shared between the sender's ephemeral key and the hop's node key
+	pub shared_secret: SharedSecret,
+	/// The factor the ephemeral key is blinded with before being passed on to the next hop
+	pub blinding_factor: [u8; 32],
+	/// The (blinded) ephemeral public key the hop sees
+	pub ephemeral_pubkey: PublicKey,
+	/// The key used to generate the hop's stream cipher
+	pub rho: [u8; 32],
+	/// The key used to HMAC the hop's payload
+	pub mu: [u8; 32],
 }
 
 #[inline]
 pub(super) fn gen_rho_mu_from_shared_secret(shared_secret: &[u8]) -> ([u8; 32], [u8; 32]) {
 	assert_eq!(shared_secret.len(), 32);
-	({
-		let mut hmac = HmacEngine::<Sha256>::new(&[0x72, 0x68, 0x6f]); // rho
-		hmac.input(&shared_secret[..]);
-		Hmac::from_engine(hmac).into_inner()
-	},
-	{
-		let mut hmac = HmacEngine::<Sha256>::new(&[0x6d, 0x75]); // mu
-		hmac.input(&shared_secret[..]);
-		Hmac::from_engine(hmac).into_inner()
-	})
+	(hmac_sha256(&[0x72, 0x68, 0x6f], &shared_secret[..]), // rho
+	 hmac_sha256(&[0x6d, 0x75], &shared_secret[..])) // mu
 }
 
 #[inline]
 pub(super) fn gen_um_from_shared_secret(shared_secret: &[u8]) -> [u8; 32] {
 	assert_eq!(shared_secret.len(), 32);
-	let mut hmac = HmacEngine::<Sha256>::new(&[0x75, 0x6d]); // um
-	hmac.input(&shared_secret[..]);
-	Hmac::from_engine(hmac).into_inner()
+	hmac_sha256(&[0x75, 0x6d], &shared_secret[..]) // um
 }
 
 #[inline]
 pub(super) fn gen_ammag_from_shared_secret(shared_secret: &[u8]) -> [u8; 32] {
 	assert_eq!(shared_secret.len(), 32);
-	let mut hmac = HmacEngine::<Sha256>::new(&[0x61, 0x6d, 0x6d, 0x61, 0x67]); // ammag
-	hmac.input(&shared_secret[..]);
-	Hmac::from_engine(hmac).into_inner()
+	hmac_sha256(&[0x61, 0x6d, 0x6d, 0x61, 0x67], &shared_secret[..]) // ammag
 }
 
 // can only fail if an intermediary hop has an invalid public key or session_priv is invalid
 #[inline]
-pub(super) fn construct_onion_keys_callback<T: secp256k1::Signing, FType: FnMut(SharedSecret, [u8; 32], PublicKey, &RouteHop)> (secp_ctx: &Secp256k1<T>, route: &Route, session_priv: &SecretKey, mut callback: FType) -> Result<(), secp256k1::Error> {
+fn construct_onion_keys_for_pubkeys_callback<'a, T: secp256k1::Signing, I: Iterator<Item=&'a PublicKey>, FType: FnMut(SharedSecret, [u8; 32], PublicKey, usize)> (secp_ctx: &Secp256k1<T>, hops: I, session_priv: &SecretKey, mut callback: FType) -> Result<(), secp256k1::Error> {
 	let mut blinded_priv = session_priv.clone();
 	let mut blinded_pub = PublicKey::from_secret_key(secp_ctx, &blinded_priv);
 
-	for hop in route.hops.iter() {
-		let shared_secret = SharedSecret::new(&hop.pubkey, &blinded_priv);
+	for (idx, hop_pubkey) in hops.enumerate() {
+		let shared_secret = SharedSecret::new(hop_pubkey, &blinded_priv);
 
 		let mut sha = Sha256::engine();
 		sha.input(&blinded_pub.serialize()[..]);
@@ -80,24 +74,29 @@ pub(super) fn construct_onion_keys_callback<T: secp256k1::Signing, FType: FnMut(
 		blinded_priv.mul_assign(&blinding_factor)?;
 		blinded_pub = PublicKey::from_secret_key(secp_ctx, &blinded_priv);
 
-		callback(shared_secret, blinding_factor, ephemeral_pubkey, hop);
+		callback(shared_secret, blinding_factor, ephemeral_pubkey, idx);
 	}
 
 	Ok(())
 }
 
 // can only fail if an intermediary hop has an invalid public key or session_priv is invalid
-pub(super) fn construct_onion_keys<T: secp256k1::Signing>(secp_ctx: &Secp256k1<T>, route: &Route, session_priv: &SecretKey) -> Result<Vec<OnionKeys>, secp256k1::Error> {
-	let mut res = Vec::with_capacity(route.hops.len());
+#[inline]
+pub(super) fn construct_onion_keys_callback<T: secp256k1::Signing, FType: FnMut(SharedSecret, [u8; 32], PublicKey, &RouteHop)> (secp_ctx: &Secp256k1<T>, route: &Route, session_priv: &SecretKey, mut callback: FType) -> Result<(), secp256k1::Error> {
+	construct_onion_keys_for_pubkeys_callback(secp_ctx, route.hops.iter().map(|hop| &hop.pubkey), session_priv, |shared_secret, blinding_factor, ephemeral_pubkey, idx| {
+		callback(shared_secret, blinding_factor, ephemeral_pubkey, &route.hops[idx]);
+	})
+}
+
+pub(super) fn build_onion_keys<'a, T: secp256k1::Signing, I: Iterator<Item=&'a PublicKey>>(secp_ctx: &Secp256k1<T>, hops: I, hop_count: usize, session_priv: &SecretKey) -> Result<Vec<OnionKeys>, secp256k1::Error> {
+	let mut res = Vec::with_capacity(hop_count);
 
-	construct_onion_keys_callback(secp_ctx, route, session_priv, |shared_secret, _blinding_factor, ephemeral_pubkey, _| {
+	construct_onion_keys_for_pubkeys_callback(secp_ctx, hops, session_priv, |shared_secret, blinding_factor, ephemeral_pubkey, _| {
 		let (rho, mu) = gen_rho_mu_from_shared_secret(&shared_secret[..]);
 
 		res.push(OnionKeys {
-			#[cfg(test)]
 			shared_secret,
-			#[cfg(test)]
-			blinding_factor: _blinding_factor,
+			blinding_factor,
 			ephemeral_pubkey,
 			rho,
 			mu,
@@ -107,6 +106,31 @@ pub(super) fn construct_onion_keys<T: secp256k1::Signing>(secp_ctx: &Secp256k1<T
 	Ok(res)
 }
 
+// can only fail if an intermediary hop has an invalid public key or session_priv is invalid
+pub(super) fn construct_onion_keys<T: secp256k1::Signing>(secp_ctx: &Secp256k1<T>, route: &Route, session_priv: &SecretKey) -> Result<Vec<OnionKeys>, secp256k1::Error> {
+	build_onion_keys(secp_ctx, route.hops.iter().map(|hop| &hop.pubkey), route.hops.len(), session_priv)
+}
+
+/// Derives an onion session key with HKDF-SHA256, using session_seed as the salt and the
+/// payment_hash as the input keying material, so that retrying the same payment attempt produces
+/// the same onion. Takes the first output block which is a valid secret key.
+pub(super) fn derive_session_key(session_seed: &[u8; 32], payment_hash: &PaymentHash) -> SecretKey {
+	const INFO: &[u8] = b"onion session key";
+	let prk = hmac_sha256(session_seed, &payment_hash.0);
+	let mut block: Vec<u8> = Vec::new();
+	let mut counter = 1u8;
+	loop {
+		block.extend_from_slice(INFO);
+		block.push(counter);
+		let okm = hmac_sha256(&prk, &block);
+		if let Ok(key) = SecretKey::from_slice(&okm) {
+			return key;
+		}
+		block = okm.to_vec();
+		counter += 1;
+	}
+}
+
 /// returns the hop data, as well as the first-hop value_msat and CLTV value we should send.
 pub(super) fn build_onion_payloads(route: &Route, starting_htlc_offset: u32) -> Result<(Vec<msgs::OnionHopData>, u64, u32), APIError> {
 	let mut cur_value_msat = 0u64;
@@ -161,8 +185,17 @@ fn xor_bufs(dst: &mut[u8], src: &[u8]) {
 	}
 }
 
-const ZERO:[u8; 21*65] = [0; 21*65];
-pub(super) fn construct_onion_packet(mut payloads: Vec<msgs::OnionHopData>, onion_keys: Vec<OnionKeys>, associated_data: &PaymentHash) -> msgs::OnionPacket {
+/// Builds the onion packet, failing if payloads is empty or the payloads don't fit in the
+/// 1300-byte hop data (ie there are more than 20 hops).
+pub(super) fn construct_onion_packet(mut payloads: Vec<msgs::OnionHopData>, onion_keys: Vec<OnionKeys>, associated_data: &PaymentHash) -> Result<msgs::OnionPacket, APIError> {
+	if payloads.is_empty() || payloads.len() != onion_keys.len() {
+		return Err(APIError::RouteError{err: "Onion payloads didn't match the route's hops"});
+	}
+	let hop_data_len = payloads.iter().fold(0, |len, payload| len + payload.encode().len());
+	if hop_data_len > 20*65 {
+		return Err(APIError::RouteError{err: "Onion payloads didn't fit in the 1300-byte hop data"});
+	}
+
 	let mut buf = Vec::with_capacity(21*65);
 	buf.resize(21*65, 0);
 
@@ -174,9 +207,8 @@ pub(super) fn construct_onion_packet(mut payloads: Vec<msgs::OnionHopData>, onio
 
 		for (i, keys) in onion_keys.iter().enumerate() {
 			if i == payloads.len() - 1 { continue; }
-			let mut chacha = ChaCha20::new(&keys.rho, &[0u8; 8]);
-			chacha.process(&ZERO, &mut buf); // We don't have a seek function :(
-			xor_bufs(&mut res[0..(i + 1)*65], &buf[(20 - i)*65..21*65]);
+			let keystream = ChaCha20::new(&keys.rho, &[0u8; 8]).get_keystream(21*65); // We don't have a seek function :(
+			xor_bufs(&mut res[0..(i + 1)*65], &keystream[(20 - i)*65..21*65]);
 		}
 		res
 	};
@@ -203,12 +235,12 @@ pub(super) fn construct_onion_packet(mut payloads: Vec<msgs::OnionHopData>, onio
 		hmac_res = Hmac::from_engine(hmac).into_inner();
 	}
 
-	msgs::OnionPacket{
+	Ok(msgs::OnionPacket{
 		version: 0,
 		public_key: Ok(onion_keys.first().unwrap().ephemeral_pubkey),
 		hop_data: packet_data,
 		hmac: hmac_res,
-	}
+	})
 }
 
 /// Encrypts a failure packet. raw_packet can either be a
@@ -249,9 +281,7 @@ pub(super) fn build_failure_packet(shared_secret: &[u8], failure_type: u16, fail
 		pad: pad,
 	};
 
-	let mut hmac = HmacEngine::<Sha256>::new(&um);
-	hmac.input(&packet.encode()[32..]);
-	packet.hmac = Hmac::from_engine(hmac).into_inner();
+	packet.hmac = hmac_sha256(&um, &packet.encode()[32..]);
 
 	packet
 }
@@ -264,8 +294,9 @@ pub(super) fn build_first_hop_failure_packet(shared_secret: &[u8], failure_type:
 
 /// Process failure we got back from upstream on a payment we sent (implying htlc_source is an
 /// OutboundRoute).
-/// Returns update, a boolean indicating that the payment itself failed, and the error code.
-pub(super) fn process_onion_failure<T: secp256k1::Signing>(secp_ctx: &Secp256k1<T>, logger: &Arc<Logger>, htlc_source: &HTLCSource, mut packet_decrypted: Vec<u8>) -> (Option<msgs::HTLCFailChannelUpdate>, bool, Option<u16>) {
+/// Returns update, a boolean indicating that the payment itself failed, the error code and the
+/// short_channel_id of the channel the failure is attributed to, if any.
+pub(super) fn process_onion_failure<T: secp256k1::Signing>(secp_ctx: &Secp256k1<T>, logger: &Arc<Logger>, htlc_source: &HTLCSource, mut packet_decrypted: Vec<u8>) -> (Option<msgs::HTLCFailChannelUpdate>, bool, Option<u16>, Option<u64>) {
 	if let &HTLCSource::OutboundRoute { ref route, ref session_priv, ref first_hop_htlc_msat } = htlc_source {
 		let mut res = None;
 		let mut htlc_msat = *first_hop_htlc_msat;
@@ -293,15 +324,8 @@ pub(super) fn process_onion_failure<T: secp256k1::Signing>(secp_ctx: &Secp256k1<
 
 			if let Ok(err_packet) = msgs::DecodedOnionErrorPacket::read(&mut Cursor::new(&packet_decrypted)) {
 				let um = gen_um_from_shared_secret(&shared_secret[..]);
-				let mut hmac = HmacEngine::<Sha256>::new(&um);
-				hmac.input(&err_packet.encode()[32..]);
-
-				if fixed_time_eq(&Hmac::from_engine(hmac).into_inner(), &err_packet.hmac) {
+				if fixed_time_eq(&hmac_sha256(&um, &err_packet.encode()[32..]), &err_packet.hmac) {
 					if let Some(error_code_slice) = err_packet.failuremsg.get(0..2) {
-						const PERM: u16 = 0x4000;
-						const NODE: u16 = 0x2000;
-						const UPDATE: u16 = 0x1000;
-
 						let error_code = byte_utils::slice_to_be16(&error_code_slice);
 						error_code_ret = Some(error_code);
 
@@ -315,6 +339,12 @@ pub(super) fn process_onion_failure<T: secp256k1::Signing>(secp_ctx: &Secp256k1<
 						} && is_from_final_node) // PERM bit observed below even this error is from the intermediate nodes
 						|| error_code == 21; // Special case error 21 as the Route object is bogus, TODO: Maybe fail the node if the CLTV was reasonable?
 
+						// Blame the channel the erring node should have forwarded over, or the one
+						// into the final node if it failed for reasons other than the payment itself
+						let failed_short_channel_id = if payment_failed { None }
+							else if is_from_final_node { Some(route_hop.short_channel_id) }
+							else { Some(route.hops[next_route_hop_ix].short_channel_id) };
+
 						let mut fail_channel_update = None;
 
 						if error_code & NODE == NODE {
@@ -327,38 +357,33 @@ pub(super) fn process_onion_failure<T: secp256k1::Signing>(secp_ctx: &Secp256k1<
 							})};
 						}
 						else if error_code & UPDATE == UPDATE {
-							if let Some(update_len_slice) = err_packet.failuremsg.get(debug_field_size+2..debug_field_size+4) {
-								let update_len = byte_utils::slice_to_be16(&update_len_slice) as usize;
-								if let Some(update_slice) = err_packet.failuremsg.get(debug_field_size + 4..debug_field_size + 4 + update_len) {
-									if let Ok(chan_update) = msgs::ChannelUpdate::read(&mut Cursor::new(&update_slice)) {
-										// if channel_update should NOT have caused the failure:
-										// MAY treat the channel_update as invalid.
-										let is_chan_update_invalid = match error_code & 0xff {
-											7 => false,
-											11 => amt_to_forward > chan_update.contents.htlc_minimum_msat,
-											12 => {
-												let new_fee = amt_to_forward.checked_mul(chan_update.contents.fee_proportional_millionths as u64).and_then(|prop_fee| { (prop_fee / 1000000).checked_add(chan_update.contents.fee_base_msat as u64) });
-												new_fee.is_some() && route_hop.fee_msat >= new_fee.unwrap()
-											}
-											13 => route_hop.cltv_expiry_delta as u16 >= chan_update.contents.cltv_expiry_delta,
-											14 => false, // expiry_too_soon; always valid?
-											20 => chan_update.contents.flags & 2 == 0,
-											_ => false, // unknown error code; take channel_update as valid
-										};
-										fail_channel_update = if is_chan_update_invalid {
-											// This probably indicates the node which forwarded
-											// to the node in question corrupted something.
-											Some(msgs::HTLCFailChannelUpdate::ChannelClosed {
-												short_channel_id: route_hop.short_channel_id,
-												is_permanent: true,
-											})
-										} else {
-											Some(msgs::HTLCFailChannelUpdate::ChannelUpdateMessage {
-												msg: chan_update,
-											})
-										};
+							if let Some(chan_update) = onion::channel_update_from_failure(error_code, &err_packet.failuremsg[2..]) {
+								// if channel_update should NOT have caused the failure:
+								// MAY treat the channel_update as invalid.
+								let is_chan_update_invalid = match error_code & 0xff {
+									7 => false,
+									11 => amt_to_forward > chan_update.contents.htlc_minimum_msat,
+									12 => {
+										let new_fee = amt_to_forward.checked_mul(chan_update.contents.fee_proportional_millionths as u64).and_then(|prop_fee| { (prop_fee / 1000000).checked_add(chan_update.contents.fee_base_msat as u64) });
+										new_fee.is_some() && route_hop.fee_msat >= new_fee.unwrap()
 									}
-								}
+									13 => route_hop.cltv_expiry_delta as u16 >= chan_update.contents.cltv_expiry_delta,
+									14 => false, // expiry_too_soon; always valid?
+									20 => chan_update.contents.flags & 2 == 0,
+									_ => false, // unknown error code; take channel_update as valid
+								};
+								fail_channel_update = if is_chan_update_invalid {
+									// This probably indicates the node which forwarded
+									// to the node in question corrupted something.
+									Some(msgs::HTLCFailChannelUpdate::ChannelClosed {
+										short_channel_id: route_hop.short_channel_id,
+										is_permanent: true,
+									})
+								} else {
+									Some(msgs::HTLCFailChannelUpdate::ChannelUpdateMessage {
+										msg: chan_update,
+									})
+								};
 							}
 							if fail_channel_update.is_none() {
 								// They provided an UPDATE which was obviously bogus, not worth
@@ -381,7 +406,7 @@ pub(super) fn process_onion_failure<T: secp256k1::Signing>(secp_ctx: &Secp256k1<
 						// TODO: Here (and a few other places) we assume that BADONION errors
 						// are always "sourced" from the node previous to the one which failed
 						// to decode the onion.
-						res = Some((fail_channel_update, !(error_code & PERM == PERM && is_from_final_node)));
+						res = Some((fail_channel_update, !(error_code & PERM == PERM && is_from_final_node), failed_short_channel_id));
 
 						let (description, title) = errors::get_onion_error_description(error_code);
 						if debug_field_size > 0 && err_packet.failuremsg.len() >= 4 + debug_field_size {
@@ -398,17 +423,17 @@ pub(super) fn process_onion_failure<T: secp256k1::Signing>(secp_ctx: &Secp256k1<
 						res = Some((Some(msgs::HTLCFailChannelUpdate::NodeFailure {
 							node_id: route_hop.pubkey,
 							is_permanent: true,
-						}), !is_from_final_node));
+						}), !is_from_final_node, Some(if is_from_final_node { route_hop.short_channel_id } else { route.hops[next_route_hop_ix].short_channel_id })));
 					}
 				}
 			}
 		}).expect("Route that we sent via spontaneously grew invalid keys in the middle of it?");
-		if let Some((channel_update, payment_retryable)) = res {
-			(channel_update, payment_retryable, error_code_ret)
+		if let Some((channel_update, payment_retryable, failed_short_channel_id)) = res {
+			(channel_update, payment_retryable, error_code_ret, failed_short_channel_id)
 		} else {
 			// only not set either packet unparseable or hmac does not match with any
 			// payment not retryable only when garbage is from the final node
-			(None, !is_from_final_node, None)
+			(None, !is_from_final_node, None, None)
 		}
 	} else { unreachable!(); }
 }
@@ -418,6 +443,7 @@ mod tests {
 	use ln::channelmanager::PaymentHash;
 	use ln::router::{Route, RouteHop};
 	use ln::msgs;
+	use util::errors::APIError;
 	use util::ser::Writeable;
 
 	use hex;
@@ -460,7 +486,19 @@ mod tests {
 
 		let onion_keys = super::construct_onion_keys(&secp_ctx, &route, &session_priv).unwrap();
 		assert_eq!(onion_keys.len(), route.hops.len());
-		onion_keys
+
+		// Building from bare pubkeys must give the same keys as building from the Route
+		let pubkeys: Vec<PublicKey> = route.hops.iter().map(|hop| hop.pubkey).collect();
+		let pubkey_onion_keys = ::ln::onion::construct_onion_keys_from_pubkeys(&secp_ctx, &pubkeys, &session_priv).unwrap();
+		assert_eq!(pubkey_onion_keys.len(), onion_keys.len());
+		for (a, b) in onion_keys.iter().zip(pubkey_onion_keys.iter()) {
+			assert_eq!(a.shared_secret[..], b.shared_secret[..]);
+			assert_eq!(a.blinding_factor, b.blinding_factor);
+			assert_eq!(a.ephemeral_pubkey, b.ephemeral_pubkey);
+			assert_eq!(a.rho, b.rho);
+			assert_eq!(a.mu, b.mu);
+		}
+		pubkey_onion_keys
 	}
 
 	#[test]
@@ -547,7 +585,7 @@ mod tests {
 			},
 		);
 
-		let packet = super::construct_onion_packet(payloads, onion_keys, &PaymentHash([0x42; 32]));
+		let packet = super::construct_onion_packet(payloads, onion_keys, &PaymentHash([0x42; 32])).unwrap();
 		// Just check the final packet encoding, as it includes all the per-hop vectors in it
 		// anyway...
 		assert_eq!(packet.encode(), hex::decode("0002eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619e5f14350c2a76fc232b5e46d421e9615471ab9e0bc887beff8c95fdb878f7b3a716a996c7845c93d90e4ecbb9bde4ece2f69425c99e4bc820e44485455f135edc0d10f7d61ab590531cf08000179a333a347f8b4072f216400406bdf3bf038659793d4a1fd7b246979e3150a0a4cb052c9ec69acf0f48c3d39cd55675fe717cb7d80ce721caad69320c3a469a202f1e468c67eaf7a7cd8226d0fd32f7b48084dca885d56047694762b67021713ca673929c163ec36e04e40ca8e1c6d17569419d3039d9a1ec866abe044a9ad635778b961fc0776dc832b3a451bd5d35072d2269cf9b040f6b7a7dad84fb114ed413b1426cb96ceaf83825665ed5a1d002c1687f92465b49ed4c7f0218ff8c6c7dd7221d589c65b3b9aaa71a41484b122846c7c7b57e02e679ea8469b70e14fe4f70fee4d87b910cf144be6fe48eef24da475c0b0bcc6565ae82cd3f4e3b24c76eaa5616c6111343306ab35c1fe5ca4a77c0e314ed7dba39d6f1e0de791719c241a939cc493bea2bae1c1e932679ea94d29084278513c77b899cc98059d06a27d171b0dbdf6bee13ddc4fc17a0c4d2827d488436b57baa167544138ca2e64a11b43ac8a06cd0c2fba2d4d900ed2d9205305e2d7383cc98dacb078133de5f6fb6bed2ef26ba92cea28aafc3b9948dd9ae5559e8bd6920b8cea462aa445ca6a95e0e7ba52961b181c79e73bd581821df2b10173727a810c92b83b5ba4a0403eb710d2ca10689a35bec6c3a708e9e92f7d78ff3c5d9989574b00c6736f84c199256e76e19e78f0c98a9d580b4a658c84fc8f2096c2fbea8f5f8c59d0fdacb3be2802ef802abbecb3aba4acaac69a0e965abd8981e9896b1f6ef9d60f7a164b371af869fd0e48073742825e9434fc54da837e120266d53302954843538ea7c6c3dbfb4ff3b2fdbe244437f2a153ccf7bdb4c92aa08102d4f3cff2ae5ef86fab4653595e6a5837fa2f3e29f27a9cde5966843fb847a4a61f1e76c281fe8bb2b0a181d096100db5a1a5ce7a910238251a43ca556712eaadea167fb4d7d75825e440f3ecd782036d7574df8bceacb397abefc5f5254d2722215c53ff54af8299aaaad642c6d72a14d27882d9bbd539e1cc7a527526ba89b8c037ad09120e98ab042d3e8652b31ae0e478516bfaf88efca9f3676ffe99d2819dcaeb7610a626695f53117665d267d3f7abebd6bbd6733f645c72c389f03855bdf1e4b8075b516569b118233a0f0971d24b83113c0b096f5216a207ca99a7cddc81c130923fe3d91e7508c9ac5f2e914ff5dccab9e558566fa14efb34ac98d878580814b94b73acbfde9072f30b881f7f0fff42d4045d1ace6322d86a97d164aa84d93a60498065cc7c20e636f5862dc81531a88c60305a2e59a985be327a6902e4bed986dbf4a0b50c217af0ea7fdf9ab37f9ea1a1aaa72f54cf40154ea9b269f1a7c09f9f43245109431a175d50e2db0132337baa0ef97eed0fcf20489da36b79a1172faccc2f7ded7c60e00694282d93359c4682135642bc81f433574aa8ef0c97b4ade7ca372c5ffc23c7eddd839bab4e0f14d6df15c9dbeab176bec8b5701cf054eb3072f6dadc98f88819042bf10c407516ee58bce33fbe3b3d86a54255e577db4598e30a135361528c101683a5fcde7e8ba53f3456254be8f45fe3a56120ae96ea3773631fcb3873aa3abd91bcff00bd38bd43697a2e789e00da6077482e7b1b1a677b5afae4c54e6cbdf7377b694eb7d7a5b913476a5be923322d3de06060fd5e819635232a2cf4f0731da13b8546d1d6d4f8d75b9fce6c2341a71b0ea6f780df54bfdb0dd5cd9855179f602f9172307c7268724c3618e6817abd793adc214a0dc0bc616816632f27ea336fb56dfd").unwrap());
@@ -576,4 +614,65 @@ mod tests {
 		let onion_packet_5 = super::encrypt_failure_packet(&onion_keys[0].shared_secret[..], &onion_packet_4.data[..]);
 		assert_eq!(onion_packet_5.data, hex::decode("9c5add3963fc7f6ed7f148623c84134b5647e1306419dbe2174e523fa9e2fbed3a06a19f899145610741c83ad40b7712aefaddec8c6baf7325d92ea4ca4d1df8bce517f7e54554608bf2bd8071a4f52a7a2f7ffbb1413edad81eeea5785aa9d990f2865dc23b4bc3c301a94eec4eabebca66be5cf638f693ec256aec514620cc28ee4a94bd9565bc4d4962b9d3641d4278fb319ed2b84de5b665f307a2db0f7fbb757366067d88c50f7e829138fde4f78d39b5b5802f1b92a8a820865af5cc79f9f30bc3f461c66af95d13e5e1f0381c184572a91dee1c849048a647a1158cf884064deddbf1b0b88dfe2f791428d0ba0f6fb2f04e14081f69165ae66d9297c118f0907705c9c4954a199bae0bb96fad763d690e7daa6cfda59ba7f2c8d11448b604d12d").unwrap());
 	}
+
+	#[test]
+	fn deterministic_session_key_onion() {
+		let secp_ctx = Secp256k1::new();
+		let route = Route {
+			hops: vec!(
+				RouteHop {
+					pubkey: PublicKey::from_slice(&hex::decode("02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619").unwrap()[..]).unwrap(),
+					short_channel_id: 1, fee_msat: 100, cltv_expiry_delta: 40,
+				},
+				RouteHop {
+					pubkey: PublicKey::from_slice(&hex::decode("0324653eac434488002cc06bbfb7f10fe18991e35f9fe4302dbea6d2353dc0ab1c").unwrap()[..]).unwrap(),
+					short_channel_id: 2, fee_msat: 10000, cltv_expiry_delta: 144,
+				},
+			),
+		};
+		let build_onion = |seed: &[u8; 32], payment_hash: &PaymentHash| {
+			let session_priv = super::derive_session_key(seed, payment_hash);
+			let onion_keys = super::construct_onion_keys(&secp_ctx, &route, &session_priv).unwrap();
+			let (payloads, _, _) = super::build_onion_payloads(&route, 100).unwrap();
+			super::construct_onion_packet(payloads, onion_keys, payment_hash).unwrap().encode()
+		};
+
+		let hash = PaymentHash([0x42; 32]);
+		assert_eq!(super::derive_session_key(&[1; 32], &hash), super::derive_session_key(&[1; 32], &hash));
+		assert_eq!(build_onion(&[1; 32], &hash), build_onion(&[1; 32], &hash));
+		assert!(build_onion(&[1; 32], &hash) != build_onion(&[2; 32], &hash));
+		assert!(build_onion(&[1; 32], &hash) != build_onion(&[1; 32], &PaymentHash([0x43; 32])));
+	}
+
+	#[test]
+	fn onion_hop_data_budget() {
+		let secp_ctx = Secp256k1::new();
+		let session_priv = SecretKey::from_slice(&[0x41; 32]).unwrap();
+		let payment_hash = PaymentHash([0x42; 32]);
+		let build_onion = |hop_count: u8| {
+			let route = Route {
+				hops: (0..hop_count).map(|i| RouteHop {
+					pubkey: PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[i + 1; 32]).unwrap()),
+					short_channel_id: i as u64 + 1, fee_msat: 1000, cltv_expiry_delta: 6,
+				}).collect(),
+			};
+			let onion_keys = super::construct_onion_keys(&secp_ctx, &route, &session_priv).unwrap();
+			let (payloads, _, _) = super::build_onion_payloads(&route, 100).unwrap();
+			super::construct_onion_packet(payloads, onion_keys, &payment_hash)
+		};
+
+		// Twenty 65-byte realm 0 payloads exactly fill the 1300-byte hop data...
+		let packet = build_onion(20).unwrap();
+		assert_eq!(packet.encode().len(), 1 + 33 + 20*65 + 32);
+
+		// ...so a twenty-first doesn't fit, nor does a route with no hops at all
+		match build_onion(21) {
+			Err(APIError::RouteError { err }) => assert_eq!(err, "Onion payloads didn't fit in the 1300-byte hop data"),
+			_ => panic!(),
+		}
+		match build_onion(0) {
+			Err(APIError::RouteError { err }) => assert_eq!(err, "Onion payloads didn't match the route's hops"),
+			_ => panic!(),
+		}
+	}
 }
@@ -1,18 +1,37 @@
 use ln::msgs;
-use ln::msgs::HandleError;
+use ln::msgs::{DecodeError, HandleError};
 
 use bitcoin_hashes::sha256::Hash as Sha256;
 use bitcoin_hashes::{Hash, HashEngine, Hmac, HmacEngine};
 
 use secp256k1;
 use secp256k1::ecdh::SharedSecret;
-use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::key::{PublicKey, SecretKey, ONE_KEY};
 use secp256k1::Secp256k1;
 
+#[cfg(debug_assertions)]
+use std::cell::RefCell;
+#[cfg(debug_assertions)]
+use std::collections::{HashSet, VecDeque};
+use std::io::Read;
 use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::{compiler_fence, Ordering};
 
 use util::byte_utils;
 use util::chacha20poly1305rfc::ChaCha20Poly1305RFC;
+use util::ser::{Readable, Writeable};
+
+/// The length of handshake act one, sent by the initiator
+pub const ACT_ONE_LEN: usize = 50;
+/// The length of handshake act two, sent by the responder
+pub const ACT_TWO_LEN: usize = 50;
+/// The length of handshake act three, sent by the initiator
+pub const ACT_THREE_LEN: usize = 66;
+/// The length of the encrypted length header which precedes each message
+pub const MSG_HEADER_LEN: usize = 2 + MAC_LEN;
+/// The length of the Poly1305 tag appended to each encrypted header and message
+pub const MAC_LEN: usize = 16;
 
 // Sha256("Noise_XK_secp256k1_ChaChaPoly_SHA256")
 const NOISE_CK: [u8; 32] = [
@@ -36,11 +55,45 @@ pub struct OutboundData {
 	their_node_id: PublicKey,
 }
 
+/// Overwrites buf with zeros in a way the compiler won't optimize out, even if buf is never read
+/// again.
+#[allow(unsafe_code)]
+fn zeroize(buf: &mut [u8]) {
+	for byte in buf.iter_mut() {
+		// byte is a valid, aligned &mut u8, so writing through it is sound
+		unsafe { ptr::write_volatile(byte, 0) };
+	}
+	compiler_fence(Ordering::SeqCst);
+}
+
+/// Overwrites key with ONE_KEY (SecretKeys can't hold zero) in a way the compiler won't optimize
+/// out.
+#[allow(unsafe_code)]
+fn zeroize_key(key: &mut SecretKey) {
+	// key is a valid, aligned &mut SecretKey, and ONE_KEY is a valid SecretKey
+	unsafe { ptr::write_volatile(key, ONE_KEY) };
+	compiler_fence(Ordering::SeqCst);
+}
+
+/// Handshake state which can wipe any secrets it holds.
+pub trait ZeroizeSecrets {
+	/// Overwrites all secret material with zeros
+	fn zeroize_secrets(&mut self);
+}
+
 pub trait NoiseStep {
-	type DirectionalNoiseState;
+	type DirectionalNoiseState: ZeroizeSecrets;
 }
 pub struct PreActOne<T: Direction>(pub PhantomData<T>);
 pub struct InboundPreActOne;
+impl ZeroizeSecrets for InboundPreActOne {
+	fn zeroize_secrets(&mut self) {}
+}
+impl ZeroizeSecrets for OutboundData {
+	fn zeroize_secrets(&mut self) {
+		zeroize_key(&mut self.ie);
+	}
+}
 impl NoiseStep for PreActOne<Inbound> {
 	type DirectionalNoiseState = InboundPreActOne;
 }
@@ -51,6 +104,9 @@ pub struct PostActOne<T: Direction>(pub PhantomData<T>);
 pub struct InboundPostActOne {
 	ie: PublicKey,
 }
+impl ZeroizeSecrets for InboundPostActOne {
+	fn zeroize_secrets(&mut self) {}
+}
 impl NoiseStep for PostActOne<Inbound> {
 	type DirectionalNoiseState = InboundPostActOne;
 }
@@ -63,6 +119,12 @@ pub struct InboundPostActTwo {
 	re: SecretKey,
 	temp_k2: [u8; 32],
 }
+impl ZeroizeSecrets for InboundPostActTwo {
+	fn zeroize_secrets(&mut self) {
+		zeroize_key(&mut self.re);
+		zeroize(&mut self.temp_k2);
+	}
+}
 impl NoiseStep for PostActTwo<Inbound> {
 	type DirectionalNoiseState = InboundPostActTwo;
 }
@@ -75,6 +137,9 @@ pub struct InProgress<T: NoiseStep> {
 	state: PhantomData<T>,
 	directional_state: T::DirectionalNoiseState,
 	bidirectional_state: BidirectionalNoiseState,
+	/// Caller-supplied time of the last handshake progress, see set_last_activity. None until
+	/// the caller first sets it.
+	last_activity: Option<u32>,
 }
 impl<T> NoiseState for InProgress<T> where T: NoiseStep {}
 pub struct Finished {
@@ -84,13 +149,44 @@ pub struct Finished {
 	rk: [u8; 32],
 	rn: u64,
 	rck: [u8; 32],
+	/// The length from the last decrypted header, checked against the next message body
+	pending_length: Option<u16>,
+	/// Number of times this state has been serialized, see PeerChannelEncryptor::serialize
+	generation: u64,
 }
 impl NoiseState for Finished {}
+impl Finished {
+	fn read_fields<R: Read>(&mut self, reader: &mut R) -> Result<(), DecodeError> {
+		self.sk = Readable::read(reader)?;
+		self.sn = Readable::read(reader)?;
+		self.sck = Readable::read(reader)?;
+		self.rk = Readable::read(reader)?;
+		self.rn = Readable::read(reader)?;
+		self.rck = Readable::read(reader)?;
+		self.pending_length = Readable::read(reader)?;
+		self.generation = Readable::read(reader)?;
+		Ok(())
+	}
+}
+impl ZeroizeSecrets for Finished {
+	fn zeroize_secrets(&mut self) {
+		zeroize(&mut self.sk);
+		zeroize(&mut self.sck);
+		zeroize(&mut self.rk);
+		zeroize(&mut self.rck);
+	}
+}
 
 pub struct BidirectionalNoiseState {
 	h: [u8; 32],
 	ck: [u8; 32],
 }
+impl ZeroizeSecrets for BidirectionalNoiseState {
+	fn zeroize_secrets(&mut self) {
+		zeroize(&mut self.h);
+		zeroize(&mut self.ck);
+	}
+}
 
 pub struct PeerChannelEncryptor<T: NoiseState> {
 	secp_ctx: Secp256k1<secp256k1::SignOnly>,
@@ -115,9 +211,22 @@ impl PeerChannelEncryptor<InProgress<PreActOne<Outbound>>> {
 					their_node_id,
 				},
 				bidirectional_state: BidirectionalNoiseState { h: h, ck: NOISE_CK },
+				last_activity: None,
 			},
 		}
 	}
+
+	/// Starts an outbound handshake, returning the encryptor along with the act one bytes to send.
+	/// Equivalent to new_outbound followed by get_act_one.
+	pub fn initiate_outbound(
+		their_node_id: PublicKey,
+		ephemeral_key: SecretKey,
+	) -> (
+		PeerChannelEncryptor<InProgress<PostActOne<Outbound>>>,
+		[u8; ACT_ONE_LEN],
+	) {
+		Self::new_outbound(their_node_id, ephemeral_key).get_act_one()
+	}
 }
 
 impl PeerChannelEncryptor<InProgress<PreActOne<Inbound>>> {
@@ -136,22 +245,72 @@ impl PeerChannelEncryptor<InProgress<PreActOne<Inbound>>> {
 				state: PhantomData,
 				directional_state: InboundPreActOne,
 				bidirectional_state: BidirectionalNoiseState { h: h, ck: NOISE_CK },
+				last_activity: None,
 			},
 		}
 	}
 }
 
+/// A failure to authenticate a handshake act or message, ie its MAC didn't verify.
+struct BadMac;
+
+impl From<BadMac> for HandleError {
+	fn from(_: BadMac) -> Self {
+		HandleError {
+			err: "Bad MAC",
+			action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }),
+		}
+	}
+}
+
+/// Why an inbound handshake act was rejected, so that callers can tell a failed MAC check apart
+/// from a malformed act.
+enum ActError {
+	/// The act was malformed
+	Invalid(HandleError),
+	/// The act was well-formed but its MAC didn't verify
+	BadMac,
+}
+
+impl From<BadMac> for ActError {
+	fn from(_: BadMac) -> Self {
+		ActError::BadMac
+	}
+}
+
+impl From<ActError> for HandleError {
+	fn from(e: ActError) -> Self {
+		match e {
+			ActError::Invalid(e) => e,
+			ActError::BadMac => BadMac.into(),
+		}
+	}
+}
+
 impl<T> PeerChannelEncryptor<T>
 where
 	T: NoiseState,
 {
+	/// Rejects handshake acts which aren't exactly the expected length, so that a peer flooding
+	/// us with junk before the handshake completes is disconnected rather than buffered.
+	#[inline]
+	fn check_act_length(act: &[u8], expected_len: usize) -> Result<(), HandleError> {
+		if act.len() != expected_len {
+			return Err(HandleError {
+				err: "Handshake act was not of the expected length",
+				action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }),
+			});
+		}
+		Ok(())
+	}
+
 	#[inline]
 	fn encrypt_with_ad(res: &mut [u8], n: u64, key: &[u8; 32], h: &[u8], plaintext: &[u8]) {
 		let mut nonce = [0; 12];
 		nonce[4..].copy_from_slice(&byte_utils::le64_to_array(n));
 
 		let mut chacha = ChaCha20Poly1305RFC::new(key, &nonce, h);
-		let mut tag = [0; 16];
+		let mut tag = [0; MAC_LEN];
 		chacha.encrypt(plaintext, &mut res[0..plaintext.len()], &mut tag);
 		res[plaintext.len()..].copy_from_slice(&tag);
 	}
@@ -163,20 +322,17 @@ where
 		key: &[u8; 32],
 		h: &[u8],
 		cyphertext: &[u8],
-	) -> Result<(), HandleError> {
+	) -> Result<(), BadMac> {
 		let mut nonce = [0; 12];
 		nonce[4..].copy_from_slice(&byte_utils::le64_to_array(n));
 
 		let mut chacha = ChaCha20Poly1305RFC::new(key, &nonce, h);
 		if !chacha.decrypt(
-			&cyphertext[0..cyphertext.len() - 16],
+			&cyphertext[0..cyphertext.len() - MAC_LEN],
 			res,
-			&cyphertext[cyphertext.len() - 16..],
+			&cyphertext[cyphertext.len() - MAC_LEN..],
 		) {
-			return Err(HandleError {
-				err: "Bad MAC",
-				action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }),
-			});
+			return Err(BadMac);
 		}
 		Ok(())
 	}
@@ -207,7 +363,7 @@ where
 		state: &mut BidirectionalNoiseState,
 		our_key: &SecretKey,
 		their_key: &PublicKey,
-	) -> ([u8; 50], [u8; 32]) {
+	) -> ([u8; ACT_ONE_LEN], [u8; 32]) {
 		let our_pub = PublicKey::from_secret_key(secp_ctx, &our_key);
 
 		let mut sha = Sha256::engine();
@@ -218,7 +374,7 @@ where
 		let ss = SharedSecret::new(&their_key, &our_key);
 		let temp_k = Self::hkdf(state, ss);
 
-		let mut res = [0; 50];
+		let mut res = [0; ACT_ONE_LEN];
 		res[1..34].copy_from_slice(&our_pub.serialize()[..]);
 		Self::encrypt_with_ad(&mut res[34..], 0, &temp_k, &state.h, &[0; 0]);
 
@@ -235,22 +391,22 @@ where
 		state: &mut BidirectionalNoiseState,
 		act: &[u8],
 		our_key: &SecretKey,
-	) -> Result<(PublicKey, [u8; 32]), HandleError> {
-		assert_eq!(act.len(), 50);
+	) -> Result<(PublicKey, [u8; 32]), ActError> {
+		assert_eq!(act.len(), ACT_ONE_LEN);
 
 		if act[0] != 0 {
-			return Err(HandleError {
+			return Err(ActError::Invalid(HandleError {
 				err: "Unknown handshake version number",
 				action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }),
-			});
+			}));
 		}
 
 		let their_pub = match PublicKey::from_slice(&act[1..34]) {
 			Err(_) => {
-				return Err(HandleError {
+				return Err(ActError::Invalid(HandleError {
 					err: "Invalid public key",
 					action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }),
-				})
+				}))
 			}
 			Ok(key) => key,
 		};
@@ -275,12 +431,49 @@ where
 	}
 }
 
+impl<T: NoiseStep> PeerChannelEncryptor<InProgress<T>> {
+	/// Records the time (in seconds, from any clock the caller likes) at which the handshake last
+	/// made progress. As we have no timers, the caller should set this when the connection is
+	/// created and after each act so that stalled handshakes can be found with
+	/// is_handshake_stale. The time is carried over into the next handshake state.
+	pub fn set_last_activity(&mut self, now: u32) {
+		self.noise_state.last_activity = Some(now);
+	}
+
+	/// Returns true if at least timeout_secs have passed since the last set_last_activity call,
+	/// in which case the caller should drop the connection. A handshake whose activity time was
+	/// never set is never considered stale.
+	pub fn is_handshake_stale(&self, now: u32, timeout_secs: u32) -> bool {
+		match self.noise_state.last_activity {
+			Some(last_activity) => now.saturating_sub(last_activity) >= timeout_secs,
+			None => false,
+		}
+	}
+
+	/// Gives up on the handshake, wiping the ephemeral keys and partial chaining key right away
+	/// instead of leaving them in memory until they happen to be overwritten.
+	pub fn abort(mut self) {
+		// Wipe in place: handing self on by value could leave an unwiped copy behind
+		self.zeroize_handshake_state();
+	}
+
+	#[cfg(test)]
+	fn handshake_secrets(&self) -> ([u8; 32], [u8; 32]) {
+		(self.noise_state.bidirectional_state.h, self.noise_state.bidirectional_state.ck)
+	}
+
+	fn zeroize_handshake_state(&mut self) {
+		self.noise_state.directional_state.zeroize_secrets();
+		self.noise_state.bidirectional_state.zeroize_secrets();
+	}
+}
+
 impl PeerChannelEncryptor<InProgress<PreActOne<Outbound>>> {
 	pub fn get_act_one(
 		mut self,
 	) -> (
 		PeerChannelEncryptor<InProgress<PostActOne<Outbound>>>,
-		[u8; 50],
+		[u8; ACT_ONE_LEN],
 	) {
 		let (res, _) = Self::outbound_noise_act(
 			&self.secp_ctx,
@@ -295,6 +488,7 @@ impl PeerChannelEncryptor<InProgress<PreActOne<Outbound>>> {
 					state: PhantomData,
 					bidirectional_state: self.noise_state.bidirectional_state,
 					directional_state: self.noise_state.directional_state,
+					last_activity: self.noise_state.last_activity,
 				},
 			},
 			res,
@@ -303,7 +497,7 @@ impl PeerChannelEncryptor<InProgress<PreActOne<Outbound>>> {
 }
 
 impl PeerChannelEncryptor<InProgress<PreActOne<Inbound>>> {
-	/// panics if act_one != 50 bytes
+	/// Errors with a disconnect if act_one != ACT_ONE_LEN bytes
 	pub fn process_act_one_with_keys(
 		self,
 		act_one: &[u8], // TODO: Use sized slices
@@ -312,11 +506,11 @@ impl PeerChannelEncryptor<InProgress<PreActOne<Inbound>>> {
 	) -> Result<
 		(
 			PeerChannelEncryptor<InProgress<PostActTwo<Inbound>>>,
-			[u8; 50],
+			[u8; ACT_TWO_LEN],
 		),
 		HandleError,
 	> {
-		assert!(act_one.len() == 50);
+		Self::check_act_length(act_one, ACT_ONE_LEN)?;
 		let (their_pub, _) = Self::inbound_noise_act(
 			&mut self.noise_state.bidirectional_state,
 			act_one,
@@ -343,6 +537,7 @@ impl PeerChannelEncryptor<InProgress<PreActOne<Inbound>>> {
 					state: PhantomData,
 					bidirectional_state: self.noise_state.bidirectional_state,
 					directional_state: data,
+					last_activity: self.noise_state.last_activity,
 				},
 			},
 			res,
@@ -351,24 +546,34 @@ impl PeerChannelEncryptor<InProgress<PreActOne<Inbound>>> {
 }
 
 impl PeerChannelEncryptor<InProgress<PostActOne<Outbound>>> {
-	/// panics if act_two != 50 bytes
+	/// Errors with a disconnect if act_two != ACT_TWO_LEN bytes or if the peer doesn't hold the key for
+	/// the node_id we dialed.
 	pub fn process_act_two(
 		self,
 		act_two: &[u8], // TODO: Use sized slices
 		our_node_secret: &SecretKey,
-	) -> Result<(PeerChannelEncryptor<Finished>, [u8; 66], PublicKey), HandleError> {
-		assert!(act_two.len() == 50);
+	) -> Result<(PeerChannelEncryptor<Finished>, [u8; ACT_THREE_LEN], PublicKey), HandleError> {
+		Self::check_act_length(act_two, ACT_TWO_LEN)?;
+		// The dialed node_id was mixed into the chaining key in act one, so only a peer holding its
+		// key can produce a valid act two MAC.
 		let (re, temp_k2) = Self::inbound_noise_act(
 			&mut self.noise_state.bidirectional_state,
 			act_two,
 			&self.noise_state.directional_state.ie,
-		)?;
+		)
+		.map_err(|e| match e {
+			ActError::BadMac => HandleError {
+				err: "Peer's static key did not match the node_id we dialed",
+				action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }),
+			},
+			ActError::Invalid(e) => e,
+		})?;
 
-		let mut res = [0; 66];
+		let mut res = [0; ACT_THREE_LEN];
 		let our_node_id = PublicKey::from_secret_key(&self.secp_ctx, &our_node_secret);
 
 		Self::encrypt_with_ad(
-			&mut res[1..50],
+			&mut res[1..ACT_THREE_LEN - MAC_LEN],
 			1,
 			&temp_k2,
 			&self.noise_state.bidirectional_state.h,
@@ -377,14 +582,14 @@ impl PeerChannelEncryptor<InProgress<PostActOne<Outbound>>> {
 
 		let mut sha = Sha256::engine();
 		sha.input(&self.noise_state.bidirectional_state.h);
-		sha.input(&res[1..50]);
+		sha.input(&res[1..ACT_THREE_LEN - MAC_LEN]);
 		self.noise_state.bidirectional_state.h = Sha256::from_engine(sha).into_inner();
 
 		let ss = SharedSecret::new(&re, our_node_secret);
 		let temp_k = Self::hkdf(&mut self.noise_state.bidirectional_state, ss);
 
 		Self::encrypt_with_ad(
-			&mut res[50..],
+			&mut res[ACT_THREE_LEN - MAC_LEN..],
 			0,
 			&temp_k,
 			&self.noise_state.bidirectional_state.h,
@@ -402,6 +607,8 @@ impl PeerChannelEncryptor<InProgress<PostActOne<Outbound>>> {
 			rk: rk,
 			rn: 0,
 			rck: ck,
+			pending_length: None,
+			generation: 0,
 		};
 
 		Ok((
@@ -416,12 +623,12 @@ impl PeerChannelEncryptor<InProgress<PostActOne<Outbound>>> {
 }
 
 impl PeerChannelEncryptor<InProgress<PostActTwo<Inbound>>> {
-	/// panics if act_three != 66 bytes
+	/// Errors with a disconnect if act_three != ACT_THREE_LEN bytes
 	pub fn process_act_three(
 		self,
 		act_three: &[u8], // TODO: Use sized slices
 	) -> Result<(PeerChannelEncryptor<Finished>, PublicKey), HandleError> {
-		assert!(act_three.len() == 66);
+		Self::check_act_length(act_three, ACT_THREE_LEN)?;
 		if act_three[0] != 0 {
 			return Err(HandleError {
 				err: "Unknown handshake version number",
@@ -435,7 +642,7 @@ impl PeerChannelEncryptor<InProgress<PostActTwo<Inbound>>> {
 			1,
 			&self.noise_state.directional_state.temp_k2,
 			&self.noise_state.bidirectional_state.h,
-			&act_three[1..50],
+			&act_three[1..ACT_THREE_LEN - MAC_LEN],
 		)?;
 		let their_node_id = match PublicKey::from_slice(&their_node_id) {
 			Ok(key) => key,
@@ -449,7 +656,7 @@ impl PeerChannelEncryptor<InProgress<PostActTwo<Inbound>>> {
 
 		let mut sha = Sha256::engine();
 		sha.input(&self.noise_state.bidirectional_state.h);
-		sha.input(&act_three[1..50]);
+		sha.input(&act_three[1..ACT_THREE_LEN - MAC_LEN]);
 		self.noise_state.bidirectional_state.h = Sha256::from_engine(sha).into_inner();
 
 		let ss = SharedSecret::new(&their_node_id, &self.noise_state.directional_state.re);
@@ -460,7 +667,7 @@ impl PeerChannelEncryptor<InProgress<PostActTwo<Inbound>>> {
 			0,
 			&temp_k,
 			&self.noise_state.bidirectional_state.h,
-			&act_three[50..],
+			&act_three[ACT_THREE_LEN - MAC_LEN..],
 		)?;
 		let final_hkdf =
 			Self::hkdf_extract_expand(&self.noise_state.bidirectional_state.ck, &[0; 0]);
@@ -474,6 +681,8 @@ impl PeerChannelEncryptor<InProgress<PostActTwo<Inbound>>> {
 			rk: rk,
 			rn: 0,
 			rck: ck,
+			pending_length: None,
+			generation: 0,
 		};
 
 		Ok((
@@ -494,8 +703,8 @@ impl PeerChannelEncryptor<Finished> {
 			panic!("Attempted to encrypt message longer than 65535 bytes!");
 		}
 
-		let mut res = Vec::with_capacity(msg.len() + 16 * 2 + 2);
-		res.resize(msg.len() + 16 * 2 + 2, 0);
+		let mut res = Vec::with_capacity(MSG_HEADER_LEN + msg.len() + MAC_LEN);
+		res.resize(MSG_HEADER_LEN + msg.len() + MAC_LEN, 0);
 
 		match self.noise_state {
 			Finished {
@@ -505,8 +714,11 @@ impl PeerChannelEncryptor<Finished> {
 				rk: _,
 				rn: _,
 				rck: _,
+				pending_length: _,
+				generation: _,
 			} => {
-				if *sn >= 1000 {
+				// Skipping the rotation is non-spec-compliant, see the no-transport-rekey feature
+				if cfg!(not(feature = "no-transport-rekey")) && *sn >= 1000 {
 					let (new_sck, new_sk) = Self::hkdf_extract_expand(sck, sk);
 					*sck = new_sck;
 					*sk = new_sk;
@@ -514,7 +726,7 @@ impl PeerChannelEncryptor<Finished> {
 				}
 
 				Self::encrypt_with_ad(
-					&mut res[0..16 + 2],
+					&mut res[0..MSG_HEADER_LEN],
 					*sn,
 					sk,
 					&[0; 0],
@@ -522,7 +734,7 @@ impl PeerChannelEncryptor<Finished> {
 				);
 				*sn += 1;
 
-				Self::encrypt_with_ad(&mut res[16 + 2..], *sn, sk, &[0; 0], msg);
+				Self::encrypt_with_ad(&mut res[MSG_HEADER_LEN..], *sn, sk, &[0; 0], msg);
 				*sn += 1;
 			}
 		}
@@ -531,9 +743,9 @@ impl PeerChannelEncryptor<Finished> {
 	}
 
 	/// Decrypts a message length header from the remote peer.
-	/// panics if noise handshake has not yet finished or msg.len() != 18
+	/// panics if noise handshake has not yet finished or msg.len() != MSG_HEADER_LEN
 	pub fn decrypt_length_header(&mut self, msg: &[u8]) -> Result<u16, HandleError> {
-		assert_eq!(msg.len(), 16 + 2);
+		assert_eq!(msg.len(), MSG_HEADER_LEN);
 
 		match self.noise_state {
 			Finished {
@@ -543,8 +755,10 @@ impl PeerChannelEncryptor<Finished> {
 				ref mut rk,
 				ref mut rn,
 				ref mut rck,
+				ref mut pending_length,
+				generation: _,
 			} => {
-				if *rn >= 1000 {
+				if cfg!(not(feature = "no-transport-rekey")) && *rn >= 1000 {
 					let (new_rck, new_rk) = Self::hkdf_extract_expand(rck, rk);
 					*rck = new_rck;
 					*rk = new_rk;
@@ -554,15 +768,17 @@ impl PeerChannelEncryptor<Finished> {
 				let mut res = [0; 2];
 				Self::decrypt_with_ad(&mut res, *rn, rk, &[0; 0], msg)?;
 				*rn += 1;
-				Ok(byte_utils::slice_to_be16(&res))
+				let len = byte_utils::slice_to_be16(&res);
+				*pending_length = Some(len);
+				Ok(len)
 			}
 		}
 	}
 
-	/// Decrypts the given message.
-	/// panics if msg.len() > 65535 + 16
+	/// Decrypts the given message, failing if its length doesn't match the last decrypted header.
+	/// panics if msg.len() > 65535 + MAC_LEN
 	pub fn decrypt_message(&mut self, msg: &[u8]) -> Result<Vec<u8>, HandleError> {
-		if msg.len() > 65535 + 16 {
+		if msg.len() > 65535 + MAC_LEN {
 			panic!("Attempted to encrypt message longer than 65535 bytes!");
 		}
 
@@ -574,16 +790,148 @@ impl PeerChannelEncryptor<Finished> {
 				ref rk,
 				ref mut rn,
 				rck: _,
+				ref mut pending_length,
+				generation: _,
 			} => {
-				let mut res = Vec::with_capacity(msg.len() - 16);
-				res.resize(msg.len() - 16, 0);
+				if let Some(len) = *pending_length {
+					if msg.len() != len as usize + MAC_LEN {
+						return Err(HandleError {
+							err: "Message length did not match length header",
+							action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }),
+						});
+					}
+				}
+
+				let mut res = Vec::with_capacity(msg.len() - MAC_LEN);
+				res.resize(msg.len() - MAC_LEN, 0);
 				Self::decrypt_with_ad(&mut res[..], *rn, rk, &[0; 0], msg)?;
 				*rn += 1;
+				*pending_length = None;
 
 				Ok(res)
 			}
 		}
 	}
+
+	/// Decrypts a complete frame (length header followed by the body) in one call, advancing the
+	/// receive nonce by exactly two on success. If the frame's size does not match its decrypted
+	/// length header only the header's nonce is consumed.
+	pub fn process_frame(&mut self, frame: &[u8]) -> Result<Vec<u8>, HandleError> {
+		if frame.len() < MSG_HEADER_LEN + MAC_LEN {
+			return Err(HandleError {
+				err: "Frame was too short to hold a length header and message",
+				action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }),
+			});
+		}
+		let len = self.decrypt_length_header(&frame[..MSG_HEADER_LEN])?;
+		if frame.len() != MSG_HEADER_LEN + len as usize + MAC_LEN {
+			return Err(HandleError {
+				err: "Message length did not match length header",
+				action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }),
+			});
+		}
+		self.decrypt_message(&frame[MSG_HEADER_LEN..])
+	}
+
+	/// Tears down the transport once the connection is gone, wiping the sending and receiving
+	/// keys right away instead of leaving them in memory until they happen to be overwritten.
+	pub fn close(mut self) {
+		// Wipe in place: handing self on by value could leave an unwiped copy behind
+		self.noise_state.zeroize_secrets();
+	}
+
+	#[cfg(test)]
+	fn transport_keys(&self) -> ([u8; 32], [u8; 32], [u8; 32], [u8; 32]) {
+		(self.noise_state.sk, self.noise_state.sck, self.noise_state.rk, self.noise_state.rck)
+	}
+
+	/// Serializes the transport keys and nonces so the connection can be resumed later, consuming
+	/// the encryptor so it can no longer send or receive.
+	///
+	/// The serialized state must have exactly one owner: it may be passed to read() at most once,
+	/// and any older copy must be discarded once the resumed encryptor has been serialized again.
+	/// Reading the same bytes twice yields two encryptors which encrypt different messages under
+	/// the same key and nonce, which breaks ChaCha20-Poly1305 and leaks plaintext to anyone
+	/// observing both connections. Each call bumps a generation counter which is stored alongside
+	/// the keys, and debug builds panic if a given generation is read twice on the same thread.
+	pub fn serialize(mut self) -> Vec<u8> {
+		let mut res = Vec::with_capacity(32 * 4 + 8 * 3 + 3);
+		{
+			let state = &mut self.noise_state;
+			state.generation += 1;
+			state.sk.write(&mut res).unwrap();
+			state.sn.write(&mut res).unwrap();
+			state.sck.write(&mut res).unwrap();
+			state.rk.write(&mut res).unwrap();
+			state.rn.write(&mut res).unwrap();
+			state.rck.write(&mut res).unwrap();
+			state.pending_length.write(&mut res).unwrap();
+			state.generation.write(&mut res).unwrap();
+		}
+		self.noise_state.zeroize_secrets();
+		res
+	}
+
+	/// Reads an encryptor written by serialize(). See serialize() for the single-owner invariant
+	/// callers must uphold: never read the same serialized state more than once.
+	pub fn read<R: Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let mut noise_state = Finished {
+			sk: [0; 32],
+			sn: 0,
+			sck: [0; 32],
+			rk: [0; 32],
+			rn: 0,
+			rck: [0; 32],
+			pending_length: None,
+			generation: 0,
+		};
+		// Keys read before a failure must not be left behind when we bail
+		let read_res = noise_state.read_fields(reader);
+		if read_res.is_err() || noise_state.generation == 0 {
+			noise_state.zeroize_secrets();
+			return Err(read_res.err().unwrap_or(DecodeError::InvalidValue));
+		}
+
+		#[cfg(debug_assertions)]
+		{
+			let mut sha = Sha256::engine();
+			sha.input(&noise_state.sk);
+			sha.input(&byte_utils::be64_to_array(noise_state.sn));
+			sha.input(&noise_state.rk);
+			sha.input(&byte_utils::be64_to_array(noise_state.rn));
+			sha.input(&byte_utils::be64_to_array(noise_state.generation));
+			let fingerprint = Sha256::from_engine(sha).into_inner();
+			let first_read = READ_STATES.with(|states| {
+				let (ref mut seen, ref mut order) = *states.borrow_mut();
+				if !seen.insert(fingerprint) {
+					return false;
+				}
+				order.push_back(fingerprint);
+				if order.len() > MAX_READ_STATES {
+					seen.remove(&order.pop_front().unwrap());
+				}
+				true
+			});
+			debug_assert!(first_read, "Serialized PeerChannelEncryptor state was read twice, which would reuse nonces");
+		}
+
+		Ok(PeerChannelEncryptor {
+			secp_ctx: Secp256k1::signing_only(),
+			noise_state,
+		})
+	}
+}
+
+/// How many fingerprints READ_STATES remembers before forgetting the oldest, so long-running debug
+/// builds don't grow it forever
+#[cfg(debug_assertions)]
+const MAX_READ_STATES: usize = 4096;
+
+#[cfg(debug_assertions)]
+thread_local! {
+	/// Fingerprints of the most recent serialized states read on this thread (with the order they
+	/// were read in), to catch nonce reuse in testing
+	static READ_STATES: RefCell<(HashSet<[u8; 32]>, VecDeque<[u8; 32]>)> = RefCell::new((HashSet::new(), VecDeque::new()));
 }
 
 #[cfg(test)]
@@ -596,8 +944,11 @@ mod tests {
 
 	use ln::peer_channel_encryptor::{NoiseState, PeerChannelEncryptor};
 
+	use std::collections::HashSet;
 	use std::convert::TryInto;
 
+	use rand::{thread_rng, Rng};
+
 	fn get_outbound_peer_for_initiator_test_vectors(
 	) -> PeerChannelEncryptor<InProgress<PostActOne<Outbound>>> {
 		let their_node_id = PublicKey::from_slice(
@@ -645,6 +996,7 @@ mod tests {
 					rk,
 					rn,
 					rck,
+					..
 				} => {
 					assert_eq!(
 						sk,
@@ -714,6 +1066,17 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn initiate_outbound_matches_two_step() {
+		let their_node_id = PublicKey::from_slice(&hex::decode("028d7500dd4c12685d1f568b4c2b5048e8534b873319f3a8daa612b469132ec7f7").unwrap()[..]).unwrap();
+		let ephemeral_key = SecretKey::from_slice(&[0x12; 32]).unwrap();
+
+		let (_, two_step_act_one) = PeerChannelEncryptor::new_outbound(their_node_id, ephemeral_key.clone()).get_act_one();
+		let (_, act_one) = PeerChannelEncryptor::initiate_outbound(their_node_id, ephemeral_key);
+		assert_eq!(act_one[..], two_step_act_one[..]);
+		assert_eq!(act_one[..], hex::decode("00036360e856310ce5d294e8be33fc807077dc56ac80d95d9cd4ddbd21325eff73f70df6086551151f58b8afe6c195782c6a").unwrap()[..]);
+	}
+
 	#[test]
 	fn noise_responder_test_vectors() {
 		let our_node_id = SecretKey::from_slice(
@@ -755,6 +1118,7 @@ mod tests {
 					rk,
 					rn,
 					rck,
+					..
 				} => {
 					assert_eq!(
 						sk,
@@ -880,146 +1244,39 @@ mod tests {
 
 	#[test]
 	fn message_encryption_decryption_test_vectors() {
-		// We use the same keys as the initiator and responder test vectors, so we copy those tests
-		// here and use them to encrypt.
-		let outbound_peer = get_outbound_peer_for_initiator_test_vectors();
-
-		let mut outbound_peer = {
-			let our_node_id = SecretKey::from_slice(
-				&hex::decode("1111111111111111111111111111111111111111111111111111111111111111")
-					.unwrap()[..],
-			)
-			.unwrap();
-
-			let act_two = hex::decode("0002466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f276e2470b93aac583c9ef6eafca3f730ae").unwrap().to_vec();
-			let (outbound_peer, act_three, pubkey) = outbound_peer
-				.process_act_two(&act_two[..], &our_node_id)
-				.unwrap();
-			assert_eq!(act_three[..], hex::decode("00b9e3a702e93e3a9948c2ed6e5fd7590a6e1c3a0344cfc9d5b57357049aa22355361aa02e55a8fc28fef5bd6d71ad0c38228dc68b1c466263b47fdf31e560e139ba").unwrap()[..]);
-
-			match outbound_peer.noise_state {
-				Finished {
-					sk,
-					sn,
-					sck,
-					rk,
-					rn,
-					rck,
-				} => {
-					assert_eq!(
-						sk,
-						hex::decode(
-							"969ab31b4d288cedf6218839b27a3e2140827047f2c0f01bf5c04435d43511a9"
-						)
-						.unwrap()[..]
-					);
-					assert_eq!(sn, 0);
-					assert_eq!(
-						sck,
-						hex::decode(
-							"919219dbb2920afa8db80f9a51787a840bcf111ed8d588caf9ab4be716e42b01"
-						)
-						.unwrap()[..]
-					);
-					assert_eq!(
-						rk,
-						hex::decode(
-							"bb9020b8965f4df047e07f955f3c4b88418984aadc5cdb35096b9ea8fa5c3442"
-						)
-						.unwrap()[..]
-					);
-					assert_eq!(rn, 0);
-					assert_eq!(
-						rck,
-						hex::decode(
-							"919219dbb2920afa8db80f9a51787a840bcf111ed8d588caf9ab4be716e42b01"
-						)
-						.unwrap()[..]
-					);
-				}
-			};
-			outbound_peer
-		};
-
-		let mut inbound_peer = {
-			// transport-responder successful handshake
-			let our_node_id = SecretKey::from_slice(
-				&hex::decode("2121212121212121212121212121212121212121212121212121212121212121")
-					.unwrap()[..],
-			)
-			.unwrap();
-			let our_ephemeral = SecretKey::from_slice(
-				&hex::decode("2222222222222222222222222222222222222222222222222222222222222222")
-					.unwrap()[..],
-			)
-			.unwrap();
-
-			let inbound_peer = PeerChannelEncryptor::new_inbound(&our_node_id);
-
-			let act_one = hex::decode("00036360e856310ce5d294e8be33fc807077dc56ac80d95d9cd4ddbd21325eff73f70df6086551151f58b8afe6c195782c6a").unwrap().to_vec();
-			let (inbound_peer, act_two) = inbound_peer
-				.process_act_one_with_keys(&act_one[..], &our_node_id, our_ephemeral.clone())
-				.unwrap();
-			assert_eq!(act_two[..], hex::decode("0002466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f276e2470b93aac583c9ef6eafca3f730ae").unwrap()[..]);
-
-			let act_three = hex::decode("00b9e3a702e93e3a9948c2ed6e5fd7590a6e1c3a0344cfc9d5b57357049aa22355361aa02e55a8fc28fef5bd6d71ad0c38228dc68b1c466263b47fdf31e560e139ba").unwrap().to_vec();
-			// test vector doesn't specify the initiator static key, but it's the same as the one
-			// from transport-initiator successful handshake
-			let (inbound_peer, pubkey) = inbound_peer.process_act_three(&act_three[..]).unwrap();
-			assert_eq!(
-				pubkey.serialize()[..],
-				hex::decode("034f355bdcb7cc0af728ef3cceb9615d90684bb5b2ca5f859ab0f0b704075871aa")
-					.unwrap()[..]
-			);
-
-			match inbound_peer.noise_state {
-				Finished {
-					sk,
-					sn,
-					sck,
-					rk,
-					rn,
-					rck,
-				} => {
-					assert_eq!(
-						sk,
-						hex::decode(
-							"bb9020b8965f4df047e07f955f3c4b88418984aadc5cdb35096b9ea8fa5c3442"
-						)
-						.unwrap()[..]
-					);
-					assert_eq!(sn, 0);
-					assert_eq!(
-						sck,
-						hex::decode(
-							"919219dbb2920afa8db80f9a51787a840bcf111ed8d588caf9ab4be716e42b01"
-						)
-						.unwrap()[..]
-					);
-					assert_eq!(
-						rk,
-						hex::decode(
-							"969ab31b4d288cedf6218839b27a3e2140827047f2c0f01bf5c04435d43511a9"
-						)
-						.unwrap()[..]
-					);
-					assert_eq!(rn, 0);
-					assert_eq!(
-						rck,
-						hex::decode(
-							"919219dbb2920afa8db80f9a51787a840bcf111ed8d588caf9ab4be716e42b01"
-						)
-						.unwrap()[..]
-					);
-				}
+		// We use the same keys as the initiator and responder test vectors, whose handshake
+		// results are checked in noise_initiator_test_vectors and noise_responder_test_vectors.
+		let (mut outbound_peer, mut inbound_peer) = get_finished_pair();
+		let msg = [0x68, 0x65, 0x6c, 0x6c, 0x6f];
+
+		// BOLT 8 only mandates the ciphertexts of BOLT8_TRANSCRIPT.frames, ie messages 0, 1, 500,
+		// 501, 1000 and 1001 of the 1005 "hello"s, which sit either side of the two key rotations.
+		// For every other message we check the structure the spec requires instead: each message
+		// uses two nonces, and the key rotates (and the nonce resets) once 1000 have been used.
+		let mut seen_ciphertexts = HashSet::new();
+		let mut expected_frames = BOLT8_TRANSCRIPT.frames.iter().peekable();
+		for i in 0..1005usize {
+			let (prev_sk, prev_sck) = match outbound_peer.noise_state {
+				Finished { sk, sck, .. } => (sk, sck),
 			};
-			inbound_peer
-		};
 
-		for i in 0..1005 {
-			let msg = [0x68, 0x65, 0x6c, 0x6c, 0x6f];
 			let res = outbound_peer.encrypt_message(&msg);
 			assert_eq!(res.len(), 5 + 2 * 16 + 2);
+			assert!(seen_ciphertexts.insert(res.clone()));
+
+			let (sk, sn, sck) = match outbound_peer.noise_state {
+				Finished { sk, sn, sck, .. } => (sk, sn, sck),
+			};
+			if cfg!(not(feature = "no-transport-rekey")) {
+				assert_eq!(sn, 2 * (i as u64 % 500 + 1));
+				if i != 0 && i % 500 == 0 {
+					assert_eq!((sck, sk), PeerChannelEncryptor::<Finished>::hkdf_extract_expand(&prev_sck, &prev_sk));
+				} else {
+					assert_eq!((sck, sk), (prev_sck, prev_sk));
+				}
+			} else {
+				assert_eq!(sn, 2 * (i as u64 + 1));
+			}
 
 			let len_header = res[0..2 + 16].to_vec();
 			assert_eq!(
@@ -1030,20 +1287,463 @@ mod tests {
 				inbound_peer.decrypt_message(&res[2 + 16..]).unwrap()[..],
 				msg[..]
 			);
+			match inbound_peer.noise_state {
+				Finished { rk, rn, rck, .. } => assert_eq!((rk, rn, rck), (sk, sn, sck)),
+			}
 
-			if i == 0 {
-				assert_eq!(res, hex::decode("cf2b30ddf0cf3f80e7c35a6e6730b59fe802473180f396d88a8fb0db8cbcf25d2f214cf9ea1d95").unwrap());
-			} else if i == 1 {
-				assert_eq!(res, hex::decode("72887022101f0b6753e0c7de21657d35a4cb2a1f5cde2650528bbc8f837d0f0d7ad833b1a256a1").unwrap());
-			} else if i == 500 {
-				assert_eq!(res, hex::decode("178cb9d7387190fa34db9c2d50027d21793c9bc2d40b1e14dcf30ebeeeb220f48364f7a4c68bf8").unwrap());
-			} else if i == 501 {
-				assert_eq!(res, hex::decode("1b186c57d44eb6de4c057c49940d79bb838a145cb528d6e8fd26dbe50a60ca2c104b56b60e45bd").unwrap());
-			} else if i == 1000 {
-				assert_eq!(res, hex::decode("4a2f3cc3b5e78ddb83dcb426d9863d9d9a723b0337c89dd0b005d89f8d3c05c52b76b29b740f09").unwrap());
-			} else if i == 1001 {
-				assert_eq!(res, hex::decode("2ecd8c8a5629d0d02ab457a0fdd0f7b90a192cd46be5ecb6ca570bfc5e268338b1a16cf4ef2d36").unwrap());
+			if expected_frames.peek().map(|&&(idx, _, _)| idx) == Some(i) {
+				let &(_, frame, plaintext) = expected_frames.next().unwrap();
+				assert_eq!(hex::decode(plaintext).unwrap(), msg);
+				// Without rekeying only the messages before the first rotation match
+				if i < 500 || cfg!(not(feature = "no-transport-rekey")) {
+					assert_eq!(res, hex::decode(frame).unwrap());
+				}
 			}
 		}
+		assert!(expected_frames.next().is_none());
+	}
+
+	/// A responder-side transcript: the bytes a remote initiator put on the wire, with the frames
+	/// it sent keyed by their index in the stream.
+	struct ReceivedTranscript {
+		our_node_secret: [u8; 32],
+		our_ephemeral: [u8; 32],
+		act_one: &'static str,
+		act_three: &'static str,
+		their_node_id: &'static str,
+		frames: &'static [(usize, &'static str, &'static str)],
+	}
+
+	// The BOLT 8 transcript, which lnd and c-lightning both check their transports against.
+	//TODO: Interop captures are still missing. Handshakes recorded against live lnd and c-lightning
+	// nodes (acts plus a few messages, at least one crossing a rekey boundary) have not been added,
+	// so this is the only transcript replayed. Recording one needs our side's static and ephemeral
+	// keys, so it has to be done against a node we run with known keys; each capture then gets its
+	// own ReceivedTranscript below. They live here rather than under tests/ as the encryptor is
+	// crate-private outside of fuzztarget builds.
+	const BOLT8_TRANSCRIPT: ReceivedTranscript = ReceivedTranscript {
+		our_node_secret: [0x21; 32],
+		our_ephemeral: [0x22; 32],
+		act_one: "00036360e856310ce5d294e8be33fc807077dc56ac80d95d9cd4ddbd21325eff73f70df6086551151f58b8afe6c195782c6a",
+		act_three: "00b9e3a702e93e3a9948c2ed6e5fd7590a6e1c3a0344cfc9d5b57357049aa22355361aa02e55a8fc28fef5bd6d71ad0c38228dc68b1c466263b47fdf31e560e139ba",
+		their_node_id: "034f355bdcb7cc0af728ef3cceb9615d90684bb5b2ca5f859ab0f0b704075871aa",
+		frames: &[
+			(0, "cf2b30ddf0cf3f80e7c35a6e6730b59fe802473180f396d88a8fb0db8cbcf25d2f214cf9ea1d95", "68656c6c6f"),
+			(1, "72887022101f0b6753e0c7de21657d35a4cb2a1f5cde2650528bbc8f837d0f0d7ad833b1a256a1", "68656c6c6f"),
+			(500, "178cb9d7387190fa34db9c2d50027d21793c9bc2d40b1e14dcf30ebeeeb220f48364f7a4c68bf8", "68656c6c6f"),
+			(501, "1b186c57d44eb6de4c057c49940d79bb838a145cb528d6e8fd26dbe50a60ca2c104b56b60e45bd", "68656c6c6f"),
+			(1000, "4a2f3cc3b5e78ddb83dcb426d9863d9d9a723b0337c89dd0b005d89f8d3c05c52b76b29b740f09", "68656c6c6f"),
+			(1001, "2ecd8c8a5629d0d02ab457a0fdd0f7b90a192cd46be5ecb6ca570bfc5e268338b1a16cf4ef2d36", "68656c6c6f"),
+		],
+	};
+
+	/// Replays a transcript as the responder. Frames between the recorded ones are not part of
+	/// the transcript, so the receive state is moved past them by decrypting blank frames from a
+	/// sender which is kept in lockstep with us.
+	fn replay_received_transcript(transcript: &ReceivedTranscript) {
+		let our_node_secret = SecretKey::from_slice(&transcript.our_node_secret).unwrap();
+		let our_ephemeral = SecretKey::from_slice(&transcript.our_ephemeral).unwrap();
+		let (inbound_peer, _) = PeerChannelEncryptor::new_inbound(&our_node_secret)
+			.process_act_one_with_keys(&hex::decode(transcript.act_one).unwrap()[..], &our_node_secret, our_ephemeral)
+			.unwrap();
+		let (mut inbound_peer, their_node_id) = inbound_peer
+			.process_act_three(&hex::decode(transcript.act_three).unwrap()[..])
+			.unwrap();
+		assert_eq!(their_node_id.serialize()[..], hex::decode(transcript.their_node_id).unwrap()[..]);
+
+		let mut next_frame = 0;
+		for &(idx, frame, msg) in transcript.frames.iter() {
+			// Frames after the first key rotation can't be read without rekeying
+			if idx >= 500 && cfg!(feature = "no-transport-rekey") {
+				break;
+			}
+			while next_frame < idx {
+				let (rk, rn, rck) = match inbound_peer.noise_state {
+					Finished { rk, rn, rck, .. } => (rk, rn, rck),
+				};
+				let mut sender = PeerChannelEncryptor {
+					secp_ctx: Secp256k1::signing_only(),
+					noise_state: Finished { sk: rk, sn: rn, sck: rck, rk: [0; 32], rn: 0, rck: [0; 32], pending_length: None, generation: 0 },
+				};
+				inbound_peer.process_frame(&sender.encrypt_message(&[])[..]).unwrap();
+				next_frame += 1;
+			}
+			assert_eq!(inbound_peer.process_frame(&hex::decode(frame).unwrap()[..]).unwrap(), hex::decode(msg).unwrap());
+			next_frame += 1;
+		}
+	}
+
+	#[test]
+	fn received_transcripts() {
+		replay_received_transcript(&BOLT8_TRANSCRIPT);
+	}
+
+	/// Gets a connected (outbound, inbound) pair of encryptors which have completed the handshake
+	/// with the keys from the BOLT 8 test vectors.
+	fn get_finished_pair() -> (PeerChannelEncryptor<Finished>, PeerChannelEncryptor<Finished>) {
+		let outbound_node_id = SecretKey::from_slice(&[0x11; 32]).unwrap();
+		let inbound_node_id = SecretKey::from_slice(&[0x21; 32]).unwrap();
+		let inbound_ephemeral = SecretKey::from_slice(&[0x22; 32]).unwrap();
+
+		let outbound_peer = get_outbound_peer_for_initiator_test_vectors();
+		let act_one = hex::decode("00036360e856310ce5d294e8be33fc807077dc56ac80d95d9cd4ddbd21325eff73f70df6086551151f58b8afe6c195782c6a").unwrap();
+		let (inbound_peer, act_two) = PeerChannelEncryptor::new_inbound(&inbound_node_id)
+			.process_act_one_with_keys(&act_one[..], &inbound_node_id, inbound_ephemeral)
+			.unwrap();
+		let (outbound_peer, act_three, _) = outbound_peer.process_act_two(&act_two[..], &outbound_node_id).unwrap();
+		let (inbound_peer, _) = inbound_peer.process_act_three(&act_three[..]).unwrap();
+		(outbound_peer, inbound_peer)
+	}
+
+	fn random_secret_key() -> SecretKey {
+		let mut rng = thread_rng();
+		loop {
+			let mut key = [0; 32];
+			rng.fill_bytes(&mut key);
+			if let Ok(key) = SecretKey::from_slice(&key) {
+				return key;
+			}
+		}
+	}
+
+	/// Gets a connected (outbound, inbound) pair of encryptors which have completed the handshake
+	/// with random node and ephemeral keys.
+	fn connected_pair() -> (PeerChannelEncryptor<Finished>, PeerChannelEncryptor<Finished>) {
+		let secp_ctx = Secp256k1::signing_only();
+		let outbound_node_secret = random_secret_key();
+		let inbound_node_secret = random_secret_key();
+		let inbound_node_id = PublicKey::from_secret_key(&secp_ctx, &inbound_node_secret);
+
+		let (outbound_peer, act_one) = PeerChannelEncryptor::new_outbound(inbound_node_id, random_secret_key()).get_act_one();
+		let (inbound_peer, act_two) = PeerChannelEncryptor::new_inbound(&inbound_node_secret)
+			.process_act_one_with_keys(&act_one[..], &inbound_node_secret, random_secret_key())
+			.unwrap();
+		let (outbound_peer, act_three, their_node_id) = outbound_peer.process_act_two(&act_two[..], &outbound_node_secret).unwrap();
+		assert_eq!(their_node_id, inbound_node_id);
+		let (inbound_peer, their_node_id) = inbound_peer.process_act_three(&act_three[..]).unwrap();
+		assert_eq!(their_node_id, PublicKey::from_secret_key(&secp_ctx, &outbound_node_secret));
+		(outbound_peer, inbound_peer)
+	}
+
+	fn get_sending_key(peer: &PeerChannelEncryptor<Finished>) -> ([u8; 32], u64) {
+		match peer.noise_state {
+			Finished { sk, sn, .. } => (sk, sn),
+		}
+	}
+
+	fn send_and_receive(outbound_peer: &mut PeerChannelEncryptor<Finished>, inbound_peer: &mut PeerChannelEncryptor<Finished>, msg: &[u8]) {
+		let res = outbound_peer.encrypt_message(msg);
+		assert_eq!(inbound_peer.decrypt_length_header(&res[..2 + 16]).unwrap() as usize, msg.len());
+		assert_eq!(inbound_peer.decrypt_message(&res[2 + 16..]).unwrap()[..], msg[..]);
+	}
+
+	#[cfg(not(feature = "no-transport-rekey"))]
+	#[test]
+	fn rekey_after_1000_messages() {
+		let (mut outbound_peer, mut inbound_peer) = connected_pair();
+		let (initial_sk, _) = get_sending_key(&outbound_peer);
+		// Each message uses two nonces (the length header and the body)
+		for _ in 0..500 {
+			send_and_receive(&mut outbound_peer, &mut inbound_peer, b"hello");
+		}
+		assert_eq!(get_sending_key(&outbound_peer), (initial_sk, 1000));
+		send_and_receive(&mut outbound_peer, &mut inbound_peer, b"hello");
+		let (sk, sn) = get_sending_key(&outbound_peer);
+		assert!(sk != initial_sk);
+		assert_eq!(sn, 2);
+	}
+
+	#[cfg(feature = "no-transport-rekey")]
+	#[test]
+	fn no_rekey_with_feature() {
+		let (mut outbound_peer, mut inbound_peer) = connected_pair();
+		let (initial_sk, _) = get_sending_key(&outbound_peer);
+		for _ in 0..1005 {
+			send_and_receive(&mut outbound_peer, &mut inbound_peer, b"hello");
+		}
+		// The key is never rotated and the nonce simply keeps incrementing
+		assert_eq!(get_sending_key(&outbound_peer), (initial_sk, 2010));
+		match inbound_peer.noise_state {
+			Finished { rk, rn, .. } => {
+				assert_eq!(rk, initial_sk);
+				assert_eq!(rn, 2010);
+			},
+		}
+	}
+
+	#[test]
+	fn random_message_round_trips() {
+		let (mut outbound_peer, mut inbound_peer) = connected_pair();
+		let mut rng = thread_rng();
+		// 600 messages use 1200 nonces, crossing the first rekey
+		for i in 0..600 {
+			let len = match i {
+				0 => 0,
+				1 => 65535,
+				_ if rng.gen_weighted_bool(10) => rng.gen_range(0, 65536),
+				_ => rng.gen_range(0, 1024),
+			};
+			let mut msg = vec![0; len];
+			rng.fill_bytes(&mut msg[..]);
+			send_and_receive(&mut outbound_peer, &mut inbound_peer, &msg[..]);
+		}
+	}
+
+	#[test]
+	fn flipped_ciphertext_byte_fails_decrypt() {
+		let (mut outbound_peer, mut inbound_peer) = connected_pair();
+		let mut rng = thread_rng();
+		for _ in 0..600 {
+			let mut msg = vec![0; rng.gen_range(0, 1024)];
+			rng.fill_bytes(&mut msg[..]);
+			let res = outbound_peer.encrypt_message(&msg[..]);
+
+			let mut corrupted = res.clone();
+			let idx = rng.gen_range(0, corrupted.len());
+			corrupted[idx] ^= 1 << rng.gen_range(0, 8);
+
+			// A failed decrypt leaves the receive nonce untouched, so the original still decrypts afterwards
+			if idx < 2 + 16 {
+				assert!(inbound_peer.decrypt_length_header(&corrupted[..2 + 16]).is_err());
+				assert_eq!(inbound_peer.decrypt_length_header(&res[..2 + 16]).unwrap() as usize, msg.len());
+			} else {
+				assert_eq!(inbound_peer.decrypt_length_header(&res[..2 + 16]).unwrap() as usize, msg.len());
+				assert!(inbound_peer.decrypt_message(&corrupted[2 + 16..]).is_err());
+			}
+			assert_eq!(inbound_peer.decrypt_message(&res[2 + 16..]).unwrap()[..], msg[..]);
+		}
+	}
+
+	#[test]
+	fn body_length_must_match_header() {
+		let (mut outbound_peer, mut inbound_peer) = connected_pair();
+		let res = outbound_peer.encrypt_message(b"hello");
+		assert_eq!(inbound_peer.decrypt_length_header(&res[..2 + 16]).unwrap(), 5);
+
+		// A truncated body is rejected before any decryption is attempted
+		match inbound_peer.decrypt_message(&res[2 + 16..res.len() - 1]) {
+			Err(HandleError { err, action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }) }) => {
+				assert_eq!(err, "Message length did not match length header");
+			},
+			_ => panic!(),
+		}
+
+		// The nonce was not consumed, so the correct body still decrypts
+		assert_eq!(inbound_peer.decrypt_message(&res[2 + 16..]).unwrap()[..], b"hello"[..]);
+	}
+
+	fn receive_nonce(peer: &PeerChannelEncryptor<Finished>) -> u64 {
+		match peer.noise_state {
+			Finished { rn, .. } => rn,
+		}
+	}
+
+	#[test]
+	fn process_frame_round_trips() {
+		let (mut outbound_peer, mut inbound_peer) = connected_pair();
+		for i in 0..1005 {
+			let msg = vec![i as u8; i % 300];
+			let frame = outbound_peer.encrypt_message(&msg[..]);
+			let rn = receive_nonce(&inbound_peer);
+			assert_eq!(inbound_peer.process_frame(&frame[..]).unwrap(), msg);
+			// Rekeying resets the nonce to 0 before it is used
+			assert_eq!(receive_nonce(&inbound_peer), if rn >= 1000 && cfg!(not(feature = "no-transport-rekey")) { 2 } else { rn + 2 });
+		}
+	}
+
+	#[test]
+	fn process_frame_length_mismatch() {
+		let (mut outbound_peer, mut inbound_peer) = connected_pair();
+		let mut frame = outbound_peer.encrypt_message(b"hello");
+		frame.push(0);
+		match inbound_peer.process_frame(&frame[..]) {
+			Err(HandleError { err, action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }) }) => {
+				assert_eq!(err, "Message length did not match length header");
+			},
+			_ => panic!(),
+		}
+		// Only the header's nonce was consumed, so the body can still be decrypted
+		assert_eq!(receive_nonce(&inbound_peer), 1);
+		assert_eq!(inbound_peer.decrypt_message(&frame[2 + 16..frame.len() - 1]).unwrap()[..], b"hello"[..]);
+
+		// A frame too short to hold anything consumes no nonce at all
+		assert!(inbound_peer.process_frame(&[0; 33]).is_err());
+		assert_eq!(receive_nonce(&inbound_peer), 2);
+	}
+
+	#[test]
+	fn length_constants() {
+		let outbound_peer = get_outbound_peer_for_initiator_test_vectors();
+		let act_one = hex::decode("00036360e856310ce5d294e8be33fc807077dc56ac80d95d9cd4ddbd21325eff73f70df6086551151f58b8afe6c195782c6a").unwrap();
+		assert_eq!(act_one.len(), ACT_ONE_LEN);
+		let act_two = hex::decode("0002466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f276e2470b93aac583c9ef6eafca3f730ae").unwrap();
+		assert_eq!(act_two.len(), ACT_TWO_LEN);
+		let our_node_id = SecretKey::from_slice(&[0x11; 32]).unwrap();
+		let (mut outbound_peer, act_three, _) = outbound_peer.process_act_two(&act_two[..], &our_node_id).unwrap();
+		assert_eq!(act_three.len(), ACT_THREE_LEN);
+
+		assert_eq!(MSG_HEADER_LEN, 18);
+		assert_eq!(MAC_LEN, 16);
+		assert_eq!(outbound_peer.encrypt_message(b"hello").len(), MSG_HEADER_LEN + 5 + MAC_LEN);
+	}
+
+	#[test]
+	fn pre_handshake_flood_disconnects() {
+		let our_node_id = SecretKey::from_slice(&[0x21; 32]).unwrap();
+		let ephemeral = SecretKey::from_slice(&[0x22; 32]).unwrap();
+		let junk = [0x42; 1000];
+		match PeerChannelEncryptor::new_inbound(&our_node_id).process_act_one_with_keys(&junk[..], &our_node_id, ephemeral) {
+			Err(HandleError { action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }), .. }) => {},
+			_ => panic!(),
+		}
+
+		let outbound_peer = get_outbound_peer_for_initiator_test_vectors();
+		match outbound_peer.process_act_two(&junk[..], &our_node_id) {
+			Err(HandleError { action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }), .. }) => {},
+			_ => panic!(),
+		}
+	}
+
+	/// Builds the act two a responder holding responder_secret would send in reply to act_one,
+	/// without checking that act one was addressed to it.
+	fn forge_act_two(act_one: &[u8], responder_secret: &SecretKey) -> [u8; 50] {
+		type Responder = PeerChannelEncryptor<InProgress<PreActOne<Inbound>>>;
+		let responder = Responder::new_inbound(responder_secret);
+		let mut state = responder.noise_state.bidirectional_state;
+
+		let ie = PublicKey::from_slice(&act_one[1..34]).unwrap();
+		let mut sha = Sha256::engine();
+		sha.input(&state.h);
+		sha.input(&ie.serialize()[..]);
+		state.h = Sha256::from_engine(sha).into_inner();
+		Responder::hkdf(&mut state, SharedSecret::new(&ie, responder_secret));
+		let mut sha = Sha256::engine();
+		sha.input(&state.h);
+		sha.input(&act_one[34..]);
+		state.h = Sha256::from_engine(sha).into_inner();
+
+		Responder::outbound_noise_act(&responder.secp_ctx, &mut state, &random_secret_key(), &ie).0
+	}
+
+	#[test]
+	fn act_two_from_wrong_node_id_fails() {
+		let secp_ctx = Secp256k1::signing_only();
+		let our_node_secret = random_secret_key();
+		let dialed_secret = random_secret_key();
+		let dialed_node_id = PublicKey::from_secret_key(&secp_ctx, &dialed_secret);
+
+		// A responder holding the dialed key completes the handshake
+		let (outbound_peer, act_one) = PeerChannelEncryptor::new_outbound(dialed_node_id, random_secret_key()).get_act_one();
+		let act_two = forge_act_two(&act_one[..], &dialed_secret);
+		let (_, _, their_node_id) = outbound_peer.process_act_two(&act_two[..], &our_node_secret).unwrap();
+		assert_eq!(their_node_id, dialed_node_id);
+
+		// Any other responder is rejected
+		let (outbound_peer, act_one) = PeerChannelEncryptor::new_outbound(dialed_node_id, random_secret_key()).get_act_one();
+		let act_two = forge_act_two(&act_one[..], &random_secret_key());
+		match outbound_peer.process_act_two(&act_two[..], &our_node_secret) {
+			Err(HandleError { err, action: Some(msgs::ErrorAction::DisconnectPeer { msg: None }) }) => {
+				assert_eq!(err, "Peer's static key did not match the node_id we dialed");
+			},
+			_ => panic!(),
+		}
+	}
+
+	#[test]
+	fn stale_handshake_detection() {
+		let mut outbound_peer = PeerChannelEncryptor::new_outbound(PublicKey::from_secret_key(&Secp256k1::new(), &random_secret_key()), random_secret_key());
+		// Until the caller sets an activity time the handshake isn't stale, whatever the time
+		assert!(!outbound_peer.is_handshake_stale(u32::max_value(), 30));
+		outbound_peer.set_last_activity(1000);
+		let (mut outbound_peer, _) = outbound_peer.get_act_one();
+		// The activity time survives the move to PostActOne
+		assert!(!outbound_peer.is_handshake_stale(1000, 30));
+		assert!(!outbound_peer.is_handshake_stale(1029, 30));
+		assert!(outbound_peer.is_handshake_stale(1030, 30));
+
+		// A clock which went backwards doesn't mark the handshake stale
+		assert!(!outbound_peer.is_handshake_stale(900, 30));
+
+		outbound_peer.set_last_activity(1030);
+		assert!(!outbound_peer.is_handshake_stale(1040, 30));
+		assert!(outbound_peer.is_handshake_stale(1100, 30));
+	}
+
+	#[test]
+	fn abort_zeroizes_handshake_state() {
+		let our_node_secret = SecretKey::from_slice(&[0x21; 32]).unwrap();
+		let inbound_peer = PeerChannelEncryptor::new_inbound(&our_node_secret);
+		let act_one = hex::decode("00036360e856310ce5d294e8be33fc807077dc56ac80d95d9cd4ddbd21325eff73f70df6086551151f58b8afe6c195782c6a").unwrap();
+		let ephemeral = SecretKey::from_slice(&[0x22; 32]).unwrap();
+		let (inbound_peer, _) = inbound_peer.process_act_one_with_keys(&act_one[..], &our_node_secret, ephemeral).unwrap();
+		assert!(inbound_peer.handshake_secrets().1 != [0; 32]);
+		assert!(inbound_peer.noise_state.directional_state.temp_k2 != [0; 32]);
+
+		// abort consumes the encryptor, so check the state it wipes in place. This only shows that
+		// the right fields are wiped: the compiler would keep these writes anyway since we read
+		// them back, so whether they survive dead-store elimination when nothing reads them (as in
+		// abort) rests on zeroize using volatile writes.
+		let mut inbound_peer = inbound_peer;
+		inbound_peer.zeroize_handshake_state();
+		assert_eq!(inbound_peer.handshake_secrets(), ([0; 32], [0; 32]));
+		assert_eq!(inbound_peer.noise_state.directional_state.temp_k2, [0; 32]);
+		assert_eq!(inbound_peer.noise_state.directional_state.re, ONE_KEY);
+	}
+
+	#[test]
+	fn close_zeroizes_transport_keys() {
+		let (outbound_peer, _) = connected_pair();
+
+		assert!(outbound_peer.transport_keys().0 != [0; 32]);
+
+		// close consumes the encryptor, so check the keys it wipes in place
+		let mut outbound_peer = outbound_peer;
+		outbound_peer.noise_state.zeroize_secrets();
+		assert_eq!(outbound_peer.transport_keys(), ([0; 32], [0; 32], [0; 32], [0; 32]));
+	}
+
+	#[test]
+	fn serialize_single_owner_round_trip() {
+		let (mut outbound_peer, mut inbound_peer) = connected_pair();
+		send_and_receive(&mut outbound_peer, &mut inbound_peer, b"before");
+		let sending_key = get_sending_key(&outbound_peer);
+
+		// Serializing consumes the encryptor, leaving the bytes as the only copy of the state
+		let serialized = outbound_peer.serialize();
+		let mut outbound_peer = PeerChannelEncryptor::read(&mut &serialized[..]).unwrap();
+		assert_eq!(get_sending_key(&outbound_peer), sending_key);
+		send_and_receive(&mut outbound_peer, &mut inbound_peer, b"after");
+
+		// Persisting again without sending anything in between yields a new generation
+		let reserialized = outbound_peer.serialize();
+		assert!(reserialized != serialized);
+		let mut outbound_peer = PeerChannelEncryptor::read(&mut &reserialized[..]).unwrap();
+		send_and_receive(&mut outbound_peer, &mut inbound_peer, b"again");
+
+		assert!(PeerChannelEncryptor::read(&mut &serialized[..serialized.len() - 1]).is_err());
+	}
+
+	#[cfg(debug_assertions)]
+	#[test]
+	#[should_panic(expected = "read twice")]
+	fn serialized_state_read_twice_panics() {
+		let (outbound_peer, _) = connected_pair();
+		let serialized = outbound_peer.serialize();
+		let _first = PeerChannelEncryptor::read(&mut &serialized[..]).unwrap();
+		let _second = PeerChannelEncryptor::read(&mut &serialized[..]).unwrap();
+	}
+
+	#[cfg(debug_assertions)]
+	#[test]
+	fn read_states_is_bounded() {
+		let (mut outbound_peer, _) = connected_pair();
+		for _ in 0..MAX_READ_STATES + 10 {
+			outbound_peer = PeerChannelEncryptor::read(&mut &outbound_peer.serialize()[..]).unwrap();
+		}
+		READ_STATES.with(|states| {
+			let (ref seen, ref order) = *states.borrow();
+			assert_eq!(seen.len(), MAX_READ_STATES);
+			assert_eq!(order.len(), MAX_READ_STATES);
+		});
 	}
 }
@@ -7,9 +7,11 @@
 //! they should handle, and encoding/sending response messages.
 
 use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::Secp256k1;
 
 use ln::msgs;
-use ln::peer_channel_encryptor::{Finished, NoiseState, PeerChannelEncryptor};
+use ln::peer_channel_encryptor::{Finished, InProgress, Inbound, NoiseState, Outbound, PeerChannelEncryptor, PostActOne, PostActTwo, PreActOne};
+pub use ln::peer_channel_encryptor::{ACT_ONE_LEN, ACT_TWO_LEN, ACT_THREE_LEN, MSG_HEADER_LEN, MAC_LEN};
 use util::byte_utils;
 use util::events::MessageSendEvent;
 use util::logger::Logger;
@@ -18,7 +20,7 @@ use util::ser::{Readable, Writeable, Writer};
 use std::collections::{hash_map, HashMap, HashSet, LinkedList};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::{cmp, error, fmt, hash};
+use std::{cmp, error, fmt, hash, mem};
 
 use bitcoin_hashes::sha256::Hash as Sha256;
 use bitcoin_hashes::sha256::HashEngine as Sha256Engine;
@@ -115,6 +117,9 @@ struct Peer<T: NoiseState> {
 	pending_read_is_header: bool,
 
 	sync_status: InitSyncTracker,
+
+	/// The gossip_timestamp_filter the peer last sent us, if any.
+	gossip_filter: Option<msgs::GossipTimestampFilter>,
 }
 
 impl<T> Peer<T>
@@ -134,10 +139,98 @@ where
 			InitSyncTracker::NodesSyncing(_) => true,
 		}
 	}
+
+	/// Returns true if relayed gossip with the given timestamp should be sent to this peer.
+	///
+	/// Filters only apply to peers which signalled gossip_queries in their Init: such a peer gets
+	/// no relayed gossip until it sends a gossip_timestamp_filter, and then only gossip which falls
+	/// within it. Other peers get all gossip, as they have no way to ask for less. Gossip we
+	/// generated ourselves should be sent without consulting this.
+	fn should_forward_gossip(&self, timestamp: u32) -> bool {
+		match self.their_local_features {
+			Some(ref features) if features.supports_gossip_queries() => {},
+			_ => return true,
+		}
+		match self.gossip_filter {
+			None => false,
+			Some(ref filter) => filter.matches(timestamp),
+		}
+	}
+
+	/// Returns the number of encrypted bytes queued for the peer which have not yet been written
+	/// to its socket.
+	fn pending_outbound_bytes(&self) -> usize {
+		self.pending_outbound_buffer.iter().map(|buff| buff.len()).sum::<usize>()
+			- self.pending_outbound_buffer_first_msg_offset
+	}
+
+	/// Returns false once the peer has fallen OUTBOUND_BUFFER_LIMIT_BYTES behind on reading what
+	/// we send it, in which case we should stop reading (and thus generating responses) until it
+	/// catches up.
+	fn should_read(&self) -> bool {
+		self.pending_outbound_bytes() < OUTBOUND_BUFFER_LIMIT_BYTES
+	}
+}
+
+/// The number of unsent bytes we'll queue for a peer before asking the caller to pause reading
+/// from it.
+const OUTBOUND_BUFFER_LIMIT_BYTES: usize = 64 * 1024;
+
+/// The encryptor of a connection whose noise handshake is still running, in the state in which it
+/// waits for the peer's next act.
+enum HandshakeEncryptor {
+	/// An inbound connection, waiting on act one
+	AwaitingActOne(PeerChannelEncryptor<InProgress<PreActOne<Inbound>>>),
+	/// An outbound connection which sent act one, waiting on act two
+	AwaitingActTwo(PeerChannelEncryptor<InProgress<PostActOne<Outbound>>>),
+	/// An inbound connection which sent act two, waiting on act three
+	AwaitingActThree(PeerChannelEncryptor<InProgress<PostActTwo<Inbound>>>),
+}
+
+impl HandshakeEncryptor {
+	/// The length of the act we're waiting on
+	fn act_len(&self) -> usize {
+		match *self {
+			HandshakeEncryptor::AwaitingActOne(_) => ACT_ONE_LEN,
+			HandshakeEncryptor::AwaitingActTwo(_) => ACT_TWO_LEN,
+			HandshakeEncryptor::AwaitingActThree(_) => ACT_THREE_LEN,
+		}
+	}
+
+	fn abort(self) {
+		match self {
+			HandshakeEncryptor::AwaitingActOne(encryptor) => encryptor.abort(),
+			HandshakeEncryptor::AwaitingActTwo(encryptor) => encryptor.abort(),
+			HandshakeEncryptor::AwaitingActThree(encryptor) => encryptor.abort(),
+		}
+	}
+}
+
+/// A connection which is still running the noise handshake. It only becomes a Peer, moving over
+/// into PeerHolder::peers, once the handshake completes.
+struct HandshakingPeer {
+	/// None only once processing an act failed, until the connection is dropped
+	channel_encryptor: Option<HandshakeEncryptor>,
+	/// The part of the act we're waiting on which was read so far
+	pending_read_buffer: Vec<u8>,
+	/// The part of our act two not yet written to an inbound connection
+	pending_outbound_buffer: Vec<u8>,
+}
+
+impl HandshakingPeer {
+	fn new(channel_encryptor: HandshakeEncryptor) -> Self {
+		HandshakingPeer {
+			channel_encryptor: Some(channel_encryptor),
+			pending_read_buffer: Vec::new(),
+			pending_outbound_buffer: Vec::new(),
+		}
+	}
 }
 
 struct PeerHolder<Descriptor: SocketDescriptor> {
 	peers: HashMap<Descriptor, Peer<Complete>>,
+	/// Connections which have not completed the noise handshake yet
+	handshakes: HashMap<Descriptor, HandshakingPeer>,
 	/// Added to by do_read_event for cases where we pushed a message onto the send buffer but
 	/// didn't call do_attempt_write_data to avoid reentrancy. Cleared in process_events()
 	peers_needing_send: HashSet<Descriptor>,
@@ -146,6 +239,7 @@ struct PeerHolder<Descriptor: SocketDescriptor> {
 }
 struct MutPeerHolder<'a, Descriptor: SocketDescriptor + 'a> {
 	peers: &'a mut HashMap<Descriptor, Peer<Complete>>,
+	handshakes: &'a mut HashMap<Descriptor, HandshakingPeer>,
 	peers_needing_send: &'a mut HashSet<Descriptor>,
 	node_id_to_descriptor: &'a mut HashMap<PublicKey, Descriptor>,
 }
@@ -153,6 +247,7 @@ impl<Descriptor: SocketDescriptor> PeerHolder<Descriptor> {
 	fn borrow_parts(&mut self) -> MutPeerHolder<Descriptor> {
 		MutPeerHolder {
 			peers: &mut self.peers,
+			handshakes: &mut self.handshakes,
 			peers_needing_send: &mut self.peers_needing_send,
 			node_id_to_descriptor: &mut self.node_id_to_descriptor,
 		}
@@ -173,6 +268,7 @@ pub struct PeerManager<Descriptor: SocketDescriptor> {
 	message_handler: MessageHandler,
 	peers: Mutex<PeerHolder<Descriptor>>,
 	our_node_secret: SecretKey,
+	our_node_id: PublicKey,
 	ephemeral_key_midstate: Sha256Engine,
 
 	// Usize needs to be at least 32 bits to avoid overflowing both low and high. If usize is 64
@@ -226,9 +322,11 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 			message_handler: message_handler,
 			peers: Mutex::new(PeerHolder {
 				peers: HashMap::new(),
+				handshakes: HashMap::new(),
 				peers_needing_send: HashSet::new(),
 				node_id_to_descriptor: HashMap::new(),
 			}),
+			our_node_id: PublicKey::from_secret_key(&Secp256k1::signing_only(), &our_node_secret),
 			our_node_secret: our_node_secret,
 			ephemeral_key_midstate,
 			peer_counter_low: AtomicUsize::new(0),
@@ -244,21 +342,17 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 	/// new_outbound_connection, however entries will only appear once the initial handshake has
 	/// completed and we are sure the remote peer has the private key for the given node_id.
 	pub fn get_peer_node_ids(&self) -> Vec<PublicKey> {
-		/*
 		let peers = self.peers.lock().unwrap();
 		peers
 			.peers
 			.values()
 			.filter_map(|p| {
-				if !p.channel_encryptor.is_ready_for_encryption()
-					|| p.their_global_features.is_none()
-				{
+				if p.their_global_features.is_none() {
 					return None;
 				}
 				p.their_node_id
 			})
-			.collect()*/
-		unimplemented!()
+			.collect()
 	}
 
 	fn get_ephemeral_key(&self) -> SecretKey {
@@ -279,7 +373,7 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 	/// Note that if an Err is returned here you MUST NOT call disconnect_event for the new
 	/// descriptor but must disconnect the connection immediately.
 	///
-	/// Returns a small number of bytes to send to the remote node (currently always 50).
+	/// Returns a small number of bytes to send to the remote node (currently always ACT_ONE_LEN).
 	///
 	/// Panics if descriptor is duplicative with some other descriptor which has not yet has a
 	/// disconnect_event.
@@ -288,42 +382,19 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 		their_node_id: PublicKey,
 		descriptor: Descriptor,
 	) -> Result<Vec<u8>, PeerHandleError> {
-		/*
-		let peer_encryptor =
-			PeerChannelEncryptor::new_outbound(their_node_id.clone(), self.get_ephemeral_key());
-		let (peer_encryptor, res) = peer_encryptor.get_act_one();
-		let res = res.to_vec();
-		let pending_read_buffer = [0; 50].to_vec(); // Noise act two is 50 bytes
+		let (peer_encryptor, act_one) =
+			PeerChannelEncryptor::initiate_outbound(their_node_id, self.get_ephemeral_key());
 
 		let mut peers = self.peers.lock().unwrap();
-		if peers
-			.peers
-			.insert(
-				descriptor,
-				Peer {
-					channel_encryptor: peer_encryptor,
-					outbound: true,
-					their_node_id: None,
-					their_global_features: None,
-					their_local_features: None,
-
-					pending_outbound_buffer: LinkedList::new(),
-					pending_outbound_buffer_first_msg_offset: 0,
-					awaiting_write_event: false,
-
-					pending_read_buffer: pending_read_buffer,
-					pending_read_buffer_pos: 0,
-					pending_read_is_header: false,
-
-					sync_status: InitSyncTracker::NoSyncRequested,
-				},
-			)
-			.is_some()
+		if peers.peers.contains_key(&descriptor)
+			|| peers
+				.handshakes
+				.insert(descriptor, HandshakingPeer::new(HandshakeEncryptor::AwaitingActTwo(peer_encryptor)))
+				.is_some()
 		{
 			panic!("PeerManager driver duplicated descriptors!");
 		};
-		Ok(res)*/
-		unimplemented!()
+		Ok(act_one.to_vec())
 	}
 
 	/// Indicates a new inbound connection has been established.
@@ -336,43 +407,41 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 	/// Panics if descriptor is duplicative with some other descriptor which has not yet has a
 	/// disconnect_event.
 	pub fn new_inbound_connection(&self, descriptor: Descriptor) -> Result<(), PeerHandleError> {
-		/*
 		let peer_encryptor = PeerChannelEncryptor::new_inbound(&self.our_node_secret);
-		let pending_read_buffer = [0; 50].to_vec(); // Noise act one is 50 bytes
 
 		let mut peers = self.peers.lock().unwrap();
-		if peers
-			.peers
-			.insert(
-				descriptor,
-				Peer {
-					channel_encryptor: peer_encryptor,
-					outbound: false,
-					their_node_id: None,
-					their_global_features: None,
-					their_local_features: None,
-
-					pending_outbound_buffer: LinkedList::new(),
-					pending_outbound_buffer_first_msg_offset: 0,
-					awaiting_write_event: false,
-
-					pending_read_buffer: pending_read_buffer,
-					pending_read_buffer_pos: 0,
-					pending_read_is_header: false,
-
-					sync_status: InitSyncTracker::NoSyncRequested,
-				},
-			)
-			.is_some()
+		if peers.peers.contains_key(&descriptor)
+			|| peers
+				.handshakes
+				.insert(descriptor, HandshakingPeer::new(HandshakeEncryptor::AwaitingActOne(peer_encryptor)))
+				.is_some()
 		{
 			panic!("PeerManager driver duplicated descriptors!");
 		};
-		Ok(())*/
-		unimplemented!()
+		Ok(())
+	}
+
+	/// Gets the local features to send in our Init, asking the first INITIAL_SYNCS_TO_SEND peers
+	/// for an initial routing table sync.
+	fn init_local_features(&self) -> msgs::LocalFeatures {
+		let mut local_features = msgs::LocalFeatures::new();
+		if self.initial_syncs_sent.load(Ordering::Acquire) < INITIAL_SYNCS_TO_SEND {
+			self.initial_syncs_sent.fetch_add(1, Ordering::AcqRel);
+			local_features.set_initial_routing_sync();
+		}
+		local_features
+	}
+
+	/// Writes whatever is left of our act two to a connection which is still running the
+	/// handshake.
+	fn do_attempt_write_handshake(descriptor: &mut Descriptor, handshake: &mut HandshakingPeer) {
+		if !handshake.pending_outbound_buffer.is_empty() {
+			let data_sent = descriptor.send_data(&handshake.pending_outbound_buffer[..], true);
+			handshake.pending_outbound_buffer.drain(..data_sent);
+		}
 	}
 
 	fn do_attempt_write_data(&self, descriptor: &mut Descriptor, peer: &mut Peer<Complete>) {
-		/*
 		macro_rules! encode_and_send_msg {
 			($msg: expr, $msg_code: expr) => {{
 				log_trace!(
@@ -400,9 +469,12 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 							.route_handler
 							.get_next_channel_announcements(0, steps);
 						for &(ref announce, ref update_a, ref update_b) in all_messages.iter() {
-							encode_and_send_msg!(announce, 256);
-							encode_and_send_msg!(update_a, 258);
-							encode_and_send_msg!(update_b, 258);
+							let our_channel = announce.contents.node_id_1 == self.our_node_id || announce.contents.node_id_2 == self.our_node_id;
+							if our_channel || peer.should_forward_gossip(update_a.contents.timestamp) || peer.should_forward_gossip(update_b.contents.timestamp) {
+								encode_and_send_msg!(announce, 256);
+								encode_and_send_msg!(update_a, 258);
+								encode_and_send_msg!(update_b, 258);
+							}
 							peer.sync_status = InitSyncTracker::ChannelsSyncing(
 								announce.contents.short_channel_id + 1,
 							);
@@ -419,7 +491,9 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 							.route_handler
 							.get_next_node_announcements(None, steps);
 						for msg in all_messages.iter() {
-							encode_and_send_msg!(msg, 256);
+							if msg.contents.node_id == self.our_node_id || peer.should_forward_gossip(msg.contents.timestamp) {
+								encode_and_send_msg!(msg, 256);
+							}
 							peer.sync_status = InitSyncTracker::NodesSyncing(msg.contents.node_id);
 						}
 						if all_messages.is_empty() || all_messages.len() != steps as usize {
@@ -434,7 +508,9 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 							.route_handler
 							.get_next_node_announcements(Some(&key), steps);
 						for msg in all_messages.iter() {
-							encode_and_send_msg!(msg, 256);
+							if msg.contents.node_id == self.our_node_id || peer.should_forward_gossip(msg.contents.timestamp) {
+								encode_and_send_msg!(msg, 256);
+							}
 							peer.sync_status = InitSyncTracker::NodesSyncing(msg.contents.node_id);
 						}
 						if all_messages.is_empty() || all_messages.len() != steps as usize {
@@ -450,7 +526,7 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 					Some(buff) => buff,
 				};
 
-				let should_be_reading = peer.pending_outbound_buffer.len() < MSG_BUFF_SIZE;
+				let should_be_reading = peer.pending_outbound_buffer.len() < MSG_BUFF_SIZE && peer.should_read();
 				let pending = &next_buff[peer.pending_outbound_buffer_first_msg_offset..];
 				let data_sent = descriptor.send_data(pending, should_be_reading);
 				peer.pending_outbound_buffer_first_msg_offset += data_sent;
@@ -465,8 +541,7 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 			} else {
 				peer.awaiting_write_event = true;
 			}
-		}*/
-		unimplemented!()
+		}
 	}
 
 	/// Indicates that there is room to write data to the given socket descriptor.
@@ -480,6 +555,10 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 	/// Panics if the descriptor was not previously registered in a new_\*_connection event.
 	pub fn write_event(&self, descriptor: &mut Descriptor) -> Result<(), PeerHandleError> {
 		let mut peers = self.peers.lock().unwrap();
+		if let Some(handshake) = peers.handshakes.get_mut(descriptor) {
+			Self::do_attempt_write_handshake(descriptor, handshake);
+			return Ok(());
+		}
 		match peers.peers.get_mut(descriptor) {
 			None => panic!("Descriptor for write_event is not already known to PeerManager"),
 			Some(peer) => {
@@ -521,6 +600,11 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 		peer_descriptor: &mut Descriptor,
 		data: Vec<u8>,
 	) -> Result<bool, PeerHandleError> {
+		let handshake_read_len = match self.read_handshake(peer_descriptor, &data[..])? {
+			Some(read_len) => read_len,
+			None => return Ok(false),
+		};
+
 		let pause_read = {
 			let mut peers_lock = self.peers.lock().unwrap();
 			let peers = peers_lock.borrow_parts();
@@ -530,596 +614,216 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 					assert!(peer.pending_read_buffer.len() > 0);
 					assert!(peer.pending_read_buffer.len() > peer.pending_read_buffer_pos);
 
-					let mut read_pos = 0;
-					while read_pos < data.len() {
-						{
-							let data_to_copy = cmp::min(
-								peer.pending_read_buffer.len() - peer.pending_read_buffer_pos,
-								data.len() - read_pos,
-							);
-							peer.pending_read_buffer[peer.pending_read_buffer_pos
-								..peer.pending_read_buffer_pos + data_to_copy]
-								.copy_from_slice(&data[read_pos..read_pos + data_to_copy]);
-							read_pos += data_to_copy;
-							peer.pending_read_buffer_pos += data_to_copy;
-						}
-
-						if peer.pending_read_buffer_pos == peer.pending_read_buffer.len() {
-							peer.pending_read_buffer_pos = 0;
-
-							macro_rules! encode_and_send_msg {
-								($msg: expr, $msg_code: expr) => {{
-									log_trace!(
-										self,
-										"Encoding and sending message of type {} to {}",
-										$msg_code,
-										log_pubkey!(peer.their_node_id.unwrap())
-										);
-									peer.pending_outbound_buffer.push_back(
-										peer.channel_encryptor
-											.encrypt_message(&encode_msg!($msg, $msg_code)[..]),
-										);
-									peers.peers_needing_send.insert(peer_descriptor.clone());
-									}};
-							}
+					macro_rules! encode_and_send_msg {
+						($msg: expr, $msg_code: expr) => {{
+							log_trace!(self, "Encoding and sending message of type {} to {}", $msg_code, log_pubkey!(peer.their_node_id.unwrap()));
+							peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!($msg, $msg_code)[..]));
+							peers.peers_needing_send.insert(peer_descriptor.clone());
+						}}
+					}
 
-							macro_rules! try_potential_handleerror {
-								($thing: expr) => {
-									match $thing {
-										Ok(x) => x,
-										Err(e) => {
-											if let Some(action) = e.action {
-												match action {
-													msgs::ErrorAction::DisconnectPeer { msg: _ } => {
-														//TODO: Try to push msg
-														log_trace!(self, "Got Err handling message, disconnecting peer because {}", e.err);
-														return Err(PeerHandleError{ no_connection_possible: false });
-													},
-													msgs::ErrorAction::IgnoreError => {
-														log_trace!(self, "Got Err handling message, ignoring because {}", e.err);
-														continue;
-													},
-													msgs::ErrorAction::SendErrorMessage { msg } => {
-														log_trace!(self, "Got Err handling message, sending Error message because {}", e.err);
-														encode_and_send_msg!(msg, 17);
-														continue;
-													},
-												}
-											} else {
-												log_debug!(self, "Got Err handling message, action not yet filled in: {}", e.err);
+					macro_rules! try_potential_handleerror {
+						($thing: expr) => {
+							match $thing {
+								Ok(x) => x,
+								Err(e) => {
+									if let Some(action) = e.action {
+										match action {
+											msgs::ErrorAction::DisconnectPeer { msg: _ } => {
+												//TODO: Try to push msg
+												log_trace!(self, "Got Err handling message, disconnecting peer because {}", e.err);
 												return Err(PeerHandleError{ no_connection_possible: false });
-											}
+											},
+											msgs::ErrorAction::IgnoreError => {
+												log_trace!(self, "Got Err handling message, ignoring because {}", e.err);
+												continue;
+											},
+											msgs::ErrorAction::SendErrorMessage { msg } => {
+												log_trace!(self, "Got Err handling message, sending Error message because {}", e.err);
+												encode_and_send_msg!(msg, 17);
+												continue;
+											},
 										}
-									};
+									} else {
+										log_debug!(self, "Got Err handling message, action not yet filled in: {}", e.err);
+										return Err(PeerHandleError{ no_connection_possible: false });
+									}
 								}
 							}
+						}
+					}
 
-							macro_rules! try_potential_decodeerror {
-								($thing: expr) => {
-									match $thing {
-										Ok(x) => x,
-										Err(e) => {
-											match e {
-												msgs::DecodeError::UnknownVersion => return Err(PeerHandleError{ no_connection_possible: false }),
-												msgs::DecodeError::UnknownRequiredFeature => {
-													log_debug!(self, "Got a channel/node announcement with an known required feature flag, you may want to update!");
-													continue;
-												},
-												msgs::DecodeError::InvalidValue => {
-													log_debug!(self, "Got an invalid value while deserializing message");
-													return Err(PeerHandleError{ no_connection_possible: false });
-												},
-												msgs::DecodeError::ShortRead => {
-													log_debug!(self, "Deserialization failed due to shortness of message");
-													return Err(PeerHandleError{ no_connection_possible: false });
-												},
-												msgs::DecodeError::ExtraAddressesPerType => {
-													log_debug!(self, "Error decoding message, ignoring due to lnd spec incompatibility. See https://github.com/lightningnetwork/lnd/issues/1407");
-													continue;
-												},
-												msgs::DecodeError::BadLengthDescriptor => return Err(PeerHandleError{ no_connection_possible: false }),
-												msgs::DecodeError::Io(_) => return Err(PeerHandleError{ no_connection_possible: false }),
-											}
-										}
-									};
-								}
+					let mut read_pos = handshake_read_len;
+					while let Some(message) = self.decode_next_frame(peer, &data[..], &mut read_pos)? {
+						log_trace!(self, "Received message of type {} from {}", message.type_id(), log_pubkey!(peer.their_node_id.unwrap()));
+						if peer.their_global_features.is_none() {
+							if let msgs::Message::Init(_) = message {} else {
+								// Need an init message as first message
+								log_trace!(self, "Peer {} sent non-Init first message", log_pubkey!(peer.their_node_id.unwrap()));
+								return Err(PeerHandleError{ no_connection_possible: false });
 							}
+						}
 
-							macro_rules! insert_node_id {
-								() => {
-									match peers.node_id_to_descriptor.entry(peer.their_node_id.unwrap()) {
-										hash_map::Entry::Occupied(_) => {
-											log_trace!(self, "Got second connection with {}, closing", log_pubkey!(peer.their_node_id.unwrap()));
-											peer.their_node_id = None; // Unset so that we don't generate a peer_disconnected event
-											return Err(PeerHandleError{ no_connection_possible: false })
-										},
-										hash_map::Entry::Vacant(entry) => {
-											log_trace!(self, "Finished noise handshake for connection with {}", log_pubkey!(peer.their_node_id.unwrap()));
-											entry.insert(peer_descriptor.clone())
-										},
-									};
+						match message {
+							// Connection control:
+							msgs::Message::Init(msg) => {
+								if msg.global_features.requires_unknown_bits() {
+									log_info!(self, "Peer global features required unknown version bits");
+									return Err(PeerHandleError{ no_connection_possible: true });
+								}
+								if msg.local_features.requires_unknown_bits() {
+									log_info!(self, "Peer local features required unknown version bits");
+									return Err(PeerHandleError{ no_connection_possible: true });
+								}
+								if peer.their_global_features.is_some() {
+									return Err(PeerHandleError{ no_connection_possible: false });
 								}
-							}
 
-							/*let next_step = peer.channel_encryptor.get_noise_step();
-							match next_step {
-								NextNoiseStep::ActOne => {
-									let act_two = try_potential_handleerror!(peer
-										.channel_encryptor
-										.process_act_one_with_keys(
-											&peer.pending_read_buffer[..],
-											&self.our_node_secret,
-											self.get_ephemeral_key()
-										))
-									.to_vec();
-									peer.pending_outbound_buffer.push_back(act_two);
-									peer.pending_read_buffer = [0; 66].to_vec(); // act three is 66 bytes long
+								log_info!(self, "Received peer Init message: data_loss_protect: {}, initial_routing_sync: {}, upfront_shutdown_script: {}, gossip_queries: {}, unkown local flags: {}, unknown global flags: {}",
+									if msg.local_features.supports_data_loss_protect() { "supported" } else { "not supported"},
+									if msg.local_features.initial_routing_sync() { "requested" } else { "not requested" },
+									if msg.local_features.supports_upfront_shutdown_script() { "supported" } else { "not supported"},
+									if msg.local_features.supports_gossip_queries() { "supported" } else { "not supported"},
+									if msg.local_features.supports_unknown_bits() { "present" } else { "none" },
+									if msg.global_features.supports_unknown_bits() { "present" } else { "none" });
+
+								if msg.local_features.initial_routing_sync() {
+									peer.sync_status = InitSyncTracker::ChannelsSyncing(0);
+									peers.peers_needing_send.insert(peer_descriptor.clone());
 								}
-								NextNoiseStep::ActTwo => {
-									let (act_three, their_node_id) =
-										try_potential_handleerror!(peer
-											.channel_encryptor
-											.process_act_two(
-												&peer.pending_read_buffer[..],
-												&self.our_node_secret
-											));
-									peer.pending_outbound_buffer.push_back(act_three.to_vec());
-									peer.pending_read_buffer = [0; 18].to_vec(); // Message length header is 18 bytes
-									peer.pending_read_is_header = true;
-
-									peer.their_node_id = Some(their_node_id);
-									insert_node_id!();
-									let mut local_features = msgs::LocalFeatures::new();
-									if self.initial_syncs_sent.load(Ordering::Acquire)
-										< INITIAL_SYNCS_TO_SEND
-									{
-										self.initial_syncs_sent.fetch_add(1, Ordering::AcqRel);
-										local_features.set_initial_routing_sync();
+								peer.their_global_features = Some(msg.global_features);
+								peer.their_local_features = Some(msg.local_features);
+
+								if !peer.outbound {
+									encode_and_send_msg!(msgs::Init {
+										global_features: msgs::GlobalFeatures::new(),
+										local_features: self.init_local_features(),
+									}, 16);
+								}
+
+								self.message_handler.chan_handler.peer_connected(&peer.their_node_id.unwrap());
+							},
+							msgs::Message::Error(msg) => {
+								let mut data_is_printable = true;
+								for b in msg.data.bytes() {
+									if b < 32 || b > 126 {
+										data_is_printable = false;
+										break;
 									}
-									encode_and_send_msg!(
-										msgs::Init {
-											global_features: msgs::GlobalFeatures::new(),
-											local_features,
-										},
-										16
-									);
 								}
-								NextNoiseStep::ActThree => {
-									let their_node_id = try_potential_handleerror!(peer
-										.channel_encryptor
-										.process_act_three(&peer.pending_read_buffer[..]));
-									peer.pending_read_buffer = [0; 18].to_vec(); // Message length header is 18 bytes
-									peer.pending_read_is_header = true;
-									peer.their_node_id = Some(their_node_id);
-									insert_node_id!();
+
+								if data_is_printable {
+									log_debug!(self, "Got Err message from {}: {}", log_pubkey!(peer.their_node_id.unwrap()), msg.data);
+								} else {
+									log_debug!(self, "Got Err message from {} with non-ASCII error message", log_pubkey!(peer.their_node_id.unwrap()));
 								}
-								NextNoiseStep::NoiseComplete => {
-									if peer.pending_read_is_header {
-										let msg_len = try_potential_handleerror!(peer
-											.channel_encryptor
-											.decrypt_length_header(&peer.pending_read_buffer[..]));
-										peer.pending_read_buffer =
-											Vec::with_capacity(msg_len as usize + 16);
-										peer.pending_read_buffer.resize(msg_len as usize + 16, 0);
-										if msg_len < 2 {
-											// Need at least the message type tag
-											return Err(PeerHandleError {
-												no_connection_possible: false,
-											});
-										}
-										peer.pending_read_is_header = false;
-									} else {
-										let msg_data = try_potential_handleerror!(peer
-											.channel_encryptor
-											.decrypt_message(&peer.pending_read_buffer[..]));
-										assert!(msg_data.len() >= 2);
-
-										// Reset read buffer
-										peer.pending_read_buffer = [0; 18].to_vec();
-										peer.pending_read_is_header = true;
-
-										let msg_type = byte_utils::slice_to_be16(&msg_data[0..2]);
-										log_trace!(
-											self,
-											"Received message of type {} from {}",
-											msg_type,
-											log_pubkey!(peer.their_node_id.unwrap())
-										);
-										if msg_type != 16 && peer.their_global_features.is_none() {
-											// Need an init message as first message
-											log_trace!(
-												self,
-												"Peer {} sent non-Init first message",
-												log_pubkey!(peer.their_node_id.unwrap())
-											);
-											return Err(PeerHandleError {
-												no_connection_possible: false,
-											});
-										}
-										let mut reader = ::std::io::Cursor::new(&msg_data[2..]);
-										match msg_type {
-											// Connection control:
-											16 => {
-												let msg = try_potential_decodeerror!(
-													msgs::Init::read(&mut reader)
-												);
-												if msg.global_features.requires_unknown_bits() {
-													log_info!(self, "Peer global features required unknown version bits");
-													return Err(PeerHandleError {
-														no_connection_possible: true,
-													});
-												}
-												if msg.local_features.requires_unknown_bits() {
-													log_info!(self, "Peer local features required unknown version bits");
-													return Err(PeerHandleError {
-														no_connection_possible: true,
-													});
-												}
-												if peer.their_global_features.is_some() {
-													return Err(PeerHandleError {
-														no_connection_possible: false,
-													});
-												}
-
-												log_info!(self, "Received peer Init message: data_loss_protect: {}, initial_routing_sync: {}, upfront_shutdown_script: {}, unkown local flags: {}, unknown global flags: {}",
-													if msg.local_features.supports_data_loss_protect() { "supported" } else { "not supported"},
-													if msg.local_features.initial_routing_sync() { "requested" } else { "not requested" },
-													if msg.local_features.supports_upfront_shutdown_script() { "supported" } else { "not supported"},
-													if msg.local_features.supports_unknown_bits() { "present" } else { "none" },
-													if msg.global_features.supports_unknown_bits() { "present" } else { "none" });
-
-												if msg.local_features.initial_routing_sync() {
-													peer.sync_status =
-														InitSyncTracker::ChannelsSyncing(0);
-													peers
-														.peers_needing_send
-														.insert(peer_descriptor.clone());
-												}
-												peer.their_global_features =
-													Some(msg.global_features);
-												peer.their_local_features =
-													Some(msg.local_features);
-
-												if !peer.outbound {
-													let mut local_features =
-														msgs::LocalFeatures::new();
-													if self
-														.initial_syncs_sent
-														.load(Ordering::Acquire)
-														< INITIAL_SYNCS_TO_SEND
-													{
-														self.initial_syncs_sent
-															.fetch_add(1, Ordering::AcqRel);
-														local_features.set_initial_routing_sync();
-													}
-
-													encode_and_send_msg!(
-														msgs::Init {
-															global_features:
-																msgs::GlobalFeatures::new(),
-															local_features,
-														},
-														16
-													);
-												}
-
-												self.message_handler
-													.chan_handler
-													.peer_connected(&peer.their_node_id.unwrap());
-											}
-											17 => {
-												let msg = try_potential_decodeerror!(
-													msgs::ErrorMessage::read(&mut reader)
-												);
-												let mut data_is_printable = true;
-												for b in msg.data.bytes() {
-													if b < 32 || b > 126 {
-														data_is_printable = false;
-														break;
-													}
-												}
-
-												if data_is_printable {
-													log_debug!(
-														self,
-														"Got Err message from {}: {}",
-														log_pubkey!(peer.their_node_id.unwrap()),
-														msg.data
-													);
-												} else {
-													log_debug!(self, "Got Err message from {} with non-ASCII error message", log_pubkey!(peer.their_node_id.unwrap()));
-												}
-												self.message_handler.chan_handler.handle_error(
-													&peer.their_node_id.unwrap(),
-													&msg,
-												);
-												if msg.channel_id == [0; 32] {
-													return Err(PeerHandleError {
-														no_connection_possible: true,
-													});
-												}
-											}
+								self.message_handler.chan_handler.handle_error(&peer.their_node_id.unwrap(), &msg);
+								if msg.channel_id == [0; 32] {
+									return Err(PeerHandleError{ no_connection_possible: true });
+								}
+							},
+							msgs::Message::Ping(msg) => {
+								if msg.ponglen < 65532 {
+									let resp = msgs::Pong { byteslen: msg.ponglen };
+									encode_and_send_msg!(resp, 19);
+								}
+							},
+							msgs::Message::Pong(_) => {},
 
-											18 => {
-												let msg = try_potential_decodeerror!(
-													msgs::Ping::read(&mut reader)
-												);
-												if msg.ponglen < 65532 {
-													let resp = msgs::Pong {
-														byteslen: msg.ponglen,
-													};
-													encode_and_send_msg!(resp, 19);
-												}
-											}
-											19 => {
-												try_potential_decodeerror!(msgs::Pong::read(
-													&mut reader
-												));
-											}
+							// Channel control:
+							msgs::Message::OpenChannel(msg) => {
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_open_channel(&peer.their_node_id.unwrap(), peer.their_local_features.clone().unwrap(), &msg));
+							},
+							msgs::Message::AcceptChannel(msg) => {
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_accept_channel(&peer.their_node_id.unwrap(), peer.their_local_features.clone().unwrap(), &msg));
+							},
 
-											// Channel control:
-											32 => {
-												let msg = try_potential_decodeerror!(
-													msgs::OpenChannel::read(&mut reader)
-												);
-												try_potential_handleerror!(self
-													.message_handler
-													.chan_handler
-													.handle_open_channel(
-														&peer.their_node_id.unwrap(),
-														peer.their_local_features.clone().unwrap(),
-														&msg
-													));
-											}
-											33 => {
-												let msg = try_potential_decodeerror!(
-													msgs::AcceptChannel::read(&mut reader)
-												);
-												try_potential_handleerror!(self
-													.message_handler
-													.chan_handler
-													.handle_accept_channel(
-														&peer.their_node_id.unwrap(),
-														peer.their_local_features.clone().unwrap(),
-														&msg
-													));
-											}
+							msgs::Message::FundingCreated(msg) => {
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_funding_created(&peer.their_node_id.unwrap(), &msg));
+							},
+							msgs::Message::FundingSigned(msg) => {
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_funding_signed(&peer.their_node_id.unwrap(), &msg));
+							},
+							msgs::Message::FundingLocked(msg) => {
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_funding_locked(&peer.their_node_id.unwrap(), &msg));
+							},
 
-											34 => {
-												let msg = try_potential_decodeerror!(
-													msgs::FundingCreated::read(&mut reader)
-												);
-												try_potential_handleerror!(self
-													.message_handler
-													.chan_handler
-													.handle_funding_created(
-														&peer.their_node_id.unwrap(),
-														&msg
-													));
-											}
-											35 => {
-												let msg = try_potential_decodeerror!(
-													msgs::FundingSigned::read(&mut reader)
-												);
-												try_potential_handleerror!(self
-													.message_handler
-													.chan_handler
-													.handle_funding_signed(
-														&peer.their_node_id.unwrap(),
-														&msg
-													));
-											}
-											36 => {
-												let msg = try_potential_decodeerror!(
-													msgs::FundingLocked::read(&mut reader)
-												);
-												try_potential_handleerror!(self
-													.message_handler
-													.chan_handler
-													.handle_funding_locked(
-														&peer.their_node_id.unwrap(),
-														&msg
-													));
-											}
+							msgs::Message::Shutdown(msg) => {
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_shutdown(&peer.their_node_id.unwrap(), &msg));
+							},
+							msgs::Message::ClosingSigned(msg) => {
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_closing_signed(&peer.their_node_id.unwrap(), &msg));
+							},
 
-											38 => {
-												let msg = try_potential_decodeerror!(
-													msgs::Shutdown::read(&mut reader)
-												);
-												try_potential_handleerror!(self
-													.message_handler
-													.chan_handler
-													.handle_shutdown(
-														&peer.their_node_id.unwrap(),
-														&msg
-													));
-											}
-											39 => {
-												let msg = try_potential_decodeerror!(
-													msgs::ClosingSigned::read(&mut reader)
-												);
-												try_potential_handleerror!(self
-													.message_handler
-													.chan_handler
-													.handle_closing_signed(
-														&peer.their_node_id.unwrap(),
-														&msg
-													));
-											}
+							msgs::Message::UpdateAddHTLC(msg) => {
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_update_add_htlc(&peer.their_node_id.unwrap(), &msg));
+							},
+							msgs::Message::UpdateFulfillHTLC(msg) => {
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_update_fulfill_htlc(&peer.their_node_id.unwrap(), &msg));
+							},
+							msgs::Message::UpdateFailHTLC(msg) => {
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_update_fail_htlc(&peer.their_node_id.unwrap(), &msg));
+							},
+							msgs::Message::UpdateFailMalformedHTLC(msg) => {
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_update_fail_malformed_htlc(&peer.their_node_id.unwrap(), &msg));
+							},
 
-											128 => {
-												let msg = try_potential_decodeerror!(
-													msgs::UpdateAddHTLC::read(&mut reader)
-												);
-												try_potential_handleerror!(self
-													.message_handler
-													.chan_handler
-													.handle_update_add_htlc(
-														&peer.their_node_id.unwrap(),
-														&msg
-													));
-											}
-											130 => {
-												let msg = try_potential_decodeerror!(
-													msgs::UpdateFulfillHTLC::read(&mut reader)
-												);
-												try_potential_handleerror!(self
-													.message_handler
-													.chan_handler
-													.handle_update_fulfill_htlc(
-														&peer.their_node_id.unwrap(),
-														&msg
-													));
-											}
-											131 => {
-												let msg = try_potential_decodeerror!(
-													msgs::UpdateFailHTLC::read(&mut reader)
-												);
-												try_potential_handleerror!(self
-													.message_handler
-													.chan_handler
-													.handle_update_fail_htlc(
-														&peer.their_node_id.unwrap(),
-														&msg
-													));
-											}
-											135 => {
-												let msg = try_potential_decodeerror!(
-													msgs::UpdateFailMalformedHTLC::read(
-														&mut reader
-													)
-												);
-												try_potential_handleerror!(self
-													.message_handler
-													.chan_handler
-													.handle_update_fail_malformed_htlc(
-														&peer.their_node_id.unwrap(),
-														&msg
-													));
-											}
+							msgs::Message::CommitmentSigned(msg) => {
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_commitment_signed(&peer.their_node_id.unwrap(), &msg));
+							},
+							msgs::Message::RevokeAndACK(msg) => {
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_revoke_and_ack(&peer.their_node_id.unwrap(), &msg));
+							},
+							msgs::Message::UpdateFee(msg) => {
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_update_fee(&peer.their_node_id.unwrap(), &msg));
+							},
+							msgs::Message::ChannelReestablish(msg) => {
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_channel_reestablish(&peer.their_node_id.unwrap(), &msg));
+							},
 
-											132 => {
-												let msg = try_potential_decodeerror!(
-													msgs::CommitmentSigned::read(&mut reader)
-												);
-												try_potential_handleerror!(self
-													.message_handler
-													.chan_handler
-													.handle_commitment_signed(
-														&peer.their_node_id.unwrap(),
-														&msg
-													));
-											}
-											133 => {
-												let msg = try_potential_decodeerror!(
-													msgs::RevokeAndACK::read(&mut reader)
-												);
-												try_potential_handleerror!(self
-													.message_handler
-													.chan_handler
-													.handle_revoke_and_ack(
-														&peer.their_node_id.unwrap(),
-														&msg
-													));
-											}
-											134 => {
-												let msg = try_potential_decodeerror!(
-													msgs::UpdateFee::read(&mut reader)
-												);
-												try_potential_handleerror!(self
-													.message_handler
-													.chan_handler
-													.handle_update_fee(
-														&peer.their_node_id.unwrap(),
-														&msg
-													));
-											}
-											136 => {
-												let msg = try_potential_decodeerror!(
-													msgs::ChannelReestablish::read(&mut reader)
-												);
-												try_potential_handleerror!(self
-													.message_handler
-													.chan_handler
-													.handle_channel_reestablish(
-														&peer.their_node_id.unwrap(),
-														&msg
-													));
-											}
+							// Routing control:
+							msgs::Message::AnnouncementSignatures(msg) => {
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_announcement_signatures(&peer.their_node_id.unwrap(), &msg));
+							},
+							msgs::Message::ChannelAnnouncement(msg) => {
+								let should_forward = try_potential_handleerror!(self.message_handler.route_handler.handle_channel_announcement(&msg));
 
-											// Routing control:
-											259 => {
-												let msg = try_potential_decodeerror!(
-													msgs::AnnouncementSignatures::read(&mut reader)
-												);
-												try_potential_handleerror!(self
-													.message_handler
-													.chan_handler
-													.handle_announcement_signatures(
-														&peer.their_node_id.unwrap(),
-														&msg
-													));
-											}
-											256 => {
-												let msg = try_potential_decodeerror!(
-													msgs::ChannelAnnouncement::read(&mut reader)
-												);
-												let should_forward =
-													try_potential_handleerror!(self
-														.message_handler
-														.route_handler
-														.handle_channel_announcement(&msg));
-
-												if should_forward {
-													// TODO: forward msg along to all our other peers!
-												}
-											}
-											257 => {
-												let msg = try_potential_decodeerror!(
-													msgs::NodeAnnouncement::read(&mut reader)
-												);
-												let should_forward =
-													try_potential_handleerror!(self
-														.message_handler
-														.route_handler
-														.handle_node_announcement(&msg));
-
-												if should_forward {
-													// TODO: forward msg along to all our other peers!
-												}
-											}
-											258 => {
-												let msg = try_potential_decodeerror!(
-													msgs::ChannelUpdate::read(&mut reader)
-												);
-												let should_forward =
-													try_potential_handleerror!(self
-														.message_handler
-														.route_handler
-														.handle_channel_update(&msg));
-
-												if should_forward {
-													// TODO: forward msg along to all our other peers!
-												}
-											}
-											_ => {
-												if (msg_type & 1) == 0 {
-													return Err(PeerHandleError {
-														no_connection_possible: true,
-													});
-												}
-											}
-										}
-									}
+								if should_forward {
+									// TODO: forward msg along to all our other peers!
+								}
+							},
+							msgs::Message::NodeAnnouncement(msg) => {
+								let should_forward = try_potential_handleerror!(self.message_handler.route_handler.handle_node_announcement(&msg));
+
+								if should_forward {
+									// TODO: forward msg along to all our other peers!
+								}
+							},
+							msgs::Message::ChannelUpdate(msg) => {
+								// Updates for our private channels are unknown to the Router, which ignores
+								// them, so the ChannelManager has to see them first.
+								try_potential_handleerror!(self.message_handler.chan_handler.handle_channel_update(&peer.their_node_id.unwrap(), &msg));
+								let should_forward = try_potential_handleerror!(self.message_handler.route_handler.handle_channel_update(&msg));
+
+								if should_forward {
+									// TODO: forward msg along to all our other peers!
 								}
-							}*/
-							unimplemented!()
+							},
+							msgs::Message::GossipTimestampFilter(msg) => {
+								try_potential_handleerror!(self.message_handler.route_handler.handle_gossip_timestamp_filter(&msg));
+								peer.gossip_filter = Some(msg);
+							},
+							msgs::Message::QueryShortChannelIds(_)|msgs::Message::ReplyShortChannelIdsEnd(_)|msgs::Message::QueryChannelRange(_)|msgs::Message::ReplyChannelRange(_) => {
+								log_trace!(self, "Ignoring gossip query message, we don't answer or send queries yet");
+							},
+							// Unknown messages are filtered out by decode_next_frame
+							msgs::Message::Unknown(_) => unreachable!(),
 						}
 					}
 
 					self.do_attempt_write_data(peer_descriptor, peer);
 
-					peer.pending_outbound_buffer.len() > 10 // pause_read
+					peer.pending_outbound_buffer.len() > 10 || !peer.should_read() // pause_read
 				}
 			};
 
@@ -1129,11 +833,239 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 		Ok(pause_read)
 	}
 
+	/// Feeds data to the noise handshake of the given connection if it is still running,
+	/// processing each act once it has been read in full.
+	///
+	/// Returns None if the handshake is still running, having consumed all of data. Otherwise the
+	/// handshake has completed, and the number of bytes of data it consumed is returned; any bytes
+	/// after those are the peer's first encrypted messages.
+	fn read_handshake(&self, peer_descriptor: &Descriptor, data: &[u8]) -> Result<Option<usize>, PeerHandleError> {
+		let mut peers_lock = self.peers.lock().unwrap();
+		let peers = peers_lock.borrow_parts();
+		let handshake = match peers.handshakes.get_mut(peer_descriptor) {
+			None => return Ok(Some(0)),
+			Some(handshake) => handshake,
+		};
+		let act_len = match handshake.channel_encryptor {
+			Some(ref channel_encryptor) => channel_encryptor.act_len(),
+			None => return Err(PeerHandleError { no_connection_possible: false }),
+		};
+
+		// Until the handshake completes a peer has to wait for our reply to each act, so it may
+		// only send the rest of the current act, with the exception of act three which may be
+		// directly followed by the first message.
+		let read_len = act_len - handshake.pending_read_buffer.len();
+		if act_len != ACT_THREE_LEN && data.len() > read_len {
+			log_trace!(self, "Peer sent more data than the current handshake act, disconnecting");
+			return Err(PeerHandleError { no_connection_possible: false });
+		}
+		if data.len() < read_len {
+			handshake.pending_read_buffer.extend_from_slice(data);
+			return Ok(None);
+		}
+		handshake.pending_read_buffer.extend_from_slice(&data[..read_len]);
+		let act = mem::replace(&mut handshake.pending_read_buffer, Vec::new());
+
+		macro_rules! try_handshake_act {
+			($res: expr) => {
+				match $res {
+					Ok(res) => res,
+					Err(e) => {
+						log_trace!(self, "Noise handshake failed, disconnecting: {}", e.err);
+						return Err(PeerHandleError { no_connection_possible: false });
+					}
+				}
+			};
+		}
+
+		let (channel_encryptor, their_node_id, act_three) = match handshake.channel_encryptor.take().unwrap() {
+			HandshakeEncryptor::AwaitingActOne(channel_encryptor) => {
+				let (channel_encryptor, act_two) = try_handshake_act!(channel_encryptor
+					.process_act_one_with_keys(&act[..], &self.our_node_secret, self.get_ephemeral_key()));
+				handshake.channel_encryptor = Some(HandshakeEncryptor::AwaitingActThree(channel_encryptor));
+				handshake.pending_outbound_buffer.extend_from_slice(&act_two[..]);
+				peers.peers_needing_send.insert(peer_descriptor.clone());
+				return Ok(None);
+			}
+			HandshakeEncryptor::AwaitingActTwo(channel_encryptor) => {
+				let (channel_encryptor, act_three, their_node_id) =
+					try_handshake_act!(channel_encryptor.process_act_two(&act[..], &self.our_node_secret));
+				(channel_encryptor, their_node_id, Some(act_three))
+			}
+			HandshakeEncryptor::AwaitingActThree(channel_encryptor) => {
+				let (channel_encryptor, their_node_id) =
+					try_handshake_act!(channel_encryptor.process_act_three(&act[..]));
+				(channel_encryptor, their_node_id, None)
+			}
+		};
+
+		match peers.node_id_to_descriptor.entry(their_node_id) {
+			hash_map::Entry::Occupied(_) => {
+				log_trace!(self, "Got second connection with {}, closing", log_pubkey!(their_node_id));
+				channel_encryptor.close();
+				return Err(PeerHandleError { no_connection_possible: false });
+			}
+			hash_map::Entry::Vacant(entry) => {
+				log_trace!(self, "Finished noise handshake for connection with {}", log_pubkey!(their_node_id));
+				entry.insert(peer_descriptor.clone());
+			}
+		};
+		peers.handshakes.remove(peer_descriptor);
+
+		let mut peer = Peer {
+			channel_encryptor,
+			outbound: act_three.is_some(),
+			their_node_id: Some(their_node_id),
+			their_global_features: None,
+			their_local_features: None,
+
+			pending_outbound_buffer: LinkedList::new(),
+			pending_outbound_buffer_first_msg_offset: 0,
+			awaiting_write_event: false,
+
+			pending_read_buffer: vec![0; MSG_HEADER_LEN],
+			pending_read_buffer_pos: 0,
+			pending_read_is_header: true,
+
+			sync_status: InitSyncTracker::NoSyncRequested,
+
+			gossip_filter: None,
+		};
+		// The initiator speaks first, sending its Init right behind act three
+		if let Some(act_three) = act_three {
+			peer.pending_outbound_buffer.push_back(act_three.to_vec());
+			let init = msgs::Init {
+				global_features: msgs::GlobalFeatures::new(),
+				local_features: self.init_local_features(),
+			};
+			peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(init, 16)));
+			peers.peers_needing_send.insert(peer_descriptor.clone());
+		}
+		peers.peers.insert(peer_descriptor.clone(), peer);
+
+		Ok(Some(read_len))
+	}
+
+	/// Decrypts and decodes every complete message in data (plus whatever was left over from
+	/// previous calls) read from a peer whose handshake has completed, returning them in the order
+	/// they were received. Any trailing partial frame is kept until more data arrives, so a single
+	/// read holding a burst of gossip yields all of its messages at once.
+	///
+	/// Unlike read_event, the messages are only decoded, not handed to the message handlers.
+	/// Messages we are allowed to ignore (eg unknown odd types) are skipped. May return an Err to
+	/// indicate that the connection should be closed, in which case disconnect_event must still be
+	/// called, and any messages decoded before the bad frame are dropped.
+	///
+	/// Panics if the descriptor was not previously registered in a new_*_connection event.
+	pub fn read(&self, descriptor: &Descriptor, data: &[u8]) -> Result<Vec<msgs::Message>, PeerHandleError> {
+		let mut peers = self.peers.lock().unwrap();
+		let peer = match peers.peers.get_mut(descriptor) {
+			None => panic!("Descriptor for read is not already known to PeerManager"),
+			Some(peer) => peer,
+		};
+
+		let mut res = Vec::new();
+		let mut read_pos = 0;
+		while let Some(message) = self.decode_next_frame(peer, data, &mut read_pos)? {
+			res.push(message);
+		}
+		Ok(res)
+	}
+
+	/// Decrypts and decodes the next complete message in data from read_pos onwards (plus
+	/// whatever was left over from previous reads), advancing read_pos past the bytes consumed.
+	/// Returns None once data holds no further complete message, keeping any trailing partial
+	/// frame until more data arrives. Callers loop on this so that each message is handled before
+	/// the next frame is decrypted.
+	///
+	/// Messages we are allowed to ignore (eg unknown odd types) are skipped. An Err indicates that
+	/// the connection should be closed.
+	fn decode_next_frame(&self, peer: &mut Peer<Complete>, data: &[u8], read_pos: &mut usize) -> Result<Option<msgs::Message>, PeerHandleError> {
+		while *read_pos < data.len() {
+			let data_to_copy = cmp::min(
+				peer.pending_read_buffer.len() - peer.pending_read_buffer_pos,
+				data.len() - *read_pos,
+			);
+			peer.pending_read_buffer[peer.pending_read_buffer_pos..peer.pending_read_buffer_pos + data_to_copy]
+				.copy_from_slice(&data[*read_pos..*read_pos + data_to_copy]);
+			*read_pos += data_to_copy;
+			peer.pending_read_buffer_pos += data_to_copy;
+			if peer.pending_read_buffer_pos != peer.pending_read_buffer.len() {
+				break;
+			}
+			peer.pending_read_buffer_pos = 0;
+
+			if peer.pending_read_is_header {
+				let msg_len = match peer.channel_encryptor.decrypt_length_header(&peer.pending_read_buffer[..]) {
+					Ok(len) => len,
+					Err(e) => {
+						log_trace!(self, "Failed to decrypt message length header, disconnecting: {}", e.err);
+						return Err(PeerHandleError { no_connection_possible: false });
+					}
+				};
+				if msg_len < 2 {
+					// Need at least the message type tag
+					return Err(PeerHandleError { no_connection_possible: false });
+				}
+				peer.pending_read_buffer = vec![0; msg_len as usize + MAC_LEN];
+				peer.pending_read_is_header = false;
+				continue;
+			}
+
+			let msg_data = match peer.channel_encryptor.decrypt_message(&peer.pending_read_buffer[..]) {
+				Ok(msg_data) => msg_data,
+				Err(e) => {
+					log_trace!(self, "Failed to decrypt message, disconnecting: {}", e.err);
+					return Err(PeerHandleError { no_connection_possible: false });
+				}
+			};
+			peer.pending_read_buffer = vec![0; MSG_HEADER_LEN];
+			peer.pending_read_is_header = true;
+
+			match msgs::Message::decode(&msg_data[..]) {
+				Ok(msgs::Message::Unknown(type_id)) => {
+					if type_id & 1 == 0 {
+						log_debug!(self, "Received unknown even message of type {}, disconnecting", type_id);
+						return Err(PeerHandleError { no_connection_possible: true });
+					}
+					log_trace!(self, "Ignoring unknown odd message of type {}", type_id);
+				}
+				Ok(msg) => return Ok(Some(msg)),
+				Err(msgs::DecodeError::UnknownRequiredFeature)
+				| Err(msgs::DecodeError::ExtraAddressesPerType)
+				| Err(msgs::DecodeError::UnsupportedCompression) => {
+					log_debug!(self, "Ignoring message we could not fully decode");
+				}
+				Err(_) => {
+					log_debug!(self, "Got an invalid message, disconnecting");
+					return Err(PeerHandleError { no_connection_possible: false });
+				}
+			}
+		}
+		Ok(None)
+	}
+
+	/// Returns false if we have queued too many bytes the given peer hasn't read yet, in which case
+	/// read events for its descriptor should be paused until write events have drained the queue
+	/// and this returns true again.
+	///
+	/// Panics if the descriptor was not previously registered in a new_\*_connection event.
+	pub fn should_read_from(&self, descriptor: &Descriptor) -> bool {
+		let peers = self.peers.lock().unwrap();
+		if peers.handshakes.contains_key(descriptor) {
+			return true;
+		}
+		match peers.peers.get(descriptor) {
+			None => panic!("Descriptor for should_read_from is not already known to PeerManager"),
+			Some(peer) => peer.should_read(),
+		}
+	}
+
 	/// Checks for any events generated by our handlers and processes them. Includes sending most
 	/// response messages as well as messages generated by calls to handler functions directly (eg
 	/// functions like ChannelManager::process_pending_htlc_forward or send_payment).
 	pub fn process_events(&self) {
-		/*{
+		{
 			// TODO: There are some DoS attacks here where you can flood someone's outbound send
 			// buffer by doing things like announcing channels on another node. We should be willing to
 			// drop optional-ish messages when send buffers get full!
@@ -1391,6 +1323,22 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 						);
 						self.do_attempt_write_data(&mut descriptor, peer);
 					}
+					MessageSendEvent::SendChannelUpdate {
+						ref node_id,
+						ref msg,
+					} => {
+						log_trace!(self, "Handling SendChannelUpdate event in peer_handler for node {} for short channel id {}",
+								log_pubkey!(node_id),
+								msg.contents.short_channel_id);
+						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+							//TODO: Do whatever we're gonna do for handling dropped messages
+						});
+						peer.pending_outbound_buffer.push_back(
+							peer.channel_encryptor
+								.encrypt_message(&encode_msg!(msg, 258)),
+						);
+						self.do_attempt_write_data(&mut descriptor, peer);
+					}
 					MessageSendEvent::BroadcastChannelAnnouncement {
 						ref msg,
 						ref update_msg,
@@ -1409,9 +1357,10 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 							let encoded_msg = encode_msg!(msg, 256);
 							let encoded_update_msg = encode_msg!(update_msg, 258);
 
+							// Broadcasts from the ChannelManager are about our own channels, so they go to
+							// every peer regardless of its gossip_timestamp_filter
 							for (ref descriptor, ref mut peer) in peers.peers.iter_mut() {
-								if !peer.channel_encryptor.is_ready_for_encryption()
-									|| peer.their_global_features.is_none()
+								if peer.their_global_features.is_none()
 									|| !peer.should_forward_channel(msg.contents.short_channel_id)
 								{
 									continue;
@@ -1447,9 +1396,9 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 						{
 							let encoded_msg = encode_msg!(msg, 258);
 
+							// As with announcements, our own updates ignore gossip_timestamp_filters
 							for (ref descriptor, ref mut peer) in peers.peers.iter_mut() {
-								if !peer.channel_encryptor.is_ready_for_encryption()
-									|| peer.their_global_features.is_none()
+								if peer.their_global_features.is_none()
 									|| !peer.should_forward_channel(msg.contents.short_channel_id)
 								{
 									continue;
@@ -1526,13 +1475,16 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 			}
 
 			for mut descriptor in peers.peers_needing_send.drain() {
+				if let Some(handshake) = peers.handshakes.get_mut(&descriptor) {
+					Self::do_attempt_write_handshake(&mut descriptor, handshake);
+					continue;
+				}
 				match peers.peers.get_mut(&descriptor) {
 					Some(peer) => self.do_attempt_write_data(&mut descriptor, peer),
 					None => panic!("Inconsistent peers set state!"),
 				}
 			}
-		}*/
-		unimplemented!()
+		}
 	}
 
 	/// Indicates that the given socket descriptor's connection is now closed.
@@ -1545,39 +1497,74 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 		self.disconnect_event_internal(descriptor, false);
 	}
 
-	fn disconnect_event_internal(&self, descriptor: &Descriptor, no_connection_possible: bool) {
+	/// Indicates that the given socket descriptor's connection is now closed, dropping everything
+	/// we held for the peer: its encryptor (whose keys are wiped), any partially-read message and
+	/// any outbound bytes which were never written.
+	///
+	/// Returns the peer's node_id if its handshake had completed, in which case the caller should
+	/// drop any state of its own it keeps for that node (eg for reconnection). The same rules as
+	/// for disconnect_event apply: call exactly one of the two, and only for descriptors which
+	/// were successfully registered.
+	///
+	/// Panics if the descriptor was not previously registered in a successful new_*_connection event.
+	pub fn socket_disconnected(&self, descriptor: &Descriptor) -> Option<PublicKey> {
+		self.disconnect_event_internal(descriptor, false)
+	}
+
+	fn disconnect_event_internal(&self, descriptor: &Descriptor, no_connection_possible: bool) -> Option<PublicKey> {
 		let mut peers = self.peers.lock().unwrap();
 		peers.peers_needing_send.remove(descriptor);
+		if let Some(handshake) = peers.handshakes.remove(descriptor) {
+			if let Some(channel_encryptor) = handshake.channel_encryptor {
+				channel_encryptor.abort();
+			}
+			return None;
+		}
 		let peer_option = peers.peers.remove(descriptor);
 		match peer_option {
 			None => panic!("Descriptor for disconnect_event is not already known to PeerManager"),
-			Some(peer) => match peer.their_node_id {
-				Some(node_id) => {
-					peers.node_id_to_descriptor.remove(&node_id);
-					self.message_handler
-						.chan_handler
-						.peer_disconnected(&node_id, no_connection_possible);
+			Some(peer) => {
+				peer.channel_encryptor.close();
+				match peer.their_node_id {
+					Some(node_id) => {
+						peers.node_id_to_descriptor.remove(&node_id);
+						self.message_handler
+							.chan_handler
+							.peer_disconnected(&node_id, no_connection_possible);
+						Some(node_id)
+					}
+					None => None,
 				}
-				None => {}
-			},
-		};
+			}
+		}
 	}
 }
 
 #[cfg(test)]
 mod tests {
 	use ln::msgs;
-	use ln::peer_handler::{MessageHandler, PeerManager, SocketDescriptor};
+	use ln::peer_channel_encryptor::{Finished, PeerChannelEncryptor};
+	use ln::peer_handler::{InitSyncTracker, MessageHandler, Peer, PeerManager, SocketDescriptor, ACT_ONE_LEN, ACT_TWO_LEN, MSG_HEADER_LEN, OUTBOUND_BUFFER_LIMIT_BYTES};
 	use util::events;
 	use util::logger::Logger;
 	use util::test_utils;
 
 	use secp256k1::key::{PublicKey, SecretKey};
+	use secp256k1::Message as SecpMessage;
 	use secp256k1::Secp256k1;
 
 	use rand::{thread_rng, Rng};
 
-	use std::sync::Arc;
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::network::constants::Network;
+	use bitcoin::util::hash::BitcoinHash;
+	use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+	use bitcoin_hashes::Hash;
+
+	use std::cmp;
+	use std::collections::LinkedList;
+	use std::sync::atomic::Ordering;
+	use std::sync::{Arc, Mutex};
 
 	#[derive(PartialEq, Eq, Clone, Hash)]
 	struct FileDescriptor {
@@ -1592,7 +1579,78 @@ mod tests {
 		fn disconnect_socket(&mut self) {}
 	}
 
-	fn create_network(peer_count: usize) -> Vec<PeerManager<FileDescriptor>> {
+	/// A descriptor which keeps everything sent through it, so that it can be handed on to the
+	/// PeerManager at the other end of the connection.
+	#[derive(Clone)]
+	struct PipeDescriptor {
+		fd: u16,
+		outbound_data: Arc<Mutex<Vec<u8>>>,
+	}
+
+	impl PipeDescriptor {
+		fn new(fd: u16) -> Self {
+			PipeDescriptor { fd, outbound_data: Arc::new(Mutex::new(Vec::new())) }
+		}
+
+		fn take_outbound_data(&self) -> Vec<u8> {
+			self.outbound_data.lock().unwrap().split_off(0)
+		}
+	}
+
+	impl PartialEq for PipeDescriptor {
+		fn eq(&self, other: &Self) -> bool {
+			self.fd == other.fd
+		}
+	}
+	impl Eq for PipeDescriptor {}
+	impl ::std::hash::Hash for PipeDescriptor {
+		fn hash<H: ::std::hash::Hasher>(&self, hasher: &mut H) {
+			self.fd.hash(hasher)
+		}
+	}
+
+	impl SocketDescriptor for PipeDescriptor {
+		fn send_data(&mut self, data: &[u8], _resume_read: bool) -> usize {
+			self.outbound_data.lock().unwrap().extend_from_slice(data);
+			data.len()
+		}
+
+		fn disconnect_socket(&mut self) {}
+	}
+
+	/// A descriptor which only accepts as many bytes as its budget allows, remembering the
+	/// resume_read it was last given.
+	#[derive(Clone)]
+	struct ThrottledDescriptor {
+		send_budget: Arc<Mutex<usize>>,
+		last_resume_read: Arc<Mutex<Option<bool>>>,
+	}
+
+	impl PartialEq for ThrottledDescriptor {
+		fn eq(&self, other: &Self) -> bool {
+			Arc::ptr_eq(&self.send_budget, &other.send_budget)
+		}
+	}
+	impl Eq for ThrottledDescriptor {}
+	impl ::std::hash::Hash for ThrottledDescriptor {
+		fn hash<H: ::std::hash::Hasher>(&self, hasher: &mut H) {
+			(&*self.send_budget as *const Mutex<usize> as usize).hash(hasher)
+		}
+	}
+
+	impl SocketDescriptor for ThrottledDescriptor {
+		fn send_data(&mut self, data: &[u8], resume_read: bool) -> usize {
+			*self.last_resume_read.lock().unwrap() = Some(resume_read);
+			let mut send_budget = self.send_budget.lock().unwrap();
+			let sent = cmp::min(*send_budget, data.len());
+			*send_budget -= sent;
+			sent
+		}
+
+		fn disconnect_socket(&mut self) {}
+	}
+
+	fn create_network<Descriptor: SocketDescriptor>(peer_count: usize) -> Vec<PeerManager<Descriptor>> {
 		let mut peers = Vec::new();
 		let mut rng = thread_rng();
 		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
@@ -1619,20 +1677,32 @@ mod tests {
 		peers
 	}
 
+	/// Connects peer_b to peer_a, running the handshake and exchanging Inits through read_event,
+	/// and returns peer_a's and peer_b's descriptors for the connection.
 	fn establish_connection(
-		peer_a: &PeerManager<FileDescriptor>,
-		peer_b: &PeerManager<FileDescriptor>,
-	) {
+		peer_a: &PeerManager<PipeDescriptor>,
+		peer_b: &PeerManager<PipeDescriptor>,
+	) -> (PipeDescriptor, PipeDescriptor) {
 		let secp_ctx = Secp256k1::new();
-		let their_id = PublicKey::from_secret_key(&secp_ctx, &peer_b.our_node_secret);
-		let fd = FileDescriptor { fd: 1 };
-		peer_a.new_inbound_connection(fd.clone()).unwrap();
-		peer_a
-			.peers
-			.lock()
-			.unwrap()
-			.node_id_to_descriptor
-			.insert(their_id, fd.clone());
+		let a_id = PublicKey::from_secret_key(&secp_ctx, &peer_a.our_node_secret);
+		let mut fd_a = PipeDescriptor::new(1);
+		let mut fd_b = PipeDescriptor::new(1);
+
+		let act_one = peer_b.new_outbound_connection(a_id, fd_b.clone()).unwrap();
+		peer_a.new_inbound_connection(fd_a.clone()).unwrap();
+		assert_eq!(peer_a.read_event(&mut fd_a, act_one).unwrap(), false);
+		peer_a.process_events();
+		let act_two = fd_a.take_outbound_data();
+		assert_eq!(act_two.len(), ACT_TWO_LEN);
+
+		// Act three comes along with peer_b's Init, which peer_a answers with its own
+		peer_b.read_event(&mut fd_b, act_two).unwrap();
+		peer_b.process_events();
+		peer_a.read_event(&mut fd_a, fd_b.take_outbound_data()).unwrap();
+		peer_a.process_events();
+		peer_b.read_event(&mut fd_b, fd_a.take_outbound_data()).unwrap();
+
+		(fd_a, fd_b)
 	}
 
 	#[test]
@@ -1645,6 +1715,8 @@ mod tests {
 
 		let secp_ctx = Secp256k1::new();
 		let their_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
+		assert_eq!(peers[0].get_peer_node_ids(), vec![their_id]);
+		assert_eq!(peers[1].get_peer_node_ids(), vec![PublicKey::from_secret_key(&secp_ctx, &peers[0].our_node_secret)]);
 
 		let chan_handler = test_utils::TestChannelMessageHandler::new();
 		chan_handler
@@ -1661,4 +1733,467 @@ mod tests {
 		peers[0].process_events();
 		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 0);
 	}
+
+	#[test]
+	fn test_gossip_timestamp_filter() {
+		let our_node_secret = SecretKey::from_slice(&[42; 32]).unwrap();
+		let mut peer = Peer {
+			channel_encryptor: PeerChannelEncryptor::new_inbound(&our_node_secret),
+			outbound: false,
+			their_node_id: None,
+			their_global_features: None,
+			their_local_features: None,
+
+			pending_outbound_buffer: LinkedList::new(),
+			pending_outbound_buffer_first_msg_offset: 0,
+			awaiting_write_event: false,
+
+			pending_read_buffer: Vec::new(),
+			pending_read_buffer_pos: 0,
+			pending_read_is_header: false,
+
+			sync_status: InitSyncTracker::NoSyncRequested,
+
+			gossip_filter: None,
+		};
+
+		// Peers which didn't signal gossip_queries get everything, even if they sent a filter
+		peer.their_local_features = Some(msgs::LocalFeatures::new());
+		assert!(peer.should_forward_gossip(1_500_000_000));
+		peer.gossip_filter = Some(msgs::GossipTimestampFilter {
+			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
+			first_timestamp: 1_500_000_000,
+			timestamp_range: 1000,
+		});
+		assert!(peer.should_forward_gossip(1_499_999_999));
+
+		// Otherwise, no filter means no gossip at all
+		let mut local_features = msgs::LocalFeatures::new();
+		local_features.set_gossip_queries();
+		peer.their_local_features = Some(local_features);
+		peer.gossip_filter = None;
+		assert!(!peer.should_forward_gossip(1_500_000_000));
+
+		peer.gossip_filter = Some(msgs::GossipTimestampFilter {
+			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
+			first_timestamp: 1_500_000_000,
+			timestamp_range: 1000,
+		});
+		assert!(!peer.should_forward_gossip(1_499_999_999));
+		assert!(peer.should_forward_gossip(1_500_000_000));
+		assert!(peer.should_forward_gossip(1_500_000_999));
+		assert!(!peer.should_forward_gossip(1_500_001_000));
+
+		// A range running past u32::MAX doesn't overflow
+		peer.gossip_filter = Some(msgs::GossipTimestampFilter {
+			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
+			first_timestamp: 1,
+			timestamp_range: 0xffffffff,
+		});
+		assert!(!peer.should_forward_gossip(0));
+		assert!(peer.should_forward_gossip(0xffffffff));
+	}
+
+	/// Runs the handshake between two PeerManagers' node keys directly, returning peer_b as seen
+	/// by peer_a once the connection is established, along with peer_b's end of the connection.
+	fn finished_peer<Descriptor: SocketDescriptor>(peer_a: &PeerManager<Descriptor>, peer_b: &PeerManager<Descriptor>) -> (Peer<Finished>, PeerChannelEncryptor<Finished>) {
+		let secp_ctx = Secp256k1::new();
+		let (outbound, act_one) = PeerChannelEncryptor::new_outbound(
+			PublicKey::from_secret_key(&secp_ctx, &peer_a.our_node_secret),
+			SecretKey::from_slice(&[0x12; 32]).unwrap(),
+		).get_act_one();
+		let (inbound, act_two) = PeerChannelEncryptor::new_inbound(&peer_a.our_node_secret)
+			.process_act_one_with_keys(&act_one[..], &peer_a.our_node_secret, SecretKey::from_slice(&[0x22; 32]).unwrap())
+			.unwrap();
+		let (outbound, act_three, _) = outbound.process_act_two(&act_two[..], &peer_b.our_node_secret).unwrap();
+		let (inbound, their_node_id) = inbound.process_act_three(&act_three[..]).unwrap();
+
+		(Peer {
+			channel_encryptor: inbound,
+			outbound: false,
+			their_node_id: Some(their_node_id),
+			their_global_features: None,
+			their_local_features: None,
+
+			pending_outbound_buffer: LinkedList::new(),
+			pending_outbound_buffer_first_msg_offset: 0,
+			awaiting_write_event: false,
+
+			pending_read_buffer: vec![0; MSG_HEADER_LEN],
+			pending_read_buffer_pos: 0,
+			pending_read_is_header: true,
+
+			sync_status: InitSyncTracker::NoSyncRequested,
+
+			gossip_filter: None,
+		}, outbound)
+	}
+
+	#[test]
+	fn test_socket_disconnected_drops_peer_state() {
+		let peers = create_network(2);
+		let secp_ctx = Secp256k1::new();
+		let their_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
+
+		let (mut peer, _) = finished_peer(&peers[0], &peers[1]);
+		let pending_msg = peer.channel_encryptor.encrypt_message(b"unsent");
+		peer.pending_outbound_buffer.push_back(pending_msg);
+		peer.pending_outbound_buffer_first_msg_offset = 2;
+		peer.awaiting_write_event = true;
+		peer.pending_read_buffer = vec![0; 18];
+		peer.pending_read_buffer_pos = 5;
+
+		let fd = FileDescriptor { fd: 1 };
+		{
+			let mut peers_lock = peers[0].peers.lock().unwrap();
+			peers_lock.peers.insert(fd.clone(), peer);
+			peers_lock.node_id_to_descriptor.insert(their_id, fd.clone());
+			peers_lock.peers_needing_send.insert(fd.clone());
+		}
+		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 1);
+
+		assert_eq!(peers[0].socket_disconnected(&fd), Some(their_id));
+		let peers_lock = peers[0].peers.lock().unwrap();
+		assert!(peers_lock.peers.is_empty());
+		assert!(peers_lock.node_id_to_descriptor.is_empty());
+		assert!(peers_lock.peers_needing_send.is_empty());
+	}
+
+	#[test]
+	fn test_pre_handshake_flood_disconnects() {
+		let peers = create_network(2);
+		let secp_ctx = Secp256k1::new();
+
+		// A peer flooding us before sending act one is dropped rather than buffered
+		let mut fd = FileDescriptor { fd: 1 };
+		peers[0].new_inbound_connection(fd.clone()).unwrap();
+		assert!(peers[0].read_event(&mut fd, vec![0; 1000]).is_err());
+		{
+			let peers_lock = peers[0].peers.lock().unwrap();
+			assert!(peers_lock.handshakes.is_empty());
+			assert!(peers_lock.peers.is_empty());
+		}
+
+		// Act one may arrive in pieces, but never with a single byte more
+		peers[0].new_inbound_connection(fd.clone()).unwrap();
+		assert_eq!(peers[0].read_event(&mut fd, vec![0; ACT_ONE_LEN - 1]).unwrap(), false);
+		assert!(peers[0].read_event(&mut fd, vec![0; 2]).is_err());
+		assert!(peers[0].peers.lock().unwrap().handshakes.is_empty());
+
+		// Likewise for act two on outbound connections
+		let their_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
+		assert_eq!(peers[0].new_outbound_connection(their_id, fd.clone()).unwrap().len(), ACT_ONE_LEN);
+		assert!(peers[0].read_event(&mut fd, vec![0; 1000]).is_err());
+		assert!(peers[0].peers.lock().unwrap().handshakes.is_empty());
+	}
+
+	#[test]
+	fn test_handshake_followed_by_messages() {
+		// The initiator may send its first messages right behind act three, which we must read
+		// once the handshake completes.
+		let peers = create_network(1);
+		let secp_ctx = Secp256k1::new();
+		let our_id = PublicKey::from_secret_key(&secp_ctx, &peers[0].our_node_secret);
+		let their_node_secret = SecretKey::from_slice(&[0x11; 32]).unwrap();
+
+		let mut fd = PipeDescriptor::new(1);
+		peers[0].new_inbound_connection(fd.clone()).unwrap();
+		let (outbound, act_one) = PeerChannelEncryptor::initiate_outbound(our_id, SecretKey::from_slice(&[0x12; 32]).unwrap());
+		assert_eq!(peers[0].read_event(&mut fd, act_one.to_vec()).unwrap(), false);
+		assert!(peers[0].peers.lock().unwrap().peers.is_empty());
+		peers[0].process_events();
+		let (mut outbound, act_three, _) = outbound.process_act_two(&fd.take_outbound_data()[..], &their_node_secret).unwrap();
+
+		let mut data = act_three.to_vec();
+		let init = msgs::Message::Init(msgs::Init { global_features: msgs::GlobalFeatures::new(), local_features: msgs::LocalFeatures::new() });
+		data.extend_from_slice(&outbound.encrypt_message(&init.encode()[..]));
+		peers[0].read_event(&mut fd, data).unwrap();
+
+		let their_id = PublicKey::from_secret_key(&secp_ctx, &their_node_secret);
+		assert_eq!(peers[0].get_peer_node_ids(), vec![their_id]);
+		assert!(peers[0].peers.lock().unwrap().handshakes.is_empty());
+	}
+
+	#[test]
+	fn test_outbound_backpressure() {
+		let peers = create_network(2);
+		let fd = FileDescriptor { fd: 1 };
+		let (peer, _) = finished_peer(&peers[0], &peers[1]);
+		peers[0].peers.lock().unwrap().peers.insert(fd.clone(), peer);
+		assert!(peers[0].should_read_from(&fd));
+
+		// Queue messages the peer isn't reading until we cross the limit
+		{
+			let mut peers_lock = peers[0].peers.lock().unwrap();
+			let peer = peers_lock.peers.get_mut(&fd).unwrap();
+			while peer.should_read() {
+				let msg = peer.channel_encryptor.encrypt_message(&[0; 1000]);
+				peer.pending_outbound_buffer.push_back(msg);
+			}
+			assert!(peer.pending_outbound_bytes() >= OUTBOUND_BUFFER_LIMIT_BYTES);
+		}
+		assert!(!peers[0].should_read_from(&fd));
+
+		// A partially-written message only counts its unsent bytes
+		{
+			let mut peers_lock = peers[0].peers.lock().unwrap();
+			let peer = peers_lock.peers.get_mut(&fd).unwrap();
+			let first_len = peer.pending_outbound_buffer.front().unwrap().len();
+			let excess = peer.pending_outbound_bytes() - OUTBOUND_BUFFER_LIMIT_BYTES;
+			assert!(excess < first_len);
+			peer.pending_outbound_buffer_first_msg_offset = excess + 1;
+		}
+		assert!(peers[0].should_read_from(&fd));
+
+		// Draining the queue entirely keeps reading on
+		{
+			let mut peers_lock = peers[0].peers.lock().unwrap();
+			let peer = peers_lock.peers.get_mut(&fd).unwrap();
+			peer.pending_outbound_buffer.clear();
+			peer.pending_outbound_buffer_first_msg_offset = 0;
+			assert_eq!(peer.pending_outbound_bytes(), 0);
+		}
+		assert!(peers[0].should_read_from(&fd));
+	}
+
+	#[test]
+	fn test_write_event_backpressure() {
+		// A few large messages can exceed the byte limit while staying under the message count
+		// limit, and resume_read must still be false until enough of them have been written.
+		let peers = create_network(2);
+		let mut descriptor = ThrottledDescriptor {
+			send_budget: Arc::new(Mutex::new(0)),
+			last_resume_read: Arc::new(Mutex::new(None)),
+		};
+		let (peer, _) = finished_peer(&peers[0], &peers[1]);
+		peers[0].peers.lock().unwrap().peers.insert(descriptor.clone(), peer);
+
+		let message_len = {
+			let mut peers_lock = peers[0].peers.lock().unwrap();
+			let peer = peers_lock.peers.get_mut(&descriptor).unwrap();
+			for _ in 0..3 {
+				let msg = peer.channel_encryptor.encrypt_message(&[0; 30000]);
+				peer.pending_outbound_buffer.push_back(msg);
+			}
+			assert!(peer.pending_outbound_buffer.len() < 10);
+			peer.pending_outbound_buffer.front().unwrap().len()
+		};
+		assert!(!peers[0].should_read_from(&descriptor));
+
+		// The socket is full, so nothing gets written and the caller is told not to read
+		peers[0].write_event(&mut descriptor).unwrap();
+		assert_eq!(*descriptor.last_resume_read.lock().unwrap(), Some(false));
+		assert!(!peers[0].should_read_from(&descriptor));
+
+		// Once the first message drains we're back under the limit, and the next send_data says so
+		*descriptor.send_budget.lock().unwrap() = message_len;
+		peers[0].write_event(&mut descriptor).unwrap();
+		assert_eq!(*descriptor.send_budget.lock().unwrap(), 0);
+		assert_eq!(*descriptor.last_resume_read.lock().unwrap(), Some(true));
+		assert!(peers[0].should_read_from(&descriptor));
+		assert_eq!(peers[0].peers.lock().unwrap().peers.get(&descriptor).unwrap().pending_outbound_buffer.len(), 2);
+	}
+
+	fn dummy_channel_update(short_channel_id: u64) -> msgs::ChannelUpdate {
+		let secp_ctx = Secp256k1::new();
+		let sighash = SecpMessage::from_slice(&[42; 32]).unwrap();
+		msgs::ChannelUpdate {
+			signature: secp_ctx.sign(&sighash, &SecretKey::from_slice(&[42; 32]).unwrap()),
+			contents: msgs::UnsignedChannelUpdate {
+				chain_hash: Sha256dHash::hash(&[]),
+				short_channel_id,
+				timestamp: 1_500_000_000,
+				flags: 0,
+				cltv_expiry_delta: 144,
+				htlc_minimum_msat: 1000,
+				fee_base_msat: 1000,
+				fee_proportional_millionths: 1,
+				excess_data: Vec::new(),
+			},
+		}
+	}
+
+	fn init_message() -> msgs::Message {
+		msgs::Message::Init(msgs::Init {
+			global_features: msgs::GlobalFeatures::new(),
+			local_features: msgs::LocalFeatures::new(),
+		})
+	}
+
+	#[test]
+	fn test_read_concatenated_gossip() {
+		let mut peers = create_network(2);
+		let router = Arc::new(test_utils::TestRoutingMessageHandler::new());
+		peers[0].message_handler.route_handler = router.clone();
+		let mut fd = FileDescriptor { fd: 1 };
+		let (peer, mut remote_encryptor) = finished_peer(&peers[0], &peers[1]);
+		peers[0].peers.lock().unwrap().peers.insert(fd.clone(), peer);
+
+		let mut data = remote_encryptor.encrypt_message(&init_message().encode()[..]);
+		for scid in 1..4 {
+			let msg = msgs::Message::ChannelUpdate(dummy_channel_update(scid));
+			data.extend_from_slice(&remote_encryptor.encrypt_message(&msg.encode()[..])[..]);
+		}
+
+		// The Init and all three updates arrive in a single read, followed by the start of a fourth
+		let next_frame = remote_encryptor.encrypt_message(&msgs::Message::ChannelUpdate(dummy_channel_update(4)).encode()[..]);
+		data.extend_from_slice(&next_frame[..5]);
+		assert!(!peers[0].read_event(&mut fd, data).unwrap());
+		assert_eq!(router.chan_upds_recvd.load(Ordering::Acquire), 3);
+		{
+			let peers_lock = peers[0].peers.lock().unwrap();
+			assert!(peers_lock.peers.get(&fd).unwrap().their_global_features.is_some());
+			// We're the inbound side, so we replied with our own Init
+			assert!(peers_lock.peers_needing_send.contains(&fd));
+		}
+
+		assert!(!peers[0].read_event(&mut fd, next_frame[5..].to_vec()).unwrap());
+		assert_eq!(router.chan_upds_recvd.load(Ordering::Acquire), 4);
+	}
+
+	#[test]
+	fn test_read_decodes_messages_in_order() {
+		let peers = create_network(2);
+		let fd = FileDescriptor { fd: 1 };
+		let (peer, mut remote_encryptor) = finished_peer(&peers[0], &peers[1]);
+		peers[0].peers.lock().unwrap().peers.insert(fd.clone(), peer);
+
+		let mut data = Vec::new();
+		for scid in 1..4 {
+			let msg = msgs::Message::ChannelUpdate(dummy_channel_update(scid));
+			data.extend_from_slice(&remote_encryptor.encrypt_message(&msg.encode()[..])[..]);
+		}
+		let next_frame = remote_encryptor.encrypt_message(&msgs::Message::ChannelUpdate(dummy_channel_update(4)).encode()[..]);
+		data.extend_from_slice(&next_frame[..5]);
+
+		let messages = peers[0].read(&fd, &data[..]).unwrap();
+		assert_eq!(messages.len(), 3);
+		for (scid, message) in (1..4).zip(messages.iter()) {
+			assert!(*message == msgs::Message::ChannelUpdate(dummy_channel_update(scid)));
+		}
+
+		// The partial fourth frame is completed by the next read
+		let messages = peers[0].read(&fd, &next_frame[5..]).unwrap();
+		assert_eq!(messages.len(), 1);
+		assert!(messages[0] == msgs::Message::ChannelUpdate(dummy_channel_update(4)));
+	}
+
+	#[test]
+	fn test_read_event_handles_messages_before_bad_frame() {
+		// Each message is handled as soon as it is decoded, so a corrupt frame later in the same
+		// read doesn't stop the ones before it from being processed.
+		let mut peers = create_network(2);
+		let router = Arc::new(test_utils::TestRoutingMessageHandler::new());
+		peers[0].message_handler.route_handler = router.clone();
+		let mut fd = FileDescriptor { fd: 1 };
+		let (peer, mut remote_encryptor) = finished_peer(&peers[0], &peers[1]);
+		peers[0].peers.lock().unwrap().peers.insert(fd.clone(), peer);
+
+		let mut data = remote_encryptor.encrypt_message(&init_message().encode()[..]);
+		let msg = msgs::Message::ChannelUpdate(dummy_channel_update(1));
+		data.extend_from_slice(&remote_encryptor.encrypt_message(&msg.encode()[..])[..]);
+		let mut bad_frame = remote_encryptor.encrypt_message(&msg.encode()[..]);
+		let last = bad_frame.len() - 1;
+		bad_frame[last] ^= 1;
+		data.extend_from_slice(&bad_frame[..]);
+
+		assert!(peers[0].read_event(&mut fd, data).is_err());
+		assert_eq!(router.chan_upds_recvd.load(Ordering::Acquire), 1);
+		assert!(peers[0].peers.lock().unwrap().peers.is_empty());
+	}
+
+	#[test]
+	fn test_non_init_first_message_disconnects() {
+		let mut peers = create_network(2);
+		let router = Arc::new(test_utils::TestRoutingMessageHandler::new());
+		peers[0].message_handler.route_handler = router.clone();
+		let mut fd = FileDescriptor { fd: 1 };
+		let (peer, mut remote_encryptor) = finished_peer(&peers[0], &peers[1]);
+		peers[0].peers.lock().unwrap().peers.insert(fd.clone(), peer);
+
+		let msg = msgs::Message::ChannelUpdate(dummy_channel_update(1));
+		let data = remote_encryptor.encrypt_message(&msg.encode()[..]);
+		assert!(peers[0].read_event(&mut fd, data).is_err());
+		assert_eq!(router.chan_upds_recvd.load(Ordering::Acquire), 0);
+		assert!(peers[0].peers.lock().unwrap().peers.is_empty());
+	}
+
+	fn decrypt_frame(encryptor: &mut PeerChannelEncryptor<Finished>, frame: &[u8]) -> msgs::Message {
+		encryptor.decrypt_length_header(&frame[..MSG_HEADER_LEN]).unwrap();
+		let msg_data = encryptor.decrypt_message(&frame[MSG_HEADER_LEN..]).unwrap();
+		msgs::Message::decode(&msg_data[..]).unwrap()
+	}
+
+	#[test]
+	fn test_gossip_timestamp_filter_relay() {
+		let mut peers = create_network(2);
+		let chan_handler = Arc::new(test_utils::TestChannelMessageHandler::new());
+		peers[0].message_handler.chan_handler = chan_handler.clone();
+		peers[0].message_handler.route_handler = Arc::new(test_utils::TestRoutingMessageHandler::new());
+		let fd = FileDescriptor { fd: 1 };
+		let mut read_fd = fd.clone();
+		let (peer, mut remote_encryptor) = finished_peer(&peers[0], &peers[1]);
+		peers[0].peers.lock().unwrap().peers.insert(fd.clone(), peer);
+
+		let in_range = dummy_channel_update(1);
+		let mut out_of_range = dummy_channel_update(2);
+		out_of_range.contents.timestamp = 1_500_001_000;
+		let broadcast_updates = || {
+			let mut events = chan_handler.pending_events.lock().unwrap();
+			events.push(events::MessageSendEvent::BroadcastChannelUpdate { msg: in_range.clone() });
+			events.push(events::MessageSendEvent::BroadcastChannelUpdate { msg: out_of_range.clone() });
+		};
+		// Keep whatever we queue for the peer in its outbound buffer
+		let queued_messages = || {
+			let mut peers_lock = peers[0].peers.lock().unwrap();
+			let peer = peers_lock.peers.get_mut(&fd).unwrap();
+			peer.awaiting_write_event = true;
+			::std::mem::replace(&mut peer.pending_outbound_buffer, LinkedList::new()).into_iter().collect::<Vec<_>>()
+		};
+
+		let assert_both_updates_queued = |queued: Vec<Vec<u8>>, remote_encryptor: &mut PeerChannelEncryptor<Finished>| {
+			assert_eq!(queued.len(), 2);
+			assert!(decrypt_frame(remote_encryptor, &queued[0]) == msgs::Message::ChannelUpdate(in_range.clone()));
+			assert!(decrypt_frame(remote_encryptor, &queued[1]) == msgs::Message::ChannelUpdate(out_of_range.clone()));
+		};
+
+		queued_messages();
+		let mut local_features = msgs::LocalFeatures::new();
+		local_features.set_gossip_queries();
+		let init = msgs::Message::Init(msgs::Init { global_features: msgs::GlobalFeatures::new(), local_features });
+		let data = remote_encryptor.encrypt_message(&init.encode()[..]);
+		assert!(!peers[0].read_event(&mut read_fd, data).unwrap());
+		let queued = queued_messages();
+		assert_eq!(queued.len(), 1);
+		assert_eq!(decrypt_frame(&mut remote_encryptor, &queued[0]).type_id(), 16);
+
+		// Our own gossip is sent even though the gossip_queries peer hasn't sent a filter yet
+		broadcast_updates();
+		peers[0].process_events();
+		assert_both_updates_queued(queued_messages(), &mut remote_encryptor);
+
+		// A filter for another chain is ignored
+		let filter = msgs::Message::GossipTimestampFilter(msgs::GossipTimestampFilter {
+			chain_hash: genesis_block(Network::Bitcoin).header.bitcoin_hash(),
+			first_timestamp: 1_500_000_000,
+			timestamp_range: 1000,
+		});
+		let data = remote_encryptor.encrypt_message(&filter.encode()[..]);
+		assert!(!peers[0].read_event(&mut read_fd, data).unwrap());
+		assert!(peers[0].peers.lock().unwrap().peers.get(&fd).unwrap().gossip_filter.is_none());
+
+		let filter = msgs::Message::GossipTimestampFilter(msgs::GossipTimestampFilter {
+			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
+			first_timestamp: 1_500_000_000,
+			timestamp_range: 1000,
+		});
+		let data = remote_encryptor.encrypt_message(&filter.encode()[..]);
+		assert!(!peers[0].read_event(&mut read_fd, data).unwrap());
+		assert!(peers[0].peers.lock().unwrap().peers.get(&fd).unwrap().gossip_filter.is_some());
+
+		// And once it has, our own gossip still isn't filtered
+		broadcast_updates();
+		peers[0].process_events();
+		assert_both_updates_queued(queued_messages(), &mut remote_encryptor);
+	}
 }
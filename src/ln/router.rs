@@ -4,24 +4,30 @@
 //! interrogate it to get routes for your own payments.
 
 use secp256k1::key::PublicKey;
-use secp256k1::Secp256k1;
+use secp256k1::{Secp256k1, Signature};
 use secp256k1;
 
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
-use bitcoin_hashes::Hash;
+use bitcoin_hashes::{Hash, HashEngine};
 use bitcoin::blockdata::script::Builder;
 use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::network::constants::Network;
+use bitcoin::util::hash::BitcoinHash;
 
 use chain::chaininterface::{ChainError, ChainWatchInterface};
 use ln::channelmanager;
+use ln::invoice::{Invoice, DEFAULT_MIN_FINAL_CLTV_EXPIRY};
 use ln::msgs::{DecodeError,ErrorAction,HandleError,RoutingMessageHandler,NetAddress,GlobalFeatures};
 use ln::msgs;
 use util::ser::{Writeable, Readable, Writer, ReadableArgs};
 use util::logger::Logger;
 
 use std::cmp;
-use std::sync::{RwLock,Arc};
-use std::collections::{HashMap,BinaryHeap,BTreeMap};
+use std::sync::{RwLock,Arc,Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap,HashSet,VecDeque,BinaryHeap,BTreeMap};
 use std::collections::btree_map::Entry as BtreeEntry;
 use std;
 
@@ -117,6 +123,10 @@ struct ChannelInfo {
 	//this is cached here so we can send out it later if required by route_init_sync
 	//keep an eye on this to see if the extra memory is a problem
 	announcement_message: Option<msgs::ChannelAnnouncement>,
+	/// When we received the channel_announcement (as a UNIX timestamp), so that channels which
+	/// haven't had a channel_update yet aren't pruned as stale right away. Stored by the Router
+	/// rather than with the rest of the ChannelInfo, see Router::read.
+	announcement_received_time: u32,
 }
 
 impl std::fmt::Display for ChannelInfo {
@@ -126,12 +136,28 @@ impl std::fmt::Display for ChannelInfo {
 	}
 }
 
-impl_writeable!(ChannelInfo, 0, {
-	features,
-	one_to_two,
-	two_to_one,
-	announcement_message
-});
+impl Writeable for ChannelInfo {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		self.features.write(writer)?;
+		self.one_to_two.write(writer)?;
+		self.two_to_one.write(writer)?;
+		self.announcement_message.write(writer)?;
+		Ok(())
+	}
+}
+
+impl<R: ::std::io::Read> Readable<R> for ChannelInfo {
+	fn read(reader: &mut R) -> Result<ChannelInfo, DecodeError> {
+		Ok(ChannelInfo {
+			features: Readable::read(reader)?,
+			one_to_two: Readable::read(reader)?,
+			two_to_one: Readable::read(reader)?,
+			announcement_message: Readable::read(reader)?,
+			// Filled in by Router::read
+			announcement_received_time: 0,
+		})
+	}
+}
 
 #[derive(PartialEq)]
 struct NodeInfo {
@@ -255,17 +281,34 @@ impl<R: ::std::io::Read> Readable<R> for NetworkMap {
 		let mut channels = BTreeMap::new();
 		for _ in 0..channels_count {
 			let chan_id: u64 = Readable::read(reader)?;
-			let chan_info = Readable::read(reader)?;
+			let chan_info: ChannelInfo = Readable::read(reader)?;
 			channels.insert(chan_id, chan_info);
 		}
 		let our_node_id = Readable::read(reader)?;
 		let nodes_count: u64 = Readable::read(reader)?;
 		let mut nodes = BTreeMap::new();
 		for _ in 0..nodes_count {
-			let node_id = Readable::read(reader)?;
-			let node_info = Readable::read(reader)?;
+			let node_id: PublicKey = Readable::read(reader)?;
+			let node_info: NodeInfo = Readable::read(reader)?;
 			nodes.insert(node_id, node_info);
 		}
+
+		// Routing unwrap()s the channels each node points to, so make sure they exist
+		for (node_id, node_info) in nodes.iter() {
+			for chan_id in node_info.channels.iter() {
+				match channels.get(chan_id) {
+					Some(chan) if chan.one_to_two.src_node_id == *node_id || chan.two_to_one.src_node_id == *node_id => {},
+					_ => return Err(DecodeError::InvalidValue),
+				}
+			}
+		}
+		// ...and that every channel's endpoints exist
+		for (_, chan) in channels.iter() {
+			if !nodes.contains_key(&chan.one_to_two.src_node_id) || !nodes.contains_key(&chan.two_to_one.src_node_id) {
+				return Err(DecodeError::InvalidValue);
+			}
+		}
+
 		Ok(NetworkMap {
 			channels,
 			our_node_id,
@@ -330,6 +373,7 @@ impl NetworkMap {
 }
 
 /// A channel descriptor which provides a last-hop route to get_route
+#[derive(Clone, PartialEq, Debug)]
 pub struct RouteHint {
 	/// The node_id of the non-target end of the route
 	pub src_node_id: PublicKey,
@@ -346,25 +390,375 @@ pub struct RouteHint {
 	pub htlc_minimum_msat: u64,
 }
 
+/// A timestamped gossip message which can be checked against a GossipDedupStore
+#[derive(Clone, Copy)]
+pub enum GossipMessage<'a> {
+	/// A channel_update, deduplicated per short_channel_id and direction
+	ChannelUpdate(&'a msgs::ChannelUpdate),
+	/// A node_announcement, deduplicated per node_id
+	NodeAnnouncement(&'a msgs::NodeAnnouncement),
+}
+
+/// Gossip with a timestamp more than this many seconds in the past is considered stale.
+const GOSSIP_STALE_SECS: u64 = 60 * 60 * 24 * 14;
+/// We prune expired entries from a GossipDedupStore at most once per this many seconds.
+const GOSSIP_PRUNE_INTERVAL_SECS: u64 = 60 * 60;
+
+struct GossipDedupState {
+	channel_updates: HashMap<(u64, bool), u32>,
+	node_announcements: HashMap<PublicKey, u32>,
+	last_prune: u64,
+}
+
+/// Tracks the latest timestamp seen for each channel direction and node so that duplicate or
+/// outdated gossip can be dropped before it is re-processed or re-forwarded. Entries older than
+/// two weeks are pruned (at most once an hour) to bound memory usage, and gossip that old is
+/// rejected outright.
+pub struct GossipDedupStore {
+	state: Mutex<GossipDedupState>,
+}
+
+impl GossipDedupStore {
+	/// Creates a new, empty, GossipDedupStore
+	pub fn new() -> Self {
+		GossipDedupStore {
+			state: Mutex::new(GossipDedupState {
+				channel_updates: HashMap::new(),
+				node_announcements: HashMap::new(),
+				last_prune: 0,
+			}),
+		}
+	}
+
+	/// Returns true if the given message is newer than any we've seen for the same channel
+	/// direction or node, recording its timestamp if so. Returns false for duplicate, outdated or
+	/// stale gossip, which should be dropped.
+	///
+	/// As messages are recorded as seen here, you should only call this after checking the
+	/// message's signature.
+	pub fn should_process(&self, msg: GossipMessage) -> bool {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs();
+		self.should_process_at(msg, now)
+	}
+
+	fn should_process_at(&self, msg: GossipMessage, now: u64) -> bool {
+		let mut state = self.state.lock().unwrap();
+		if now >= state.last_prune + GOSSIP_PRUNE_INTERVAL_SECS {
+			state.channel_updates.retain(|_, timestamp| *timestamp as u64 + GOSSIP_STALE_SECS >= now);
+			state.node_announcements.retain(|_, timestamp| *timestamp as u64 + GOSSIP_STALE_SECS >= now);
+			state.last_prune = now;
+		}
+
+		let timestamp = match msg {
+			GossipMessage::ChannelUpdate(update) => update.contents.timestamp,
+			GossipMessage::NodeAnnouncement(announcement) => announcement.contents.timestamp,
+		};
+		if timestamp as u64 + GOSSIP_STALE_SECS < now {
+			return false;
+		}
+
+		let last_timestamp = match msg {
+			GossipMessage::ChannelUpdate(update) => {
+				let key = (update.contents.short_channel_id, update.contents.flags & 1 == 1);
+				state.channel_updates.entry(key).or_insert(0)
+			},
+			GossipMessage::NodeAnnouncement(announcement) => {
+				state.node_announcements.entry(announcement.contents.node_id).or_insert(0)
+			},
+		};
+		if *last_timestamp >= timestamp {
+			return false;
+		}
+		*last_timestamp = timestamp;
+		true
+	}
+}
+
+/// Collects (message hash, signature, pubkey) triples from gossip messages so that they can all be
+/// verified at once with Router::verify_signature_batch, eg while processing the flood of
+/// channel_announcements received during initial sync.
+pub struct SignatureBatch {
+	entries: Vec<(secp256k1::Message, Signature, PublicKey)>,
+}
+
+impl SignatureBatch {
+	/// Creates a new, empty, SignatureBatch
+	pub fn new() -> Self {
+		SignatureBatch { entries: Vec::new() }
+	}
+
+	fn entry_id(msg_hash: &secp256k1::Message, signature: &Signature, pubkey: &PublicKey) -> Sha256dHash {
+		let mut engine = Sha256dHash::engine();
+		engine.input(&msg_hash[..]);
+		engine.input(&signature.serialize_compact()[..]);
+		engine.input(&pubkey.serialize()[..]);
+		Sha256dHash::from_engine(engine)
+	}
+
+	/// Adds a single signature over the given message hash by the given pubkey
+	pub fn add(&mut self, msg_hash: secp256k1::Message, signature: Signature, pubkey: PublicKey) {
+		self.entries.push((msg_hash, signature, pubkey));
+	}
+
+	/// Adds the two node and two bitcoin signatures from a channel_announcement
+	pub fn add_channel_announcement(&mut self, msg: &msgs::ChannelAnnouncement) {
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
+		self.add(msg_hash, msg.node_signature_1, msg.contents.node_id_1);
+		self.add(msg_hash, msg.node_signature_2, msg.contents.node_id_2);
+		self.add(msg_hash, msg.bitcoin_signature_1, msg.contents.bitcoin_key_1);
+		self.add(msg_hash, msg.bitcoin_signature_2, msg.contents.bitcoin_key_2);
+	}
+
+	/// Adds the signature from a node_announcement
+	pub fn add_node_announcement(&mut self, msg: &msgs::NodeAnnouncement) {
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
+		self.add(msg_hash, msg.signature, msg.contents.node_id);
+	}
+
+	/// Adds the signature from a channel_update, which must be by node_id (see
+	/// ChannelUpdate::verify)
+	pub fn add_channel_update(&mut self, msg: &msgs::ChannelUpdate, node_id: &PublicKey) {
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
+		self.add(msg_hash, msg.signature, *node_id);
+	}
+
+	/// The number of signatures in this batch
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	/// Returns true if no signatures have been added to this batch
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+}
+
+impl Default for SignatureBatch {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// The most signatures from a single passing SignatureBatch which a Router remembers until the
+/// messages carrying them are handled. Past this, the rest of the batch is still verified but its
+/// signatures are checked again when handled.
+const MAX_BATCH_VERIFIED_SIGNATURES: usize = 64 * 1024;
+
+/// A gossip message waiting in a GossipQueue
+#[derive(Clone)]
+pub enum QueuedGossipMessage {
+	/// A channel_announcement
+	ChannelAnnouncement(msgs::ChannelAnnouncement),
+	/// A channel_update
+	ChannelUpdate(msgs::ChannelUpdate),
+	/// A node_announcement
+	NodeAnnouncement(msgs::NodeAnnouncement),
+}
+
+/// The most messages a GossipQueue will hold, past which further messages are dropped. This
+/// bounds the memory a peer flooding us with gossip can make us use.
+pub const MAX_QUEUED_GOSSIP_MESSAGES: usize = 16 * 1024;
+
+/// Holds gossip (eg a burst of announcements received in response to a query_channel_range) so
+/// that it can be handed to a RoutingMessageHandler in bounded chunks with process_pending,
+/// letting you interleave gossip processing with other work instead of monopolizing the calling
+/// thread. Messages are processed in the order they were queued.
+pub struct GossipQueue {
+	pending: Mutex<VecDeque<QueuedGossipMessage>>,
+}
+
+impl GossipQueue {
+	/// Creates a new, empty, GossipQueue
+	pub fn new() -> Self {
+		GossipQueue { pending: Mutex::new(VecDeque::new()) }
+	}
+
+	/// Queues a message to be processed by a later call to process_pending. Returns false, dropping
+	/// the message, if MAX_QUEUED_GOSSIP_MESSAGES are already waiting.
+	pub fn push(&self, msg: QueuedGossipMessage) -> bool {
+		let mut pending = self.pending.lock().unwrap();
+		if pending.len() >= MAX_QUEUED_GOSSIP_MESSAGES {
+			return false;
+		}
+		pending.push_back(msg);
+		true
+	}
+
+	/// The number of messages waiting to be processed
+	pub fn len(&self) -> usize {
+		self.pending.lock().unwrap().len()
+	}
+
+	/// Returns true if no messages are waiting to be processed
+	pub fn is_empty(&self) -> bool {
+		self.pending.lock().unwrap().is_empty()
+	}
+
+	/// Hands at most max_messages queued messages to the given handler, returning whether more
+	/// messages remain to be processed along with the errors for any messages the handler rejected
+	/// (eg because they are outdated or have invalid signatures).
+	///
+	/// Rejected messages are dropped, as are the handler's decisions on whether to relay the
+	/// accepted ones.
+	pub fn process_pending(&self, handler: &RoutingMessageHandler, max_messages: usize) -> (bool, Vec<HandleError>) {
+		let mut errors = Vec::new();
+		for _ in 0..max_messages {
+			// Don't hold the lock while processing so that messages can be queued concurrently
+			let msg = match self.pending.lock().unwrap().pop_front() {
+				Some(msg) => msg,
+				None => return (false, errors),
+			};
+			let res = match msg {
+				QueuedGossipMessage::ChannelAnnouncement(ref msg) => handler.handle_channel_announcement(msg),
+				QueuedGossipMessage::ChannelUpdate(ref msg) => handler.handle_channel_update(msg),
+				QueuedGossipMessage::NodeAnnouncement(ref msg) => handler.handle_node_announcement(msg),
+			};
+			if let Err(e) = res {
+				errors.push(e);
+			}
+		}
+		(!self.is_empty(), errors)
+	}
+}
+
+impl Default for GossipQueue {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// Lets get_route_with_scorer penalize channels beyond their advertised fees, eg because payments
+/// through them have failed recently.
+pub trait Score {
+	/// Gets the penalty, in msat, to add to the cost of routing over the given channel from source
+	/// to target. The penalty only affects which route is chosen, not the fees paid along it.
+	fn channel_penalty_msat(&self, short_channel_id: u64, source: &PublicKey, target: &PublicKey) -> u64;
+
+	/// Handles a payment over the given path failing at the given channel, as reported in
+	/// Event::PaymentFailed::failed_short_channel_id.
+	fn payment_path_failed(&self, path: &[RouteHop], short_channel_id: u64);
+}
+
+/// A Score which never penalizes any channel, used by get_route.
+pub struct ZeroPenaltyScorer;
+
+impl Score for ZeroPenaltyScorer {
+	fn channel_penalty_msat(&self, _short_channel_id: u64, _source: &PublicKey, _target: &PublicKey) -> u64 {
+		0
+	}
+
+	fn payment_path_failed(&self, _path: &[RouteHop], _short_channel_id: u64) {}
+}
+
+/// Provides the current time to FailureMemoryScorer, so that penalty decay can be driven by
+/// something other than the system clock (eg in tests).
+pub trait Clock: Sync + Send {
+	/// Gets the current time as a Duration since some fixed point, which must not change between
+	/// calls.
+	fn now(&self) -> Duration;
+}
+
+/// A Clock reading the system time, as a Duration since the unix epoch.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> Duration {
+		SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0))
+	}
+}
+
+/// A Score which penalizes channels that payments recently failed over. Each reported failure
+/// adds failure_penalty_msat to the channel's penalty, which then halves every half_life.
+pub struct FailureMemoryScorer {
+	failure_penalty_msat: u64,
+	half_life: Duration,
+	clock: Arc<Clock>,
+	failures: Mutex<HashMap<u64, (u64, Duration)>>,
+}
+
+impl FailureMemoryScorer {
+	/// Creates a new scorer with no failures recorded, timing penalty decay with the system clock
+	pub fn new(failure_penalty_msat: u64, half_life: Duration) -> Self {
+		Self::with_clock(failure_penalty_msat, half_life, Arc::new(SystemClock))
+	}
+
+	/// Creates a new scorer with no failures recorded, timing penalty decay with the given clock
+	pub fn with_clock(failure_penalty_msat: u64, half_life: Duration, clock: Arc<Clock>) -> Self {
+		FailureMemoryScorer {
+			failure_penalty_msat,
+			half_life,
+			clock,
+			failures: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Records that a payment failed over the given channel
+	pub fn channel_failed(&self, short_channel_id: u64) {
+		let now = self.clock.now();
+		let mut failures = self.failures.lock().unwrap();
+		let penalty_msat = match failures.get(&short_channel_id) {
+			Some(&(penalty_msat, failed_at)) => self.decayed_penalty_msat(penalty_msat, failed_at, now),
+			None => 0,
+		}.saturating_add(self.failure_penalty_msat);
+		failures.insert(short_channel_id, (penalty_msat, now));
+	}
+
+	fn decayed_penalty_msat(&self, penalty_msat: u64, failed_at: Duration, now: Duration) -> u64 {
+		// A clock which went backwards is treated as no time having passed
+		let elapsed = now.checked_sub(failed_at).unwrap_or(Duration::from_secs(0));
+		let elapsed_millis = elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64;
+		let half_life_millis = cmp::max(self.half_life.as_secs() * 1000 + (self.half_life.subsec_nanos() / 1_000_000) as u64, 1);
+		let half_lives = elapsed_millis / half_life_millis;
+		if half_lives >= 64 { 0 } else { penalty_msat >> half_lives }
+	}
+}
+
+impl Score for FailureMemoryScorer {
+	fn channel_penalty_msat(&self, short_channel_id: u64, _source: &PublicKey, _target: &PublicKey) -> u64 {
+		match self.failures.lock().unwrap().get(&short_channel_id) {
+			Some(&(penalty_msat, failed_at)) => self.decayed_penalty_msat(penalty_msat, failed_at, self.clock.now()),
+			None => 0,
+		}
+	}
+
+	fn payment_path_failed(&self, _path: &[RouteHop], short_channel_id: u64) {
+		self.channel_failed(short_channel_id);
+	}
+}
+
 /// Tracks a view of the network, receiving updates from peers and generating Routes to
 /// payment destinations.
 pub struct Router {
 	secp_ctx: Secp256k1<secp256k1::VerifyOnly>,
 	network_map: RwLock<NetworkMap>,
+	genesis_hash: Sha256dHash,
 	chain_monitor: Arc<ChainWatchInterface>,
 	logger: Arc<Logger>,
+	max_total_cltv_expiry_delta: AtomicUsize,
+	/// Signatures from the most recent batch passed to verify_signature_batch which have not yet
+	/// been used up by handling their messages, see SignatureBatch::entry_id
+	batch_verified_signatures: Mutex<HashSet<Sha256dHash>>,
 }
 
-const SERIALIZATION_VERSION: u8 = 1;
-const MIN_SERIALIZATION_VERSION: u8 = 1;
+/// The default maximum sum of cltv_expiry_deltas (including the final hop's CLTV) which
+/// get_route will accept in a route, ie the longest our funds may be locked up: one week.
+pub const DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA: u32 = 6 * 24 * 7;
+
+const SERIALIZATION_VERSION: u8 = 4;
+const MIN_SERIALIZATION_VERSION: u8 = 2;
 
 impl Writeable for Router {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
 		writer.write_all(&[SERIALIZATION_VERSION; 1])?;
 		writer.write_all(&[MIN_SERIALIZATION_VERSION; 1])?;
 
+		self.genesis_hash.write(writer)?;
 		let network = self.network_map.read().unwrap();
 		network.write(writer)?;
+		(self.max_total_cltv_expiry_delta.load(Ordering::Acquire) as u32).write(writer)?;
+		for chan in network.channels.values() {
+			chan.announcement_received_time.write(writer)?;
+		}
 		Ok(())
 	}
 }
@@ -385,34 +779,42 @@ pub struct RouterReadArgs {
 
 impl<R: ::std::io::Read> ReadableArgs<R, RouterReadArgs> for Router {
 	fn read(reader: &mut R, args: RouterReadArgs) -> Result<Router, DecodeError> {
-		let _ver: u8 = Readable::read(reader)?;
+		let ver: u8 = Readable::read(reader)?;
 		let min_ver: u8 = Readable::read(reader)?;
 		if min_ver > SERIALIZATION_VERSION {
 			return Err(DecodeError::UnknownVersion);
 		}
-		let network_map = Readable::read(reader)?;
+		// Version 1 Routers didn't record which chain their gossip was for and we have no way to
+		// tell, so they can't be read (their network map will be rebuilt from gossip anyway).
+		if ver < 2 {
+			return Err(DecodeError::UnknownVersion);
+		}
+		let genesis_hash = Readable::read(reader)?;
+		let mut network_map: NetworkMap = Readable::read(reader)?;
+		// Version 2 Routers didn't store their limit, so they get the default one
+		let max_total_cltv_expiry_delta: u32 = if ver >= 3 { Readable::read(reader)? } else { DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA };
+		// Before version 4 we didn't store when we received each channel_announcement, so treat
+		// them as just received to give them a full GOSSIP_STALE_SECS to see a channel_update
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs() as u32;
+		for chan in network_map.channels.values_mut() {
+			chan.announcement_received_time = if ver >= 4 { Readable::read(reader)? } else { now };
+		}
 		Ok(Router {
 			secp_ctx: Secp256k1::verification_only(),
 			network_map: RwLock::new(network_map),
+			genesis_hash,
 			chain_monitor: args.chain_monitor,
 			logger: args.logger,
+			max_total_cltv_expiry_delta: AtomicUsize::new(max_total_cltv_expiry_delta as usize),
+			batch_verified_signatures: Mutex::new(HashSet::new()),
 		})
 	}
 }
 
-macro_rules! secp_verify_sig {
-	( $secp_ctx: expr, $msg: expr, $sig: expr, $pubkey: expr ) => {
-		match $secp_ctx.verify($msg, $sig, $pubkey) {
-			Ok(_) => {},
-			Err(_) => return Err(HandleError{err: "Invalid signature from remote node", action: None}),
-		}
-	};
-}
-
 impl RoutingMessageHandler for Router {
 	fn handle_node_announcement(&self, msg: &msgs::NodeAnnouncement) -> Result<bool, HandleError> {
 		let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
-		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.signature, &msg.contents.node_id);
+		self.check_signature(&msg_hash, &msg.signature, &msg.contents.node_id)?;
 
 		if msg.contents.features.requires_unknown_bits() {
 			panic!("Unknown-required-features NodeAnnouncements should never deserialize!");
@@ -443,12 +845,15 @@ impl RoutingMessageHandler for Router {
 		if msg.contents.node_id_1 == msg.contents.node_id_2 || msg.contents.bitcoin_key_1 == msg.contents.bitcoin_key_2 {
 			return Err(HandleError{err: "Channel announcement node had a channel with itself", action: Some(ErrorAction::IgnoreError)});
 		}
+		if msg.contents.chain_hash != self.genesis_hash {
+			return Err(HandleError{err: "Channel announcement chain_hash didn't match our genesis block hash", action: Some(ErrorAction::IgnoreError)});
+		}
 
 		let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
-		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.node_signature_1, &msg.contents.node_id_1);
-		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.node_signature_2, &msg.contents.node_id_2);
-		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.bitcoin_signature_1, &msg.contents.bitcoin_key_1);
-		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.bitcoin_signature_2, &msg.contents.bitcoin_key_2);
+		self.check_signature(&msg_hash, &msg.node_signature_1, &msg.contents.node_id_1)?;
+		self.check_signature(&msg_hash, &msg.node_signature_2, &msg.contents.node_id_2)?;
+		self.check_signature(&msg_hash, &msg.bitcoin_signature_1, &msg.contents.bitcoin_key_1)?;
+		self.check_signature(&msg_hash, &msg.bitcoin_signature_2, &msg.contents.bitcoin_key_2)?;
 
 		if msg.contents.features.requires_unknown_bits() {
 			panic!("Unknown-required-features ChannelAnnouncements should never deserialize!");
@@ -508,6 +913,7 @@ impl RoutingMessageHandler for Router {
 					last_update_message: None,
 				},
 				announcement_message: if should_relay { Some(msg.clone()) } else { None },
+				announcement_received_time: SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs() as u32,
 			};
 
 		match network.channels.entry(NetworkMap::get_key(msg.contents.short_channel_id, msg.contents.chain_hash)) {
@@ -593,6 +999,9 @@ impl RoutingMessageHandler for Router {
 	}
 
 	fn handle_channel_update(&self, msg: &msgs::ChannelUpdate) -> Result<bool, HandleError> {
+		if msg.contents.chain_hash != self.genesis_hash {
+			return Err(HandleError{err: "Channel update chain_hash didn't match our genesis block hash", action: Some(ErrorAction::IgnoreError)});
+		}
 		let mut network = self.network_map.write().unwrap();
 		let dest_node_id;
 		let chan_enabled = msg.contents.flags & (1 << 1) != (1 << 1);
@@ -623,11 +1032,11 @@ impl RoutingMessageHandler for Router {
 				let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
 				if msg.contents.flags & 1 == 1 {
 					dest_node_id = channel.one_to_two.src_node_id.clone();
-					secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.signature, &channel.two_to_one.src_node_id);
+					self.check_signature(&msg_hash, &msg.signature, &channel.two_to_one.src_node_id)?;
 					maybe_update_channel_info!(channel.two_to_one);
 				} else {
 					dest_node_id = channel.two_to_one.src_node_id.clone();
-					secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.signature, &channel.one_to_two.src_node_id);
+					self.check_signature(&msg_hash, &msg.signature, &channel.one_to_two.src_node_id)?;
 					maybe_update_channel_info!(channel.one_to_two);
 				}
 			}
@@ -665,6 +1074,12 @@ impl RoutingMessageHandler for Router {
 		Ok(msg.contents.excess_data.is_empty())
 	}
 
+	fn handle_gossip_timestamp_filter(&self, msg: &msgs::GossipTimestampFilter) -> Result<(), HandleError> {
+		if msg.chain_hash != self.genesis_hash {
+			return Err(HandleError{err: "Gossip timestamp filter chain_hash didn't match our genesis block hash", action: Some(ErrorAction::IgnoreError)});
+		}
+		Ok(())
+	}
 
 	fn get_next_channel_announcements(&self, starting_point: u64, batch_amount: u8) -> Vec<(msgs::ChannelAnnouncement, msgs::ChannelUpdate,msgs::ChannelUpdate)> {
 		let mut result = Vec::with_capacity(batch_amount as usize);
@@ -717,11 +1132,17 @@ struct RouteGraphNode {
 	pubkey: PublicKey,
 	lowest_fee_to_peer_through_node: u64,
 	lowest_fee_to_node: u64,
+	cltv_to_target: u32,
+	penalty_to_target: u64,
+	/// Breaks ties on lowest_fee_to_peer_through_node, which holds the CLTV to the target rather
+	/// than the fee when get_route falls back to searching by CLTV. 0 otherwise.
+	fee_tie_breaker: u64,
 }
 
 impl cmp::Ord for RouteGraphNode {
 	fn cmp(&self, other: &RouteGraphNode) -> cmp::Ordering {
 		other.lowest_fee_to_peer_through_node.cmp(&self.lowest_fee_to_peer_through_node)
+			.then_with(|| other.fee_tie_breaker.cmp(&self.fee_tie_breaker))
 			.then_with(|| other.pubkey.serialize().cmp(&self.pubkey.serialize()))
 	}
 }
@@ -741,8 +1162,9 @@ struct DummyDirectionalChannelInfo {
 }
 
 impl Router {
-	/// Creates a new router with the given node_id to be used as the source for get_route()
-	pub fn new(our_pubkey: PublicKey, chain_monitor: Arc<ChainWatchInterface>, logger: Arc<Logger>) -> Router {
+	/// Creates a new router with the given node_id to be used as the source for get_route().
+	/// Gossip messages whose chain_hash isn't the given network's genesis block hash are rejected.
+	pub fn new(our_pubkey: PublicKey, network: Network, chain_monitor: Arc<ChainWatchInterface>, logger: Arc<Logger>) -> Router {
 		let mut nodes = BTreeMap::new();
 		nodes.insert(our_pubkey.clone(), NodeInfo {
 			channels: Vec::new(),
@@ -762,8 +1184,57 @@ impl Router {
 				our_node_id: our_pubkey,
 				nodes: nodes,
 			}),
+			genesis_hash: genesis_block(network).header.bitcoin_hash(),
 			chain_monitor,
 			logger,
+			max_total_cltv_expiry_delta: AtomicUsize::new(DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA as usize),
+			batch_verified_signatures: Mutex::new(HashSet::new()),
+		}
+	}
+
+	/// Sets the maximum sum of cltv_expiry_deltas, including the final hop's final_cltv, which
+	/// get_route will accept in a route. Paths which would lock funds up for longer are skipped,
+	/// even if they are cheaper. Defaults to DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA and is stored
+	/// when the Router is serialized.
+	pub fn set_max_total_cltv_expiry_delta(&self, max_total_cltv_expiry_delta: u32) {
+		self.max_total_cltv_expiry_delta.store(max_total_cltv_expiry_delta as usize, Ordering::Release);
+	}
+
+	/// Verifies every signature in the given batch, failing if any one of them is invalid. Once a
+	/// batch passes, its signatures are not checked again when the messages carrying them are
+	/// handled, so the work is not duplicated.
+	///
+	/// Only the most recent batch is remembered: any signatures left over from a previous batch
+	/// (eg because some of its messages were never handled) are forgotten on each call, so the
+	/// messages from a batch should be handled before the next batch is verified.
+	///
+	/// The secp256k1 library does not yet expose ECDSA batch verification, so for now this falls
+	/// back to checking each signature in turn, stopping at the first invalid one.
+	pub fn verify_signature_batch(&self, batch: &SignatureBatch) -> Result<(), HandleError> {
+		self.batch_verified_signatures.lock().unwrap().clear();
+		for &(ref msg_hash, ref signature, ref pubkey) in batch.entries.iter() {
+			if self.secp_ctx.verify(msg_hash, signature, pubkey).is_err() {
+				return Err(HandleError{err: "Invalid signature from remote node", action: None});
+			}
+		}
+		let mut verified = self.batch_verified_signatures.lock().unwrap();
+		for &(ref msg_hash, ref signature, ref pubkey) in batch.entries.iter() {
+			if verified.len() >= MAX_BATCH_VERIFIED_SIGNATURES {
+				break;
+			}
+			verified.insert(SignatureBatch::entry_id(msg_hash, signature, pubkey));
+		}
+		Ok(())
+	}
+
+	/// Checks a gossip signature, skipping the check if it already passed verify_signature_batch
+	fn check_signature(&self, msg_hash: &secp256k1::Message, signature: &Signature, pubkey: &PublicKey) -> Result<(), HandleError> {
+		if self.batch_verified_signatures.lock().unwrap().remove(&SignatureBatch::entry_id(msg_hash, signature, pubkey)) {
+			return Ok(());
+		}
+		match self.secp_ctx.verify(msg_hash, signature, pubkey) {
+			Ok(_) => Ok(()),
+			Err(_) => Err(HandleError{err: "Invalid signature from remote node", action: None}),
 		}
 	}
 
@@ -779,6 +1250,45 @@ impl Router {
 		network.nodes.get(pubkey).map(|n| n.addresses.clone())
 	}
 
+	/// Checks whether our network view contains any path from us to target over enabled channels
+	/// which accept an HTLC of amount_msat. This is much cheaper than get_route as fees and CLTV
+	/// deltas are ignored, and so may return true even if get_route would fail.
+	pub fn is_reachable(&self, target: &PublicKey, amount_msat: u64) -> bool {
+		let network = self.network_map.read().unwrap();
+		if *target == network.our_node_id {
+			return true;
+		}
+
+		let mut visited = HashSet::new();
+		let mut to_visit = VecDeque::new();
+		visited.insert(network.our_node_id);
+		to_visit.push_back(network.our_node_id);
+		while let Some(node_id) = to_visit.pop_front() {
+			let node = match network.nodes.get(&node_id) {
+				Some(node) => node,
+				None => continue,
+			};
+			for chan_id in node.channels.iter() {
+				let chan = network.channels.get(chan_id).unwrap();
+				let (directional_info, next_node_id) = if chan.one_to_two.src_node_id == node_id {
+					(&chan.one_to_two, chan.two_to_one.src_node_id)
+				} else {
+					(&chan.two_to_one, chan.one_to_two.src_node_id)
+				};
+				if !directional_info.enabled || directional_info.htlc_minimum_msat > amount_msat {
+					continue;
+				}
+				if next_node_id == *target {
+					return true;
+				}
+				if visited.insert(next_node_id) {
+					to_visit.push_back(next_node_id);
+				}
+			}
+		}
+		false
+	}
+
 	/// Marks a node as having failed a route. This will avoid re-using the node in routes for now,
 	/// with an exponential decay in node "badness". Note that there is deliberately no
 	/// mark_channel_bad as a node may simply lie and suggest that an upstream channel from it is
@@ -789,6 +1299,26 @@ impl Router {
 		unimplemented!();
 	}
 
+	/// Removes channels for which neither direction has had a channel_update within the last two
+	/// weeks as of now (a UNIX timestamp), as well as any nodes left without channels. Channels
+	/// whose channel_announcement we received within the last two weeks are kept even if they
+	/// haven't had a channel_update yet.
+	pub fn prune_stale_channels(&self, now: u32) {
+		let mut network_lock = self.network_map.write().unwrap();
+		let network = &mut *network_lock;
+		let min_update_time = (now as u64).saturating_sub(GOSSIP_STALE_SECS);
+		let stale_keys: Vec<_> = network.channels.iter().filter(|&(_, chan)| {
+			(chan.announcement_received_time as u64) < min_update_time &&
+				(chan.one_to_two.last_update as u64) < min_update_time && (chan.two_to_one.last_update as u64) < min_update_time
+		}).map(|(key, _)| key.clone()).collect();
+		for key in stale_keys {
+			if let Some(chan) = network.channels.remove(&key) {
+				log_trace!(self, "Pruning stale channel {}", NetworkMap::get_short_id(&key));
+				Self::remove_channel_in_nodes(&mut network.nodes, &chan, *NetworkMap::get_short_id(&key));
+			}
+		}
+	}
+
 	fn remove_channel_in_nodes(nodes: &mut BTreeMap<PublicKey, NodeInfo>, chan: &ChannelInfo, short_channel_id: u64) {
 		macro_rules! remove_from_node {
 			($node_id: expr) => {
@@ -824,7 +1354,109 @@ impl Router {
 	/// The fees on channels from us to next-hops are ignored (as they are assumed to all be
 	/// equal), however the enabled/disabled bit on such channels as well as the htlc_minimum_msat
 	/// *is* checked as they may change based on the receiving node.
+	///
+	/// Paths whose cltv_expiry_deltas plus final_cltv sum to more than the configured
+	/// max_total_cltv_expiry_delta are never returned.
 	pub fn get_route(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32) -> Result<Route, HandleError> {
+		self.get_route_with_scorer(target, first_hops, last_hops, final_value_msat, final_cltv, &ZeroPenaltyScorer)
+	}
+
+	/// Gets a route to pay the given invoice, using its route hints (see
+	/// get_route_with_route_hints) and its min_final_cltv_expiry (or the BOLT #11 default) as
+	/// final_cltv.
+	///
+	/// amount_msat is the amount the payer chose to pay, which is required if the invoice doesn't
+	/// specify one, see Invoice::amount_to_pay_msat.
+	pub fn get_route_for_invoice(&self, invoice: &Invoice, first_hops: Option<&[channelmanager::ChannelDetails]>, amount_msat: Option<u64>) -> Result<Route, HandleError> {
+		let final_value_msat = invoice.amount_to_pay_msat(amount_msat)?;
+		let final_cltv = invoice.min_final_cltv_expiry().unwrap_or(DEFAULT_MIN_FINAL_CLTV_EXPIRY);
+		if final_cltv > ::std::u32::MAX as u64 {
+			return Err(HandleError{err: "Invoice min_final_cltv_expiry was too large", action: None});
+		}
+		self.get_route_with_route_hints(invoice.payee_pub_key(), first_hops, invoice.route_hints(), final_value_msat, final_cltv as u32)
+	}
+
+	/// Gets a route to the given target node as get_route does, but taking route hints of any
+	/// length, as found in invoices. Each hint is a private path ending at target, given in order
+	/// from its first hop.
+	///
+	/// Single-hop hints are passed to get_route as last_hops. A multi-hop hint has to be used in
+	/// its entirety, so we route to its first node and append the hint's hops, returning whichever
+	/// candidate route costs the least in fees.
+	pub fn get_route_with_route_hints(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, route_hints: &[Vec<RouteHint>], final_value_msat: u64, final_cltv: u32) -> Result<Route, HandleError> {
+		let last_hops: Vec<RouteHint> = route_hints.iter().filter(|hops| hops.len() == 1).map(|hops| hops[0].clone()).collect();
+		let mut best_route = self.get_route(target, first_hops, &last_hops, final_value_msat, final_cltv);
+		let total_fees_msat = |route: &Route| route.hops[..route.hops.len() - 1].iter().map(|hop| hop.fee_msat).sum::<u64>();
+
+		for hops in route_hints.iter().filter(|hops| hops.len() > 1) {
+			if let Ok(route) = self.get_route_through_hint(target, first_hops, hops, final_value_msat, final_cltv) {
+				let is_cheaper = match best_route {
+					Ok(ref best) => total_fees_msat(&route) < total_fees_msat(best),
+					Err(_) => true,
+				};
+				if is_cheaper {
+					best_route = Ok(route);
+				}
+			}
+		}
+		best_route
+	}
+
+	/// Gets a route to target which ends with every hop of the given (non-empty) route hint.
+	fn get_route_through_hint(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, hint: &[RouteHint], final_value_msat: u64, final_cltv: u32) -> Result<Route, HandleError> {
+		// Walk the hint back from target to work out the fee each hint node charges and the value
+		// and CLTV which the hint's first node has to receive.
+		let mut fees_msat = vec![0; hint.len()];
+		let mut amount_msat = final_value_msat;
+		let mut cltv = final_cltv;
+		for (idx, hop) in hint.iter().enumerate().rev() {
+			if amount_msat < hop.htlc_minimum_msat {
+				return Err(HandleError{err: "Route hint channel's htlc_minimum_msat is above the value to forward", action: None});
+			}
+			let fee_msat = amount_msat.checked_mul(hop.fee_proportional_millionths as u64)
+				.and_then(|part| (hop.fee_base_msat as u64).checked_add(part / 1000000));
+			fees_msat[idx] = match fee_msat {
+				Some(fee_msat) => fee_msat,
+				None => return Err(HandleError{err: "Route hint fees overflowed", action: None}),
+			};
+			amount_msat = match amount_msat.checked_add(fees_msat[idx]) {
+				Some(amount_msat) => amount_msat,
+				None => return Err(HandleError{err: "Route hint fees overflowed", action: None}),
+			};
+			cltv = match cltv.checked_add(hop.cltv_expiry_delta as u32) {
+				Some(cltv) => cltv,
+				None => return Err(HandleError{err: "Route hint CLTV overflowed", action: None}),
+			};
+		}
+
+		let mut route = self.get_route(&hint[0].src_node_id, first_hops, &[], amount_msat, cltv)?;
+		{
+			let entry_hop = route.hops.last_mut().unwrap();
+			entry_hop.fee_msat = fees_msat[0];
+			entry_hop.cltv_expiry_delta = hint[0].cltv_expiry_delta as u32;
+		}
+		for (idx, hop) in hint.iter().enumerate() {
+			route.hops.push(match hint.get(idx + 1) {
+				Some(next_hop) => RouteHop {
+					pubkey: next_hop.src_node_id,
+					short_channel_id: hop.short_channel_id,
+					fee_msat: fees_msat[idx + 1],
+					cltv_expiry_delta: next_hop.cltv_expiry_delta as u32,
+				},
+				None => RouteHop {
+					pubkey: *target,
+					short_channel_id: hop.short_channel_id,
+					fee_msat: final_value_msat,
+					cltv_expiry_delta: final_cltv,
+				},
+			});
+		}
+		Ok(route)
+	}
+
+	/// Gets a route from us to the given target node as get_route does, but adding the penalties
+	/// scorer gives each channel to its fees when choosing between paths.
+	pub fn get_route_with_scorer(&self, target: &PublicKey, first_hops: Option<&[channelmanager::ChannelDetails]>, last_hops: &[RouteHint], final_value_msat: u64, final_cltv: u32, scorer: &Score) -> Result<Route, HandleError> {
 		// TODO: Obviously *only* using total fee cost sucks. We should consider weighting by
 		// uptime/success in using a node in the past.
 		let network = self.network_map.read().unwrap();
@@ -837,6 +1469,13 @@ impl Router {
 			return Err(HandleError{err: "Cannot generate a route of more value than all existing satoshis", action: None});
 		}
 
+		let max_total_cltv_expiry_delta = self.max_total_cltv_expiry_delta.load(Ordering::Acquire) as u32;
+		if final_cltv > max_total_cltv_expiry_delta {
+			return Err(HandleError{err: "Cannot generate a route with a final CLTV above the maximum total CLTV expiry delta", action: None});
+		}
+		// The cltv_expiry_deltas along a path, excluding final_cltv, may not exceed this
+		let max_path_cltv = max_total_cltv_expiry_delta - final_cltv;
+
 		// We do a dest-to-source Dijkstra's sorting by each node's distance from the destination
 		// plus the minimum per-HTLC fee to get from it to another node (aka "shitty A*").
 		// TODO: There are a few tweaks we could do, including possibly pre-calculating more stuff
@@ -853,6 +1492,12 @@ impl Router {
 
 		let mut targets = BinaryHeap::new(); //TODO: Do we care about switching to eg Fibbonaci heap?
 		let mut dist = HashMap::with_capacity(network.nodes.len());
+		// Each node only keeps its cheapest path to the target, so a cheap path which uses up most
+		// of max_path_cltv can hide a pricier one which would have fit. If the search by fees
+		// fails we search again by CLTV, which finds a path within the limit if any exists. Fees
+		// (and penalties) then only break ties between paths with the same CLTV, so the path found
+		// may cost far more than a somewhat longer one which would also have fit.
+		let mut minimize_cltv = false;
 
 		let mut first_hop_targets = HashMap::with_capacity(if first_hops.is_some() { first_hops.as_ref().unwrap().len() } else { 0 });
 		if let Some(hops) = first_hops {
@@ -878,19 +1523,23 @@ impl Router {
 		macro_rules! add_entry {
 			// Adds entry which goes from the node pointed to by $directional_info to
 			// $dest_node_id over the channel with id $chan_id with fees described in
-			// $directional_info.
-			( $chan_id: expr, $dest_node_id: expr, $directional_info: expr, $starting_fee_msat: expr ) => {
+			// $directional_info. $starting_cltv is the sum of the cltv_expiry_deltas from
+			// $dest_node_id to the target, and $starting_penalty the sum of scorer penalties.
+			( $chan_id: expr, $dest_node_id: expr, $directional_info: expr, $starting_fee_msat: expr, $starting_cltv: expr, $starting_penalty: expr ) => {
+				// The channel's cltv_expiry_delta is charged by its source, unless that's us
+				let cltv_to_target = $starting_cltv as u32 + if $directional_info.src_node_id != network.our_node_id { $directional_info.cltv_expiry_delta as u32 } else { 0 };
 				//TODO: Explore simply adding fee to hit htlc_minimum_msat
-				if $starting_fee_msat as u64 + final_value_msat >= $directional_info.htlc_minimum_msat {
+				if $starting_fee_msat as u64 + final_value_msat >= $directional_info.htlc_minimum_msat && cltv_to_target <= max_path_cltv {
 					let proportional_fee_millions = ($starting_fee_msat + final_value_msat).checked_mul($directional_info.fee_proportional_millionths as u64);
 					if let Some(new_fee) = proportional_fee_millions.and_then(|part| {
 							($directional_info.fee_base_msat as u64).checked_add(part / 1000000) })
 					{
+						let penalty_to_target = ($starting_penalty as u64).saturating_add(scorer.channel_penalty_msat($chan_id.clone(), &$directional_info.src_node_id, &$dest_node_id));
 						let mut total_fee = $starting_fee_msat as u64;
 						let hm_entry = dist.entry(&$directional_info.src_node_id);
 						let old_entry = hm_entry.or_insert_with(|| {
 							let node = network.nodes.get(&$directional_info.src_node_id).unwrap();
-							(u64::max_value(),
+							((u64::max_value(), 0),
 								node.lowest_inbound_channel_fee_base_msat,
 								node.lowest_inbound_channel_fee_proportional_millionths,
 								RouteHop {
@@ -907,18 +1556,25 @@ impl Router {
 							if let Some(fee_inc) = final_value_msat.checked_add(total_fee).and_then(|inc| { (old_entry.2 as u64).checked_mul(inc) }) {
 								total_fee += fee_inc / 1000000 + (old_entry.1 as u64);
 							} else {
-								// max_value means we'll always fail the old_entry.0 > total_fee check
+								// max_value means we'll always fail the old_entry.0 > cost check
 								total_fee = u64::max_value();
 							}
 						}
+						// Penalties only affect which path is picked, never the fees we pay, and
+						// when searching by CLTV fees and penalties only break ties
+						let total_fee = total_fee.saturating_add(penalty_to_target);
+						let cost = if minimize_cltv { (cltv_to_target as u64, total_fee) } else { (total_fee, 0) };
 						let new_graph_node = RouteGraphNode {
 							pubkey: $directional_info.src_node_id,
-							lowest_fee_to_peer_through_node: total_fee,
+							lowest_fee_to_peer_through_node: cost.0,
 							lowest_fee_to_node: $starting_fee_msat as u64 + new_fee,
+							cltv_to_target,
+							penalty_to_target,
+							fee_tie_breaker: cost.1,
 						};
-						if old_entry.0 > total_fee {
+						if old_entry.0 > cost {
 							targets.push(new_graph_node);
-							old_entry.0 = total_fee;
+							old_entry.0 = cost;
 							old_entry.3 = RouteHop {
 								pubkey: $dest_node_id.clone(),
 								short_channel_id: $chan_id.clone(),
@@ -932,10 +1588,10 @@ impl Router {
 		}
 
 		macro_rules! add_entries_to_cheapest_to_target_node {
-			( $node: expr, $node_id: expr, $fee_to_target_msat: expr ) => {
+			( $node: expr, $node_id: expr, $fee_to_target_msat: expr, $cltv_to_target: expr, $penalty_to_target: expr ) => {
 				if first_hops.is_some() {
 					if let Some(first_hop) = first_hop_targets.get(&$node_id) {
-						add_entry!(first_hop, $node_id, dummy_directional_info, $fee_to_target_msat);
+						add_entry!(first_hop, $node_id, dummy_directional_info, $fee_to_target_msat, $cltv_to_target, $penalty_to_target);
 					}
 				}
 
@@ -945,13 +1601,13 @@ impl Router {
 						// ie $node is one, ie next hop in A* is two, via the two_to_one channel
 						if first_hops.is_none() || chan.two_to_one.src_node_id != network.our_node_id {
 							if chan.two_to_one.enabled {
-								add_entry!(chan_id, chan.one_to_two.src_node_id, chan.two_to_one, $fee_to_target_msat);
+								add_entry!(chan_id, chan.one_to_two.src_node_id, chan.two_to_one, $fee_to_target_msat, $cltv_to_target, $penalty_to_target);
 							}
 						}
 					} else {
 						if first_hops.is_none() || chan.one_to_two.src_node_id != network.our_node_id {
 							if chan.one_to_two.enabled {
-								add_entry!(chan_id, chan.two_to_one.src_node_id, chan.one_to_two, $fee_to_target_msat);
+								add_entry!(chan_id, chan.two_to_one.src_node_id, chan.one_to_two, $fee_to_target_msat, $cltv_to_target, $penalty_to_target);
 							}
 						}
 					}
@@ -959,51 +1615,59 @@ impl Router {
 			};
 		}
 
-		match network.nodes.get(target) {
-			None => {},
-			Some(node) => {
-				add_entries_to_cheapest_to_target_node!(node, target, 0);
-			},
-		}
+		loop {
+			match network.nodes.get(target) {
+				None => {},
+				Some(node) => {
+					add_entries_to_cheapest_to_target_node!(node, target, 0, 0, 0);
+				},
+			}
 
-		for hop in last_hops.iter() {
-			if first_hops.is_none() || hop.src_node_id != network.our_node_id { // first_hop overrules last_hops
-				if network.nodes.get(&hop.src_node_id).is_some() {
-					if first_hops.is_some() {
-						if let Some(first_hop) = first_hop_targets.get(&hop.src_node_id) {
-							add_entry!(first_hop, hop.src_node_id, dummy_directional_info, 0);
+			for hop in last_hops.iter() {
+				if first_hops.is_none() || hop.src_node_id != network.our_node_id { // first_hop overrules last_hops
+					if network.nodes.get(&hop.src_node_id).is_some() {
+						if first_hops.is_some() {
+							if let Some(first_hop) = first_hop_targets.get(&hop.src_node_id) {
+								add_entry!(first_hop, hop.src_node_id, dummy_directional_info, 0, hop.cltv_expiry_delta, 0);
+							}
 						}
+						add_entry!(hop.short_channel_id, target, hop, 0, 0, 0);
 					}
-					add_entry!(hop.short_channel_id, target, hop, 0);
 				}
 			}
-		}
 
-		while let Some(RouteGraphNode { pubkey, lowest_fee_to_node, .. }) = targets.pop() {
-			if pubkey == network.our_node_id {
-				let mut res = vec!(dist.remove(&network.our_node_id).unwrap().3);
-				while res.last().unwrap().pubkey != *target {
-					let new_entry = match dist.remove(&res.last().unwrap().pubkey) {
-						Some(hop) => hop.3,
-						None => return Err(HandleError{err: "Failed to find a non-fee-overflowing path to the given destination", action: None}),
-					};
-					res.last_mut().unwrap().fee_msat = new_entry.fee_msat;
-					res.last_mut().unwrap().cltv_expiry_delta = new_entry.cltv_expiry_delta;
-					res.push(new_entry);
+			while let Some(RouteGraphNode { pubkey, lowest_fee_to_node, cltv_to_target, penalty_to_target, .. }) = targets.pop() {
+				if pubkey == network.our_node_id {
+					let mut res = vec!(dist.remove(&network.our_node_id).unwrap().3);
+					while res.last().unwrap().pubkey != *target {
+						let new_entry = match dist.remove(&res.last().unwrap().pubkey) {
+							Some(hop) => hop.3,
+							None => return Err(HandleError{err: "Failed to find a non-fee-overflowing path to the given destination", action: None}),
+						};
+						res.last_mut().unwrap().fee_msat = new_entry.fee_msat;
+						res.last_mut().unwrap().cltv_expiry_delta = new_entry.cltv_expiry_delta;
+						res.push(new_entry);
+					}
+					res.last_mut().unwrap().fee_msat = final_value_msat;
+					res.last_mut().unwrap().cltv_expiry_delta = final_cltv;
+					let route = Route { hops: res };
+					log_trace!(self, "Got route: {}", log_route!(route));
+					return Ok(route);
+				}
+
+				match network.nodes.get(&pubkey) {
+					None => {},
+					Some(node) => {
+						add_entries_to_cheapest_to_target_node!(node, &pubkey, lowest_fee_to_node, cltv_to_target, penalty_to_target);
+					},
 				}
-				res.last_mut().unwrap().fee_msat = final_value_msat;
-				res.last_mut().unwrap().cltv_expiry_delta = final_cltv;
-				let route = Route { hops: res };
-				log_trace!(self, "Got route: {}", log_route!(route));
-				return Ok(route);
 			}
 
-			match network.nodes.get(&pubkey) {
-				None => {},
-				Some(node) => {
-					add_entries_to_cheapest_to_target_node!(node, &pubkey, lowest_fee_to_node);
-				},
+			if minimize_cltv {
+				break;
 			}
+			minimize_cltv = true;
+			dist.clear();
 		}
 
 		Err(HandleError{err: "Failed to find a path to the given destination", action: None})
@@ -1014,23 +1678,44 @@ impl Router {
 mod tests {
 	use chain::chaininterface;
 	use ln::channelmanager;
-	use ln::router::{Router,NodeInfo,NetworkMap,ChannelInfo,DirectionalChannelInfo,RouteHint};
-	use ln::msgs::GlobalFeatures;
+	use ln::router::{Router,NodeInfo,NetworkMap,ChannelInfo,DirectionalChannelInfo,RouteHint,GossipDedupStore,GossipMessage,DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA};
+	use ln::router::{Score,ZeroPenaltyScorer,FailureMemoryScorer,Clock,RouterReadArgs,SignatureBatch,GossipQueue,QueuedGossipMessage,MAX_QUEUED_GOSSIP_MESSAGES};
+	use ln::msgs::DecodeError;
+	use ln::msgs::{GlobalFeatures, RoutingMessageHandler};
+	use ln::msgs;
 	use util::test_utils;
 	use util::test_utils::TestVecWriter;
 	use util::logger::Logger;
-	use util::ser::{Writeable, Readable};
+	use util::ser::{Writeable, Readable, ReadableArgs};
 
 	use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 	use bitcoin_hashes::Hash;
 	use bitcoin::network::constants::Network;
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::util::hash::BitcoinHash;
 
 	use hex;
 
 	use secp256k1::key::{PublicKey,SecretKey};
-	use secp256k1::Secp256k1;
-
-	use std::sync::Arc;
+	use secp256k1::{Secp256k1, Message, All};
+
+	use std::sync::atomic::Ordering;
+	use std::sync::{Arc, Mutex};
+	use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+	/// A Clock which only moves when told to, so penalty decay can be tested deterministically
+	struct TestClock(Mutex<Duration>);
+	impl TestClock {
+		fn advance(&self, by: Duration) {
+			let mut now = self.0.lock().unwrap();
+			*now += by;
+		}
+	}
+	impl Clock for TestClock {
+		fn now(&self) -> Duration {
+			*self.0.lock().unwrap()
+		}
+	}
 
 	#[test]
 	fn route_test() {
@@ -1038,7 +1723,9 @@ mod tests {
 		let our_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&hex::decode("0101010101010101010101010101010101010101010101010101010101010101").unwrap()[..]).unwrap());
 		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
 		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
-		let router = Router::new(our_id, chain_monitor, Arc::clone(&logger));
+		let router = Router::new(our_id, Network::Testnet, chain_monitor, Arc::clone(&logger));
+		// This network's cltv_expiry_deltas encode channel ids, so are far above any sane limit
+		router.set_max_total_cltv_expiry_delta(u32::max_value());
 
 		// Build network from our_id to node8:
 		//
@@ -1144,6 +1831,7 @@ mod tests {
 					last_update_message: None,
 				},
 				announcement_message: None,
+				announcement_received_time: 0,
 			});
 			network.nodes.insert(node2.clone(), NodeInfo {
 				channels: vec!(NetworkMap::get_key(2, zero_hash.clone()), NetworkMap::get_key(4, zero_hash.clone())),
@@ -1178,6 +1866,7 @@ mod tests {
 					last_update_message: None,
 				},
 				announcement_message: None,
+				announcement_received_time: 0,
 			});
 			network.nodes.insert(node8.clone(), NodeInfo {
 				channels: vec!(NetworkMap::get_key(12, zero_hash.clone()), NetworkMap::get_key(13, zero_hash.clone())),
@@ -1212,6 +1901,7 @@ mod tests {
 					last_update_message: None,
 				},
 				announcement_message: None,
+				announcement_received_time: 0,
 			});
 			network.nodes.insert(node3.clone(), NodeInfo {
 				channels: vec!(
@@ -1252,6 +1942,7 @@ mod tests {
 					last_update_message: None,
 				},
 				announcement_message: None,
+				announcement_received_time: 0,
 			});
 			network.channels.insert(NetworkMap::get_key(4, zero_hash.clone()), ChannelInfo {
 				features: GlobalFeatures::new(),
@@ -1275,6 +1966,7 @@ mod tests {
 					last_update_message: None,
 				},
 				announcement_message: None,
+				announcement_received_time: 0,
 			});
 			network.channels.insert(NetworkMap::get_key(13, zero_hash.clone()), ChannelInfo {
 				features: GlobalFeatures::new(),
@@ -1298,6 +1990,7 @@ mod tests {
 					last_update_message: None,
 				},
 				announcement_message: None,
+				announcement_received_time: 0,
 			});
 			network.nodes.insert(node4.clone(), NodeInfo {
 				channels: vec!(NetworkMap::get_key(5, zero_hash.clone()), NetworkMap::get_key(11, zero_hash.clone())),
@@ -1332,6 +2025,7 @@ mod tests {
 					last_update_message: None,
 				},
 				announcement_message: None,
+				announcement_received_time: 0,
 			});
 			network.nodes.insert(node5.clone(), NodeInfo {
 				channels: vec!(NetworkMap::get_key(6, zero_hash.clone()), NetworkMap::get_key(11, zero_hash.clone())),
@@ -1366,6 +2060,7 @@ mod tests {
 					last_update_message: None,
 				},
 				announcement_message: None,
+				announcement_received_time: 0,
 			});
 			network.channels.insert(NetworkMap::get_key(11, zero_hash.clone()), ChannelInfo {
 				features: GlobalFeatures::new(),
@@ -1389,6 +2084,7 @@ mod tests {
 					last_update_message: None,
 				},
 				announcement_message: None,
+				announcement_received_time: 0,
 			});
 			network.nodes.insert(node6.clone(), NodeInfo {
 				channels: vec!(NetworkMap::get_key(7, zero_hash.clone())),
@@ -1423,6 +2119,7 @@ mod tests {
 					last_update_message: None,
 				},
 				announcement_message: None,
+				announcement_received_time: 0,
 			});
 		}
 
@@ -1470,6 +2167,8 @@ mod tests {
 				user_id: 0,
 				outbound_capacity_msat: 0,
 				inbound_capacity_msat: 0,
+				pending_inbound_htlc_msat: 0,
+				pending_outbound_htlc_msat: 0,
 				is_live: true,
 			}];
 			let route = router.get_route(&node3, Some(&our_chans), &Vec::new(), 100, 42).unwrap();
@@ -1548,6 +2247,8 @@ mod tests {
 				user_id: 0,
 				outbound_capacity_msat: 0,
 				inbound_capacity_msat: 0,
+				pending_inbound_htlc_msat: 0,
+				pending_outbound_htlc_msat: 0,
 				is_live: true,
 			}];
 			let route = router.get_route(&node7, Some(&our_chans), &last_hops, 100, 42).unwrap();
@@ -1630,4 +2331,799 @@ mod tests {
 			assert!(<NetworkMap>::read(&mut ::std::io::Cursor::new(&w.0)).unwrap() == *network);
 		}
 	}
+
+	#[test]
+	fn route_multi_hop_hint_test() {
+		let secp_ctx = Secp256k1::new();
+		let node_id = |byte: u8| PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[byte; 32]).unwrap());
+		let (our_id, node1, node2, target) = (node_id(1), node_id(2), node_id(3), node_id(4));
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
+		let router = Router::new(our_id, Network::Testnet, chain_monitor, Arc::clone(&logger));
+		add_test_channel(&mut router.network_map.write().unwrap(), 1, our_id, node1, 0, 10);
+
+		// Only node1 is public, target sits behind node2 over private channels
+		let hint_hop = |src_node_id: PublicKey, short_channel_id: u64, fee_base_msat: u32, fee_proportional_millionths: u32, cltv_expiry_delta: u16| RouteHint {
+			src_node_id, short_channel_id, fee_base_msat, fee_proportional_millionths, cltv_expiry_delta, htlc_minimum_msat: 0,
+		};
+		let multi_hop_hint = vec![hint_hop(node1, 100, 100, 1000, 20), hint_hop(node2, 101, 10, 0, 30)];
+		assert!(router.get_route(&target, None, &Vec::new(), 1_000_000, 42).is_err());
+
+		let route = router.get_route_with_route_hints(&target, None, &[Vec::new(), multi_hop_hint.clone()], 1_000_000, 42).unwrap();
+		assert_eq!(route.hops.len(), 3);
+
+		// node1 forwards 1_000_010 msat over the first hint channel, for 100 + 1000 msat
+		assert_eq!(route.hops[0].pubkey, node1);
+		assert_eq!(route.hops[0].short_channel_id, 1);
+		assert_eq!(route.hops[0].fee_msat, 1100);
+		assert_eq!(route.hops[0].cltv_expiry_delta, 20);
+
+		assert_eq!(route.hops[1].pubkey, node2);
+		assert_eq!(route.hops[1].short_channel_id, 100);
+		assert_eq!(route.hops[1].fee_msat, 10);
+		assert_eq!(route.hops[1].cltv_expiry_delta, 30);
+
+		assert_eq!(route.hops[2].pubkey, target);
+		assert_eq!(route.hops[2].short_channel_id, 101);
+		assert_eq!(route.hops[2].fee_msat, 1_000_000);
+		assert_eq!(route.hops[2].cltv_expiry_delta, 42);
+
+		// A single-hop hint straight from node1 is used instead only if it is cheaper
+		let expensive_hint = vec![hint_hop(node1, 102, 2000, 0, 20)];
+		let route = router.get_route_with_route_hints(&target, None, &[expensive_hint, multi_hop_hint.clone()], 1_000_000, 42).unwrap();
+		assert_eq!(route.hops.len(), 3);
+		assert_eq!(route.hops[2].short_channel_id, 101);
+
+		let cheap_hint = vec![hint_hop(node1, 102, 1000, 0, 20)];
+		let route = router.get_route_with_route_hints(&target, None, &[cheap_hint, multi_hop_hint.clone()], 1_000_000, 42).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert_eq!(route.hops[0].fee_msat, 1000);
+		assert_eq!(route.hops[1].pubkey, target);
+		assert_eq!(route.hops[1].short_channel_id, 102);
+
+		// A hint channel which can't carry the payment makes the hint unusable
+		let mut small_hint = multi_hop_hint.clone();
+		small_hint[1].htlc_minimum_msat = 1_000_001;
+		assert!(router.get_route_with_route_hints(&target, None, &[small_hint], 1_000_000, 42).is_err());
+	}
+
+	fn add_test_channel(network: &mut NetworkMap, short_channel_id: u64, node_one: PublicKey, node_two: PublicKey, fee_base_msat: u32, cltv_expiry_delta: u16) {
+		// Both directions share the same fees and cltv_expiry_delta
+		macro_rules! directional_info {
+			($src_node_id: expr) => {
+				DirectionalChannelInfo {
+					src_node_id: $src_node_id,
+					last_update: 0,
+					enabled: true,
+					cltv_expiry_delta,
+					htlc_minimum_msat: 0,
+					fee_base_msat,
+					fee_proportional_millionths: 0,
+					last_update_message: None,
+				}
+			}
+		}
+		let key = NetworkMap::get_key(short_channel_id, Sha256dHash::hash(&[0; 32]));
+		network.channels.insert(key, ChannelInfo {
+			features: GlobalFeatures::new(),
+			one_to_two: directional_info!(node_one),
+			two_to_one: directional_info!(node_two),
+			announcement_message: None,
+			announcement_received_time: 0,
+		});
+		for node in [node_one, node_two].iter() {
+			network.nodes.entry(*node).or_insert(NodeInfo {
+				channels: Vec::new(),
+				lowest_inbound_channel_fee_base_msat: 0,
+				lowest_inbound_channel_fee_proportional_millionths: 0,
+				features: GlobalFeatures::new(),
+				last_update: 1,
+				rgb: [0; 3],
+				alias: [0; 32],
+				addresses: Vec::new(),
+				announcement_message: None,
+			}).channels.push(key);
+		}
+	}
+
+	fn get_signed_channel_announcement(secp_ctx: &Secp256k1<All>, short_channel_id: u64, node_1: &SecretKey, node_2: &SecretKey, bitcoin_1: &SecretKey, bitcoin_2: &SecretKey) -> msgs::ChannelAnnouncement {
+		let contents = msgs::UnsignedChannelAnnouncement {
+			features: GlobalFeatures::new(),
+			chain_hash: genesis_block(Network::Bitcoin).header.bitcoin_hash(),
+			short_channel_id,
+			node_id_1: PublicKey::from_secret_key(secp_ctx, node_1),
+			node_id_2: PublicKey::from_secret_key(secp_ctx, node_2),
+			bitcoin_key_1: PublicKey::from_secret_key(secp_ctx, bitcoin_1),
+			bitcoin_key_2: PublicKey::from_secret_key(secp_ctx, bitcoin_2),
+			excess_data: Vec::new(),
+		};
+		let msghash = Message::from_slice(&Sha256dHash::hash(&contents.encode()[..])[..]).unwrap();
+		msgs::ChannelAnnouncement {
+			node_signature_1: secp_ctx.sign(&msghash, node_1),
+			node_signature_2: secp_ctx.sign(&msghash, node_2),
+			bitcoin_signature_1: secp_ctx.sign(&msghash, bitcoin_1),
+			bitcoin_signature_2: secp_ctx.sign(&msghash, bitcoin_2),
+			contents,
+		}
+	}
+
+	fn get_signed_channel_update(secp_ctx: &Secp256k1<All>, short_channel_id: u64, flags: u16, signer: &SecretKey) -> msgs::ChannelUpdate {
+		let contents = msgs::UnsignedChannelUpdate {
+			chain_hash: genesis_block(Network::Bitcoin).header.bitcoin_hash(),
+			short_channel_id,
+			timestamp: 1,
+			flags,
+			cltv_expiry_delta: 144,
+			htlc_minimum_msat: 1000,
+			fee_base_msat: 1000,
+			fee_proportional_millionths: 1,
+			excess_data: Vec::new(),
+		};
+		let msghash = Message::from_slice(&Sha256dHash::hash(&contents.encode()[..])[..]).unwrap();
+		msgs::ChannelUpdate { signature: secp_ctx.sign(&msghash, signer), contents }
+	}
+
+	fn get_signed_node_announcement(secp_ctx: &Secp256k1<All>, alias: [u8; 32], signer: &SecretKey) -> msgs::NodeAnnouncement {
+		let contents = msgs::UnsignedNodeAnnouncement {
+			features: GlobalFeatures::new(),
+			timestamp: 1,
+			node_id: PublicKey::from_secret_key(secp_ctx, signer),
+			rgb: [0; 3],
+			alias,
+			addresses: Vec::new(),
+			excess_address_data: Vec::new(),
+			excess_data: Vec::new(),
+		};
+		let msghash = Message::from_slice(&Sha256dHash::hash(&contents.encode()[..])[..]).unwrap();
+		msgs::NodeAnnouncement { signature: secp_ctx.sign(&msghash, signer), contents }
+	}
+
+	#[test]
+	fn route_cltv_limit_test() {
+		let secp_ctx = Secp256k1::new();
+		let node_id = |byte: u8| PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[byte; 32]).unwrap());
+		let (our_id, node1, node2, target) = (node_id(1), node_id(2), node_id(3), node_id(4));
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
+		let router = Router::new(our_id, Network::Testnet, chain_monitor, Arc::clone(&logger));
+
+		// our_id -1- node1 -3- target is free but node1 wants 1000 blocks, while
+		// our_id -2- node2 -4- target costs 1000 msat but node2 only wants 40 blocks.
+		{
+			let mut network = router.network_map.write().unwrap();
+			add_test_channel(&mut network, 1, our_id, node1, 0, 0);
+			add_test_channel(&mut network, 2, our_id, node2, 0, 0);
+			add_test_channel(&mut network, 3, node1, target, 0, 1000);
+			add_test_channel(&mut network, 4, node2, target, 1000, 40);
+		}
+
+		// 1000 + 42 exceeds the default maximum, so we pay for the shorter path
+		assert!(1000 + 42 > DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA);
+		let route = router.get_route(&target, None, &Vec::new(), 100, 42).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert_eq!(route.hops[0].short_channel_id, 2);
+		assert_eq!(route.hops[0].fee_msat, 1000);
+		assert_eq!(route.hops[0].cltv_expiry_delta, 40);
+		assert_eq!(route.hops[1].short_channel_id, 4);
+		assert_eq!(route.hops[1].cltv_expiry_delta, 42);
+
+		// With a higher limit the cheaper path is fine
+		router.set_max_total_cltv_expiry_delta(1042);
+		let route = router.get_route(&target, None, &Vec::new(), 100, 42).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 1);
+		assert_eq!(route.hops[0].fee_msat, 0);
+		assert_eq!(route.hops[0].cltv_expiry_delta, 1000);
+
+		// And with a very low one neither path is acceptable
+		router.set_max_total_cltv_expiry_delta(81);
+		match router.get_route(&target, None, &Vec::new(), 100, 42) {
+			Err(e) => assert_eq!(e.err, "Failed to find a path to the given destination"),
+			Ok(_) => panic!(),
+		}
+		router.set_max_total_cltv_expiry_delta(41);
+		match router.get_route(&target, None, &Vec::new(), 100, 42) {
+			Err(e) => assert_eq!(e.err, "Cannot generate a route with a final CLTV above the maximum total CLTV expiry delta"),
+			Ok(_) => panic!(),
+		}
+	}
+
+	#[test]
+	fn route_cltv_limit_pricier_path_to_node_test() {
+		let secp_ctx = Secp256k1::new();
+		let node_id = |byte: u8| PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[byte; 32]).unwrap());
+		let (our_id, node1, node2, node3, node4, target) = (node_id(1), node_id(2), node_id(3), node_id(4), node_id(6), node_id(5));
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
+		let router = Router::new(our_id, Network::Testnet, chain_monitor, Arc::clone(&logger));
+
+		// node2 -4- target is free but node2 wants 100 blocks, while node2 -3- node3 -5- target
+		// costs 2000 msat and only 20 blocks. Either way we get to node2 through node1, which
+		// wants another 50 blocks.
+		{
+			let mut network = router.network_map.write().unwrap();
+			add_test_channel(&mut network, 1, our_id, node1, 0, 0);
+			add_test_channel(&mut network, 2, node1, node2, 0, 50);
+			add_test_channel(&mut network, 3, node2, node3, 1000, 10);
+			add_test_channel(&mut network, 4, node2, target, 0, 100);
+			add_test_channel(&mut network, 5, node3, target, 1000, 10);
+			// As short as node2 -3- node3 -5- target, but cheaper
+			add_test_channel(&mut network, 6, node2, node4, 300, 10);
+			add_test_channel(&mut network, 7, node4, target, 300, 10);
+		}
+
+		let route = router.get_route(&target, None, &Vec::new(), 100, 42).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 2, 4]);
+
+		// node2's cheapest path to target leaves too little for node1, but the pricier ones fit, of
+		// which we pick the cheaper
+		router.set_max_total_cltv_expiry_delta(42 + 120);
+		let route = router.get_route(&target, None, &Vec::new(), 100, 42).unwrap();
+		assert_eq!(route.hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![1, 2, 6, 7]);
+		assert_eq!(route.hops.iter().map(|hop| hop.cltv_expiry_delta).sum::<u32>(), 42 + 50 + 10 + 10);
+
+		router.set_max_total_cltv_expiry_delta(42 + 69);
+		match router.get_route(&target, None, &Vec::new(), 100, 42) {
+			Err(e) => assert_eq!(e.err, "Failed to find a path to the given destination"),
+			Ok(_) => panic!(),
+		}
+	}
+
+	#[test]
+	fn route_scorer_test() {
+		let secp_ctx = Secp256k1::new();
+		let node_id = |byte: u8| PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[byte; 32]).unwrap());
+		let (our_id, node1, node2, target) = (node_id(1), node_id(2), node_id(3), node_id(4));
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
+		let router = Router::new(our_id, Network::Testnet, chain_monitor, Arc::clone(&logger));
+
+		// our_id -1- node1 -3- target is free while our_id -2- node2 -4- target costs 1000 msat
+		{
+			let mut network = router.network_map.write().unwrap();
+			add_test_channel(&mut network, 1, our_id, node1, 0, 10);
+			add_test_channel(&mut network, 2, our_id, node2, 0, 10);
+			add_test_channel(&mut network, 3, node1, target, 0, 10);
+			add_test_channel(&mut network, 4, node2, target, 1000, 10);
+		}
+
+		let route = router.get_route_with_scorer(&target, None, &Vec::new(), 100, 42, &ZeroPenaltyScorer).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 1);
+		assert_eq!(route.hops[1].short_channel_id, 3);
+
+		// Once channel 3 fails it is penalized above the fee of the other path...
+		let scorer = FailureMemoryScorer::new(10_000, Duration::from_secs(3600));
+		assert_eq!(scorer.channel_penalty_msat(3, &node1, &target), 0);
+		scorer.channel_failed(3);
+		assert_eq!(scorer.channel_penalty_msat(3, &node1, &target), 10_000);
+		assert_eq!(scorer.channel_penalty_msat(4, &node2, &target), 0);
+		let route = router.get_route_with_scorer(&target, None, &Vec::new(), 100, 42, &scorer).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert_eq!(route.hops[0].short_channel_id, 2);
+		assert_eq!(route.hops[0].fee_msat, 1000);
+		assert_eq!(route.hops[1].short_channel_id, 4);
+		assert_eq!(route.hops[1].fee_msat, 100);
+
+		// ...but the penalty halves every half life until the cheaper path is used again
+		let clock = Arc::new(TestClock(Mutex::new(Duration::from_secs(1_500_000_000))));
+		let scorer = FailureMemoryScorer::with_clock(10_000, Duration::from_secs(60), clock.clone());
+		scorer.channel_failed(3);
+		clock.advance(Duration::from_secs(59));
+		assert_eq!(scorer.channel_penalty_msat(3, &node1, &target), 10_000);
+		clock.advance(Duration::from_secs(1));
+		assert_eq!(scorer.channel_penalty_msat(3, &node1, &target), 5_000);
+		let route = router.get_route_with_scorer(&target, None, &Vec::new(), 100, 42, &scorer).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 2);
+		assert_eq!(route.hops[1].short_channel_id, 4);
+
+		// A further failure adds to what is left of the decayed penalty
+		scorer.channel_failed(3);
+		assert_eq!(scorer.channel_penalty_msat(3, &node1, &target), 15_000);
+
+		clock.advance(Duration::from_secs(4 * 60));
+		assert_eq!(scorer.channel_penalty_msat(3, &node1, &target), 937);
+		let route = router.get_route_with_scorer(&target, None, &Vec::new(), 100, 42, &scorer).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 1);
+		assert_eq!(route.hops[1].short_channel_id, 3);
+	}
+
+	#[test]
+	fn route_payment_path_failed_test() {
+		let secp_ctx = Secp256k1::new();
+		let node_id = |byte: u8| PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[byte; 32]).unwrap());
+		let (our_id, node1, node2, target) = (node_id(1), node_id(2), node_id(3), node_id(4));
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
+		let router = Router::new(our_id, Network::Testnet, chain_monitor, Arc::clone(&logger));
+
+		// our_id -1- node1 -3- target costs 100 msat while our_id -2- node2 -4- target costs 1000
+		{
+			let mut network = router.network_map.write().unwrap();
+			add_test_channel(&mut network, 1, our_id, node1, 0, 10);
+			add_test_channel(&mut network, 2, our_id, node2, 0, 10);
+			add_test_channel(&mut network, 3, node1, target, 100, 10);
+			add_test_channel(&mut network, 4, node2, target, 1000, 10);
+		}
+
+		// A single failure costs less than the difference in fees, so channel 3 is still used...
+		let scorer = FailureMemoryScorer::new(500, Duration::from_secs(3600));
+		let route = router.get_route_with_scorer(&target, None, &Vec::new(), 100, 42, &scorer).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert_eq!(route.hops[0].short_channel_id, 1);
+		assert_eq!(route.hops[1].short_channel_id, 3);
+		scorer.payment_path_failed(&route.hops, 3);
+		assert_eq!(scorer.channel_penalty_msat(3, &node1, &target), 500);
+		assert_eq!(scorer.channel_penalty_msat(1, &our_id, &node1), 0);
+		let route = router.get_route_with_scorer(&target, None, &Vec::new(), 100, 42, &scorer).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert_eq!(route.hops[0].short_channel_id, 1);
+		assert_eq!(route.hops[0].fee_msat, 100);
+		assert_eq!(route.hops[1].short_channel_id, 3);
+		assert_eq!(route.hops[1].fee_msat, 100);
+
+		// ...but repeated failures accumulate until it is avoided
+		scorer.payment_path_failed(&route.hops, 3);
+		assert_eq!(scorer.channel_penalty_msat(3, &node1, &target), 1000);
+		let route = router.get_route_with_scorer(&target, None, &Vec::new(), 100, 42, &scorer).unwrap();
+		assert_eq!(route.hops.len(), 2);
+		assert_eq!(route.hops[0].short_channel_id, 2);
+		assert_eq!(route.hops[0].fee_msat, 1000);
+		assert_eq!(route.hops[1].short_channel_id, 4);
+		assert_eq!(route.hops[1].fee_msat, 100);
+
+		// ZeroPenaltyScorer ignores failures entirely
+		let scorer = ZeroPenaltyScorer;
+		scorer.payment_path_failed(&route.hops, 3);
+		assert_eq!(scorer.channel_penalty_msat(3, &node1, &target), 0);
+		let route = router.get_route_with_scorer(&target, None, &Vec::new(), 100, 42, &scorer).unwrap();
+		assert_eq!(route.hops[0].short_channel_id, 1);
+		assert_eq!(route.hops[1].short_channel_id, 3);
+	}
+
+	#[test]
+	fn reachability_test() {
+		let secp_ctx = Secp256k1::new();
+		let node_id = |byte: u8| PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[byte; 32]).unwrap());
+		let (our_id, node1, node2, node3, node4) = (node_id(1), node_id(2), node_id(3), node_id(4), node_id(5));
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
+		let router = Router::new(our_id, Network::Testnet, chain_monitor, Arc::clone(&logger));
+
+		// our_id -1- node1 -2- node2, while node3 -3- node4 is isolated from us
+		{
+			let mut network = router.network_map.write().unwrap();
+			add_test_channel(&mut network, 1, our_id, node1, 0, 10);
+			add_test_channel(&mut network, 2, node1, node2, 0, 10);
+			add_test_channel(&mut network, 3, node3, node4, 0, 10);
+		}
+
+		assert!(router.is_reachable(&our_id, 1000));
+		assert!(router.is_reachable(&node1, 1000));
+		assert!(router.is_reachable(&node2, 1000));
+		assert!(!router.is_reachable(&node3, 1000));
+		assert!(!router.is_reachable(&node4, 1000));
+		assert!(!router.is_reachable(&node_id(6), 1000));
+
+		// Only the direction from node1 to node2 matters for reaching node2
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(2, Sha256dHash::hash(&[0; 32]))).unwrap().two_to_one.enabled = false;
+		assert!(router.is_reachable(&node2, 1000));
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(2, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.enabled = false;
+		assert!(!router.is_reachable(&node2, 1000));
+		assert!(router.is_reachable(&node1, 1000));
+
+		// Channels whose htlc_minimum_msat is above the amount can't be used either
+		router.network_map.write().unwrap().channels.get_mut(&NetworkMap::get_key(1, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.htlc_minimum_msat = 5000;
+		assert!(!router.is_reachable(&node1, 1000));
+		assert!(router.is_reachable(&node1, 5000));
+	}
+
+	#[test]
+	fn prune_stale_channels_test() {
+		let secp_ctx = Secp256k1::new();
+		let node_id = |byte: u8| PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[byte; 32]).unwrap());
+		let (our_id, node1, node2, node3, node4) = (node_id(1), node_id(2), node_id(3), node_id(4), node_id(5));
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
+		let router = Router::new(our_id, Network::Testnet, chain_monitor, Arc::clone(&logger));
+		let now: u32 = 1_600_000_000;
+		let two_weeks: u32 = 60 * 60 * 24 * 14;
+
+		{
+			let mut network = router.network_map.write().unwrap();
+			add_test_channel(&mut network, 1, our_id, node1, 0, 10);
+			add_test_channel(&mut network, 2, node1, node2, 0, 10);
+			add_test_channel(&mut network, 3, node2, node3, 0, 10);
+			add_test_channel(&mut network, 4, node1, node4, 0, 10);
+			// Channel 1 was updated recently in one direction, channel 2 only long ago and
+			// channels 3 and 4 never, though channel 4 was only just announced
+			network.channels.get_mut(&NetworkMap::get_key(1, Sha256dHash::hash(&[0; 32]))).unwrap().two_to_one.last_update = now - 60;
+			network.channels.get_mut(&NetworkMap::get_key(3, Sha256dHash::hash(&[0; 32]))).unwrap().announcement_received_time = now - two_weeks - 1;
+			network.channels.get_mut(&NetworkMap::get_key(4, Sha256dHash::hash(&[0; 32]))).unwrap().announcement_received_time = now - 60;
+			network.channels.get_mut(&NetworkMap::get_key(2, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.last_update = now - two_weeks - 1;
+			network.channels.get_mut(&NetworkMap::get_key(2, Sha256dHash::hash(&[0; 32]))).unwrap().two_to_one.last_update = now - two_weeks - 60;
+		}
+
+		router.prune_stale_channels(now);
+		let network = router.network_map.read().unwrap();
+		assert_eq!(network.channels.len(), 2);
+		assert!(network.channels.get(&NetworkMap::get_key(1, Sha256dHash::hash(&[0; 32]))).is_some());
+		assert!(network.channels.get(&NetworkMap::get_key(4, Sha256dHash::hash(&[0; 32]))).is_some());
+		assert_eq!(network.nodes.len(), 3);
+		assert_eq!(network.nodes.get(&our_id).unwrap().channels.len(), 1);
+		assert_eq!(network.nodes.get(&node1).unwrap().channels.len(), 2);
+		assert_eq!(network.nodes.get(&node4).unwrap().channels.len(), 1);
+		assert!(network.nodes.get(&node2).is_none());
+		assert!(network.nodes.get(&node3).is_none());
+	}
+
+	#[test]
+	fn router_serialization_test() {
+		let secp_ctx = Secp256k1::new();
+		let node_id = |byte: u8| PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[byte; 32]).unwrap());
+		let (our_id, node1, node2, node3, target) = (node_id(1), node_id(2), node_id(3), node_id(4), node_id(5));
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
+		let router = Router::new(our_id, Network::Testnet, chain_monitor.clone(), Arc::clone(&logger));
+
+		{
+			let mut network = router.network_map.write().unwrap();
+			add_test_channel(&mut network, 1, our_id, node1, 0, 10);
+			add_test_channel(&mut network, 2, our_id, node2, 0, 10);
+			add_test_channel(&mut network, 3, node1, node3, 100, 20);
+			add_test_channel(&mut network, 4, node2, node3, 50, 30);
+			add_test_channel(&mut network, 5, node3, target, 10, 40);
+			let chan = network.channels.get_mut(&NetworkMap::get_key(3, Sha256dHash::hash(&[0; 32]))).unwrap();
+			chan.one_to_two.last_update = 1_600_000_000;
+			chan.announcement_received_time = 1_500_000_000;
+			chan.two_to_one.fee_proportional_millionths = 1000;
+			let node = network.nodes.get_mut(&node3).unwrap();
+			node.last_update = 1_600_000_000;
+			node.rgb = [1, 2, 3];
+			node.alias = [42; 32];
+			node.addresses.push(msgs::NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 });
+		}
+
+		router.set_max_total_cltv_expiry_delta(500);
+		let encoded = router.encode();
+		let read_router = <Router>::read(&mut ::std::io::Cursor::new(&encoded), RouterReadArgs {
+			chain_monitor: chain_monitor.clone(),
+			logger: Arc::clone(&logger),
+		}).unwrap();
+		assert!(*read_router.network_map.read().unwrap() == *router.network_map.read().unwrap());
+		assert_eq!(read_router.max_total_cltv_expiry_delta.load(Ordering::Acquire), 500);
+		assert_eq!(read_router.encode(), encoded);
+
+		// Version 3 Routers didn't store when they received each channel_announcement, so their
+		// channels are treated as just announced
+		let received_times_len = 5 * 4;
+		let read_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+		let mut v3_encoded = encoded[..encoded.len() - received_times_len].to_vec();
+		v3_encoded[0] = 3;
+		let v3_router = <Router>::read(&mut ::std::io::Cursor::new(&v3_encoded), RouterReadArgs {
+			chain_monitor: chain_monitor.clone(),
+			logger: Arc::clone(&logger),
+		}).unwrap();
+		for (chan_id, chan) in v3_router.network_map.write().unwrap().channels.iter_mut() {
+			assert!(chan.announcement_received_time >= read_time);
+			chan.announcement_received_time = router.network_map.read().unwrap().channels.get(chan_id).unwrap().announcement_received_time;
+		}
+		assert!(*v3_router.network_map.read().unwrap() == *router.network_map.read().unwrap());
+		assert_eq!(v3_router.max_total_cltv_expiry_delta.load(Ordering::Acquire), 500);
+
+		// Version 2 Routers didn't store the limit either, so it goes back to the default
+		let mut legacy_encoded = encoded[..encoded.len() - received_times_len - 4].to_vec();
+		legacy_encoded[0] = 2;
+		let legacy_router = <Router>::read(&mut ::std::io::Cursor::new(&legacy_encoded), RouterReadArgs {
+			chain_monitor: chain_monitor.clone(),
+			logger: Arc::clone(&logger),
+		}).unwrap();
+		for (chan_id, chan) in legacy_router.network_map.write().unwrap().channels.iter_mut() {
+			assert!(chan.announcement_received_time >= read_time);
+			chan.announcement_received_time = router.network_map.read().unwrap().channels.get(chan_id).unwrap().announcement_received_time;
+		}
+		assert!(*legacy_router.network_map.read().unwrap() == *router.network_map.read().unwrap());
+		assert_eq!(legacy_router.max_total_cltv_expiry_delta.load(Ordering::Acquire), DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA as usize);
+
+		// Version 1 Routers had no genesis hash either, so we can't know which chain they're for
+		let mut v1_encoded = vec![1, 1];
+		v1_encoded.extend_from_slice(&encoded[2 + 32..encoded.len() - received_times_len - 4]);
+		match <Router>::read(&mut ::std::io::Cursor::new(&v1_encoded), RouterReadArgs {
+			chain_monitor: chain_monitor.clone(),
+			logger: Arc::clone(&logger),
+		}) {
+			Err(DecodeError::UnknownVersion) => {},
+			_ => panic!("Read a version 1 Router without knowing its chain"),
+		}
+
+		let route = router.get_route(&target, None, &Vec::new(), 10000, 42).unwrap();
+		let read_route = read_router.get_route(&target, None, &Vec::new(), 10000, 42).unwrap();
+		assert_eq!(route.hops.len(), 3);
+		assert!(route == read_route);
+
+		// Truncated data never deserializes
+		for len in 0..encoded.len() {
+			assert!(<Router>::read(&mut ::std::io::Cursor::new(&encoded[..len]), RouterReadArgs {
+				chain_monitor: chain_monitor.clone(),
+				logger: Arc::clone(&logger),
+			}).is_err());
+		}
+
+		// Nor does a network map whose channels' endpoints are unknown
+		let target_info = router.network_map.write().unwrap().nodes.remove(&target).unwrap();
+		match <Router>::read(&mut ::std::io::Cursor::new(&router.encode()), RouterReadArgs { chain_monitor: chain_monitor.clone(), logger: Arc::clone(&logger) }) {
+			Err(DecodeError::InvalidValue) => {},
+			_ => panic!(),
+		}
+		router.network_map.write().unwrap().nodes.insert(target, target_info);
+		assert!(<Router>::read(&mut ::std::io::Cursor::new(&router.encode()), RouterReadArgs { chain_monitor: chain_monitor.clone(), logger: Arc::clone(&logger) }).is_ok());
+
+		// Or whose nodes point at unknown channels
+		router.network_map.write().unwrap().nodes.get_mut(&target).unwrap().channels.push(NetworkMap::get_key(6, Sha256dHash::hash(&[0; 32])));
+		match <Router>::read(&mut ::std::io::Cursor::new(&router.encode()), RouterReadArgs { chain_monitor, logger }) {
+			Err(DecodeError::InvalidValue) => {},
+			_ => panic!(),
+		}
+	}
+
+	#[test]
+	fn wrong_chain_gossip_test() {
+		let secp_ctx = Secp256k1::new();
+		let privkeys: Vec<SecretKey> = (1..5).map(|byte| SecretKey::from_slice(&[byte; 32]).unwrap()).collect();
+		let pubkeys: Vec<PublicKey> = privkeys.iter().map(|privkey| PublicKey::from_secret_key(&secp_ctx, privkey)).collect();
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Bitcoin, Arc::clone(&logger)));
+		let router = Router::new(pubkeys[0], Network::Bitcoin, chain_monitor, Arc::clone(&logger));
+
+		let channel_announcement = |network: Network| {
+			let contents = msgs::UnsignedChannelAnnouncement {
+				features: GlobalFeatures::new(),
+				chain_hash: genesis_block(network).header.bitcoin_hash(),
+				short_channel_id: 42,
+				node_id_1: pubkeys[0],
+				node_id_2: pubkeys[1],
+				bitcoin_key_1: pubkeys[2],
+				bitcoin_key_2: pubkeys[3],
+				excess_data: Vec::new(),
+			};
+			let msghash = Message::from_slice(&Sha256dHash::hash(&contents.encode()[..])[..]).unwrap();
+			msgs::ChannelAnnouncement {
+				node_signature_1: secp_ctx.sign(&msghash, &privkeys[0]),
+				node_signature_2: secp_ctx.sign(&msghash, &privkeys[1]),
+				bitcoin_signature_1: secp_ctx.sign(&msghash, &privkeys[2]),
+				bitcoin_signature_2: secp_ctx.sign(&msghash, &privkeys[3]),
+				contents,
+			}
+		};
+		let channel_update = |network: Network| {
+			let contents = msgs::UnsignedChannelUpdate {
+				chain_hash: genesis_block(network).header.bitcoin_hash(),
+				short_channel_id: 42,
+				timestamp: 1,
+				flags: 0,
+				cltv_expiry_delta: 144,
+				htlc_minimum_msat: 1000,
+				fee_base_msat: 1000,
+				fee_proportional_millionths: 1,
+				excess_data: Vec::new(),
+			};
+			let msghash = Message::from_slice(&Sha256dHash::hash(&contents.encode()[..])[..]).unwrap();
+			msgs::ChannelUpdate { signature: secp_ctx.sign(&msghash, &privkeys[0]), contents }
+		};
+
+		match router.handle_channel_announcement(&channel_announcement(Network::Testnet)) {
+			Err(e) => assert_eq!(e.err, "Channel announcement chain_hash didn't match our genesis block hash"),
+			Ok(_) => panic!(),
+		}
+		assert!(router.network_map.read().unwrap().channels.is_empty());
+		assert!(router.handle_channel_announcement(&channel_announcement(Network::Bitcoin)).is_ok());
+
+		// Updates for the same short_channel_id from another chain don't touch our channel
+		match router.handle_channel_update(&channel_update(Network::Testnet)) {
+			Err(e) => assert_eq!(e.err, "Channel update chain_hash didn't match our genesis block hash"),
+			Ok(_) => panic!(),
+		}
+		assert_eq!(router.network_map.read().unwrap().channels.get(&NetworkMap::get_key(42, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.last_update, 0);
+		assert!(router.handle_channel_update(&channel_update(Network::Bitcoin)).is_ok());
+		assert_eq!(router.network_map.read().unwrap().channels.get(&NetworkMap::get_key(42, Sha256dHash::hash(&[0; 32]))).unwrap().one_to_two.last_update, 1);
+	}
+
+	#[test]
+	fn signature_batch_test() {
+		let secp_ctx = Secp256k1::new();
+		let privkeys: Vec<SecretKey> = (1..5).map(|byte| SecretKey::from_slice(&[byte; 32]).unwrap()).collect();
+		let pubkeys: Vec<PublicKey> = privkeys.iter().map(|privkey| PublicKey::from_secret_key(&secp_ctx, privkey)).collect();
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Bitcoin, Arc::clone(&logger)));
+		let router = Router::new(pubkeys[0], Network::Bitcoin, chain_monitor, Arc::clone(&logger));
+
+		let channel_announcement = |short_channel_id: u64| {
+			get_signed_channel_announcement(&secp_ctx, short_channel_id, &privkeys[0], &privkeys[1], &privkeys[2], &privkeys[3])
+		};
+		let channel_update = |short_channel_id: u64| get_signed_channel_update(&secp_ctx, short_channel_id, 0, &privkeys[0]);
+
+		// An empty batch trivially passes
+		let mut batch = SignatureBatch::default();
+		assert!(batch.is_empty());
+		assert!(router.verify_signature_batch(&batch).is_ok());
+
+		// A batch of all-valid signatures passes
+		for short_channel_id in 0..10 {
+			batch.add_channel_announcement(&channel_announcement(short_channel_id));
+			batch.add_channel_update(&channel_update(short_channel_id), &pubkeys[0]);
+		}
+		assert_eq!(batch.len(), 10 * 5);
+		assert!(router.verify_signature_batch(&batch).is_ok());
+		assert_eq!(router.batch_verified_signatures.lock().unwrap().len(), 10 * 5);
+
+		// Handling a message from a passing batch uses up its signatures rather than checking them
+		// again
+		assert!(router.handle_channel_announcement(&channel_announcement(0)).is_ok());
+		assert_eq!(router.batch_verified_signatures.lock().unwrap().len(), 10 * 5 - 4);
+
+		// A single bad signature anywhere in the batch causes it to fail
+		let mut bad_announcement = channel_announcement(10);
+		bad_announcement.bitcoin_signature_2 = bad_announcement.bitcoin_signature_1;
+		let mut bad_batch = SignatureBatch::new();
+		for short_channel_id in 0..5 {
+			bad_batch.add_channel_announcement(&channel_announcement(short_channel_id));
+		}
+		bad_batch.add_channel_announcement(&bad_announcement);
+		for short_channel_id in 5..10 {
+			bad_batch.add_channel_announcement(&channel_announcement(short_channel_id));
+		}
+		match router.verify_signature_batch(&bad_batch) {
+			Err(e) => assert_eq!(e.err, "Invalid signature from remote node"),
+			Ok(_) => panic!(),
+		}
+		// ...and none of its signatures are remembered as verified, while those left over from the
+		// previous batch are forgotten
+		assert!(router.batch_verified_signatures.lock().unwrap().is_empty());
+
+		// Each passing batch replaces whatever was left over from the last one
+		assert!(router.verify_signature_batch(&batch).is_ok());
+		let mut small_batch = SignatureBatch::new();
+		small_batch.add_channel_update(&channel_update(0), &pubkeys[0]);
+		assert!(router.verify_signature_batch(&small_batch).is_ok());
+		assert_eq!(router.batch_verified_signatures.lock().unwrap().len(), 1);
+
+		// As does a channel_update signed by the wrong node
+		batch.add_channel_update(&channel_update(10), &pubkeys[1]);
+		assert!(router.verify_signature_batch(&batch).is_err());
+		assert!(router.batch_verified_signatures.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn gossip_queue_test() {
+		let secp_ctx = Secp256k1::new();
+		let privkeys: Vec<SecretKey> = (1..11).map(|byte| SecretKey::from_slice(&[byte; 32]).unwrap()).collect();
+		let pubkeys: Vec<PublicKey> = privkeys.iter().map(|privkey| PublicKey::from_secret_key(&secp_ctx, privkey)).collect();
+		let our_key = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42; 32]).unwrap());
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Bitcoin, Arc::clone(&logger)));
+		let router = Router::new(our_key, Network::Bitcoin, chain_monitor, Arc::clone(&logger));
+
+		// 5 channels, each between a distinct pair of nodes, with an update in each direction and
+		// a node_announcement for each node, for 25 messages in total.
+		let queue = GossipQueue::new();
+		for short_channel_id in 0..5 {
+			let (node_1, node_2) = (short_channel_id as usize * 2, short_channel_id as usize * 2 + 1);
+			assert!(queue.push(QueuedGossipMessage::ChannelAnnouncement(get_signed_channel_announcement(&secp_ctx, short_channel_id,
+				&privkeys[node_1], &privkeys[node_2], &privkeys[node_1], &privkeys[node_2]))));
+			for &(flags, node) in [(0, node_1), (1, node_2)].iter() {
+				assert!(queue.push(QueuedGossipMessage::ChannelUpdate(get_signed_channel_update(&secp_ctx, short_channel_id, flags, &privkeys[node]))));
+			}
+			for &node in [node_1, node_2].iter() {
+				assert!(queue.push(QueuedGossipMessage::NodeAnnouncement(get_signed_node_announcement(&secp_ctx, [node as u8; 32], &privkeys[node]))));
+			}
+		}
+		assert_eq!(queue.len(), 25);
+
+		let (more_pending, errors) = queue.process_pending(&router, 10);
+		assert!(more_pending && errors.is_empty());
+		assert_eq!(queue.len(), 15);
+		let (more_pending, errors) = queue.process_pending(&router, 10);
+		assert!(more_pending && errors.is_empty());
+		assert_eq!(queue.len(), 5);
+
+		// A message the handler rejects is dropped, with its error handed back to the caller
+		let mut bad_announcement = match queue.pending.lock().unwrap()[3] {
+			QueuedGossipMessage::NodeAnnouncement(ref msg) => msg.clone(),
+			_ => panic!(),
+		};
+		bad_announcement.contents.timestamp = 2;
+		assert!(queue.push(QueuedGossipMessage::NodeAnnouncement(bad_announcement)));
+		let (more_pending, errors) = queue.process_pending(&router, 10);
+		assert!(!more_pending);
+		assert_eq!(errors.len(), 1);
+		assert_eq!(errors[0].err, "Invalid signature from remote node");
+		assert!(queue.is_empty());
+		let (more_pending, errors) = queue.process_pending(&router, 10);
+		assert!(!more_pending && errors.is_empty());
+
+		let network = router.network_map.read().unwrap();
+		assert_eq!(network.channels.len(), 5);
+		for (_, channel) in network.channels.iter() {
+			assert_eq!(channel.one_to_two.last_update, 1);
+			assert_eq!(channel.two_to_one.last_update, 1);
+		}
+		// Our own node is always in the map
+		assert_eq!(network.nodes.len(), 10 + 1);
+		for (idx, node_id) in pubkeys.iter().enumerate() {
+			let node = network.nodes.get(node_id).unwrap();
+			assert_eq!(node.last_update, 1);
+			assert_eq!(node.alias, [idx as u8; 32]);
+		}
+	}
+
+	#[test]
+	fn gossip_queue_limit_test() {
+		let secp_ctx = Secp256k1::new();
+		let privkey = SecretKey::from_slice(&[42; 32]).unwrap();
+		let update = msgs::ChannelUpdate {
+			signature: secp_ctx.sign(&Message::from_slice(&[1; 32]).unwrap(), &privkey),
+			contents: msgs::UnsignedChannelUpdate {
+				chain_hash: Sha256dHash::hash(&[0; 32]),
+				short_channel_id: 42,
+				timestamp: 1,
+				flags: 0,
+				cltv_expiry_delta: 144,
+				htlc_minimum_msat: 1000,
+				fee_base_msat: 1000,
+				fee_proportional_millionths: 1,
+				excess_data: Vec::new(),
+			},
+		};
+
+		let queue = GossipQueue::default();
+		assert!(queue.is_empty());
+		for _ in 0..MAX_QUEUED_GOSSIP_MESSAGES {
+			assert!(queue.push(QueuedGossipMessage::ChannelUpdate(update.clone())));
+		}
+		assert!(!queue.push(QueuedGossipMessage::ChannelUpdate(update.clone())));
+		assert_eq!(queue.len(), MAX_QUEUED_GOSSIP_MESSAGES);
+	}
+
+	#[test]
+	fn gossip_dedup_test() {
+		let secp_ctx = Secp256k1::new();
+		let privkey = SecretKey::from_slice(&[42; 32]).unwrap();
+		let signature = secp_ctx.sign(&Message::from_slice(&[1; 32]).unwrap(), &privkey);
+		let now: u32 = 1_600_000_000;
+
+		let channel_update = |flags: u16, timestamp: u32| msgs::ChannelUpdate {
+			signature,
+			contents: msgs::UnsignedChannelUpdate {
+				chain_hash: Sha256dHash::hash(&[0; 32]),
+				short_channel_id: 42,
+				timestamp,
+				flags,
+				cltv_expiry_delta: 144,
+				htlc_minimum_msat: 1000,
+				fee_base_msat: 1000,
+				fee_proportional_millionths: 1,
+				excess_data: Vec::new(),
+			},
+		};
+
+		let store = GossipDedupStore::new();
+		assert!(store.should_process_at(GossipMessage::ChannelUpdate(&channel_update(0, now - 100)), now as u64));
+		// An exact duplicate or an older update is rejected...
+		assert!(!store.should_process_at(GossipMessage::ChannelUpdate(&channel_update(0, now - 100)), now as u64));
+		assert!(!store.should_process_at(GossipMessage::ChannelUpdate(&channel_update(0, now - 200)), now as u64));
+		// ...but a newer one, or one for the other direction, is accepted
+		assert!(store.should_process_at(GossipMessage::ChannelUpdate(&channel_update(0, now - 50)), now as u64));
+		assert!(store.should_process_at(GossipMessage::ChannelUpdate(&channel_update(1, now - 200)), now as u64));
+
+		// Gossip more than two weeks old is dropped outright
+		let two_weeks = 60 * 60 * 24 * 14;
+		assert!(!store.should_process_at(GossipMessage::ChannelUpdate(&channel_update(1, now - two_weeks - 1)), now as u64));
+
+		// Once entries expire they are pruned, though newer gossip is then still accepted
+		let later = now as u64 + two_weeks as u64;
+		assert!(store.should_process_at(GossipMessage::ChannelUpdate(&channel_update(0, now)), later));
+		{
+			let state = store.state.lock().unwrap();
+			assert_eq!(state.channel_updates.len(), 1);
+			assert_eq!(state.channel_updates.get(&(42, false)), Some(&now));
+		}
+	}
 }
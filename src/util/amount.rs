@@ -0,0 +1,160 @@
+//! Newtypes for amounts denominated in satoshis and millisatoshis, so that the two can't be
+//! confused for one another.
+
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+
+/// An amount denominated in millisatoshis.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct MilliSatoshi(pub u64);
+
+/// An amount denominated in satoshis.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Satoshi(pub u64);
+
+impl Satoshi {
+	/// Converts to millisatoshis, returning None if the result would overflow a u64.
+	pub fn to_msat(&self) -> Option<MilliSatoshi> {
+		self.0.checked_mul(1000).map(|msat| MilliSatoshi(msat))
+	}
+}
+
+impl MilliSatoshi {
+	/// Converts to satoshis, rounding down any sub-satoshi remainder (as is done when building
+	/// on-chain outputs).
+	pub fn to_sat_floor(&self) -> Satoshi {
+		Satoshi(self.0 / 1000)
+	}
+
+	/// Adds two amounts, returning None on overflow. Use this when accounting for balances where
+	/// an overflow indicates a counterparty-provided value is bogus and must be rejected.
+	pub fn checked_add(self, other: MilliSatoshi) -> Option<MilliSatoshi> {
+		self.0.checked_add(other.0).map(|msat| MilliSatoshi(msat))
+	}
+
+	/// Subtracts other from self, returning None if the result would be negative. Use this when
+	/// removing value from a balance, eg to check that an HTLC can be afforded at all.
+	pub fn checked_sub(self, other: MilliSatoshi) -> Option<MilliSatoshi> {
+		self.0.checked_sub(other.0).map(|msat| MilliSatoshi(msat))
+	}
+
+	/// Adds two amounts, clamping at u64::MAX. Only appropriate where the result is used as an
+	/// upper bound or for display and a clamped value can't cause funds to be misallocated.
+	pub fn saturating_add(self, other: MilliSatoshi) -> MilliSatoshi {
+		MilliSatoshi(self.0.saturating_add(other.0))
+	}
+
+	/// Subtracts other from self, clamping at zero. Only appropriate for computing limits, eg the
+	/// amount still available to send, where "nothing left" is the correct answer on underflow.
+	pub fn saturating_sub(self, other: MilliSatoshi) -> MilliSatoshi {
+		MilliSatoshi(self.0.saturating_sub(other.0))
+	}
+}
+
+// Unlike plain u64 arithmetic, these panic on overflow in release builds too, as silently wrapping
+// an amount can misallocate funds.
+macro_rules! impl_amount_ops {
+	($ty: ident) => {
+		impl Add for $ty {
+			type Output = $ty;
+			fn add(self, other: $ty) -> $ty {
+				$ty(self.0.checked_add(other.0).expect("Amount addition overflowed"))
+			}
+		}
+		impl AddAssign for $ty {
+			fn add_assign(&mut self, other: $ty) {
+				*self = *self + other;
+			}
+		}
+		impl Sub for $ty {
+			type Output = $ty;
+			fn sub(self, other: $ty) -> $ty {
+				$ty(self.0.checked_sub(other.0).expect("Amount subtraction underflowed"))
+			}
+		}
+		impl SubAssign for $ty {
+			fn sub_assign(&mut self, other: $ty) {
+				*self = *self - other;
+			}
+		}
+	}
+}
+impl_amount_ops!(MilliSatoshi);
+impl_amount_ops!(Satoshi);
+
+#[cfg(test)]
+mod tests {
+	use util::amount::{MilliSatoshi, Satoshi};
+	use util::ser::{Readable, Writeable};
+
+	#[test]
+	fn test_conversion() {
+		assert_eq!(Satoshi(0).to_msat(), Some(MilliSatoshi(0)));
+		assert_eq!(Satoshi(100_000).to_msat(), Some(MilliSatoshi(100_000_000)));
+		assert_eq!(MilliSatoshi(100_000_999).to_sat_floor(), Satoshi(100_000));
+		assert_eq!(MilliSatoshi(999).to_sat_floor(), Satoshi(0));
+	}
+
+	#[test]
+	fn test_conversion_overflow() {
+		let max_convertible = ::std::u64::MAX / 1000;
+		assert_eq!(Satoshi(max_convertible).to_msat(), Some(MilliSatoshi(max_convertible * 1000)));
+		assert_eq!(Satoshi(max_convertible + 1).to_msat(), None);
+		assert_eq!(Satoshi(::std::u64::MAX).to_msat(), None);
+	}
+
+	#[test]
+	fn test_arithmetic() {
+		assert_eq!(MilliSatoshi(1000) + MilliSatoshi(234), MilliSatoshi(1234));
+		assert_eq!(Satoshi(1000) - Satoshi(1), Satoshi(999));
+		let mut amt = MilliSatoshi(5);
+		amt += MilliSatoshi(10);
+		amt -= MilliSatoshi(3);
+		assert_eq!(amt, MilliSatoshi(12));
+		assert!(MilliSatoshi(1) < MilliSatoshi(2));
+	}
+
+	#[test]
+	#[should_panic(expected = "Amount addition overflowed")]
+	fn test_add_overflow_panics() {
+		let _ = MilliSatoshi(::std::u64::MAX) + MilliSatoshi(1);
+	}
+
+	#[test]
+	#[should_panic(expected = "Amount subtraction underflowed")]
+	fn test_sub_underflow_panics() {
+		let mut amt = Satoshi(1);
+		amt -= Satoshi(2);
+	}
+
+	#[test]
+	fn test_checked_arithmetic() {
+		let max = MilliSatoshi(::std::u64::MAX);
+		assert_eq!(MilliSatoshi(1).checked_add(MilliSatoshi(2)), Some(MilliSatoshi(3)));
+		assert_eq!(max.checked_add(MilliSatoshi(0)), Some(max));
+		assert_eq!(max.checked_add(MilliSatoshi(1)), None);
+		assert_eq!(MilliSatoshi(3).checked_sub(MilliSatoshi(3)), Some(MilliSatoshi(0)));
+		assert_eq!(MilliSatoshi(3).checked_sub(MilliSatoshi(4)), None);
+		assert_eq!(MilliSatoshi(0).checked_sub(max), None);
+	}
+
+	#[test]
+	fn test_saturating_arithmetic() {
+		let max = MilliSatoshi(::std::u64::MAX);
+		assert_eq!(MilliSatoshi(1).saturating_add(MilliSatoshi(2)), MilliSatoshi(3));
+		assert_eq!(max.saturating_add(MilliSatoshi(1)), max);
+		assert_eq!(max.saturating_add(max), max);
+		assert_eq!(MilliSatoshi(5).saturating_sub(MilliSatoshi(2)), MilliSatoshi(3));
+		assert_eq!(MilliSatoshi(3).saturating_sub(MilliSatoshi(4)), MilliSatoshi(0));
+		assert_eq!(MilliSatoshi(0).saturating_sub(max), MilliSatoshi(0));
+	}
+
+	#[test]
+	fn test_serialization() {
+		let encoded = MilliSatoshi(0x0102030405060708).encode();
+		assert_eq!(encoded, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+		let decoded: MilliSatoshi = Readable::read(&mut ::std::io::Cursor::new(&encoded)).unwrap();
+		assert_eq!(decoded, MilliSatoshi(0x0102030405060708));
+		let decoded: Satoshi = Readable::read(&mut ::std::io::Cursor::new(&Satoshi(42).encode())).unwrap();
+		assert_eq!(decoded, Satoshi(42));
+	}
+}
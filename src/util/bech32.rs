@@ -0,0 +1,268 @@
+//! Encoding and decoding of bech32, the checksummed base32 format defined in BIP 173 and used by
+//! segwit addresses and BOLT 11 invoices.
+
+use ln::msgs::HandleError;
+
+const CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+const CHECKSUM_LEN: usize = 6;
+/// The maximum length of a bech32 string, as defined in BIP 173.
+pub const MAX_LEN: usize = 90;
+
+/// A 5-bit value, ie a single bech32 data character.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct u5(u8);
+
+impl u5 {
+	/// Converts a u8 to a u5, failing if the value is out of range (ie >= 32).
+	pub fn try_from_u8(value: u8) -> Result<u5, HandleError> {
+		if value > 31 {
+			return Err(HandleError{err: "Value out of range for a u5", action: None});
+		}
+		Ok(u5(value))
+	}
+
+	/// Gets the value as a u8.
+	pub fn to_u8(&self) -> u8 {
+		self.0
+	}
+}
+
+fn polymod(values: &[u5]) -> u32 {
+	let mut chk: u32 = 1;
+	for v in values {
+		let top = chk >> 25;
+		chk = (chk & 0x1ffffff) << 5 ^ v.0 as u32;
+		for (i, gen) in GENERATOR.iter().enumerate() {
+			if (top >> i) & 1 == 1 {
+				chk ^= gen;
+			}
+		}
+	}
+	chk
+}
+
+fn hrp_expand(hrp: &[u8]) -> Vec<u5> {
+	let mut res = Vec::with_capacity(hrp.len() * 2 + 1);
+	for c in hrp {
+		res.push(u5(c >> 5));
+	}
+	res.push(u5(0));
+	for c in hrp {
+		res.push(u5(c & 0x1f));
+	}
+	res
+}
+
+/// Encodes the given human-readable part and data as a bech32 string, appending the checksum.
+/// The human-readable part is lower-cased and must consist of between 1 and 83 US-ASCII
+/// characters in the range [33-126].
+pub fn encode(hrp: &str, data: &[u5]) -> String {
+	let hrp = hrp.to_lowercase();
+
+	let mut values = hrp_expand(hrp.as_bytes());
+	values.extend_from_slice(data);
+	values.extend_from_slice(&[u5(0); CHECKSUM_LEN]);
+	let checksum = polymod(&values) ^ 1;
+
+	let mut res = String::with_capacity(hrp.len() + 1 + data.len() + CHECKSUM_LEN);
+	res.push_str(&hrp);
+	res.push('1');
+	for v in data {
+		res.push(CHARSET[v.0 as usize] as char);
+	}
+	for i in 0..CHECKSUM_LEN {
+		res.push(CHARSET[((checksum >> (5 * (5 - i))) & 0x1f) as usize] as char);
+	}
+	res
+}
+
+/// Decodes the given bech32 string, checking its checksum and returning the (lower-cased)
+/// human-readable part and the data (without the checksum).
+///
+/// Per BIP 173, strings longer than 90 characters or using mixed case are rejected.
+pub fn decode(s: &str) -> Result<(String, Vec<u5>), HandleError> {
+	if s.len() > MAX_LEN {
+		return Err(HandleError{err: "Bech32 string too long", action: None});
+	}
+	decode_without_length_limit(s)
+}
+
+/// Decodes the given bech32 string as decode() does, but without the 90 character limit, which
+/// BOLT 11 invoices do not follow.
+pub fn decode_without_length_limit(s: &str) -> Result<(String, Vec<u5>), HandleError> {
+	if s.bytes().any(|c| c < 33 || c > 126) {
+		return Err(HandleError{err: "Invalid character in bech32 string", action: None});
+	}
+	let lower = s.to_lowercase();
+	if lower != s && s.to_uppercase() != s {
+		return Err(HandleError{err: "Mixed-case bech32 string", action: None});
+	}
+
+	let sep = match lower.rfind('1') {
+		Some(0) => return Err(HandleError{err: "Empty bech32 human-readable part", action: None}),
+		Some(sep) => sep,
+		None => return Err(HandleError{err: "Missing bech32 separator", action: None}),
+	};
+	let (hrp, data) = (&lower[..sep], &lower.as_bytes()[sep + 1..]);
+	if data.len() < CHECKSUM_LEN {
+		return Err(HandleError{err: "Bech32 data too short to contain a checksum", action: None});
+	}
+
+	let mut values = hrp_expand(hrp.as_bytes());
+	let hrp_values_len = values.len();
+	for c in data {
+		match CHARSET.iter().position(|a| a == c) {
+			Some(v) => values.push(u5(v as u8)),
+			None => return Err(HandleError{err: "Invalid bech32 data character", action: None}),
+		}
+	}
+	if polymod(&values) != 1 {
+		return Err(HandleError{err: "Invalid bech32 checksum", action: None});
+	}
+
+	let data_len = values.len() - CHECKSUM_LEN;
+	Ok((hrp.to_owned(), values[hrp_values_len..data_len].to_vec()))
+}
+
+/// Regroups 5-bit values into bytes, dropping any incomplete trailing byte (which is padding).
+pub fn u5s_to_bytes(data: &[u5]) -> Vec<u8> {
+	let mut res = Vec::with_capacity(data.len() * 5 / 8);
+	let mut acc: u32 = 0;
+	let mut bits = 0;
+	for v in data {
+		acc = (acc << 5) | v.0 as u32;
+		bits += 5;
+		if bits >= 8 {
+			bits -= 8;
+			res.push((acc >> bits) as u8);
+		}
+	}
+	res
+}
+
+/// Regroups 5-bit values into bytes, padding an incomplete trailing byte with zero bits rather
+/// than dropping it, as is done when hashing BOLT 11 invoice data for signing.
+pub fn u5s_to_padded_bytes(data: &[u5]) -> Vec<u8> {
+	let mut res = Vec::with_capacity((data.len() * 5 + 7) / 8);
+	let mut acc: u32 = 0;
+	let mut bits = 0;
+	for v in data {
+		acc = (acc << 5) | v.0 as u32;
+		bits += 5;
+		if bits >= 8 {
+			bits -= 8;
+			res.push((acc >> bits) as u8);
+		}
+	}
+	if bits > 0 {
+		res.push((acc << (8 - bits)) as u8);
+	}
+	res
+}
+
+/// Regroups bytes into 5-bit values, padding the last value with zero bits.
+pub fn bytes_to_u5s(data: &[u8]) -> Vec<u5> {
+	let mut res = Vec::with_capacity((data.len() * 8 + 4) / 5);
+	let mut acc: u32 = 0;
+	let mut bits = 0;
+	for b in data {
+		acc = (acc << 8) | *b as u32;
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			res.push(u5(((acc >> bits) & 0x1f) as u8));
+		}
+	}
+	if bits > 0 {
+		res.push(u5(((acc << (5 - bits)) & 0x1f) as u8));
+	}
+	res
+}
+
+#[cfg(test)]
+mod tests {
+	use util::bech32::{bytes_to_u5s, decode, decode_without_length_limit, encode, u5, u5s_to_bytes, u5s_to_padded_bytes};
+
+	#[test]
+	fn test_valid_vectors() {
+		// From BIP 173
+		let vectors = [
+			"A12UEL5L",
+			"a12uel5l",
+			"an83characterlonghumanreadablepartthatcontainsthenumber1andtheexcludedcharactersbio1tt5tgs",
+			"abcdef1qpzry9x8gf2tvdw0s3jn54khce6mua7lmqqqxw",
+			"11qqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqc8247j",
+			"split1checkupstagehandshakeupstreamerranterredcaperred2y9e3w",
+			"?1ezyfcl",
+		];
+		for s in vectors.iter() {
+			let (hrp, data) = decode(s).unwrap();
+			assert_eq!(encode(&hrp, &data), s.to_lowercase());
+		}
+
+		let (hrp, data) = decode("abcdef1qpzry9x8gf2tvdw0s3jn54khce6mua7lmqqqxw").unwrap();
+		assert_eq!(hrp, "abcdef");
+		assert_eq!(data, (0..32).map(|i| u5::try_from_u8(i).unwrap()).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn test_invalid_vectors() {
+		// From BIP 173
+		let vectors = [
+			("\x201nwldj5", "Invalid character in bech32 string"),
+			("\x7f1axkwrx", "Invalid character in bech32 string"),
+			("\u{80}1eym55h", "Invalid character in bech32 string"),
+			("an84characterslonghumanreadablepartthatcontainsthenumber1andtheexcludedcharactersbio1569pvx", "Bech32 string too long"),
+			("pzry9x0s0muk", "Missing bech32 separator"),
+			("1pzry9x0s0muk", "Empty bech32 human-readable part"),
+			("x1b4n0q5v", "Invalid bech32 data character"),
+			("li1dgmt3", "Bech32 data too short to contain a checksum"),
+			("de1lg7wt\u{ff}", "Invalid character in bech32 string"),
+			("A1G7SGD8", "Invalid bech32 checksum"),
+			("10a06t8", "Empty bech32 human-readable part"),
+			("1qzzfhee", "Empty bech32 human-readable part"),
+		];
+		for &(s, err) in vectors.iter() {
+			assert_eq!(decode(s).unwrap_err().err, err);
+		}
+	}
+
+	#[test]
+	fn test_mixed_case() {
+		assert_eq!(decode("a12UEL5L").unwrap_err().err, "Mixed-case bech32 string");
+		assert_eq!(decode("A12uel5l").unwrap_err().err, "Mixed-case bech32 string");
+	}
+
+	#[test]
+	fn test_round_trip() {
+		let data: Vec<u5> = (0..50).map(|i| u5::try_from_u8(i % 32).unwrap()).collect();
+		let encoded = encode("lnbc", &data);
+		assert_eq!(decode(&encoded).unwrap(), ("lnbc".to_owned(), data.clone()));
+		assert_eq!(decode(&encoded.to_uppercase()).unwrap(), ("lnbc".to_owned(), data));
+
+		assert!(u5::try_from_u8(31).is_ok());
+		assert!(u5::try_from_u8(32).is_err());
+
+		let long: Vec<u5> = (0..200).map(|i| u5::try_from_u8(i % 32).unwrap()).collect();
+		let encoded = encode("lnbc", &long);
+		assert_eq!(decode(&encoded).unwrap_err().err, "Bech32 string too long");
+		assert_eq!(decode_without_length_limit(&encoded).unwrap(), ("lnbc".to_owned(), long));
+	}
+
+	#[test]
+	fn test_regrouping() {
+		for len in 0..12 {
+			let bytes: Vec<u8> = (0..len).map(|i| (i * 37 + 200) as u8).collect();
+			let values = bytes_to_u5s(&bytes);
+			assert_eq!(values.len(), (len * 8 + 4) / 5);
+			assert_eq!(u5s_to_bytes(&values), bytes);
+		}
+		assert_eq!(bytes_to_u5s(&[0xff]), vec![u5(31), u5(28)]);
+		assert_eq!(u5s_to_bytes(&[u5(31), u5(28)]), vec![0xff]);
+		assert_eq!(u5s_to_padded_bytes(&[u5(31), u5(28)]), vec![0xff, 0]);
+		assert_eq!(u5s_to_padded_bytes(&[u5(31), u5(31), u5(31)]), vec![0xff, 0xfe]);
+		assert_eq!(u5s_to_padded_bytes(&[u5(1)]), vec![0x08]);
+	}
+}
@@ -248,6 +248,14 @@ mod real_chacha {
 				self.offset += count;
 			}
 		}
+
+		/// Gets the next len bytes of raw keystream, without any input to XOR it with.
+		pub fn get_keystream(&mut self, len: usize) -> Vec<u8> {
+			let zeros = vec![0; len];
+			let mut res = vec![0; len];
+			self.process(&zeros, &mut res);
+			res
+		}
 	}
 }
 #[cfg(not(feature = "fuzztarget"))]
@@ -267,6 +275,10 @@ mod fuzzy_chacha {
 		pub fn process(&mut self, input: &[u8], output: &mut [u8]) {
 			output.copy_from_slice(input);
 		}
+
+		pub fn get_keystream(&mut self, len: usize) -> Vec<u8> {
+			vec![0; len]
+		}
 	}
 }
 #[cfg(feature = "fuzztarget")]
@@ -547,4 +559,36 @@ mod test {
 			assert_eq!(output, tv.keystream);
 		}
 	}
+
+	#[test]
+	fn test_chacha20_rfc8439_block_function() {
+		use hex;
+		// RFC 8439 section 2.3.2, which starts at block counter 1 so we skip the first block
+		let key: Vec<u8> = (0..32).collect();
+		let nonce = hex::decode("000000090000004a00000000").unwrap();
+		let mut c = ChaCha20::new(&key, &nonce);
+		let keystream = c.get_keystream(128);
+		assert_eq!(keystream[64..], hex::decode("10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4ed2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e").unwrap()[..]);
+	}
+
+	#[test]
+	fn test_chacha20_rfc8439_encryption() {
+		use hex;
+		// RFC 8439 section 2.4.2, again starting at block counter 1
+		let key: Vec<u8> = (0..32).collect();
+		let nonce = hex::decode("000000000000004a00000000").unwrap();
+		let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+		let mut c = ChaCha20::new(&key, &nonce);
+		c.get_keystream(64);
+		let mut ciphertext = vec![0; plaintext.len()];
+		c.process(&plaintext[..], &mut ciphertext);
+		assert_eq!(ciphertext, hex::decode("6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0bf91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d807ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab77937365af90bbf74a35be6b40b8eedf2785e42874d").unwrap());
+
+		// The keystream is the same as what we XOR'd with
+		let mut c = ChaCha20::new(&key, &nonce);
+		let keystream = c.get_keystream(64 + plaintext.len());
+		for (i, byte) in plaintext.iter().enumerate() {
+			assert_eq!(ciphertext[i], byte ^ keystream[64 + i]);
+		}
+	}
 }
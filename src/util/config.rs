@@ -1,7 +1,8 @@
 //! Various user-configurable channel limits and settings which ChannelManager
 //! applies for you.
 
-use ln::channelmanager::{BREAKDOWN_TIMEOUT, MAX_LOCAL_BREAKDOWN_TIMEOUT};
+use ln::channelmanager::{BREAKDOWN_TIMEOUT, MAX_LOCAL_BREAKDOWN_TIMEOUT, CLTV_EXPIRY_DELTA};
+use ln::channelmonitor::{CLTV_CLAIM_BUFFER, LATENCY_GRACE_PERIOD_BLOCKS};
 
 /// Top-level config which holds ChannelHandshakeLimits and ChannelConfig.
 #[derive(Clone, Debug)]
@@ -12,6 +13,10 @@ pub struct UserConfig {
 	pub peer_channel_config_limits: ChannelHandshakeLimits,
 	/// Channel config which affects behavior during channel lifetime.
 	pub channel_options: ChannelConfig,
+	/// When generating route hints for an invoice, include every usable private channel, rather
+	/// than only those with enough inbound capacity to receive the invoice amount.
+	/// Defaults to false.
+	pub include_all_private_channel_hints: bool,
 }
 
 impl UserConfig {
@@ -21,6 +26,7 @@ impl UserConfig {
 			own_channel_config: ChannelHandshakeConfig::new(),
 			peer_channel_config_limits: ChannelHandshakeLimits::new(),
 			channel_options: ChannelConfig::new(),
+			include_all_private_channel_hints: false,
 		}
 	}
 }
@@ -142,6 +148,22 @@ pub struct ChannelConfig {
 	/// This may be allowed to change at runtime in a later update, however doing so must result in
 	/// update messages sent to notify all nodes of our updated relay fee.
 	pub fee_proportional_millionths: u32,
+	/// Base fee (in msat) the channel will charge for each HTLC it forwards.
+	///
+	/// If None, we charge roughly what it would cost us to claim the HTLC on-chain at the current
+	/// feerate.
+	///
+	/// This may be changed at runtime with ChannelManager::update_channel_config.
+	pub fee_base_msat: Option<u32>,
+	/// The difference in CLTV expiry we require between an HTLC we receive and the HTLC we forward
+	/// on over this channel.
+	///
+	/// Must be large enough to allow us to time out the outbound HTLC on-chain before the inbound
+	/// one expires. Opening a channel or calling ChannelManager::update_channel_config with a value
+	/// below that bound fails.
+	///
+	/// Defaults to 72 blocks.
+	pub cltv_expiry_delta: u16,
 	/// Set to announce the channel publicly and notify all nodes that they can route via this
 	/// channel.
 	///
@@ -161,7 +183,20 @@ pub struct ChannelConfig {
 	/// lightning payments, so we never require that our counterparties support this option.
 	///
 	/// This cannot be changed after a channel has been initialized.
-	pub commit_upfront_shutdown_pubkey: bool
+	pub commit_upfront_shutdown_pubkey: bool,
+	/// The number of blocks before an HTLC's cltv_expiry at which we consider it about to time out,
+	/// leaving room to fail back or claim it on-chain before the counterparty can.
+	///
+	/// Defaults to CLTV_CLAIM_BUFFER + LATENCY_GRACE_PERIOD_BLOCKS (9 blocks).
+	pub htlc_expiry_buffer_blocks: u32,
+	/// The maximum total value, in msat, of pending HTLCs which are dust (ie trimmed from) on
+	/// either side's commitment transaction. Dust HTLCs can't be claimed on-chain and go to fees if
+	/// the channel is force-closed, so this bounds how much we can lose that way. HTLCs we send or
+	/// are asked to forward which would put us over this limit are failed.
+	///
+	/// Defaults to 5_000_000 (5000 satoshis), which is also what channels serialized before this
+	/// limit existed are given when read.
+	pub max_dust_htlc_exposure_msat: u64,
 }
 
 impl ChannelConfig {
@@ -169,15 +204,23 @@ impl ChannelConfig {
 	pub fn new() -> Self {
 		ChannelConfig {
 			fee_proportional_millionths: 0,
+			fee_base_msat: None,
+			cltv_expiry_delta: CLTV_EXPIRY_DELTA,
 			announced_channel: false,
 			commit_upfront_shutdown_pubkey: true,
+			htlc_expiry_buffer_blocks: CLTV_CLAIM_BUFFER + LATENCY_GRACE_PERIOD_BLOCKS,
+			max_dust_htlc_exposure_msat: 5_000_000,
 		}
 	}
 }
 
 //Add write and readable traits to channelconfig
-impl_writeable!(ChannelConfig, 8+1+1, {
+impl_writeable!(ChannelConfig, 8+5+2+1+1+4+8, {
 	fee_proportional_millionths,
+	fee_base_msat,
+	cltv_expiry_delta,
 	announced_channel,
-	commit_upfront_shutdown_pubkey
+	commit_upfront_shutdown_pubkey,
+	htlc_expiry_buffer_blocks,
+	max_dust_htlc_exposure_msat
 });
@@ -86,6 +86,9 @@ pub enum Event {
 		/// the payment has failed, not just the route in question. If this is not set, you may
 		/// retry the payment via a different route.
 		rejected_by_dest: bool,
+		/// The channel the failure was attributed to, if it could be identified from the error
+		/// onion. This should be passed to Score::payment_path_failed so that future routes avoid it.
+		failed_short_channel_id: Option<u64>,
 #[cfg(test)]
 		error_code: Option<u16>,
 	},
@@ -192,6 +195,14 @@ pub enum MessageSendEvent {
 		/// The message which should be sent.
 		msg: msgs::ChannelReestablish,
 	},
+	/// Used to indicate that a channel_update should be sent to the peer with the given node_id.
+	/// Only generated for private channels, whose channel_updates are never broadcast.
+	SendChannelUpdate {
+		/// The node_id of the node which should receive this message
+		node_id: PublicKey,
+		/// The message which should be sent.
+		msg: msgs::ChannelUpdate,
+	},
 	/// Used to indicate that a channel_announcement and channel_update should be broadcast to all
 	/// peers (except the peer with node_id either msg.contents.node_id_1 or msg.contents.node_id_2).
 	BroadcastChannelAnnouncement {
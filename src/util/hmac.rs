@@ -0,0 +1,34 @@
+//! A small HMAC-SHA256 helper for the many places (mostly onion handling) which key an HMAC and
+//! feed it a single buffer.
+
+use bitcoin_hashes::{Hash, HashEngine};
+use bitcoin_hashes::hmac::{Hmac, HmacEngine};
+use bitcoin_hashes::sha256::Hash as Sha256;
+
+/// Computes HMAC-SHA256(key, data)
+#[inline]
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+	let mut hmac = HmacEngine::<Sha256>::new(key);
+	hmac.input(data);
+	Hmac::from_engine(hmac).into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::hmac_sha256;
+
+	use hex;
+
+	#[test]
+	fn rfc4231_test_vectors() {
+		// Test case 1
+		assert_eq!(hmac_sha256(&[0x0b; 20], b"Hi There")[..],
+			hex::decode("b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7").unwrap()[..]);
+		// Test case 2
+		assert_eq!(hmac_sha256(b"Jefe", b"what do ya want for nothing?")[..],
+			hex::decode("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843").unwrap()[..]);
+		// Test case 6, with a key longer than the block size
+		assert_eq!(hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")[..],
+			hex::decode("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54").unwrap()[..]);
+	}
+}
@@ -0,0 +1,112 @@
+//! Signing and recovery for the "Lightning Signed Message" scheme used by lnd's and
+//! c-lightning's signmessage/verifymessage commands.
+//!
+//! A message is signed by producing a compact recoverable signature over the double-SHA256 of
+//! "Lightning Signed Message:" followed by the message bytes. The signature is serialized as a
+//! 65-byte array: a header byte of 31 plus the recovery id, followed by the 64-byte compact
+//! signature (this is the form the above tools zbase32-encode). Verification recovers the
+//! signing public key, which the caller should compare against the expected node_id.
+
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::{Hash, HashEngine};
+
+use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::recovery::{RecoverableSignature, RecoveryId};
+use secp256k1::{Error, Secp256k1};
+
+/// The prefix which is prepended to messages before they are hashed and signed.
+pub const LN_SIGNED_MSG_PREFIX: &[u8] = b"Lightning Signed Message:";
+
+/// Gets the double-SHA256 hash which is signed for the given prefix and message.
+pub fn hash_to_sign(prefix: &[u8], msg: &[u8]) -> Sha256dHash {
+	let mut engine = Sha256dHash::engine();
+	engine.input(prefix);
+	engine.input(msg);
+	Sha256dHash::from_engine(engine)
+}
+
+/// Signs the given message with the given key, returning the 65-byte header-prefixed compact
+/// recoverable signature.
+pub fn sign(msg: &[u8], sk: &SecretKey) -> [u8; 65] {
+	let secp_ctx = Secp256k1::signing_only();
+	let msg_hash = hash_to_message!(&hash_to_sign(LN_SIGNED_MSG_PREFIX, msg)[..]);
+	let (recovery_id, compact_sig) = secp_ctx.sign_recoverable(&msg_hash, sk).serialize_compact();
+
+	let mut res = [0; 65];
+	res[0] = recovery_id.to_i32() as u8 + 31;
+	res[1..].copy_from_slice(&compact_sig[..]);
+	res
+}
+
+/// Recovers the public key which signed the given message, given a signature as returned by
+/// sign(). Fails if the signature is malformed, but note that any well-formed signature will
+/// recover *some* public key, so you must check that the result is the one you expected.
+pub fn recover(msg: &[u8], sig: &[u8]) -> Result<PublicKey, Error> {
+	if sig.len() != 65 || sig[0] < 31 {
+		return Err(Error::InvalidSignature);
+	}
+	let recovery_id = RecoveryId::from_i32(sig[0] as i32 - 31)?;
+	let sig = RecoverableSignature::from_compact(&sig[1..], recovery_id)?;
+
+	let secp_ctx = Secp256k1::verification_only();
+	let msg_hash = hash_to_message!(&hash_to_sign(LN_SIGNED_MSG_PREFIX, msg)[..]);
+	secp_ctx.recover(&msg_hash, &sig)
+}
+
+#[cfg(test)]
+mod tests {
+	use util::message_signing::{hash_to_sign, recover, sign, LN_SIGNED_MSG_PREFIX};
+	use util::zbase32;
+
+	use secp256k1::key::{PublicKey, SecretKey};
+	use secp256k1::Secp256k1;
+
+	use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+	use bitcoin_hashes::Hash;
+
+	use hex;
+
+	fn one_key() -> SecretKey {
+		let mut one = [0; 32];
+		one[31] = 1;
+		SecretKey::from_slice(&one).unwrap()
+	}
+
+	#[test]
+	fn test_hash_to_sign() {
+		let mut preimage = LN_SIGNED_MSG_PREFIX.to_vec();
+		preimage.extend_from_slice(b"test message");
+		assert_eq!(hash_to_sign(LN_SIGNED_MSG_PREFIX, b"test message"), Sha256dHash::hash(&preimage));
+	}
+
+	#[test]
+	fn test_sign_known_vector() {
+		// Signature over "test message" by the private key 1, as produced by lnd/c-lightning's
+		// signmessage
+		let sig = zbase32::decode("d9tibmnic9t5y41hg7hkakdcra94akas9ku3rmmj4ag9mritc8ok4p5qzefs78c9pqfhpuftqqzhydbdwfg7u6w6wdxcqpqn4sj4e73e").unwrap();
+		assert_eq!(&sig[..], &hex::decode("1fe350ac5567e3b06a5c3778ac286c263fac2b16faa7922d69d60df592b161e0ad376eba0b6e9d9f6b8bc6ccb173afc00c23a14dd9fa9ea0dec735c2d593a47728").unwrap()[..]);
+		assert_eq!(&sign(b"test message", &one_key())[..], &sig[..]);
+
+		let pubkey = PublicKey::from_slice(&hex::decode("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798").unwrap()[..]).unwrap();
+		assert_eq!(recover(b"test message", &sig).unwrap(), pubkey);
+	}
+
+	#[test]
+	fn test_recover() {
+		let secp_ctx = Secp256k1::new();
+		let sk = SecretKey::from_slice(&[42; 32]).unwrap();
+		let pubkey = PublicKey::from_secret_key(&secp_ctx, &sk);
+		let mut sig = sign(b"another message", &sk);
+		assert_eq!(recover(b"another message", &sig).unwrap(), pubkey);
+
+		// A signature over a different message recovers some other key
+		assert!(recover(b"other message", &sig).unwrap() != pubkey);
+
+		// Malformed signatures are rejected
+		assert!(recover(b"another message", &sig[..64]).is_err());
+		sig[0] = 30;
+		assert!(recover(b"another message", &sig).is_err());
+		sig[0] = 35;
+		assert!(recover(b"another message", &sig).is_err());
+	}
+}
@@ -3,9 +3,13 @@
 pub mod events;
 pub mod errors;
 pub mod ser;
+pub mod amount;
+pub mod zbase32;
+pub mod bech32;
 
 pub(crate) mod byte_utils;
 pub(crate) mod chacha20;
+pub(crate) mod hmac;
 #[cfg(not(feature = "fuzztarget"))]
 pub(crate) mod poly1305;
 pub(crate) mod chacha20poly1305rfc;
@@ -25,3 +29,6 @@ pub(crate) mod test_utils;
 
 #[macro_use]
 pub(crate) mod fuzz_wrappers;
+
+// Uses hash_to_message!, so has to come after fuzz_wrappers
+pub mod message_signing;
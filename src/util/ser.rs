@@ -14,12 +14,11 @@ use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 use std::marker::Sized;
 use ln::msgs::DecodeError;
 use ln::channelmanager::{PaymentPreimage, PaymentHash};
+use util::amount::{MilliSatoshi, Satoshi};
 use util::byte_utils;
 
 use util::byte_utils::{be64_to_array, be48_to_array, be32_to_array, be16_to_array, slice_to_be16, slice_to_be32, slice_to_be48, slice_to_be64};
 
-const MAX_BUF_SIZE: usize = 64 * 1024;
-
 /// A trait that is similar to std::io::Write but has one extra function which can be used to size
 /// buffers being written into.
 /// An impl is provided for any type that also impls std::io::Write which simply ignores size
@@ -162,6 +161,41 @@ impl<R: Read> Readable<R> for u8 {
 	}
 }
 
+/// The variable-length integer used in TLV streams (BOLT #1). Reading rejects non-minimal
+/// encodings.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct BigSize(pub u64);
+impl Writeable for BigSize {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		if self.0 < 0xfd {
+			(self.0 as u8).write(writer)
+		} else if self.0 <= 0xffff {
+			0xfdu8.write(writer)?;
+			(self.0 as u16).write(writer)
+		} else if self.0 <= 0xffffffff {
+			0xfeu8.write(writer)?;
+			(self.0 as u32).write(writer)
+		} else {
+			0xffu8.write(writer)?;
+			self.0.write(writer)
+		}
+	}
+}
+impl<R: Read> Readable<R> for BigSize {
+	fn read(reader: &mut R) -> Result<BigSize, DecodeError> {
+		let (value, min) = match <u8 as Readable<R>>::read(reader)? {
+			0xff => (<u64 as Readable<R>>::read(reader)?, 0x100000000),
+			0xfe => (<u32 as Readable<R>>::read(reader)? as u64, 0x10000),
+			0xfd => (<u16 as Readable<R>>::read(reader)? as u64, 0xfd),
+			n => return Ok(BigSize(n as u64)),
+		};
+		if value < min {
+			return Err(DecodeError::InvalidValue);
+		}
+		Ok(BigSize(value))
+	}
+}
+
 impl Writeable for bool {
 	#[inline]
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
@@ -246,6 +280,32 @@ impl<R, K, V> Readable<R> for HashMap<K, V>
 }
 
 // Vectors
+
+/// Writes a u16 count of elements followed by each element in turn. Fails if there are more
+/// elements than fit in a u16.
+pub(crate) fn write_u16_count_vec<W: Writer, T: Writeable>(w: &mut W, v: &[T]) -> Result<(), ::std::io::Error> {
+	if v.len() > ::std::u16::MAX as usize {
+		return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidInput, "Too many elements for a u16 count"));
+	}
+	(v.len() as u16).write(w)?;
+	for e in v.iter() {
+		e.write(w)?;
+	}
+	Ok(())
+}
+
+/// Reads a u16 count of elements followed by that many elements, as written by
+/// write_u16_count_vec. Fails with ShortRead if the declared count exceeds what is available.
+pub(crate) fn read_u16_count_vec<R: Read, T: Readable<R>>(r: &mut R) -> Result<Vec<T>, DecodeError> {
+	let len: u16 = Readable::read(r)?;
+	// Don't trust len for our allocation, the buffer may be much shorter than claimed
+	let mut ret = Vec::with_capacity(::std::cmp::min(len as usize, 64));
+	for _ in 0..len {
+		ret.push(T::read(r)?);
+	}
+	Ok(ret)
+}
+
 impl Writeable for Vec<u8> {
 	#[inline]
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
@@ -267,27 +327,14 @@ impl<R: Read> Readable<R> for Vec<u8> {
 impl Writeable for Vec<Signature> {
 	#[inline]
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
-		(self.len() as u16).write(w)?;
-		for e in self.iter() {
-			e.write(w)?;
-		}
-		Ok(())
+		write_u16_count_vec(w, &self[..])
 	}
 }
 
 impl<R: Read> Readable<R> for Vec<Signature> {
 	#[inline]
 	fn read(r: &mut R) -> Result<Self, DecodeError> {
-		let len: u16 = Readable::read(r)?;
-		let byte_size = (len as usize)
-		                .checked_mul(33)
-		                .ok_or(DecodeError::BadLengthDescriptor)?;
-		if byte_size > MAX_BUF_SIZE {
-			return Err(DecodeError::BadLengthDescriptor);
-		}
-		let mut ret = Vec::with_capacity(len as usize);
-		for _ in 0..len { ret.push(Signature::read(r)?); }
-		Ok(ret)
+		read_u16_count_vec(r)
 	}
 }
 
@@ -398,6 +445,30 @@ impl<R: Read> Readable<R> for PaymentHash {
 	}
 }
 
+impl Writeable for MilliSatoshi {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		self.0.write(w)
+	}
+}
+
+impl<R: Read> Readable<R> for MilliSatoshi {
+	fn read(r: &mut R) -> Result<Self, DecodeError> {
+		Ok(MilliSatoshi(Readable::read(r)?))
+	}
+}
+
+impl Writeable for Satoshi {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		self.0.write(w)
+	}
+}
+
+impl<R: Read> Readable<R> for Satoshi {
+	fn read(r: &mut R) -> Result<Self, DecodeError> {
+		Ok(Satoshi(Readable::read(r)?))
+	}
+}
+
 impl<T: Writeable> Writeable for Option<T> {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
 		match *self {
@@ -442,3 +513,183 @@ impl<R: Read> Readable<R> for OutPoint {
 		})
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use util::ser::{BigSize, Readable, ReadableArgs, Writeable, Writer, read_u16_count_vec, write_u16_count_vec};
+	use ln::msgs::DecodeError;
+
+	use std::io::{Cursor, Read};
+	use std::sync::Arc;
+
+	/// An example of a type which holds a reference to some shared context that isn't serialized
+	/// and thus must be provided when reading it back in.
+	struct ScaledValue {
+		value: u32,
+		scale: Arc<u32>,
+	}
+
+	impl Writeable for ScaledValue {
+		fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+			self.value.write(w)
+		}
+	}
+
+	impl<R: Read> ReadableArgs<R, Arc<u32>> for ScaledValue {
+		fn read(r: &mut R, scale: Arc<u32>) -> Result<Self, DecodeError> {
+			let value: u32 = Readable::read(r)?;
+			if value.checked_mul(*scale).is_none() {
+				return Err(DecodeError::InvalidValue);
+			}
+			Ok(ScaledValue { value, scale })
+		}
+	}
+
+	impl ScaledValue {
+		fn scaled(&self) -> u32 {
+			self.value * *self.scale
+		}
+	}
+
+	#[test]
+	fn readable_args_round_trip() {
+		let scale = Arc::new(1000);
+		let encoded = ScaledValue { value: 42, scale: scale.clone() }.encode();
+		assert_eq!(encoded, vec![0, 0, 0, 42]);
+
+		let read: ScaledValue = ReadableArgs::read(&mut Cursor::new(&encoded), scale.clone()).unwrap();
+		assert_eq!(read.value, 42);
+		assert_eq!(read.scaled(), 42000);
+		assert!(Arc::ptr_eq(&read.scale, &scale));
+
+		// The args can be used to validate what is read
+		let res: Result<ScaledValue, _> = ReadableArgs::read(&mut Cursor::new(&encoded), Arc::new(::std::u32::MAX));
+		match res {
+			Err(DecodeError::InvalidValue) => {},
+			_ => panic!("Expected InvalidValue"),
+		}
+	}
+
+	#[test]
+	fn u16_count_vec_round_trip() {
+		for v in [vec![], vec![0xdeadbeefu32], vec![1u32, 2, 3, ::std::u32::MAX]].iter() {
+			let mut encoded = Vec::new();
+			write_u16_count_vec(&mut encoded, &v[..]).unwrap();
+			assert_eq!(encoded.len(), 2 + v.len() * 4);
+			assert_eq!(&encoded[..2], &[0, v.len() as u8]);
+			let read: Vec<u32> = read_u16_count_vec(&mut Cursor::new(&encoded)).unwrap();
+			assert_eq!(read, *v);
+		}
+
+		let too_long = vec![0u8; ::std::u16::MAX as usize + 1];
+		assert!(write_u16_count_vec(&mut Vec::new(), &too_long[..]).is_err());
+	}
+
+	#[test]
+	fn u16_count_vec_short_read() {
+		// Declares three u32s but only contains two
+		let encoded = vec![0, 3, 0, 0, 0, 1, 0, 0, 0, 2];
+		let res: Result<Vec<u32>, _> = read_u16_count_vec(&mut Cursor::new(&encoded));
+		match res {
+			Err(DecodeError::ShortRead) => {},
+			_ => panic!("Expected ShortRead"),
+		}
+		let res: Result<Vec<u32>, _> = read_u16_count_vec(&mut Cursor::new(&[0xff, 0xff]));
+		match res {
+			Err(DecodeError::ShortRead) => {},
+			_ => panic!("Expected ShortRead"),
+		}
+	}
+
+	#[test]
+	fn u16_length_byte_vec() {
+		for v in [vec![], vec![42u8], vec![1u8, 2, 3, 4, 5]].iter() {
+			let encoded = v.encode();
+			assert_eq!(encoded.len(), 2 + v.len());
+			let read: Vec<u8> = Readable::read(&mut Cursor::new(&encoded)).unwrap();
+			assert_eq!(read, *v);
+		}
+
+		// Declares five bytes but only contains three
+		let res: Result<Vec<u8>, _> = Readable::read(&mut Cursor::new(&[0, 5, 1, 2, 3]));
+		match res {
+			Err(DecodeError::ShortRead) => {},
+			_ => panic!("Expected ShortRead"),
+		}
+	}
+
+	#[test]
+	fn big_size_encoding() {
+		use hex;
+		let vectors: [(u64, &str); 7] = [
+			(0, "00"),
+			(252, "fc"),
+			(253, "fd00fd"),
+			(65535, "fdffff"),
+			(65536, "fe00010000"),
+			(4294967295, "feffffffff"),
+			(4294967296, "ff0000000100000000"),
+		];
+		for &(value, encoded) in vectors.iter() {
+			let encoded = hex::decode(encoded).unwrap();
+			assert_eq!(BigSize(value).encode(), encoded);
+			let read: BigSize = Readable::read(&mut Cursor::new(&encoded)).unwrap();
+			assert_eq!(read, BigSize(value));
+		}
+	}
+
+	#[test]
+	fn big_size_rejects_non_minimal() {
+		use hex;
+		for encoded in ["fd00fc", "fe0000ffff", "ff00000000ffffffff"].iter() {
+			match <BigSize as Readable<Cursor<Vec<u8>>>>::read(&mut Cursor::new(hex::decode(encoded).unwrap())) {
+				Err(DecodeError::InvalidValue) => {},
+				_ => panic!(),
+			}
+		}
+		match <BigSize as Readable<Cursor<Vec<u8>>>>::read(&mut Cursor::new(hex::decode("fd00").unwrap())) {
+			Err(DecodeError::ShortRead) => {},
+			_ => panic!(),
+		}
+	}
+
+	#[test]
+	fn secp256k1_round_trips() {
+		use secp256k1::{Message, Secp256k1, Signature};
+		use secp256k1::key::{PublicKey, SecretKey};
+
+		let secp_ctx = Secp256k1::new();
+		let secret_key = SecretKey::from_slice(&[0x42; 32]).unwrap();
+		let pubkey = PublicKey::from_secret_key(&secp_ctx, &secret_key);
+		let encoded = pubkey.encode();
+		assert_eq!(encoded.len(), 33);
+		let read: PublicKey = Readable::read(&mut Cursor::new(&encoded)).unwrap();
+		assert_eq!(read, pubkey);
+
+		let sig = secp_ctx.sign(&Message::from_slice(&[0x01; 32]).unwrap(), &secret_key);
+		let encoded = sig.encode();
+		assert_eq!(encoded.len(), 64);
+		let read: Signature = Readable::read(&mut Cursor::new(&encoded)).unwrap();
+		assert_eq!(read, sig);
+	}
+
+	#[test]
+	fn secp256k1_invalid_encodings() {
+		use secp256k1::Signature;
+		use secp256k1::key::PublicKey;
+
+		match <PublicKey as Readable<Cursor<&[u8]>>>::read(&mut Cursor::new(&[0; 33][..])) {
+			Err(DecodeError::InvalidValue) => {},
+			_ => panic!("Expected InvalidValue"),
+		}
+		match <PublicKey as Readable<Cursor<&[u8]>>>::read(&mut Cursor::new(&[2; 32][..])) {
+			Err(DecodeError::ShortRead) => {},
+			_ => panic!("Expected ShortRead"),
+		}
+		// r is larger than the curve order
+		match <Signature as Readable<Cursor<&[u8]>>>::read(&mut Cursor::new(&[0xff; 64][..])) {
+			Err(DecodeError::InvalidValue) => {},
+			_ => panic!("Expected InvalidValue"),
+		}
+	}
+}
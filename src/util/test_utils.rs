@@ -11,6 +11,8 @@ use util::events;
 use util::logger::{Logger, Level, Record};
 use util::ser::{ReadableArgs, Writer};
 
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::util::hash::BitcoinHash;
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::blockdata::script::Script;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
@@ -20,6 +22,7 @@ use secp256k1::{SecretKey, PublicKey};
 
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::{mem};
 
 pub struct TestVecWriter(pub Vec<u8>);
@@ -146,6 +149,9 @@ impl msgs::ChannelMessageHandler for TestChannelMessageHandler {
 	fn handle_channel_reestablish(&self, _their_node_id: &PublicKey, _msg: &msgs::ChannelReestablish) -> Result<(), HandleError> {
 		Err(HandleError { err: "", action: None })
 	}
+	fn handle_channel_update(&self, _their_node_id: &PublicKey, _msg: &msgs::ChannelUpdate) -> Result<(), HandleError> {
+		Ok(())
+	}
 	fn peer_disconnected(&self, _their_node_id: &PublicKey, _no_connection_possible: bool) {}
 	fn peer_connected(&self, _their_node_id: &PublicKey) {}
 	fn handle_error(&self, _their_node_id: &PublicKey, _msg: &msgs::ErrorMessage) {}
@@ -160,11 +166,15 @@ impl events::MessageSendEventsProvider for TestChannelMessageHandler {
 	}
 }
 
-pub struct TestRoutingMessageHandler {}
+pub struct TestRoutingMessageHandler {
+	pub chan_upds_recvd: AtomicUsize,
+}
 
 impl TestRoutingMessageHandler {
 	pub fn new() -> Self {
-		TestRoutingMessageHandler {}
+		TestRoutingMessageHandler {
+			chan_upds_recvd: AtomicUsize::new(0),
+		}
 	}
 }
 impl msgs::RoutingMessageHandler for TestRoutingMessageHandler {
@@ -175,9 +185,16 @@ impl msgs::RoutingMessageHandler for TestRoutingMessageHandler {
 		Err(HandleError { err: "", action: None })
 	}
 	fn handle_channel_update(&self, _msg: &msgs::ChannelUpdate) -> Result<bool, HandleError> {
-		Err(HandleError { err: "", action: None })
+		self.chan_upds_recvd.fetch_add(1, Ordering::AcqRel);
+		Ok(true)
 	}
 	fn handle_htlc_fail_channel_update(&self, _update: &msgs::HTLCFailChannelUpdate) {}
+	fn handle_gossip_timestamp_filter(&self, msg: &msgs::GossipTimestampFilter) -> Result<(), HandleError> {
+		if msg.chain_hash != genesis_block(Network::Testnet).header.bitcoin_hash() {
+			return Err(HandleError { err: "", action: Some(msgs::ErrorAction::IgnoreError) });
+		}
+		Ok(())
+	}
 	fn get_next_channel_announcements(&self, _starting_point: u64, _batch_amount: u8) -> Vec<(msgs::ChannelAnnouncement, msgs::ChannelUpdate,msgs::ChannelUpdate)> {
 		Vec::new()
 	}
@@ -218,13 +235,17 @@ pub struct TestKeysInterface {
 	backing: keysinterface::KeysManager,
 	pub override_session_priv: Mutex<Option<SecretKey>>,
 	pub override_channel_id_priv: Mutex<Option<[u8; 32]>>,
+	pub channel_keys_derived: AtomicUsize,
 }
 
 impl keysinterface::KeysInterface for TestKeysInterface {
 	fn get_node_secret(&self) -> SecretKey { self.backing.get_node_secret() }
 	fn get_destination_script(&self) -> Script { self.backing.get_destination_script() }
 	fn get_shutdown_pubkey(&self) -> PublicKey { self.backing.get_shutdown_pubkey() }
-	fn get_channel_keys(&self, inbound: bool) -> keysinterface::ChannelKeys { self.backing.get_channel_keys(inbound) }
+	fn get_channel_keys(&self, inbound: bool) -> keysinterface::ChannelKeys {
+		self.channel_keys_derived.fetch_add(1, Ordering::AcqRel);
+		self.backing.get_channel_keys(inbound)
+	}
 
 	fn get_session_key(&self) -> SecretKey {
 		match *self.override_session_priv.lock().unwrap() {
@@ -233,6 +254,8 @@ impl keysinterface::KeysInterface for TestKeysInterface {
 		}
 	}
 
+	fn get_payment_preimage(&self) -> [u8; 32] { self.backing.get_payment_preimage() }
+
 	fn get_channel_id(&self) -> [u8; 32] {
 		match *self.override_channel_id_priv.lock().unwrap() {
 			Some(key) => key.clone(),
@@ -248,6 +271,7 @@ impl TestKeysInterface {
 			backing: keysinterface::KeysManager::new(seed, network, logger, now.as_secs(), now.subsec_nanos()),
 			override_session_priv: Mutex::new(None),
 			override_channel_id_priv: Mutex::new(None),
+			channel_keys_derived: AtomicUsize::new(0),
 		}
 	}
 }
@@ -0,0 +1,94 @@
+//! Encoding and decoding of zbase32, the human-oriented base32 encoding used by lnd's and
+//! c-lightning's signmessage/verifymessage commands. See
+//! https://philzimmermann.com/docs/human-oriented-base-32-encoding.txt
+
+use ln::msgs::HandleError;
+
+const ALPHABET: &[u8] = b"ybndrfg8ejkmcpqxot1uwisza345h769";
+
+/// Encodes the given bytes as a zbase32 string. The final character is zero-padded if the input
+/// length (in bits) is not a multiple of 5.
+pub fn encode(data: &[u8]) -> String {
+	let mut res = String::with_capacity((data.len() * 8 + 4) / 5);
+	let mut buffer: u16 = 0;
+	let mut bits = 0;
+	for byte in data {
+		buffer = (buffer << 8) | *byte as u16;
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			res.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+		}
+	}
+	if bits > 0 {
+		res.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+	}
+	res
+}
+
+/// Decodes the given zbase32 string. Any trailing bits which do not form a complete byte are
+/// dropped, so decode(encode(x)) == x.
+pub fn decode(data: &str) -> Result<Vec<u8>, HandleError> {
+	let mut res = Vec::with_capacity(data.len() * 5 / 8);
+	let mut buffer: u16 = 0;
+	let mut bits = 0;
+	for c in data.bytes() {
+		let value = match ALPHABET.iter().position(|a| *a == c) {
+			Some(value) => value as u16,
+			None => return Err(HandleError{err: "Invalid zbase32 character", action: None}),
+		};
+		buffer = (buffer << 5) | value;
+		bits += 5;
+		if bits >= 8 {
+			bits -= 8;
+			res.push((buffer >> bits) as u8);
+		}
+	}
+	Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+	use util::zbase32::{decode, encode};
+
+	const TEST_VECTORS: &[(&str, &[u8])] = &[
+		("", &[]),
+		("yy", &[0x00]),
+		("oy", &[0x80]),
+		("tqrey", &[0x8b, 0x88, 0x80]),
+		("6n9hq", &[0xf0, 0xbf, 0xc7]),
+		("4t7ye", &[0xd4, 0x7a, 0x04]),
+		("6im5sdy", &[0xf5, 0x57, 0xbb, 0x0c]),
+		("ybndrfg8ejkmcpqxot1uwisza345h769", &[0x00, 0x44, 0x32, 0x14, 0xc7, 0x42, 0x54, 0xb6, 0x35, 0xcf, 0x84, 0x65, 0x3a, 0x56, 0xd7, 0xc6, 0x75, 0xbe, 0x77, 0xdf]),
+	];
+
+	#[test]
+	fn test_encode() {
+		for &(encoded, raw) in TEST_VECTORS {
+			assert_eq!(encode(raw), encoded);
+		}
+	}
+
+	#[test]
+	fn test_decode() {
+		for &(encoded, raw) in TEST_VECTORS {
+			assert_eq!(&decode(encoded).unwrap()[..], raw);
+		}
+	}
+
+	#[test]
+	fn test_round_trip() {
+		for len in 0..64 {
+			let data: Vec<u8> = (0..len).map(|i| (i * 37 + len) as u8).collect();
+			assert_eq!(decode(&encode(&data)).unwrap(), data);
+		}
+	}
+
+	#[test]
+	fn test_decode_invalid() {
+		// 'l', 'v' and '2' (among others) are excluded from the alphabet, as are upper-case letters
+		for invalid in ["yl", "vy", "y2", "YY", "y y"].iter() {
+			assert_eq!(decode(invalid).unwrap_err().err, "Invalid zbase32 character");
+		}
+	}
+}
@@ -0,0 +1,313 @@
+//! End-to-end test wiring two ChannelManagers together through the public API only, with a
+//! mocked chain: opens a channel, confirms the funding transaction via simulated blocks, routes
+//! a payment over it and settles it.
+
+extern crate bitcoin;
+extern crate bitcoin_hashes;
+extern crate lightning;
+extern crate secp256k1;
+
+use lightning::chain::chaininterface::{BroadcasterInterface, ChainWatchInterfaceUtil, ConfirmationTarget, FeeEstimator};
+use lightning::chain::keysinterface::{KeysInterface, KeysManager};
+use lightning::chain::transaction::OutPoint;
+use lightning::ln::channelmanager::{ChannelManager, PaymentHash, PaymentPreimage};
+use lightning::ln::channelmonitor::SimpleManyChannelMonitor;
+use lightning::ln::msgs;
+use lightning::ln::msgs::{ChannelMessageHandler, LocalFeatures, RoutingMessageHandler};
+use lightning::ln::router::Router;
+use lightning::util::config::UserConfig;
+use lightning::util::events::{Event, EventsProvider, MessageSendEvent, MessageSendEventsProvider};
+use lightning::util::logger::{Logger, Record};
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
+use bitcoin::network::constants::Network;
+use bitcoin::util::hash::BitcoinHash;
+
+use bitcoin_hashes::sha256::Hash as Sha256;
+use bitcoin_hashes::Hash;
+
+use secp256k1::key::PublicKey;
+use secp256k1::Secp256k1;
+
+use std::sync::{Arc, Mutex};
+
+const CHAN_CONFIRM_DEPTH: u32 = 100;
+
+struct TestFeeEstimator;
+impl FeeEstimator for TestFeeEstimator {
+	fn get_est_sat_per_1000_weight(&self, _confirmation_target: ConfirmationTarget) -> u64 {
+		253
+	}
+}
+
+struct TestBroadcaster {
+	txn_broadcasted: Mutex<Vec<Transaction>>,
+}
+impl BroadcasterInterface for TestBroadcaster {
+	fn broadcast_transaction(&self, tx: &Transaction) {
+		self.txn_broadcasted.lock().unwrap().push(tx.clone());
+	}
+}
+
+struct TestLogger;
+impl Logger for TestLogger {
+	fn log(&self, _record: &Record) {}
+}
+
+struct Node {
+	chain_monitor: Arc<ChainWatchInterfaceUtil>,
+	tx_broadcaster: Arc<TestBroadcaster>,
+	node: Arc<ChannelManager>,
+	router: Router,
+}
+
+fn create_node(seed: u8) -> Node {
+	let secp_ctx = Secp256k1::new();
+	let logger: Arc<Logger> = Arc::new(TestLogger);
+	let feeest = Arc::new(TestFeeEstimator);
+	let chain_monitor = Arc::new(ChainWatchInterfaceUtil::new(Network::Testnet, Arc::clone(&logger)));
+	let tx_broadcaster = Arc::new(TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) });
+	let keys_manager = Arc::new(KeysManager::new(&[seed; 32], Network::Testnet, Arc::clone(&logger), 42, 42));
+	let chan_monitor = SimpleManyChannelMonitor::<OutPoint>::new(chain_monitor.clone(), tx_broadcaster.clone(), Arc::clone(&logger), feeest.clone());
+	let mut config = UserConfig::new();
+	config.channel_options.announced_channel = true;
+	config.peer_channel_config_limits.force_announced_channel_preference = false;
+	let node = ChannelManager::new(Network::Testnet, feeest, chan_monitor, chain_monitor.clone(), tx_broadcaster.clone(), Arc::clone(&logger), keys_manager.clone(), config).unwrap();
+	let router = Router::new(PublicKey::from_secret_key(&secp_ctx, &keys_manager.get_node_secret()), Network::Testnet, chain_monitor.clone(), logger);
+	Node { chain_monitor, tx_broadcaster, node, router }
+}
+
+fn confirm_transaction(chain: &ChainWatchInterfaceUtil, tx: &Transaction) {
+	assert!(chain.does_match_tx(tx));
+	let mut header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	chain.block_connected_checked(&header, 1, &[tx; 1], &[1; 1]);
+	for i in 2..CHAN_CONFIRM_DEPTH {
+		header = BlockHeader { version: 0x20000000, prev_blockhash: header.bitcoin_hash(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		chain.block_connected_checked(&header, i, &[tx; 0], &[0; 0]);
+	}
+}
+
+fn single_msg_event(node: &Node) -> MessageSendEvent {
+	let mut events = node.node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	events.pop().unwrap()
+}
+
+fn single_event(node: &Node) -> Event {
+	let mut events = node.node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	events.pop().unwrap()
+}
+
+/// Runs the commitment_signed/revoke_and_ack exchange after `origin` has sent `receiver` an
+/// update along with `commitment_signed`, leaving both sides with the update irrevocably
+/// committed.
+fn commitment_signed_dance(origin: &Node, receiver: &Node, commitment_signed: &msgs::CommitmentSigned) {
+	let origin_id = origin.node.get_our_node_id();
+	let receiver_id = receiver.node.get_our_node_id();
+
+	receiver.node.handle_commitment_signed(&origin_id, commitment_signed).unwrap();
+	let events = receiver.node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 2);
+	let receiver_raa = match events[0] {
+		MessageSendEvent::SendRevokeAndACK { ref node_id, ref msg } => {
+			assert_eq!(*node_id, origin_id);
+			msg.clone()
+		},
+		_ => panic!("Unexpected event"),
+	};
+	let receiver_cs = match events[1] {
+		MessageSendEvent::UpdateHTLCs { ref node_id, ref updates } => {
+			assert_eq!(*node_id, origin_id);
+			assert!(updates.update_add_htlcs.is_empty());
+			assert!(updates.update_fulfill_htlcs.is_empty());
+			updates.commitment_signed.clone()
+		},
+		_ => panic!("Unexpected event"),
+	};
+
+	origin.node.handle_revoke_and_ack(&receiver_id, &receiver_raa).unwrap();
+	assert!(origin.node.get_and_clear_pending_msg_events().is_empty());
+	origin.node.handle_commitment_signed(&receiver_id, &receiver_cs).unwrap();
+	let origin_raa = match single_msg_event(origin) {
+		MessageSendEvent::SendRevokeAndACK { node_id, msg } => {
+			assert_eq!(node_id, receiver_id);
+			msg
+		},
+		_ => panic!("Unexpected event"),
+	};
+
+	receiver.node.handle_revoke_and_ack(&origin_id, &origin_raa).unwrap();
+	assert!(receiver.node.get_and_clear_pending_msg_events().is_empty());
+}
+
+#[test]
+fn open_channel_send_and_settle_payment() {
+	let channel_value_sat = 100_000;
+	let payment_msat = 5_000_000;
+
+	let nodes = vec![create_node(1), create_node(2)];
+	let a_id = nodes[0].node.get_our_node_id();
+	let b_id = nodes[1].node.get_our_node_id();
+
+	// open_channel/accept_channel
+	nodes[0].node.create_channel(b_id, channel_value_sat, 0, 42).unwrap();
+	let open_channel = match single_msg_event(&nodes[0]) {
+		MessageSendEvent::SendOpenChannel { node_id, msg } => { assert_eq!(node_id, b_id); msg },
+		_ => panic!("Unexpected event"),
+	};
+	nodes[1].node.handle_open_channel(&a_id, LocalFeatures::new(), &open_channel).unwrap();
+	let accept_channel = match single_msg_event(&nodes[1]) {
+		MessageSendEvent::SendAcceptChannel { node_id, msg } => { assert_eq!(node_id, a_id); msg },
+		_ => panic!("Unexpected event"),
+	};
+	nodes[0].node.handle_accept_channel(&b_id, LocalFeatures::new(), &accept_channel).unwrap();
+
+	// funding_created/funding_signed
+	let (temporary_channel_id, funding_tx) = match single_event(&nodes[0]) {
+		Event::FundingGenerationReady { temporary_channel_id, channel_value_satoshis, output_script, user_channel_id } => {
+			assert_eq!(channel_value_satoshis, channel_value_sat);
+			assert_eq!(user_channel_id, 42);
+			(temporary_channel_id, Transaction { version: 1, lock_time: 0, input: Vec::new(), output: vec![TxOut {
+				value: channel_value_satoshis, script_pubkey: output_script,
+			}]})
+		},
+		_ => panic!("Unexpected event"),
+	};
+	let funding_outpoint = OutPoint::new(funding_tx.txid(), 0);
+	nodes[0].node.funding_transaction_generated(&temporary_channel_id, funding_outpoint);
+	let funding_created = match single_msg_event(&nodes[0]) {
+		MessageSendEvent::SendFundingCreated { node_id, msg } => { assert_eq!(node_id, b_id); msg },
+		_ => panic!("Unexpected event"),
+	};
+	nodes[1].node.handle_funding_created(&a_id, &funding_created).unwrap();
+	let funding_signed = match single_msg_event(&nodes[1]) {
+		MessageSendEvent::SendFundingSigned { node_id, msg } => { assert_eq!(node_id, a_id); msg },
+		_ => panic!("Unexpected event"),
+	};
+	nodes[0].node.handle_funding_signed(&b_id, &funding_signed).unwrap();
+	match single_event(&nodes[0]) {
+		Event::FundingBroadcastSafe { funding_txo, user_channel_id } => {
+			assert_eq!(funding_txo, funding_outpoint);
+			assert_eq!(user_channel_id, 42);
+		},
+		_ => panic!("Unexpected event"),
+	}
+
+	// Confirm the funding transaction on both sides and exchange funding_locked
+	confirm_transaction(&nodes[1].chain_monitor, &funding_tx);
+	let bs_funding_locked = match single_msg_event(&nodes[1]) {
+		MessageSendEvent::SendFundingLocked { node_id, msg } => { assert_eq!(node_id, a_id); msg },
+		_ => panic!("Unexpected event"),
+	};
+	nodes[0].node.handle_funding_locked(&b_id, &bs_funding_locked).unwrap();
+	confirm_transaction(&nodes[0].chain_monitor, &funding_tx);
+	let events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 2);
+	let as_funding_locked = match events[0] {
+		MessageSendEvent::SendFundingLocked { ref msg, .. } => msg.clone(),
+		_ => panic!("Unexpected event"),
+	};
+	let as_announcement_sigs = match events[1] {
+		MessageSendEvent::SendAnnouncementSignatures { ref msg, .. } => msg.clone(),
+		_ => panic!("Unexpected event"),
+	};
+	nodes[1].node.handle_funding_locked(&a_id, &as_funding_locked).unwrap();
+	let bs_announcement_sigs = match single_msg_event(&nodes[1]) {
+		MessageSendEvent::SendAnnouncementSignatures { msg, .. } => msg,
+		_ => panic!("Unexpected event"),
+	};
+
+	// Announce the channel and hand the gossip to both routers
+	nodes[1].node.handle_announcement_signatures(&a_id, &as_announcement_sigs).unwrap();
+	let (announcement, bs_update) = match single_msg_event(&nodes[1]) {
+		MessageSendEvent::BroadcastChannelAnnouncement { msg, update_msg } => (msg, update_msg),
+		_ => panic!("Unexpected event"),
+	};
+	nodes[0].node.handle_announcement_signatures(&b_id, &bs_announcement_sigs).unwrap();
+	let as_update = match single_msg_event(&nodes[0]) {
+		MessageSendEvent::BroadcastChannelAnnouncement { msg, update_msg } => {
+			assert!(msg == announcement);
+			update_msg
+		},
+		_ => panic!("Unexpected event"),
+	};
+	for node in nodes.iter() {
+		assert!(node.router.handle_channel_announcement(&announcement).unwrap());
+		node.router.handle_channel_update(&as_update).unwrap();
+		node.router.handle_channel_update(&bs_update).unwrap();
+	}
+
+	let channels = nodes[0].node.list_usable_channels();
+	assert_eq!(channels.len(), 1);
+	assert!(channels[0].short_channel_id.is_some());
+	// We can't send more than the 10% of the channel value nodes[1] allows in flight
+	assert_eq!(channels[0].outbound_capacity_msat, channel_value_sat * 1000 / 10);
+	assert!(channels[0].outbound_capacity_msat >= payment_msat);
+
+	// Send the payment
+	let payment_preimage = PaymentPreimage([42; 32]);
+	let payment_hash = PaymentHash(Sha256::hash(&payment_preimage.0[..]).into_inner());
+	let route = nodes[0].router.get_route(&b_id, None, &Vec::new(), payment_msat, 42).unwrap();
+	nodes[0].node.send_payment(route, payment_hash).unwrap();
+	let (update_add, commitment_signed) = match single_msg_event(&nodes[0]) {
+		MessageSendEvent::UpdateHTLCs { node_id, updates } => {
+			assert_eq!(node_id, b_id);
+			assert_eq!(updates.update_add_htlcs.len(), 1);
+			(updates.update_add_htlcs[0].clone(), updates.commitment_signed)
+		},
+		_ => panic!("Unexpected event"),
+	};
+	nodes[1].node.handle_update_add_htlc(&a_id, &update_add).unwrap();
+	commitment_signed_dance(&nodes[0], &nodes[1], &commitment_signed);
+
+	match single_event(&nodes[1]) {
+		Event::PendingHTLCsForwardable { .. } => {},
+		_ => panic!("Unexpected event"),
+	}
+	nodes[1].node.process_pending_htlc_forwards();
+	match single_event(&nodes[1]) {
+		Event::PaymentReceived { payment_hash: received_hash, amt } => {
+			assert_eq!(received_hash, payment_hash);
+			assert_eq!(amt, payment_msat);
+		},
+		_ => panic!("Unexpected event"),
+	}
+
+	// Settle it
+	assert!(nodes[1].node.claim_funds(payment_preimage));
+	let (update_fulfill, commitment_signed) = match single_msg_event(&nodes[1]) {
+		MessageSendEvent::UpdateHTLCs { node_id, updates } => {
+			assert_eq!(node_id, a_id);
+			assert_eq!(updates.update_fulfill_htlcs.len(), 1);
+			(updates.update_fulfill_htlcs[0].clone(), updates.commitment_signed)
+		},
+		_ => panic!("Unexpected event"),
+	};
+	nodes[0].node.handle_update_fulfill_htlc(&b_id, &update_fulfill).unwrap();
+	commitment_signed_dance(&nodes[1], &nodes[0], &commitment_signed);
+	match single_event(&nodes[0]) {
+		Event::PaymentSent { payment_preimage: sent_preimage } => assert_eq!(sent_preimage, payment_preimage),
+		_ => panic!("Unexpected event"),
+	}
+
+	// The payment has moved from A's side of the channel to B's, which can now send it back less
+	// the 1000 sat reserve A requires. A is still only limited by B's in-flight maximum.
+	let as_channels = nodes[0].node.list_channels();
+	assert_eq!(as_channels.len(), 1);
+	assert_eq!(as_channels[0].outbound_capacity_msat, channel_value_sat * 1000 / 10);
+	assert_eq!(as_channels[0].pending_outbound_htlc_msat, 0);
+	let bs_channels = nodes[1].node.list_channels();
+	assert_eq!(bs_channels.len(), 1);
+	assert_eq!(bs_channels[0].outbound_capacity_msat, payment_msat - 1000 * 1000);
+	assert_eq!(bs_channels[0].inbound_capacity_msat, channel_value_sat * 1000 / 10);
+	assert_eq!(bs_channels[0].pending_inbound_htlc_msat, 0);
+
+	// Nothing should have been broadcast by either side
+	for node in nodes.iter() {
+		assert!(node.tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+		assert!(node.node.get_and_clear_pending_msg_events().is_empty());
+		assert!(node.node.get_and_clear_pending_events().is_empty());
+	}
+}